@@ -0,0 +1,79 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Decodes guest cross-domain command buffers, and writes host messages to the channel ring, as
+//! Wayland clients in the guest do for every request and event.
+
+use std::ffi::c_void;
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use criterion::Throughput;
+use rutabaga_gfx::parse_cross_domain_commands;
+use rutabaga_gfx::write_cross_domain_receive;
+use rutabaga_gfx::RutabagaIovec;
+
+const CROSS_DOMAIN_CMD_SEND: u8 = 4;
+const CROSS_DOMAIN_MAX_IDENTIFIERS: usize = 28;
+// The size of CrossDomainSendReceive: the header, num_identifiers, opaque_data_size, and the
+// three identifier arrays.
+const SEND_RECEIVE_SIZE: usize = 8 + 4 + 4 + 3 * 4 * CROSS_DOMAIN_MAX_IDENTIFIERS;
+const CHANNEL_ID: u32 = 1;
+const PAGE_SIZE: usize = 4096;
+// The typical size of a Wayland message, such as a wl_surface.damage_buffer request.
+const WAYLAND_MESSAGE_SIZE: usize = 64;
+
+// A CROSS_DOMAIN_CMD_SEND of `data` without identifiers, as the guest writes it.
+fn send_command(data: &[u8]) -> Vec<u8> {
+    let cmd_size = (SEND_RECEIVE_SIZE + data.len()) as u16;
+
+    let mut command = Vec::with_capacity(SEND_RECEIVE_SIZE + data.len());
+    command.push(CROSS_DOMAIN_CMD_SEND);
+    command.push(0);
+    command.extend_from_slice(&cmd_size.to_le_bytes());
+    command.extend_from_slice(&CHANNEL_ID.to_le_bytes());
+    command.extend_from_slice(&0u32.to_le_bytes());
+    command.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    command.resize(SEND_RECEIVE_SIZE, 0);
+    command.extend_from_slice(data);
+    command
+}
+
+fn parse_commands(c: &mut Criterion) {
+    let message = [0xa5; WAYLAND_MESSAGE_SIZE];
+    let mut group = c.benchmark_group("parse_cross_domain_commands");
+    // One message per submission, and a batch flushed at once.
+    for count in [1, 32] {
+        let commands = send_command(&message).repeat(count);
+        group.throughput(Throughput::Bytes(commands.len() as u64));
+        group.bench_function(BenchmarkId::new("send_64b", count), |b| {
+            b.iter(|| parse_cross_domain_commands(&commands).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn write_to_ring(c: &mut Criterion) {
+    let mut ring = vec![0; PAGE_SIZE];
+    let iovecs = [RutabagaIovec {
+        base: ring.as_mut_ptr() as *mut c_void,
+        len: ring.len(),
+    }];
+
+    let mut group = c.benchmark_group("write_cross_domain_receive");
+    // A single Wayland message, and as much as a one page ring takes.
+    for size in [WAYLAND_MESSAGE_SIZE, PAGE_SIZE - SEND_RECEIVE_SIZE] {
+        let data = vec![0xa5; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter(|| write_cross_domain_receive(&iovecs, CHANNEL_ID, &data).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse_commands, write_to_ring);
+criterion_main!(benches);
@@ -0,0 +1,80 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Serializes the state of Rutabaga with `snapshot()`, which pauses the guest during live
+//! migration and suspend.
+
+use std::fs;
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BatchSize;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use rutabaga_gfx::ResourceCreate3D;
+use rutabaga_gfx::Rutabaga;
+use rutabaga_gfx::RutabagaBuilder;
+use rutabaga_gfx::RutabagaComponentType;
+use rutabaga_gfx::RutabagaHandler;
+use rutabaga_gfx::RUTABAGA_PIPE_BIND_RENDER_TARGET;
+use rutabaga_gfx::RUTABAGA_PIPE_TEXTURE_2D;
+
+// B8G8R8A8_UNORM
+const FORMAT: u32 = 1;
+
+// Rutabaga with `count` 1080p 2D resources, like a guest with as many surfaces.
+fn new_rutabaga(count: u32) -> Rutabaga {
+    let mut rutabaga = RutabagaBuilder::new(0, RutabagaHandler::new(|_| {}))
+        .set_default_component(RutabagaComponentType::Rutabaga2D)
+        .build()
+        .unwrap();
+
+    for resource_id in 1..=count {
+        rutabaga
+            .resource_create_3d(
+                resource_id,
+                ResourceCreate3D {
+                    target: RUTABAGA_PIPE_TEXTURE_2D,
+                    format: FORMAT,
+                    bind: RUTABAGA_PIPE_BIND_RENDER_TARGET,
+                    width: 1920,
+                    height: 1080,
+                    depth: 1,
+                    array_size: 1,
+                    last_level: 0,
+                    nr_samples: 0,
+                    flags: 0,
+                },
+            )
+            .unwrap();
+    }
+
+    rutabaga
+}
+
+fn snapshot(c: &mut Criterion) {
+    let dir = std::env::temp_dir().join(format!("rutabaga_snapshot_bench_{}", std::process::id()));
+
+    let mut group = c.benchmark_group("snapshot");
+    for count in [16, 256] {
+        let rutabaga = new_rutabaga(count);
+        group.bench_function(BenchmarkId::new("2d_resources", count), |b| {
+            // Fragments are never overwritten, so every snapshot starts from an empty directory.
+            b.iter_batched(
+                || {
+                    let _ = fs::remove_dir_all(&dir);
+                    fs::create_dir(&dir).unwrap();
+                },
+                |()| rutabaga.snapshot(&dir).unwrap(),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+criterion_group!(benches, snapshot);
+criterion_main!(benches);
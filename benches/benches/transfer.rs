@@ -0,0 +1,104 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Copies guest backing to host memory with `transfer_write()`, as 2D guests do every frame.
+
+use std::ffi::c_void;
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use criterion::Throughput;
+use rutabaga_gfx::ResourceCreate3D;
+use rutabaga_gfx::Rutabaga;
+use rutabaga_gfx::RutabagaBuilder;
+use rutabaga_gfx::RutabagaComponentType;
+use rutabaga_gfx::RutabagaHandler;
+use rutabaga_gfx::RutabagaIovec;
+use rutabaga_gfx::Transfer3D;
+use rutabaga_gfx::RUTABAGA_PIPE_BIND_RENDER_TARGET;
+use rutabaga_gfx::RUTABAGA_PIPE_TEXTURE_2D;
+
+const RESOURCE_ID: u32 = 1;
+// B8G8R8A8_UNORM
+const FORMAT: u32 = 1;
+const BYTES_PER_PIXEL: u32 = 4;
+const PAGE_SIZE: usize = 4096;
+
+const MODES: [(&str, u32, u32); 2] = [("1080p", 1920, 1080), ("4k", 3840, 2160)];
+
+// A 2D resource backed by `backing`, split into pages like guest memory if `paged`.
+fn new_resource(width: u32, height: u32, backing: &mut [u8], paged: bool) -> Rutabaga {
+    let mut rutabaga = RutabagaBuilder::new(0, RutabagaHandler::new(|_| {}))
+        .set_default_component(RutabagaComponentType::Rutabaga2D)
+        .build()
+        .unwrap();
+
+    rutabaga
+        .resource_create_3d(
+            RESOURCE_ID,
+            ResourceCreate3D {
+                target: RUTABAGA_PIPE_TEXTURE_2D,
+                format: FORMAT,
+                bind: RUTABAGA_PIPE_BIND_RENDER_TARGET,
+                width,
+                height,
+                depth: 1,
+                array_size: 1,
+                last_level: 0,
+                nr_samples: 0,
+                flags: 0,
+            },
+        )
+        .unwrap();
+
+    let chunk_size = if paged { PAGE_SIZE } else { backing.len() };
+    let iovecs = backing
+        .chunks_mut(chunk_size)
+        .map(|chunk| RutabagaIovec {
+            base: chunk.as_mut_ptr() as *mut c_void,
+            len: chunk.len(),
+        })
+        .collect();
+    rutabaga.attach_backing(RESOURCE_ID, iovecs).unwrap();
+    rutabaga
+}
+
+fn transfer_write(c: &mut Criterion) {
+    let mut group = c.benchmark_group("transfer_write");
+    for (name, width, height) in MODES {
+        let stride = width * BYTES_PER_PIXEL;
+        let mut backing = vec![0x5a; (stride * height) as usize];
+
+        for paged in [false, true] {
+            let mut rutabaga = new_resource(width, height, &mut backing, paged);
+            let layout = if paged { "paged" } else { "contiguous" };
+
+            // The whole frame, as after a full repaint.
+            let full = Transfer3D::new_2d(0, 0, width, height, 0);
+            group.throughput(Throughput::Bytes(u64::from(stride * height)));
+            group.bench_function(BenchmarkId::new(format!("full/{layout}"), name), |b| {
+                b.iter(|| rutabaga.transfer_write(0, RESOURCE_ID, full, None).unwrap())
+            });
+
+            // A centered quarter of the frame, as after a partial repaint.  Rows are copied one at
+            // a time, since they are strided in the backing.
+            let (x, y, w, h) = (width / 4, height / 4, width / 2, height / 2);
+            let damage = Transfer3D::new_2d(x, y, w, h, 0);
+            group.throughput(Throughput::Bytes(u64::from(w * BYTES_PER_PIXEL * h)));
+            group.bench_function(BenchmarkId::new(format!("damage/{layout}"), name), |b| {
+                b.iter(|| {
+                    rutabaga
+                        .transfer_write(0, RESOURCE_ID, damage, None)
+                        .unwrap()
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, transfer_write);
+criterion_main!(benches);
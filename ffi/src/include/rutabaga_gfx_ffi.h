@@ -52,6 +52,8 @@ extern "C" {
 #define RUTABAGA_BLOB_FLAG_USE_MAPPABLE 1
 #define RUTABAGA_BLOB_FLAG_USE_SHAREABLE 2
 #define RUTABAGA_BLOB_FLAG_USE_CROSS_DEVICE 4
+#define RUTABAGA_BLOB_FLAG_PROTECTED 0x80000000
+#define RUTABAGA_BLOB_FLAG_CREATE_GUEST_HANDLE 8
 
 /**
  * Mapped memory caching flags (see virtio_gpu spec)
@@ -92,6 +94,9 @@ extern "C" {
  * Rutabaga channel types
  */
 #define RUTABAGA_CHANNEL_TYPE_WAYLAND 1
+#define RUTABAGA_CHANNEL_TYPE_X11 3
+#define RUTABAGA_CHANNEL_TYPE_PULSE 4
+#define RUTABAGA_CHANNEL_TYPE_CAMERA 5
 
 /**
  * Rutabaga WSI
@@ -112,6 +117,22 @@ extern "C" {
 #define RUTABAGA_DEBUG_WARN 0x2
 #define RUTABAGA_DEBUG_INFO 0x3
 
+/**
+ * Rutabaga error codes, returned negated.  The values are those of the Linux errno with the same
+ * meaning, on every platform.
+ */
+#define RUTABAGA_ERROR_PERMISSION_DENIED 1
+#define RUTABAGA_ERROR_NOT_FOUND 2
+#define RUTABAGA_ERROR_PANICKED 3
+#define RUTABAGA_ERROR_IO 5
+#define RUTABAGA_ERROR_OUT_OF_MEMORY 12
+#define RUTABAGA_ERROR_BUSY 16
+#define RUTABAGA_ERROR_CONTEXT_LOST 19
+#define RUTABAGA_ERROR_INVALID_ARGUMENT 22
+#define RUTABAGA_ERROR_LIMIT_EXCEEDED 28
+#define RUTABAGA_ERROR_UNSUPPORTED 95
+#define RUTABAGA_ERROR_TIMED_OUT 110
+
 #ifdef RUTABAGA_GFX_FFI_UNSTABLE
 
 /**
@@ -266,6 +287,15 @@ struct rutabaga_builder {
  */
 int32_t rutabaga_calculate_capset_mask(const char *capset_names, uint64_t *capset_mask);
 
+/**
+ * Returns the last error returned to the calling thread, or 0, and copies its message to
+ * `message` as a null-terminated C-string truncated to `message_len` bytes.
+ *
+ * # Safety
+ * - If `message` is not null, it must point to `message_len` writable bytes.
+ */
+int32_t rutabaga_last_error(char *message, size_t message_len);
+
 /**
  * # Safety
  * - If `(*builder).channels` is not null, the caller must ensure `(*channels).channels` points to
@@ -351,6 +381,14 @@ int32_t rutabaga_resource_create_blob(struct rutabaga *ptr, uint32_t ctx_id, uin
                                       const struct rutabaga_iovecs *iovecs,
                                       const struct rutabaga_handle *handle);
 
+/**
+ * Writes the handle type `rutabaga_resource_export_blob` would return for a blob created with the
+ * same `ctx_id` and `rutabaga_create_blob`, without creating it.
+ */
+int32_t rutabaga_resource_probe_blob_handle_type(struct rutabaga *ptr, uint32_t ctx_id,
+                                                 const struct rutabaga_create_blob *rutabaga_create_blob,
+                                                 uint32_t *handle_type);
+
 int32_t rutabaga_resource_unref(struct rutabaga *ptr, uint32_t resource_id);
 
 /**
@@ -367,6 +405,21 @@ int32_t rutabaga_resource_unmap(struct rutabaga *ptr, uint32_t resource_id);
 
 int32_t rutabaga_resource_map_info(struct rutabaga *ptr, uint32_t resource_id, uint32_t *map_info);
 
+/**
+ * Writes the UUID generated when the resource was created, which is preserved across snapshot and
+ * restore, unlike host handles.
+ */
+int32_t rutabaga_resource_uuid(struct rutabaga *ptr, uint32_t resource_id, uint8_t uuid[16]);
+
+/**
+ * Labels the resource with a guest-provided name of at most 64 bytes, for debugging.  A null
+ * `name` clears the label.
+ *
+ * # Safety
+ * - `name` must be null or a null-terminated C-string.
+ */
+int32_t rutabaga_resource_set_name(struct rutabaga *ptr, uint32_t resource_id, const char *name);
+
 /**
  * # Safety
  * - `cmd` must be not null
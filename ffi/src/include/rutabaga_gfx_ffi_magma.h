@@ -0,0 +1,445 @@
+/*
+ * Copyright 2025 The ChromiumOS Authors
+ * Use of this source code is governed by a BSD-style license that can be
+ * found in the LICENSE file.
+ */
+
+#ifndef RUTABAGA_GFX_FFI_MAGMA_H
+#define RUTABAGA_GFX_FFI_MAGMA_H
+
+/*
+ * Mirrors ffi/src/magma.rs and the mesa3d_magma definitions it exposes.  The magma_header tests
+ * of rutabaga_gfx_ffi check the constants, functions and structure layouts declared here against
+ * them.
+ */
+
+#include "rutabaga_gfx_ffi.h"
+
+/**
+ * The major version is bumped for incompatible changes to this API, and the minor version when
+ * functions are added.  Structures are never changed within a major version.
+ */
+#define MAGMA_FFI_VERSION_MAJOR 1
+#define MAGMA_FFI_VERSION_MINOR 3
+
+/**
+ * The version of the binary interface described by this header: the layout of its structures and
+ * the signatures of its functions.  Bumped whenever either changes, even compatibly.  Drivers
+ * should compare it to `magma_get_abi_version` at runtime, with `magma_check_abi_version`, rather
+ * than trust that the library they load was built from the same sources as the header.
+ */
+#define MAGMA_FFI_ABI_VERSION 1
+
+/**
+ * Engine classes
+ */
+#define MAGMA_ENGINE_CLASS_RENDER 0
+#define MAGMA_ENGINE_CLASS_COPY 1
+#define MAGMA_ENGINE_CLASS_COMPUTE 2
+#define MAGMA_ENGINE_CLASS_VIDEO 3
+
+/**
+ * Heap flags
+ */
+#define MAGMA_HEAP_DEVICE_LOCAL_BIT 0x00000001
+#define MAGMA_HEAP_CPU_VISIBLE_BIT 0x00000010
+#define MAGMA_HEAP_PRESERVED_ON_SUSPEND_BIT 0x00000100
+
+/**
+ * Memory type property flags
+ */
+#define MAGMA_MEMORY_PROPERTY_DEVICE_LOCAL_BIT 0x00000001
+#define MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT 0x00000002
+#define MAGMA_MEMORY_PROPERTY_HOST_COHERENT_BIT 0x00000004
+#define MAGMA_MEMORY_PROPERTY_HOST_CACHED_BIT 0x00000008
+#define MAGMA_MEMORY_PROPERTY_LAZILY_ALLOCATED_BIT 0x00000010
+#define MAGMA_MEMORY_PROPERTY_PROTECTED_BIT 0x00000020
+
+#define MAGMA_MAX_MEMORY_TYPES 32
+#define MAGMA_MAX_MEMORY_HEAPS 16
+
+/**
+ * Buffer creation flags
+ */
+#define MAGMA_BUFFER_FLAG_EXTERNAL 0x00000001
+#define MAGMA_BUFFER_FLAG_SCANOUT 0x00000002
+#define MAGMA_BUFFER_FLAG_SPARSE 0x00000004
+
+#define MAGMA_BUFFER_FLAG_AMD_OA 0x00000001
+#define MAGMA_BUFFER_FLAG_AMD_GDS 0x00000002
+
+/**
+ * GPU virtual address mapping flags
+ */
+#define MAGMA_MAP_GPU_FLAG_READ (1 << 0)
+#define MAGMA_MAP_GPU_FLAG_WRITE (1 << 1)
+#define MAGMA_MAP_GPU_FLAG_EXECUTE (1 << 2)
+#define MAGMA_MAP_GPU_FLAGS \
+    (MAGMA_MAP_GPU_FLAG_READ | MAGMA_MAP_GPU_FLAG_WRITE | MAGMA_MAP_GPU_FLAG_EXECUTE)
+
+#define MAGMA_USER_FENCE_SIZE 4096
+
+/**
+ * Cache maintenance flags
+ */
+#define MAGMA_SYNC_WHOLE_RANGE (1 << 0)
+#define MAGMA_SYNC_RANGES (1 << 1)
+#define MAGMA_SYNC_INVALIDATE_READ (1 << 2)
+#define MAGMA_SYNC_INVALIDATE_WRITE (1 << 3)
+
+/**
+ * PCI vendor ids
+ */
+#define MAGMA_VENDOR_ID_INTEL 0x8086
+#define MAGMA_VENDOR_ID_AMD 0x1002
+#define MAGMA_VENDOR_ID_MALI 0x13B5
+#define MAGMA_VENDOR_ID_QCOM 0x5413
+#define MAGMA_VENDOR_ID_VIRTIO 0x1AF4
+
+/**
+ * PCI domain reported for platform (non-PCI) devices.  The device number is then the minor of the
+ * DRM render node.
+ */
+#define MAGMA_PCI_DOMAIN_PLATFORM 0xffff
+
+typedef uint64_t magma_handle_t;
+
+typedef magma_handle_t magma_physical_device_t;
+
+struct magma_pci_info {
+    uint16_t vendor_id;
+    uint16_t device_id;
+    uint16_t subvendor_id;
+    uint16_t subdevice_id;
+    uint8_t revision_id;
+    uint8_t padding[7];
+};
+
+typedef struct magma_pci_info magma_pci_info;
+
+struct magma_pci_bus_info {
+    uint16_t domain;
+    uint8_t bus;
+    uint8_t device;
+    uint8_t function;
+    uint8_t padding[7];
+};
+
+typedef struct magma_pci_bus_info magma_pci_bus_info;
+
+typedef magma_handle_t magma_device_t;
+
+struct magma_memory_type {
+    uint32_t property_flags;
+    uint32_t heap_idx;
+};
+
+struct magma_heap {
+    uint64_t heap_size;
+    uint64_t heap_flags;
+};
+
+struct magma_memory_properties {
+    uint32_t memory_type_count;
+    uint32_t memory_heap_count;
+    struct magma_memory_type memory_types[MAGMA_MAX_MEMORY_TYPES];
+    struct magma_heap memory_heaps[MAGMA_MAX_MEMORY_HEAPS];
+};
+
+typedef struct magma_memory_properties magma_memory_properties;
+
+struct magma_heap_budget {
+    uint64_t budget;
+    uint64_t usage;
+};
+
+typedef struct magma_heap_budget magma_heap_budget;
+
+struct magma_create_buffer_info {
+    uint32_t memory_type_idx;
+    uint32_t alignment;
+    uint32_t common_flags;
+    uint32_t vendor_flags;
+    uint64_t size;
+};
+
+typedef struct magma_create_buffer_info magma_create_buffer_info;
+
+typedef magma_handle_t magma_buffer_t;
+
+typedef magma_handle_t magma_buffer_pool_t;
+
+/**
+ * Counters of a `MagmaBufferPool`, summed over all memory types.
+ */
+struct magma_buffer_pool_stats {
+    uint64_t block_count;
+    uint64_t block_bytes;
+    uint64_t allocation_count;
+    uint64_t allocated_bytes;
+    /**
+     * Free ranges between allocations.  Many ranges for few free bytes mean the pool is
+     * fragmented.
+     */
+    uint64_t free_range_count;
+    /**
+     * The largest allocation that fits without a new block, ignoring alignment.
+     */
+    uint64_t largest_free_range;
+};
+
+typedef struct magma_buffer_pool_stats magma_buffer_pool_stats;
+
+struct magma_mapped_memory_range {
+    uint64_t offset;
+    uint64_t size;
+};
+
+typedef struct magma_mapped_memory_range magma_mapped_memory_range;
+
+typedef magma_handle_t magma_context_t;
+
+struct magma_engine_info {
+    uint32_t engine_class;
+    uint32_t count;
+};
+
+typedef magma_handle_t magma_semaphore_t;
+
+/**
+ * Objects are referred to by handles, which are never reused within a process.  Every function
+ * is thread-safe and handles may be used from any thread.  Passing a destroyed handle, or a handle
+ * of the wrong type, returns -EINVAL.  Destroying an object while another thread is using it is
+ * safe: the object is freed once the last such call returns.
+ */
+#define MAGMA_NULL_HANDLE 0
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Returns the version of the API implemented by the library, which may be newer than the one this
+ * header describes.
+ */
+int32_t magma_get_version(uint32_t *major, uint32_t *minor);
+
+/**
+ * Returns MAGMA_FFI_ABI_VERSION of the library, which must match that of this header.  Unlike
+ * `magma_get_version`, this can't fail and takes no pointers, so it's safe to call before
+ * anything else is known about the library.
+ */
+uint32_t magma_get_abi_version(void);
+
+/**
+ * If `devices` is null, `*num_devices` is set to the number of devices.  Otherwise, up to
+ * `*num_devices` devices are written and `*num_devices` is set to the number written.  Every
+ * returned physical device must be destroyed with `magma_physical_device_destroy`.
+ *
+ * # Safety
+ * - If `devices` is not null, it must point to an array of at least `*num_devices` elements.
+ */
+int32_t magma_enumerate_devices(magma_physical_device_t *devices, uint32_t *num_devices);
+
+int32_t magma_physical_device_get_pci_info(magma_physical_device_t physical_device,
+                                           magma_pci_info *pci_info,
+                                           magma_pci_bus_info *pci_bus_info);
+
+int32_t magma_physical_device_destroy(magma_physical_device_t physical_device);
+
+int32_t magma_device_create(magma_physical_device_t physical_device, magma_device_t *device);
+
+int32_t magma_device_destroy(magma_device_t device);
+
+int32_t magma_device_get_memory_properties(magma_device_t device,
+                                           magma_memory_properties *memory_properties);
+
+int32_t magma_device_get_memory_budget(magma_device_t device,
+                                       uint32_t heap_idx,
+                                       magma_heap_budget *budget);
+
+/**
+ * Makes the vendor-specific query `id`: an AMDGPU_INFO_*, I915_PARAM_*, DRM_XE_DEVICE_QUERY_*,
+ * MSM_PARAM_* or VIRTGPU_PARAM_* value on Linux, depending on the driver, and a
+ * KMTQUERYADAPTERINFOTYPE on Windows.  `input` holds the query's arguments, if any.
+ *
+ * Scalar results are written to `*value`, and `*buffer_size` is set to zero.  Other results are
+ * written to `buffer`, truncated to `*buffer_size` bytes, and `*buffer_size` is set to their full
+ * size.  `buffer` may be null to only get the size.  Queries the driver doesn't expose fail with
+ * -EINVAL.  Available since minor version 1.
+ *
+ * # Safety
+ * - If `input_size` is not zero, `input` must point to `input_size` readable bytes.
+ * - If `buffer` is not null, it must point to `*buffer_size` writable bytes.
+ */
+int32_t magma_device_query(magma_device_t device,
+                           uint64_t id,
+                           const void *input,
+                           size_t input_size,
+                           uint64_t *value,
+                           void *buffer,
+                           size_t *buffer_size);
+
+int32_t magma_device_create_buffer(magma_device_t device,
+                                   const magma_create_buffer_info *create_info,
+                                   magma_buffer_t *buffer);
+
+/**
+ * Only dma-bufs (NT handles on Windows) are accepted.  Other handle types, which could carry a
+ * legacy GEM flink name, fail with -EACCES.
+ *
+ * # Safety
+ * - `handle` must be a valid OS-descriptor.  Ownership is transferred to magma.
+ */
+int32_t magma_device_import_buffer(magma_device_t device,
+                                   const struct rutabaga_handle *handle,
+                                   uint64_t size,
+                                   uint32_t memory_type_idx,
+                                   magma_buffer_t *buffer);
+
+/**
+ * Any CPU mapping of the buffer is unmapped.
+ */
+int32_t magma_buffer_destroy(magma_buffer_t buffer);
+
+/**
+ * The mapping stays valid until `magma_buffer_unmap` or `magma_buffer_destroy`.
+ */
+int32_t magma_buffer_map(magma_buffer_t buffer, struct rutabaga_mapping *mapping);
+
+int32_t magma_buffer_unmap(magma_buffer_t buffer);
+
+/**
+ * # Safety
+ * Caller owns raw descriptor on success and is responsible for closing it.
+ */
+int32_t magma_buffer_export(magma_buffer_t buffer, struct rutabaga_handle *handle);
+
+/**
+ * Creates a pool that sub-allocates buffers from driver buffers (blocks) of `block_size` bytes, or
+ * of a default size if zero.  Each memory type has its own blocks.  Available since minor version
+ * 3.
+ */
+int32_t magma_device_create_buffer_pool(magma_device_t device,
+                                        uint64_t block_size,
+                                        magma_buffer_pool_t *pool);
+
+/**
+ * Buffers allocated from the pool stay valid, and their blocks are freed with the last of them.
+ */
+int32_t magma_buffer_pool_destroy(magma_buffer_pool_t pool);
+
+/**
+ * Allocates `create_info->size` bytes at `*offset` of a block, aligned to
+ * `create_info->alignment`.  Buffer flags must be zero.  The returned buffer refers to the whole
+ * block: mappings must be offset by `*offset`, and exporting it fails with -EACCES.  Destroying it
+ * returns the range to the pool.  Available since minor version 3.
+ */
+int32_t magma_buffer_pool_allocate(magma_buffer_pool_t pool,
+                                   const magma_create_buffer_info *create_info,
+                                   magma_buffer_t *buffer,
+                                   uint64_t *offset);
+
+/**
+ * Available since minor version 3.
+ */
+int32_t magma_buffer_pool_get_stats(magma_buffer_pool_t pool, magma_buffer_pool_stats *stats);
+
+/**
+ * `sync_flags` must contain exactly one of MAGMA_SYNC_WHOLE_RANGE and MAGMA_SYNC_RANGES, and with
+ * the latter every range must lie within the buffer, or -EINVAL is returned.  Flushing makes CPU
+ * writes visible to later GPU work, and invalidating makes completed GPU writes visible to the
+ * CPU.  Both are cheap on HOST_COHERENT memory types but must still be called around accesses.
+ *
+ * # Safety
+ * - If `num_ranges` is not zero, `ranges` must point to an array of `num_ranges` elements.
+ */
+int32_t magma_buffer_flush(magma_buffer_t buffer,
+                           uint64_t sync_flags,
+                           const magma_mapped_memory_range *ranges,
+                           uint32_t num_ranges);
+
+/**
+ * # Safety
+ * - If `num_ranges` is not zero, `ranges` must point to an array of `num_ranges` elements.
+ */
+int32_t magma_buffer_invalidate(magma_buffer_t buffer,
+                                uint64_t sync_flags,
+                                const magma_mapped_memory_range *ranges,
+                                uint32_t num_ranges);
+
+/**
+ * Creates a context on the render engine.
+ */
+int32_t magma_device_create_context(magma_device_t device, magma_context_t *context);
+
+/**
+ * If `engines` is null, `*num_engines` is set to the number of engine classes of the device.
+ * Otherwise, up to `*num_engines` classes are written and `*num_engines` is set to the number
+ * written.  Available since minor version 2.
+ *
+ * # Safety
+ * - If `engines` is not null, it must point to an array of at least `*num_engines` elements.
+ */
+int32_t magma_device_enumerate_engines(magma_device_t device,
+                                       struct magma_engine_info *engines,
+                                       uint32_t *num_engines);
+
+/**
+ * Creates a context on an engine of `engine_class`, a MAGMA_ENGINE_CLASS_* value.  Fails with
+ * -EINVAL if the device has no such engine.  Available since minor version 2.
+ */
+int32_t magma_device_create_context_on_engine(magma_device_t device,
+                                              uint32_t engine_class,
+                                              magma_context_t *context);
+
+int32_t magma_context_destroy(magma_context_t context);
+
+int32_t magma_context_map_gpu(magma_context_t context,
+                              magma_buffer_t buffer,
+                              uint64_t gpu_va,
+                              uint64_t flags);
+
+int32_t magma_context_unmap_gpu(magma_context_t context, magma_buffer_t buffer, uint64_t gpu_va);
+
+/**
+ * Semaphores are binary, and a successful wait resets them.
+ */
+int32_t magma_device_create_semaphore(magma_device_t device, magma_semaphore_t *semaphore);
+
+/**
+ * # Safety
+ * - `handle` must be a valid OS-descriptor.  Ownership is transferred to magma.
+ */
+int32_t magma_device_import_semaphore(magma_device_t device,
+                                      const struct rutabaga_handle *handle,
+                                      magma_semaphore_t *semaphore);
+
+int32_t magma_semaphore_destroy(magma_semaphore_t semaphore);
+
+int32_t magma_semaphore_signal(magma_semaphore_t semaphore);
+
+/**
+ * Returns -ETIMEDOUT if the semaphore isn't signaled within `timeout_ns`.
+ */
+int32_t magma_semaphore_wait(magma_semaphore_t semaphore, uint64_t timeout_ns);
+
+/**
+ * # Safety
+ * Caller owns raw descriptor on success and is responsible for closing it.
+ */
+int32_t magma_semaphore_export(magma_semaphore_t semaphore, struct rutabaga_handle *handle);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+/**
+ * Returns true if the library implements the binary interface this header describes.
+ */
+static inline bool magma_check_abi_version(void)
+{
+    return magma_get_abi_version() == MAGMA_FFI_ABI_VERSION;
+}
+
+#endif  /* RUTABAGA_GFX_FFI_MAGMA_H */
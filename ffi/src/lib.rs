@@ -6,6 +6,7 @@
 
 extern crate rutabaga_gfx;
 
+use std::cell::RefCell;
 use std::convert::TryInto;
 use std::ffi::CStr;
 use std::ffi::CString;
@@ -22,6 +23,7 @@ use std::ptr::copy_nonoverlapping;
 use std::ptr::null_mut;
 use std::slice::from_raw_parts;
 use std::slice::from_raw_parts_mut;
+use std::str::Utf8Error;
 use std::sync::Mutex;
 use std::sync::OnceLock;
 
@@ -37,6 +39,8 @@ use rutabaga_gfx::RutabagaComponentType;
 use rutabaga_gfx::RutabagaDebug;
 use rutabaga_gfx::RutabagaDebugHandler;
 use rutabaga_gfx::RutabagaDescriptor;
+use rutabaga_gfx::RutabagaError;
+use rutabaga_gfx::RutabagaErrorCode;
 use rutabaga_gfx::RutabagaFence;
 use rutabaga_gfx::RutabagaFenceHandler;
 use rutabaga_gfx::RutabagaFromRawDescriptor;
@@ -64,6 +68,28 @@ const RUTABAGA_WSI_SURFACELESS: u64 = 1;
 
 static S_DEBUG_HANDLER: OnceLock<Mutex<RutabagaDebugHandler>> = OnceLock::new();
 
+thread_local! {
+    // The last error returned on this thread and its message, for rutabaga_last_error().
+    static S_LAST_ERROR: RefCell<(i32, CString)> = RefCell::new((NO_ERROR, CString::default()));
+}
+
+// Errors returned to C callers, as negated error codes.
+trait FfiError: ToString {
+    fn code(&self) -> RutabagaErrorCode;
+}
+
+impl FfiError for RutabagaError {
+    fn code(&self) -> RutabagaErrorCode {
+        self.into()
+    }
+}
+
+impl FfiError for Utf8Error {
+    fn code(&self) -> RutabagaErrorCode {
+        RutabagaErrorCode::InvalidArgument
+    }
+}
+
 fn log_error(debug_string: String) {
     if let Some(handler_mutex) = S_DEBUG_HANDLER.get() {
         let cstring = CString::new(debug_string.as_str()).expect("CString creation failed");
@@ -78,12 +104,22 @@ fn log_error(debug_string: String) {
     }
 }
 
+// Records `errno` and `message` as the thread's last error, logs the message, and returns `errno`.
+fn set_last_error(errno: i32, message: String) -> i32 {
+    let cstring = CString::new(message.replace('\0', "")).unwrap_or_default();
+    S_LAST_ERROR.with_borrow_mut(|last_error| *last_error = (errno, cstring));
+    log_error(message);
+    errno
+}
+
+fn to_errno(e: impl FfiError) -> i32 {
+    set_last_error(-(e.code() as i32), e.to_string())
+}
+
 fn return_result<T>(result: RutabagaResult<T>) -> i32 {
-    if let Err(e) = result {
-        log_error(e.to_string());
-        -EINVAL
-    } else {
-        NO_ERROR
+    match result {
+        Ok(_) => NO_ERROR,
+        Err(e) => to_errno(e),
     }
 }
 
@@ -91,14 +127,14 @@ macro_rules! return_on_error {
     ($result:expr) => {
         match $result {
             Ok(t) => t,
-            Err(e) => {
-                log_error(e.to_string());
-                return -EINVAL;
-            }
+            Err(e) => return to_errno(e),
         }
     };
 }
 
+#[cfg(feature = "magma")]
+mod magma;
+
 #[allow(non_camel_case_types)]
 type rutabaga = Rutabaga;
 
@@ -192,6 +228,25 @@ fn create_ffi_debug_handler(
     RutabagaDebugHandler::new(move |rutabaga_debug| debug_cb(user_data, &rutabaga_debug))
 }
 
+/// # Safety
+/// - If `message` isn't null, it must point to `message_len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn rutabaga_last_error(message: *mut c_char, message_len: usize) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        S_LAST_ERROR.with_borrow(|(errno, last_message)| {
+            if !message.is_null() && message_len != 0 {
+                let bytes = last_message.as_bytes();
+                let len = bytes.len().min(message_len - 1);
+                copy_nonoverlapping(bytes.as_ptr(), message as *mut u8, len);
+                *message.add(len) = 0;
+            }
+
+            *errno
+        })
+    }))
+    .unwrap_or(-ESRCH)
+}
+
 #[no_mangle]
 /// # Safety
 /// - `capset_names` must be a null-terminated C-string.
@@ -604,6 +659,21 @@ pub unsafe extern "C" fn rutabaga_resource_create_blob(
     .unwrap_or(-ESRCH)
 }
 
+#[no_mangle]
+pub extern "C" fn rutabaga_resource_probe_blob_handle_type(
+    ptr: &mut rutabaga,
+    ctx_id: u32,
+    create_blob: &rutabaga_create_blob,
+    handle_type: &mut u32,
+) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let result = ptr.probe_blob_handle_type(ctx_id, *create_blob);
+        *handle_type = return_on_error!(result);
+        NO_ERROR
+    }))
+    .unwrap_or(-ESRCH)
+}
+
 #[no_mangle]
 pub extern "C" fn rutabaga_resource_unref(ptr: &mut rutabaga, resource_id: u32) -> i32 {
     catch_unwind(AssertUnwindSafe(|| {
@@ -624,7 +694,7 @@ pub extern "C" fn rutabaga_resource_export_blob(
     catch_unwind(AssertUnwindSafe(|| {
         let result = ptr.export_blob(resource_id);
         let hnd = return_on_error!(result);
-        let hnd = return_on_error!(RutabagaMesaHandle::try_from(hnd));
+        let hnd = return_on_error!(RutabagaMesaHandle::try_from(hnd).map_err(RutabagaError::from));
 
         handle.handle_type = hnd.handle_type;
         handle.os_handle = hnd.os_handle.into_raw_descriptor() as i64;
@@ -672,6 +742,42 @@ pub extern "C" fn rutabaga_resource_map_info(
     .unwrap_or(-ESRCH)
 }
 
+#[no_mangle]
+pub extern "C" fn rutabaga_resource_uuid(
+    ptr: &mut rutabaga,
+    resource_id: u32,
+    uuid: &mut [u8; 16],
+) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let result = ptr.resource_uuid(resource_id);
+        *uuid = return_on_error!(result);
+        NO_ERROR
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+/// # Safety
+/// - `name` must be null or a null-terminated C-string.
+#[no_mangle]
+pub unsafe extern "C" fn rutabaga_resource_set_name(
+    ptr: &mut rutabaga,
+    resource_id: u32,
+    name: *const c_char,
+) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let name = if name.is_null() {
+            None
+        } else {
+            let result = CStr::from_ptr(name).to_str();
+            Some(return_on_error!(result))
+        };
+
+        let result = ptr.set_resource_name(resource_id, name);
+        return_result(result)
+    }))
+    .unwrap_or(-ESRCH)
+}
+
 /// # Safety
 /// - `commands` must point to a contiguous memory region of `size` bytes.
 #[no_mangle]
@@ -737,3 +843,76 @@ pub unsafe extern "C" fn rutabaga_restore(ptr: &mut rutabaga, dir: *const c_char
     }))
     .unwrap_or(-ESRCH)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use rutabaga_gfx::RutabagaErrorCode::*;
+
+    use super::*;
+
+    // Returns the message of the calling thread's last error, read through a `len` byte buffer.
+    fn last_error(len: usize) -> (i32, String) {
+        let mut message = vec![0x7f as c_char; len];
+        // SAFETY: `message` holds `len` bytes.
+        let errno = unsafe { rutabaga_last_error(message.as_mut_ptr(), len) };
+        // SAFETY: rutabaga_last_error() null-terminates the message within `len` bytes.
+        let message = unsafe { CStr::from_ptr(message.as_ptr()) };
+        (errno, message.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn error_codes_match_header() {
+        let defines: BTreeMap<&str, i32> = include_str!("include/rutabaga_gfx_ffi.h")
+            .lines()
+            .filter_map(|line| line.strip_prefix("#define RUTABAGA_ERROR_"))
+            .filter_map(|define| define.split_once(' '))
+            .map(|(name, value)| (name, value.trim().parse().unwrap()))
+            .collect();
+
+        let codes = [
+            ("PERMISSION_DENIED", PermissionDenied as i32, libc::EPERM),
+            ("NOT_FOUND", NotFound as i32, libc::ENOENT),
+            ("PANICKED", ESRCH, libc::ESRCH),
+            ("IO", Io as i32, libc::EIO),
+            ("OUT_OF_MEMORY", OutOfMemory as i32, libc::ENOMEM),
+            ("BUSY", Busy as i32, libc::EBUSY),
+            ("CONTEXT_LOST", ContextLost as i32, libc::ENODEV),
+            ("INVALID_ARGUMENT", InvalidArgument as i32, libc::EINVAL),
+            ("LIMIT_EXCEEDED", LimitExceeded as i32, libc::ENOSPC),
+            ("UNSUPPORTED", Unsupported as i32, libc::EOPNOTSUPP),
+            ("TIMED_OUT", TimedOut as i32, libc::ETIMEDOUT),
+        ];
+        assert_eq!(defines.len(), codes.len());
+        for (name, code, errno) in codes {
+            assert_eq!(defines[name], code, "RUTABAGA_ERROR_{name}");
+            #[cfg(target_os = "linux")]
+            assert_eq!(code, errno, "RUTABAGA_ERROR_{name}");
+            #[cfg(not(target_os = "linux"))]
+            let _ = errno;
+        }
+    }
+
+    #[test]
+    fn last_error_is_per_thread() {
+        assert_eq!(last_error(16), (NO_ERROR, String::new()));
+
+        let errno = return_result::<()>(Err(RutabagaError::InvalidResourceId));
+        assert_eq!(errno, -(NotFound as i32));
+        let message = RutabagaError::InvalidResourceId.to_string();
+        assert_eq!(last_error(256), (errno, message.clone()));
+
+        // Messages are truncated to the buffer, and the error is still returned without one.
+        assert_eq!(last_error(4), (errno, message[..3].to_string()));
+        // SAFETY: A null message isn't written to.
+        assert_eq!(unsafe { rutabaga_last_error(null_mut(), 0) }, errno);
+
+        let other_thread = std::thread::spawn(|| last_error(16)).join().unwrap();
+        assert_eq!(other_thread, (NO_ERROR, String::new()));
+
+        // Successes leave the last error alone.
+        assert_eq!(return_result(Ok(())), NO_ERROR);
+        assert_eq!(last_error(256), (errno, message));
+    }
+}
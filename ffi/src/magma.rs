@@ -0,0 +1,1220 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! C bindings for the mesa3d_magma crate, so C/C++ user-mode drivers can use the Rust magma
+//! implementation.
+//!
+//! Objects are referred to by integer handles rather than pointers.  Handles are looked up in a
+//! process-wide table, which makes every function thread-safe and turns use of a destroyed handle
+//! into an error rather than undefined behavior.
+
+use std::collections::BTreeMap as Map;
+use std::os::raw::c_void;
+use std::panic::catch_unwind;
+use std::panic::AssertUnwindSafe;
+use std::ptr::copy_nonoverlapping;
+use std::slice::from_raw_parts;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use libc::EACCES;
+use libc::EINVAL;
+use libc::ESRCH;
+use libc::ETIMEDOUT;
+use mesa3d_magma::magma_enumerate_devices as enumerate_devices;
+use mesa3d_magma::MagmaBuffer;
+use mesa3d_magma::MagmaBufferPool;
+use mesa3d_magma::MagmaBufferPoolConfig;
+use mesa3d_magma::MagmaBufferPoolStats;
+use mesa3d_magma::MagmaContext;
+use mesa3d_magma::MagmaContextPriority;
+use mesa3d_magma::MagmaCreateBufferInfo;
+use mesa3d_magma::MagmaDevice;
+use mesa3d_magma::MagmaEngineClass;
+use mesa3d_magma::MagmaError;
+use mesa3d_magma::MagmaHeapBudget;
+use mesa3d_magma::MagmaImportHandleInfo;
+use mesa3d_magma::MagmaMappedMemoryRange;
+use mesa3d_magma::MagmaMemoryProperties;
+use mesa3d_magma::MagmaPciBusInfo;
+use mesa3d_magma::MagmaPciInfo;
+use mesa3d_magma::MagmaPhysicalDevice;
+use mesa3d_magma::MagmaQueryResult;
+use mesa3d_magma::MagmaResult;
+use mesa3d_magma::MagmaSemaphore;
+use mesa3d_magma::MagmaSubAllocation;
+use rutabaga_gfx::RutabagaDescriptor;
+use rutabaga_gfx::RutabagaFromRawDescriptor;
+use rutabaga_gfx::RutabagaIntoRawDescriptor;
+use rutabaga_gfx::RutabagaMappedRegion;
+use rutabaga_gfx::RutabagaMesaHandle;
+use rutabaga_gfx::RutabagaRawDescriptor;
+
+use crate::rutabaga_handle;
+use crate::rutabaga_mapping;
+use crate::set_last_error;
+use crate::NO_ERROR;
+
+/// The major version is bumped for incompatible changes to this API, and the minor version when
+/// functions are added.  Structures are never changed within a major version.
+pub const MAGMA_FFI_VERSION_MAJOR: u32 = 1;
+pub const MAGMA_FFI_VERSION_MINOR: u32 = 3;
+
+/// The version of the binary interface described by this header: the layout of its structures and
+/// the signatures of its functions.  Bumped whenever either changes, even compatibly.  Drivers
+/// should compare it to `magma_get_abi_version` at runtime, with `magma_check_abi_version`, rather
+/// than trust that the library they load was built from the same sources as the header.
+pub const MAGMA_FFI_ABI_VERSION: u32 = 1;
+
+/// Objects are referred to by handles, which are never reused within a process.  Every function
+/// is thread-safe and handles may be used from any thread.  Passing a destroyed handle, or a handle
+/// of the wrong type, returns -EINVAL.  Destroying an object while another thread is using it is
+/// safe: the object is freed once the last such call returns.
+pub const MAGMA_NULL_HANDLE: magma_handle_t = 0;
+
+#[allow(non_camel_case_types)]
+pub type magma_handle_t = u64;
+
+#[allow(non_camel_case_types)]
+pub type magma_physical_device_t = magma_handle_t;
+
+#[allow(non_camel_case_types)]
+pub type magma_device_t = magma_handle_t;
+
+#[allow(non_camel_case_types)]
+pub type magma_context_t = magma_handle_t;
+
+#[allow(non_camel_case_types)]
+pub type magma_buffer_t = magma_handle_t;
+
+#[allow(non_camel_case_types)]
+pub type magma_buffer_pool_t = magma_handle_t;
+
+#[allow(non_camel_case_types)]
+pub type magma_semaphore_t = magma_handle_t;
+
+#[allow(non_camel_case_types)]
+type magma_pci_info = MagmaPciInfo;
+
+#[allow(non_camel_case_types)]
+type magma_pci_bus_info = MagmaPciBusInfo;
+
+#[allow(non_camel_case_types)]
+type magma_memory_properties = MagmaMemoryProperties;
+
+#[allow(non_camel_case_types)]
+type magma_heap_budget = MagmaHeapBudget;
+
+#[allow(non_camel_case_types)]
+type magma_create_buffer_info = MagmaCreateBufferInfo;
+
+#[allow(non_camel_case_types)]
+type magma_mapped_memory_range = MagmaMappedMemoryRange;
+
+#[allow(non_camel_case_types)]
+type magma_buffer_pool_stats = MagmaBufferPoolStats;
+
+/// Engine classes
+pub const MAGMA_ENGINE_CLASS_RENDER: u32 = 0;
+pub const MAGMA_ENGINE_CLASS_COPY: u32 = 1;
+pub const MAGMA_ENGINE_CLASS_COMPUTE: u32 = 2;
+pub const MAGMA_ENGINE_CLASS_VIDEO: u32 = 3;
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+pub struct magma_engine_info {
+    engine_class: u32,
+    count: u32,
+}
+
+/// A buffer, along with its CPU mapping while mapped.  Buffers allocated from a pool keep their
+/// range of the pool's block until destroyed.
+struct MagmaFfiBuffer {
+    buffer: MagmaBuffer,
+    mapping: Mutex<Option<Arc<dyn RutabagaMappedRegion>>>,
+    sub_allocation: Option<MagmaSubAllocation>,
+}
+
+#[derive(Clone)]
+enum MagmaObject {
+    PhysicalDevice(MagmaPhysicalDevice),
+    Device(MagmaDevice),
+    Context(MagmaContext),
+    Buffer(Arc<MagmaFfiBuffer>),
+    BufferPool(MagmaBufferPool),
+    Semaphore(Arc<MagmaSemaphore>),
+}
+
+// Handles are never reused, so a stale handle can't refer to a newer object.  Objects are cloned
+// out of the table, so a call racing with the destruction of its object keeps the object alive
+// until the call returns.
+struct MagmaObjects {
+    next_handle: magma_handle_t,
+    table: Map<magma_handle_t, MagmaObject>,
+}
+
+static MAGMA_OBJECTS: Mutex<MagmaObjects> = Mutex::new(MagmaObjects {
+    next_handle: MAGMA_NULL_HANDLE + 1,
+    table: Map::new(),
+});
+
+fn add_object(object: MagmaObject) -> magma_handle_t {
+    let mut objects = MAGMA_OBJECTS.lock().unwrap();
+    let handle = objects.next_handle;
+    objects.next_handle += 1;
+    objects.table.insert(handle, object);
+    handle
+}
+
+fn get_object(handle: magma_handle_t) -> MagmaResult<MagmaObject> {
+    MAGMA_OBJECTS
+        .lock()
+        .unwrap()
+        .table
+        .get(&handle)
+        .cloned()
+        .ok_or(MagmaError::InvalidArgs)
+}
+
+fn remove_object(handle: magma_handle_t, is_type: fn(&MagmaObject) -> bool) -> MagmaResult<()> {
+    let mut objects = MAGMA_OBJECTS.lock().unwrap();
+    match objects.table.get(&handle) {
+        Some(object) if is_type(object) => {
+            objects.table.remove(&handle);
+            Ok(())
+        }
+        _ => Err(MagmaError::InvalidArgs),
+    }
+}
+
+fn get_physical_device(handle: magma_handle_t) -> MagmaResult<MagmaPhysicalDevice> {
+    match get_object(handle)? {
+        MagmaObject::PhysicalDevice(physical_device) => Ok(physical_device),
+        _ => Err(MagmaError::InvalidArgs),
+    }
+}
+
+fn get_device(handle: magma_handle_t) -> MagmaResult<MagmaDevice> {
+    match get_object(handle)? {
+        MagmaObject::Device(device) => Ok(device),
+        _ => Err(MagmaError::InvalidArgs),
+    }
+}
+
+fn get_context(handle: magma_handle_t) -> MagmaResult<MagmaContext> {
+    match get_object(handle)? {
+        MagmaObject::Context(context) => Ok(context),
+        _ => Err(MagmaError::InvalidArgs),
+    }
+}
+
+fn get_buffer(handle: magma_handle_t) -> MagmaResult<Arc<MagmaFfiBuffer>> {
+    match get_object(handle)? {
+        MagmaObject::Buffer(buffer) => Ok(buffer),
+        _ => Err(MagmaError::InvalidArgs),
+    }
+}
+
+fn get_buffer_pool(handle: magma_handle_t) -> MagmaResult<MagmaBufferPool> {
+    match get_object(handle)? {
+        MagmaObject::BufferPool(pool) => Ok(pool),
+        _ => Err(MagmaError::InvalidArgs),
+    }
+}
+
+fn get_semaphore(handle: magma_handle_t) -> MagmaResult<Arc<MagmaSemaphore>> {
+    match get_object(handle)? {
+        MagmaObject::Semaphore(semaphore) => Ok(semaphore),
+        _ => Err(MagmaError::InvalidArgs),
+    }
+}
+
+fn add_buffer(buffer: MagmaBuffer) -> magma_handle_t {
+    add_object(MagmaObject::Buffer(Arc::new(MagmaFfiBuffer {
+        buffer,
+        mapping: Mutex::new(None),
+        sub_allocation: None,
+    })))
+}
+
+fn to_errno(e: MagmaError) -> i32 {
+    let errno = match e {
+        MagmaError::AccessDenied => -EACCES,
+        MagmaError::TimedOut => -ETIMEDOUT,
+        _ => -EINVAL,
+    };
+
+    set_last_error(errno, e.to_string())
+}
+
+fn return_result<T>(result: MagmaResult<T>) -> i32 {
+    match result {
+        Ok(_) => NO_ERROR,
+        Err(e) => to_errno(e),
+    }
+}
+
+macro_rules! return_on_magma_error {
+    ($result:expr) => {
+        match $result {
+            Ok(t) => t,
+            Err(e) => return to_errno(e),
+        }
+    };
+}
+
+/// Returns the version of the API implemented by the library, which may be newer than the one this
+/// header describes.
+#[no_mangle]
+pub extern "C" fn magma_get_version(major: &mut u32, minor: &mut u32) -> i32 {
+    *major = MAGMA_FFI_VERSION_MAJOR;
+    *minor = MAGMA_FFI_VERSION_MINOR;
+    NO_ERROR
+}
+
+/// Returns MAGMA_FFI_ABI_VERSION of the library, which must match that of this header.  Unlike
+/// `magma_get_version`, this can't fail and takes no pointers, so it's safe to call before
+/// anything else is known about the library.
+#[no_mangle]
+pub extern "C" fn magma_get_abi_version() -> u32 {
+    MAGMA_FFI_ABI_VERSION
+}
+
+/// If `devices` is null, `*num_devices` is set to the number of devices.  Otherwise, up to
+/// `*num_devices` devices are written and `*num_devices` is set to the number written.  Every
+/// returned physical device must be destroyed with `magma_physical_device_destroy`.
+///
+/// # Safety
+/// - If `devices` is not null, it must point to an array of at least `*num_devices` elements.
+#[no_mangle]
+pub unsafe extern "C" fn magma_enumerate_devices(
+    devices: *mut magma_physical_device_t,
+    num_devices: &mut u32,
+) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let physical_devices = return_on_magma_error!(enumerate_devices());
+        if devices.is_null() {
+            *num_devices = physical_devices.len() as u32;
+            return NO_ERROR;
+        }
+
+        let count = physical_devices.len().min(*num_devices as usize);
+        for (i, physical_device) in physical_devices.into_iter().take(count).enumerate() {
+            *devices.add(i) = add_object(MagmaObject::PhysicalDevice(physical_device));
+        }
+
+        *num_devices = count as u32;
+        NO_ERROR
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+#[no_mangle]
+pub extern "C" fn magma_physical_device_get_pci_info(
+    physical_device: magma_physical_device_t,
+    pci_info: &mut magma_pci_info,
+    pci_bus_info: &mut magma_pci_bus_info,
+) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let physical_device = return_on_magma_error!(get_physical_device(physical_device));
+        *pci_info = physical_device.pci_info().clone();
+        *pci_bus_info = physical_device.pci_bus_info().clone();
+        NO_ERROR
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+#[no_mangle]
+pub extern "C" fn magma_physical_device_destroy(physical_device: magma_physical_device_t) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let result = remove_object(physical_device, |o| {
+            matches!(o, MagmaObject::PhysicalDevice(_))
+        });
+        return_result(result)
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+#[no_mangle]
+pub extern "C" fn magma_device_create(
+    physical_device: magma_physical_device_t,
+    device: &mut magma_device_t,
+) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let physical_device = return_on_magma_error!(get_physical_device(physical_device));
+        let dev = return_on_magma_error!(physical_device.create_device());
+        *device = add_object(MagmaObject::Device(dev));
+        NO_ERROR
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+#[no_mangle]
+pub extern "C" fn magma_device_destroy(device: magma_device_t) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let result = remove_object(device, |o| matches!(o, MagmaObject::Device(_)));
+        return_result(result)
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+#[no_mangle]
+pub extern "C" fn magma_device_get_memory_properties(
+    device: magma_device_t,
+    memory_properties: &mut magma_memory_properties,
+) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let device = return_on_magma_error!(get_device(device));
+        *memory_properties = return_on_magma_error!(device.get_memory_properties());
+        NO_ERROR
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+#[no_mangle]
+pub extern "C" fn magma_device_get_memory_budget(
+    device: magma_device_t,
+    heap_idx: u32,
+    budget: &mut magma_heap_budget,
+) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let device = return_on_magma_error!(get_device(device));
+        *budget = return_on_magma_error!(device.get_memory_budget(heap_idx));
+        NO_ERROR
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+/// Makes the vendor-specific query `id`: an AMDGPU_INFO_*, I915_PARAM_*, DRM_XE_DEVICE_QUERY_*,
+/// MSM_PARAM_* or VIRTGPU_PARAM_* value on Linux, depending on the driver, and a
+/// KMTQUERYADAPTERINFOTYPE on Windows.  `input` holds the query's arguments, if any.
+///
+/// Scalar results are written to `*value`, and `*buffer_size` is set to zero.  Other results are
+/// written to `buffer`, truncated to `*buffer_size` bytes, and `*buffer_size` is set to their full
+/// size.  `buffer` may be null to only get the size.  Queries the driver doesn't expose fail with
+/// -EINVAL.  Available since minor version 1.
+///
+/// # Safety
+/// - If `input_size` is not zero, `input` must point to `input_size` readable bytes.
+/// - If `buffer` is not null, it must point to `*buffer_size` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn magma_device_query(
+    device: magma_device_t,
+    id: u64,
+    input: *const c_void,
+    input_size: usize,
+    value: &mut u64,
+    buffer: *mut c_void,
+    buffer_size: &mut usize,
+) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let input_slice = match (input_size, input.is_null()) {
+            (0, _) => &[],
+            (_, true) => return -EINVAL,
+            _ => from_raw_parts(input as *const u8, input_size),
+        };
+
+        let device = return_on_magma_error!(get_device(device));
+        let result = return_on_magma_error!(device.query(id, input_slice));
+        write_query_result(result, value, buffer, buffer_size);
+        NO_ERROR
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+// Writes `result` as magma_device_query() describes.
+//
+// # Safety
+// If `buffer` is not null, it must point to `*buffer_size` writable bytes.
+unsafe fn write_query_result(
+    result: MagmaQueryResult,
+    value: &mut u64,
+    buffer: *mut c_void,
+    buffer_size: &mut usize,
+) {
+    match result {
+        MagmaQueryResult::Value(v) => {
+            *value = v;
+            *buffer_size = 0;
+        }
+        MagmaQueryResult::Buffer(data) => {
+            let len = data.len().min(*buffer_size);
+            if !buffer.is_null() && len != 0 {
+                copy_nonoverlapping(data.as_ptr(), buffer as *mut u8, len);
+            }
+            *buffer_size = data.len();
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn magma_device_create_buffer(
+    device: magma_device_t,
+    create_info: &magma_create_buffer_info,
+    buffer: &mut magma_buffer_t,
+) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let device = return_on_magma_error!(get_device(device));
+        let buf = return_on_magma_error!(device.create_buffer(create_info));
+        *buffer = add_buffer(buf);
+        NO_ERROR
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+/// Only dma-bufs (NT handles on Windows) are accepted.  Other handle types, which could carry a
+/// legacy GEM flink name, fail with -EACCES.
+///
+/// # Safety
+/// - `handle` must be a valid OS-descriptor.  Ownership is transferred to magma.
+#[no_mangle]
+pub unsafe extern "C" fn magma_device_import_buffer(
+    device: magma_device_t,
+    handle: &rutabaga_handle,
+    size: u64,
+    memory_type_idx: u32,
+    buffer: &mut magma_buffer_t,
+) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let info = MagmaImportHandleInfo {
+            handle: RutabagaMesaHandle {
+                os_handle: RutabagaDescriptor::from_raw_descriptor(
+                    handle.os_handle as RutabagaRawDescriptor,
+                ),
+                handle_type: handle.handle_type,
+            },
+            size,
+            memory_type_idx,
+        };
+
+        let device = return_on_magma_error!(get_device(device));
+        let buf = return_on_magma_error!(device.import(info));
+        *buffer = add_buffer(buf);
+        NO_ERROR
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+/// Any CPU mapping of the buffer is unmapped.
+#[no_mangle]
+pub extern "C" fn magma_buffer_destroy(buffer: magma_buffer_t) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let result = remove_object(buffer, |o| matches!(o, MagmaObject::Buffer(_)));
+        return_result(result)
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+/// The mapping stays valid until `magma_buffer_unmap` or `magma_buffer_destroy`.
+#[no_mangle]
+pub extern "C" fn magma_buffer_map(buffer: magma_buffer_t, mapping: &mut rutabaga_mapping) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let buffer = return_on_magma_error!(get_buffer(buffer));
+        let mut buffer_mapping = buffer.mapping.lock().unwrap();
+        if buffer_mapping.is_none() {
+            *buffer_mapping = Some(return_on_magma_error!(buffer.buffer.map()));
+        }
+
+        if let Some(ref region) = *buffer_mapping {
+            mapping.ptr = region.as_ptr() as *mut c_void;
+            mapping.size = region.size() as u64;
+        }
+
+        NO_ERROR
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+#[no_mangle]
+pub extern "C" fn magma_buffer_unmap(buffer: magma_buffer_t) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let buffer = return_on_magma_error!(get_buffer(buffer));
+        if buffer.mapping.lock().unwrap().take().is_none() {
+            return -EINVAL;
+        }
+
+        NO_ERROR
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+/// # Safety
+/// Caller owns raw descriptor on success and is responsible for closing it.
+#[no_mangle]
+pub extern "C" fn magma_buffer_export(buffer: magma_buffer_t, handle: &mut rutabaga_handle) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let buffer = return_on_magma_error!(get_buffer(buffer));
+        // The block of a pooled buffer holds other allocations too.
+        if buffer.sub_allocation.is_some() {
+            return to_errno(MagmaError::AccessDenied);
+        }
+
+        let hnd = return_on_magma_error!(buffer.buffer.export());
+
+        handle.handle_type = hnd.handle_type;
+        handle.os_handle = hnd.os_handle.into_raw_descriptor() as i64;
+        NO_ERROR
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+/// Creates a pool that sub-allocates buffers from driver buffers (blocks) of `block_size` bytes, or
+/// of a default size if zero.  Each memory type has its own blocks.  Available since minor version
+/// 3.
+#[no_mangle]
+pub extern "C" fn magma_device_create_buffer_pool(
+    device: magma_device_t,
+    block_size: u64,
+    pool: &mut magma_buffer_pool_t,
+) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let device = return_on_magma_error!(get_device(device));
+        let mut config: MagmaBufferPoolConfig = Default::default();
+        if block_size != 0 {
+            config.block_size = block_size;
+        }
+
+        *pool = add_object(MagmaObject::BufferPool(MagmaBufferPool::new(
+            &device, config,
+        )));
+        NO_ERROR
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+/// Buffers allocated from the pool stay valid, and their blocks are freed with the last of them.
+#[no_mangle]
+pub extern "C" fn magma_buffer_pool_destroy(pool: magma_buffer_pool_t) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let result = remove_object(pool, |o| matches!(o, MagmaObject::BufferPool(_)));
+        return_result(result)
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+/// Allocates `create_info->size` bytes at `*offset` of a block, aligned to
+/// `create_info->alignment`.  Buffer flags must be zero.  The returned buffer refers to the whole
+/// block: mappings must be offset by `*offset`, and exporting it fails with -EACCES.  Destroying it
+/// returns the range to the pool.  Available since minor version 3.
+#[no_mangle]
+pub extern "C" fn magma_buffer_pool_allocate(
+    pool: magma_buffer_pool_t,
+    create_info: &magma_create_buffer_info,
+    buffer: &mut magma_buffer_t,
+    offset: &mut u64,
+) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let pool = return_on_magma_error!(get_buffer_pool(pool));
+        let sub_allocation = return_on_magma_error!(pool.allocate(create_info));
+        *offset = sub_allocation.offset();
+        *buffer = add_object(MagmaObject::Buffer(Arc::new(MagmaFfiBuffer {
+            buffer: sub_allocation.buffer().clone(),
+            mapping: Mutex::new(None),
+            sub_allocation: Some(sub_allocation),
+        })));
+        NO_ERROR
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+/// Available since minor version 3.
+#[no_mangle]
+pub extern "C" fn magma_buffer_pool_get_stats(
+    pool: magma_buffer_pool_t,
+    stats: &mut magma_buffer_pool_stats,
+) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let pool = return_on_magma_error!(get_buffer_pool(pool));
+        *stats = pool.stats();
+        NO_ERROR
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+/// `sync_flags` must contain exactly one of MAGMA_SYNC_WHOLE_RANGE and MAGMA_SYNC_RANGES, and with
+/// the latter every range must lie within the buffer, or -EINVAL is returned.  Flushing makes CPU
+/// writes visible to later GPU work, and invalidating makes completed GPU writes visible to the
+/// CPU.  Both are cheap on HOST_COHERENT memory types but must still be called around accesses.
+///
+/// # Safety
+/// - If `num_ranges` is not zero, `ranges` must point to an array of `num_ranges` elements.
+#[no_mangle]
+pub unsafe extern "C" fn magma_buffer_flush(
+    buffer: magma_buffer_t,
+    sync_flags: u64,
+    ranges: *const magma_mapped_memory_range,
+    num_ranges: u32,
+) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let ranges_slice = if num_ranges != 0 {
+            from_raw_parts(ranges, num_ranges as usize)
+        } else {
+            &[]
+        };
+
+        let buffer = return_on_magma_error!(get_buffer(buffer));
+        let result = buffer.buffer.flush(sync_flags, ranges_slice);
+        return_result(result)
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+/// # Safety
+/// - If `num_ranges` is not zero, `ranges` must point to an array of `num_ranges` elements.
+#[no_mangle]
+pub unsafe extern "C" fn magma_buffer_invalidate(
+    buffer: magma_buffer_t,
+    sync_flags: u64,
+    ranges: *const magma_mapped_memory_range,
+    num_ranges: u32,
+) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let ranges_slice = if num_ranges != 0 {
+            from_raw_parts(ranges, num_ranges as usize)
+        } else {
+            &[]
+        };
+
+        let buffer = return_on_magma_error!(get_buffer(buffer));
+        let result = buffer.buffer.invalidate(sync_flags, ranges_slice);
+        return_result(result)
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+/// Creates a context on the render engine.
+#[no_mangle]
+pub extern "C" fn magma_device_create_context(
+    device: magma_device_t,
+    context: &mut magma_context_t,
+) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let device = return_on_magma_error!(get_device(device));
+        let ctx = return_on_magma_error!(
+            device.create_context(MagmaContextPriority::Medium, MagmaEngineClass::Render)
+        );
+        *context = add_object(MagmaObject::Context(ctx));
+        NO_ERROR
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+/// If `engines` is null, `*num_engines` is set to the number of engine classes of the device.
+/// Otherwise, up to `*num_engines` classes are written and `*num_engines` is set to the number
+/// written.  Available since minor version 2.
+///
+/// # Safety
+/// - If `engines` is not null, it must point to an array of at least `*num_engines` elements.
+#[no_mangle]
+pub unsafe extern "C" fn magma_device_enumerate_engines(
+    device: magma_device_t,
+    engines: *mut magma_engine_info,
+    num_engines: &mut u32,
+) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let device = return_on_magma_error!(get_device(device));
+        let engine_infos = return_on_magma_error!(device.enumerate_engines());
+        if engines.is_null() {
+            *num_engines = engine_infos.len() as u32;
+            return NO_ERROR;
+        }
+
+        let count = engine_infos.len().min(*num_engines as usize);
+        for (i, info) in engine_infos.into_iter().take(count).enumerate() {
+            let engine_class = match info.engine_class {
+                MagmaEngineClass::Render => MAGMA_ENGINE_CLASS_RENDER,
+                MagmaEngineClass::Copy => MAGMA_ENGINE_CLASS_COPY,
+                MagmaEngineClass::Compute => MAGMA_ENGINE_CLASS_COMPUTE,
+                MagmaEngineClass::Video => MAGMA_ENGINE_CLASS_VIDEO,
+            };
+
+            *engines.add(i) = magma_engine_info {
+                engine_class,
+                count: info.count,
+            };
+        }
+
+        *num_engines = count as u32;
+        NO_ERROR
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+/// Creates a context on an engine of `engine_class`, a MAGMA_ENGINE_CLASS_* value.  Fails with
+/// -EINVAL if the device has no such engine.  Available since minor version 2.
+#[no_mangle]
+pub extern "C" fn magma_device_create_context_on_engine(
+    device: magma_device_t,
+    engine_class: u32,
+    context: &mut magma_context_t,
+) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let engine_class = match engine_class {
+            MAGMA_ENGINE_CLASS_RENDER => MagmaEngineClass::Render,
+            MAGMA_ENGINE_CLASS_COPY => MagmaEngineClass::Copy,
+            MAGMA_ENGINE_CLASS_COMPUTE => MagmaEngineClass::Compute,
+            MAGMA_ENGINE_CLASS_VIDEO => MagmaEngineClass::Video,
+            _ => return to_errno(MagmaError::InvalidArgs),
+        };
+
+        let device = return_on_magma_error!(get_device(device));
+        let ctx = return_on_magma_error!(
+            device.create_context(MagmaContextPriority::Medium, engine_class)
+        );
+        *context = add_object(MagmaObject::Context(ctx));
+        NO_ERROR
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+#[no_mangle]
+pub extern "C" fn magma_context_destroy(context: magma_context_t) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let result = remove_object(context, |o| matches!(o, MagmaObject::Context(_)));
+        return_result(result)
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+#[no_mangle]
+pub extern "C" fn magma_context_map_gpu(
+    context: magma_context_t,
+    buffer: magma_buffer_t,
+    gpu_va: u64,
+    flags: u64,
+) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let context = return_on_magma_error!(get_context(context));
+        let buffer = return_on_magma_error!(get_buffer(buffer));
+        let result = context.map_gpu(&buffer.buffer, gpu_va, flags);
+        return_result(result)
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+#[no_mangle]
+pub extern "C" fn magma_context_unmap_gpu(
+    context: magma_context_t,
+    buffer: magma_buffer_t,
+    gpu_va: u64,
+) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let context = return_on_magma_error!(get_context(context));
+        let buffer = return_on_magma_error!(get_buffer(buffer));
+        let result = context.unmap_gpu(&buffer.buffer, gpu_va);
+        return_result(result)
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+/// Semaphores are binary, and a successful wait resets them.
+#[no_mangle]
+pub extern "C" fn magma_device_create_semaphore(
+    device: magma_device_t,
+    semaphore: &mut magma_semaphore_t,
+) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let device = return_on_magma_error!(get_device(device));
+        let sem = return_on_magma_error!(device.create_semaphore());
+        *semaphore = add_object(MagmaObject::Semaphore(Arc::new(sem)));
+        NO_ERROR
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+/// # Safety
+/// - `handle` must be a valid OS-descriptor.  Ownership is transferred to magma.
+#[no_mangle]
+pub unsafe extern "C" fn magma_device_import_semaphore(
+    device: magma_device_t,
+    handle: &rutabaga_handle,
+    semaphore: &mut magma_semaphore_t,
+) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let hnd = RutabagaMesaHandle {
+            os_handle: RutabagaDescriptor::from_raw_descriptor(
+                handle.os_handle as RutabagaRawDescriptor,
+            ),
+            handle_type: handle.handle_type,
+        };
+
+        let device = return_on_magma_error!(get_device(device));
+        let sem = return_on_magma_error!(device.import_semaphore(hnd));
+        *semaphore = add_object(MagmaObject::Semaphore(Arc::new(sem)));
+        NO_ERROR
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+#[no_mangle]
+pub extern "C" fn magma_semaphore_destroy(semaphore: magma_semaphore_t) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let result = remove_object(semaphore, |o| matches!(o, MagmaObject::Semaphore(_)));
+        return_result(result)
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+#[no_mangle]
+pub extern "C" fn magma_semaphore_signal(semaphore: magma_semaphore_t) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let semaphore = return_on_magma_error!(get_semaphore(semaphore));
+        let result = semaphore.signal();
+        return_result(result)
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+/// Returns -ETIMEDOUT if the semaphore isn't signaled within `timeout_ns`.
+#[no_mangle]
+pub extern "C" fn magma_semaphore_wait(semaphore: magma_semaphore_t, timeout_ns: u64) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let semaphore = return_on_magma_error!(get_semaphore(semaphore));
+        let result = semaphore.wait(Duration::from_nanos(timeout_ns));
+        return_result(result)
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+/// # Safety
+/// Caller owns raw descriptor on success and is responsible for closing it.
+#[no_mangle]
+pub extern "C" fn magma_semaphore_export(
+    semaphore: magma_semaphore_t,
+    handle: &mut rutabaga_handle,
+) -> i32 {
+    catch_unwind(AssertUnwindSafe(|| {
+        let semaphore = return_on_magma_error!(get_semaphore(semaphore));
+        let hnd = return_on_magma_error!(semaphore.export());
+
+        handle.handle_type = hnd.handle_type;
+        handle.os_handle = hnd.os_handle.into_raw_descriptor() as i64;
+        NO_ERROR
+    }))
+    .unwrap_or(-ESRCH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_results() {
+        let mut value = 0;
+        let mut buffer = [0u8; 4];
+        let mut buffer_size = buffer.len();
+
+        // SAFETY: `buffer` holds `buffer_size` bytes.
+        unsafe {
+            write_query_result(
+                MagmaQueryResult::Value(7),
+                &mut value,
+                buffer.as_mut_ptr() as *mut c_void,
+                &mut buffer_size,
+            )
+        };
+        assert_eq!((value, buffer_size), (7, 0));
+
+        // Results are truncated to the buffer, which gets their full size.
+        buffer_size = 2;
+        // SAFETY: `buffer` holds more than `buffer_size` bytes.
+        unsafe {
+            write_query_result(
+                MagmaQueryResult::Buffer(vec![1, 2, 3]),
+                &mut value,
+                buffer.as_mut_ptr() as *mut c_void,
+                &mut buffer_size,
+            )
+        };
+        assert_eq!(buffer, [1, 2, 0, 0]);
+        assert_eq!(buffer_size, 3);
+
+        // A null buffer only gets the size, whatever size it claims.
+        buffer_size = 16;
+        // SAFETY: `buffer` is null.
+        unsafe {
+            write_query_result(
+                MagmaQueryResult::Buffer(vec![1, 2, 3, 4, 5]),
+                &mut value,
+                std::ptr::null_mut(),
+                &mut buffer_size,
+            )
+        };
+        assert_eq!(buffer_size, 5);
+    }
+}
+
+#[cfg(test)]
+mod magma_header {
+    use std::collections::BTreeMap;
+    use std::collections::BTreeSet;
+    use std::mem::align_of;
+    use std::mem::size_of;
+
+    use mesa3d_magma::*;
+
+    use super::*;
+
+    const HEADER: &str = include_str!("include/rutabaga_gfx_ffi_magma.h");
+    const SOURCE: &str = include_str!("magma.rs");
+
+    // The header's #defines, with line continuations joined.
+    fn defines() -> BTreeMap<String, String> {
+        HEADER
+            .replace("\\\n", " ")
+            .lines()
+            .filter_map(|line| line.strip_prefix("#define MAGMA_"))
+            .filter_map(|define| define.split_once(' '))
+            .map(|(name, value)| (format!("MAGMA_{name}"), value.trim().to_string()))
+            .collect()
+    }
+
+    // Evaluates the integer expressions the header uses: literals, shifts, ors and other defines.
+    fn evaluate(expr: &str, defines: &BTreeMap<String, String>) -> u64 {
+        let expr = expr.trim();
+        if let Some(inner) = expr.strip_prefix('(').and_then(|e| e.strip_suffix(')')) {
+            return evaluate(inner, defines);
+        }
+        if expr.contains('|') {
+            return expr
+                .split('|')
+                .map(|e| evaluate(e, defines))
+                .fold(0, |a, b| a | b);
+        }
+        if let Some((value, shift)) = expr.split_once("<<") {
+            return evaluate(value, defines) << evaluate(shift, defines);
+        }
+        if let Some(hex) = expr.strip_prefix("0x") {
+            return u64::from_str_radix(hex, 16).unwrap();
+        }
+        match defines.get(expr) {
+            Some(value) => evaluate(value, defines),
+            None => expr
+                .parse()
+                .unwrap_or_else(|_| panic!("can't evaluate {expr}")),
+        }
+    }
+
+    #[test]
+    fn constants_match() {
+        let expected: BTreeMap<&str, u64> = [
+            ("MAGMA_FFI_VERSION_MAJOR", MAGMA_FFI_VERSION_MAJOR as u64),
+            ("MAGMA_FFI_VERSION_MINOR", MAGMA_FFI_VERSION_MINOR as u64),
+            ("MAGMA_FFI_ABI_VERSION", MAGMA_FFI_ABI_VERSION as u64),
+            ("MAGMA_NULL_HANDLE", MAGMA_NULL_HANDLE),
+            (
+                "MAGMA_ENGINE_CLASS_RENDER",
+                MAGMA_ENGINE_CLASS_RENDER as u64,
+            ),
+            ("MAGMA_ENGINE_CLASS_COPY", MAGMA_ENGINE_CLASS_COPY as u64),
+            (
+                "MAGMA_ENGINE_CLASS_COMPUTE",
+                MAGMA_ENGINE_CLASS_COMPUTE as u64,
+            ),
+            ("MAGMA_ENGINE_CLASS_VIDEO", MAGMA_ENGINE_CLASS_VIDEO as u64),
+            ("MAGMA_HEAP_DEVICE_LOCAL_BIT", MAGMA_HEAP_DEVICE_LOCAL_BIT),
+            ("MAGMA_HEAP_CPU_VISIBLE_BIT", MAGMA_HEAP_CPU_VISIBLE_BIT),
+            (
+                "MAGMA_HEAP_PRESERVED_ON_SUSPEND_BIT",
+                MAGMA_HEAP_PRESERVED_ON_SUSPEND_BIT,
+            ),
+            (
+                "MAGMA_MEMORY_PROPERTY_DEVICE_LOCAL_BIT",
+                MAGMA_MEMORY_PROPERTY_DEVICE_LOCAL_BIT as u64,
+            ),
+            (
+                "MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT",
+                MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT as u64,
+            ),
+            (
+                "MAGMA_MEMORY_PROPERTY_HOST_COHERENT_BIT",
+                MAGMA_MEMORY_PROPERTY_HOST_COHERENT_BIT as u64,
+            ),
+            (
+                "MAGMA_MEMORY_PROPERTY_HOST_CACHED_BIT",
+                MAGMA_MEMORY_PROPERTY_HOST_CACHED_BIT as u64,
+            ),
+            (
+                "MAGMA_MEMORY_PROPERTY_LAZILY_ALLOCATED_BIT",
+                MAGMA_MEMORY_PROPERTY_LAZILY_ALLOCATED_BIT as u64,
+            ),
+            (
+                "MAGMA_MEMORY_PROPERTY_PROTECTED_BIT",
+                MAGMA_MEMORY_PROPERTY_PROTECTED_BIT as u64,
+            ),
+            ("MAGMA_MAX_MEMORY_TYPES", MAGMA_MAX_MEMORY_TYPES as u64),
+            ("MAGMA_MAX_MEMORY_HEAPS", MAGMA_MAX_MEMORY_HEAPS as u64),
+            (
+                "MAGMA_BUFFER_FLAG_EXTERNAL",
+                MAGMA_BUFFER_FLAG_EXTERNAL as u64,
+            ),
+            (
+                "MAGMA_BUFFER_FLAG_SCANOUT",
+                MAGMA_BUFFER_FLAG_SCANOUT as u64,
+            ),
+            ("MAGMA_BUFFER_FLAG_SPARSE", MAGMA_BUFFER_FLAG_SPARSE as u64),
+            ("MAGMA_BUFFER_FLAG_AMD_OA", MAGMA_BUFFER_FLAG_AMD_OA as u64),
+            (
+                "MAGMA_BUFFER_FLAG_AMD_GDS",
+                MAGMA_BUFFER_FLAG_AMD_GDS as u64,
+            ),
+            ("MAGMA_MAP_GPU_FLAG_READ", MAGMA_MAP_GPU_FLAG_READ),
+            ("MAGMA_MAP_GPU_FLAG_WRITE", MAGMA_MAP_GPU_FLAG_WRITE),
+            ("MAGMA_MAP_GPU_FLAG_EXECUTE", MAGMA_MAP_GPU_FLAG_EXECUTE),
+            ("MAGMA_MAP_GPU_FLAGS", MAGMA_MAP_GPU_FLAGS),
+            ("MAGMA_USER_FENCE_SIZE", MAGMA_USER_FENCE_SIZE),
+            ("MAGMA_SYNC_WHOLE_RANGE", MAGMA_SYNC_WHOLE_RANGE),
+            ("MAGMA_SYNC_RANGES", MAGMA_SYNC_RANGES),
+            ("MAGMA_SYNC_INVALIDATE_READ", MAGMA_SYNC_INVALIDATE_READ),
+            ("MAGMA_SYNC_INVALIDATE_WRITE", MAGMA_SYNC_INVALIDATE_WRITE),
+            ("MAGMA_VENDOR_ID_INTEL", MAGMA_VENDOR_ID_INTEL as u64),
+            ("MAGMA_VENDOR_ID_AMD", MAGMA_VENDOR_ID_AMD as u64),
+            ("MAGMA_VENDOR_ID_MALI", MAGMA_VENDOR_ID_MALI as u64),
+            ("MAGMA_VENDOR_ID_QCOM", MAGMA_VENDOR_ID_QCOM as u64),
+            ("MAGMA_VENDOR_ID_VIRTIO", MAGMA_VENDOR_ID_VIRTIO as u64),
+            (
+                "MAGMA_PCI_DOMAIN_PLATFORM",
+                MAGMA_PCI_DOMAIN_PLATFORM as u64,
+            ),
+        ]
+        .into_iter()
+        .collect();
+
+        let defines = defines();
+        let header: BTreeMap<&str, u64> = defines
+            .iter()
+            .map(|(name, value)| (name.as_str(), evaluate(value, &defines)))
+            .collect();
+        assert_eq!(header, expected);
+    }
+
+    #[test]
+    fn functions_match() {
+        let exported: BTreeSet<&str> = SOURCE
+            .split("extern \"C\" fn ")
+            .skip(1)
+            .filter_map(|rest| rest.split('(').next())
+            .collect();
+
+        // Declarations start with the return type at the start of a line.
+        let declared: BTreeSet<&str> = HEADER
+            .lines()
+            .filter(|line| line.starts_with("int32_t ") || line.starts_with("uint32_t "))
+            .filter_map(|line| line.split_once(' '))
+            .filter_map(|(_, rest)| rest.split('(').next())
+            .collect();
+
+        assert_eq!(declared, exported);
+    }
+
+    // The size and alignment of a C field type, including arrays of it.
+    fn c_layout(
+        field_type: &str,
+        length: &str,
+        structs: &BTreeMap<String, (usize, usize)>,
+    ) -> (usize, usize) {
+        let (size, align) = match field_type {
+            "uint8_t" => (1, 1),
+            "uint16_t" => (2, 2),
+            "uint32_t" => (4, 4),
+            "uint64_t" => (8, 8),
+            _ => structs[field_type.trim_start_matches("struct ")],
+        };
+        let count = match length {
+            "" => 1,
+            length => evaluate(length, &defines()) as usize,
+        };
+        (size * count, align)
+    }
+
+    // The size and alignment of each structure the header defines, laid out as a C compiler
+    // would.
+    fn c_structs() -> BTreeMap<String, (usize, usize)> {
+        let mut structs = BTreeMap::new();
+        for body in HEADER.split("\nstruct ").skip(1) {
+            let Some((name, rest)) = body.split_once(" {\n") else {
+                continue;
+            };
+            let fields = rest.split("\n};").next().unwrap();
+
+            let (mut size, mut align) = (0usize, 1);
+            for field in fields.lines().map(str::trim) {
+                let Some(field) = field.strip_suffix(';') else {
+                    continue;
+                };
+                let (field_type, declarator) = field.rsplit_once(' ').unwrap();
+                let length = declarator
+                    .split_once('[')
+                    .map_or("", |(_, length)| length.trim_end_matches(']'));
+                let (field_size, field_align) = c_layout(field_type, length, &structs);
+                size = size.next_multiple_of(field_align) + field_size;
+                align = align.max(field_align);
+            }
+            structs.insert(name.to_string(), (size.next_multiple_of(align), align));
+        }
+        structs
+    }
+
+    #[test]
+    fn structs_match() {
+        let expected: BTreeMap<String, (usize, usize)> = [
+            (
+                "magma_pci_info",
+                size_of::<MagmaPciInfo>(),
+                align_of::<MagmaPciInfo>(),
+            ),
+            (
+                "magma_pci_bus_info",
+                size_of::<MagmaPciBusInfo>(),
+                align_of::<MagmaPciBusInfo>(),
+            ),
+            (
+                "magma_memory_type",
+                size_of::<MagmaMemoryType>(),
+                align_of::<MagmaMemoryType>(),
+            ),
+            (
+                "magma_heap",
+                size_of::<MagmaHeap>(),
+                align_of::<MagmaHeap>(),
+            ),
+            (
+                "magma_memory_properties",
+                size_of::<MagmaMemoryProperties>(),
+                align_of::<MagmaMemoryProperties>(),
+            ),
+            (
+                "magma_heap_budget",
+                size_of::<MagmaHeapBudget>(),
+                align_of::<MagmaHeapBudget>(),
+            ),
+            (
+                "magma_create_buffer_info",
+                size_of::<MagmaCreateBufferInfo>(),
+                align_of::<MagmaCreateBufferInfo>(),
+            ),
+            (
+                "magma_buffer_pool_stats",
+                size_of::<MagmaBufferPoolStats>(),
+                align_of::<MagmaBufferPoolStats>(),
+            ),
+            (
+                "magma_mapped_memory_range",
+                size_of::<MagmaMappedMemoryRange>(),
+                align_of::<MagmaMappedMemoryRange>(),
+            ),
+            (
+                "magma_engine_info",
+                size_of::<magma_engine_info>(),
+                align_of::<magma_engine_info>(),
+            ),
+        ]
+        .into_iter()
+        .map(|(name, size, align)| (name.to_string(), (size, align)))
+        .collect();
+
+        assert_eq!(c_structs(), expected);
+    }
+}
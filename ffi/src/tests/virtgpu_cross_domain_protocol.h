@@ -15,14 +15,44 @@
 #define CROSS_DOMAIN_CMD_RECEIVE 5
 #define CROSS_DOMAIN_CMD_READ 6
 #define CROSS_DOMAIN_CMD_WRITE 7
+#define CROSS_DOMAIN_CMD_GET_IMAGE_REQUIREMENTS_WITH_MODIFIERS 8
+#define CROSS_DOMAIN_CMD_SET_STAGING 9
+#define CROSS_DOMAIN_CMD_OPEN_CHANNEL 10
+#define CROSS_DOMAIN_CMD_WORKER_RESTART 11
+#define CROSS_DOMAIN_CMD_ERROR 12
+#define CROSS_DOMAIN_CMD_QUERY_MODIFIERS 13
+#define CROSS_DOMAIN_CMD_READ_ACK 14
+#define CROSS_DOMAIN_CMD_DEBUG_MARKER 15
+#define CROSS_DOMAIN_CMD_SET_READ_BATCHING 16
+#define CROSS_DOMAIN_CMD_SET_RESOURCE_NAME 17
 
-// Channel types (must match rutabaga channel types)
+// Channel types.  Also the bit positions of supported_channels.
 #define CROSS_DOMAIN_CHANNEL_TYPE_WAYLAND 0x0001
 #define CROSS_DOMAIN_CHANNEL_TYPE_CAMERA 0x0002
+#define CROSS_DOMAIN_CHANNEL_TYPE_X11 0x0003
+#define CROSS_DOMAIN_CHANNEL_TYPE_PULSE 0x0004
+
+// Debug marker types.  See CrossDomainDebugMarker.
+#define CROSS_DOMAIN_DEBUG_MARKER_BEGIN 0x0001
+#define CROSS_DOMAIN_DEBUG_MARKER_END 0x0002
+#define CROSS_DOMAIN_DEBUG_MARKER_INSERT 0x0003
+
+// The maximum size of a debug marker label
+#define CROSS_DOMAIN_MAX_DEBUG_LABEL 256
+
+// The maximum size of a resource name
+#define CROSS_DOMAIN_MAX_RESOURCE_NAME 64
 
 // The maximum number of identifiers (value based on wp_linux_dmabuf)
 #define CROSS_DOMAIN_MAX_IDENTIFIERS 4
 
+// The maximum number of DRM format modifiers proposed per image
+#define CROSS_DOMAIN_MAX_MODIFIERS 16
+
+// The maximum number of DRM format modifiers returned by
+// CROSS_DOMAIN_CMD_QUERY_MODIFIERS
+#define CROSS_DOMAIN_MAX_QUERY_MODIFIERS 32
+
 // virtgpu memory resource ID.  Also works with non-blob memory resources,
 // despite the name.
 #define CROSS_DOMAIN_ID_TYPE_VIRTGPU_BLOB 1
@@ -50,11 +80,41 @@
 // Read pipe IDs start at this value.
 #define CROSS_DOMAIN_PIPE_READ_START 0x80000000
 
+// The channel opened by CROSS_DOMAIN_CMD_INIT.
+#define CROSS_DOMAIN_CHANNEL_DEFAULT 0
+
+// Unregisters the staging blob.
+#define CROSS_DOMAIN_STAGING_NONE 0
+
+// The command isn't set in CrossDomainCapabilities::supported_commands.
+#define CROSS_DOMAIN_ERROR_UNSUPPORTED_COMMAND 1
+
+// The host ran out of descriptors receiving a channel message.  Dropping the message would break
+// the channel's stream, so the channel is closed instead, and appears to hang up.
+#define CROSS_DOMAIN_ERROR_DESCRIPTOR_LIMIT 2
+
+// The command is malformed.
+#define CROSS_DOMAIN_ERROR_INVALID_COMMAND 3
+
+// The host failed to run the command.
+#define CROSS_DOMAIN_ERROR_HOST_FAILURE 4
+
+// The host stopped polling channels and pipes after repeated errors.
+#define CROSS_DOMAIN_ERROR_WORKER_HALTED 5
+
 struct CrossDomainCapabilities {
     uint32_t version;
     uint32_t supported_channels;
     uint32_t supports_dmabuf;
     uint32_t supports_external_gpu_memory;
+    uint32_t supports_blob_resize;
+    uint32_t supported_commands;
+    uint32_t strict;
+    uint32_t supports_protected;
+    uint32_t supports_context_priority;
+    uint32_t supports_compute_only;
+    uint32_t pipe_high_water;
+    uint32_t export_handle_types;
 };
 
 struct CrossDomainImageRequirements {
@@ -66,13 +126,16 @@ struct CrossDomainImageRequirements {
     uint32_t map_info;
     int32_t memory_idx;
     int32_t physical_device_idx;
+    // Only written for guests of version 19 or later.
+    uint8_t device_uuid[16];
+    uint8_t driver_uuid[16];
 };
 
 struct CrossDomainHeader {
     uint8_t cmd;
     uint8_t fence_ctx_idx;
     uint16_t cmd_size;
-    uint32_t pad;
+    uint32_t channel_id;
 };
 
 struct CrossDomainInit {
@@ -80,6 +143,13 @@ struct CrossDomainInit {
     uint32_t query_ring_id;
     uint32_t channel_ring_id;
     uint32_t channel_type;
+    uint32_t import_handle_types;
+    uint32_t version;
+};
+
+struct CrossDomainInitResponse {
+    uint32_t handle_types;
+    uint32_t pad;
 };
 
 struct CrossDomainGetImageRequirements {
@@ -90,6 +160,17 @@ struct CrossDomainGetImageRequirements {
     uint32_t flags;
 };
 
+struct CrossDomainGetImageRequirementsWithModifiers {
+    struct CrossDomainHeader hdr;
+    uint32_t width;
+    uint32_t height;
+    uint32_t drm_format;
+    uint32_t flags;
+    uint32_t num_modifiers;
+    uint32_t pad;
+    uint64_t modifiers[CROSS_DOMAIN_MAX_MODIFIERS];
+};
+
 struct CrossDomainPoll {
     struct CrossDomainHeader hdr;
     uint64_t pad;
@@ -112,4 +193,75 @@ struct CrossDomainReadWrite {
     uint32_t pad;
 };
 
+struct CrossDomainOpenChannel {
+    struct CrossDomainHeader hdr;
+    uint32_t channel_id;
+    uint32_t channel_ring_id;
+    uint32_t channel_type;
+    uint32_t pad;
+};
+
+struct CrossDomainCommandError {
+    uint32_t seqno;
+    uint32_t cmd;
+    uint32_t error;
+    uint32_t pad;
+};
+
+struct CrossDomainWorkerRestart {
+    struct CrossDomainHeader hdr;
+    uint32_t restarts;
+    uint32_t pad;
+};
+
+struct CrossDomainError {
+    struct CrossDomainHeader hdr;
+    uint32_t cmd;
+    uint32_t error;
+};
+
+struct CrossDomainSetStaging {
+    struct CrossDomainHeader hdr;
+    uint32_t staging_id;
+    uint32_t pad;
+};
+
+struct CrossDomainReadAck {
+    struct CrossDomainHeader hdr;
+    uint32_t identifier;
+    uint32_t bytes;
+};
+
+struct CrossDomainSetReadBatching {
+    struct CrossDomainHeader hdr;
+    uint32_t enable;
+    uint32_t pad;
+};
+
+// Followed by a label of label_size bytes.
+struct CrossDomainDebugMarker {
+    struct CrossDomainHeader hdr;
+    uint32_t marker_type;
+    uint32_t label_size;
+};
+
+// Followed by a name of name_size bytes.
+struct CrossDomainSetResourceName {
+    struct CrossDomainHeader hdr;
+    uint32_t resource_id;
+    uint32_t name_size;
+};
+
+struct CrossDomainQueryModifiers {
+    struct CrossDomainHeader hdr;
+    uint32_t drm_format;
+    uint32_t flags;
+};
+
+struct CrossDomainModifiers {
+    uint32_t drm_format;
+    uint32_t num_modifiers;
+    uint64_t modifiers[CROSS_DOMAIN_MAX_QUERY_MODIFIERS];
+};
+
 #endif
@@ -0,0 +1,14 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Decodes guest cross-domain command buffers.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rutabaga_gfx::parse_cross_domain_commands;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_cross_domain_commands(data);
+});
@@ -0,0 +1,17 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Decodes kumquat GPU protocol messages, as received from a guest or a server.
+
+#![no_main]
+
+use std::collections::VecDeque;
+
+use libfuzzer_sys::fuzz_target;
+use mesa3d_protocols::ipc::KumquatStream;
+
+fuzz_target!(|data: &[u8]| {
+    // Messages that carry descriptors fail to decode, since none are received.
+    let _ = KumquatStream::decode(data, &mut VecDeque::new());
+});
@@ -4,11 +4,21 @@
 
 use std::collections::btree_map::Entry;
 use std::collections::BTreeMap as Map;
+#[cfg(unix)]
+use std::env;
 use std::path::PathBuf;
+#[cfg(unix)]
+use std::process;
+use std::time::Duration;
+use std::time::Instant;
 
 use mesa3d_util::AsBorrowedDescriptor;
+#[cfg(unix)]
+use mesa3d_util::FromRawDescriptor;
 use mesa3d_util::Listener;
 use mesa3d_util::MesaError;
+#[cfg(unix)]
+use mesa3d_util::OwnedDescriptor;
 use mesa3d_util::WaitContext;
 use mesa3d_util::WaitTimeout;
 
@@ -16,31 +26,141 @@ use crate::kumquat_gpu::KumquatGpu;
 use crate::kumquat_gpu::KumquatGpuConnection;
 use crate::kumquat_gpu::KumquatGpuResult;
 
+// The first descriptor passed by systemd socket activation.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+// The name systemd reports for sockets without one.
+#[cfg(unix)]
+const LISTEN_FDNAME_UNKNOWN: &str = "unknown";
+
+/// The environment variables describing the sockets passed by systemd socket activation.  They
+/// should be removed once `inherited_listeners` returns, before any thread or child process is
+/// started, so children don't take the sockets as theirs.
+pub const LISTEN_ENV_VARS: [&str; 3] = ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"];
+
+/// A listening socket passed by systemd socket activation.
+pub struct InheritedListener {
+    /// The FileDescriptorName= of the socket, which names the GPU serving it.
+    pub name: String,
+    pub listener: Listener,
+}
+
+/// Returns the listening sockets passed by systemd socket activation, if any.  They must be Unix
+/// sockets of type SOCK_SEQPACKET, set with ListenSequentialPacket= in the socket unit, as kumquat
+/// clients connect with that type.  The environment is left unchanged, see `LISTEN_ENV_VARS`.
+#[cfg(unix)]
+pub fn inherited_listeners() -> KumquatGpuResult<Vec<InheritedListener>> {
+    let var = |name| env::var(name).ok();
+    let fds = listen_fds(
+        var("LISTEN_PID").as_deref(),
+        var("LISTEN_FDS").as_deref(),
+        var("LISTEN_FDNAMES").as_deref(),
+        process::id(),
+    )?;
+
+    fds.into_iter()
+        .map(|(fd, name)| {
+            // SAFETY:
+            // systemd passes LISTEN_FDS descriptors starting at LISTEN_FDS_START, which nothing
+            // else in this process owns.
+            let socket = unsafe { OwnedDescriptor::from_raw_descriptor(fd) };
+            Ok(InheritedListener {
+                name,
+                listener: Listener::from_descriptor(socket)?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn inherited_listeners() -> KumquatGpuResult<Vec<InheritedListener>> {
+    Ok(Vec::new())
+}
+
+// Returns the descriptors and names of the sockets the socket activation variables pass to the
+// process `pid`.
+#[cfg(unix)]
+fn listen_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    listen_fdnames: Option<&str>,
+    pid: u32,
+) -> KumquatGpuResult<Vec<(i32, String)>> {
+    // The sockets were meant for another process if the pid doesn't match.
+    let (Some(listen_pid), Some(listen_fds)) = (listen_pid, listen_fds) else {
+        return Ok(Vec::new());
+    };
+    if listen_pid.parse::<u32>().ok() != Some(pid) {
+        return Ok(Vec::new());
+    }
+
+    let count: u16 = listen_fds
+        .parse()
+        .map_err(|_| MesaError::WithContext("invalid LISTEN_FDS"))?;
+    let names: Vec<&str> = listen_fdnames.map_or(Vec::new(), |names| names.split(':').collect());
+
+    Ok((0..count)
+        .map(|index| {
+            let name = names
+                .get(index as usize)
+                .copied()
+                .unwrap_or(LISTEN_FDNAME_UNKNOWN);
+            (LISTEN_FDS_START + index as i32, name.to_string())
+        })
+        .collect())
+}
+
 enum KumquatConnection {
-    GpuListener,
-    GpuConnection(Box<KumquatGpuConnection>),
+    GpuListener(usize),
+    // A connection and the index of the GPU serving it.
+    GpuConnection(Box<KumquatGpuConnection>, usize),
+}
+
+// A listening socket and the index of the GPU serving it.
+struct KumquatListener {
+    listener: Listener,
+    gpu: usize,
 }
 
 pub struct Kumquat {
     connection_id: u64,
     wait_ctx: WaitContext,
-    kumquat_gpu_opt: Option<KumquatGpu>,
-    gpu_listener_opt: Option<Listener>,
+    gpus: Vec<KumquatGpu>,
+    gpu_listeners: Vec<KumquatListener>,
     connections: Map<u64, KumquatConnection>,
+    idle_timeout: Option<Duration>,
+    // When the last GPU connection closed, or None if there are GPU connections.
+    idle_since: Option<Instant>,
 }
 
 impl Kumquat {
+    /// Returns true if the server had no GPU connections for the idle timeout, and should exit.
+    pub fn idle_expired(&self) -> bool {
+        match (self.idle_timeout, self.idle_since) {
+            (Some(timeout), Some(idle_since)) => idle_since.elapsed() >= timeout,
+            _ => false,
+        }
+    }
+
     pub fn run(&mut self) -> KumquatGpuResult<()> {
-        let events = self.wait_ctx.wait(WaitTimeout::NoTimeout)?;
+        let timeout = match (self.idle_timeout, self.idle_since) {
+            (Some(timeout), Some(idle_since)) => {
+                WaitTimeout::Finite(timeout.saturating_sub(idle_since.elapsed()))
+            }
+            _ => WaitTimeout::NoTimeout,
+        };
+
+        let events = self.wait_ctx.wait(timeout)?;
         for event in events {
             let mut hung_up = false;
             match self.connections.entry(event.connection_id) {
                 Entry::Occupied(mut o) => {
                     let connection = o.get_mut();
                     match connection {
-                        KumquatConnection::GpuListener => {
-                            if let Some(ref listener) = self.gpu_listener_opt {
-                                let stream = listener.accept()?;
+                        KumquatConnection::GpuListener(index) => {
+                            if let Some(gpu_listener) = self.gpu_listeners.get(*index) {
+                                let stream = gpu_listener.listener.accept()?;
                                 self.connection_id += 1;
                                 let new_gpu_conn = KumquatGpuConnection::new(stream);
                                 self.wait_ctx.add(
@@ -49,13 +169,17 @@ impl Kumquat {
                                 )?;
                                 self.connections.insert(
                                     self.connection_id,
-                                    KumquatConnection::GpuConnection(Box::new(new_gpu_conn)),
+                                    KumquatConnection::GpuConnection(
+                                        Box::new(new_gpu_conn),
+                                        gpu_listener.gpu,
+                                    ),
                                 );
+                                self.idle_since = None;
                             }
                         }
-                        KumquatConnection::GpuConnection(ref mut gpu_conn) => {
+                        KumquatConnection::GpuConnection(ref mut gpu_conn, gpu) => {
                             if event.readable {
-                                if let Some(ref mut kumquat_gpu) = self.kumquat_gpu_opt {
+                                if let Some(kumquat_gpu) = self.gpus.get_mut(*gpu) {
                                     hung_up =
                                         !gpu_conn.process_command(kumquat_gpu)? && event.hung_up;
                                 }
@@ -74,22 +198,36 @@ impl Kumquat {
             }
         }
 
+        let connected = self
+            .connections
+            .values()
+            .any(|c| matches!(c, KumquatConnection::GpuConnection(..)));
+        if connected {
+            self.idle_since = None;
+        } else if self.idle_since.is_none() {
+            self.idle_since = Some(Instant::now());
+        }
+
         Ok(())
     }
 }
 
 pub struct KumquatBuilder {
     capset_names_opt: Option<String>,
-    gpu_socket_opt: Option<String>,
+    gpu_sockets: Vec<String>,
+    gpu_listeners: Vec<InheritedListener>,
     renderer_features_opt: Option<String>,
+    idle_timeout: Option<Duration>,
 }
 
 impl KumquatBuilder {
     pub fn new() -> KumquatBuilder {
         KumquatBuilder {
             capset_names_opt: None,
-            gpu_socket_opt: None,
+            gpu_sockets: Vec::new(),
+            gpu_listeners: Vec::new(),
             renderer_features_opt: None,
+            idle_timeout: None,
         }
     }
 
@@ -98,8 +236,26 @@ impl KumquatBuilder {
         self
     }
 
-    pub fn set_gpu_socket(mut self, gpu_socket_opt: Option<String>) -> KumquatBuilder {
-        self.gpu_socket_opt = gpu_socket_opt;
+    /// Sets the paths of the sockets to bind and listen on.  Each socket is served by its own GPU,
+    /// with its own resources and contexts.  Renderers are global to the process, so only one GPU
+    /// can use the capsets of a 3D renderer.
+    pub fn set_gpu_sockets(mut self, gpu_sockets: Vec<String>) -> KumquatBuilder {
+        self.gpu_sockets = gpu_sockets;
+        self
+    }
+
+    /// Sets sockets that are already listening, such as ones from `inherited_listeners()`.  They
+    /// are served in addition to the sockets set with `set_gpu_sockets`, and sockets of the same
+    /// name are served by the same GPU.
+    pub fn set_gpu_listeners(mut self, gpu_listeners: Vec<InheritedListener>) -> KumquatBuilder {
+        self.gpu_listeners = gpu_listeners;
+        self
+    }
+
+    /// Sets how long the server may go without GPU connections before `Kumquat::idle_expired`
+    /// returns true.  The server never expires if unset.
+    pub fn set_idle_timeout(mut self, idle_timeout: Option<Duration>) -> KumquatBuilder {
+        self.idle_timeout = idle_timeout;
         self
     }
 
@@ -109,36 +265,127 @@ impl KumquatBuilder {
     }
 
     pub fn build(self) -> KumquatGpuResult<Kumquat> {
-        let connection_id: u64 = 0;
+        let mut connection_id: u64 = 0;
         let mut wait_ctx = WaitContext::new()?;
-        let mut kumquat_gpu_opt: Option<KumquatGpu> = None;
-        let mut gpu_listener_opt: Option<Listener> = None;
         let mut connections: Map<u64, KumquatConnection> = Default::default();
 
-        if let Some(gpu_socket) = self.gpu_socket_opt {
+        // Each listener and the name of the GPU serving it.  Bound sockets are named by their
+        // paths, which are distinct.
+        let mut named_listeners: Vec<(String, Listener)> = self
+            .gpu_listeners
+            .into_iter()
+            .map(|inherited| (inherited.name, inherited.listener))
+            .collect();
+        for gpu_socket in self.gpu_sockets {
             // Remove path if it exists
             let path = PathBuf::from(&gpu_socket);
             let _ = std::fs::remove_file(&path);
 
-            // Should not panic, since main.rs always calls set_capset_names and
-            // set_renderer_features, even with the empty string.
-            kumquat_gpu_opt = Some(KumquatGpu::new(
-                self.capset_names_opt.unwrap(),
-                self.renderer_features_opt.unwrap(),
-            )?);
-
-            let gpu_listener = Listener::bind(path)?;
-            wait_ctx.add(connection_id, gpu_listener.as_borrowed_descriptor())?;
-            connections.insert(connection_id, KumquatConnection::GpuListener);
-            gpu_listener_opt = Some(gpu_listener);
+            named_listeners.push((gpu_socket, Listener::bind(path)?));
+        }
+
+        let mut gpu_names: Vec<String> = Vec::new();
+        let mut gpu_listeners: Vec<KumquatListener> = Vec::new();
+        for (name, listener) in named_listeners {
+            let gpu = match gpu_names.iter().position(|gpu_name| *gpu_name == name) {
+                Some(gpu) => gpu,
+                None => {
+                    gpu_names.push(name);
+                    gpu_names.len() - 1
+                }
+            };
+            gpu_listeners.push(KumquatListener { listener, gpu });
+        }
+
+        // Should not panic, since main.rs always calls set_capset_names and set_renderer_features,
+        // even with the empty string.
+        let gpus = gpu_names
+            .iter()
+            .map(|_| {
+                KumquatGpu::new(
+                    self.capset_names_opt.clone().unwrap(),
+                    self.renderer_features_opt.clone().unwrap(),
+                )
+            })
+            .collect::<KumquatGpuResult<Vec<KumquatGpu>>>()?;
+
+        for (index, gpu_listener) in gpu_listeners.iter().enumerate() {
+            connection_id += 1;
+            wait_ctx.add(
+                connection_id,
+                gpu_listener.listener.as_borrowed_descriptor(),
+            )?;
+            connections.insert(connection_id, KumquatConnection::GpuListener(index));
         }
 
         Ok(Kumquat {
             connection_id,
             wait_ctx,
-            kumquat_gpu_opt,
-            gpu_listener_opt,
+            gpus,
+            gpu_listeners,
             connections,
+            idle_timeout: self.idle_timeout,
+            idle_since: Some(Instant::now()),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn listen_fds_of_other_processes() {
+        assert!(listen_fds(None, None, None, 7).unwrap().is_empty());
+        assert!(listen_fds(Some("8"), Some("2"), None, 7)
+            .unwrap()
+            .is_empty());
+        assert!(listen_fds(Some("7"), Some("-1"), None, 7).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn listen_fds_names() {
+        assert_eq!(
+            listen_fds(Some("7"), Some("3"), Some("gpu0:gpu1"), 7).unwrap(),
+            vec![
+                (3, "gpu0".to_string()),
+                (4, "gpu1".to_string()),
+                (5, LISTEN_FDNAME_UNKNOWN.to_string()),
+            ]
+        );
+        assert_eq!(
+            listen_fds(Some("7"), Some("1"), None, 7).unwrap(),
+            vec![(3, LISTEN_FDNAME_UNKNOWN.to_string())]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn gpus_per_socket_name() {
+        let dir = std::env::temp_dir().join(format!("kumquat_listeners_{}", process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let inherited = |name: &str, file: &str| InheritedListener {
+            name: name.to_string(),
+            listener: Listener::bind(dir.join(file)).unwrap(),
+        };
+
+        let kumquat = KumquatBuilder::new()
+            .set_capset_names("cross-domain".to_string())
+            .set_renderer_features(String::new())
+            .set_gpu_listeners(vec![
+                inherited("gpu0", "a"),
+                inherited("gpu1", "b"),
+                inherited("gpu0", "c"),
+            ])
+            .set_gpu_sockets(vec![dir.join("d").to_str().unwrap().to_string()])
+            .build()
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(kumquat.gpus.len(), 3);
+        let gpus: Vec<usize> = kumquat.gpu_listeners.iter().map(|l| l.gpu).collect();
+        assert_eq!(gpus, vec![0, 1, 0, 2]);
+    }
+}
@@ -5,19 +5,27 @@
 use std::collections::btree_map::Entry;
 use std::collections::BTreeMap as Map;
 use std::collections::BTreeSet as Set;
+use std::env;
+use std::fs;
 use std::os::raw::c_void;
-use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::ptr::copy_nonoverlapping;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::sync::Mutex;
 
 use log::error;
 use mesa3d_protocols::ipc::KumquatStream;
 use mesa3d_protocols::protocols::kumquat_gpu_protocol::*;
+use mesa3d_util::seal_shared_memory;
 use mesa3d_util::AsBorrowedDescriptor;
 use mesa3d_util::Event;
 use mesa3d_util::MemoryMapping;
 use mesa3d_util::MesaError;
 use mesa3d_util::MesaHandle;
+use mesa3d_util::MesaResult;
 use mesa3d_util::OwnedDescriptor;
 use mesa3d_util::SharedMemory;
 use mesa3d_util::Tube;
@@ -37,12 +45,11 @@ use rutabaga_gfx::Transfer3D;
 use rutabaga_gfx::VulkanInfo as RutabagaVulkanInfo;
 use rutabaga_gfx::RUTABAGA_FLAG_FENCE;
 use rutabaga_gfx::RUTABAGA_FLAG_FENCE_HOST_SHAREABLE;
+use rutabaga_gfx::RUTABAGA_MAP_ACCESS_READ;
 use rutabaga_gfx::RUTABAGA_MAP_ACCESS_RW;
 use rutabaga_gfx::RUTABAGA_MAP_CACHE_CACHED;
 use thiserror::Error;
 
-const SNAPSHOT_DIR: &str = "/tmp/";
-
 #[sorted]
 #[non_exhaustive]
 #[derive(Error, Debug)]
@@ -76,6 +83,19 @@ pub struct KumquatGpuResource {
     mapping: Option<MemoryMapping>,
 }
 
+struct KumquatGpuResourceSnapshot {
+    attached_contexts: Set<u32>,
+    /// A copy of the shared memory backing the resource, sealed where the platform supports it,
+    /// and its size.
+    memory: Option<(OwnedDescriptor, usize)>,
+}
+
+/// Server state that rutabaga doesn't snapshot itself.
+struct KumquatGpuSnapshot {
+    id_allocator: u32,
+    resources: Map<u32, KumquatGpuResourceSnapshot>,
+}
+
 pub struct FenceData {
     pub pending_fences: Map<u64, Event>,
 }
@@ -98,11 +118,76 @@ pub fn create_fence_handler(fence_state: FenceState) -> RutabagaFenceHandler {
     })
 }
 
+// The GPUs created by this process, which snapshot to different directories.
+static GPU_COUNT: AtomicU32 = AtomicU32::new(0);
+
 pub struct KumquatGpu {
     rutabaga: Rutabaga,
     fence_state: FenceState,
     id_allocator: u32,
     resources: Map<u32, KumquatGpuResource>,
+    snapshot_dir: PathBuf,
+    snapshot: Option<KumquatGpuSnapshot>,
+}
+
+fn create_shared_mapping(size: usize) -> KumquatGpuResult<(OwnedDescriptor, MemoryMapping)> {
+    let descriptor: OwnedDescriptor = SharedMemory::new("rutabaga_server", size as u64)?.into();
+    let clone = descriptor.try_clone().map_err(MesaError::IoError)?;
+    let mapping = MemoryMapping::from_safe_descriptor(
+        clone,
+        size,
+        RUTABAGA_MAP_CACHE_CACHED | RUTABAGA_MAP_ACCESS_RW,
+    )?;
+
+    Ok((descriptor, mapping))
+}
+
+fn copy_mapping(src: &MemoryMapping, dst: &MemoryMapping) {
+    let src = src.as_mesa_mapping();
+    let dst = dst.as_mesa_mapping();
+    // SAFETY:
+    // Both mappings are live for the duration of the copy, are distinct, and are at least
+    // `size` bytes long.
+    unsafe {
+        copy_nonoverlapping(
+            src.ptr as *const u8,
+            dst.ptr as *mut u8,
+            src.size.min(dst.size) as usize,
+        );
+    }
+}
+
+// Copies `mapping` into new shared memory, sealed with `seal` so the snapshot can't change.
+// Nothing else holds the copy, so platforms without seals keep it unsealed.
+fn snapshot_memory(
+    mapping: &MemoryMapping,
+    seal: fn(&OwnedDescriptor) -> MesaResult<()>,
+) -> KumquatGpuResult<(OwnedDescriptor, usize)> {
+    let size = mapping.as_mesa_mapping().size as usize;
+    let (descriptor, copy) = create_shared_mapping(size)?;
+    copy_mapping(mapping, &copy);
+    // Sealing fails while the copy is still writable.
+    drop(copy);
+    match seal(&descriptor) {
+        Ok(()) | Err(MesaError::Unsupported) => Ok((descriptor, size)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Copies the snapshot of `size` bytes in `descriptor` back into `mapping`.
+fn restore_memory(
+    descriptor: &OwnedDescriptor,
+    size: usize,
+    mapping: &MemoryMapping,
+) -> KumquatGpuResult<()> {
+    let clone = descriptor.try_clone().map_err(MesaError::IoError)?;
+    let snapshot = MemoryMapping::from_safe_descriptor(
+        clone,
+        size,
+        RUTABAGA_MAP_CACHE_CACHED | RUTABAGA_MAP_ACCESS_READ,
+    )?;
+    copy_mapping(&snapshot, mapping);
+    Ok(())
 }
 
 impl KumquatGpu {
@@ -136,6 +221,12 @@ impl KumquatGpu {
             fence_state,
             id_allocator: 0,
             resources: Default::default(),
+            snapshot_dir: env::temp_dir().join(format!(
+                "kumquat_snapshot_{}_{}",
+                process::id(),
+                GPU_COUNT.fetch_add(1, Ordering::Relaxed)
+            )),
+            snapshot: None,
         })
     }
 
@@ -143,6 +234,91 @@ impl KumquatGpu {
         self.id_allocator += 1;
         self.id_allocator
     }
+
+    /// Snapshots rutabaga, along with copies of the shared memory backing resources, so a later
+    /// restore brings back their contents too.
+    pub fn snapshot(&mut self) -> KumquatGpuResult<()> {
+        fs::create_dir_all(&self.snapshot_dir).map_err(MesaError::IoError)?;
+        self.rutabaga.snapshot(&self.snapshot_dir)?;
+
+        let mut resources: Map<u32, KumquatGpuResourceSnapshot> = Default::default();
+        for (resource_id, resource) in &self.resources {
+            let memory = match resource.mapping {
+                Some(ref mapping) => Some(snapshot_memory(mapping, seal_shared_memory)?),
+                None => None,
+            };
+
+            resources.insert(
+                *resource_id,
+                KumquatGpuResourceSnapshot {
+                    attached_contexts: resource.attached_contexts.clone(),
+                    memory,
+                },
+            );
+        }
+
+        self.snapshot = Some(KumquatGpuSnapshot {
+            id_allocator: self.id_allocator,
+            resources,
+        });
+
+        Ok(())
+    }
+
+    /// Restores the last snapshot.  Resources that are still alive keep their shared memory, so
+    /// clients that mapped it see the restored contents.
+    pub fn restore(&mut self) -> KumquatGpuResult<()> {
+        let snapshot = self
+            .snapshot
+            .as_ref()
+            .ok_or(MesaError::WithContext("no snapshot to restore"))?;
+
+        self.rutabaga.restore(&self.snapshot_dir)?;
+
+        let mut resources: Map<u32, KumquatGpuResource> = Default::default();
+        for (resource_id, resource_snapshot) in &snapshot.resources {
+            let live_mapping = self
+                .resources
+                .remove(resource_id)
+                .and_then(|resource| resource.mapping);
+
+            let mapping = match resource_snapshot.memory {
+                Some((ref descriptor, size)) => {
+                    let mapping = match live_mapping {
+                        Some(mapping) if mapping.as_mesa_mapping().size as usize == size => mapping,
+                        _ => create_shared_mapping(size)?.1,
+                    };
+
+                    restore_memory(descriptor, size, &mapping)?;
+
+                    // Rutabaga recreates resources without backing.
+                    let rutabaga_mapping = mapping.as_mesa_mapping();
+                    self.rutabaga.attach_backing(
+                        *resource_id,
+                        vec![RutabagaIovec {
+                            base: rutabaga_mapping.ptr as *mut c_void,
+                            len: size,
+                        }],
+                    )?;
+                    Some(mapping)
+                }
+                None => None,
+            };
+
+            resources.insert(
+                *resource_id,
+                KumquatGpuResource {
+                    attached_contexts: resource_snapshot.attached_contexts.clone(),
+                    mapping,
+                },
+            );
+        }
+
+        // Resources created after the snapshot are gone, but clients may still hold their ids.
+        self.resources = resources;
+        self.id_allocator = self.id_allocator.max(snapshot.id_allocator);
+        Ok(())
+    }
 }
 
 impl KumquatGpuConnection {
@@ -261,17 +437,9 @@ impl KumquatGpuConnection {
                     };
 
                     let size = cmd.size as usize;
-                    let descriptor: OwnedDescriptor =
-                        SharedMemory::new("rutabaga_server", size as u64)?.into();
-
-                    let clone = descriptor.try_clone().map_err(MesaError::IoError)?;
+                    let (descriptor, mapping) = create_shared_mapping(size)?;
                     let mut vecs: Vec<RutabagaIovec> = Vec::new();
 
-                    let mapping = MemoryMapping::from_safe_descriptor(
-                        clone,
-                        size,
-                        RUTABAGA_MAP_CACHE_CACHED | RUTABAGA_MAP_ACCESS_RW,
-                    )?;
                     let rutabaga_mapping = mapping.as_mesa_mapping();
 
                     vecs.push(RutabagaIovec {
@@ -474,8 +642,46 @@ impl KumquatGpuConnection {
                         .rutabaga
                         .context_attach_resource(cmd.ctx_id, resource_id)?;
                 }
+                KumquatGpuProtocol::ResourceResizeBlob(cmd) => {
+                    // The resized blob is backed by fresh shared memory, so contents are not
+                    // preserved across the resize.
+                    let descriptor: OwnedDescriptor =
+                        SharedMemory::new("rutabaga_server", cmd.size)?.into();
+                    let clone = descriptor.try_clone().map_err(MesaError::IoError)?;
+
+                    kumquat_gpu.rutabaga.resource_resize_blob(
+                        cmd.resource_id,
+                        cmd.size,
+                        None,
+                        Some(
+                            MesaHandle {
+                                os_handle: clone,
+                                handle_type: MESA_HANDLE_TYPE_MEM_SHM,
+                            }
+                            .into(),
+                        ),
+                    )?;
+
+                    let resp = kumquat_gpu_protocol_resp_resource_create {
+                        hdr: kumquat_gpu_protocol_ctrl_hdr {
+                            type_: KUMQUAT_GPU_PROTOCOL_RESP_RESOURCE_CREATE,
+                            ..Default::default()
+                        },
+                        resource_id: cmd.resource_id,
+                        handle_type: MESA_HANDLE_TYPE_MEM_SHM,
+                        ..Default::default()
+                    };
+
+                    self.stream.write(KumquatGpuProtocolWrite::CmdWithHandle(
+                        resp,
+                        MesaHandle {
+                            os_handle: descriptor,
+                            handle_type: MESA_HANDLE_TYPE_MEM_SHM,
+                        },
+                    ))?;
+                }
                 KumquatGpuProtocol::SnapshotSave => {
-                    kumquat_gpu.rutabaga.snapshot(Path::new(SNAPSHOT_DIR))?;
+                    kumquat_gpu.snapshot()?;
 
                     let resp = kumquat_gpu_protocol_ctrl_hdr {
                         type_: KUMQUAT_GPU_PROTOCOL_RESP_OK_SNAPSHOT,
@@ -485,7 +691,7 @@ impl KumquatGpuConnection {
                     self.stream.write(KumquatGpuProtocolWrite::Cmd(resp))?;
                 }
                 KumquatGpuProtocol::SnapshotRestore => {
-                    kumquat_gpu.rutabaga.restore(Path::new(SNAPSHOT_DIR))?;
+                    kumquat_gpu.restore()?;
 
                     let resp = kumquat_gpu_protocol_ctrl_hdr {
                         type_: KUMQUAT_GPU_PROTOCOL_RESP_OK_SNAPSHOT,
@@ -513,3 +719,51 @@ impl AsBorrowedDescriptor for KumquatGpuConnection {
         self.stream.as_borrowed_descriptor()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill(mapping: &MemoryMapping, value: u8) {
+        let mapping = mapping.as_mesa_mapping();
+        // SAFETY:
+        // The mapping is writable and `size` bytes long.
+        unsafe { std::ptr::write_bytes(mapping.ptr as *mut u8, value, mapping.size as usize) };
+    }
+
+    fn contents(mapping: &MemoryMapping) -> Vec<u8> {
+        let mapping = mapping.as_mesa_mapping();
+        // SAFETY:
+        // The mapping is readable and `size` bytes long.
+        unsafe { std::slice::from_raw_parts(mapping.ptr as *const u8, mapping.size as usize) }
+            .to_vec()
+    }
+
+    #[test]
+    fn snapshot_restore_memory() {
+        let (_, mapping) = create_shared_mapping(4096).unwrap();
+        fill(&mapping, 0xab);
+
+        let (descriptor, size) = snapshot_memory(&mapping, seal_shared_memory).unwrap();
+        assert_eq!(size, 4096);
+        fill(&mapping, 0);
+
+        restore_memory(&descriptor, size, &mapping).unwrap();
+        assert!(contents(&mapping).iter().all(|byte| *byte == 0xab));
+    }
+
+    #[test]
+    fn snapshot_memory_without_seals() {
+        let (_, mapping) = create_shared_mapping(4096).unwrap();
+        fill(&mapping, 0xcd);
+
+        let (descriptor, size) =
+            snapshot_memory(&mapping, |_| Err(MesaError::Unsupported)).unwrap();
+        let (_, restored) = create_shared_mapping(size).unwrap();
+        restore_memory(&descriptor, size, &restored).unwrap();
+        assert!(contents(&restored).iter().all(|byte| *byte == 0xcd));
+
+        // Other failures still fail the snapshot.
+        assert!(snapshot_memory(&mapping, |_| Err(MesaError::WithContext("seal"))).is_err());
+    }
+}
@@ -5,8 +5,13 @@
 mod kumquat;
 mod kumquat_gpu;
 
+use std::env;
+use std::time::Duration;
+
 use clap::Parser;
+use kumquat::inherited_listeners;
 use kumquat::KumquatBuilder;
+use kumquat::LISTEN_ENV_VARS;
 use mesa3d_util::IntoRawDescriptor;
 use mesa3d_util::WritePipe;
 
@@ -20,9 +25,12 @@ struct Args {
     #[arg(long, default_value = "gfxstream-vulkan")]
     capset_names: String,
 
-    /// Path to the emulated virtio-gpu socket.
+    /// Path to the emulated virtio-gpu socket.  May be given multiple times to emulate several
+    /// GPUs, one per socket.  Ignored if listening sockets are passed by systemd socket
+    /// activation, which must be SOCK_SEQPACKET sockets (ListenSequentialPacket=).  Those are
+    /// grouped into GPUs by their FileDescriptorName=.
     #[arg(long, default_value = "/tmp/kumquat-gpu-0")]
-    gpu_socket_path: String,
+    gpu_socket_path: Vec<String>,
 
     /// Opaque renderer specific features
     #[arg(long, default_value = "")]
@@ -31,15 +39,37 @@ struct Args {
     /// An OS-specific pipe descriptor to the parent process
     #[arg(long, default_value = "0")]
     pipe_descriptor: i64,
+
+    /// Exit after this many seconds without GPU connections.  0 means never.
+    #[arg(long, default_value = "0")]
+    idle_timeout_secs: u64,
 }
 
 fn main() -> KumquatGpuResult<()> {
     let args = Args::parse();
 
+    let gpu_listeners = inherited_listeners()?;
+    // Nothing else runs yet, so the environment can still be changed safely.
+    for name in LISTEN_ENV_VARS {
+        env::remove_var(name);
+    }
+    let gpu_sockets = if gpu_listeners.is_empty() {
+        args.gpu_socket_path
+            .into_iter()
+            .filter(|path| !path.is_empty())
+            .collect()
+    } else {
+        Vec::new()
+    };
+
     let mut kumquat = KumquatBuilder::new()
         .set_capset_names(args.capset_names)
-        .set_gpu_socket((!args.gpu_socket_path.is_empty()).then_some(args.gpu_socket_path))
+        .set_gpu_sockets(gpu_sockets)
+        .set_gpu_listeners(gpu_listeners)
         .set_renderer_features(args.renderer_features)
+        .set_idle_timeout(
+            (args.idle_timeout_secs != 0).then(|| Duration::from_secs(args.idle_timeout_secs)),
+        )
         .build()?;
 
     if args.pipe_descriptor != 0 {
@@ -47,7 +77,9 @@ fn main() -> KumquatGpuResult<()> {
         write_pipe.write(&1u64.to_ne_bytes())?;
     }
 
-    loop {
+    while !kumquat.idle_expired() {
         kumquat.run()?;
     }
+
+    Ok(())
 }
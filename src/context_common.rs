@@ -3,16 +3,39 @@
 // found in the LICENSE file.
 
 use std::collections::BTreeMap as Map;
+use std::ops::Range;
 use std::sync::Arc;
 use std::sync::Mutex;
 
 use crate::handle::RutabagaHandle;
 
-use crate::rutabaga_utils::RutabagaIovec;
+use crate::rutabaga_utils::RutabagaBacking;
 
 pub struct ContextResource {
     pub handle: Option<Arc<RutabagaHandle>>,
-    pub backing_iovecs: Option<Vec<RutabagaIovec>>,
+    pub backing_iovecs: Option<RutabagaBacking>,
 }
 
 pub type ContextResources = Arc<Mutex<Map<u32, ContextResource>>>;
+
+/// Drops the backing iovecs of every context resource that intersect the host address `range`,
+/// returning the ids of the affected resources.
+pub fn invalidate_guest_memory(
+    context_resources: &ContextResources,
+    range: &Range<u64>,
+) -> Vec<u32> {
+    let mut resource_ids = Vec::new();
+    for (resource_id, context_resource) in context_resources.lock().unwrap().iter_mut() {
+        let intersects = context_resource
+            .backing_iovecs
+            .as_ref()
+            .is_some_and(|backing| backing.intersects(range));
+
+        if intersects {
+            context_resource.backing_iovecs = None;
+            resource_ids.push(*resource_id);
+        }
+    }
+
+    resource_ids
+}
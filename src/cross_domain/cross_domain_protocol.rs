@@ -19,14 +19,43 @@ pub const CROSS_DOMAIN_CMD_SEND: u8 = 4;
 pub const CROSS_DOMAIN_CMD_RECEIVE: u8 = 5;
 pub const CROSS_DOMAIN_CMD_READ: u8 = 6;
 pub const CROSS_DOMAIN_CMD_WRITE: u8 = 7;
+pub const CROSS_DOMAIN_CMD_GET_IMAGE_REQUIREMENTS_WITH_MODIFIERS: u8 = 8;
+pub const CROSS_DOMAIN_CMD_SET_STAGING: u8 = 9;
+pub const CROSS_DOMAIN_CMD_OPEN_CHANNEL: u8 = 10;
+pub const CROSS_DOMAIN_CMD_WORKER_RESTART: u8 = 11;
+pub const CROSS_DOMAIN_CMD_ERROR: u8 = 12;
+pub const CROSS_DOMAIN_CMD_QUERY_MODIFIERS: u8 = 13;
+pub const CROSS_DOMAIN_CMD_READ_ACK: u8 = 14;
+pub const CROSS_DOMAIN_CMD_DEBUG_MARKER: u8 = 15;
+pub const CROSS_DOMAIN_CMD_SET_READ_BATCHING: u8 = 16;
+pub const CROSS_DOMAIN_CMD_SET_RESOURCE_NAME: u8 = 17;
 
-/// Channel types (must match rutabaga channel types)
+/// Channel types.  Also the bit positions of CrossDomainCapabilities::supported_channels.
 pub const CROSS_DOMAIN_CHANNEL_TYPE_WAYLAND: u32 = 0x0001;
 pub const CROSS_DOMAIN_CHANNEL_TYPE_CAMERA: u32 = 0x0002;
+pub const CROSS_DOMAIN_CHANNEL_TYPE_X11: u32 = 0x0003;
+pub const CROSS_DOMAIN_CHANNEL_TYPE_PULSE: u32 = 0x0004;
+
+/// Debug marker types.  See CrossDomainDebugMarker.
+pub const CROSS_DOMAIN_DEBUG_MARKER_BEGIN: u32 = 0x0001;
+pub const CROSS_DOMAIN_DEBUG_MARKER_END: u32 = 0x0002;
+pub const CROSS_DOMAIN_DEBUG_MARKER_INSERT: u32 = 0x0003;
+
+/// The maximum size of a debug marker label
+pub const CROSS_DOMAIN_MAX_DEBUG_LABEL: usize = 256;
+
+/// The maximum size of a resource name
+pub const CROSS_DOMAIN_MAX_RESOURCE_NAME: usize = 64;
 
 /// The maximum number of identifiers
 pub const CROSS_DOMAIN_MAX_IDENTIFIERS: usize = 28;
 
+/// The maximum number of DRM format modifiers proposed per image
+pub const CROSS_DOMAIN_MAX_MODIFIERS: usize = 16;
+
+/// The maximum number of DRM format modifiers returned by CROSS_DOMAIN_CMD_QUERY_MODIFIERS
+pub const CROSS_DOMAIN_MAX_QUERY_MODIFIERS: usize = 32;
+
 /// virtgpu memory resource ID.  Also works with non-blob memory resources, despite the name.
 pub const CROSS_DOMAIN_ID_TYPE_VIRTGPU_BLOB: u32 = 1;
 /// virtgpu synchronization resource id.
@@ -48,6 +77,30 @@ pub const CROSS_DOMAIN_CHANNEL_RING: u32 = 1;
 /// Read pipe IDs start at this value.
 pub const CROSS_DOMAIN_PIPE_READ_START: u32 = 0x80000000;
 
+/// The channel opened by CROSS_DOMAIN_CMD_INIT.
+pub const CROSS_DOMAIN_CHANNEL_DEFAULT: u32 = 0;
+
+/// Unregisters the staging blob.
+pub const CROSS_DOMAIN_STAGING_NONE: u32 = 0;
+
+/// The command isn't set in CrossDomainCapabilities::supported_commands.
+pub const CROSS_DOMAIN_ERROR_UNSUPPORTED_COMMAND: u32 = 1;
+
+/// The host ran out of descriptors receiving a channel message.  Dropping the message would break
+/// the channel's stream, so the channel is closed instead, and appears to hang up.
+pub const CROSS_DOMAIN_ERROR_DESCRIPTOR_LIMIT: u32 = 2;
+
+/// The command is malformed: its size is out of range or doesn't cover its data, it starts
+/// unaligned, or it initializes the context again.
+pub const CROSS_DOMAIN_ERROR_INVALID_COMMAND: u32 = 3;
+
+/// The host failed to run the command, for example because a host socket or allocation failed.
+pub const CROSS_DOMAIN_ERROR_HOST_FAILURE: u32 = 4;
+
+/// The host stopped polling channels and pipes after repeated errors.  No more messages arrive
+/// on the channel ring.
+pub const CROSS_DOMAIN_ERROR_WORKER_HALTED: u32 = 5;
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
 pub struct CrossDomainCapabilities {
@@ -55,6 +108,28 @@ pub struct CrossDomainCapabilities {
     pub supported_channels: u32,
     pub supports_dmabuf: u32,
     pub supports_external_gpu_memory: u32,
+    pub supports_blob_resize: u32,
+    /// Bit `1 << cmd` is set for every command the host accepts from the guest.
+    pub supported_commands: u32,
+    /// If set, other commands are rejected and reported with a CrossDomainCommandError, as are
+    /// commands newer than the version given by a guest at init.
+    pub strict: u32,
+    /// If set, images may be allocated from protected memory by setting the gralloc protected
+    /// usage flag in the image requirements and RUTABAGA_BLOB_FLAG_PROTECTED on the blob.
+    pub supports_protected: u32,
+    /// If set, the host accepts a priority in the context_init of GPU contexts and schedules by
+    /// it where the driver supports priorities.  See RUTABAGA_CONTEXT_INIT_PRIORITY_MASK.  Hosts
+    /// that ignore priorities leave this unset.
+    pub supports_context_priority: u32,
+    /// If set, the host accepts RUTABAGA_CONTEXT_INIT_COMPUTE_ONLY in the context_init of GPU
+    /// contexts, and skips graphics state or picks a compute engine where the component can.
+    /// Hosts that ignore the flag leave this unset, and create full contexts.
+    pub supports_compute_only: u32,
+    /// The number of bytes read from a Wayland read pipe that may be unacknowledged before the
+    /// host stops reading it.  See CrossDomainReadAck.
+    pub pipe_high_water: u32,
+    /// The handle types (see handle_type_mask()) blobs of the context may be exported as.
+    pub export_handle_types: u32,
 }
 
 #[repr(C)]
@@ -68,6 +143,10 @@ pub struct CrossDomainImageRequirements {
     pub map_info: u32,
     pub memory_idx: i32,
     pub physical_device_idx: i32,
+    /// The UUIDs of the Vulkan device the memory is allocated from.  Only written for guests of
+    /// version 19 or later, which reserve room for them.
+    pub device_uuid: [u8; 16],
+    pub driver_uuid: [u8; 16],
 }
 
 #[repr(C)]
@@ -76,7 +155,9 @@ pub struct CrossDomainHeader {
     pub cmd: u8,
     pub ring_idx: u8,
     pub cmd_size: u16,
-    pub pad: u32,
+    /// The channel a CROSS_DOMAIN_CMD_SEND or CROSS_DOMAIN_CMD_RECEIVE applies to.  Must be
+    /// CROSS_DOMAIN_CHANNEL_DEFAULT for other commands.
+    pub channel_id: u32,
 }
 
 #[repr(C)]
@@ -86,6 +167,21 @@ pub struct CrossDomainInit {
     pub query_ring_id: u32,
     pub channel_ring_id: u32,
     pub channel_type: u32,
+    /// The handle types (see handle_type_mask()) the guest can import blobs from.  If set, the
+    /// host rejects the init when it exports none of them, and otherwise writes a
+    /// CrossDomainInitResponse to the query ring.  Zero accepts every handle type.
+    pub import_handle_types: u32,
+    /// The protocol version the guest speaks, at most CrossDomainCapabilities::version.  Zero for
+    /// guests that predate it.
+    pub version: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+pub struct CrossDomainInitResponse {
+    /// The exported handle types the guest can import.
+    pub handle_types: u32,
+    pub pad: u32,
 }
 
 #[repr(C)]
@@ -98,6 +194,19 @@ pub struct CrossDomainGetImageRequirements {
     pub flags: u32,
 }
 
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+pub struct CrossDomainGetImageRequirementsWithModifiers {
+    pub hdr: CrossDomainHeader,
+    pub width: u32,
+    pub height: u32,
+    pub drm_format: u32,
+    pub flags: u32,
+    pub num_modifiers: u32,
+    pub pad: u32,
+    pub modifiers: [u64; CROSS_DOMAIN_MAX_MODIFIERS],
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
 pub struct CrossDomainSendReceive {
@@ -120,3 +229,135 @@ pub struct CrossDomainReadWrite {
     pub pad: u32,
     // Data of size "opaque data size follows"
 }
+
+/// Opens an additional channel of `channel_type` in a context initialized with a channel.  The
+/// guest picks a `channel_id` that's unique in the context, and messages received on the channel
+/// are written to `channel_ring_id`.  Wayland pipes are always proxied over the default channel.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+pub struct CrossDomainOpenChannel {
+    pub hdr: CrossDomainHeader,
+    pub channel_id: u32,
+    pub channel_ring_id: u32,
+    pub channel_type: u32,
+    pub pad: u32,
+}
+
+/// In strict mode, submissions are validated before any of their commands run.  The first rejected
+/// command is reported in the last bytes of the query ring, and the whole submission is dropped.
+/// Channels closed because the host ran out of descriptors are reported the same way, in any
+/// mode.  `seqno` is incremented for every report, so guests notice new errors by comparing
+/// it with the last value seen.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+pub struct CrossDomainCommandError {
+    pub seqno: u32,
+    pub cmd: u32,
+    pub error: u32,
+    pub pad: u32,
+}
+
+/// Written to the channel ring after the host restarted the worker polling channels and pipes,
+/// following an error.  Channel messages and pipe data in flight may have been lost, so guest
+/// proxies should resynchronize.  `restarts` counts the restarts of the context so far.  Only
+/// sent to guests of version 17 or later; the fence of older guests stays pending until the next
+/// message instead.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+pub struct CrossDomainWorkerRestart {
+    pub hdr: CrossDomainHeader,
+    pub restarts: u32,
+    pub pad: u32,
+}
+
+/// Written to the channel ring when the host fails to run `cmd`, with one of the
+/// CROSS_DOMAIN_ERROR_* codes in `error`.  The rest of the submission is dropped.  Reports are
+/// delivered in order, each on its own channel ring fence, ahead of further channel messages.
+/// Failures of the worker polling channels and pipes are reported with CROSS_DOMAIN_CMD_POLL.
+/// Only sent to guests of version 11 or later.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+pub struct CrossDomainError {
+    pub hdr: CrossDomainHeader,
+    pub cmd: u32,
+    pub error: u32,
+}
+
+/// Registers a guest memory blob that Wayland pipe data is read into.  While registered,
+/// CROSS_DOMAIN_CMD_READ responses on the channel ring carry only the header, and the
+/// "opaque data size" bytes of data are at the start of the staging blob.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+pub struct CrossDomainSetStaging {
+    pub hdr: CrossDomainHeader,
+    pub staging_id: u32,
+    pub pad: u32,
+}
+
+/// Acknowledges that the guest consumed `bytes` of the data read from the pipe `identifier` with
+/// CROSS_DOMAIN_CMD_READ.  Once `pipe_high_water` bytes of a pipe are unacknowledged, the host
+/// stops reading it until the guest catches up.  Flow control starts with the first
+/// acknowledgment of the context, so guests that never send one are unaffected.  Only data read
+/// after that acknowledgment is counted, and the acknowledgment itself is not.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+pub struct CrossDomainReadAck {
+    pub hdr: CrossDomainHeader,
+    pub identifier: u32,
+    pub bytes: u32,
+}
+
+/// Lets the host write what several Wayland read pipes have ready with one fence, rather than
+/// one pipe per fence.  The CROSS_DOMAIN_CMD_READ records of a batch follow one another in the
+/// channel ring, each starting 8-byte aligned with `hdr.cmd_size` covering its data and padding.
+/// A header with a zero `cmd` ends the batch.  Data read into the staging blob isn't batched.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+pub struct CrossDomainSetReadBatching {
+    pub hdr: CrossDomainHeader,
+    pub enable: u32,
+    pub pad: u32,
+}
+
+/// Annotates the host trace of the context, e.g. with the frames or render passes of a guest
+/// application.  BEGIN opens a span closed by the next END of the context, and INSERT marks a
+/// single point.  The UTF-8 label of `label_size` bytes follows, and is ignored by END.  Markers
+/// are dropped unless the host is tracing.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+pub struct CrossDomainDebugMarker {
+    pub hdr: CrossDomainHeader,
+    pub marker_type: u32,
+    pub label_size: u32,
+}
+
+/// Labels the resource `resource_id`, attached to the context, for host debugging.  The UTF-8 name
+/// of `name_size` bytes follows, and an empty name clears the label.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+pub struct CrossDomainSetResourceName {
+    pub hdr: CrossDomainHeader,
+    pub resource_id: u32,
+    pub name_size: u32,
+}
+
+/// Asks for the DRM format modifiers the host can allocate images of `drm_format` with, for the
+/// gralloc usage in `flags`.  Answered on the query ring with a CrossDomainModifiers, for guest
+/// proxies to advertise with zwp_linux_dmabuf_v1.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+pub struct CrossDomainQueryModifiers {
+    pub hdr: CrossDomainHeader,
+    pub drm_format: u32,
+    pub flags: u32,
+}
+
+/// The first `num_modifiers` entries of `modifiers` are supported.  An empty list means the host
+/// can't enumerate modifiers, and only implicit ones should be used.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+pub struct CrossDomainModifiers {
+    pub drm_format: u32,
+    pub num_modifiers: u32,
+    pub modifiers: [u64; CROSS_DOMAIN_MAX_QUERY_MODIFIERS],
+}
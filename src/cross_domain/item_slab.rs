@@ -0,0 +1,162 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A slab of cross-domain items, addressed by generational ids.
+//!
+//! The low bits of an id select a slot, and the high bits hold the generation of the slot when
+//! the item was inserted.  Removing an item bumps the generation, so an id the guest keeps using
+//! after the item was freed never resolves to an item inserted later in the same slot.  Slots
+//! whose generation is exhausted are retired rather than reused.
+
+use mesa3d_util::MesaError;
+
+use crate::cross_domain::cross_domain_protocol::CROSS_DOMAIN_PIPE_READ_START;
+use crate::rutabaga_utils::RutabagaResult;
+
+const ITEM_SLAB_INDEX_BITS: u32 = 20;
+const ITEM_SLAB_INDEX_MASK: u32 = (1 << ITEM_SLAB_INDEX_BITS) - 1;
+const ITEM_SLAB_MAX_SLOTS: usize = 1 << ITEM_SLAB_INDEX_BITS;
+// Ids stay below CROSS_DOMAIN_PIPE_READ_START, which read pipes are numbered from.
+const ITEM_SLAB_MAX_GENERATION: u32 = (CROSS_DOMAIN_PIPE_READ_START - 1) >> ITEM_SLAB_INDEX_BITS;
+
+struct ItemSlot<T> {
+    // Starts at 1, so that no id is 0.
+    generation: u32,
+    item: Option<T>,
+}
+
+pub struct ItemSlab<T> {
+    slots: Vec<ItemSlot<T>>,
+    free: Vec<u32>,
+}
+
+impl<T> Default for ItemSlab<T> {
+    fn default() -> ItemSlab<T> {
+        ItemSlab {
+            slots: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+}
+
+fn item_id(index: u32, generation: u32) -> u32 {
+    (generation << ITEM_SLAB_INDEX_BITS) | index
+}
+
+impl<T> ItemSlab<T> {
+    /// Inserts `item`, and returns its id.
+    pub fn insert(&mut self, item: T) -> RutabagaResult<u32> {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None if self.slots.len() < ITEM_SLAB_MAX_SLOTS => {
+                self.slots.push(ItemSlot {
+                    generation: 1,
+                    item: None,
+                });
+                (self.slots.len() - 1) as u32
+            }
+            None => return Err(MesaError::WithContext("cross domain item slab is full").into()),
+        };
+
+        let slot = &mut self.slots[index as usize];
+        slot.item = Some(item);
+        Ok(item_id(index, slot.generation))
+    }
+
+    fn slot(&self, item_id: u32) -> Option<&ItemSlot<T>> {
+        let slot = self.slots.get((item_id & ITEM_SLAB_INDEX_MASK) as usize)?;
+        (slot.generation == item_id >> ITEM_SLAB_INDEX_BITS).then_some(slot)
+    }
+
+    pub fn get(&self, item_id: u32) -> Option<&T> {
+        self.slot(item_id)?.item.as_ref()
+    }
+
+    pub fn get_mut(&mut self, item_id: u32) -> Option<&mut T> {
+        self.slot(item_id)?;
+        self.slots[(item_id & ITEM_SLAB_INDEX_MASK) as usize]
+            .item
+            .as_mut()
+    }
+
+    pub fn remove(&mut self, item_id: u32) -> Option<T> {
+        self.slot(item_id)?;
+        let index = item_id & ITEM_SLAB_INDEX_MASK;
+        let slot = &mut self.slots[index as usize];
+        let item = slot.item.take()?;
+
+        if slot.generation < ITEM_SLAB_MAX_GENERATION {
+            slot.generation += 1;
+            self.free.push(index);
+        }
+
+        Some(item)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &T)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            let item = slot.item.as_ref()?;
+            Some((item_id(index as u32, slot.generation), item))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removed_ids_stay_stale() {
+        let mut slab: ItemSlab<u32> = Default::default();
+        let first = slab.insert(1).unwrap();
+        assert_ne!(first, 0);
+        assert_eq!(slab.get(first), Some(&1));
+
+        assert_eq!(slab.remove(first), Some(1));
+        assert_eq!(slab.remove(first), None);
+
+        // The slot is reused under a new generation.
+        let second = slab.insert(2).unwrap();
+        assert_eq!(second & ITEM_SLAB_INDEX_MASK, first & ITEM_SLAB_INDEX_MASK);
+        assert_ne!(second, first);
+        assert_eq!(slab.get(first), None);
+        assert_eq!(slab.get_mut(first), None);
+        assert_eq!(slab.get(second), Some(&2));
+    }
+
+    #[test]
+    fn exhausted_slots_are_retired() {
+        let mut slab: ItemSlab<u32> = Default::default();
+        slab.slots.push(ItemSlot {
+            generation: ITEM_SLAB_MAX_GENERATION,
+            item: None,
+        });
+        slab.free.push(0);
+
+        let last = slab.insert(1).unwrap();
+        assert!(last < CROSS_DOMAIN_PIPE_READ_START);
+        assert_eq!(slab.remove(last), Some(1));
+
+        // The next item gets a fresh slot.
+        let next = slab.insert(2).unwrap();
+        assert_eq!(next & ITEM_SLAB_INDEX_MASK, 1);
+        assert_eq!(slab.get(last), None);
+    }
+
+    #[test]
+    fn iter_returns_live_items() {
+        let mut slab: ItemSlab<u32> = Default::default();
+        let ids: Vec<u32> = (0..3).map(|item| slab.insert(item).unwrap()).collect();
+        slab.remove(ids[1]).unwrap();
+
+        let items: Vec<(u32, u32)> = slab.iter().map(|(id, item)| (id, *item)).collect();
+        assert_eq!(items, vec![(ids[0], 0), (ids[2], 2)]);
+    }
+
+    #[test]
+    fn ids_below_read_pipes() {
+        let max_id = item_id(ITEM_SLAB_INDEX_MASK, ITEM_SLAB_MAX_GENERATION);
+        assert!(max_id < CROSS_DOMAIN_PIPE_READ_START);
+    }
+}
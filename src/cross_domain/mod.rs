@@ -5,17 +5,24 @@
 //! The cross-domain component type, specialized for allocating and sharing resources across domain
 //! boundaries.
 
-use std::cmp::max;
 use std::collections::BTreeMap as Map;
+use std::collections::BTreeSet as Set;
 use std::collections::VecDeque;
 use std::convert::TryInto;
+use std::io::IoSliceMut;
 use std::mem::size_of;
+use std::ops::Range;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::sync::Condvar;
 use std::sync::Mutex;
 use std::thread;
+use std::time::Duration;
 
-use log::error;
+use log::Level;
 use mesa3d_util::create_pipe;
 use mesa3d_util::AsBorrowedDescriptor;
 use mesa3d_util::AsRawDescriptor;
@@ -28,6 +35,7 @@ use mesa3d_util::ReadPipe;
 use mesa3d_util::Tube;
 use mesa3d_util::TubeType;
 use mesa3d_util::WaitContext;
+use mesa3d_util::WaitEvent;
 use mesa3d_util::WaitTimeout;
 use mesa3d_util::WritePipe;
 use mesa3d_util::MESA_HANDLE_TYPE_MEM_DMABUF;
@@ -36,46 +44,218 @@ use zerocopy::FromBytes;
 use zerocopy::Immutable;
 use zerocopy::IntoBytes;
 
+use crate::context_common::invalidate_guest_memory;
 use crate::context_common::ContextResource;
 use crate::context_common::ContextResources;
 use crate::cross_domain::cross_domain_protocol::*;
+use crate::cross_domain::item_slab::ItemSlab;
+use crate::cross_domain::wayland_trace::trace_wayland_messages;
+use crate::cross_domain::wayland_trace::WaylandDirection;
 use crate::handle::RutabagaHandle;
 use crate::rutabaga_core::RutabagaComponent;
 use crate::rutabaga_core::RutabagaContext;
+use crate::rutabaga_core::RutabagaContextParams;
 use crate::rutabaga_core::RutabagaResource;
+#[cfg(feature = "deterministic")]
+use crate::rutabaga_deterministic::RutabagaWorkerStatus;
+use crate::rutabaga_stats::RutabagaResourceNames;
+use crate::rutabaga_trace::RutabagaDebugMarker;
+use crate::rutabaga_trace::RutabagaTrace;
+use crate::rutabaga_utils::handle_type_mask;
+use crate::rutabaga_utils::validate_iovecs;
 use crate::rutabaga_utils::Resource3DInfo;
 use crate::rutabaga_utils::ResourceCreateBlob;
+use crate::rutabaga_utils::RutabagaBacking;
 use crate::rutabaga_utils::RutabagaComponentType;
 use crate::rutabaga_utils::RutabagaError;
 use crate::rutabaga_utils::RutabagaFence;
 use crate::rutabaga_utils::RutabagaFenceHandler;
+use crate::rutabaga_utils::RutabagaHandleTypes;
 use crate::rutabaga_utils::RutabagaIovec;
+use crate::rutabaga_utils::RutabagaLogSink;
+use crate::rutabaga_utils::RutabagaLogger;
 use crate::rutabaga_utils::RutabagaPath;
 use crate::rutabaga_utils::RutabagaResult;
+use crate::rutabaga_utils::RutabagaThreadConfig;
+use crate::rutabaga_utils::RutabagaThreadType;
+use crate::rutabaga_utils::RUTABAGA_BLOB_FLAG_PROTECTED;
 use crate::rutabaga_utils::RUTABAGA_BLOB_FLAG_USE_MAPPABLE;
 use crate::rutabaga_utils::RUTABAGA_BLOB_MEM_GUEST;
 use crate::rutabaga_utils::RUTABAGA_MAP_ACCESS_READ;
 use crate::rutabaga_utils::RUTABAGA_MAP_ACCESS_RW;
 use crate::rutabaga_utils::RUTABAGA_MAP_CACHE_CACHED;
+use crate::rutabaga_utils::RUTABAGA_PATH_TYPE_CAMERA;
+use crate::rutabaga_utils::RUTABAGA_PATH_TYPE_PULSE;
+use crate::rutabaga_utils::RUTABAGA_PATH_TYPE_WAYLAND;
+use crate::rutabaga_utils::RUTABAGA_PATH_TYPE_X11;
 use crate::DrmFormat;
 use crate::ImageAllocationInfo;
 use crate::ImageMemoryRequirements;
 use crate::RutabagaGralloc;
 use crate::RutabagaGrallocBackendFlags;
 use crate::RutabagaGrallocFlags;
+use crate::RutabagaGrallocPolicy;
 
 mod cross_domain_protocol;
+mod item_slab;
+mod wayland_trace;
 
-const CROSS_DOMAIN_CONTEXT_CHANNEL_ID: u64 = 1;
 const CROSS_DOMAIN_RESAMPLE_ID: u64 = 2;
 const CROSS_DOMAIN_KILL_ID: u64 = 3;
-
-const CROSS_DOMAIN_DEFAULT_BUFFER_SIZE: usize = 4096;
-const CROSS_DOMAIN_MAX_SEND_RECV_SIZE: usize =
-    CROSS_DOMAIN_DEFAULT_BUFFER_SIZE - size_of::<CrossDomainSendReceive>();
+// Channels are polled with ids above the range of read pipe ids.
+const CROSS_DOMAIN_CHANNEL_ID_START: u64 = 1 << 32;
+
+// The smallest and largest cmd_size of each command.  SEND and WRITE are followed by their opaque
+// data, and guests pad POLL to 16 bytes.
+const CROSS_DOMAIN_COMMAND_SIZES: [(u8, usize, usize); 13] = [
+    (
+        CROSS_DOMAIN_CMD_INIT,
+        size_of::<CrossDomainInitLegacy>(),
+        size_of::<CrossDomainInit>(),
+    ),
+    (
+        CROSS_DOMAIN_CMD_GET_IMAGE_REQUIREMENTS,
+        size_of::<CrossDomainGetImageRequirements>(),
+        size_of::<CrossDomainGetImageRequirements>(),
+    ),
+    (
+        CROSS_DOMAIN_CMD_POLL,
+        size_of::<CrossDomainHeader>(),
+        size_of::<CrossDomainHeader>() + size_of::<u64>(),
+    ),
+    (
+        CROSS_DOMAIN_CMD_SEND,
+        size_of::<CrossDomainSendReceive>(),
+        u16::MAX as usize,
+    ),
+    (
+        CROSS_DOMAIN_CMD_WRITE,
+        size_of::<CrossDomainReadWrite>(),
+        u16::MAX as usize,
+    ),
+    (
+        CROSS_DOMAIN_CMD_GET_IMAGE_REQUIREMENTS_WITH_MODIFIERS,
+        size_of::<CrossDomainGetImageRequirementsWithModifiers>(),
+        size_of::<CrossDomainGetImageRequirementsWithModifiers>(),
+    ),
+    (
+        CROSS_DOMAIN_CMD_SET_STAGING,
+        size_of::<CrossDomainSetStaging>(),
+        size_of::<CrossDomainSetStaging>(),
+    ),
+    (
+        CROSS_DOMAIN_CMD_OPEN_CHANNEL,
+        size_of::<CrossDomainOpenChannel>(),
+        size_of::<CrossDomainOpenChannel>(),
+    ),
+    (
+        CROSS_DOMAIN_CMD_QUERY_MODIFIERS,
+        size_of::<CrossDomainQueryModifiers>(),
+        size_of::<CrossDomainQueryModifiers>(),
+    ),
+    (
+        CROSS_DOMAIN_CMD_READ_ACK,
+        size_of::<CrossDomainReadAck>(),
+        size_of::<CrossDomainReadAck>(),
+    ),
+    (
+        CROSS_DOMAIN_CMD_DEBUG_MARKER,
+        size_of::<CrossDomainDebugMarker>(),
+        size_of::<CrossDomainDebugMarker>() + CROSS_DOMAIN_MAX_DEBUG_LABEL,
+    ),
+    (
+        CROSS_DOMAIN_CMD_SET_READ_BATCHING,
+        size_of::<CrossDomainSetReadBatching>(),
+        size_of::<CrossDomainSetReadBatching>(),
+    ),
+    (
+        CROSS_DOMAIN_CMD_SET_RESOURCE_NAME,
+        size_of::<CrossDomainSetResourceName>(),
+        size_of::<CrossDomainSetResourceName>() + CROSS_DOMAIN_MAX_RESOURCE_NAME,
+    ),
+];
+
+// The protocol version that added each command guests send.
+const CROSS_DOMAIN_COMMAND_VERSIONS: [(u8, u32); 13] = [
+    (CROSS_DOMAIN_CMD_INIT, 1),
+    (CROSS_DOMAIN_CMD_GET_IMAGE_REQUIREMENTS, 1),
+    (CROSS_DOMAIN_CMD_POLL, 1),
+    (CROSS_DOMAIN_CMD_SEND, 1),
+    (CROSS_DOMAIN_CMD_WRITE, 1),
+    (CROSS_DOMAIN_CMD_GET_IMAGE_REQUIREMENTS_WITH_MODIFIERS, 2),
+    (CROSS_DOMAIN_CMD_SET_STAGING, 3),
+    (CROSS_DOMAIN_CMD_OPEN_CHANNEL, 5),
+    (CROSS_DOMAIN_CMD_QUERY_MODIFIERS, 12),
+    (CROSS_DOMAIN_CMD_READ_ACK, 13),
+    (CROSS_DOMAIN_CMD_DEBUG_MARKER, 14),
+    (CROSS_DOMAIN_CMD_SET_READ_BATCHING, 15),
+    (CROSS_DOMAIN_CMD_SET_RESOURCE_NAME, 18),
+];
+
+// Commands start 4-byte aligned in strict mode.
+const CROSS_DOMAIN_COMMAND_ALIGNMENT: usize = 4;
+
+// A worker failing for several fences in a row is likely to fail for every fence, so consecutive
+// restarts are bounded.
+const CROSS_DOMAIN_MAX_WORKER_RESTARTS: u32 = 8;
+
+// The first guest version that understands CROSS_DOMAIN_CMD_ERROR.
+const CROSS_DOMAIN_VERSION_COMMAND_ERROR: u32 = 11;
+
+// The first guest version that understands CROSS_DOMAIN_CMD_WORKER_RESTART.
+const CROSS_DOMAIN_VERSION_WORKER_RESTART: u32 = 17;
+
+// The first guest version that negotiates handle types at init.
+const CROSS_DOMAIN_VERSION_HANDLE_TYPES: u32 = 16;
+
+// The first guest version whose CrossDomainImageRequirements has the device and driver UUIDs.
+const CROSS_DOMAIN_VERSION_DEVICE_UUIDS: u32 = 19;
+
+// readv() accepts at most IOV_MAX buffers.  Rings and staging blobs with more iovecs are only
+// partially used.
+const CROSS_DOMAIN_MAX_READ_IOVECS: usize = 1024;
+
+// Batched CROSS_DOMAIN_CMD_READ records start at this alignment.
+const CROSS_DOMAIN_READ_ALIGNMENT: usize = 8;
+
+// Unacknowledged bytes past which a Wayland read pipe is no longer read.  Bounds what a guest
+// that stopped fencing leaves queued in the ring and the pipe, without slowing clipboard
+// transfers of a guest keeping up.
+const CROSS_DOMAIN_PIPE_HIGH_WATER: usize = 1 << 20;
+
+// Image requirements cached per context.  Guests choose the query keys, so past this count the
+// least recently used requirements blob is removed.
+const CROSS_DOMAIN_MAX_IMAGE_REQUIREMENTS: usize = 64;
+
+// Each guest channel type and the type of host path it connects to.
+const CROSS_DOMAIN_CHANNEL_PATH_TYPES: [(u32, u32); 4] = [
+    (
+        CROSS_DOMAIN_CHANNEL_TYPE_WAYLAND,
+        RUTABAGA_PATH_TYPE_WAYLAND,
+    ),
+    (CROSS_DOMAIN_CHANNEL_TYPE_CAMERA, RUTABAGA_PATH_TYPE_CAMERA),
+    (CROSS_DOMAIN_CHANNEL_TYPE_X11, RUTABAGA_PATH_TYPE_X11),
+    (CROSS_DOMAIN_CHANNEL_TYPE_PULSE, RUTABAGA_PATH_TYPE_PULSE),
+];
+
+// A guest command decoded from a command buffer.  Opaque data borrows from the buffer.
+enum CrossDomainCommand<'a> {
+    Init(CrossDomainInit),
+    GetImageRequirements(CrossDomainGetImageRequirements, Vec<u64>),
+    Send(Box<CrossDomainSendReceive>, &'a [u8]),
+    Poll,
+    Write(CrossDomainReadWrite, &'a [u8]),
+    SetStaging(CrossDomainSetStaging),
+    OpenChannel(CrossDomainOpenChannel),
+    QueryModifiers(CrossDomainQueryModifiers),
+    ReadAck(CrossDomainReadAck),
+    DebugMarker(CrossDomainDebugMarker, &'a [u8]),
+    SetReadBatching(CrossDomainSetReadBatching),
+    SetResourceName(CrossDomainSetResourceName, &'a [u8]),
+}
 
 enum CrossDomainItem {
-    ImageRequirements(ImageMemoryRequirements),
+    ImageRequirements(Box<ImageMemoryRequirements>),
     Blob(MesaHandle),
     WaylandReadPipe(ReadPipe),
     WaylandWritePipe(WritePipe),
@@ -84,30 +264,80 @@ enum CrossDomainItem {
 enum CrossDomainJob {
     HandleFence(RutabagaFence),
     AddReadPipe(u32),
+    AddChannel(u32),
     Finish,
 }
 
+// Pipe reads batched with others are given the most data they may read, and record their size in
+// the header.
 enum RingWrite<'a, T> {
     Write(T, Option<&'a [u8]>),
-    WriteFromPipe(CrossDomainReadWrite, &'a mut ReadPipe, bool),
+    WriteFromPipe(CrossDomainReadWrite, &'a mut ReadPipe, bool, Option<usize>),
+}
+
+// Bytes read from each Wayland read pipe that the guest hasn't acknowledged yet.  Only bytes read
+// after flow control starts are counted, since the guest may not acknowledge those read before.
+#[derive(Default)]
+struct CrossDomainFlowControl {
+    // Set by the first CROSS_DOMAIN_CMD_READ_ACK of the context.
+    enabled: bool,
+    unacked: Map<u32, usize>,
+    // Pipes no longer polled until the guest acknowledges some of their data.
+    paused: Set<u32>,
 }
 
 type CrossDomainJobs = Mutex<Option<VecDeque<CrossDomainJob>>>;
 type CrossDomainItemState = Arc<Mutex<CrossDomainItems>>;
 
+// The width, height, DRM format, gralloc flags and modifiers of an image requirements query.
+type CrossDomainImageKey = (u32, u32, u32, u32, Vec<u64>);
+
+// Host descriptors held by the items of every context.  Guests can make the host hold an
+// unbounded number of pipes and dmabufs, so the total is checked against a soft limit well before
+// the process runs into EMFILE.
+struct CrossDomainDescriptors {
+    used: AtomicUsize,
+    limit: Option<usize>,
+}
+
+// Read pipes are numbered sequentially from CROSS_DOMAIN_PIPE_READ_START, since the guest guesses
+// their ids.  Other items live in a slab, which recycles the ids of removed items.
 struct CrossDomainItems {
-    descriptor_id: u32,
     read_pipe_id: u32,
-    table: Map<u32, CrossDomainItem>,
+    read_pipes: Map<u32, CrossDomainItem>,
+    slab: ItemSlab<CrossDomainItem>,
+    // The requirements blob of each query and when it was last used, so repeated queries reuse
+    // the blob of the first.
+    image_requirements: Map<CrossDomainImageKey, (u32, u64)>,
+    image_requirements_clock: u64,
+    descriptors: Arc<CrossDomainDescriptors>,
+    // The descriptors this context holds, released when it's destroyed.
+    held: usize,
+}
+
+struct CrossDomainChannel {
+    channel_type: u32,
+    ring_id: u32,
+    connection: Tube,
+    wayland_trace: bool,
 }
 
 struct CrossDomainState {
     context_resources: ContextResources,
     query_ring_id: u32,
     channel_ring_id: u32,
-    connection: Option<Tube>,
+    // The protocol version the guest gave at init.
+    guest_version: u32,
+    channels: Mutex<Map<u32, Arc<CrossDomainChannel>>>,
     jobs: CrossDomainJobs,
     jobs_cvar: Condvar,
+    staging_id: Mutex<Option<u32>>,
+    error_seqno: AtomicU32,
+    // Failed commands and their error codes, waiting to be reported on the channel ring.
+    errors: Mutex<VecDeque<(u8, u32)>>,
+    flow_control: Mutex<CrossDomainFlowControl>,
+    // Set by CROSS_DOMAIN_CMD_SET_READ_BATCHING.
+    read_batching: AtomicBool,
 }
 
 struct CrossDomainWorker {
@@ -115,6 +345,10 @@ struct CrossDomainWorker {
     state: Arc<CrossDomainState>,
     item_state: CrossDomainItemState,
     fence_handler: RutabagaFenceHandler,
+    logger: RutabagaLogger,
+    restarts: u32,
+    // The fences failed since the last one handled successfully.
+    failed_fences: u32,
 }
 
 struct CrossDomainContext {
@@ -127,6 +361,14 @@ struct CrossDomainContext {
     worker_thread: Option<thread::JoinHandle<RutabagaResult<()>>>,
     resample_evt: Option<Event>,
     kill_evt: Option<Event>,
+    thread_config: RutabagaThreadConfig,
+    wayland_trace: bool,
+    // The commands accepted in strict mode, or None if every known command is accepted.
+    strict_commands: Option<u32>,
+    logger: RutabagaLogger,
+    ctx_id: u32,
+    trace: RutabagaTrace,
+    resource_names: RutabagaResourceNames,
 }
 
 /// The CrossDomain component contains a list of paths that the guest may connect to and the
@@ -135,76 +377,727 @@ pub struct CrossDomain {
     paths: Option<Vec<RutabagaPath>>,
     gralloc: Arc<Mutex<RutabagaGralloc>>,
     fence_handler: RutabagaFenceHandler,
+    thread_config: RutabagaThreadConfig,
+    wayland_trace: bool,
+    strict: bool,
+    descriptors: Arc<CrossDomainDescriptors>,
+    logger: RutabagaLogger,
+    trace: RutabagaTrace,
+    resource_names: RutabagaResourceNames,
+}
+
+fn channel_path_type(channel_type: u32) -> Option<u32> {
+    CROSS_DOMAIN_CHANNEL_PATH_TYPES
+        .iter()
+        .find(|(channel, _)| *channel == channel_type)
+        .map(|(_, path)| *path)
 }
 
-// TODO(gurchetansingh): optimize the item tracker.  Each requirements blob is long-lived and can
-// be stored in a Slab or vector.  OwnedDescriptors received from the Wayland socket *seem* to come
-// one at a time, and can be stored as options.  Need to confirm.
-fn add_item(item_state: &CrossDomainItemState, item: CrossDomainItem) -> u32 {
-    let mut items = item_state.lock().unwrap();
+fn path_channel_type(path_type: u32) -> Option<u32> {
+    CROSS_DOMAIN_CHANNEL_PATH_TYPES
+        .iter()
+        .find(|(_, path)| *path == path_type)
+        .map(|(channel, _)| *channel)
+}
+
+// Only Wayland passes pipes, for clipboard and drag-and-drop data.  X11 and PulseAudio only share
+// memory (MIT-SHM/DRI3 buffers and memfd pools) over their sockets.
+fn channel_supports_pipes(channel_type: u32) -> bool {
+    channel_type == CROSS_DOMAIN_CHANNEL_TYPE_WAYLAND
+}
+
+// Blobs are allocated by gralloc, or are shared memory pools of the host compositor.
+fn cross_domain_handle_types(gralloc: &RutabagaGralloc) -> RutabagaHandleTypes {
+    RutabagaHandleTypes {
+        export: gralloc.handle_types() | handle_type_mask(&[MESA_HANDLE_TYPE_MEM_SHM]),
+        import: 0,
+    }
+}
+
+fn command_supported(supported_commands: u32, cmd: u8) -> bool {
+    1u32.checked_shl(cmd.into())
+        .is_some_and(|bit| supported_commands & bit != 0)
+}
+
+// Returns the version of the guest sending `cmd_init`.  Guests that leave out their version are
+// only known to speak the version that added the last field they set.
+fn init_guest_version(cmd_init: &CrossDomainInit) -> u32 {
+    match cmd_init.version {
+        0 if cmd_init.import_handle_types != 0 => CROSS_DOMAIN_VERSION_HANDLE_TYPES,
+        version => version,
+    }
+}
+
+// Returns the commands a guest speaking `guest_version` may send, as a mask of `1 << cmd` bits.
+// Guests older than CROSS_DOMAIN_VERSION_HANDLE_TYPES may speak any earlier version, so they
+// aren't limited.
+fn version_commands(guest_version: u32) -> u32 {
+    if guest_version < CROSS_DOMAIN_VERSION_HANDLE_TYPES {
+        return u32::MAX;
+    }
+
+    CROSS_DOMAIN_COMMAND_VERSIONS
+        .iter()
+        .filter(|(_, version)| *version <= guest_version)
+        .fold(0, |commands, (cmd, _)| commands | 1 << cmd)
+}
+
+// Decodes the command at the start of `commands`, and returns it along with the commands after
+// it.  Every command must at least cover its header, so decoding always makes progress.
+fn parse_command(commands: &[u8]) -> RutabagaResult<(CrossDomainCommand<'_>, &[u8])> {
+    let (hdr, _) = CrossDomainHeader::read_from_prefix(commands)
+        .map_err(|_| RutabagaError::InvalidCommandBuffer)?;
+
+    let cmd_size = hdr.cmd_size as usize;
+    if cmd_size < size_of::<CrossDomainHeader>() {
+        return Err(RutabagaError::InvalidCommandSize(cmd_size));
+    }
+
+    let command = match hdr.cmd {
+        CROSS_DOMAIN_CMD_INIT => {
+            let cmd_init = if cmd_size > size_of::<CrossDomainInitLegacy>() {
+                // Guests that predate handle type negotiation leave out import_handle_types,
+                // which reads as zero.
+                let mut cmd_init = CrossDomainInit::default();
+                let init_size = cmd_size.min(size_of::<CrossDomainInit>());
+                let init_bytes = commands
+                    .get(..init_size)
+                    .ok_or(RutabagaError::InvalidCommandBuffer)?;
+                cmd_init.as_mut_bytes()[..init_size].copy_from_slice(init_bytes);
+                cmd_init
+            } else {
+                let (cmd_init, _) = CrossDomainInitLegacy::read_from_prefix(commands)
+                    .map_err(|_e| RutabagaError::InvalidCommandBuffer)?;
+                CrossDomainInit {
+                    hdr: cmd_init.hdr,
+                    query_ring_id: cmd_init.query_ring_id,
+                    channel_ring_id: cmd_init.query_ring_id,
+                    channel_type: cmd_init.channel_type,
+                    import_handle_types: 0,
+                    version: 0,
+                }
+            };
+
+            CrossDomainCommand::Init(cmd_init)
+        }
+        CROSS_DOMAIN_CMD_GET_IMAGE_REQUIREMENTS => {
+            let (cmd_get_reqs, _) = CrossDomainGetImageRequirements::read_from_prefix(commands)
+                .map_err(|_e| RutabagaError::InvalidCommandBuffer)?;
+
+            CrossDomainCommand::GetImageRequirements(cmd_get_reqs, Vec::new())
+        }
+        CROSS_DOMAIN_CMD_GET_IMAGE_REQUIREMENTS_WITH_MODIFIERS => {
+            let (cmd_get_reqs, _) =
+                CrossDomainGetImageRequirementsWithModifiers::read_from_prefix(commands)
+                    .map_err(|_e| RutabagaError::InvalidCommandBuffer)?;
+
+            let modifiers = cmd_get_reqs
+                .modifiers
+                .get(..cmd_get_reqs.num_modifiers as usize)
+                .ok_or(RutabagaError::InvalidGrallocModifier)?;
+
+            let cmd_base = CrossDomainGetImageRequirements {
+                hdr: cmd_get_reqs.hdr,
+                width: cmd_get_reqs.width,
+                height: cmd_get_reqs.height,
+                drm_format: cmd_get_reqs.drm_format,
+                flags: cmd_get_reqs.flags,
+            };
+
+            CrossDomainCommand::GetImageRequirements(cmd_base, modifiers.to_vec())
+        }
+        CROSS_DOMAIN_CMD_SEND => {
+            let opaque_data_offset = size_of::<CrossDomainSendReceive>();
+            let (cmd_send, _) = CrossDomainSendReceive::read_from_prefix(commands)
+                .map_err(|_e| RutabagaError::InvalidCommandBuffer)?;
+
+            let opaque_data = commands
+                .get(opaque_data_offset..opaque_data_offset + cmd_send.opaque_data_size as usize)
+                .ok_or(RutabagaError::InvalidCommandSize(
+                    cmd_send.opaque_data_size as usize,
+                ))?;
+
+            CrossDomainCommand::Send(Box::new(cmd_send), opaque_data)
+        }
+        CROSS_DOMAIN_CMD_POLL => CrossDomainCommand::Poll,
+        CROSS_DOMAIN_CMD_WRITE => {
+            let opaque_data_offset = size_of::<CrossDomainReadWrite>();
+            let (cmd_write, _) = CrossDomainReadWrite::read_from_prefix(commands)
+                .map_err(|_e| RutabagaError::InvalidCommandBuffer)?;
+
+            let opaque_data = commands
+                .get(opaque_data_offset..opaque_data_offset + cmd_write.opaque_data_size as usize)
+                .ok_or(RutabagaError::InvalidCommandSize(
+                    cmd_write.opaque_data_size as usize,
+                ))?;
+
+            CrossDomainCommand::Write(cmd_write, opaque_data)
+        }
+        CROSS_DOMAIN_CMD_SET_STAGING => {
+            let (cmd_set_staging, _) = CrossDomainSetStaging::read_from_prefix(commands)
+                .map_err(|_e| RutabagaError::InvalidCommandBuffer)?;
+
+            CrossDomainCommand::SetStaging(cmd_set_staging)
+        }
+        CROSS_DOMAIN_CMD_OPEN_CHANNEL => {
+            let (cmd_open, _) = CrossDomainOpenChannel::read_from_prefix(commands)
+                .map_err(|_e| RutabagaError::InvalidCommandBuffer)?;
+
+            CrossDomainCommand::OpenChannel(cmd_open)
+        }
+        CROSS_DOMAIN_CMD_QUERY_MODIFIERS => {
+            let (cmd_query, _) = CrossDomainQueryModifiers::read_from_prefix(commands)
+                .map_err(|_e| RutabagaError::InvalidCommandBuffer)?;
+
+            CrossDomainCommand::QueryModifiers(cmd_query)
+        }
+        CROSS_DOMAIN_CMD_READ_ACK => {
+            let (cmd_ack, _) = CrossDomainReadAck::read_from_prefix(commands)
+                .map_err(|_e| RutabagaError::InvalidCommandBuffer)?;
+
+            CrossDomainCommand::ReadAck(cmd_ack)
+        }
+        CROSS_DOMAIN_CMD_SET_READ_BATCHING => {
+            let (cmd_batching, _) = CrossDomainSetReadBatching::read_from_prefix(commands)
+                .map_err(|_e| RutabagaError::InvalidCommandBuffer)?;
 
-    let item_id = match item {
-        CrossDomainItem::WaylandReadPipe(_) => {
-            items.read_pipe_id += 1;
-            max(items.read_pipe_id, CROSS_DOMAIN_PIPE_READ_START)
+            CrossDomainCommand::SetReadBatching(cmd_batching)
         }
-        _ => {
-            items.descriptor_id += 1;
-            items.descriptor_id
+        CROSS_DOMAIN_CMD_DEBUG_MARKER => {
+            let label_offset = size_of::<CrossDomainDebugMarker>();
+            let (cmd_marker, _) = CrossDomainDebugMarker::read_from_prefix(commands)
+                .map_err(|_e| RutabagaError::InvalidCommandBuffer)?;
+
+            // The label must also fit in the command, so it can't take bytes of the next one.
+            let label_size = cmd_marker.label_size as usize;
+            if label_size > CROSS_DOMAIN_MAX_DEBUG_LABEL || label_offset + label_size > cmd_size {
+                return Err(RutabagaError::InvalidCommandSize(label_size));
+            }
+
+            let label = commands
+                .get(label_offset..label_offset + label_size)
+                .ok_or(RutabagaError::InvalidCommandSize(label_size))?;
+
+            CrossDomainCommand::DebugMarker(cmd_marker, label)
         }
+        CROSS_DOMAIN_CMD_SET_RESOURCE_NAME => {
+            let name_offset = size_of::<CrossDomainSetResourceName>();
+            let (cmd_name, _) = CrossDomainSetResourceName::read_from_prefix(commands)
+                .map_err(|_e| RutabagaError::InvalidCommandBuffer)?;
+
+            let name_size = cmd_name.name_size as usize;
+            if name_size > CROSS_DOMAIN_MAX_RESOURCE_NAME || name_offset + name_size > cmd_size {
+                return Err(RutabagaError::InvalidCommandSize(name_size));
+            }
+
+            let name = commands
+                .get(name_offset..name_offset + name_size)
+                .ok_or(RutabagaError::InvalidCommandSize(name_size))?;
+
+            CrossDomainCommand::SetResourceName(cmd_name, name)
+        }
+        _ => return Err(MesaError::WithContext("invalid cross domain command").into()),
     };
 
-    items.table.insert(item_id, item);
+    let remaining = commands
+        .get(cmd_size..)
+        .ok_or(RutabagaError::InvalidCommandSize(cmd_size))?;
 
-    item_id
+    Ok((command, remaining))
 }
 
-impl Default for CrossDomainItems {
-    fn default() -> Self {
-        // Odd for descriptors, and even for requirement blobs.
+// Checks every command of a strict mode submission before any of them runs.  On failure, returns
+// the error reported to the guest along with the one returned to the VMM.
+fn validate_commands(
+    commands: &[u8],
+    supported_commands: u32,
+    mut guest_version: Option<u32>,
+) -> Result<(), (u8, u32, RutabagaError)> {
+    let mut offset = 0;
+    while offset < commands.len() {
+        let remaining = &commands[offset..];
+        let (hdr, _) = CrossDomainHeader::read_from_prefix(remaining).unwrap_or_default();
+        let reject = |error: u32, reason: &'static str| {
+            let e = RutabagaError::InvalidCrossDomainCommand {
+                cmd: hdr.cmd,
+                offset,
+                reason,
+            };
+            Err((hdr.cmd, error, e))
+        };
+
+        if remaining.len() < size_of::<CrossDomainHeader>() {
+            return reject(CROSS_DOMAIN_ERROR_INVALID_COMMAND, "truncated header");
+        }
+
+        if offset % CROSS_DOMAIN_COMMAND_ALIGNMENT != 0 {
+            return reject(CROSS_DOMAIN_ERROR_INVALID_COMMAND, "unaligned");
+        }
+
+        let sizes = CROSS_DOMAIN_COMMAND_SIZES
+            .iter()
+            .find(|(cmd, _, _)| *cmd == hdr.cmd);
+        let (min_size, max_size) = match sizes {
+            Some((_, min_size, max_size)) if command_supported(supported_commands, hdr.cmd) => {
+                (*min_size, *max_size)
+            }
+            _ => return reject(CROSS_DOMAIN_ERROR_UNSUPPORTED_COMMAND, "unsupported"),
+        };
+
+        if !command_supported(guest_version.map_or(u32::MAX, version_commands), hdr.cmd) {
+            return reject(
+                CROSS_DOMAIN_ERROR_UNSUPPORTED_COMMAND,
+                "newer than the guest's version",
+            );
+        }
+
+        let cmd_size = hdr.cmd_size as usize;
+        if cmd_size < min_size || cmd_size > max_size {
+            return reject(CROSS_DOMAIN_ERROR_INVALID_COMMAND, "size out of range");
+        }
+
+        if cmd_size > remaining.len() {
+            return reject(CROSS_DOMAIN_ERROR_INVALID_COMMAND, "truncated");
+        }
+
+        let opaque_data_end = match hdr.cmd {
+            CROSS_DOMAIN_CMD_INIT if guest_version.is_some() => {
+                return reject(CROSS_DOMAIN_ERROR_INVALID_COMMAND, "duplicate init");
+            }
+            CROSS_DOMAIN_CMD_INIT => {
+                if let Ok((CrossDomainCommand::Init(cmd_init), _)) = parse_command(remaining) {
+                    guest_version = Some(init_guest_version(&cmd_init));
+                }
+                0
+            }
+            CROSS_DOMAIN_CMD_SEND => {
+                let (cmd_send, _) =
+                    CrossDomainSendReceive::read_from_prefix(remaining).unwrap_or_default();
+                if cmd_send.num_identifiers as usize > CROSS_DOMAIN_MAX_IDENTIFIERS {
+                    return reject(CROSS_DOMAIN_ERROR_INVALID_COMMAND, "too many identifiers");
+                }
+
+                size_of::<CrossDomainSendReceive>() + cmd_send.opaque_data_size as usize
+            }
+            CROSS_DOMAIN_CMD_WRITE => {
+                let (cmd_write, _) =
+                    CrossDomainReadWrite::read_from_prefix(remaining).unwrap_or_default();
+                size_of::<CrossDomainReadWrite>() + cmd_write.opaque_data_size as usize
+            }
+            _ => 0,
+        };
+
+        if opaque_data_end > cmd_size {
+            return reject(
+                CROSS_DOMAIN_ERROR_INVALID_COMMAND,
+                "opaque data exceeds command",
+            );
+        }
+
+        offset += cmd_size;
+    }
+
+    Ok(())
+}
+
+/// Decodes every command in `commands` without executing them.  Only allocates what a single
+/// command needs, so it is suitable as a fuzzing entry point.
+#[cfg(any(feature = "fuzzing", feature = "benchmarks"))]
+pub fn parse_cross_domain_commands(mut commands: &[u8]) -> RutabagaResult<()> {
+    while !commands.is_empty() {
+        let (_, remaining) = parse_command(commands)?;
+        commands = remaining;
+    }
+
+    Ok(())
+}
+
+// Returns whether `descriptor` hung up, and whether it still has data to read.
+fn poll_state(descriptor: &OwnedDescriptor) -> RutabagaResult<(bool, bool)> {
+    let mut wait_ctx = WaitContext::new()?;
+    wait_ctx.add(0, descriptor)?;
+    let events = wait_ctx.wait(WaitTimeout::Finite(Duration::ZERO))?;
+    Ok(events
+        .first()
+        .map_or((false, false), |event| (event.hung_up, event.readable)))
+}
+
+// Polls `descriptor` as `id`, replacing any previous registration.
+fn repoll(wait_ctx: &mut WaitContext, id: u64, descriptor: &OwnedDescriptor) -> RutabagaResult<()> {
+    // Fails if the descriptor wasn't polled yet, which is fine.
+    let _ = wait_ctx.delete(descriptor);
+    wait_ctx.add(id, descriptor)?;
+    Ok(())
+}
+
+// Returns true if `e` means the host can't take on more descriptors, either because the soft
+// limit was reached or the process (or system) ran out of them.
+fn descriptors_exhausted(e: &RutabagaError) -> bool {
+    let errno = match e {
+        RutabagaError::DescriptorLimitExceeded(_) => return true,
+        RutabagaError::MesaError(MesaError::IoError(e)) => e.raw_os_error(),
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        RutabagaError::MesaError(MesaError::RustixError(e)) => Some(e.raw_os_error()),
+        _ => None,
+    };
+
+    matches!(errno, Some(libc::EMFILE) | Some(libc::ENFILE))
+}
+
+// Returns the CROSS_DOMAIN_ERROR_* code reported to the guest for a command failing with `e`.
+fn command_error_code(e: &RutabagaError) -> u32 {
+    match e {
+        e if descriptors_exhausted(e) => CROSS_DOMAIN_ERROR_DESCRIPTOR_LIMIT,
+        RutabagaError::InvalidCommandBuffer
+        | RutabagaError::InvalidCommandSize(_)
+        | RutabagaError::InvalidCrossDomainChannel
+        | RutabagaError::InvalidCrossDomainCommand { .. }
+        | RutabagaError::InvalidCrossDomainItemId
+        | RutabagaError::InvalidCrossDomainItemType => CROSS_DOMAIN_ERROR_INVALID_COMMAND,
+        _ => CROSS_DOMAIN_ERROR_HOST_FAILURE,
+    }
+}
+
+impl CrossDomainItem {
+    fn holds_descriptor(&self) -> bool {
+        !matches!(self, CrossDomainItem::ImageRequirements(_))
+    }
+}
+
+impl CrossDomainDescriptors {
+    fn new(limit: Option<usize>) -> CrossDomainDescriptors {
+        CrossDomainDescriptors {
+            used: AtomicUsize::new(0),
+            limit,
+        }
+    }
+
+    // Fails if `count` more descriptors would exceed the limit.
+    fn check(&self, count: usize) -> RutabagaResult<()> {
+        match self.limit {
+            Some(limit) if self.used.load(Ordering::Relaxed) + count > limit => {
+                Err(RutabagaError::DescriptorLimitExceeded(limit))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn acquire(&self, count: usize) -> RutabagaResult<()> {
+        let used = self.used.fetch_add(count, Ordering::Relaxed) + count;
+        match self.limit {
+            Some(limit) if used > limit => {
+                self.release(count);
+                Err(RutabagaError::DescriptorLimitExceeded(limit))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn release(&self, count: usize) {
+        self.used.fetch_sub(count, Ordering::Relaxed);
+    }
+}
+
+impl CrossDomainItems {
+    fn new(descriptors: Arc<CrossDomainDescriptors>) -> CrossDomainItems {
         CrossDomainItems {
-            descriptor_id: 1,
             read_pipe_id: CROSS_DOMAIN_PIPE_READ_START,
-            table: Default::default(),
+            read_pipes: Default::default(),
+            slab: Default::default(),
+            image_requirements: Default::default(),
+            image_requirements_clock: 0,
+            descriptors,
+            held: 0,
         }
     }
+
+    // Inserts `item` and returns its id, unless that would exceed the descriptor limit.  The item
+    // is dropped on failure.
+    fn insert(&mut self, item: CrossDomainItem) -> RutabagaResult<u32> {
+        let holds_descriptor = item.holds_descriptor();
+        if holds_descriptor {
+            self.descriptors.acquire(1)?;
+        }
+
+        let result = match item {
+            CrossDomainItem::WaylandReadPipe(_) => self.next_read_pipe_id().inspect(|item_id| {
+                self.read_pipes.insert(*item_id, item);
+            }),
+            _ => self.slab.insert(item),
+        };
+
+        match result {
+            Ok(_) if holds_descriptor => self.held += 1,
+            Err(_) if holds_descriptor => self.descriptors.release(1),
+            _ => (),
+        }
+
+        result
+    }
+
+    // Returns the id after the last read pipe's.  Ids wrap around to CROSS_DOMAIN_PIPE_READ_START,
+    // skipping those of read pipes still open.
+    fn next_read_pipe_id(&mut self) -> RutabagaResult<u32> {
+        let id_count = (u32::MAX - CROSS_DOMAIN_PIPE_READ_START) as usize + 1;
+        if self.read_pipes.len() >= id_count {
+            return Err(MesaError::WithContext("out of cross domain read pipe ids").into());
+        }
+
+        loop {
+            self.read_pipe_id = self
+                .read_pipe_id
+                .checked_add(1)
+                .unwrap_or(CROSS_DOMAIN_PIPE_READ_START);
+            if !self.read_pipes.contains_key(&self.read_pipe_id) {
+                return Ok(self.read_pipe_id);
+            }
+        }
+    }
+
+    fn get(&self, item_id: u32) -> Option<&CrossDomainItem> {
+        if item_id >= CROSS_DOMAIN_PIPE_READ_START {
+            self.read_pipes.get(&item_id)
+        } else {
+            self.slab.get(item_id)
+        }
+    }
+
+    fn get_mut(&mut self, item_id: u32) -> Option<&mut CrossDomainItem> {
+        if item_id >= CROSS_DOMAIN_PIPE_READ_START {
+            self.read_pipes.get_mut(&item_id)
+        } else {
+            self.slab.get_mut(item_id)
+        }
+    }
+
+    fn remove(&mut self, item_id: u32) -> Option<CrossDomainItem> {
+        let item = if item_id >= CROSS_DOMAIN_PIPE_READ_START {
+            self.read_pipes.remove(&item_id)?
+        } else {
+            self.slab.remove(item_id)?
+        };
+
+        if item.holds_descriptor() {
+            self.descriptors.release(1);
+            self.held -= 1;
+        }
+
+        Some(item)
+    }
+
+    // Returns the blob id and requirements cached for `key`, marking them as recently used.
+    fn cached_image_requirements(
+        &mut self,
+        key: &CrossDomainImageKey,
+    ) -> Option<(u32, ImageMemoryRequirements)> {
+        self.image_requirements_clock += 1;
+        let (blob_id, last_used) = self.image_requirements.get_mut(key)?;
+        *last_used = self.image_requirements_clock;
+        match self.slab.get(*blob_id) {
+            Some(CrossDomainItem::ImageRequirements(reqs)) => Some((*blob_id, **reqs)),
+            _ => None,
+        }
+    }
+
+    // Adds a requirements blob for `key` and returns its id.  The least recently used blob is
+    // removed once CROSS_DOMAIN_MAX_IMAGE_REQUIREMENTS are cached, so blobs must be created soon
+    // after querying their requirements.
+    fn cache_image_requirements(
+        &mut self,
+        key: CrossDomainImageKey,
+        reqs: ImageMemoryRequirements,
+    ) -> RutabagaResult<u32> {
+        if self.image_requirements.len() >= CROSS_DOMAIN_MAX_IMAGE_REQUIREMENTS {
+            let oldest = self
+                .image_requirements
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| key.clone());
+            if let Some((blob_id, _)) = oldest.and_then(|key| self.image_requirements.remove(&key))
+            {
+                self.remove(blob_id);
+            }
+        }
+
+        let blob_id = self.insert(CrossDomainItem::ImageRequirements(Box::new(reqs)))?;
+        self.image_requirements_clock += 1;
+        self.image_requirements
+            .insert(key, (blob_id, self.image_requirements_clock));
+        Ok(blob_id)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (u32, &CrossDomainItem)> {
+        self.read_pipes
+            .iter()
+            .map(|(item_id, item)| (*item_id, item))
+            .chain(self.slab.iter())
+    }
+}
+
+impl Drop for CrossDomainItems {
+    fn drop(&mut self) {
+        self.descriptors.release(self.held);
+    }
+}
+
+fn add_item(item_state: &CrossDomainItemState, item: CrossDomainItem) -> RutabagaResult<u32> {
+    item_state.lock().unwrap().insert(item)
 }
 
 impl CrossDomainState {
     fn new(
         query_ring_id: u32,
         channel_ring_id: u32,
+        guest_version: u32,
         context_resources: ContextResources,
-        connection: Option<Tube>,
     ) -> CrossDomainState {
         CrossDomainState {
             query_ring_id,
             channel_ring_id,
+            guest_version,
             context_resources,
-            connection,
+            channels: Mutex::new(Default::default()),
             jobs: Mutex::new(Some(VecDeque::new())),
             jobs_cvar: Condvar::new(),
+            staging_id: Mutex::new(None),
+            error_seqno: AtomicU32::new(0),
+            errors: Mutex::new(VecDeque::new()),
+            flow_control: Default::default(),
+            read_batching: AtomicBool::new(false),
         }
     }
 
+    // Returns true if ready read pipes are batched into the channel ring.  The staging blob only
+    // holds the data of one pipe.
+    fn batches_reads(&self) -> bool {
+        self.read_batching.load(Ordering::Relaxed) && self.staging_id.lock().unwrap().is_none()
+    }
+
+    // Records `bytes` read from `pipe_id` into the channel ring.  Returns true if the pipe is
+    // now past the high-water mark and must no longer be polled.
+    fn pipe_read(&self, pipe_id: u32, bytes: usize) -> bool {
+        let mut flow_control = self.flow_control.lock().unwrap();
+        let flow_control = &mut *flow_control;
+        if !flow_control.enabled {
+            return false;
+        }
+
+        let unacked = flow_control.unacked.entry(pipe_id).or_default();
+        *unacked = unacked.saturating_add(bytes);
+        if *unacked < CROSS_DOMAIN_PIPE_HIGH_WATER {
+            return false;
+        }
+
+        flow_control.paused.insert(pipe_id)
+    }
+
+    // Records the guest consuming `bytes` read from `pipe_id`.  Returns true if the pipe was
+    // paused and may be polled again.  The first acknowledgment starts flow control, and only
+    // acknowledges bytes read before it, which were never counted.
+    fn ack_read(&self, pipe_id: u32, bytes: usize) -> bool {
+        let mut flow_control = self.flow_control.lock().unwrap();
+        let flow_control = &mut *flow_control;
+        if !flow_control.enabled {
+            flow_control.enabled = true;
+            return false;
+        }
+
+        let Some(unacked) = flow_control.unacked.get_mut(&pipe_id) else {
+            return false;
+        };
+
+        *unacked = unacked.saturating_sub(bytes);
+        *unacked < CROSS_DOMAIN_PIPE_HIGH_WATER && flow_control.paused.remove(&pipe_id)
+    }
+
+    fn pipe_paused(&self, pipe_id: u32) -> bool {
+        self.flow_control.lock().unwrap().paused.contains(&pipe_id)
+    }
+
+    fn remove_pipe(&self, pipe_id: u32) {
+        let mut flow_control = self.flow_control.lock().unwrap();
+        flow_control.unacked.remove(&pipe_id);
+        flow_control.paused.remove(&pipe_id);
+    }
+
+    // Queues the failure of `cmd` for the channel ring.  Returns false if the guest predates
+    // CROSS_DOMAIN_CMD_ERROR, and can't be told.
+    fn add_error(&self, cmd: u8, error: u32) -> bool {
+        if self.guest_version < CROSS_DOMAIN_VERSION_COMMAND_ERROR {
+            return false;
+        }
+
+        self.errors.lock().unwrap().push_back((cmd, error));
+        true
+    }
+
+    fn take_error(&self) -> Option<(u8, u32)> {
+        self.errors.lock().unwrap().pop_front()
+    }
+
+    #[cfg(feature = "deterministic")]
+    fn has_errors(&self) -> bool {
+        !self.errors.lock().unwrap().is_empty()
+    }
+
+    fn add_channel(&self, channel_id: u32, channel: CrossDomainChannel) -> RutabagaResult<()> {
+        let mut channels = self.channels.lock().unwrap();
+        if channels.contains_key(&channel_id) {
+            return Err(RutabagaError::InvalidCrossDomainChannel);
+        }
+
+        channels.insert(channel_id, Arc::new(channel));
+        Ok(())
+    }
+
+    // The channel is cloned out of the table, so sending on one channel never blocks receiving
+    // on another.
+    fn channel(&self, channel_id: u32) -> RutabagaResult<Arc<CrossDomainChannel>> {
+        self.channels
+            .lock()
+            .unwrap()
+            .get(&channel_id)
+            .cloned()
+            .ok_or(RutabagaError::InvalidCrossDomainChannel)
+    }
+
     fn send_msg(
         &self,
+        channel_id: u32,
         opaque_data: &[u8],
         descriptors: &[OwnedDescriptor],
     ) -> RutabagaResult<usize> {
-        match self.connection {
-            Some(ref connection) => connection
-                .send(opaque_data, descriptors)
-                .map_err(|e| e.into()),
-            None => Err(RutabagaError::InvalidCrossDomainChannel),
+        let channel = self.channel(channel_id)?;
+        if channel.wayland_trace {
+            trace_wayland_messages(
+                WaylandDirection::GuestToHost,
+                opaque_data,
+                descriptors.len(),
+            );
         }
+
+        channel
+            .connection
+            .send(opaque_data, descriptors)
+            .map_err(|e| e.into())
     }
 
-    fn receive_msg(&self, opaque_data: &mut [u8]) -> RutabagaResult<(usize, Vec<OwnedDescriptor>)> {
-        match self.connection {
-            Some(ref connection) => connection.receive(opaque_data).map_err(|e| e.into()),
-            None => Err(RutabagaError::InvalidCrossDomainChannel),
+    fn receive_msg(
+        &self,
+        channel: &CrossDomainChannel,
+        opaque_data: &mut [u8],
+    ) -> RutabagaResult<(usize, Vec<OwnedDescriptor>)> {
+        let (len, descriptors) = channel.connection.receive(opaque_data)?;
+
+        if channel.wayland_trace {
+            trace_wayland_messages(
+                WaylandDirection::HostToGuest,
+                &opaque_data[0..len],
+                descriptors.len(),
+            );
         }
+
+        Ok((len, descriptors))
     }
 
     fn add_job(&self, job: CrossDomainJob) {
@@ -215,6 +1108,21 @@ impl CrossDomainState {
         }
     }
 
+    // Returns None once the queue is closed, and Some(None) if it's empty.
+    #[cfg(feature = "deterministic")]
+    fn try_job(&self) -> Option<Option<CrossDomainJob>> {
+        let mut jobs = self.jobs.lock().unwrap();
+        Some(jobs.as_mut()?.pop_front())
+    }
+
+    // Puts `job` back at the front of the queue, ahead of the fences that came after it.
+    fn requeue_job(&self, job: CrossDomainJob) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(queue) = jobs.as_mut() {
+            queue.push_front(job);
+        }
+    }
+
     fn wait_for_job(&self) -> Option<CrossDomainJob> {
         let mut jobs = self.jobs.lock().unwrap();
         loop {
@@ -225,79 +1133,484 @@ impl CrossDomainState {
         }
     }
 
-    fn write_to_ring<T>(&self, mut ring_write: RingWrite<T>, ring_id: u32) -> RutabagaResult<usize>
+    // Reports `error` for `cmd` at the end of the query ring, where it doesn't clobber pending
+    // replies.
+    fn write_command_error(&self, cmd: u8, error: u32) -> RutabagaResult<()> {
+        let report = CrossDomainCommandError {
+            seqno: self
+                .error_seqno
+                .fetch_add(1, Ordering::Relaxed)
+                .wrapping_add(1),
+            cmd: cmd.into(),
+            error,
+            pad: 0,
+        };
+
+        let context_resources = self.context_resources.lock().unwrap();
+        let ring = context_resources
+            .get(&self.query_ring_id)
+            .ok_or(RutabagaError::InvalidResourceId)?
+            .backing_iovecs
+            .as_ref()
+            .ok_or(RutabagaError::InvalidIovec)?
+            .pin()?;
+
+        let offset = backed_len(&ring)
+            .checked_sub(size_of::<CrossDomainCommandError>())
+            .ok_or(RutabagaError::InvalidIovec)?;
+        copy_to_iovecs(&ring, offset, report.as_bytes());
+        Ok(())
+    }
+
+    // Returns the number of bytes the ring `ring_id` can hold.
+    fn ring_capacity(&self, ring_id: u32) -> RutabagaResult<usize> {
+        let context_resources = self.context_resources.lock().unwrap();
+        let iovecs = context_resources
+            .get(&ring_id)
+            .ok_or(RutabagaError::InvalidResourceId)?
+            .backing_iovecs
+            .as_ref()
+            .ok_or(RutabagaError::InvalidIovec)?
+            .pin()?;
+
+        Ok(backed_len(&iovecs))
+    }
+
+    fn write_to_ring<T>(&self, ring_write: RingWrite<T>, ring_id: u32) -> RutabagaResult<usize>
     where
         T: FromBytes + IntoBytes + Immutable,
     {
-        let mut context_resources = self.context_resources.lock().unwrap();
+        self.write_to_ring_at(ring_write, ring_id, 0)
+    }
+
+    // Like write_to_ring(), but starting `offset` bytes into the ring.
+    fn write_to_ring_at<T>(
+        &self,
+        ring_write: RingWrite<T>,
+        ring_id: u32,
+        offset: usize,
+    ) -> RutabagaResult<usize>
+    where
+        T: FromBytes + IntoBytes + Immutable,
+    {
+        let context_resources = self.context_resources.lock().unwrap();
         let mut bytes_read: usize = 0;
 
-        let resource = context_resources
-            .get_mut(&ring_id)
-            .ok_or(RutabagaError::InvalidResourceId)?;
+        // The pins keep a concurrent detach_backing() from returning the memory to the guest
+        // mid-write.
+        let ring = context_resources
+            .get(&ring_id)
+            .ok_or(RutabagaError::InvalidResourceId)?
+            .backing_iovecs
+            .as_ref()
+            .ok_or(RutabagaError::InvalidIovec)?
+            .pin()?;
+
+        match ring_write {
+            RingWrite::Write(cmd, opaque_data_opt) => {
+                write_record(
+                    &ring,
+                    offset,
+                    cmd.as_bytes(),
+                    opaque_data_opt.unwrap_or(&[]),
+                )?;
+            }
+            RingWrite::WriteFromPipe(mut cmd_read, read_pipe, readable, limit) => {
+                let header_size = size_of::<CrossDomainReadWrite>();
+                if backed_len(&ring) < offset + header_size {
+                    return Err(RutabagaError::InvalidIovec);
+                }
+
+                if readable {
+                    let staging_id = *self.staging_id.lock().unwrap();
+                    let staging = match staging_id {
+                        Some(staging_id) => Some(
+                            context_resources
+                                .get(&staging_id)
+                                .and_then(|resource| resource.backing_iovecs.as_ref())
+                                .ok_or(RutabagaError::InvalidIovec)?
+                                .pin()?,
+                        ),
+                        None => None,
+                    };
+                    let limit = limit.unwrap_or(usize::MAX);
+                    let mut bufs = match &staging {
+                        Some(staging) => backed_slices_limited(staging, 0, limit),
+                        None => backed_slices_limited(&ring, offset + header_size, limit),
+                    };
+
+                    bytes_read = read_pipe.read_vectored(&mut bufs)?;
+                }
+
+                if bytes_read == 0 {
+                    cmd_read.hang_up = 1;
+                }
+
+                cmd_read.opaque_data_size =
+                    bytes_read.try_into().map_err(MesaError::TryFromIntError)?;
+                if limit.is_some() {
+                    let record_size =
+                        (header_size + bytes_read).next_multiple_of(CROSS_DOMAIN_READ_ALIGNMENT);
+                    cmd_read.hdr.cmd_size =
+                        record_size.try_into().map_err(MesaError::TryFromIntError)?;
+                }
+                copy_to_iovecs(&ring, offset, cmd_read.as_bytes());
+            }
+        }
+
+        Ok(bytes_read)
+    }
+}
+
+// Rings and staging blobs may be sparse, with only a backed prefix guests can read.  This returns
+// the length of that prefix.
+fn backed_len(iovecs: &[RutabagaIovec]) -> usize {
+    iovecs
+        .iter()
+        .take(CROSS_DOMAIN_MAX_READ_IOVECS)
+        .take_while(|iovec| !iovec.is_hole())
+        .map(|iovec| iovec.len)
+        .sum()
+}
+
+// Returns the backed prefix of `iovecs`, starting `offset` bytes in.
+fn backed_slices(iovecs: &[RutabagaIovec], offset: usize) -> Vec<IoSliceMut<'_>> {
+    backed_slices_limited(iovecs, offset, usize::MAX)
+}
+
+// Returns at most `limit` bytes of the backed prefix of `iovecs`, starting `offset` bytes in.
+fn backed_slices_limited(
+    iovecs: &[RutabagaIovec],
+    mut offset: usize,
+    mut limit: usize,
+) -> Vec<IoSliceMut<'_>> {
+    let mut slices = Vec::new();
+    for iovec in iovecs.iter().take_while(|iovec| !iovec.is_hole()) {
+        if limit == 0 {
+            break;
+        }
+
+        if offset >= iovec.len {
+            offset -= iovec.len;
+            continue;
+        }
+
+        let slice =
+            // SAFETY:
+            // Safe because the iovecs are attached and owned only by this context, and the
+            // context resources lock is held by the caller.
+            unsafe { std::slice::from_raw_parts_mut(iovec.base as *mut u8, iovec.len) };
+        let len = (iovec.len - offset).min(limit);
+        slices.push(IoSliceMut::new(&mut slice[offset..offset + len]));
+        offset = 0;
+        limit -= len;
+
+        if slices.len() == CROSS_DOMAIN_MAX_READ_IOVECS {
+            break;
+        }
+    }
+
+    slices
+}
+
+// Copies `data` into the backed prefix of `iovecs`, starting `offset` bytes in.  The caller checks
+// the prefix is large enough.
+fn copy_to_iovecs(iovecs: &[RutabagaIovec], offset: usize, mut data: &[u8]) {
+    for mut slice in backed_slices(iovecs, offset) {
+        if data.is_empty() {
+            break;
+        }
+
+        let len = slice.len().min(data.len());
+        slice[..len].copy_from_slice(&data[..len]);
+        data = &data[len..];
+    }
+}
+
+// Writes `cmd` followed by `opaque_data` to the backed prefix of `ring`, starting `offset` bytes
+// in.
+fn write_record(
+    ring: &[RutabagaIovec],
+    offset: usize,
+    cmd: &[u8],
+    opaque_data: &[u8],
+) -> RutabagaResult<()> {
+    if backed_len(ring) < offset + cmd.len() + opaque_data.len() {
+        return Err(RutabagaError::InvalidIovec);
+    }
+
+    copy_to_iovecs(ring, offset, cmd);
+    copy_to_iovecs(ring, offset + cmd.len(), opaque_data);
+    Ok(())
+}
+
+/// Writes a CROSS_DOMAIN_CMD_RECEIVE carrying `data` to the start of `ring`, as the worker does for
+/// every message from the host.  Only the copy is measured, without a channel or a context.
+#[cfg(feature = "benchmarks")]
+pub fn write_cross_domain_receive(
+    ring: &[RutabagaIovec],
+    channel_id: u32,
+    data: &[u8],
+) -> RutabagaResult<()> {
+    let mut cmd_receive: CrossDomainSendReceive = Default::default();
+    cmd_receive.hdr.cmd = CROSS_DOMAIN_CMD_RECEIVE;
+    cmd_receive.hdr.channel_id = channel_id;
+    cmd_receive.opaque_data_size = data
+        .len()
+        .try_into()
+        .map_err(|_| RutabagaError::InvalidCommandSize(data.len()))?;
+
+    write_record(ring, 0, cmd_receive.as_bytes(), data)
+}
+
+impl CrossDomainWorker {
+    fn new(
+        wait_ctx: WaitContext,
+        state: Arc<CrossDomainState>,
+        item_state: CrossDomainItemState,
+        fence_handler: RutabagaFenceHandler,
+        logger: RutabagaLogger,
+    ) -> CrossDomainWorker {
+        CrossDomainWorker {
+            wait_ctx,
+            state,
+            item_state,
+            fence_handler,
+            logger,
+            restarts: 0,
+            failed_fences: 0,
+        }
+    }
+
+    // Re-polls every channel and read pipe, in case the failure left any of them unregistered.
+    // Descriptors that hung up are left out, since polling them would only report the hang-up
+    // again, likely failing the same way.  Read pipes that hung up with nothing left to read are
+    // closed.
+    fn resync(&mut self) -> RutabagaResult<()> {
+        for (channel_id, channel) in self.state.channels.lock().unwrap().iter() {
+            let descriptor = channel.connection.as_borrowed_descriptor();
+            if poll_state(descriptor)?.0 {
+                let _ = self.wait_ctx.delete(descriptor);
+                self.logger.log(
+                    Level::Error,
+                    format_args!("channel {} hung up, no longer polling it", channel_id),
+                );
+                continue;
+            }
+
+            repoll(
+                &mut self.wait_ctx,
+                CROSS_DOMAIN_CHANNEL_ID_START + *channel_id as u64,
+                channel.connection.as_borrowed_descriptor(),
+            )?;
+        }
+
+        let mut items = self.item_state.lock().unwrap();
+        let mut closed = Vec::new();
+        for (item_id, item) in items.iter() {
+            if let CrossDomainItem::WaylandReadPipe(read_pipe) = item {
+                let descriptor = read_pipe.as_borrowed_descriptor();
+                match poll_state(descriptor)? {
+                    (true, false) => {
+                        let _ = self.wait_ctx.delete(descriptor);
+                        closed.push(item_id);
+                    }
+                    _ if self.state.pipe_paused(item_id) => (),
+                    _ => repoll(&mut self.wait_ctx, item_id as u64, descriptor)?,
+                }
+            }
+        }
+
+        for pipe_id in closed {
+            items.remove(pipe_id);
+            self.state.remove_pipe(pipe_id);
+        }
+
+        Ok(())
+    }
+
+    // Recovers from an error handling `fence`, rather than leaving the context wedged.  The guest
+    // is notified on the channel ring and `fence` is signaled so it reads the notification, or,
+    // if the guest predates the notification, `fence` is handled again.
+    fn restart(&mut self, fence: RutabagaFence, e: RutabagaError) -> RutabagaResult<()> {
+        if self.failed_fences >= CROSS_DOMAIN_MAX_WORKER_RESTARTS {
+            self.logger
+                .log(Level::Error, format_args!("Worker halting due to: {}", e));
+            // Tell the guest nothing more is coming, rather than leaving the ring wedged.
+            if self.state.guest_version >= CROSS_DOMAIN_VERSION_COMMAND_ERROR
+                && self
+                    .write_error(CROSS_DOMAIN_CMD_POLL, CROSS_DOMAIN_ERROR_WORKER_HALTED)
+                    .is_ok()
+            {
+                self.fence_handler.call(fence);
+            }
+            return Err(e);
+        }
+
+        self.failed_fences += 1;
+        self.restarts = self.restarts.saturating_add(1);
+        self.logger.log(
+            Level::Error,
+            format_args!("Worker restarting due to: {}", e),
+        );
+        self.resync()?;
+
+        // Older guests read whatever the channel ring holds once the fence signals.
+        if self.state.guest_version < CROSS_DOMAIN_VERSION_WORKER_RESTART {
+            self.state.requeue_job(CrossDomainJob::HandleFence(fence));
+            return Ok(());
+        }
+
+        let mut cmd_restart: CrossDomainWorkerRestart = Default::default();
+        cmd_restart.hdr.cmd = CROSS_DOMAIN_CMD_WORKER_RESTART;
+        cmd_restart.restarts = self.restarts;
+        self.state.write_to_ring(
+            RingWrite::Write(cmd_restart, None),
+            self.state.channel_ring_id,
+        )?;
+
+        self.fence_handler.call(fence);
+        Ok(())
+    }
+
+    // Adds the descriptor `file` received on a channel of `channel_type` as an item, and returns
+    // its id, CROSS_DOMAIN_ID_TYPE_* and size.
+    fn receive_item(
+        &self,
+        channel_type: u32,
+        file: OwnedDescriptor,
+    ) -> RutabagaResult<(u32, u32, u32)> {
+        // Determine the descriptor type and size
+        let desc_type = file
+            .determine_type()
+            .map_err(|e| RutabagaError::MesaError(e.into()))?;
+        match desc_type {
+            DescriptorType::Memory(size, handle_type) => {
+                let mesa_handle = MesaHandle {
+                    os_handle: file,
+                    handle_type,
+                };
+                let item_id = add_item(&self.item_state, CrossDomainItem::Blob(mesa_handle))?;
+                Ok((item_id, CROSS_DOMAIN_ID_TYPE_VIRTGPU_BLOB, size))
+            }
+            DescriptorType::WritePipe if channel_supports_pipes(channel_type) => {
+                let write_pipe = WritePipe::new(file.as_raw_descriptor());
+                std::mem::forget(file); // Prevent double-free since WritePipe now owns the descriptor
+                let item_id = add_item(
+                    &self.item_state,
+                    CrossDomainItem::WaylandWritePipe(write_pipe),
+                )?;
+                Ok((item_id, CROSS_DOMAIN_ID_TYPE_WRITE_PIPE, 0))
+            }
+            _ => Err(RutabagaError::InvalidCrossDomainItemType),
+        }
+    }
+
+    // Stops polling the channel `channel_id` and closes it.  An empty message is forwarded to its
+    // ring, as when the other end hangs up.
+    fn close_channel(&mut self, channel_id: u32) -> RutabagaResult<()> {
+        let channel = self
+            .state
+            .channels
+            .lock()
+            .unwrap()
+            .remove(&channel_id)
+            .ok_or(RutabagaError::InvalidCrossDomainChannel)?;
+        let _ = self
+            .wait_ctx
+            .delete(channel.connection.as_borrowed_descriptor());
+
+        let mut cmd_receive: CrossDomainSendReceive = Default::default();
+        cmd_receive.hdr.cmd = CROSS_DOMAIN_CMD_RECEIVE;
+        cmd_receive.hdr.channel_id = channel_id;
+        self.state
+            .write_to_ring(RingWrite::Write(cmd_receive, None), channel.ring_id)?;
+        Ok(())
+    }
+
+    fn write_error(&self, cmd: u8, error: u32) -> RutabagaResult<()> {
+        let mut cmd_error: CrossDomainError = Default::default();
+        cmd_error.hdr.cmd = CROSS_DOMAIN_CMD_ERROR;
+        cmd_error.cmd = cmd.into();
+        cmd_error.error = error;
+        self.state.write_to_ring(
+            RingWrite::Write(cmd_error, None),
+            self.state.channel_ring_id,
+        )?;
+        Ok(())
+    }
 
-        let iovecs = resource
-            .backing_iovecs
-            .as_mut()
-            .ok_or(RutabagaError::InvalidIovec)?;
-        let slice =
-            // SAFETY:
-            // Safe because we've verified the iovecs are attached and owned only by this context.
-            unsafe { std::slice::from_raw_parts_mut(iovecs[0].base as *mut u8, iovecs[0].len) };
+    // Forwards a message received on the channel `channel_id` to the channel's ring.
+    fn receive_channel(
+        &mut self,
+        channel_id: u32,
+        receive_buf: &mut Vec<u8>,
+    ) -> RutabagaResult<()> {
+        let channel = self.state.channel(channel_id)?;
+
+        // Messages are as large as the channel ring allows, which the guest may resize at any
+        // time.
+        let ring_capacity = self.state.ring_capacity(channel.ring_id)?;
+        receive_buf.resize(
+            ring_capacity.saturating_sub(size_of::<CrossDomainSendReceive>()),
+            0,
+        );
+
+        let (len, files) = self.state.receive_msg(&channel, receive_buf)?;
+        // Check up front, so a message is never forwarded with only some of its descriptors.
+        self.item_state
+            .lock()
+            .unwrap()
+            .descriptors
+            .check(files.len())?;
 
-        match ring_write {
-            RingWrite::Write(cmd, opaque_data_opt) => {
-                if slice.len() < size_of::<T>() {
-                    return Err(RutabagaError::InvalidIovec);
-                }
-                let (cmd_slice, opaque_data_slice) = slice.split_at_mut(size_of::<T>());
-                cmd_slice.copy_from_slice(cmd.as_bytes());
-                if let Some(opaque_data) = opaque_data_opt {
-                    if opaque_data_slice.len() < opaque_data.len() {
-                        return Err(RutabagaError::InvalidIovec);
-                    }
-                    opaque_data_slice[..opaque_data.len()].copy_from_slice(opaque_data);
-                }
-            }
-            RingWrite::WriteFromPipe(mut cmd_read, ref mut read_pipe, readable) => {
-                if slice.len() < size_of::<CrossDomainReadWrite>() {
-                    return Err(RutabagaError::InvalidIovec);
-                }
+        let mut cmd_receive: CrossDomainSendReceive = Default::default();
 
-                let (cmd_slice, opaque_data_slice) =
-                    slice.split_at_mut(size_of::<CrossDomainReadWrite>());
+        let num_files = files.len();
+        cmd_receive.hdr.cmd = CROSS_DOMAIN_CMD_RECEIVE;
+        cmd_receive.hdr.channel_id = channel_id;
+        cmd_receive.num_identifiers = files
+            .len()
+            .try_into()
+            .map_err(|_| RutabagaError::InvalidCommandSize(files.len()))?;
+        cmd_receive.opaque_data_size = len
+            .try_into()
+            .map_err(|_| RutabagaError::InvalidCommandSize(len))?;
 
-                if readable {
-                    bytes_read = read_pipe.read(opaque_data_slice)?;
+        let iter = cmd_receive
+            .identifiers
+            .iter_mut()
+            .zip(cmd_receive.identifier_types.iter_mut())
+            .zip(cmd_receive.identifier_sizes.iter_mut())
+            .zip(files)
+            .take(num_files);
+
+        let mut added = Vec::new();
+        for (((identifier, identifier_type), identifier_size), file) in iter {
+            match self.receive_item(channel.channel_type, file) {
+                Ok((item_id, item_type, item_size)) => {
+                    *identifier = item_id;
+                    *identifier_type = item_type;
+                    *identifier_size = item_size;
+                    added.push(item_id);
                 }
-
-                if bytes_read == 0 {
-                    cmd_read.hang_up = 1;
+                Err(e) => {
+                    // The guest never learns of the items of a message that isn't forwarded.
+                    let mut items = self.item_state.lock().unwrap();
+                    for item_id in added {
+                        items.remove(item_id);
+                    }
+                    return Err(e);
                 }
-
-                cmd_read.opaque_data_size =
-                    bytes_read.try_into().map_err(MesaError::TryFromIntError)?;
-                cmd_slice.copy_from_slice(cmd_read.as_bytes());
             }
         }
 
-        Ok(bytes_read)
-    }
-}
+        self.state.write_to_ring(
+            RingWrite::Write(cmd_receive, Some(&receive_buf[0..len])),
+            channel.ring_id,
+        )?;
 
-impl CrossDomainWorker {
-    fn new(
-        wait_ctx: WaitContext,
-        state: Arc<CrossDomainState>,
-        item_state: CrossDomainItemState,
-        fence_handler: RutabagaFenceHandler,
-    ) -> CrossDomainWorker {
-        CrossDomainWorker {
-            wait_ctx,
-            state,
-            item_state,
-            fence_handler,
-        }
+        Ok(())
     }
 
     // Handles the fence according the the token according to the event token.  On success, a
@@ -306,8 +1619,15 @@ impl CrossDomainWorker {
         &mut self,
         fence: RutabagaFence,
         thread_resample_evt: &Event,
-        receive_buf: &mut [u8],
+        receive_buf: &mut Vec<u8>,
     ) -> RutabagaResult<()> {
+        // Failed commands are reported ahead of channel messages, one per fence.
+        if let Some((cmd, error)) = self.state.take_error() {
+            self.write_error(cmd, error)?;
+            self.fence_handler.call(fence);
+            return Ok(());
+        }
+
         let events = self.wait_ctx.wait(WaitTimeout::NoTimeout)?;
 
         // The worker thread must:
@@ -324,66 +1644,32 @@ impl CrossDomainWorker {
         // a new fence before we can resume polling.
         //
         // The CrossDomainJob queue guarantees a new fence has been generated before polling is
-        // resumed.
+        // resumed.  Guests that enable batched reads get the data of every ready read pipe with
+        // one fence instead, see read_pipes().
         if let Some(event) = events.first() {
             match event.connection_id {
-                CROSS_DOMAIN_CONTEXT_CHANNEL_ID => {
-                    let (len, files) = self.state.receive_msg(receive_buf)?;
-                    let mut cmd_receive: CrossDomainSendReceive = Default::default();
-
-                    let num_files = files.len();
-                    cmd_receive.hdr.cmd = CROSS_DOMAIN_CMD_RECEIVE;
-                    cmd_receive.num_identifiers = files
-                        .len()
-                        .try_into()
-                        .map_err(|_| RutabagaError::InvalidCommandSize(files.len()))?;
-                    cmd_receive.opaque_data_size = len
+                id if id >= CROSS_DOMAIN_CHANNEL_ID_START => {
+                    let channel_id: u32 = (id - CROSS_DOMAIN_CHANNEL_ID_START)
                         .try_into()
-                        .map_err(|_| RutabagaError::InvalidCommandSize(len))?;
-
-                    let iter = cmd_receive
-                        .identifiers
-                        .iter_mut()
-                        .zip(cmd_receive.identifier_types.iter_mut())
-                        .zip(cmd_receive.identifier_sizes.iter_mut())
-                        .zip(files)
-                        .take(num_files);
-
-                    for (((identifier, identifier_type), identifier_size), file) in iter {
-                        // Determine the descriptor type and size
-                        let desc_type = file
-                            .determine_type()
-                            .map_err(|e| RutabagaError::MesaError(e.into()))?;
-                        match desc_type {
-                            DescriptorType::Memory(size, handle_type) => {
-                                *identifier_type = CROSS_DOMAIN_ID_TYPE_VIRTGPU_BLOB;
-                                *identifier_size = size;
-
-                                let mesa_handle = MesaHandle {
-                                    os_handle: file,
-                                    handle_type,
-                                };
-                                *identifier =
-                                    add_item(&self.item_state, CrossDomainItem::Blob(mesa_handle));
-                            }
-                            DescriptorType::WritePipe => {
-                                *identifier_type = CROSS_DOMAIN_ID_TYPE_WRITE_PIPE;
-                                *identifier_size = 0;
-                                let write_pipe = WritePipe::new(file.as_raw_descriptor());
-                                std::mem::forget(file); // Prevent double-free since WritePipe now owns the descriptor
-                                *identifier = add_item(
-                                    &self.item_state,
-                                    CrossDomainItem::WaylandWritePipe(write_pipe),
-                                );
-                            }
-                            _ => return Err(RutabagaError::InvalidCrossDomainItemType),
+                        .map_err(MesaError::TryFromIntError)?;
+                    // Running out of descriptors is reported to the guest rather than halting the
+                    // worker, since it may recover once the guest releases some.  The message was
+                    // already taken from the channel, and its stream can't go on without it, so
+                    // only the channel is closed.
+                    match self.receive_channel(channel_id, receive_buf) {
+                        Err(e) if descriptors_exhausted(&e) => {
+                            self.logger.log(
+                                Level::Error,
+                                format_args!("closing channel {}: {}", channel_id, e),
+                            );
+                            self.close_channel(channel_id)?;
+                            self.state.write_command_error(
+                                CROSS_DOMAIN_CMD_RECEIVE,
+                                CROSS_DOMAIN_ERROR_DESCRIPTOR_LIMIT,
+                            )?;
                         }
+                        result => result?,
                     }
-
-                    self.state.write_to_ring(
-                        RingWrite::Write(cmd_receive, Some(&receive_buf[0..len])),
-                        self.state.channel_ring_id,
-                    )?;
                     self.fence_handler.call(fence);
                 }
                 CROSS_DOMAIN_RESAMPLE_ID => {
@@ -404,52 +1690,130 @@ impl CrossDomainWorker {
                     self.fence_handler.call(fence);
                 }
                 _ => {
-                    let mut items = self.item_state.lock().unwrap();
-                    let mut cmd_read: CrossDomainReadWrite = Default::default();
-                    let pipe_id: u32 = event
-                        .connection_id
-                        .try_into()
-                        .map_err(MesaError::TryFromIntError)?;
-                    let bytes_read;
-
-                    cmd_read.hdr.cmd = CROSS_DOMAIN_CMD_READ;
-                    cmd_read.identifier = pipe_id;
-
-                    let item = items
-                        .table
-                        .get_mut(&pipe_id)
-                        .ok_or(RutabagaError::InvalidCrossDomainItemId)?;
-
-                    match item {
-                        CrossDomainItem::WaylandReadPipe(ref mut readpipe) => {
-                            let ring_write =
-                                RingWrite::WriteFromPipe(cmd_read, readpipe, event.readable);
-                            bytes_read = self.state.write_to_ring::<CrossDomainReadWrite>(
-                                ring_write,
-                                self.state.channel_ring_id,
-                            )?;
+                    self.read_pipes(&events)?;
+                    self.fence_handler.call(fence);
+                }
+            }
+        }
 
-                            // Zero bytes read indicates end-of-file on POSIX.
-                            if event.hung_up && bytes_read == 0 {
-                                self.wait_ctx.delete(readpipe.as_borrowed_descriptor())?;
-                            }
-                        }
-                        _ => return Err(RutabagaError::InvalidCrossDomainItemType),
-                    }
+        Ok(())
+    }
 
-                    if event.hung_up && bytes_read == 0 {
-                        items.table.remove(&pipe_id);
-                    }
+    // Writes the data of the ready read pipes among `events` to the channel ring, or only of the
+    // first one unless the guest enabled batched reads.  Batching keeps the order of each pipe's
+    // data, as every pipe is reported once per wait and pipes are independent streams.  Channel
+    // messages may refer to pipes, so they're never batched with pipe data.
+    fn read_pipes(&mut self, events: &[WaitEvent]) -> RutabagaResult<()> {
+        let mut pipe_events = events.iter().filter(|event| {
+            ![CROSS_DOMAIN_RESAMPLE_ID, CROSS_DOMAIN_KILL_ID].contains(&event.connection_id)
+                && event.connection_id < CROSS_DOMAIN_CHANNEL_ID_START
+        });
+
+        if !self.state.batches_reads() {
+            if let Some(event) = pipe_events.next() {
+                self.read_pipe(event, 0, None)?;
+            }
+            return Ok(());
+        }
 
-                    self.fence_handler.call(fence);
+        let header_size = size_of::<CrossDomainReadWrite>();
+        let capacity = self.state.ring_capacity(self.state.channel_ring_id)?;
+        // Room is kept for the header ending the batch.
+        let end = capacity.saturating_sub(size_of::<CrossDomainHeader>());
+        let max_record =
+            u16::MAX as usize / CROSS_DOMAIN_READ_ALIGNMENT * CROSS_DOMAIN_READ_ALIGNMENT;
+
+        let mut offset = 0;
+        for event in pipe_events {
+            let room = end.saturating_sub(offset).min(max_record) / CROSS_DOMAIN_READ_ALIGNMENT
+                * CROSS_DOMAIN_READ_ALIGNMENT;
+            // Reading into no room at all would look like the pipe hung up.
+            let limit = room.saturating_sub(header_size);
+            if limit == 0 {
+                break;
+            }
+
+            match self.read_pipe(event, offset, Some(limit)) {
+                Ok(record_size) => offset += record_size,
+                // The data of the pipes already read is gone from them, so the batch ends before
+                // the failing pipe and still reaches the guest.  The pipe stays ready, so it fails
+                // again with a later fence unless the failure was transient.
+                Err(e) if offset > 0 => {
+                    self.logger
+                        .log(Level::Error, format_args!("ending read batch early: {}", e));
+                    break;
                 }
+                Err(e) => return Err(e),
             }
         }
 
+        if offset <= end {
+            let ring_write = RingWrite::Write(CrossDomainHeader::default(), None);
+            self.state
+                .write_to_ring_at(ring_write, self.state.channel_ring_id, offset)?;
+        }
+
         Ok(())
     }
 
-    fn run(&mut self, thread_kill_evt: Event, thread_resample_evt: Event) -> RutabagaResult<()> {
+    // Reads the pipe of `event` into a CROSS_DOMAIN_CMD_READ record `offset` bytes into the
+    // channel ring, reading at most `limit` bytes if batched.  Returns the size of the record.
+    fn read_pipe(
+        &mut self,
+        event: &WaitEvent,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> RutabagaResult<usize> {
+        let mut items = self.item_state.lock().unwrap();
+        let mut cmd_read: CrossDomainReadWrite = Default::default();
+        let pipe_id: u32 = event
+            .connection_id
+            .try_into()
+            .map_err(MesaError::TryFromIntError)?;
+        let bytes_read;
+
+        cmd_read.hdr.cmd = CROSS_DOMAIN_CMD_READ;
+        cmd_read.identifier = pipe_id;
+
+        let item = items
+            .get_mut(pipe_id)
+            .ok_or(RutabagaError::InvalidCrossDomainItemId)?;
+
+        match item {
+            CrossDomainItem::WaylandReadPipe(ref mut readpipe) => {
+                let ring_write =
+                    RingWrite::WriteFromPipe(cmd_read, readpipe, event.readable, limit);
+                bytes_read = self.state.write_to_ring_at::<CrossDomainReadWrite>(
+                    ring_write,
+                    self.state.channel_ring_id,
+                    offset,
+                )?;
+
+                // Zero bytes read indicates end-of-file on POSIX.
+                if event.hung_up && bytes_read == 0 {
+                    self.wait_ctx.delete(readpipe.as_borrowed_descriptor())?;
+                } else if self.state.pipe_read(pipe_id, bytes_read) {
+                    // The guest's acknowledgment of this data resumes polling.
+                    self.wait_ctx.delete(readpipe.as_borrowed_descriptor())?;
+                }
+            }
+            _ => return Err(RutabagaError::InvalidCrossDomainItemType),
+        }
+
+        if event.hung_up && bytes_read == 0 {
+            items.remove(pipe_id);
+            self.state.remove_pipe(pipe_id);
+        }
+
+        Ok((size_of::<CrossDomainReadWrite>() + bytes_read)
+            .next_multiple_of(CROSS_DOMAIN_READ_ALIGNMENT))
+    }
+
+    fn add_events(
+        &mut self,
+        thread_kill_evt: &Event,
+        thread_resample_evt: &Event,
+    ) -> RutabagaResult<()> {
         self.wait_ctx.add(
             CROSS_DOMAIN_RESAMPLE_ID,
             thread_resample_evt.as_borrowed_descriptor(),
@@ -458,74 +1822,213 @@ impl CrossDomainWorker {
             CROSS_DOMAIN_KILL_ID,
             thread_kill_evt.as_borrowed_descriptor(),
         )?;
-        let mut receive_buf: Vec<u8> = vec![0; CROSS_DOMAIN_MAX_SEND_RECV_SIZE];
+        Ok(())
+    }
+
+    fn run(&mut self, thread_kill_evt: Event, thread_resample_evt: Event) -> RutabagaResult<()> {
+        self.add_events(&thread_kill_evt, &thread_resample_evt)?;
+        let mut receive_buf: Vec<u8> = Vec::new();
 
         while let Some(job) = self.state.wait_for_job() {
-            match job {
-                CrossDomainJob::HandleFence(fence) => {
-                    match self.handle_fence(fence, &thread_resample_evt, &mut receive_buf) {
-                        Ok(()) => (),
-                        Err(e) => {
-                            error!("Worker halting due to: {}", e);
-                            return Err(e);
-                        }
-                    }
+            if self.handle_job(job, &thread_resample_evt, &mut receive_buf)? {
+                return Ok(());
+            }
+        }
+
+        Ok(())
+    }
+
+    // Runs at most one job without blocking, for the deterministic executor.  A fence job is put
+    // back until one of the polled descriptors is ready.
+    #[cfg(feature = "deterministic")]
+    fn step(
+        &mut self,
+        thread_resample_evt: &Event,
+        receive_buf: &mut Vec<u8>,
+    ) -> RutabagaResult<RutabagaWorkerStatus> {
+        let Some(job) = self.state.try_job() else {
+            return Ok(RutabagaWorkerStatus::Finished);
+        };
+
+        let job = match job {
+            Some(CrossDomainJob::HandleFence(fence)) => {
+                if !self.state.has_errors()
+                    && self
+                        .wait_ctx
+                        .wait(WaitTimeout::Finite(Duration::ZERO))?
+                        .is_empty()
+                {
+                    self.state.requeue_job(CrossDomainJob::HandleFence(fence));
+                    return Ok(RutabagaWorkerStatus::Idle);
                 }
-                CrossDomainJob::AddReadPipe(read_pipe_id) => {
-                    let items = self.item_state.lock().unwrap();
-                    let item = items
-                        .table
-                        .get(&read_pipe_id)
-                        .ok_or(RutabagaError::InvalidCrossDomainItemId)?;
-
-                    match item {
-                        CrossDomainItem::WaylandReadPipe(read_pipe) => self
-                            .wait_ctx
-                            .add(read_pipe_id as u64, read_pipe.as_borrowed_descriptor())?,
-                        _ => return Err(RutabagaError::InvalidCrossDomainItemType),
-                    }
+
+                CrossDomainJob::HandleFence(fence)
+            }
+            Some(job) => job,
+            None => return Ok(RutabagaWorkerStatus::Idle),
+        };
+
+        match self.handle_job(job, thread_resample_evt, receive_buf)? {
+            true => Ok(RutabagaWorkerStatus::Finished),
+            false => Ok(RutabagaWorkerStatus::Progress),
+        }
+    }
+
+    // Returns true if the worker should exit.
+    fn handle_job(
+        &mut self,
+        job: CrossDomainJob,
+        thread_resample_evt: &Event,
+        receive_buf: &mut Vec<u8>,
+    ) -> RutabagaResult<bool> {
+        match job {
+            CrossDomainJob::HandleFence(fence) => {
+                match self.handle_fence(fence, thread_resample_evt, receive_buf) {
+                    Ok(()) => self.failed_fences = 0,
+                    Err(e) => self.restart(fence, e)?,
+                }
+            }
+            // Also resumes pipes paused by flow control.
+            CrossDomainJob::AddReadPipe(read_pipe_id) => {
+                let items = self.item_state.lock().unwrap();
+                let item = items
+                    .get(read_pipe_id)
+                    .ok_or(RutabagaError::InvalidCrossDomainItemId)?;
+
+                // A restart may have polled the pipe already.
+                match item {
+                    CrossDomainItem::WaylandReadPipe(read_pipe) => repoll(
+                        &mut self.wait_ctx,
+                        read_pipe_id as u64,
+                        read_pipe.as_borrowed_descriptor(),
+                    )?,
+                    _ => return Err(RutabagaError::InvalidCrossDomainItemType),
                 }
-                CrossDomainJob::Finish => return Ok(()),
             }
+            CrossDomainJob::AddChannel(channel_id) => {
+                let channel = self.state.channel(channel_id)?;
+                repoll(
+                    &mut self.wait_ctx,
+                    CROSS_DOMAIN_CHANNEL_ID_START + channel_id as u64,
+                    channel.connection.as_borrowed_descriptor(),
+                )?;
+            }
+            CrossDomainJob::Finish => return Ok(true),
         }
 
-        Ok(())
+        Ok(false)
     }
 }
 
 impl CrossDomain {
     /// Initializes the cross-domain component by taking the the rutabaga paths (if any) and
-    /// initializing rutabaga gralloc.
+    /// initializing rutabaga gralloc with `gralloc_policy`.  If `wayland_trace` is set, Wayland
+    /// messages passing over context channels are logged.  If `strict` is set, commands the
+    /// component doesn't advertise in its capset are rejected.  Items of all contexts may hold at
+    /// most `descriptor_limit` host descriptors, if set.
     pub fn init(
         paths: Option<Vec<RutabagaPath>>,
         fence_handler: RutabagaFenceHandler,
+        thread_config: RutabagaThreadConfig,
+        wayland_trace: bool,
+        gralloc_policy: RutabagaGrallocPolicy,
+        strict: bool,
+        descriptor_limit: Option<usize>,
     ) -> RutabagaResult<Box<dyn RutabagaComponent>> {
-        let gralloc = RutabagaGralloc::new(RutabagaGrallocBackendFlags::new())?;
+        let mut gralloc = RutabagaGralloc::new(RutabagaGrallocBackendFlags::new())?;
+        gralloc.set_policy(gralloc_policy);
         Ok(Box::new(CrossDomain {
             paths,
             gralloc: Arc::new(Mutex::new(gralloc)),
             fence_handler,
+            thread_config,
+            wayland_trace,
+            strict,
+            descriptors: Arc::new(CrossDomainDescriptors::new(descriptor_limit)),
+            logger: RutabagaLogger::new(RutabagaComponentType::CrossDomain, None),
+            trace: Default::default(),
+            resource_names: Default::default(),
         }))
     }
+
+    // Returns the commands guests may send, as a mask of `1 << cmd` bits.
+    fn supported_commands(&self) -> u32 {
+        let mut commands = vec![
+            CROSS_DOMAIN_CMD_INIT,
+            CROSS_DOMAIN_CMD_GET_IMAGE_REQUIREMENTS,
+            CROSS_DOMAIN_CMD_SET_STAGING,
+            CROSS_DOMAIN_CMD_DEBUG_MARKER,
+            CROSS_DOMAIN_CMD_SET_RESOURCE_NAME,
+        ];
+
+        if self.gralloc.lock().unwrap().supports_dmabuf() {
+            commands.extend([
+                CROSS_DOMAIN_CMD_GET_IMAGE_REQUIREMENTS_WITH_MODIFIERS,
+                CROSS_DOMAIN_CMD_QUERY_MODIFIERS,
+            ]);
+        }
+
+        // Channel commands are useless without a path to connect to.
+        let has_channels = self.paths.as_ref().is_some_and(|paths| {
+            paths
+                .iter()
+                .any(|path| path_channel_type(path.path_type).is_some())
+        });
+        if has_channels {
+            commands.extend([
+                CROSS_DOMAIN_CMD_POLL,
+                CROSS_DOMAIN_CMD_SEND,
+                CROSS_DOMAIN_CMD_WRITE,
+                CROSS_DOMAIN_CMD_OPEN_CHANNEL,
+                CROSS_DOMAIN_CMD_READ_ACK,
+                CROSS_DOMAIN_CMD_SET_READ_BATCHING,
+            ]);
+        }
+
+        commands.iter().fold(0, |mask, cmd| mask | (1 << cmd))
+    }
 }
 
 impl CrossDomainContext {
-    fn get_connection(&mut self, cmd_init: &CrossDomainInit) -> RutabagaResult<Tube> {
-        let paths = self
+    fn connect_channel(
+        &self,
+        channel_type: u32,
+        channel_ring_id: u32,
+    ) -> RutabagaResult<CrossDomainChannel> {
+        if !self
+            .context_resources
+            .lock()
+            .unwrap()
+            .contains_key(&channel_ring_id)
+        {
+            return Err(RutabagaError::InvalidResourceId);
+        }
+
+        let path_type =
+            channel_path_type(channel_type).ok_or(RutabagaError::InvalidCrossDomainChannel)?;
+        let path = &self
             .paths
-            .take()
-            .ok_or(RutabagaError::InvalidCrossDomainChannel)?;
-        let path = &paths
+            .as_ref()
+            .ok_or(RutabagaError::InvalidCrossDomainChannel)?
             .iter()
-            .find(|path| path.path_type == cmd_init.channel_type)
+            .find(|path| path.path_type == path_type)
             .ok_or(RutabagaError::InvalidCrossDomainChannel)?
             .path;
 
-        let tube = Tube::new(path.clone(), TubeType::Stream)?;
-        Ok(tube)
+        let connection = Tube::new(path.clone(), TubeType::Stream)?;
+        Ok(CrossDomainChannel {
+            channel_type,
+            ring_id: channel_ring_id,
+            connection,
+            wayland_trace: self.wayland_trace && channel_type == CROSS_DOMAIN_CHANNEL_TYPE_WAYLAND,
+        })
     }
 
     fn initialize(&mut self, cmd_init: &CrossDomainInit) -> RutabagaResult<()> {
+        if self.state.is_some() {
+            return Err(RutabagaError::InvalidCrossDomainState);
+        }
+
         if !self
             .context_resources
             .lock()
@@ -535,22 +2038,18 @@ impl CrossDomainContext {
             return Err(RutabagaError::InvalidResourceId);
         }
 
+        let handle_types = cross_domain_handle_types(&self.gralloc.lock().unwrap())
+            .negotiate(cmd_init.import_handle_types)?;
+
         let query_ring_id = cmd_init.query_ring_id;
         let channel_ring_id = cmd_init.channel_ring_id;
         let context_resources = self.context_resources.clone();
 
+        let guest_version = init_guest_version(cmd_init);
+
         // Zero means no requested channel.
         if cmd_init.channel_type != 0 {
-            if !self
-                .context_resources
-                .lock()
-                .unwrap()
-                .contains_key(&cmd_init.channel_ring_id)
-            {
-                return Err(RutabagaError::InvalidResourceId);
-            }
-
-            let connection = self.get_connection(cmd_init)?;
+            let channel = self.connect_channel(cmd_init.channel_type, channel_ring_id)?;
 
             let kill_evt = Event::new()?;
             let thread_kill_evt = kill_evt.try_clone()?;
@@ -560,34 +2059,27 @@ impl CrossDomainContext {
 
             let mut wait_ctx = WaitContext::new()?;
             wait_ctx.add(
-                CROSS_DOMAIN_CONTEXT_CHANNEL_ID,
-                connection.as_borrowed_descriptor(),
+                CROSS_DOMAIN_CHANNEL_ID_START + CROSS_DOMAIN_CHANNEL_DEFAULT as u64,
+                channel.connection.as_borrowed_descriptor(),
             )?;
 
             let state = Arc::new(CrossDomainState::new(
                 query_ring_id,
                 channel_ring_id,
+                guest_version,
                 context_resources,
-                Some(connection),
             ));
+            state.add_channel(CROSS_DOMAIN_CHANNEL_DEFAULT, channel)?;
+
+            let worker = CrossDomainWorker::new(
+                wait_ctx,
+                state.clone(),
+                self.item_state.clone(),
+                self.fence_handler.clone(),
+                self.logger.clone(),
+            );
 
-            let thread_state = state.clone();
-            let thread_items = self.item_state.clone();
-            let thread_fence_handler = self.fence_handler.clone();
-
-            let worker_result = thread::Builder::new()
-                .name("cross domain".to_string())
-                .spawn(move || -> RutabagaResult<()> {
-                    CrossDomainWorker::new(
-                        wait_ctx,
-                        thread_state,
-                        thread_items,
-                        thread_fence_handler,
-                    )
-                    .run(thread_kill_evt, thread_resample_evt)
-                });
-
-            self.worker_thread = Some(worker_result.unwrap());
+            self.worker_thread = self.start_worker(worker, thread_kill_evt, thread_resample_evt)?;
             self.state = Some(state);
             self.resample_evt = Some(resample_evt);
             self.kill_evt = Some(kill_evt);
@@ -595,56 +2087,222 @@ impl CrossDomainContext {
             self.state = Some(Arc::new(CrossDomainState::new(
                 query_ring_id,
                 channel_ring_id,
+                guest_version,
                 context_resources,
-                None,
             )));
         }
 
+        // Guests that predate negotiation don't expect a response.
+        if cmd_init.import_handle_types != 0 {
+            let response = CrossDomainInitResponse {
+                handle_types,
+                ..Default::default()
+            };
+            let state = self
+                .state
+                .as_ref()
+                .ok_or(RutabagaError::InvalidCrossDomainState)?;
+            state.write_to_ring(RingWrite::Write(response, None), query_ring_id)?;
+        }
+
+        Ok(())
+    }
+
+    // Reports a command rejected in strict mode to the guest.
+    fn reject_command(&self, cmd: u8, error: u32) -> RutabagaResult<()> {
+        self.state
+            .as_ref()
+            .ok_or(RutabagaError::InvalidCrossDomainState)?
+            .write_command_error(cmd, error)
+    }
+
+    // Runs the worker on a new thread, or on the deterministic executor if one is configured.
+    fn start_worker(
+        &self,
+        mut worker: CrossDomainWorker,
+        thread_kill_evt: Event,
+        thread_resample_evt: Event,
+    ) -> RutabagaResult<Option<thread::JoinHandle<RutabagaResult<()>>>> {
+        #[cfg(feature = "deterministic")]
+        if let Some(executor) = self.thread_config.executor() {
+            // The kill event only interrupts blocking waits, which stepped workers never do.
+            worker.add_events(&thread_kill_evt, &thread_resample_evt)?;
+            let mut receive_buf: Vec<u8> = Vec::new();
+            executor.add_worker(move || worker.step(&thread_resample_evt, &mut receive_buf));
+            return Ok(None);
+        }
+
+        let thread_config = self.thread_config.clone();
+        let worker_result = thread_config
+            .builder(RutabagaThreadType::CrossDomainWorker)
+            .spawn(move || -> RutabagaResult<()> {
+                thread_config.apply(RutabagaThreadType::CrossDomainWorker);
+                thread_config.confine()?;
+                worker.run(thread_kill_evt, thread_resample_evt)
+            });
+
+        Ok(Some(worker_result.unwrap()))
+    }
+
+    fn run_command(&mut self, command: CrossDomainCommand) -> RutabagaResult<()> {
+        match command {
+            CrossDomainCommand::Init(cmd_init) => self.initialize(&cmd_init),
+            CrossDomainCommand::GetImageRequirements(cmd_get_reqs, modifiers) => {
+                self.get_image_requirements(&cmd_get_reqs, &modifiers)
+            }
+            CrossDomainCommand::Send(cmd_send, opaque_data) => self.send(&cmd_send, opaque_data),
+            // Actual polling is done in the subsequent when creating a fence.
+            CrossDomainCommand::Poll => Ok(()),
+            CrossDomainCommand::Write(cmd_write, opaque_data) => {
+                self.write(&cmd_write, opaque_data)
+            }
+            CrossDomainCommand::SetStaging(cmd_set_staging) => self.set_staging(&cmd_set_staging),
+            CrossDomainCommand::OpenChannel(cmd_open) => self.open_channel(&cmd_open),
+            CrossDomainCommand::QueryModifiers(cmd_query) => self.query_modifiers(&cmd_query),
+            CrossDomainCommand::ReadAck(cmd_ack) => self.read_ack(&cmd_ack),
+            CrossDomainCommand::SetReadBatching(cmd_batching) => {
+                self.set_read_batching(&cmd_batching)
+            }
+            CrossDomainCommand::DebugMarker(cmd_marker, label) => {
+                self.debug_marker(&cmd_marker, label)
+            }
+            CrossDomainCommand::SetResourceName(cmd_name, name) => {
+                self.set_resource_name(&cmd_name, name)
+            }
+        }
+    }
+
+    // Reports the failure of `cmd` on the channel ring, and wakes the worker to write it on the
+    // next channel ring fence.  Contexts without a channel have nowhere to report it.
+    fn report_error(&mut self, cmd: u8, e: &RutabagaError) -> RutabagaResult<()> {
+        if let (Some(state), Some(ref mut resample_evt)) = (&self.state, &mut self.resample_evt) {
+            if state.add_error(cmd, command_error_code(e)) {
+                resample_evt.signal()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn open_channel(&mut self, cmd_open: &CrossDomainOpenChannel) -> RutabagaResult<()> {
+        let channel = self.connect_channel(cmd_open.channel_type, cmd_open.channel_ring_id)?;
+
+        if let (Some(state), Some(ref mut resample_evt)) = (&self.state, &mut self.resample_evt) {
+            state.add_channel(cmd_open.channel_id, channel)?;
+            state.add_job(CrossDomainJob::AddChannel(cmd_open.channel_id));
+            resample_evt.signal()?;
+        } else {
+            return Err(RutabagaError::InvalidCrossDomainState);
+        }
+
+        Ok(())
+    }
+
+    fn query_modifiers(&mut self, cmd_query: &CrossDomainQueryModifiers) -> RutabagaResult<()> {
+        let modifiers = self.gralloc.lock().unwrap().query_modifiers(
+            DrmFormat::from(cmd_query.drm_format),
+            RutabagaGrallocFlags::new(cmd_query.flags),
+        )?;
+
+        let mut response = CrossDomainModifiers {
+            drm_format: cmd_query.drm_format,
+            ..Default::default()
+        };
+        for (slot, modifier) in response.modifiers.iter_mut().zip(&modifiers) {
+            *slot = *modifier;
+            response.num_modifiers += 1;
+        }
+
+        let state = self
+            .state
+            .as_ref()
+            .ok_or(RutabagaError::InvalidCrossDomainState)?;
+        state.write_to_ring(RingWrite::Write(response, None), state.query_ring_id)?;
         Ok(())
     }
 
     fn get_image_requirements(
         &mut self,
         cmd_get_reqs: &CrossDomainGetImageRequirements,
+        modifiers: &[u64],
     ) -> RutabagaResult<()> {
-        let info = ImageAllocationInfo {
-            width: cmd_get_reqs.width,
-            height: cmd_get_reqs.height,
-            drm_format: DrmFormat::from(cmd_get_reqs.drm_format),
-            flags: RutabagaGrallocFlags::new(cmd_get_reqs.flags),
-        };
-
-        let reqs = self
-            .gralloc
+        let state = self
+            .state
+            .as_ref()
+            .ok_or(RutabagaError::InvalidCrossDomainState)?;
+
+        let key = (
+            cmd_get_reqs.width,
+            cmd_get_reqs.height,
+            cmd_get_reqs.drm_format,
+            cmd_get_reqs.flags,
+            modifiers.to_vec(),
+        );
+
+        // Compositors may query the same requirements every frame.
+        let cached = self
+            .item_state
             .lock()
             .unwrap()
-            .get_image_memory_requirements(info)?;
+            .cached_image_requirements(&key);
+
+        let (blob_id, reqs) = match cached {
+            Some(cached) => cached,
+            None => {
+                let mut info = ImageAllocationInfo {
+                    width: cmd_get_reqs.width,
+                    height: cmd_get_reqs.height,
+                    drm_format: DrmFormat::from(cmd_get_reqs.drm_format),
+                    flags: RutabagaGrallocFlags::new(cmd_get_reqs.flags),
+                    ..Default::default()
+                };
+                info.set_modifiers(modifiers)?;
+
+                let reqs = self
+                    .gralloc
+                    .lock()
+                    .unwrap()
+                    .get_image_memory_requirements(info)?;
+
+                let blob_id = self
+                    .item_state
+                    .lock()
+                    .unwrap()
+                    .cache_image_requirements(key, reqs)?;
+                (blob_id, reqs)
+            }
+        };
 
         let mut response = CrossDomainImageRequirements {
             strides: reqs.strides,
             offsets: reqs.offsets,
             modifier: reqs.modifier,
             size: reqs.size,
-            blob_id: 0,
+            blob_id,
             map_info: reqs.map_info,
             memory_idx: -1,
             physical_device_idx: -1,
+            ..Default::default()
         };
 
         if let Some(ref vk_info) = reqs.vulkan_info {
             response.memory_idx = vk_info.memory_idx as i32;
-            // We return -1 for now since physical_device_idx is deprecated. If this backend is
-            // put back into action, it should be using device_id from the request instead.
+            // physical_device_idx is deprecated.  Guests match the physical device using the
+            // device and driver UUIDs instead.
             response.physical_device_idx = -1;
+            response.device_uuid = vk_info.device_id.device_uuid;
+            response.driver_uuid = vk_info.device_id.driver_uuid;
         }
 
-        if let Some(state) = &self.state {
-            response.blob_id = add_item(&self.item_state, CrossDomainItem::ImageRequirements(reqs));
+        // Older guests may not leave room for the UUIDs in the query ring.
+        if state.guest_version >= CROSS_DOMAIN_VERSION_DEVICE_UUIDS {
             state.write_to_ring(RingWrite::Write(response, None), state.query_ring_id)?;
-            Ok(())
         } else {
-            Err(RutabagaError::InvalidCrossDomainState)
+            let response = CrossDomainImageRequirementsLegacy::from(&response);
+            state.write_to_ring(RingWrite::Write(response, None), state.query_ring_id)?;
         }
+
+        Ok(())
     }
 
     fn send(
@@ -652,6 +2310,12 @@ impl CrossDomainContext {
         cmd_send: &CrossDomainSendReceive,
         opaque_data: &[u8],
     ) -> RutabagaResult<()> {
+        let channel_type = self
+            .state
+            .as_ref()
+            .ok_or(RutabagaError::InvalidCrossDomainState)?
+            .channel(cmd_send.hdr.channel_id)?
+            .channel_type;
         let mut descriptors: Vec<OwnedDescriptor> = vec![];
         let mut write_pipe_opt: Option<WritePipe> = None;
         let mut read_pipe_id_opt: Option<u32> = None;
@@ -693,7 +2357,9 @@ impl CrossDomainContext {
                 } else {
                     return Err(MesaError::InvalidMesaHandle.into());
                 }
-            } else if *identifier_type == CROSS_DOMAIN_ID_TYPE_READ_PIPE {
+            } else if *identifier_type == CROSS_DOMAIN_ID_TYPE_READ_PIPE
+                && channel_supports_pipes(channel_type)
+            {
                 // In practice, just 1 pipe pair per send is observed.  If we encounter
                 // more, this can be changed later.
                 if write_pipe_opt.is_some() {
@@ -711,7 +2377,7 @@ impl CrossDomainContext {
                 let read_pipe_id: u32 = add_item(
                     &self.item_state,
                     CrossDomainItem::WaylandReadPipe(read_pipe),
-                );
+                )?;
 
                 // For Wayland read pipes, the guest guesses which identifier the host will use to
                 // avoid waiting for the host to generate one.  Validate guess here.  This works
@@ -732,15 +2398,109 @@ impl CrossDomainContext {
             }
         }
 
-        if let (Some(state), Some(ref mut resample_evt)) = (&self.state, &mut self.resample_evt) {
-            state.send_msg(opaque_data, &descriptors)?;
+        if let (Some(state), Some(ref mut resample_evt)) = (&self.state, &mut self.resample_evt) {
+            state.send_msg(cmd_send.hdr.channel_id, opaque_data, &descriptors)?;
+
+            if let Some(read_pipe_id) = read_pipe_id_opt {
+                state.add_job(CrossDomainJob::AddReadPipe(read_pipe_id));
+                resample_evt.signal()?;
+            }
+        } else {
+            return Err(RutabagaError::InvalidCrossDomainState);
+        }
+
+        Ok(())
+    }
+
+    fn set_staging(&self, cmd_set_staging: &CrossDomainSetStaging) -> RutabagaResult<()> {
+        let state = self
+            .state
+            .as_ref()
+            .ok_or(RutabagaError::InvalidCrossDomainState)?;
+
+        let context_resources = self.context_resources.lock().unwrap();
+        let staging_id = match cmd_set_staging.staging_id {
+            CROSS_DOMAIN_STAGING_NONE => None,
+            staging_id => {
+                let iovecs = context_resources
+                    .get(&staging_id)
+                    .ok_or(RutabagaError::InvalidResourceId)?
+                    .backing_iovecs
+                    .as_ref()
+                    .ok_or(RutabagaError::InvalidIovec)?
+                    .pin()?;
+                validate_iovecs(&iovecs, false)?;
+                Some(staging_id)
+            }
+        };
+
+        *state.staging_id.lock().unwrap() = staging_id;
+        Ok(())
+    }
+
+    fn read_ack(&mut self, cmd_ack: &CrossDomainReadAck) -> RutabagaResult<()> {
+        if let (Some(state), Some(ref mut resample_evt)) = (&self.state, &mut self.resample_evt) {
+            if state.ack_read(cmd_ack.identifier, cmd_ack.bytes as usize) {
+                state.add_job(CrossDomainJob::AddReadPipe(cmd_ack.identifier));
+                resample_evt.signal()?;
+            }
+        } else {
+            return Err(RutabagaError::InvalidCrossDomainState);
+        }
+
+        Ok(())
+    }
+
+    fn set_read_batching(&self, cmd_batching: &CrossDomainSetReadBatching) -> RutabagaResult<()> {
+        let state = self
+            .state
+            .as_ref()
+            .ok_or(RutabagaError::InvalidCrossDomainState)?;
+
+        state
+            .read_batching
+            .store(cmd_batching.enable != 0, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn debug_marker(
+        &self,
+        cmd_marker: &CrossDomainDebugMarker,
+        label: &[u8],
+    ) -> RutabagaResult<()> {
+        let label = String::from_utf8_lossy(label);
+        let marker = match cmd_marker.marker_type {
+            CROSS_DOMAIN_DEBUG_MARKER_BEGIN => RutabagaDebugMarker::Begin(&label),
+            CROSS_DOMAIN_DEBUG_MARKER_END => RutabagaDebugMarker::End,
+            CROSS_DOMAIN_DEBUG_MARKER_INSERT => RutabagaDebugMarker::Insert(&label),
+            _ => return Err(MesaError::WithContext("invalid debug marker type").into()),
+        };
+
+        self.trace.debug_marker(self.ctx_id, marker);
+        Ok(())
+    }
+
+    fn set_resource_name(
+        &self,
+        cmd_name: &CrossDomainSetResourceName,
+        name: &[u8],
+    ) -> RutabagaResult<()> {
+        let resource_id = cmd_name.resource_id;
+        if !self
+            .context_resources
+            .lock()
+            .unwrap()
+            .contains_key(&resource_id)
+        {
+            return Err(RutabagaError::InvalidResourceId);
+        }
 
-            if let Some(read_pipe_id) = read_pipe_id_opt {
-                state.add_job(CrossDomainJob::AddReadPipe(read_pipe_id));
-                resample_evt.signal()?;
-            }
+        let name = String::from_utf8_lossy(name);
+        if name.is_empty() {
+            self.resource_names.remove(resource_id);
         } else {
-            return Err(RutabagaError::InvalidCrossDomainState);
+            self.resource_names.set(resource_id, Some(&name))?;
+            self.trace.resource_named(resource_id, &name);
         }
 
         Ok(())
@@ -749,35 +2509,29 @@ impl CrossDomainContext {
     fn write(&self, cmd_write: &CrossDomainReadWrite, opaque_data: &[u8]) -> RutabagaResult<()> {
         let mut items = self.item_state.lock().unwrap();
 
-        // Most of the time, hang-up and writing will be paired.  In lieu of this, remove the
-        // item rather than getting a reference.  In case of an error, there's not much to do
-        // besides reporting it.
-        let item = items
-            .table
-            .remove(&cmd_write.identifier)
-            .ok_or(RutabagaError::InvalidCrossDomainItemId)?;
-
         let len: usize = cmd_write
             .opaque_data_size
             .try_into()
             .map_err(MesaError::TryFromIntError)?;
-        match item {
-            CrossDomainItem::WaylandWritePipe(write_pipe) => {
-                if len != 0 {
-                    write_pipe.write(opaque_data)?;
-                }
-
-                if cmd_write.hang_up == 0 {
-                    items.table.insert(
-                        cmd_write.identifier,
-                        CrossDomainItem::WaylandWritePipe(write_pipe),
-                    );
-                }
+        let item = items
+            .get_mut(cmd_write.identifier)
+            .ok_or(RutabagaError::InvalidCrossDomainItemId)?;
 
-                Ok(())
+        let result = match item {
+            CrossDomainItem::WaylandWritePipe(write_pipe) if len != 0 => {
+                write_pipe.write(opaque_data).map(|_| ())
             }
-            _ => Err(RutabagaError::InvalidCrossDomainItemType),
+            CrossDomainItem::WaylandWritePipe(_) => Ok(()),
+            _ => return Err(RutabagaError::InvalidCrossDomainItemType),
+        };
+
+        // In case of an error, there's not much to do besides reporting it, so the pipe is closed
+        // just like on hang-up.
+        if result.is_err() || cmd_write.hang_up != 0 {
+            items.remove(cmd_write.identifier);
         }
+
+        Ok(result?)
     }
 }
 
@@ -792,7 +2546,10 @@ impl Drop for CrossDomainContext {
             match kill_evt.signal() {
                 Ok(_) => (),
                 Err(e) => {
-                    error!("failed to write cross domain kill event: {}", e);
+                    self.logger.log(
+                        Level::Error,
+                        format_args!("failed to write cross domain kill event: {}", e),
+                    );
                 }
             }
 
@@ -811,6 +2568,34 @@ struct CrossDomainInitLegacy {
     channel_type: u32,
 }
 
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+struct CrossDomainImageRequirementsLegacy {
+    strides: [u32; 4],
+    offsets: [u32; 4],
+    modifier: u64,
+    size: u64,
+    blob_id: u32,
+    map_info: u32,
+    memory_idx: i32,
+    physical_device_idx: i32,
+}
+
+impl From<&CrossDomainImageRequirements> for CrossDomainImageRequirementsLegacy {
+    fn from(reqs: &CrossDomainImageRequirements) -> Self {
+        CrossDomainImageRequirementsLegacy {
+            strides: reqs.strides,
+            offsets: reqs.offsets,
+            modifier: reqs.modifier,
+            size: reqs.size,
+            blob_id: reqs.blob_id,
+            map_info: reqs.map_info,
+            memory_idx: reqs.memory_idx,
+            physical_device_idx: reqs.physical_device_idx,
+        }
+    }
+}
+
 impl RutabagaContext for CrossDomainContext {
     fn context_create_blob(
         &mut self,
@@ -822,8 +2607,7 @@ impl RutabagaContext for CrossDomainContext {
 
         let mut items = self.item_state.lock().unwrap();
         let item = items
-            .table
-            .get_mut(&item_id)
+            .get_mut(item_id)
             .ok_or(RutabagaError::InvalidCrossDomainItemId)?;
 
         // Items that are kept in the table after usage.
@@ -832,13 +2616,20 @@ impl RutabagaContext for CrossDomainContext {
                 return Err(MesaError::WithContext("blob size mismatch").into());
             }
 
+            // The layout of protected images may differ, so the requirements must have been
+            // queried for protected memory too.
+            let protected = resource_create_blob.blob_flags & RUTABAGA_BLOB_FLAG_PROTECTED != 0;
+            if protected != reqs.info.flags.uses_protected() {
+                return Err(MesaError::WithContext("blob protection mismatch").into());
+            }
+
             // Strictly speaking, it's against the virtio-gpu spec to allocate memory in the context
             // create blob function, which says "the actual allocation is done via
             // VIRTIO_GPU_CMD_SUBMIT_3D."  However, atomic resource creation is easiest for the
             // cross-domain use case, so whatever.
             let hnd = match handle_opt {
                 Some(handle) => handle,
-                None => self.gralloc.lock().unwrap().allocate_memory(*reqs)?.into(),
+                None => self.gralloc.lock().unwrap().allocate_memory(**reqs)?.into(),
             };
 
             let info_3d = Resource3DInfo {
@@ -869,8 +2660,7 @@ impl RutabagaContext for CrossDomainContext {
         }
 
         let item = items
-            .table
-            .remove(&item_id)
+            .remove(item_id)
             .ok_or(RutabagaError::InvalidCrossDomainItemId)?;
 
         // Items that are removed from the table after one usage.
@@ -906,86 +2696,60 @@ impl RutabagaContext for CrossDomainContext {
         }
     }
 
+    fn probe_blob_handle_type(
+        &self,
+        resource_create_blob: &ResourceCreateBlob,
+    ) -> RutabagaResult<u32> {
+        let item_id = resource_create_blob.blob_id as u32;
+
+        let items = self.item_state.lock().unwrap();
+        let item = items
+            .get(item_id)
+            .ok_or(RutabagaError::InvalidCrossDomainItemId)?;
+
+        match item {
+            CrossDomainItem::ImageRequirements(reqs) => {
+                self.gralloc.lock().unwrap().allocation_handle_type(reqs)
+            }
+            CrossDomainItem::Blob(hnd) => Ok(hnd.handle_type),
+            _ => Err(RutabagaError::InvalidCrossDomainItemType),
+        }
+    }
+
     fn submit_cmd(
         &mut self,
-        mut commands: &mut [u8],
+        commands: &mut [u8],
         _fence_ids: &[u64],
         _shareable_fences: Vec<MesaHandle>,
     ) -> RutabagaResult<()> {
-        while !commands.is_empty() {
-            let (hdr, _) = CrossDomainHeader::read_from_prefix(commands)
-                .map_err(|_| RutabagaError::InvalidCommandBuffer)?;
-
-            match hdr.cmd {
-                CROSS_DOMAIN_CMD_INIT => {
-                    let cmd_init = match CrossDomainInit::read_from_prefix(commands) {
-                        Ok((cmd_init, _)) => cmd_init,
-                        _ => {
-                            if let Ok((cmd_init, _)) =
-                                CrossDomainInitLegacy::read_from_prefix(commands)
-                            {
-                                CrossDomainInit {
-                                    hdr: cmd_init.hdr,
-                                    query_ring_id: cmd_init.query_ring_id,
-                                    channel_ring_id: cmd_init.query_ring_id,
-                                    channel_type: cmd_init.channel_type,
-                                }
-                            } else {
-                                return Err(RutabagaError::InvalidCommandBuffer);
-                            }
-                        }
-                    };
-
-                    self.initialize(&cmd_init)?;
-                }
-                CROSS_DOMAIN_CMD_GET_IMAGE_REQUIREMENTS => {
-                    let (cmd_get_reqs, _) =
-                        CrossDomainGetImageRequirements::read_from_prefix(commands)
-                            .map_err(|_e| RutabagaError::InvalidCommandBuffer)?;
-
-                    self.get_image_requirements(&cmd_get_reqs)?;
-                }
-                CROSS_DOMAIN_CMD_SEND => {
-                    let opaque_data_offset = size_of::<CrossDomainSendReceive>();
-                    let (cmd_send, _) = CrossDomainSendReceive::read_from_prefix(commands)
-                        .map_err(|_e| RutabagaError::InvalidCommandBuffer)?;
-
-                    let opaque_data = commands
-                        .get_mut(
-                            opaque_data_offset
-                                ..opaque_data_offset + cmd_send.opaque_data_size as usize,
-                        )
-                        .ok_or(RutabagaError::InvalidCommandSize(
-                            cmd_send.opaque_data_size as usize,
-                        ))?;
-
-                    self.send(&cmd_send, opaque_data)?;
-                }
-                CROSS_DOMAIN_CMD_POLL => {
-                    // Actual polling is done in the subsequent when creating a fence.
-                }
-                CROSS_DOMAIN_CMD_WRITE => {
-                    let opaque_data_offset = size_of::<CrossDomainReadWrite>();
-                    let (cmd_write, _) = CrossDomainReadWrite::read_from_prefix(commands)
-                        .map_err(|_e| RutabagaError::InvalidCommandBuffer)?;
-
-                    let opaque_data = commands
-                        .get_mut(
-                            opaque_data_offset
-                                ..opaque_data_offset + cmd_write.opaque_data_size as usize,
-                        )
-                        .ok_or::<RutabagaError>(RutabagaError::InvalidCommandSize(
-                            cmd_write.opaque_data_size as usize,
-                        ))?;
-
-                    self.write(&cmd_write, opaque_data)?;
+        if let Some(supported_commands) = self.strict_commands {
+            let guest_version = self.state.as_ref().map(|state| state.guest_version);
+            if let Err((cmd, error, e)) =
+                validate_commands(commands, supported_commands, guest_version)
+            {
+                // Without a query ring, the guest can't be told.
+                if self.state.is_some() {
+                    self.reject_command(cmd, error)?;
                 }
-                _ => return Err(MesaError::WithContext("invalid cross domain command").into()),
+                return Err(e);
             }
+        }
+
+        let mut commands: &[u8] = commands;
+        while !commands.is_empty() {
+            let cmd = commands[0];
+            let result = parse_command(commands).and_then(|(command, remaining)| {
+                self.run_command(command)?;
+                Ok(remaining)
+            });
 
-            commands = commands
-                .get_mut(hdr.cmd_size as usize..)
-                .ok_or(RutabagaError::InvalidCommandSize(hdr.cmd_size as usize))?;
+            commands = match result {
+                Ok(remaining) => remaining,
+                Err(e) => {
+                    self.report_error(cmd, &e)?;
+                    return Err(e);
+                }
+            };
         }
 
         Ok(())
@@ -997,7 +2761,7 @@ impl RutabagaContext for CrossDomainContext {
                 resource.resource_id,
                 ContextResource {
                     handle: None,
-                    backing_iovecs: resource.backing_iovecs.take(),
+                    backing_iovecs: resource.backing_iovecs.clone(),
                 },
             );
         } else if let Some(ref handle) = resource.handle {
@@ -1012,10 +2776,37 @@ impl RutabagaContext for CrossDomainContext {
     }
 
     fn detach(&mut self, resource: &RutabagaResource) {
-        self.context_resources
-            .lock()
-            .unwrap()
-            .remove(&resource.resource_id);
+        let mut context_resources = self.context_resources.lock().unwrap();
+        context_resources.remove(&resource.resource_id);
+
+        // Pipe data goes back to the channel ring once the staging blob is gone.
+        if let Some(state) = &self.state {
+            let mut staging_id = state.staging_id.lock().unwrap();
+            if *staging_id == Some(resource.resource_id) {
+                *staging_id = None;
+            }
+        }
+    }
+
+    fn resize_blob(&mut self, resource: &mut RutabagaResource) {
+        // The worker thread only touches rings with the lock held, so swapping the backing here
+        // is atomic with respect to ring writes, and the old backing is no longer pinned.
+        let mut context_resources = self.context_resources.lock().unwrap();
+        if let Some(context_resource) = context_resources.get_mut(&resource.resource_id) {
+            if resource.blob_mem == RUTABAGA_BLOB_MEM_GUEST {
+                let backing = resource.backing_iovecs.clone();
+                if let Some(old) = std::mem::replace(&mut context_resource.backing_iovecs, backing)
+                {
+                    old.detach();
+                }
+            } else {
+                context_resource.handle = resource.handle.clone();
+            }
+        }
+    }
+
+    fn invalidate_guest_memory(&mut self, range: &Range<u64>) -> Vec<u32> {
+        invalidate_guest_memory(&self.context_resources, range)
     }
 
     fn context_create_fence(&mut self, fence: RutabagaFence) -> RutabagaResult<Option<MesaHandle>> {
@@ -1038,6 +2829,22 @@ impl RutabagaContext for CrossDomainContext {
 }
 
 impl RutabagaComponent for CrossDomain {
+    fn set_log_sink(&mut self, sink: RutabagaLogSink) {
+        self.logger = RutabagaLogger::new(RutabagaComponentType::CrossDomain, Some(sink));
+    }
+
+    fn set_trace(&mut self, trace: RutabagaTrace) {
+        self.trace = trace;
+    }
+
+    fn set_resource_names(&mut self, names: RutabagaResourceNames) {
+        self.resource_names = names;
+    }
+
+    fn handle_types(&self) -> RutabagaHandleTypes {
+        cross_domain_handle_types(&self.gralloc.lock().unwrap())
+    }
+
     fn get_capset_info(&self, _capset_id: u32) -> (u32, u32) {
         (0u32, size_of::<CrossDomainCapabilities>() as u32)
     }
@@ -1045,8 +2852,11 @@ impl RutabagaComponent for CrossDomain {
     fn get_capset(&self, _capset_id: u32, _version: u32) -> Vec<u8> {
         let mut caps: CrossDomainCapabilities = Default::default();
         if let Some(ref paths) = self.paths {
-            for path in paths {
-                caps.supported_channels |= 1 << path.path_type;
+            for channel_type in paths
+                .iter()
+                .filter_map(|path| path_channel_type(path.path_type))
+            {
+                caps.supported_channels |= 1 << channel_type;
             }
         }
 
@@ -1058,8 +2868,38 @@ impl RutabagaComponent for CrossDomain {
             caps.supports_external_gpu_memory = 1;
         }
 
+        if self.gralloc.lock().unwrap().supports_protected() {
+            caps.supports_protected = 1;
+        }
+
+        caps.supports_blob_resize = 1;
+        caps.supported_commands = self.supported_commands();
+        caps.strict = self.strict.into();
+        // supports_context_priority and supports_compute_only stay unset until a component
+        // schedules by priority or treats compute-only contexts differently.
+        caps.pipe_high_water = CROSS_DOMAIN_PIPE_HIGH_WATER as u32;
+        caps.export_handle_types = self.handle_types().export;
+
         // Version 1 supports all commands up to and including CROSS_DOMAIN_CMD_WRITE.
-        caps.version = 1;
+        // Version 2 adds CROSS_DOMAIN_CMD_GET_IMAGE_REQUIREMENTS_WITH_MODIFIERS.
+        // Version 3 adds CROSS_DOMAIN_CMD_SET_STAGING.
+        // Version 4 uses the whole channel ring rather than its first page.
+        // Version 5 adds CROSS_DOMAIN_CMD_OPEN_CHANNEL.
+        // Version 6 adds supported_commands and strict mode.
+        // Version 7 adds supports_protected.
+        // Version 8 adds supports_context_priority.
+        // Version 9 validates whole submissions in strict mode.
+        // Version 10 adds supports_compute_only.
+        // Version 11 reports failed commands with CROSS_DOMAIN_CMD_ERROR on the channel ring.
+        // Version 12 adds CROSS_DOMAIN_CMD_QUERY_MODIFIERS.
+        // Version 13 adds CROSS_DOMAIN_CMD_READ_ACK and pipe_high_water.
+        // Version 14 adds CROSS_DOMAIN_CMD_DEBUG_MARKER.
+        // Version 15 adds CROSS_DOMAIN_CMD_SET_READ_BATCHING.
+        // Version 16 adds export_handle_types and handle type negotiation at init.
+        // Version 17 adds the guest's version at init, and CROSS_DOMAIN_CMD_WORKER_RESTART.
+        // Version 18 adds CROSS_DOMAIN_CMD_SET_RESOURCE_NAME.
+        // Version 19 adds the device and driver UUIDs to CrossDomainImageRequirements.
+        caps.version = 19;
         caps.as_bytes().to_vec()
     }
 
@@ -1087,30 +2927,84 @@ impl RutabagaComponent for CrossDomain {
             info_2d: None,
             info_3d: None,
             vulkan_info: None,
-            backing_iovecs: iovec_opt,
+            backing_iovecs: iovec_opt.map(RutabagaBacking::new),
             component_mask: 1 << (RutabagaComponentType::CrossDomain as u8),
             size: resource_create_blob.size,
             mapping: None,
         })
     }
 
+    // Only the first iovec of a ring resource is ever accessed, and it is checked on every write.
+    fn supports_sparse_iovecs(&self) -> bool {
+        true
+    }
+
+    fn resize_blob(
+        &self,
+        resource: &mut RutabagaResource,
+        size: u64,
+        iovecs: Option<Vec<RutabagaIovec>>,
+        handle: Option<RutabagaHandle>,
+    ) -> RutabagaResult<()> {
+        // Image layouts are fixed at allocation time.
+        if resource.info_3d.is_some() {
+            return Err(MesaError::WithContext("image blobs cannot be resized").into());
+        }
+
+        if resource.blob_mem == RUTABAGA_BLOB_MEM_GUEST {
+            let iovecs = iovecs.ok_or(RutabagaError::InvalidIovec)?;
+            if validate_iovecs(&iovecs, true)? < size {
+                return Err(RutabagaError::InvalidIovec);
+            }
+
+            resource.backing_iovecs = Some(RutabagaBacking::new(iovecs));
+        } else {
+            let handle = handle.ok_or(MesaError::InvalidMesaHandle)?;
+            match handle.as_mesa_handle() {
+                Some(mesa_handle) if mesa_handle.handle_type == MESA_HANDLE_TYPE_MEM_SHM => (),
+                _ => return Err(MesaError::WithContext("expected a shared memory handle").into()),
+            }
+
+            resource.handle = Some(Arc::new(handle));
+        }
+
+        resource.size = size;
+        Ok(())
+    }
+
+    fn mapped_handle_types(&self) -> u32 {
+        handle_type_mask(&[MESA_HANDLE_TYPE_MEM_SHM])
+    }
+
+    // Contexts keep their own state, and only share the gralloc, which is locked, and an atomic
+    // count of descriptors.
+    fn concurrent_contexts(&self) -> bool {
+        true
+    }
+
     fn create_context(
         &self,
-        _ctx_id: u32,
-        _context_init: u32,
-        _context_name: Option<&str>,
+        params: RutabagaContextParams,
         fence_handler: RutabagaFenceHandler,
     ) -> RutabagaResult<Box<dyn RutabagaContext>> {
+        let ctx_id = params.ctx_id;
         Ok(Box::new(CrossDomainContext {
             paths: self.paths.clone(),
             gralloc: self.gralloc.clone(),
             state: None,
             context_resources: Arc::new(Mutex::new(Default::default())),
-            item_state: Arc::new(Mutex::new(Default::default())),
+            item_state: Arc::new(Mutex::new(CrossDomainItems::new(self.descriptors.clone()))),
             fence_handler,
             worker_thread: None,
             resample_evt: None,
             kill_evt: None,
+            thread_config: self.thread_config.clone(),
+            wayland_trace: self.wayland_trace,
+            strict_commands: self.strict.then(|| self.supported_commands()),
+            logger: self.logger.for_context(ctx_id),
+            ctx_id,
+            trace: self.trace.clone(),
+            resource_names: self.resource_names.clone(),
         }))
     }
 
@@ -1122,3 +3016,830 @@ impl RutabagaComponent for CrossDomain {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHANNEL_RING_ID: u32 = 2;
+
+    fn read_pipe() -> CrossDomainItem {
+        let (read_pipe, _) = create_pipe().unwrap();
+        CrossDomainItem::WaylandReadPipe(read_pipe)
+    }
+
+    // A worker of a guest speaking `guest_version`, whose channel ring is `ring`.  The ids of
+    // the fences it signals are pushed to `signaled`.
+    fn new_worker(
+        ring: &mut [u8],
+        guest_version: u32,
+        signaled: Arc<Mutex<Vec<u64>>>,
+    ) -> CrossDomainWorker {
+        let backing = RutabagaBacking::new(vec![RutabagaIovec {
+            base: ring.as_mut_ptr() as *mut _,
+            len: ring.len(),
+        }]);
+        let context_resources: ContextResources = Default::default();
+        context_resources.lock().unwrap().insert(
+            CHANNEL_RING_ID,
+            ContextResource {
+                handle: None,
+                backing_iovecs: Some(backing),
+            },
+        );
+
+        let state = CrossDomainState::new(1, CHANNEL_RING_ID, guest_version, context_resources);
+        let items = CrossDomainItems::new(Arc::new(CrossDomainDescriptors::new(None)));
+        CrossDomainWorker::new(
+            WaitContext::new().unwrap(),
+            Arc::new(state),
+            Arc::new(Mutex::new(items)),
+            RutabagaFenceHandler::new(move |fence: RutabagaFence| {
+                signaled.lock().unwrap().push(fence.fence_id)
+            }),
+            RutabagaLogger::new(RutabagaComponentType::CrossDomain, None),
+        )
+    }
+
+    fn fence(fence_id: u64) -> RutabagaFence {
+        RutabagaFence {
+            flags: 0,
+            fence_id,
+            ctx_id: 1,
+            ring_idx: 0,
+        }
+    }
+
+    #[test]
+    fn read_pipe_ids_skip_open_pipes() {
+        let mut items = CrossDomainItems::new(Arc::new(CrossDomainDescriptors::new(None)));
+        let first = items.insert(read_pipe()).unwrap();
+        assert_eq!(first, CROSS_DOMAIN_PIPE_READ_START + 1);
+
+        // Wrap around while the first pipe is still open.
+        items.read_pipe_id = u32::MAX - 1;
+        assert_eq!(items.insert(read_pipe()).unwrap(), u32::MAX);
+        assert_eq!(
+            items.insert(read_pipe()).unwrap(),
+            CROSS_DOMAIN_PIPE_READ_START
+        );
+        assert_eq!(
+            items.insert(read_pipe()).unwrap(),
+            CROSS_DOMAIN_PIPE_READ_START + 2
+        );
+        assert_eq!(items.read_pipes.len(), 4);
+    }
+
+    fn image_key(width: u32) -> CrossDomainImageKey {
+        (width, 1, 0, 0, Vec::new())
+    }
+
+    fn cache_image(items: &mut CrossDomainItems, width: u32) -> u32 {
+        let reqs = ImageMemoryRequirements {
+            size: width as u64,
+            ..Default::default()
+        };
+        items
+            .cache_image_requirements(image_key(width), reqs)
+            .unwrap()
+    }
+
+    #[test]
+    fn image_requirements_reuse_blob() {
+        let mut items = CrossDomainItems::new(Arc::new(CrossDomainDescriptors::new(None)));
+        assert!(items.cached_image_requirements(&image_key(1)).is_none());
+
+        let blob_id = cache_image(&mut items, 1);
+        let (cached_id, reqs) = items.cached_image_requirements(&image_key(1)).unwrap();
+        assert_eq!(cached_id, blob_id);
+        assert_eq!(reqs.size, 1);
+        assert!(items.cached_image_requirements(&image_key(2)).is_none());
+    }
+
+    #[test]
+    fn image_requirements_are_bounded() {
+        let mut items = CrossDomainItems::new(Arc::new(CrossDomainDescriptors::new(None)));
+        let max = CROSS_DOMAIN_MAX_IMAGE_REQUIREMENTS as u32;
+        let blob_ids: Vec<u32> = (0..max)
+            .map(|width| cache_image(&mut items, width))
+            .collect();
+
+        // The first query was used again, so the second is the least recently used.
+        assert!(items.cached_image_requirements(&image_key(0)).is_some());
+        cache_image(&mut items, max);
+
+        assert_eq!(
+            items.image_requirements.len(),
+            CROSS_DOMAIN_MAX_IMAGE_REQUIREMENTS
+        );
+        assert!(items.cached_image_requirements(&image_key(1)).is_none());
+        assert!(items.get(blob_ids[1]).is_none());
+        assert!(items.cached_image_requirements(&image_key(0)).is_some());
+        assert!(items.get(blob_ids[0]).is_some());
+
+        for width in max + 1..max * 4 {
+            cache_image(&mut items, width);
+        }
+        assert_eq!(
+            items.image_requirements.len(),
+            CROSS_DOMAIN_MAX_IMAGE_REQUIREMENTS
+        );
+        assert_eq!(items.iter().count(), CROSS_DOMAIN_MAX_IMAGE_REQUIREMENTS);
+    }
+
+    #[test]
+    fn worker_restart_notifies_guest() {
+        let mut ring = vec![0u8; 4096];
+        let signaled: Arc<Mutex<Vec<u64>>> = Default::default();
+        let mut worker = new_worker(&mut ring, 17, signaled.clone());
+
+        worker
+            .restart(fence(1), RutabagaError::InvalidIovec)
+            .unwrap();
+        worker
+            .restart(fence(2), RutabagaError::InvalidIovec)
+            .unwrap();
+        assert_eq!(*signaled.lock().unwrap(), [1, 2]);
+
+        let (cmd_restart, _) = CrossDomainWorkerRestart::read_from_prefix(&ring).unwrap();
+        assert_eq!(cmd_restart.hdr.cmd, CROSS_DOMAIN_CMD_WORKER_RESTART);
+        assert_eq!(cmd_restart.restarts, 2);
+    }
+
+    #[test]
+    fn worker_restart_holds_fence_of_old_guests() {
+        let mut ring = vec![0u8; 4096];
+        let signaled: Arc<Mutex<Vec<u64>>> = Default::default();
+        let mut worker = new_worker(&mut ring, 16, signaled.clone());
+        worker.state.add_job(CrossDomainJob::HandleFence(fence(2)));
+
+        // The failed fence is handled again ahead of later ones, and nothing is written.
+        worker
+            .restart(fence(1), RutabagaError::InvalidIovec)
+            .unwrap();
+        assert!(signaled.lock().unwrap().is_empty());
+        let jobs = worker.state.jobs.lock().unwrap();
+        let fence_ids: Vec<u64> = jobs
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|job| match job {
+                CrossDomainJob::HandleFence(fence) => fence.fence_id,
+                _ => panic!("unexpected job"),
+            })
+            .collect();
+        assert_eq!(fence_ids, [1, 2]);
+        drop(jobs);
+        assert!(ring.iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn worker_restarts_reset_after_success() {
+        let mut ring = vec![0u8; 4096];
+        let signaled: Arc<Mutex<Vec<u64>>> = Default::default();
+        let mut worker = new_worker(&mut ring, 17, signaled.clone());
+        let resample_evt = Event::new().unwrap();
+        let mut receive_buf = Vec::new();
+
+        for fence_id in 0..CROSS_DOMAIN_MAX_WORKER_RESTARTS {
+            worker
+                .restart(fence(fence_id.into()), RutabagaError::InvalidIovec)
+                .unwrap();
+        }
+
+        // A pending command error makes the next fence succeed without polling.
+        assert!(worker.state.add_error(CROSS_DOMAIN_CMD_SEND, 1));
+        let job = CrossDomainJob::HandleFence(fence(100));
+        assert!(!worker
+            .handle_job(job, &resample_evt, &mut receive_buf)
+            .unwrap());
+        assert_eq!(signaled.lock().unwrap().last(), Some(&100));
+
+        for fence_id in 0..CROSS_DOMAIN_MAX_WORKER_RESTARTS {
+            worker
+                .restart(fence(fence_id.into()), RutabagaError::InvalidIovec)
+                .unwrap();
+        }
+        assert!(worker
+            .restart(fence(200), RutabagaError::InvalidIovec)
+            .is_err());
+
+        let (cmd_restart, _) = CrossDomainWorkerRestart::read_from_prefix(&ring).unwrap();
+        assert_ne!(cmd_restart.hdr.cmd, CROSS_DOMAIN_CMD_WORKER_RESTART);
+    }
+
+    #[test]
+    fn command_errors_reach_channel_ring() {
+        let mut ring = vec![0u8; 4096];
+        let signaled: Arc<Mutex<Vec<u64>>> = Default::default();
+        let mut worker = new_worker(&mut ring, 11, signaled.clone());
+        let resample_evt = Event::new().unwrap();
+        let mut receive_buf = Vec::new();
+
+        assert!(worker
+            .state
+            .add_error(CROSS_DOMAIN_CMD_SEND, CROSS_DOMAIN_ERROR_INVALID_COMMAND));
+        assert!(worker
+            .state
+            .add_error(CROSS_DOMAIN_CMD_WRITE, CROSS_DOMAIN_ERROR_HOST_FAILURE));
+
+        // One report per fence, in order.
+        for (fence_id, cmd, error) in [
+            (1, CROSS_DOMAIN_CMD_SEND, CROSS_DOMAIN_ERROR_INVALID_COMMAND),
+            (2, CROSS_DOMAIN_CMD_WRITE, CROSS_DOMAIN_ERROR_HOST_FAILURE),
+        ] {
+            let job = CrossDomainJob::HandleFence(fence(fence_id));
+            worker
+                .handle_job(job, &resample_evt, &mut receive_buf)
+                .unwrap();
+            assert_eq!(signaled.lock().unwrap().last(), Some(&fence_id));
+
+            let (cmd_error, _) = CrossDomainError::read_from_prefix(&ring).unwrap();
+            assert_eq!(cmd_error.hdr.cmd, CROSS_DOMAIN_CMD_ERROR);
+            assert_eq!(cmd_error.cmd, cmd as u32);
+            assert_eq!(cmd_error.error, error);
+        }
+        assert!(worker.state.take_error().is_none());
+    }
+
+    #[test]
+    fn command_errors_skip_old_guests() {
+        let mut ring = vec![0u8; 4096];
+        let signaled: Arc<Mutex<Vec<u64>>> = Default::default();
+        let mut worker = new_worker(&mut ring, 10, signaled.clone());
+
+        assert!(!worker
+            .state
+            .add_error(CROSS_DOMAIN_CMD_SEND, CROSS_DOMAIN_ERROR_INVALID_COMMAND));
+        assert!(worker.state.take_error().is_none());
+
+        // Halting isn't reported either, and leaves the fence pending.
+        for fence_id in 0..CROSS_DOMAIN_MAX_WORKER_RESTARTS {
+            worker
+                .restart(fence(fence_id.into()), RutabagaError::InvalidIovec)
+                .unwrap();
+        }
+        assert!(worker
+            .restart(fence(100), RutabagaError::InvalidIovec)
+            .is_err());
+        assert!(signaled.lock().unwrap().is_empty());
+        assert!(ring.iter().all(|byte| *byte == 0));
+    }
+
+    // A CROSS_DOMAIN_CMD_DEBUG_MARKER inserting `label` of `label_size` bytes, with `padding`
+    // bytes after the label.
+    fn debug_marker_command(label: &[u8], label_size: u32, padding: usize) -> Vec<u8> {
+        let label_offset = size_of::<CrossDomainDebugMarker>();
+        let cmd_marker = CrossDomainDebugMarker {
+            hdr: CrossDomainHeader {
+                cmd: CROSS_DOMAIN_CMD_DEBUG_MARKER,
+                cmd_size: (label_offset + label.len() + padding) as u16,
+                ..Default::default()
+            },
+            marker_type: CROSS_DOMAIN_DEBUG_MARKER_INSERT,
+            label_size,
+        };
+
+        let mut commands = cmd_marker.as_bytes().to_vec();
+        commands.extend_from_slice(label);
+        commands.resize(commands.len() + padding, 0);
+        commands
+    }
+
+    fn parse_debug_marker(commands: &[u8]) -> RutabagaResult<Vec<u8>> {
+        match parse_command(commands)? {
+            (CrossDomainCommand::DebugMarker(_, label), []) => Ok(label.to_vec()),
+            _ => panic!("not a single debug marker"),
+        }
+    }
+
+    #[test]
+    fn debug_marker_labels() {
+        let label = [b'a'; CROSS_DOMAIN_MAX_DEBUG_LABEL];
+        let commands = debug_marker_command(&label, label.len() as u32, 0);
+        assert_eq!(parse_debug_marker(&commands).unwrap(), label);
+
+        // The label may be shorter than the command.
+        let commands = debug_marker_command(b"frame", 5, 3);
+        assert_eq!(parse_debug_marker(&commands).unwrap(), b"frame");
+
+        let commands = debug_marker_command(b"", 0, 0);
+        assert_eq!(parse_debug_marker(&commands).unwrap(), b"");
+    }
+
+    #[test]
+    fn debug_marker_label_bounds() {
+        let label = [b'a'; CROSS_DOMAIN_MAX_DEBUG_LABEL + 1];
+        let commands = debug_marker_command(&label, label.len() as u32, 0);
+        assert!(matches!(
+            parse_command(&commands),
+            Err(RutabagaError::InvalidCommandSize(_))
+        ));
+
+        // A label running into the next command.
+        let mut commands = debug_marker_command(b"frame", 8, 0);
+        commands.extend(debug_marker_command(b"next", 4, 0));
+        assert!(matches!(
+            parse_command(&commands),
+            Err(RutabagaError::InvalidCommandSize(_))
+        ));
+
+        // A label running past the end of the buffer.
+        let mut commands = debug_marker_command(b"frame", 5, 0);
+        commands.truncate(commands.len() - 1);
+        assert!(parse_command(&commands).is_err());
+    }
+
+    #[test]
+    fn legacy_image_requirements() {
+        let reqs = CrossDomainImageRequirements {
+            strides: [1, 2, 3, 4],
+            modifier: 5,
+            blob_id: 6,
+            memory_idx: -1,
+            physical_device_idx: -1,
+            device_uuid: [7; 16],
+            driver_uuid: [8; 16],
+            ..Default::default()
+        };
+
+        // Older guests read the requirements without the UUIDs.
+        let legacy = CrossDomainImageRequirementsLegacy::from(&reqs);
+        assert_eq!(
+            legacy.as_bytes(),
+            &reqs.as_bytes()[..size_of::<CrossDomainImageRequirementsLegacy>()]
+        );
+        assert_eq!(
+            size_of::<CrossDomainImageRequirements>() - legacy.as_bytes().len(),
+            32
+        );
+    }
+
+    #[test]
+    fn commands_cover_their_header() {
+        let poll = |cmd_size: u16| {
+            CrossDomainHeader {
+                cmd: CROSS_DOMAIN_CMD_POLL,
+                cmd_size,
+                ..Default::default()
+            }
+            .as_bytes()
+            .to_vec()
+        };
+
+        let mut commands = poll(size_of::<CrossDomainHeader>() as u16);
+        commands.extend(poll(size_of::<CrossDomainHeader>() as u16));
+        assert!(matches!(
+            parse_command(&commands),
+            Ok((CrossDomainCommand::Poll, remaining)) if remaining.len() == commands.len() / 2
+        ));
+
+        // A command of size zero used to be decoded over and over.
+        assert!(matches!(
+            parse_command(&poll(0)),
+            Err(RutabagaError::InvalidCommandSize(0))
+        ));
+        assert!(matches!(
+            parse_command(&poll(2)),
+            Err(RutabagaError::InvalidCommandSize(2))
+        ));
+    }
+
+    fn init_command(version: u32) -> Vec<u8> {
+        let cmd_init = CrossDomainInit {
+            hdr: CrossDomainHeader {
+                cmd: CROSS_DOMAIN_CMD_INIT,
+                cmd_size: size_of::<CrossDomainInit>() as u16,
+                ..Default::default()
+            },
+            version,
+            ..Default::default()
+        };
+        cmd_init.as_bytes().to_vec()
+    }
+
+    fn resource_name_command() -> Vec<u8> {
+        let cmd_name = CrossDomainSetResourceName {
+            hdr: CrossDomainHeader {
+                cmd: CROSS_DOMAIN_CMD_SET_RESOURCE_NAME,
+                cmd_size: size_of::<CrossDomainSetResourceName>() as u16,
+                ..Default::default()
+            },
+            resource_id: 1,
+            name_size: 0,
+        };
+        cmd_name.as_bytes().to_vec()
+    }
+
+    fn rejection(result: Result<(), (u8, u32, RutabagaError)>) -> Option<(u8, u32)> {
+        result.err().map(|(cmd, error, _)| (cmd, error))
+    }
+
+    #[test]
+    fn strict_commands() {
+        let commands = resource_name_command();
+        assert_eq!(
+            rejection(validate_commands(&commands, u32::MAX, Some(18))),
+            None
+        );
+
+        let supported = !(1 << CROSS_DOMAIN_CMD_SET_RESOURCE_NAME);
+        assert_eq!(
+            rejection(validate_commands(&commands, supported, Some(18))),
+            Some((
+                CROSS_DOMAIN_CMD_SET_RESOURCE_NAME,
+                CROSS_DOMAIN_ERROR_UNSUPPORTED_COMMAND
+            ))
+        );
+
+        assert_eq!(
+            rejection(validate_commands(
+                &commands[..commands.len() - 4],
+                u32::MAX,
+                Some(18)
+            )),
+            Some((
+                CROSS_DOMAIN_CMD_SET_RESOURCE_NAME,
+                CROSS_DOMAIN_ERROR_INVALID_COMMAND
+            ))
+        );
+
+        let commands = init_command(18);
+        assert_eq!(
+            rejection(validate_commands(&commands, u32::MAX, None)),
+            None
+        );
+        assert_eq!(
+            rejection(validate_commands(&commands, u32::MAX, Some(18))),
+            Some((CROSS_DOMAIN_CMD_INIT, CROSS_DOMAIN_ERROR_INVALID_COMMAND))
+        );
+    }
+
+    fn write_command(data_size: u32, cmd_size: usize) -> Vec<u8> {
+        let cmd_write = CrossDomainReadWrite {
+            hdr: CrossDomainHeader {
+                cmd: CROSS_DOMAIN_CMD_WRITE,
+                cmd_size: cmd_size as u16,
+                ..Default::default()
+            },
+            opaque_data_size: data_size,
+            ..Default::default()
+        };
+
+        let mut commands = cmd_write.as_bytes().to_vec();
+        commands.resize(cmd_size, 0);
+        commands
+    }
+
+    #[test]
+    fn strict_command_layout() {
+        let invalid = |commands: &[u8]| {
+            rejection(validate_commands(commands, u32::MAX, Some(18)))
+                .map(|(_, error)| error == CROSS_DOMAIN_ERROR_INVALID_COMMAND)
+        };
+        let header_size = size_of::<CrossDomainReadWrite>();
+
+        assert_eq!(invalid(&write_command(8, header_size + 8)), None);
+        assert_eq!(invalid(&[0u8; 4]), Some(true));
+
+        // cmd_size below the command's minimum, or not covering its opaque data.
+        assert_eq!(invalid(&write_command(0, header_size - 4)), Some(true));
+        assert_eq!(invalid(&write_command(9, header_size + 8)), Some(true));
+
+        // Commands following one whose size isn't a multiple of 4 start unaligned.
+        let mut commands = write_command(1, header_size + 1);
+        commands.extend(resource_name_command());
+        assert_eq!(invalid(&commands), Some(true));
+
+        let mut commands = write_command(1, header_size + 4);
+        commands.extend(resource_name_command());
+        assert_eq!(invalid(&commands), None);
+
+        let cmd_send = CrossDomainSendReceive {
+            hdr: CrossDomainHeader {
+                cmd: CROSS_DOMAIN_CMD_SEND,
+                cmd_size: size_of::<CrossDomainSendReceive>() as u16,
+                ..Default::default()
+            },
+            num_identifiers: CROSS_DOMAIN_MAX_IDENTIFIERS as u32 + 1,
+            ..Default::default()
+        };
+        assert_eq!(invalid(cmd_send.as_bytes()), Some(true));
+
+        // Every command is checked before any of them runs.
+        let mut commands = resource_name_command();
+        commands.extend([0xff; 8]);
+        assert_eq!(
+            rejection(validate_commands(&commands, u32::MAX, Some(18))),
+            Some((0xff, CROSS_DOMAIN_ERROR_UNSUPPORTED_COMMAND))
+        );
+    }
+
+    #[test]
+    fn strict_commands_follow_guest_version() {
+        let commands = resource_name_command();
+        assert_eq!(
+            rejection(validate_commands(&commands, u32::MAX, Some(17))),
+            Some((
+                CROSS_DOMAIN_CMD_SET_RESOURCE_NAME,
+                CROSS_DOMAIN_ERROR_UNSUPPORTED_COMMAND
+            ))
+        );
+        // Guests that don't tell their version may speak any version before 16.
+        assert_eq!(
+            rejection(validate_commands(&commands, u32::MAX, Some(0))),
+            None
+        );
+
+        // The version given at init applies to the rest of its submission.
+        let mut commands = init_command(17);
+        commands.extend(resource_name_command());
+        assert_eq!(
+            rejection(validate_commands(&commands, u32::MAX, None)),
+            Some((
+                CROSS_DOMAIN_CMD_SET_RESOURCE_NAME,
+                CROSS_DOMAIN_ERROR_UNSUPPORTED_COMMAND
+            ))
+        );
+
+        let mut commands = init_command(18);
+        commands.extend(resource_name_command());
+        assert_eq!(
+            rejection(validate_commands(&commands, u32::MAX, None)),
+            None
+        );
+    }
+
+    #[test]
+    fn command_error_codes() {
+        let emfile = RutabagaError::MesaError(MesaError::IoError(
+            std::io::Error::from_raw_os_error(libc::EMFILE),
+        ));
+        assert_eq!(
+            command_error_code(&emfile),
+            CROSS_DOMAIN_ERROR_DESCRIPTOR_LIMIT
+        );
+        assert_eq!(
+            command_error_code(&RutabagaError::InvalidCrossDomainItemId),
+            CROSS_DOMAIN_ERROR_INVALID_COMMAND
+        );
+        assert_eq!(
+            command_error_code(&RutabagaError::InvalidIovec),
+            CROSS_DOMAIN_ERROR_HOST_FAILURE
+        );
+    }
+
+    // A worker of a guest batching reads, with a ring of `ring` filled with garbage.
+    fn new_batching_worker(ring: &mut [u8]) -> CrossDomainWorker {
+        ring.fill(0xff);
+        let worker = new_worker(ring, 17, Default::default());
+        worker.state.read_batching.store(true, Ordering::Relaxed);
+        worker
+    }
+
+    // Adds a read pipe holding `data`, and returns its id and write end.
+    fn ready_pipe(worker: &CrossDomainWorker, data: &[u8]) -> (u32, WritePipe) {
+        let (read_pipe, write_pipe) = create_pipe().unwrap();
+        write_pipe.write(data).unwrap();
+        let pipe_id = worker
+            .item_state
+            .lock()
+            .unwrap()
+            .insert(CrossDomainItem::WaylandReadPipe(read_pipe))
+            .unwrap();
+        (pipe_id, write_pipe)
+    }
+
+    fn readable(pipe_id: u32) -> WaitEvent {
+        WaitEvent {
+            connection_id: pipe_id as u64,
+            hung_up: false,
+            readable: true,
+        }
+    }
+
+    // Returns the CROSS_DOMAIN_CMD_READ records of a batch at the start of `ring`, checking their
+    // alignment and that a header ends the batch.
+    fn read_records(ring: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let header_size = size_of::<CrossDomainReadWrite>();
+        let mut records = Vec::new();
+        let mut offset = 0;
+        loop {
+            assert_eq!(offset % CROSS_DOMAIN_READ_ALIGNMENT, 0);
+            let (hdr, _) = CrossDomainHeader::read_from_prefix(&ring[offset..]).unwrap();
+            if hdr.cmd == 0 {
+                return records;
+            }
+
+            let (cmd_read, _) = CrossDomainReadWrite::read_from_prefix(&ring[offset..]).unwrap();
+            assert_eq!(cmd_read.hdr.cmd, CROSS_DOMAIN_CMD_READ);
+            let data_size = cmd_read.opaque_data_size as usize;
+            assert_eq!(
+                cmd_read.hdr.cmd_size as usize,
+                (header_size + data_size).next_multiple_of(CROSS_DOMAIN_READ_ALIGNMENT)
+            );
+
+            let data = &ring[offset + header_size..offset + header_size + data_size];
+            records.push((cmd_read.identifier, data.to_vec()));
+            offset += cmd_read.hdr.cmd_size as usize;
+        }
+    }
+
+    #[test]
+    fn batched_reads_layout() {
+        let mut ring = vec![0u8; 4096];
+        let mut worker = new_batching_worker(&mut ring);
+        let (first, _first_writer) = ready_pipe(&worker, &[1]);
+        let (second, _second_writer) = ready_pipe(&worker, &[2; 8]);
+        let (third, _third_writer) = ready_pipe(&worker, &[3; 13]);
+
+        worker
+            .read_pipes(&[readable(first), readable(second), readable(third)])
+            .unwrap();
+        drop(worker);
+
+        assert_eq!(
+            read_records(&ring),
+            vec![(first, vec![1]), (second, vec![2; 8]), (third, vec![3; 13]),]
+        );
+    }
+
+    #[test]
+    fn batched_reads_fit_ring() {
+        // Room for one record of 32 bytes of data, and the header ending the batch.
+        let mut ring = vec![0u8; 64];
+        let mut worker = new_batching_worker(&mut ring);
+        let (first, _first_writer) = ready_pipe(&worker, &[1; 100]);
+        let (second, _second_writer) = ready_pipe(&worker, &[2]);
+
+        worker
+            .read_pipes(&[readable(first), readable(second)])
+            .unwrap();
+        drop(worker);
+
+        assert_eq!(read_records(&ring), vec![(first, vec![1; 32])]);
+    }
+
+    #[test]
+    fn batched_read_errors_end_batch() {
+        let mut ring = vec![0u8; 4096];
+        let mut worker = new_batching_worker(&mut ring);
+        let (first, _first_writer) = ready_pipe(&worker, &[1; 3]);
+        let unknown = 1000;
+
+        // Without records to deliver, the error is returned.
+        assert!(worker.read_pipes(&[readable(unknown)]).is_err());
+
+        worker
+            .read_pipes(&[readable(first), readable(unknown)])
+            .unwrap();
+        drop(worker);
+
+        assert_eq!(read_records(&ring), vec![(first, vec![1; 3])]);
+    }
+
+    #[test]
+    fn flow_control_starts_with_first_ack() {
+        let state = CrossDomainState::new(1, CHANNEL_RING_ID, 17, Default::default());
+        let pipe_id = CROSS_DOMAIN_PIPE_READ_START + 1;
+
+        // Reads before the guest acknowledges any are never counted.
+        assert!(!state.pipe_read(pipe_id, CROSS_DOMAIN_PIPE_HIGH_WATER * 2));
+        assert!(!state.ack_read(pipe_id, CROSS_DOMAIN_PIPE_HIGH_WATER));
+        assert!(!state.pipe_read(pipe_id, CROSS_DOMAIN_PIPE_HIGH_WATER - 1));
+        assert!(!state.pipe_paused(pipe_id));
+
+        assert!(state.pipe_read(pipe_id, 1));
+        assert!(state.pipe_paused(pipe_id));
+        // A pipe is paused once.
+        assert!(!state.pipe_read(pipe_id, 1));
+    }
+
+    #[test]
+    fn flow_control_resumes_on_ack() {
+        let state = CrossDomainState::new(1, CHANNEL_RING_ID, 17, Default::default());
+        let (first, second) = (
+            CROSS_DOMAIN_PIPE_READ_START + 1,
+            CROSS_DOMAIN_PIPE_READ_START + 2,
+        );
+        state.ack_read(first, 0);
+
+        assert!(state.pipe_read(first, CROSS_DOMAIN_PIPE_HIGH_WATER + 10));
+        assert!(!state.pipe_read(second, 10));
+
+        // Pipes are counted separately, and resume once below the high-water mark.
+        assert!(!state.ack_read(second, 10));
+        assert!(!state.ack_read(first, 10));
+        assert!(state.ack_read(first, 1));
+        assert!(!state.pipe_paused(first));
+        assert!(!state.ack_read(first, 1));
+
+        // Removed pipes start over.
+        assert!(state.pipe_read(second, CROSS_DOMAIN_PIPE_HIGH_WATER));
+        state.remove_pipe(second);
+        assert!(!state.pipe_paused(second));
+        assert!(!state.pipe_read(second, 1));
+    }
+
+    #[test]
+    fn worker_resync_skips_hung_up_pipes() {
+        let mut ring = vec![0u8; 4096];
+        let mut worker = new_worker(&mut ring, 17, Default::default());
+
+        let (live_pipe, live_writer) = create_pipe().unwrap();
+        let (dead_pipe, dead_writer) = create_pipe().unwrap();
+        drop(dead_writer);
+        let (live_id, dead_id) = {
+            let mut items = worker.item_state.lock().unwrap();
+            (
+                items
+                    .insert(CrossDomainItem::WaylandReadPipe(live_pipe))
+                    .unwrap(),
+                items
+                    .insert(CrossDomainItem::WaylandReadPipe(dead_pipe))
+                    .unwrap(),
+            )
+        };
+
+        worker.resync().unwrap();
+        assert!(worker.item_state.lock().unwrap().get(dead_id).is_none());
+        assert!(worker
+            .wait_ctx
+            .wait(WaitTimeout::Finite(Duration::ZERO))
+            .unwrap()
+            .is_empty());
+
+        live_writer.write(&[1]).unwrap();
+        let events = worker
+            .wait_ctx
+            .wait(WaitTimeout::Finite(Duration::ZERO))
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].connection_id, live_id as u64);
+    }
+
+    #[test]
+    fn worker_closes_channel_out_of_descriptors() {
+        let mut ring = vec![0u8; 4096];
+        let mut query_ring = vec![0u8; 256];
+        let signaled: Arc<Mutex<Vec<u64>>> = Default::default();
+        let mut worker = new_worker(&mut ring, 17, signaled.clone());
+        worker.state.context_resources.lock().unwrap().insert(
+            1,
+            ContextResource {
+                handle: None,
+                backing_iovecs: Some(RutabagaBacking::new(vec![RutabagaIovec {
+                    base: query_ring.as_mut_ptr() as *mut _,
+                    len: query_ring.len(),
+                }])),
+            },
+        );
+        let descriptors = Arc::new(CrossDomainDescriptors::new(Some(0)));
+        worker.item_state = Arc::new(Mutex::new(CrossDomainItems::new(descriptors.clone())));
+
+        let path = std::env::temp_dir().join(format!("cross-domain-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = mesa3d_util::Listener::bind(&path).unwrap();
+        let connection = Tube::new(&path, TubeType::Packet).unwrap();
+        let peer = listener.accept().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        repoll(
+            &mut worker.wait_ctx,
+            CROSS_DOMAIN_CHANNEL_ID_START,
+            connection.as_borrowed_descriptor(),
+        )
+        .unwrap();
+        let channel = CrossDomainChannel {
+            channel_type: CROSS_DOMAIN_CHANNEL_TYPE_WAYLAND,
+            ring_id: CHANNEL_RING_ID,
+            connection,
+            wayland_trace: false,
+        };
+        worker.state.add_channel(0, channel).unwrap();
+
+        let file = std::fs::File::open("/dev/null").unwrap();
+        peer.send(b"message", &[OwnedDescriptor::from(file)])
+            .unwrap();
+        worker
+            .handle_fence(fence(1), &Event::new().unwrap(), &mut Vec::new())
+            .unwrap();
+        assert_eq!(*signaled.lock().unwrap(), [1]);
+
+        // The guest sees the channel hang up rather than a message missing its data.
+        let (cmd_receive, _) = CrossDomainSendReceive::read_from_prefix(&ring).unwrap();
+        assert_eq!(cmd_receive.hdr.cmd, CROSS_DOMAIN_CMD_RECEIVE);
+        assert_eq!(cmd_receive.hdr.channel_id, 0);
+        assert_eq!(cmd_receive.opaque_data_size, 0);
+        assert_eq!(cmd_receive.num_identifiers, 0);
+
+        let offset = query_ring.len() - size_of::<CrossDomainCommandError>();
+        let (report, _) = CrossDomainCommandError::read_from_prefix(&query_ring[offset..]).unwrap();
+        assert_eq!(report.seqno, 1);
+        assert_eq!(report.error, CROSS_DOMAIN_ERROR_DESCRIPTOR_LIMIT);
+
+        assert!(worker.state.channel(0).is_err());
+        assert_eq!(descriptors.used.load(Ordering::Relaxed), 0);
+        let (len, files) = peer.receive(&mut [0u8; 16]).unwrap();
+        assert_eq!((len, files.len()), (0, 0));
+    }
+}
@@ -0,0 +1,72 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Decodes and logs Wayland wire messages passing over a cross-domain channel, for debugging
+//! guest proxy (e.g. Sommelier) interop without modifying the guest.
+
+use log::info;
+use log::warn;
+
+// Each Wayland message starts with the sender's object id, followed by a word containing the
+// message size in bytes (upper 16 bits) and the opcode (lower 16 bits).
+const WAYLAND_HEADER_SIZE: usize = 8;
+
+/// The direction of a Wayland message relative to the host compositor.
+#[derive(Copy, Clone, Debug)]
+pub enum WaylandDirection {
+    GuestToHost,
+    HostToGuest,
+}
+
+impl WaylandDirection {
+    fn as_str(&self) -> &'static str {
+        match self {
+            WaylandDirection::GuestToHost => "guest -> host",
+            WaylandDirection::HostToGuest => "host -> guest",
+        }
+    }
+}
+
+/// Logs every Wayland message in `data`, which was transferred along with `num_fds` file
+/// descriptors.  Wayland doesn't say which message a descriptor belongs to, so the count is
+/// reported for the whole transfer.
+pub fn trace_wayland_messages(direction: WaylandDirection, data: &[u8], num_fds: usize) {
+    let direction = direction.as_str();
+    info!(
+        "wayland {}: {} bytes, {} fds",
+        direction,
+        data.len(),
+        num_fds
+    );
+
+    let mut remaining = data;
+    while remaining.len() >= WAYLAND_HEADER_SIZE {
+        let object_id = u32::from_ne_bytes(remaining[0..4].try_into().unwrap());
+        let size_opcode = u32::from_ne_bytes(remaining[4..8].try_into().unwrap());
+        let size = (size_opcode >> 16) as usize;
+        let opcode = size_opcode & 0xffff;
+
+        if size < WAYLAND_HEADER_SIZE || size > remaining.len() {
+            warn!(
+                "wayland {}: malformed message (object {}, opcode {}, size {}), {} bytes left",
+                direction,
+                object_id,
+                opcode,
+                size,
+                remaining.len()
+            );
+            return;
+        }
+
+        info!(
+            "wayland {}: object {} opcode {} size {}",
+            direction, object_id, opcode, size
+        );
+        remaining = &remaining[size..];
+    }
+
+    if !remaining.is_empty() {
+        warn!("wayland {}: {} trailing bytes", direction, remaining.len());
+    }
+}
@@ -30,6 +30,14 @@ use mesa3d_util::MesaHandle;
 use mesa3d_util::MesaMapping;
 use mesa3d_util::OwnedDescriptor;
 use mesa3d_util::RawDescriptor;
+#[cfg(not(windows))]
+use mesa3d_util::MESA_HANDLE_TYPE_MEM_DMABUF;
+#[cfg(not(windows))]
+use mesa3d_util::MESA_HANDLE_TYPE_MEM_OPAQUE_FD;
+#[cfg(windows)]
+use mesa3d_util::MESA_HANDLE_TYPE_MEM_OPAQUE_WIN32;
+#[cfg(not(windows))]
+use mesa3d_util::MESA_HANDLE_TYPE_MEM_SHM;
 use serde::Deserialize;
 use serde::Serialize;
 
@@ -42,17 +50,21 @@ use crate::renderer_utils::RutabagaCookie;
 use crate::renderer_utils::VirglBox;
 use crate::rutabaga_core::RutabagaComponent;
 use crate::rutabaga_core::RutabagaContext;
+use crate::rutabaga_core::RutabagaContextParams;
 use crate::rutabaga_core::RutabagaResource;
+use crate::rutabaga_utils::handle_type_mask;
 use crate::rutabaga_utils::DeviceId;
 use crate::rutabaga_utils::GfxstreamFlags;
 use crate::rutabaga_utils::ResourceCreate3D;
 use crate::rutabaga_utils::ResourceCreateBlob;
+use crate::rutabaga_utils::RutabagaBacking;
 use crate::rutabaga_utils::RutabagaComponentType;
 use crate::rutabaga_utils::RutabagaDebug;
 use crate::rutabaga_utils::RutabagaDebugHandler;
 use crate::rutabaga_utils::RutabagaError;
 use crate::rutabaga_utils::RutabagaFence;
 use crate::rutabaga_utils::RutabagaFenceHandler;
+use crate::rutabaga_utils::RutabagaHandleTypes;
 use crate::rutabaga_utils::RutabagaImportData;
 use crate::rutabaga_utils::RutabagaIovec;
 use crate::rutabaga_utils::RutabagaResult;
@@ -60,6 +72,7 @@ use crate::rutabaga_utils::Transfer3D;
 use crate::rutabaga_utils::VulkanInfo;
 use crate::rutabaga_utils::RUTABAGA_FLAG_FENCE_HOST_SHAREABLE;
 use crate::rutabaga_utils::RUTABAGA_HANDLE_TYPE_PLATFORM_AHB;
+use crate::rutabaga_utils::RUTABAGA_IMPORT_FLAG_3D_INFO;
 use crate::rutabaga_utils::RUTABAGA_IMPORT_FLAG_RESOURCE_EXISTS;
 use crate::rutabaga_utils::RUTABAGA_IMPORT_FLAG_VULKAN_INFO;
 use crate::rutabaga_utils::RUTABAGA_MAP_ACCESS_RW;
@@ -597,6 +610,32 @@ impl Drop for Gfxstream {
 }
 
 impl RutabagaComponent for Gfxstream {
+    // stream_renderer_create_blob() takes ownership of the handle.
+    fn imports_guest_handles(&self) -> bool {
+        true
+    }
+
+    // Android hardware buffers aren't memory handle types, so they aren't negotiated.
+    fn handle_types(&self) -> RutabagaHandleTypes {
+        #[cfg(windows)]
+        let handle_types = handle_type_mask(&[MESA_HANDLE_TYPE_MEM_OPAQUE_WIN32]);
+        #[cfg(not(windows))]
+        let handle_types = handle_type_mask(&[
+            MESA_HANDLE_TYPE_MEM_OPAQUE_FD,
+            MESA_HANDLE_TYPE_MEM_DMABUF,
+            MESA_HANDLE_TYPE_MEM_SHM,
+        ]);
+
+        RutabagaHandleTypes {
+            export: handle_types,
+            import: if cfg!(gfxstream_unstable) {
+                handle_types
+            } else {
+                0
+            },
+        }
+    }
+
     fn get_capset_info(&self, capset_id: u32) -> (u32, u32) {
         let mut version = 0;
         let mut size = 0;
@@ -770,6 +809,27 @@ impl RutabagaComponent for Gfxstream {
         }
     }
 
+    #[cfg(gfxstream_unstable)]
+    fn import_shared(&self, resource: &RutabagaResource) -> RutabagaResult<()> {
+        let handle = resource
+            .handle
+            .as_ref()
+            .ok_or(MesaError::InvalidMesaHandle)?
+            .try_clone()?;
+        let info_3d = resource
+            .info_3d
+            .ok_or(MesaError::WithContext("gfxstream imports require 3D info"))?;
+
+        let import_data = RutabagaImportData {
+            flags: RUTABAGA_IMPORT_FLAG_3D_INFO,
+            info_3d,
+        };
+
+        // The returned resource only mirrors the one already tracked by rutabaga.
+        self.import(resource.resource_id, handle, import_data)?;
+        Ok(())
+    }
+
     fn transfer_write(
         &self,
         ctx_id: u32,
@@ -933,7 +993,7 @@ impl RutabagaComponent for Gfxstream {
             info_2d: None,
             info_3d: None,
             vulkan_info: self.vulkan_info(resource_id).ok(),
-            backing_iovecs: iovec_opt,
+            backing_iovecs: iovec_opt.map(RutabagaBacking::new),
             component_mask: 1 << (RutabagaComponentType::Gfxstream as u8),
             size: resource_create_blob.size,
             mapping: None,
@@ -963,13 +1023,19 @@ impl RutabagaComponent for Gfxstream {
         ret_to_res(ret)
     }
 
+    // gfxstream schedules every context on the host's own Vulkan or GLES queues, and has no
+    // compute-only contexts.
     fn create_context(
         &self,
-        ctx_id: u32,
-        context_init: u32,
-        context_name: Option<&str>,
+        params: RutabagaContextParams,
         fence_handler: RutabagaFenceHandler,
     ) -> RutabagaResult<Box<dyn RutabagaContext>> {
+        let RutabagaContextParams {
+            ctx_id,
+            context_init,
+            context_name,
+            ..
+        } = params;
         let mut name: &str = "gpu_renderer";
         if let Some(name_string) = context_name.filter(|s| !s.is_empty()) {
             name = name_string;
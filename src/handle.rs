@@ -1,9 +1,23 @@
 // Copyright 2025 Google
 // SPDX-License-Identifier: BSD-3-Clause
 
+#[cfg(windows)]
+use mesa3d_util::AsRawDescriptor;
+use mesa3d_util::DescriptorType;
 use mesa3d_util::MesaError;
 use mesa3d_util::MesaHandle;
 use mesa3d_util::OwnedDescriptor;
+#[cfg(windows)]
+use mesa3d_util::RawDescriptor;
+use mesa3d_util::MESA_HANDLE_TYPE_MEM_DMABUF;
+use serde::Deserialize;
+use serde::Serialize;
+#[cfg(windows)]
+use windows_sys::Win32::Foundation::DuplicateHandle;
+#[cfg(windows)]
+use windows_sys::Win32::Foundation::DUPLICATE_SAME_ACCESS;
+#[cfg(windows)]
+use windows_sys::Win32::System::Threading::GetCurrentProcess;
 
 use crate::rutabaga_utils::RutabagaResult;
 
@@ -26,6 +40,32 @@ impl AhbInfo {
             metadata: self.metadata.clone(),
         })
     }
+
+    /// Returns the dmabuf holding the buffer's memory, which gralloc implementations pass as the
+    /// first fd.  Fails if the first fd isn't a dmabuf.
+    pub fn into_dmabuf(mut self) -> RutabagaResult<MesaHandle> {
+        if self.fds.is_empty() {
+            return Err(MesaError::InvalidMesaHandle.into());
+        }
+
+        let fd = self.fds.swap_remove(0);
+        match fd.determine_type() {
+            Ok(DescriptorType::Memory(_, MESA_HANDLE_TYPE_MEM_DMABUF)) => Ok(MesaHandle {
+                os_handle: fd,
+                handle_type: MESA_HANDLE_TYPE_MEM_DMABUF,
+            }),
+            _ => Err(MesaError::Unsupported.into()),
+        }
+    }
+}
+
+/// Describes a `RutabagaHandle` without its descriptors, so it can be saved in a snapshot or sent
+/// to another process.  The descriptors travel separately, in the order given by
+/// `RutabagaHandle::descriptors`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum RutabagaHandleMetadata {
+    MesaHandle { handle_type: u32 },
+    AhbInfo { num_fds: usize, metadata: Vec<u8> },
 }
 
 pub enum RutabagaHandle {
@@ -85,4 +125,97 @@ impl RutabagaHandle {
             _ => None,
         }
     }
+
+    /// Returns a new dmabuf for the handle's memory, converting an `AhbInfo` if possible.
+    pub fn to_dmabuf(&self) -> RutabagaResult<MesaHandle> {
+        match self {
+            RutabagaHandle::MesaHandle(handle) => {
+                if handle.handle_type != MESA_HANDLE_TYPE_MEM_DMABUF {
+                    return Err(MesaError::Unsupported.into());
+                }
+                Ok(handle.try_clone()?)
+            }
+            RutabagaHandle::AhbInfo(info) => info.try_clone()?.into_dmabuf(),
+        }
+    }
+
+    /// Duplicates the handle into `target_process`, which must have been opened with
+    /// PROCESS_DUP_HANDLE access.  The returned handle is only valid in, and owned by, the target
+    /// process.
+    #[cfg(windows)]
+    pub fn duplicate_into(&self, target_process: RawDescriptor) -> RutabagaResult<RawDescriptor> {
+        let handle = self.as_mesa_handle().ok_or(MesaError::InvalidMesaHandle)?;
+        let mut target_handle: RawDescriptor = std::ptr::null_mut();
+
+        // SAFETY:
+        // `handle` is valid for the lifetime of `self`, and DuplicateHandle only writes to
+        // `target_handle`.
+        let ret = unsafe {
+            DuplicateHandle(
+                GetCurrentProcess(),
+                handle.os_handle.as_raw_descriptor(),
+                target_process,
+                &mut target_handle,
+                0,
+                0,
+                DUPLICATE_SAME_ACCESS,
+            )
+        };
+
+        if ret == 0 {
+            return Err(MesaError::IoError(std::io::Error::last_os_error()).into());
+        }
+
+        Ok(target_handle)
+    }
+
+    /// Returns everything about the handle except its descriptors.
+    pub fn metadata(&self) -> RutabagaHandleMetadata {
+        match self {
+            RutabagaHandle::MesaHandle(handle) => RutabagaHandleMetadata::MesaHandle {
+                handle_type: handle.handle_type,
+            },
+            RutabagaHandle::AhbInfo(info) => RutabagaHandleMetadata::AhbInfo {
+                num_fds: info.fds.len(),
+                metadata: info.metadata.clone(),
+            },
+        }
+    }
+
+    /// Returns the handle's descriptors, in the order `from_parts` expects them.
+    pub fn descriptors(&self) -> Vec<&OwnedDescriptor> {
+        match self {
+            RutabagaHandle::MesaHandle(handle) => vec![&handle.os_handle],
+            RutabagaHandle::AhbInfo(info) => info.fds.iter().collect(),
+        }
+    }
+
+    /// Rebuilds a handle from the output of `metadata` and `descriptors`.
+    pub fn from_parts(
+        metadata: RutabagaHandleMetadata,
+        mut descriptors: Vec<OwnedDescriptor>,
+    ) -> RutabagaResult<RutabagaHandle> {
+        match metadata {
+            RutabagaHandleMetadata::MesaHandle { handle_type } => {
+                if descriptors.len() != 1 {
+                    return Err(MesaError::InvalidMesaHandle.into());
+                }
+
+                Ok(RutabagaHandle::MesaHandle(MesaHandle {
+                    os_handle: descriptors.remove(0),
+                    handle_type,
+                }))
+            }
+            RutabagaHandleMetadata::AhbInfo { num_fds, metadata } => {
+                if descriptors.len() != num_fds {
+                    return Err(MesaError::InvalidMesaHandle.into());
+                }
+
+                Ok(RutabagaHandle::AhbInfo(AhbInfo {
+                    fds: descriptors,
+                    metadata,
+                }))
+            }
+        }
+    }
 }
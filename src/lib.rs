@@ -16,9 +16,32 @@ mod macros;
 #[cfg(any(feature = "gfxstream", feature = "virgl_renderer"))]
 mod renderer_utils;
 mod rutabaga_2d;
+#[cfg(any(test, feature = "conformance"))]
+mod rutabaga_conformance;
 mod rutabaga_core;
+mod rutabaga_deferred;
+#[cfg(feature = "deterministic")]
+mod rutabaga_deterministic;
+mod rutabaga_events;
 mod rutabaga_gralloc;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+mod rutabaga_hostmem;
+mod rutabaga_lost;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+mod rutabaga_render_server;
+mod rutabaga_renderdoc;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+mod rutabaga_sandbox;
+mod rutabaga_shared;
+mod rutabaga_stats;
+mod rutabaga_subscribers;
+mod rutabaga_trace;
+mod rutabaga_transfer;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+mod rutabaga_udmabuf;
 mod rutabaga_utils;
+mod rutabaga_venus;
+mod rutabaga_vhost_user;
 mod snapshot;
 mod virgl_renderer;
 
@@ -27,21 +50,89 @@ pub use mesa3d_util::IntoRawDescriptor as RutabagaIntoRawDescriptor;
 pub use mesa3d_util::MappedRegion as RutabagaMappedRegion;
 pub use mesa3d_util::MesaError::Unsupported as RutabagaUnsupported;
 pub use mesa3d_util::MesaHandle as RutabagaMesaHandle;
+pub use mesa3d_util::MesaSchedulingPolicy as RutabagaSchedulingPolicy;
+pub use mesa3d_util::MesaThreadScheduling as RutabagaThreadScheduling;
 pub use mesa3d_util::OwnedDescriptor as RutabagaDescriptor;
 pub use mesa3d_util::RawDescriptor as RutabagaRawDescriptor;
 pub use mesa3d_util::MESA_HANDLE_TYPE_MEM_DMABUF as RUTABAGA_HANDLE_TYPE_MEM_DMABUF;
 pub use mesa3d_util::MESA_HANDLE_TYPE_MEM_OPAQUE_FD as RUTABAGA_HANDLE_TYPE_MEM_OPAQUE_FD;
 
+#[cfg(any(feature = "fuzzing", feature = "benchmarks"))]
+pub use crate::cross_domain::parse_cross_domain_commands;
+#[cfg(feature = "benchmarks")]
+pub use crate::cross_domain::write_cross_domain_receive;
 pub use crate::handle::AhbInfo;
 pub use crate::handle::RutabagaHandle;
+pub use crate::handle::RutabagaHandleMetadata;
+pub use crate::magma::RutabagaGpuDevice;
+#[cfg(feature = "conformance")]
+pub use crate::rutabaga_conformance::run_conformance;
+#[cfg(feature = "conformance")]
+pub use crate::rutabaga_conformance::ConformanceInit;
+#[cfg(feature = "conformance")]
+pub use crate::rutabaga_conformance::ConformanceTarget;
+#[cfg(feature = "conformance")]
+pub use crate::rutabaga_conformance::MockComponent;
 pub use crate::rutabaga_core::calculate_capset_mask;
 pub use crate::rutabaga_core::calculate_capset_names;
 pub use crate::rutabaga_core::Rutabaga;
 pub use crate::rutabaga_core::RutabagaBuilder;
+#[cfg(feature = "conformance")]
+pub use crate::rutabaga_core::RutabagaComponent;
+pub use crate::rutabaga_core::RutabagaHeldResource;
+pub use crate::rutabaga_core::RutabagaMappingInfo;
+pub use crate::rutabaga_core::RutabagaResourceInfo;
+pub use crate::rutabaga_core::RutabagaScanout;
+#[cfg(feature = "deterministic")]
+pub use crate::rutabaga_deterministic::RutabagaDeterministicExecutor;
+pub use crate::rutabaga_events::RutabagaCapsetsChanged;
+pub use crate::rutabaga_events::RutabagaDeviceMemoryReport;
+pub use crate::rutabaga_events::RutabagaEventHeader;
+pub use crate::rutabaga_events::RutabagaEventRingHeader;
+pub use crate::rutabaga_events::RUTABAGA_CONTEXT_EVENT_CAPSETS_CHANGED;
+pub use crate::rutabaga_events::RUTABAGA_CONTEXT_EVENT_DEVICE_MEMORY_REPORT;
+pub use crate::rutabaga_events::RUTABAGA_DEVICE_MEMORY_REPORT_ALLOCATE;
+pub use crate::rutabaga_events::RUTABAGA_DEVICE_MEMORY_REPORT_ALLOCATION_FAILED;
+pub use crate::rutabaga_events::RUTABAGA_DEVICE_MEMORY_REPORT_FREE;
+pub use crate::rutabaga_events::RUTABAGA_EVENT_ALIGNMENT;
 pub use crate::rutabaga_gralloc::DrmFormat;
+pub use crate::rutabaga_gralloc::GrallocBackend;
 pub use crate::rutabaga_gralloc::ImageAllocationInfo;
 pub use crate::rutabaga_gralloc::ImageMemoryRequirements;
 pub use crate::rutabaga_gralloc::RutabagaGralloc;
 pub use crate::rutabaga_gralloc::RutabagaGrallocBackendFlags;
 pub use crate::rutabaga_gralloc::RutabagaGrallocFlags;
+pub use crate::rutabaga_gralloc::RutabagaGrallocPolicy;
+pub use crate::rutabaga_gralloc::RutabagaGrallocRule;
+pub use crate::rutabaga_gralloc::DRM_FORMAT_MOD_INVALID;
+pub use crate::rutabaga_gralloc::DRM_FORMAT_MOD_LINEAR;
+pub use crate::rutabaga_gralloc::RUTABAGA_GRALLOC_MAX_MODIFIERS;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use crate::rutabaga_hostmem::RutabagaBlobPlacement;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use crate::rutabaga_hostmem::RutabagaSharedMemoryRegion;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use crate::rutabaga_render_server::RutabagaRenderServerConfig;
+pub use crate::rutabaga_renderdoc::RutabagaCaptureBoundary;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use crate::rutabaga_sandbox::RutabagaSandboxPolicy;
+pub use crate::rutabaga_shared::RutabagaShared;
+pub use crate::rutabaga_stats::RutabagaContextStats;
+pub use crate::rutabaga_stats::RutabagaDebugInfo;
+pub use crate::rutabaga_stats::RutabagaResourceStats;
+pub use crate::rutabaga_stats::RUTABAGA_FENCE_LATENCY_BUCKETS;
+pub use crate::rutabaga_stats::RUTABAGA_FENCE_LATENCY_BUCKET_LIMITS_US;
+pub use crate::rutabaga_subscribers::RutabagaFenceFilter;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use crate::rutabaga_udmabuf::RutabagaMemfdRegion;
 pub use crate::rutabaga_utils::*;
+pub use crate::rutabaga_venus::RUTABAGA_VENUS_FEATURE_MULTIPLE_TIMELINES;
+pub use crate::rutabaga_venus::RUTABAGA_VENUS_FEATURE_WAIT_SYNCS;
+pub use crate::rutabaga_vhost_user::RutabagaVhostUserCommand;
+pub use crate::rutabaga_vhost_user::RutabagaVhostUserDisplay;
+pub use crate::rutabaga_vhost_user::RutabagaVhostUserFences;
+pub use crate::rutabaga_vhost_user::RutabagaVhostUserGpu;
+pub use crate::rutabaga_vhost_user::RutabagaVhostUserMemory;
+pub use crate::rutabaga_vhost_user::RutabagaVhostUserMemoryRegion;
+pub use crate::rutabaga_vhost_user::RutabagaVhostUserUsed;
+pub use crate::rutabaga_vhost_user::RutabagaVhostUserUsedHandler;
@@ -0,0 +1,502 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Allocates the host blobs of magma contexts from the memory types of their host GPU, placing
+//! mappable blobs by the component's `RutabagaMemoryPlacement`.
+
+use std::collections::BTreeMap as Map;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use mesa3d_magma::magma_enumerate_devices;
+use mesa3d_magma::magma_error;
+use mesa3d_magma::MagmaBuffer;
+use mesa3d_magma::MagmaCreateBufferInfo;
+use mesa3d_magma::MagmaDevice;
+use mesa3d_magma::MagmaError;
+use mesa3d_magma::MagmaHeapBudget;
+use mesa3d_magma::MagmaMemoryType;
+use mesa3d_magma::MagmaSuspendSnapshot;
+use mesa3d_magma::MAGMA_BUFFER_FLAG_EXTERNAL;
+use mesa3d_magma::MAGMA_MEMORY_PROPERTY_DEVICE_LOCAL_BIT;
+use mesa3d_magma::MAGMA_MEMORY_PROPERTY_HOST_CACHED_BIT;
+use mesa3d_magma::MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT;
+use mesa3d_util::MesaError;
+use mesa3d_util::MesaHandle;
+use zerocopy::IntoBytes;
+
+use crate::magma::devices::RutabagaGpuDevice;
+use crate::rutabaga_events::RutabagaDeviceMemoryReport;
+use crate::rutabaga_events::RutabagaEventSink;
+use crate::rutabaga_events::RUTABAGA_CONTEXT_EVENT_DEVICE_MEMORY_REPORT;
+use crate::rutabaga_events::RUTABAGA_DEVICE_MEMORY_REPORT_ALLOCATE;
+use crate::rutabaga_events::RUTABAGA_DEVICE_MEMORY_REPORT_ALLOCATION_FAILED;
+use crate::rutabaga_events::RUTABAGA_DEVICE_MEMORY_REPORT_FREE;
+use crate::rutabaga_utils::RutabagaError;
+use crate::rutabaga_utils::RutabagaMemoryPlacement;
+use crate::rutabaga_utils::RutabagaResult;
+use crate::rutabaga_utils::RUTABAGA_MAP_ACCESS_RW;
+use crate::rutabaga_utils::RUTABAGA_MAP_CACHE_CACHED;
+use crate::rutabaga_utils::RUTABAGA_MAP_CACHE_WC;
+
+/// Share of the VRAM budget in use, in percent, past which `RutabagaMemoryPlacement::Heuristic`
+/// places blobs in system memory.
+const VRAM_LOW_PERCENT: u64 = 90;
+
+/// How long a VRAM budget sample is reused, so allocating many blobs doesn't query the kernel
+/// for each one.
+const VRAM_SAMPLE_PERIOD: Duration = Duration::from_secs(1);
+
+/// VK_OBJECT_TYPE_DEVICE_MEMORY, the object type of every reported blob.
+const VK_OBJECT_TYPE_DEVICE_MEMORY: u32 = 8;
+
+/// Converts a magma error, logging errors that have no `MesaError` equivalent.  A killed context
+/// or lost connection means the host GPU was reset, or the driver gave up on the connection.
+pub(crate) fn magma_error(e: MagmaError) -> RutabagaError {
+    match e {
+        MagmaError::MesaError(e) => e.into(),
+        MagmaError::ContextKilled | MagmaError::ConnectionLost => RutabagaError::ContextLost,
+        e => {
+            magma_error!("magma error: {e}");
+            MesaError::WithContext("magma allocation failed").into()
+        }
+    }
+}
+
+fn budget_low(budget: &MagmaHeapBudget) -> bool {
+    budget.budget > 0
+        && budget.usage.saturating_mul(100) >= budget.budget.saturating_mul(VRAM_LOW_PERCENT)
+}
+
+/// Returns the index of the memory type a blob is allocated from.  Mappable blobs must be host
+/// visible, and are placed by `placement`, which must already be resolved.  Memory types that
+/// don't match the placement are only used if none do.
+fn select_memory_type(
+    property_flags: &[u32],
+    placement: RutabagaMemoryPlacement,
+    mappable: bool,
+) -> Option<usize> {
+    let device_local = |flags: u32| flags & MAGMA_MEMORY_PROPERTY_DEVICE_LOCAL_BIT != 0;
+    let host_visible = |flags: u32| flags & MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT != 0;
+    let host_cached = |flags: u32| flags & MAGMA_MEMORY_PROPERTY_HOST_CACHED_BIT != 0;
+
+    if !mappable {
+        return property_flags
+            .iter()
+            .position(|flags| device_local(*flags))
+            .or_else(|| (!property_flags.is_empty()).then_some(0));
+    }
+
+    let preferred = |flags: u32| match placement {
+        RutabagaMemoryPlacement::Vram => device_local(flags),
+        RutabagaMemoryPlacement::Gtt => !device_local(flags) && !host_cached(flags),
+        RutabagaMemoryPlacement::System => !device_local(flags) && host_cached(flags),
+        RutabagaMemoryPlacement::Default | RutabagaMemoryPlacement::Heuristic => true,
+    };
+
+    property_flags
+        .iter()
+        .position(|flags| host_visible(*flags) && preferred(*flags))
+        .or_else(|| property_flags.iter().position(|flags| host_visible(*flags)))
+}
+
+struct ReportedBlob {
+    ctx_id: u32,
+    size: u64,
+    heap_index: u32,
+}
+
+/// Reports the host blobs of magma contexts as device memory reports, to the event rings of the
+/// contexts that have one.
+#[derive(Clone, Default)]
+pub(crate) struct MagmaMemoryReporter {
+    events: Option<RutabagaEventSink>,
+    // Every live blob, so frees can be reported.
+    blobs: Arc<Mutex<Map<u32, ReportedBlob>>>,
+}
+
+impl MagmaMemoryReporter {
+    pub fn new(events: Option<RutabagaEventSink>) -> MagmaMemoryReporter {
+        MagmaMemoryReporter {
+            events,
+            blobs: Default::default(),
+        }
+    }
+
+    pub fn allocated(&self, ctx_id: u32, resource_id: u32, size: u64, heap_index: u32) {
+        self.blobs.lock().unwrap().insert(
+            resource_id,
+            ReportedBlob {
+                ctx_id,
+                size,
+                heap_index,
+            },
+        );
+        self.report(
+            ctx_id,
+            RUTABAGA_DEVICE_MEMORY_REPORT_ALLOCATE,
+            resource_id,
+            size,
+            heap_index,
+        );
+    }
+
+    pub fn allocation_failed(&self, ctx_id: u32, resource_id: u32, size: u64) {
+        self.report(
+            ctx_id,
+            RUTABAGA_DEVICE_MEMORY_REPORT_ALLOCATION_FAILED,
+            resource_id,
+            size,
+            0,
+        );
+    }
+
+    pub fn freed(&self, resource_id: u32) {
+        let blob = self.blobs.lock().unwrap().remove(&resource_id);
+        if let Some(blob) = blob {
+            self.report(
+                blob.ctx_id,
+                RUTABAGA_DEVICE_MEMORY_REPORT_FREE,
+                resource_id,
+                blob.size,
+                blob.heap_index,
+            );
+        }
+    }
+
+    fn report(&self, ctx_id: u32, event_type: u32, resource_id: u32, size: u64, heap_index: u32) {
+        let Some(events) = &self.events else {
+            return;
+        };
+
+        let report = RutabagaDeviceMemoryReport {
+            event_type,
+            memory_object_id: resource_id as u64,
+            size,
+            object_type: VK_OBJECT_TYPE_DEVICE_MEMORY,
+            object_handle: resource_id as u64,
+            heap_index,
+            ..Default::default()
+        };
+        // Contexts without an event ring don't want reports.
+        let _ = events.send(
+            ctx_id,
+            RUTABAGA_CONTEXT_EVENT_DEVICE_MEMORY_REPORT,
+            report.as_bytes(),
+        );
+    }
+}
+
+/// The mappable host blobs of every magma context, by resource id.  Their contents are saved
+/// across suspend when their heap doesn't preserve them.  Cheap to clone, since all clones share
+/// the same state.
+#[derive(Clone, Default)]
+pub(crate) struct MagmaSuspendBuffers {
+    buffers: Arc<Mutex<Map<u32, (MagmaDevice, MagmaBuffer)>>>,
+    // The contents saved by `suspend()`, until `resume()` writes them back.
+    snapshots: Arc<Mutex<Vec<MagmaSuspendSnapshot>>>,
+}
+
+impl MagmaSuspendBuffers {
+    fn add(&self, resource_id: u32, device: &MagmaDevice, buffer: MagmaBuffer) {
+        self.buffers
+            .lock()
+            .unwrap()
+            .insert(resource_id, (device.clone(), buffer));
+    }
+
+    pub fn remove(&self, resource_id: u32) {
+        self.buffers.lock().unwrap().remove(&resource_id);
+    }
+
+    /// Saves the contents of the blobs in heaps that lose them on suspend.  The GPU must be idle.
+    pub fn suspend(&self) -> RutabagaResult<()> {
+        let buffers = self.buffers.lock().unwrap();
+        let mut snapshots = self.snapshots.lock().unwrap();
+        for (device, buffer) in buffers.values() {
+            let snapshot = device
+                .prepare_suspend(std::slice::from_ref(buffer))
+                .map_err(magma_error)?;
+            if !snapshot.is_empty() {
+                snapshots.push(snapshot);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the contents saved by `suspend()` back, once the GPU has resumed.
+    pub fn resume(&self) -> RutabagaResult<()> {
+        let snapshots = std::mem::take(&mut *self.snapshots.lock().unwrap());
+        for snapshot in snapshots {
+            snapshot.restore().map_err(magma_error)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) struct MagmaBlobAllocator {
+    device: MagmaDevice,
+    memory_types: Vec<MagmaMemoryType>,
+    placement: RutabagaMemoryPlacement,
+    suspend_buffers: MagmaSuspendBuffers,
+    // Whether VRAM was low when last sampled, and when, for `Heuristic` placement.
+    vram_sample: Option<(Instant, bool)>,
+}
+
+impl MagmaBlobAllocator {
+    /// Opens `device`, or the first host GPU if it is None.  Mappable blobs are added to
+    /// `suspend_buffers`.
+    pub fn open(
+        device: Option<RutabagaGpuDevice>,
+        placement: RutabagaMemoryPlacement,
+        suspend_buffers: MagmaSuspendBuffers,
+    ) -> RutabagaResult<MagmaBlobAllocator> {
+        let physical_devices = magma_enumerate_devices().map_err(magma_error)?;
+        let physical_device = physical_devices
+            .iter()
+            .find(|physical_device| {
+                let Some(device) = device else {
+                    return true;
+                };
+
+                let pci_info = physical_device.pci_info();
+                let pci_bus_info = physical_device.pci_bus_info();
+                pci_info.vendor_id == device.vendor_id
+                    && pci_info.device_id == device.device_id
+                    && pci_bus_info.domain == device.pci_domain
+                    && pci_bus_info.bus == device.pci_bus
+                    && pci_bus_info.device == device.pci_device
+                    && pci_bus_info.function == device.pci_function
+            })
+            .ok_or(MesaError::WithContext("gpu device not found"))?;
+
+        let device = physical_device.create_device().map_err(magma_error)?;
+        let mem_props = device.get_memory_properties().map_err(magma_error)?;
+        let memory_types = mem_props.memory_types[..mem_props.memory_type_count as usize].to_vec();
+
+        Ok(MagmaBlobAllocator {
+            device,
+            memory_types,
+            placement,
+            suspend_buffers,
+            vram_sample: None,
+        })
+    }
+
+    fn vram_low(&mut self) -> bool {
+        if let Some((sampled, low)) = self.vram_sample {
+            if sampled.elapsed() < VRAM_SAMPLE_PERIOD {
+                return low;
+            }
+        }
+
+        // Without a budget for the device local heap, VRAM is assumed to be plentiful.
+        let low = self
+            .memory_types
+            .iter()
+            .find(|memory_type| memory_type.is_device_local())
+            .and_then(|memory_type| self.device.get_memory_budget(memory_type.heap_idx).ok())
+            .is_some_and(|budget| budget_low(&budget));
+
+        self.vram_sample = Some((Instant::now(), low));
+        low
+    }
+
+    /// Allocates an exportable blob of `size` bytes for `resource_id`, returning its handle, its
+    /// map_info if it is mappable, and the heap it was allocated from.
+    pub fn allocate(
+        &mut self,
+        resource_id: u32,
+        size: u64,
+        mappable: bool,
+    ) -> RutabagaResult<(MesaHandle, Option<u32>, u32)> {
+        let placement = match self.placement {
+            RutabagaMemoryPlacement::Heuristic => match self.vram_low() {
+                true => RutabagaMemoryPlacement::System,
+                false => RutabagaMemoryPlacement::Vram,
+            },
+            placement => placement,
+        };
+
+        let property_flags: Vec<u32> = self
+            .memory_types
+            .iter()
+            .map(|memory_type| memory_type.property_flags)
+            .collect();
+        let memory_type_idx = select_memory_type(&property_flags, placement, mappable)
+            .ok_or(MesaError::WithContext("no suitable memory type"))?;
+
+        let create_info = MagmaCreateBufferInfo {
+            memory_type_idx: memory_type_idx as u32,
+            alignment: 0,
+            common_flags: MAGMA_BUFFER_FLAG_EXTERNAL,
+            vendor_flags: 0,
+            size,
+        };
+
+        // The exported handle keeps the memory alive once the buffer is dropped.
+        let buffer = self
+            .device
+            .create_buffer(&create_info)
+            .map_err(magma_error)?;
+        let handle = buffer.export().map_err(magma_error)?;
+        // Only mappable blobs are host visible, so the contents of others can't be saved.
+        if mappable {
+            self.suspend_buffers.add(resource_id, &self.device, buffer);
+        }
+
+        let map_info = mappable.then(|| {
+            let cache =
+                if property_flags[memory_type_idx] & MAGMA_MEMORY_PROPERTY_HOST_CACHED_BIT != 0 {
+                    RUTABAGA_MAP_CACHE_CACHED
+                } else {
+                    RUTABAGA_MAP_CACHE_WC
+                };
+            cache | RUTABAGA_MAP_ACCESS_RW
+        });
+
+        Ok((
+            handle,
+            map_info,
+            self.memory_types[memory_type_idx].heap_idx,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::c_void;
+
+    use mesa3d_magma::MAGMA_MEMORY_PROPERTY_HOST_COHERENT_BIT;
+    use zerocopy::FromBytes;
+
+    use super::*;
+    use crate::rutabaga_events::RutabagaEventHeader;
+    use crate::rutabaga_events::RutabagaEventRingHeader;
+    use crate::rutabaga_utils::RutabagaBacking;
+    use crate::rutabaga_utils::RutabagaFence;
+    use crate::rutabaga_utils::RutabagaHandler;
+    use crate::rutabaga_utils::RutabagaIovec;
+    use crate::rutabaga_utils::RUTABAGA_FLAG_FENCE;
+    use crate::rutabaga_utils::RUTABAGA_FLAG_INFO_RING_IDX;
+
+    const DL: u32 = MAGMA_MEMORY_PROPERTY_DEVICE_LOCAL_BIT;
+    const HV: u32 =
+        MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT | MAGMA_MEMORY_PROPERTY_HOST_COHERENT_BIT;
+    const HC: u32 = MAGMA_MEMORY_PROPERTY_HOST_CACHED_BIT;
+
+    // The memory types of a discrete GPU with resizable BAR: VRAM, cached GTT, visible VRAM
+    // and write-combined GTT.
+    const DISCRETE: [u32; 4] = [DL, HV | HC, DL | HV, HV];
+
+    #[test]
+    fn placement_selects_memory_type() {
+        let select = |placement| select_memory_type(&DISCRETE, placement, true);
+        assert_eq!(select(RutabagaMemoryPlacement::Default), Some(1));
+        assert_eq!(select(RutabagaMemoryPlacement::Vram), Some(2));
+        assert_eq!(select(RutabagaMemoryPlacement::Gtt), Some(3));
+        assert_eq!(select(RutabagaMemoryPlacement::System), Some(1));
+    }
+
+    #[test]
+    fn placement_falls_back_to_host_visible() {
+        // Integrated GPUs only have device local memory, which is host visible.
+        let integrated = [DL, DL | HV | HC];
+        for placement in [
+            RutabagaMemoryPlacement::Vram,
+            RutabagaMemoryPlacement::Gtt,
+            RutabagaMemoryPlacement::System,
+        ] {
+            assert_eq!(select_memory_type(&integrated, placement, true), Some(1));
+        }
+
+        assert_eq!(
+            select_memory_type(&[DL], RutabagaMemoryPlacement::System, true),
+            None
+        );
+    }
+
+    #[test]
+    fn unmappable_blobs_prefer_device_local() {
+        assert_eq!(
+            select_memory_type(&[HV, DL], RutabagaMemoryPlacement::System, false),
+            Some(1)
+        );
+        assert_eq!(
+            select_memory_type(&[HV], RutabagaMemoryPlacement::Vram, false),
+            Some(0)
+        );
+        assert_eq!(
+            select_memory_type(&[], RutabagaMemoryPlacement::Vram, false),
+            None
+        );
+    }
+
+    #[test]
+    fn memory_reports_reach_event_ring() {
+        let events = RutabagaEventSink::new(RutabagaHandler::new(|_| {}));
+        let mut ring = vec![0u8; 256];
+        let backing = RutabagaBacking::new(vec![RutabagaIovec {
+            base: ring.as_mut_ptr() as *mut c_void,
+            len: ring.len(),
+        }]);
+        events.attach(1, 0, backing).unwrap();
+
+        let reporter = MagmaMemoryReporter::new(Some(events.clone()));
+        reporter.allocated(1, 5, 4096, 2);
+        reporter.allocation_failed(1, 6, 1 << 40);
+        reporter.freed(5);
+        // Frees of unknown blobs, and reports for contexts without an event ring, are dropped.
+        reporter.freed(5);
+        reporter.allocated(2, 7, 4096, 0);
+
+        events
+            .wait(RutabagaFence {
+                flags: RUTABAGA_FLAG_FENCE | RUTABAGA_FLAG_INFO_RING_IDX,
+                fence_id: 1,
+                ctx_id: 1,
+                ring_idx: 0,
+            })
+            .unwrap();
+
+        let (header, mut records) = RutabagaEventRingHeader::read_from_prefix(&ring).unwrap();
+        assert_eq!(header.count, 3);
+        assert_eq!(header.dropped, 0);
+        let mut reports = Vec::new();
+        for _ in 0..header.count {
+            let (event, rest) = RutabagaEventHeader::read_from_prefix(records).unwrap();
+            assert_eq!(
+                event.event_type,
+                RUTABAGA_CONTEXT_EVENT_DEVICE_MEMORY_REPORT
+            );
+            assert_eq!(event.size as usize, size_of::<RutabagaDeviceMemoryReport>());
+            let (report, rest) = RutabagaDeviceMemoryReport::read_from_prefix(rest).unwrap();
+            reports.push((report.event_type, report.memory_object_id, report.size));
+            assert_eq!(report.object_type, VK_OBJECT_TYPE_DEVICE_MEMORY);
+            records = rest;
+        }
+
+        assert_eq!(
+            reports,
+            vec![
+                (RUTABAGA_DEVICE_MEMORY_REPORT_ALLOCATE, 5, 4096),
+                (RUTABAGA_DEVICE_MEMORY_REPORT_ALLOCATION_FAILED, 6, 1 << 40),
+                (RUTABAGA_DEVICE_MEMORY_REPORT_FREE, 5, 4096),
+            ]
+        );
+    }
+
+    #[test]
+    fn vram_low_threshold() {
+        let budget = |budget, usage| MagmaHeapBudget { budget, usage };
+        assert!(!budget_low(&budget(100, 89)));
+        assert!(budget_low(&budget(100, 90)));
+        assert!(budget_low(&budget(100, 120)));
+        assert!(!budget_low(&budget(0, 0)));
+    }
+}
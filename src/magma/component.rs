@@ -2,41 +2,138 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use mesa3d_util::MesaError;
+use mesa3d_util::MESA_HANDLE_TYPE_MEM_DMABUF;
+
+#[cfg(feature = "magma")]
+use crate::magma::allocator::MagmaMemoryReporter;
+#[cfg(feature = "magma")]
+use crate::magma::allocator::MagmaSuspendBuffers;
 use crate::magma::context::MagmaVirtioGpuContext;
+use crate::magma::devices::device_capset;
+use crate::magma::devices::RutabagaGpuDevice;
 use crate::rutabaga_core::RutabagaComponent;
 use crate::rutabaga_core::RutabagaContext;
+use crate::rutabaga_core::RutabagaContextParams;
+#[cfg(feature = "magma")]
+use crate::rutabaga_events::RutabagaEventSink;
+use crate::rutabaga_utils::handle_type_mask;
+use crate::rutabaga_utils::RutabagaDeviceLostHandler;
+use crate::rutabaga_utils::RutabagaFence;
 use crate::rutabaga_utils::RutabagaFenceHandler;
+use crate::rutabaga_utils::RutabagaMemoryPlacement;
 use crate::rutabaga_utils::RutabagaResult;
+use crate::rutabaga_utils::RUTABAGA_CONTEXT_INIT_DEVICE_MASK;
+use crate::rutabaga_utils::RUTABAGA_CONTEXT_INIT_DEVICE_SHIFT;
 
 pub struct MagmaVirtioGpu {
-    _fence_handler: RutabagaFenceHandler,
+    fence_handler: RutabagaFenceHandler,
+    devices: Vec<RutabagaGpuDevice>,
+    memory_placement: RutabagaMemoryPlacement,
+    device_lost_handler: Option<RutabagaDeviceLostHandler>,
+    #[cfg(feature = "magma")]
+    memory_reporter: MagmaMemoryReporter,
+    #[cfg(feature = "magma")]
+    suspend_buffers: MagmaSuspendBuffers,
 }
 
 impl MagmaVirtioGpu {
-    /// Initializes the magma component.
+    /// Initializes the magma component, which creates contexts on any of `devices`.
     pub fn init(
-        _fence_handler: RutabagaFenceHandler,
+        fence_handler: RutabagaFenceHandler,
+        devices: Vec<RutabagaGpuDevice>,
     ) -> RutabagaResult<Box<dyn RutabagaComponent>> {
-        Ok(Box::new(MagmaVirtioGpu { _fence_handler }))
+        Ok(Box::new(MagmaVirtioGpu {
+            fence_handler,
+            devices,
+            memory_placement: Default::default(),
+            device_lost_handler: None,
+            #[cfg(feature = "magma")]
+            memory_reporter: Default::default(),
+            #[cfg(feature = "magma")]
+            suspend_buffers: Default::default(),
+        }))
     }
 }
 
 impl RutabagaComponent for MagmaVirtioGpu {
+    // Magma has no work of its own outside contexts.
+    fn create_fence(&mut self, fence: RutabagaFence) -> RutabagaResult<()> {
+        self.fence_handler.call(fence);
+        Ok(())
+    }
+
     fn get_capset_info(&self, _capset_id: u32) -> (u32, u32) {
-        (0u32, 0u32)
+        (0u32, device_capset(&self.devices).len() as u32)
     }
 
     fn get_capset(&self, _capset_id: u32, _version: u32) -> Vec<u8> {
-        Vec::new()
+        device_capset(&self.devices)
+    }
+
+    /// Host blobs are exported as dma-bufs, which rutabaga maps directly.
+    fn mapped_handle_types(&self) -> u32 {
+        handle_type_mask(&[MESA_HANDLE_TYPE_MEM_DMABUF])
+    }
+
+    fn set_memory_placement(&mut self, placement: RutabagaMemoryPlacement) {
+        self.memory_placement = placement;
+    }
+
+    fn set_device_lost_handler(&mut self, handler: RutabagaDeviceLostHandler) {
+        self.device_lost_handler = Some(handler);
+    }
+
+    #[cfg(feature = "magma")]
+    fn set_event_sink(&mut self, events: RutabagaEventSink) {
+        self.memory_reporter = MagmaMemoryReporter::new(Some(events));
+    }
+
+    #[cfg(feature = "magma")]
+    fn unref_resource(&self, resource_id: u32) {
+        self.memory_reporter.freed(resource_id);
+        self.suspend_buffers.remove(resource_id);
+    }
+
+    /// Blobs in heaps that lose their contents while the GPU is suspended are saved to system
+    /// memory.
+    #[cfg(feature = "magma")]
+    fn suspend(&self) -> RutabagaResult<()> {
+        self.suspend_buffers.suspend()
+    }
+
+    #[cfg(feature = "magma")]
+    fn resume(&self) -> RutabagaResult<()> {
+        self.suspend_buffers.resume()
     }
 
     fn create_context(
         &self,
-        _ctx_id: u32,
-        _context_init: u32,
-        _context_name: Option<&str>,
+        params: RutabagaContextParams,
         _fence_handler: RutabagaFenceHandler,
     ) -> RutabagaResult<Box<dyn RutabagaContext>> {
-        Ok(Box::new(MagmaVirtioGpuContext::new(_fence_handler)))
+        // An index into the capset's GPUs.  Without any, index 0 opens magma's default GPU.
+        let device_index = (params.context_init & RUTABAGA_CONTEXT_INIT_DEVICE_MASK)
+            >> RUTABAGA_CONTEXT_INIT_DEVICE_SHIFT;
+        let device = self.devices.get(device_index as usize).copied();
+        if device.is_none() && device_index != 0 {
+            return Err(MesaError::WithContext("no gpu device with that index").into());
+        }
+
+        #[allow(unused_mut)]
+        let mut context = MagmaVirtioGpuContext::new(
+            params.ctx_id,
+            _fence_handler,
+            params.priority,
+            params.compute_only,
+            device,
+            self.memory_placement,
+            self.device_lost_handler.clone(),
+        );
+        #[cfg(feature = "magma")]
+        context.set_memory_reporter(self.memory_reporter.clone());
+        #[cfg(feature = "magma")]
+        context.set_suspend_buffers(self.suspend_buffers.clone());
+        Ok(Box::new(context))
     }
 }
@@ -2,39 +2,186 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::ops::Range;
 use std::sync::Arc;
 use std::sync::Mutex;
 
 use mesa3d_util::MesaError;
 use mesa3d_util::MesaHandle;
 
+use crate::context_common::invalidate_guest_memory;
 use crate::context_common::ContextResource;
 use crate::context_common::ContextResources;
 use crate::handle::RutabagaHandle;
+#[cfg(feature = "magma")]
+use crate::magma::allocator::MagmaBlobAllocator;
+#[cfg(feature = "magma")]
+use crate::magma::allocator::MagmaMemoryReporter;
+#[cfg(feature = "magma")]
+use crate::magma::allocator::MagmaSuspendBuffers;
+use crate::magma::devices::RutabagaGpuDevice;
 use crate::rutabaga_core::RutabagaContext;
 use crate::rutabaga_core::RutabagaResource;
 use crate::rutabaga_utils::ResourceCreateBlob;
 use crate::rutabaga_utils::RutabagaComponentType;
+use crate::rutabaga_utils::RutabagaContextPriority;
+#[cfg(feature = "magma")]
+use crate::rutabaga_utils::RutabagaDeviceLost;
+use crate::rutabaga_utils::RutabagaDeviceLostHandler;
+#[cfg(feature = "magma")]
+use crate::rutabaga_utils::RutabagaError;
 use crate::rutabaga_utils::RutabagaFence;
 use crate::rutabaga_utils::RutabagaFenceHandler;
+use crate::rutabaga_utils::RutabagaMemoryPlacement;
 use crate::rutabaga_utils::RutabagaResult;
+#[cfg(feature = "magma")]
+use crate::rutabaga_utils::RUTABAGA_BLOB_FLAG_USE_MAPPABLE;
 use crate::rutabaga_utils::RUTABAGA_BLOB_MEM_GUEST;
+#[cfg(feature = "magma")]
+use crate::rutabaga_utils::RUTABAGA_BLOB_MEM_HOST3D;
 
 pub struct MagmaVirtioGpuContext {
+    #[cfg(feature = "magma")]
+    ctx_id: u32,
     context_resources: ContextResources,
-    _fence_handler: RutabagaFenceHandler,
+    fence_handler: RutabagaFenceHandler,
+    // The priority of the host magma context, once magma contexts submit work.
+    _priority: RutabagaContextPriority,
+    // Would select the compute engine rather than the render engine for the host magma context,
+    // once magma contexts submit work.
+    _compute_only: bool,
+    // The host GPU the context runs on, or None for the default one.
+    #[cfg(feature = "magma")]
+    device: Option<RutabagaGpuDevice>,
+    // Where mappable blobs are allocated.
+    #[cfg(feature = "magma")]
+    memory_placement: RutabagaMemoryPlacement,
+    // Opened when the first host blob is created.
+    #[cfg(feature = "magma")]
+    allocator: Option<MagmaBlobAllocator>,
+    // Reports the context lost when the host GPU kills it.
+    #[cfg(feature = "magma")]
+    device_lost_handler: Option<RutabagaDeviceLostHandler>,
+    // Reports blobs to the context's event ring, if it has one.
+    #[cfg(feature = "magma")]
+    memory_reporter: MagmaMemoryReporter,
+    // The component's mappable blobs, saved across suspend.
+    #[cfg(feature = "magma")]
+    suspend_buffers: MagmaSuspendBuffers,
 }
 
 impl MagmaVirtioGpuContext {
-    pub fn new(fence_handler: RutabagaFenceHandler) -> MagmaVirtioGpuContext {
+    #[cfg_attr(not(feature = "magma"), allow(unused_variables))]
+    pub fn new(
+        ctx_id: u32,
+        fence_handler: RutabagaFenceHandler,
+        priority: RutabagaContextPriority,
+        compute_only: bool,
+        device: Option<RutabagaGpuDevice>,
+        memory_placement: RutabagaMemoryPlacement,
+        device_lost_handler: Option<RutabagaDeviceLostHandler>,
+    ) -> MagmaVirtioGpuContext {
         MagmaVirtioGpuContext {
+            #[cfg(feature = "magma")]
+            ctx_id,
             context_resources: Arc::new(Mutex::new(Default::default())),
-            _fence_handler: fence_handler,
+            fence_handler,
+            _priority: priority,
+            _compute_only: compute_only,
+            #[cfg(feature = "magma")]
+            device,
+            #[cfg(feature = "magma")]
+            memory_placement,
+            #[cfg(feature = "magma")]
+            allocator: None,
+            #[cfg(feature = "magma")]
+            device_lost_handler,
+            #[cfg(feature = "magma")]
+            memory_reporter: Default::default(),
+            #[cfg(feature = "magma")]
+            suspend_buffers: Default::default(),
         }
     }
+
+    #[cfg(feature = "magma")]
+    pub fn set_memory_reporter(&mut self, memory_reporter: MagmaMemoryReporter) {
+        self.memory_reporter = memory_reporter;
+    }
+
+    #[cfg(feature = "magma")]
+    pub fn set_suspend_buffers(&mut self, suspend_buffers: MagmaSuspendBuffers) {
+        self.suspend_buffers = suspend_buffers;
+    }
 }
 
 impl RutabagaContext for MagmaVirtioGpuContext {
+    /// Allocates host blobs from the context's GPU.  Without the magma feature, there is no host
+    /// GPU to allocate from.
+    #[cfg(feature = "magma")]
+    fn context_create_blob(
+        &mut self,
+        resource_id: u32,
+        resource_create_blob: ResourceCreateBlob,
+        handle_opt: Option<RutabagaHandle>,
+    ) -> RutabagaResult<RutabagaResource> {
+        if resource_create_blob.blob_mem != RUTABAGA_BLOB_MEM_HOST3D || handle_opt.is_some() {
+            return Err(MesaError::Unsupported.into());
+        }
+
+        let allocator = match self.allocator.as_mut() {
+            Some(allocator) => allocator,
+            None => self.allocator.insert(MagmaBlobAllocator::open(
+                self.device,
+                self.memory_placement,
+                self.suspend_buffers.clone(),
+            )?),
+        };
+
+        let mappable = resource_create_blob.blob_flags & RUTABAGA_BLOB_FLAG_USE_MAPPABLE != 0;
+        let allocated = allocator.allocate(resource_id, resource_create_blob.size, mappable);
+        if let (Err(RutabagaError::ContextLost), Some(handler)) =
+            (&allocated, &self.device_lost_handler)
+        {
+            handler.call(RutabagaDeviceLost {
+                ctx_id: Some(self.ctx_id),
+            });
+        }
+        let (handle, map_info, heap_index) = match allocated {
+            Ok(allocated) => allocated,
+            Err(e) => {
+                self.memory_reporter.allocation_failed(
+                    self.ctx_id,
+                    resource_id,
+                    resource_create_blob.size,
+                );
+                return Err(e);
+            }
+        };
+        self.memory_reporter.allocated(
+            self.ctx_id,
+            resource_id,
+            resource_create_blob.size,
+            heap_index,
+        );
+
+        Ok(RutabagaResource {
+            resource_id,
+            handle: Some(Arc::new(handle.into())),
+            blob: true,
+            blob_mem: resource_create_blob.blob_mem,
+            blob_flags: resource_create_blob.blob_flags,
+            map_info,
+            info_2d: None,
+            info_3d: None,
+            vulkan_info: None,
+            backing_iovecs: None,
+            component_mask: 1 << (RutabagaComponentType::Magma as u8),
+            size: resource_create_blob.size,
+            mapping: None,
+        })
+    }
+
+    #[cfg(not(feature = "magma"))]
     fn context_create_blob(
         &mut self,
         _resource_id: u32,
@@ -59,7 +206,7 @@ impl RutabagaContext for MagmaVirtioGpuContext {
                 resource.resource_id,
                 ContextResource {
                     handle: None,
-                    backing_iovecs: resource.backing_iovecs.take(),
+                    backing_iovecs: resource.backing_iovecs.clone(),
                 },
             );
         } else if let Some(ref handle) = resource.handle {
@@ -80,10 +227,13 @@ impl RutabagaContext for MagmaVirtioGpuContext {
             .remove(&resource.resource_id);
     }
 
-    fn context_create_fence(
-        &mut self,
-        _fence: RutabagaFence,
-    ) -> RutabagaResult<Option<MesaHandle>> {
+    fn invalidate_guest_memory(&mut self, range: &Range<u64>) -> Vec<u32> {
+        invalidate_guest_memory(&self.context_resources, range)
+    }
+
+    // Submissions don't run any work yet, so their fences signal right away.
+    fn context_create_fence(&mut self, fence: RutabagaFence) -> RutabagaResult<Option<MesaHandle>> {
+        self.fence_handler.call(fence);
         Ok(None)
     }
 
@@ -0,0 +1,181 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Host GPUs that magma contexts can be created on, and the capset that lists them to guests.
+
+#[cfg(feature = "magma")]
+use mesa3d_magma::magma_warn;
+#[cfg(feature = "magma")]
+use mesa3d_magma::MagmaLogHandler;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+
+#[cfg(feature = "magma")]
+use crate::rutabaga_utils::RutabagaComponentType;
+use crate::rutabaga_utils::RutabagaLogSink;
+#[cfg(feature = "magma")]
+use crate::rutabaga_utils::RutabagaLogger;
+
+/// A host GPU, identified by its PCI ids and location.  Platform devices have zeroed PCI ids
+/// except for the vendor.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RutabagaGpuDevice {
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub pci_domain: u16,
+    pub pci_bus: u8,
+    pub pci_device: u8,
+    pub pci_function: u8,
+}
+
+/// The magma capset is a `MagmaCapsetHeader` followed by `num_devices` `MagmaCapsetDevice`, in
+/// device index order.
+#[repr(C)]
+#[derive(Copy, Clone, Default, IntoBytes, Immutable)]
+struct MagmaCapsetHeader {
+    num_devices: u32,
+    pad: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default, IntoBytes, Immutable)]
+struct MagmaCapsetDevice {
+    vendor_id: u16,
+    device_id: u16,
+    pci_domain: u16,
+    pci_bus: u8,
+    pci_device: u8,
+    pci_function: u8,
+    pad: [u8; 7],
+}
+
+/// Sends the log messages of the magma library to `sink`, or to the `log` crate if it's None.  The
+/// library has a single handler per process, so the sink of the last magma component brought up
+/// wins.
+#[cfg(feature = "magma")]
+pub(crate) fn set_magma_log_sink(sink: Option<RutabagaLogSink>) {
+    mesa3d_magma::magma_set_log_handler(sink.map(magma_log_handler));
+}
+
+#[cfg(feature = "magma")]
+fn magma_log_handler(sink: RutabagaLogSink) -> MagmaLogHandler {
+    let logger = RutabagaLogger::new(RutabagaComponentType::Magma, Some(sink));
+    Box::new(move |level, args| logger.log(level, args))
+}
+
+#[cfg(not(feature = "magma"))]
+pub(crate) fn set_magma_log_sink(_sink: Option<RutabagaLogSink>) {}
+
+/// Enumerates the host GPUs with magma.  An empty list means only the default GPU is available.
+pub(crate) fn enumerate_gpu_devices() -> Vec<RutabagaGpuDevice> {
+    #[cfg(feature = "magma")]
+    match mesa3d_magma::magma_enumerate_devices() {
+        Ok(devices) => {
+            return devices
+                .iter()
+                .map(|device| {
+                    let pci_info = device.pci_info();
+                    let pci_bus_info = device.pci_bus_info();
+                    RutabagaGpuDevice {
+                        vendor_id: pci_info.vendor_id,
+                        device_id: pci_info.device_id,
+                        pci_domain: pci_bus_info.domain,
+                        pci_bus: pci_bus_info.bus,
+                        pci_device: pci_bus_info.device,
+                        pci_function: pci_bus_info.function,
+                    }
+                })
+                .collect();
+        }
+        Err(e) => magma_warn!("failed to enumerate gpu devices: {e}"),
+    }
+
+    Vec::new()
+}
+
+pub(crate) fn device_capset(devices: &[RutabagaGpuDevice]) -> Vec<u8> {
+    let header = MagmaCapsetHeader {
+        num_devices: devices.len() as u32,
+        ..Default::default()
+    };
+
+    let mut capset = header.as_bytes().to_vec();
+    for device in devices {
+        let capset_device = MagmaCapsetDevice {
+            vendor_id: device.vendor_id,
+            device_id: device.device_id,
+            pci_domain: device.pci_domain,
+            pci_bus: device.pci_bus,
+            pci_device: device.pci_device,
+            pci_function: device.pci_function,
+            ..Default::default()
+        };
+        capset.extend_from_slice(capset_device.as_bytes());
+    }
+
+    capset
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+    #[cfg(feature = "magma")]
+    use std::sync::Arc;
+    #[cfg(feature = "magma")]
+    use std::sync::Mutex;
+
+    #[cfg(feature = "magma")]
+    use log::Level;
+
+    use super::*;
+    #[cfg(feature = "magma")]
+    use crate::rutabaga_utils::RutabagaHandler;
+    #[cfg(feature = "magma")]
+    use crate::rutabaga_utils::RutabagaLogMessage;
+
+    #[test]
+    #[cfg(feature = "magma")]
+    fn magma_logs_reach_sink() {
+        let messages: Arc<Mutex<Vec<RutabagaLogMessage>>> = Default::default();
+        let sink_messages = messages.clone();
+        let handler = magma_log_handler(RutabagaHandler::new(move |message| {
+            sink_messages.lock().unwrap().push(message)
+        }));
+
+        handler(Level::Warn, format_args!("denied {}", 1));
+
+        let messages = messages.lock().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].component == RutabagaComponentType::Magma);
+        assert_eq!(messages[0].level, Level::Warn);
+        assert_eq!(messages[0].ctx_id, None);
+        assert_eq!(messages[0].message, "denied 1");
+    }
+
+    #[test]
+    fn capset_layout() {
+        assert_eq!(size_of::<MagmaCapsetHeader>(), 8);
+        assert_eq!(size_of::<MagmaCapsetDevice>(), 16);
+
+        let device = RutabagaGpuDevice {
+            vendor_id: 0x8086,
+            device_id: 0x46a6,
+            pci_domain: 0x0102,
+            pci_bus: 3,
+            pci_device: 4,
+            pci_function: 5,
+        };
+        let capset = device_capset(&[device, Default::default()]);
+        assert_eq!(
+            capset[..24],
+            [
+                2, 0, 0, 0, 0, 0, 0, 0, // header
+                0x86, 0x80, 0xa6, 0x46, 0x02, 0x01, 3, 4, 5, 0, 0, 0, 0, 0, 0, 0,
+            ]
+        );
+        assert_eq!(capset.len(), 8 + 2 * 16);
+        assert!(capset[24..].iter().all(|byte| *byte == 0));
+        assert_eq!(device_capset(&[]), [0; 8]);
+    }
+}
@@ -2,7 +2,15 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+#[cfg(feature = "magma")]
+mod allocator;
 mod component;
 mod context;
+mod devices;
 
+#[cfg(all(windows, feature = "magma"))]
+pub(crate) use allocator::magma_error;
 pub use component::MagmaVirtioGpu;
+pub(crate) use devices::enumerate_gpu_devices;
+pub(crate) use devices::set_magma_log_sink;
+pub use devices::RutabagaGpuDevice;
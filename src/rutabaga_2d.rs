@@ -7,6 +7,7 @@
 use std::cmp::max;
 use std::cmp::min;
 use std::cmp::Ordering;
+use std::io::ErrorKind;
 use std::io::IoSlice;
 use std::io::IoSliceMut;
 
@@ -16,8 +17,10 @@ use crate::handle::RutabagaHandle;
 use crate::rutabaga_core::Rutabaga2DInfo;
 use crate::rutabaga_core::RutabagaComponent;
 use crate::rutabaga_core::RutabagaResource;
+use crate::rutabaga_transfer::RutabagaTransferJob;
 use crate::rutabaga_utils::ResourceCreate3D;
 use crate::rutabaga_utils::ResourceCreateBlob;
+use crate::rutabaga_utils::RutabagaBacking;
 use crate::rutabaga_utils::RutabagaComponentType;
 use crate::rutabaga_utils::RutabagaError;
 use crate::rutabaga_utils::RutabagaFence;
@@ -29,6 +32,38 @@ use crate::snapshot::RutabagaSnapshotReader;
 use crate::snapshot::RutabagaSnapshotWriter;
 use crate::RUTABAGA_BLOB_MEM_GUEST;
 
+/// A chunk of a transfer source.  Holes take up space in the source, but have no backing memory
+/// and read as zeros.
+enum TransferSource<'a> {
+    Backed(&'a [u8]),
+    Hole(usize),
+}
+
+impl TransferSource<'_> {
+    fn len(&self) -> usize {
+        match self {
+            TransferSource::Backed(slice) => slice.len(),
+            TransferSource::Hole(len) => *len,
+        }
+    }
+}
+
+fn iovec_sources(iovecs: &[RutabagaIovec]) -> Vec<TransferSource<'_>> {
+    iovecs
+        .iter()
+        .map(|iovec| {
+            if iovec.is_hole() {
+                TransferSource::Hole(iovec.len)
+            } else {
+                // SAFETY:
+                // Safe because Rutabaga users should have already checked the iovecs.
+                let slice = unsafe { std::slice::from_raw_parts(iovec.base as *mut u8, iovec.len) };
+                TransferSource::Backed(slice)
+            }
+        })
+        .collect()
+}
+
 /// Transfers a resource from potentially many chunked src slices to a dst slice.
 #[allow(clippy::too_many_arguments)]
 fn transfer_2d(
@@ -43,7 +78,7 @@ fn transfer_2d(
     mut dst: IoSliceMut,
     src_stride: u32,
     src_offset: u64,
-    srcs: &[&[u8]],
+    srcs: &[TransferSource],
 ) -> RutabagaResult<()> {
     if rect_w == 0 || rect_h == 0 {
         return Ok(());
@@ -65,6 +100,44 @@ fn transfer_2d(
     let src_stride = src_stride as u64;
     let src_resource_offset = src_offset + (rect_y * src_stride) + (rect_x * bytes_per_pixel);
 
+    let line_size = rect_w * bytes_per_pixel;
+
+    // Rows spanning the whole stride of both src and dst are contiguous, and copy as one span.
+    if line_size == src_stride && line_size == dst_stride {
+        let span_size = checked_arithmetic!(line_size * rect_h)?;
+        return copy_span(
+            &mut dst,
+            dst_resource_offset,
+            srcs,
+            src_resource_offset,
+            span_size,
+        );
+    }
+
+    // A single backed src, such as host memory or a contiguous guest backing, copies row by row
+    // once the last row is known to fit.  Otherwise, the general path below reports errors.
+    if let [TransferSource::Backed(src)] = srcs {
+        let last_line = rect_h - 1;
+        let src_last_line_offset = checked_arithmetic!(last_line * src_stride)?;
+        let src_last_line_start = checked_arithmetic!(src_resource_offset + src_last_line_offset)?;
+        let src_end = checked_arithmetic!(src_last_line_start + line_size)?;
+        let dst_last_line_offset = checked_arithmetic!(last_line * dst_stride)?;
+        let dst_last_line_start = checked_arithmetic!(dst_resource_offset + dst_last_line_offset)?;
+        let dst_end = checked_arithmetic!(dst_last_line_start + line_size)?;
+
+        if src_end <= src.len() as u64 && dst_end <= dst.len() as u64 {
+            let line_size = line_size as usize;
+            for line in 0..rect_h {
+                let src_start = (src_resource_offset + line * src_stride) as usize;
+                let dst_start = (dst_resource_offset + line * dst_stride) as usize;
+                dst[dst_start..dst_start + line_size]
+                    .copy_from_slice(&src[src_start..src_start + line_size]);
+            }
+
+            return Ok(());
+        }
+    }
+
     let mut next_src;
     let mut next_line;
     let mut current_height = 0u64;
@@ -84,13 +157,11 @@ fn transfer_2d(
         let src_end_offset = checked_arithmetic!(src_start_offset + src_size)?;
 
         let src_line_vertical_offset = checked_arithmetic!(current_height * src_stride)?;
-        let src_line_horizontal_offset = checked_arithmetic!(rect_w * bytes_per_pixel)?;
 
         // Cumulative start/end offsets of the next line to copy within all srcs.
         let src_line_start_offset =
             checked_arithmetic!(src_resource_offset + src_line_vertical_offset)?;
-        let src_line_end_offset =
-            checked_arithmetic!(src_line_start_offset + src_line_horizontal_offset)?;
+        let src_line_end_offset = checked_arithmetic!(src_line_start_offset + line_size)?;
 
         // Clamp the line start/end offset to be inside the current src.
         let src_copyable_start_offset = max(src_line_start_offset, src_start_offset);
@@ -117,11 +188,6 @@ fn transfer_2d(
                 }
             }
 
-            let src_end = offset_within_src + copyable_size;
-            let src_subslice = src
-                .get(offset_within_src as usize..src_end as usize)
-                .ok_or(RutabagaError::InvalidIovec)?;
-
             let dst_line_vertical_offset = checked_arithmetic!(current_height * dst_stride)?;
             let dst_line_horizontal_offset =
                 checked_arithmetic!(src_copyable_start_offset - src_line_start_offset)?;
@@ -134,7 +200,16 @@ fn transfer_2d(
                 .get_mut(dst_start_offset as usize..dst_end_offset as usize)
                 .ok_or(RutabagaError::InvalidIovec)?;
 
-            dst_subslice.copy_from_slice(src_subslice);
+            match src {
+                TransferSource::Backed(src) => {
+                    let src_end = offset_within_src + copyable_size;
+                    let src_subslice = src
+                        .get(offset_within_src as usize..src_end as usize)
+                        .ok_or(RutabagaError::InvalidIovec)?;
+                    dst_subslice.copy_from_slice(src_subslice);
+                }
+                TransferSource::Hole(_) => dst_subslice.fill(0),
+            }
         } else if src_line_start_offset >= src_start_offset {
             next_src = true;
             next_line = false;
@@ -156,6 +231,279 @@ fn transfer_2d(
     Ok(())
 }
 
+/// Copies `size` bytes, starting `src_offset` bytes into the concatenation of `srcs`, to `dst` at
+/// `dst_offset`.  Takes one copy per src, and stops early if the srcs run out.
+fn copy_span(
+    dst: &mut [u8],
+    dst_offset: u64,
+    srcs: &[TransferSource],
+    src_offset: u64,
+    size: u64,
+) -> RutabagaResult<()> {
+    let span_end = checked_arithmetic!(src_offset + size)?;
+
+    // Cumulative start offset of the current src.
+    let mut src_start_offset = 0u64;
+    for src in srcs {
+        if src_start_offset >= span_end {
+            break;
+        }
+
+        let src_size = src.len() as u64;
+        let src_end_offset = checked_arithmetic!(src_start_offset + src_size)?;
+        let copyable_start_offset = max(src_offset, src_start_offset);
+        let copyable_end_offset = min(span_end, src_end_offset);
+
+        if copyable_start_offset < copyable_end_offset {
+            let copyable_size = (copyable_end_offset - copyable_start_offset) as usize;
+            let dst_start = dst_offset + (copyable_start_offset - src_offset);
+            let dst_start = dst_start as usize;
+            let dst_subslice = dst
+                .get_mut(dst_start..dst_start + copyable_size)
+                .ok_or(RutabagaError::InvalidIovec)?;
+
+            match src {
+                TransferSource::Backed(src) => {
+                    let offset_within_src = (copyable_start_offset - src_start_offset) as usize;
+                    let src_subslice = src
+                        .get(offset_within_src..offset_within_src + copyable_size)
+                        .ok_or(RutabagaError::InvalidIovec)?;
+                    dst_subslice.copy_from_slice(src_subslice);
+                }
+                TransferSource::Hole(_) => dst_subslice.fill(0),
+            }
+        }
+
+        src_start_offset = src_end_offset;
+    }
+
+    Ok(())
+}
+
+/// Where a mip level of a resource lives in its host memory.  Levels are stored one after another,
+/// each holding all of its layers.
+#[derive(Copy, Clone)]
+struct LevelLayout {
+    width: u32,
+    height: u32,
+    offset: u64,
+    layer_size: u64,
+}
+
+impl LevelLayout {
+    fn new(width: u32, height: u32, layers: u32, level: u32) -> RutabagaResult<LevelLayout> {
+        // All official virtio_gpu formats are 4 bytes per pixel.
+        let resource_bpp = 4u64;
+        let extent = |level: u32| {
+            let level_width = width.checked_shr(level).unwrap_or(0).max(1);
+            let level_height = height.checked_shr(level).unwrap_or(0).max(1);
+            let layer_size = resource_bpp * level_width as u64 * level_height as u64;
+            (level_width, level_height, layer_size)
+        };
+
+        let layers = layers as u64;
+        let mut offset = 0u64;
+        for previous in 0..level {
+            let (_, _, layer_size) = extent(previous);
+            let level_size = checked_arithmetic!(layer_size * layers)?;
+            offset = checked_arithmetic!(offset + level_size)?;
+        }
+
+        let (width, height, layer_size) = extent(level);
+        Ok(LevelLayout {
+            width,
+            height,
+            offset,
+            layer_size,
+        })
+    }
+
+    /// Returns the layout of the level of `info_2d` accessed by `transfer`, after checking the
+    /// transfer stays within the resource's levels and layers.
+    fn for_transfer(
+        info_2d: &Rutabaga2DInfo,
+        transfer: &Transfer3D,
+    ) -> RutabagaResult<LevelLayout> {
+        let last_level = info_2d.levels.saturating_sub(1);
+        let layer_end = transfer.z as u64 + transfer.d.max(1) as u64;
+        let layers = info_2d.layers as u64;
+        checked_range!(transfer.level; <= last_level)?;
+        checked_range!(layer_end; <= layers)?;
+
+        LevelLayout::new(
+            info_2d.width,
+            info_2d.height,
+            info_2d.layers,
+            transfer.level,
+        )
+    }
+
+    /// Returns the offset of `layer` in memory.
+    fn layer_offset(&self, layer: u32) -> RutabagaResult<u64> {
+        let (offset, layer_size, layer) = (self.offset, self.layer_size, layer as u64);
+        let layer_offset = checked_arithmetic!(layer * layer_size)?;
+        checked_arithmetic!(offset + layer_offset)
+    }
+}
+
+/// Returns the size of the host memory of a `width` x `height` resource with `layers` array layers
+/// and `levels` mip levels.
+pub(crate) fn host_mem_size(
+    width: u32,
+    height: u32,
+    layers: u32,
+    levels: u32,
+) -> RutabagaResult<usize> {
+    checked_range!(levels; <= u32::BITS)?;
+
+    let last = LevelLayout::new(width, height, layers, levels.saturating_sub(1))?;
+    let (offset, layer_size, layers) = (last.offset, last.layer_size, layers as u64);
+    let last_size = checked_arithmetic!(layer_size * layers)?;
+    let size = checked_arithmetic!(offset + last_size)?;
+    Ok(size.try_into().map_err(MesaError::TryFromIntError)?)
+}
+
+/// Copies the region of the guest's `iovecs` given by `transfer` to `level` of a resource's host
+/// memory.  The guest's layers are `transfer.layer_stride` bytes apart, or packed if it's zero.
+fn write_host_mem(
+    level: LevelLayout,
+    transfer: &Transfer3D,
+    host_mem: &mut [u8],
+    iovecs: &[RutabagaIovec],
+) -> RutabagaResult<()> {
+    // All official virtio_gpu formats are 4 bytes per pixel.
+    let resource_bpp = 4;
+    let src_slices = iovec_sources(iovecs);
+
+    let src_stride = resource_bpp * level.width;
+    let src_layer_stride = match transfer.layer_stride {
+        0 => level.layer_size,
+        layer_stride => layer_stride as u64,
+    };
+
+    let dst_stride = resource_bpp * level.width;
+
+    let base_offset = transfer.offset;
+    for layer in 0..transfer.d.max(1) {
+        let index = layer as u64;
+        let src_layer_offset = checked_arithmetic!(src_layer_stride * index)?;
+        let src_offset = checked_arithmetic!(src_layer_offset + base_offset)?;
+        let dst_offset = level.layer_offset(transfer.z + layer)?;
+
+        transfer_2d(
+            level.width,
+            level.height,
+            transfer.x,
+            transfer.y,
+            transfer.w,
+            transfer.h,
+            dst_stride,
+            dst_offset,
+            IoSliceMut::new(host_mem),
+            src_stride,
+            src_offset,
+            &src_slices,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Copies `src` to the concatenation of `iovecs`, starting `offset` bytes in.  What falls in holes
+/// is dropped.
+fn scatter_to_iovecs(iovecs: &[RutabagaIovec], offset: u64, src: &[u8]) -> RutabagaResult<()> {
+    let len = src.len() as u64;
+    let end = checked_arithmetic!(offset + len)?;
+
+    // Cumulative start offset of the current iovec.
+    let mut iovec_start = 0u64;
+    for iovec in iovecs {
+        if iovec_start >= end {
+            break;
+        }
+
+        let iovec_len = iovec.len as u64;
+        let iovec_end = checked_arithmetic!(iovec_start + iovec_len)?;
+        let copy_start = max(offset, iovec_start);
+        let copy_end = min(end, iovec_end);
+        if copy_start < copy_end && !iovec.is_hole() {
+            // SAFETY:
+            // Safe because Rutabaga users should have already checked the iovecs.
+            let dst = unsafe { std::slice::from_raw_parts_mut(iovec.base as *mut u8, iovec.len) };
+            dst[(copy_start - iovec_start) as usize..(copy_end - iovec_start) as usize]
+                .copy_from_slice(
+                    &src[(copy_start - offset) as usize..(copy_end - offset) as usize],
+                );
+        }
+
+        iovec_start = iovec_end;
+    }
+
+    if iovec_start < end {
+        return Err(RutabagaError::InvalidIovec);
+    }
+
+    Ok(())
+}
+
+/// Copies the region of `level` of a resource's host memory given by `transfer` to the guest's
+/// `iovecs`, laid out as `write_host_mem()` expects them.
+fn read_host_mem(
+    level: LevelLayout,
+    transfer: &Transfer3D,
+    host_mem: &[u8],
+    iovecs: &[RutabagaIovec],
+) -> RutabagaResult<()> {
+    if transfer.is_empty() {
+        return Ok(());
+    }
+
+    let (x, y, w, h) = (transfer.x, transfer.y, transfer.w, transfer.h);
+    checked_range!(checked_arithmetic!(x + w)?; <= level.width)?;
+    checked_range!(checked_arithmetic!(y + h)?; <= level.height)?;
+
+    // All official virtio_gpu formats are 4 bytes per pixel.
+    let resource_bpp = 4u64;
+    let stride = resource_bpp * level.width as u64;
+    let line_size = resource_bpp * w as u64;
+    let dst_layer_stride = match transfer.layer_stride {
+        0 => level.layer_size,
+        layer_stride => layer_stride as u64,
+    };
+
+    let base_offset = transfer.offset;
+    for layer in 0..transfer.d.max(1) {
+        let index = layer as u64;
+        let src_offset = level.layer_offset(transfer.z + layer)?;
+        let dst_layer_offset = checked_arithmetic!(dst_layer_stride * index)?;
+        let dst_offset = checked_arithmetic!(dst_layer_offset + base_offset)?;
+
+        for line in y as u64..(y + h) as u64 {
+            let line_offset = line * stride + x as u64 * resource_bpp;
+            let src_start = checked_arithmetic!(src_offset + line_offset)?;
+            let src_end = checked_arithmetic!(src_start + line_size)?;
+            let src = host_mem
+                .get(src_start as usize..src_end as usize)
+                .ok_or(RutabagaError::InvalidIovec)?;
+            let dst_start = checked_arithmetic!(dst_offset + line_offset)?;
+            scatter_to_iovecs(iovecs, dst_start, src)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The host memory of a resource, as accessed by a queued transfer.
+struct HostMem {
+    base: *mut u8,
+    len: usize,
+}
+
+// SAFETY:
+// Rutabaga waits for the queued transfers of a resource before freeing or otherwise accessing its
+// host memory, so the transfer worker has exclusive access to it.
+unsafe impl Send for HostMem {}
+
 pub struct Rutabaga2D {
     fence_handler: RutabagaFenceHandler,
 }
@@ -177,14 +525,28 @@ impl RutabagaComponent for Rutabaga2D {
         resource_id: u32,
         resource_create_3d: ResourceCreate3D,
     ) -> RutabagaResult<RutabagaResource> {
-        // All virtio formats are 4 bytes per pixel.
-        let resource_bpp = 4;
-        let resource_stride = resource_bpp * resource_create_3d.width;
-        let resource_size = (resource_stride as usize) * (resource_create_3d.height as usize);
+        let layers = resource_create_3d.array_size.max(1);
+        let levels = resource_create_3d.last_level.saturating_add(1);
+        let resource_size = host_mem_size(
+            resource_create_3d.width,
+            resource_create_3d.height,
+            layers,
+            levels,
+        )?;
+
+        // The size is up to the guest, so failing to allocate it mustn't abort.
+        let mut host_mem = Vec::new();
+        host_mem
+            .try_reserve_exact(resource_size)
+            .map_err(|_| MesaError::IoError(ErrorKind::OutOfMemory.into()))?;
+        host_mem.resize(resource_size, 0);
+
         let info_2d = Rutabaga2DInfo {
             width: resource_create_3d.width,
             height: resource_create_3d.height,
-            host_mem: Some(vec![0; resource_size]),
+            layers,
+            levels,
+            host_mem: Some(host_mem),
             scanout_stride: None,
         };
 
@@ -221,6 +583,8 @@ impl RutabagaComponent for Rutabaga2D {
         let info_2d = Rutabaga2DInfo {
             width: 0,
             height: 0,
+            layers: 1,
+            levels: 1,
             host_mem: None,
             scanout_stride: None,
         };
@@ -235,13 +599,18 @@ impl RutabagaComponent for Rutabaga2D {
             info_2d: Some(info_2d),
             info_3d: None,
             vulkan_info: None,
-            backing_iovecs: iovec_opt,
+            backing_iovecs: iovec_opt.map(RutabagaBacking::new),
             component_mask: 1 << (RutabagaComponentType::Rutabaga2D as u8),
             size: resource_create_blob.size,
             mapping: None,
         })
     }
 
+    // Holes are skipped when reading from guest memory, and read back as zeros.
+    fn supports_sparse_iovecs(&self) -> bool {
+        true
+    }
+
     fn transfer_write(
         &self,
         _ctx_id: u32,
@@ -267,43 +636,109 @@ impl RutabagaComponent for Rutabaga2D {
             return Ok(());
         }
 
+        let level = LevelLayout::for_transfer(info_2d, &transfer)?;
         let iovecs = resource
             .backing_iovecs
             .as_ref()
-            .ok_or(RutabagaError::InvalidIovec)?;
+            .ok_or(RutabagaError::InvalidIovec)?
+            .pin()?;
+
+        write_host_mem(
+            level,
+            &transfer,
+            info_2d.host_mem.as_mut().unwrap().as_mut_slice(),
+            &iovecs,
+        )
+    }
 
-        // All official virtio_gpu formats are 4 bytes per pixel.
-        let resource_bpp = 4;
-        let mut src_slices = Vec::with_capacity(iovecs.len());
-        for iovec in iovecs {
+    fn transfer_write_job(
+        &self,
+        _ctx_id: u32,
+        resource: &mut RutabagaResource,
+        transfer: Transfer3D,
+    ) -> RutabagaResult<Option<RutabagaTransferJob>> {
+        if transfer.is_empty() {
+            return Ok(None);
+        }
+
+        let info_2d = resource
+            .info_2d
+            .as_mut()
+            .ok_or(RutabagaError::Invalid2DInfo)?;
+
+        // For guest-only blobs, transfer_write to host_mem is a no-op.
+        if info_2d.host_mem.is_none() {
+            return Ok(None);
+        }
+
+        let level = LevelLayout::for_transfer(info_2d, &transfer)?;
+        let host_mem = info_2d.host_mem.as_mut().unwrap();
+
+        // The job keeps the backing pinned, so detaching it waits for the job to run.
+        let iovecs = resource
+            .backing_iovecs
+            .as_ref()
+            .ok_or(RutabagaError::InvalidIovec)?
+            .pin()?;
+
+        let host_mem = HostMem {
+            base: host_mem.as_mut_ptr(),
+            len: host_mem.len(),
+        };
+
+        Ok(Some(Box::new(move || {
+            // Captures `host_mem` as a whole, rather than its fields that aren't Send.
+            let host_mem = host_mem;
             // SAFETY:
-            // Safe because Rutabaga users should have already checked the iovecs.
-            let slice = unsafe { std::slice::from_raw_parts(iovec.base as *mut u8, iovec.len) };
-            src_slices.push(slice);
+            // The memory is owned by the resource, which outlives the job.  See `HostMem`.
+            let dst = unsafe { std::slice::from_raw_parts_mut(host_mem.base, host_mem.len) };
+            write_host_mem(level, &transfer, dst, &iovecs)
+        })))
+    }
+
+    fn transfer_read_job(
+        &self,
+        _ctx_id: u32,
+        resource: &mut RutabagaResource,
+        transfer: Transfer3D,
+    ) -> RutabagaResult<Option<RutabagaTransferJob>> {
+        if transfer.is_empty() {
+            return Ok(None);
+        }
+
+        let info_2d = resource
+            .info_2d
+            .as_mut()
+            .ok_or(RutabagaError::Invalid2DInfo)?;
+
+        // For guest-only blobs, the guest memory already holds the contents.
+        if info_2d.host_mem.is_none() {
+            return Ok(None);
         }
 
-        let src_stride = resource_bpp * info_2d.width;
-        let src_offset = transfer.offset;
+        let level = LevelLayout::for_transfer(info_2d, &transfer)?;
+        let host_mem = info_2d.host_mem.as_mut().unwrap();
 
-        let dst_stride = resource_bpp * info_2d.width;
-        let dst_offset = 0;
+        // The job keeps the backing pinned, so detaching it waits for the job to run.
+        let iovecs = resource
+            .backing_iovecs
+            .as_ref()
+            .ok_or(RutabagaError::InvalidIovec)?
+            .pin()?;
 
-        transfer_2d(
-            info_2d.width,
-            info_2d.height,
-            transfer.x,
-            transfer.y,
-            transfer.w,
-            transfer.h,
-            dst_stride,
-            dst_offset,
-            IoSliceMut::new(info_2d.host_mem.as_mut().unwrap().as_mut_slice()),
-            src_stride,
-            src_offset,
-            &src_slices,
-        )?;
+        let host_mem = HostMem {
+            base: host_mem.as_mut_ptr(),
+            len: host_mem.len(),
+        };
 
-        Ok(())
+        Ok(Some(Box::new(move || {
+            // Captures `host_mem` as a whole, rather than its fields that aren't Send.
+            let host_mem = host_mem;
+            // SAFETY:
+            // The memory is owned by the resource, which outlives the job.  See `HostMem`.
+            let src = unsafe { std::slice::from_raw_parts(host_mem.base, host_mem.len) };
+            read_host_mem(level, &transfer, src, &iovecs)
+        })))
     }
 
     fn transfer_read(
@@ -313,65 +748,94 @@ impl RutabagaComponent for Rutabaga2D {
         transfer: Transfer3D,
         buf: Option<IoSliceMut>,
     ) -> RutabagaResult<()> {
-        let src_offset = 0;
-        let dst_offset = 0;
-
-        let dst_slice = buf.ok_or(MesaError::WithContext(
-            "need a destination slice for transfer read",
-        ))?;
-
         let info_2d = resource
             .info_2d
             .as_mut()
             .ok_or(RutabagaError::Invalid2DInfo)?;
 
-        let (width, height, src_slices, src_stride) = if info_2d.host_mem.is_none() {
+        let Some(mut dst_slice) = buf else {
+            // For guest-only blobs, the guest memory already holds the contents.
+            let Some(host_mem) = info_2d.host_mem.as_ref() else {
+                return Ok(());
+            };
+
+            let level = LevelLayout::for_transfer(info_2d, &transfer)?;
+            let iovecs = resource
+                .backing_iovecs
+                .as_ref()
+                .ok_or(RutabagaError::InvalidIovec)?
+                .pin()?;
+            return read_host_mem(level, &transfer, host_mem, &iovecs);
+        };
+
+        let iovecs;
+        let (level, src_slices, src_stride) = if info_2d.host_mem.is_none() {
             // Blob (guest only) provides stride in the scanout command.
             let Some(scanout_stride) = info_2d.scanout_stride else {
                 return Err(RutabagaError::InvalidResourceId);
             };
 
-            let iovecs = resource
+            // Guest-only blobs have a single level, and their layers are `transfer.layer_stride`
+            // bytes apart, or packed if it's zero.
+            if transfer.level != 0 {
+                return Err(MesaError::WithContext("guest blobs have a single mip level").into());
+            }
+
+            iovecs = resource
                 .backing_iovecs
                 .as_ref()
-                .ok_or(RutabagaError::InvalidIovec)?;
+                .ok_or(RutabagaError::InvalidIovec)?
+                .pin()?;
 
-            let mut src_slices = Vec::with_capacity(iovecs.len());
-            for iovec in iovecs {
-                // SAFETY:
-                // Safe because Rutabaga users should have already checked the iovecs.
-                let slice = unsafe { std::slice::from_raw_parts(iovec.base as *mut u8, iovec.len) };
-                src_slices.push(slice);
-            }
+            let layer_size = match transfer.layer_stride {
+                0 => scanout_stride as u64 * transfer.h as u64,
+                layer_stride => layer_stride as u64,
+            };
+            let level = LevelLayout {
+                width: transfer.w,
+                height: transfer.h,
+                offset: 0,
+                layer_size,
+            };
 
-            (transfer.w, transfer.h, src_slices, scanout_stride)
+            (level, iovec_sources(&iovecs), scanout_stride)
         } else {
+            let level = LevelLayout::for_transfer(info_2d, &transfer)?;
             // All official virtio_gpu formats are 4 bytes per pixel.
             let resource_bpp = 4;
-            let src_stride = resource_bpp * info_2d.width;
+            let src_stride = resource_bpp * level.width;
 
             (
-                info_2d.width,
-                info_2d.height,
-                vec![info_2d.host_mem.as_mut().unwrap().as_slice()],
+                level,
+                vec![TransferSource::Backed(
+                    info_2d.host_mem.as_mut().unwrap().as_slice(),
+                )],
                 src_stride,
             )
         };
 
-        transfer_2d(
-            width,
-            height,
-            transfer.x,
-            transfer.y,
-            transfer.w,
-            transfer.h,
-            transfer.stride,
-            dst_offset,
-            dst_slice,
-            src_stride,
-            src_offset,
-            &src_slices,
-        )?;
+        // Layers are packed in the destination.
+        let dst_layer_size = transfer.stride as u64 * level.height as u64;
+        for layer in 0..transfer.d.max(1) {
+            let index = layer as u64;
+            let src_offset = level.layer_offset(transfer.z + layer)?;
+            let dst_offset = checked_arithmetic!(dst_layer_size * index)?;
+
+            transfer_2d(
+                level.width,
+                level.height,
+                transfer.x,
+                transfer.y,
+                transfer.w,
+                transfer.h,
+                transfer.stride,
+                dst_offset,
+                IoSliceMut::new(&mut dst_slice),
+                src_stride,
+                src_offset,
+                &src_slices,
+            )?;
+        }
 
         Ok(())
     }
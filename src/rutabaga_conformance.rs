@@ -0,0 +1,861 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! rutabaga_conformance: checks components against the `RutabagaComponent` and `RutabagaContext`
+//! contracts without booting a guest, and a mock component that implements all of them.
+//!
+//! Backends describe the optional parts of the contract they implement with a
+//! `ConformanceTarget`, and call `run_conformance` from their tests.  The suite plays the part of
+//! `Rutabaga`, so it keeps the `RutabagaResource` of each resource and hands it back to the
+//! component the way the frontend would.  The `conformance` feature exports the suite to backends
+//! outside of this crate.
+
+use std::collections::BTreeMap as Map;
+use std::collections::BTreeSet as Set;
+use std::io::IoSlice;
+use std::io::IoSliceMut;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use mesa3d_util::MesaError;
+use mesa3d_util::MesaHandle;
+use mesa3d_util::MesaMapping;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::handle::RutabagaHandle;
+use crate::rutabaga_core::RutabagaComponent;
+use crate::rutabaga_core::RutabagaContext;
+use crate::rutabaga_core::RutabagaContextParams;
+use crate::rutabaga_core::RutabagaResource;
+use crate::rutabaga_utils::ResourceCreate3D;
+use crate::rutabaga_utils::ResourceCreateBlob;
+use crate::rutabaga_utils::RutabagaBacking;
+use crate::rutabaga_utils::RutabagaComponentType;
+use crate::rutabaga_utils::RutabagaContextPriority;
+use crate::rutabaga_utils::RutabagaError;
+use crate::rutabaga_utils::RutabagaFence;
+use crate::rutabaga_utils::RutabagaFenceHandler;
+use crate::rutabaga_utils::RutabagaHandler;
+use crate::rutabaga_utils::RutabagaIovec;
+use crate::rutabaga_utils::RutabagaResult;
+use crate::rutabaga_utils::Transfer3D;
+use crate::rutabaga_utils::RUTABAGA_BLOB_FLAG_USE_MAPPABLE;
+use crate::rutabaga_utils::RUTABAGA_BLOB_MEM_HOST3D;
+use crate::rutabaga_utils::RUTABAGA_FLAG_FENCE;
+use crate::rutabaga_utils::RUTABAGA_FLAG_INFO_RING_IDX;
+use crate::rutabaga_utils::RUTABAGA_MAP_ACCESS_RW;
+use crate::rutabaga_utils::RUTABAGA_MAP_CACHE_CACHED;
+use crate::rutabaga_utils::RUTABAGA_PIPE_BIND_RENDER_TARGET;
+use crate::rutabaga_utils::RUTABAGA_PIPE_TEXTURE_2D;
+use crate::snapshot::RutabagaSnapshotReader;
+use crate::snapshot::RutabagaSnapshotWriter;
+
+const CONFORMANCE_WIDTH: u32 = 64;
+const CONFORMANCE_HEIGHT: u32 = 32;
+const CONFORMANCE_BLOB_SIZE: u64 = 4096;
+const CONFORMANCE_CTX_ID: u32 = 1;
+const CONFORMANCE_FENCES: u64 = 16;
+// Asynchronous components signal fences from their own threads.
+const CONFORMANCE_FENCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Creates the component under test, which must signal fences with the given handler.
+pub type ConformanceInit = fn(RutabagaFenceHandler) -> RutabagaResult<Box<dyn RutabagaComponent>>;
+
+/// The parts of the contract a component implements.  Everything else is checked to fail
+/// cleanly, or skipped where the contract allows any behavior.
+pub struct ConformanceTarget {
+    pub init: ConformanceInit,
+    /// The type reported by contexts of the component.
+    pub component_type: RutabagaComponentType,
+    /// Transfers round trip between guest backing and 3D resources.
+    pub transfers: bool,
+    /// The `context_init` of contexts created by the suite, or None if the component has no
+    /// contexts.
+    pub context_init: Option<u32>,
+    /// The rings of contexts whose fences signal without any submitted work.
+    pub fence_rings: &'static [u8],
+    /// Mappable host3d blobs can be created outside of contexts.
+    pub mappable_blobs: bool,
+    /// Components snapshot and restore, and contexts serialize themselves.
+    pub snapshots: bool,
+}
+
+/// Collects the fences signaled by a component, in signaling order.
+#[derive(Clone, Default)]
+struct FenceLog {
+    fences: Arc<Mutex<Vec<RutabagaFence>>>,
+}
+
+impl FenceLog {
+    fn handler(&self) -> RutabagaFenceHandler {
+        let fences = self.fences.clone();
+        RutabagaHandler::new(move |fence| fences.lock().unwrap().push(fence))
+    }
+
+    // Waits for `count` fences, polling `component` the way Rutabaga does, and returns the ids of
+    // all fences signaled so far.
+    fn wait(&self, component: &dyn RutabagaComponent, count: usize) -> Vec<(u32, u8, u64)> {
+        let deadline = Instant::now() + CONFORMANCE_FENCE_TIMEOUT;
+        while self.fences.lock().unwrap().len() < count && Instant::now() < deadline {
+            component.event_poll();
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        self.fences
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|fence| (fence.ctx_id, fence.ring_idx, fence.fence_id))
+            .collect()
+    }
+}
+
+// A snapshot directory that is removed once dropped.
+struct SnapshotDir(PathBuf);
+
+impl SnapshotDir {
+    fn new() -> SnapshotDir {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        let mut dir = std::env::temp_dir();
+        dir.push(format!(
+            "rutabaga_conformance_{}_{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir(&dir).unwrap();
+        SnapshotDir(dir)
+    }
+}
+
+impl Drop for SnapshotDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn resource_create_3d() -> ResourceCreate3D {
+    ResourceCreate3D {
+        target: RUTABAGA_PIPE_TEXTURE_2D,
+        format: 1,
+        bind: RUTABAGA_PIPE_BIND_RENDER_TARGET,
+        width: CONFORMANCE_WIDTH,
+        height: CONFORMANCE_HEIGHT,
+        depth: 1,
+        array_size: 1,
+        last_level: 0,
+        nr_samples: 0,
+        flags: 0,
+    }
+}
+
+fn init(target: &ConformanceTarget, fences: &FenceLog) -> Box<dyn RutabagaComponent> {
+    (target.init)(fences.handler()).expect("component failed to initialize")
+}
+
+fn create_context(
+    target: &ConformanceTarget,
+    component: &dyn RutabagaComponent,
+    fences: &FenceLog,
+) -> Option<Box<dyn RutabagaContext>> {
+    let context_init = target.context_init?;
+    let context = component
+        .create_context(
+            RutabagaContextParams {
+                ctx_id: CONFORMANCE_CTX_ID,
+                context_init,
+                context_name: Some("conformance"),
+                priority: RutabagaContextPriority::Normal,
+                compute_only: false,
+            },
+            fences.handler(),
+        )
+        .expect("context creation failed");
+    assert!(
+        context.component_type() == target.component_type,
+        "context reports the wrong component type"
+    );
+    Some(context)
+}
+
+/// Resources are created with the requested id and can be backed, transferred to and from, and
+/// released.  Released ids can be reused.
+fn check_resource_lifecycle(component: &dyn RutabagaComponent, target: &ConformanceTarget) {
+    let resource_id = 1;
+    let size = (CONFORMANCE_WIDTH * CONFORMANCE_HEIGHT * 4) as usize;
+
+    let mut resource = component
+        .create_3d(resource_id, resource_create_3d())
+        .expect("create_3d failed");
+    assert_eq!(resource.resource_id, resource_id);
+
+    let mut backing: Vec<u8> = (0..size).map(|i| i as u8).collect();
+    let mut iovecs = vec![RutabagaIovec {
+        base: backing.as_mut_ptr() as *mut _,
+        len: backing.len(),
+    }];
+    component
+        .attach_backing(resource_id, &mut iovecs)
+        .expect("attach_backing failed");
+    resource.backing_iovecs = Some(RutabagaBacking::new(iovecs));
+
+    let transfer = Transfer3D {
+        stride: CONFORMANCE_WIDTH * 4,
+        ..Transfer3D::new_2d(0, 0, CONFORMANCE_WIDTH, CONFORMANCE_HEIGHT, 0)
+    };
+    component
+        .transfer_write(0, &mut resource, transfer, None)
+        .expect("transfer_write failed");
+
+    component.detach_backing(resource_id);
+    resource.backing_iovecs = None;
+
+    if target.transfers {
+        let mut readback = vec![0u8; size];
+        component
+            .transfer_read(
+                0,
+                &mut resource,
+                transfer,
+                Some(IoSliceMut::new(&mut readback)),
+            )
+            .expect("transfer_read failed");
+        assert!(
+            readback == backing,
+            "transfer_read doesn't return what was written"
+        );
+    }
+
+    // Empty transfers are no-ops, with or without backing.
+    component
+        .transfer_write(0, &mut resource, Transfer3D::new_2d(0, 0, 0, 0, 0), None)
+        .expect("empty transfer_write failed");
+
+    component.unref_resource(resource_id);
+    drop(resource);
+
+    let resource = component
+        .create_3d(resource_id, resource_create_3d())
+        .expect("resource id can't be reused");
+    assert_eq!(resource.resource_id, resource_id);
+    component.unref_resource(resource_id);
+}
+
+/// Fences signal in creation order on each timeline: globally for context 0, and per ring for
+/// contexts.
+fn check_fence_ordering(
+    component: &mut dyn RutabagaComponent,
+    target: &ConformanceTarget,
+    fences: &FenceLog,
+) {
+    for fence_id in 1..=CONFORMANCE_FENCES {
+        component
+            .create_fence(RutabagaFence {
+                flags: RUTABAGA_FLAG_FENCE,
+                fence_id,
+                ctx_id: 0,
+                ring_idx: 0,
+            })
+            .expect("create_fence failed");
+    }
+
+    let signaled: Vec<u64> = fences
+        .wait(component, CONFORMANCE_FENCES as usize)
+        .iter()
+        .filter(|(ctx_id, _, _)| *ctx_id == 0)
+        .map(|(_, _, fence_id)| *fence_id)
+        .collect();
+    let expected: Vec<u64> = (1..=CONFORMANCE_FENCES).collect();
+    assert!(
+        signaled == expected,
+        "global fences signaled out of order: {signaled:?}"
+    );
+
+    let Some(mut context) = create_context(target, component, fences) else {
+        return;
+    };
+
+    // Interleave the rings, so a component that keeps a single timeline is caught.
+    let mut created = 0;
+    for fence_id in 1..=CONFORMANCE_FENCES {
+        for ring_idx in target.fence_rings {
+            context
+                .context_create_fence(RutabagaFence {
+                    flags: RUTABAGA_FLAG_FENCE | RUTABAGA_FLAG_INFO_RING_IDX,
+                    fence_id: 100 + fence_id,
+                    ctx_id: CONFORMANCE_CTX_ID,
+                    ring_idx: *ring_idx,
+                })
+                .expect("context_create_fence failed");
+            created += 1;
+        }
+    }
+
+    let signaled = fences.wait(component, CONFORMANCE_FENCES as usize + created);
+    for ring_idx in target.fence_rings {
+        let ring: Vec<u64> = signaled
+            .iter()
+            .filter(|(ctx_id, idx, _)| *ctx_id == CONFORMANCE_CTX_ID && idx == ring_idx)
+            .map(|(_, _, fence_id)| *fence_id)
+            .collect();
+        let expected: Vec<u64> = (1..=CONFORMANCE_FENCES).map(|id| 100 + id).collect();
+        assert!(
+            ring == expected,
+            "ring {ring_idx} fences signaled out of order: {ring:?}"
+        );
+    }
+}
+
+/// Resources can be attached to and detached from contexts repeatedly, including after their
+/// backing is gone.
+fn check_attach_detach(
+    component: &dyn RutabagaComponent,
+    target: &ConformanceTarget,
+    fences: &FenceLog,
+) {
+    let Some(mut context) = create_context(target, component, fences) else {
+        return;
+    };
+
+    let resource_id = 1;
+    let mut resource = component
+        .create_3d(resource_id, resource_create_3d())
+        .expect("create_3d failed");
+
+    context.attach(&mut resource);
+    context.detach(&resource);
+    context.attach(&mut resource);
+    context.attach(&mut resource);
+    context.detach(&resource);
+
+    // Detaching resources that aren't attached is allowed, since the guest may race unref
+    // against detach.
+    context.detach(&resource);
+
+    component.unref_resource(resource_id);
+}
+
+/// Mappable blobs map to at least their size, stay coherent while mapped, and unmap.
+fn check_blob_mapping(component: &mut dyn RutabagaComponent, target: &ConformanceTarget) {
+    let resource_id = 1;
+    let create_blob = ResourceCreateBlob {
+        blob_mem: RUTABAGA_BLOB_MEM_HOST3D,
+        blob_flags: RUTABAGA_BLOB_FLAG_USE_MAPPABLE,
+        blob_id: 0,
+        size: CONFORMANCE_BLOB_SIZE,
+    };
+
+    if !target.mappable_blobs {
+        return;
+    }
+
+    let resource = component
+        .create_blob(0, resource_id, create_blob, None, None)
+        .expect("create_blob failed");
+    assert_eq!(resource.resource_id, resource_id);
+    assert!(resource.blob, "blob resource isn't marked as a blob");
+    assert!(resource.size >= CONFORMANCE_BLOB_SIZE, "blob is too small");
+
+    let mapping = component.map(resource_id).expect("map failed");
+    assert!(
+        mapping.size >= CONFORMANCE_BLOB_SIZE,
+        "mapping is too small"
+    );
+
+    // SAFETY:
+    // The mapping is at least CONFORMANCE_BLOB_SIZE bytes, and stays valid until unmapped.
+    let bytes = unsafe {
+        std::slice::from_raw_parts_mut(mapping.ptr as *mut u8, CONFORMANCE_BLOB_SIZE as usize)
+    };
+    bytes.fill(0x5a);
+    assert!(bytes.iter().all(|b| *b == 0x5a), "mapping isn't coherent");
+
+    component.unmap(resource_id).expect("unmap failed");
+    component.unref_resource(resource_id);
+}
+
+/// Snapshots restore into a new component, and restoring a snapshot without the component's data
+/// fails.  Contexts survive a round trip through their serialized form.
+///
+/// Renderers keep global state, so the new component replaces the snapshotted one rather than
+/// running alongside it.
+fn check_snapshot_round_trip(
+    component: Box<dyn RutabagaComponent>,
+    target: &ConformanceTarget,
+    fences: &FenceLog,
+) {
+    let dir = SnapshotDir::new();
+
+    let snapshot = component.snapshot(RutabagaSnapshotWriter::from_existing(&dir.0));
+    if !target.snapshots {
+        assert!(snapshot.is_err(), "unexpected snapshot support");
+        return;
+    }
+    snapshot.expect("snapshot failed");
+
+    let serialized = create_context(target, component.as_ref(), fences)
+        .map(|context| context.snapshot().expect("context snapshot failed"));
+    drop(component);
+
+    let restored = init(target, fences);
+    let empty = SnapshotDir::new();
+    let reader = RutabagaSnapshotReader::from_existing(&empty.0).unwrap();
+    assert!(
+        restored.restore(reader).is_err(),
+        "restored a snapshot without component data"
+    );
+
+    let reader = RutabagaSnapshotReader::from_existing(&dir.0).unwrap();
+    restored.restore(reader).expect("restore failed");
+
+    let Some(serialized) = serialized else {
+        return;
+    };
+    let context = restored
+        .restore_context(serialized, fences.handler())
+        .expect("restore_context failed");
+    assert!(
+        context.component_type() == target.component_type,
+        "restored context reports the wrong component type"
+    );
+}
+
+/// Runs every check of the suite against `target`.  The checks share one component, since some
+/// renderers can only be initialized once per process.
+pub fn run_conformance(target: &ConformanceTarget) {
+    let fences = FenceLog::default();
+    let mut component = init(target, &fences);
+
+    check_resource_lifecycle(component.as_ref(), target);
+    check_fence_ordering(component.as_mut(), target, &fences);
+    check_attach_detach(component.as_ref(), target, &fences);
+    check_blob_mapping(component.as_mut(), target);
+    check_snapshot_round_trip(component, target, &fences);
+}
+
+struct MockResource {
+    data: Vec<u8>,
+    backing: Option<Vec<RutabagaIovec>>,
+    mapped: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+struct MockSnapshot {
+    resources: Map<u32, Vec<u8>>,
+}
+
+/// A component that keeps resources in host memory and signals fences as soon as they are
+/// created.  It implements every optional part of the contract, so it passes the whole suite.
+pub struct MockComponent {
+    fence_handler: RutabagaFenceHandler,
+    resources: Mutex<Map<u32, MockResource>>,
+}
+
+impl MockComponent {
+    pub fn init(fence_handler: RutabagaFenceHandler) -> RutabagaResult<Box<dyn RutabagaComponent>> {
+        Ok(Box::new(MockComponent {
+            fence_handler,
+            resources: Mutex::new(Map::new()),
+        }))
+    }
+
+    fn insert(&self, resource_id: u32, size: u64, blob: bool) -> RutabagaResult<RutabagaResource> {
+        let len = usize::try_from(size).map_err(MesaError::TryFromIntError)?;
+        self.resources.lock().unwrap().insert(
+            resource_id,
+            MockResource {
+                data: vec![0; len],
+                backing: None,
+                mapped: false,
+            },
+        );
+
+        Ok(RutabagaResource {
+            resource_id,
+            handle: None,
+            blob,
+            blob_mem: if blob { RUTABAGA_BLOB_MEM_HOST3D } else { 0 },
+            blob_flags: if blob {
+                RUTABAGA_BLOB_FLAG_USE_MAPPABLE
+            } else {
+                0
+            },
+            map_info: blob.then_some(RUTABAGA_MAP_CACHE_CACHED | RUTABAGA_MAP_ACCESS_RW),
+            info_2d: None,
+            info_3d: None,
+            vulkan_info: None,
+            backing_iovecs: None,
+            component_mask: 0,
+            size,
+            mapping: None,
+        })
+    }
+}
+
+impl RutabagaComponent for MockComponent {
+    fn create_fence(&mut self, fence: RutabagaFence) -> RutabagaResult<()> {
+        self.fence_handler.call(fence);
+        Ok(())
+    }
+
+    fn create_3d(
+        &self,
+        resource_id: u32,
+        resource_create_3d: ResourceCreate3D,
+    ) -> RutabagaResult<RutabagaResource> {
+        let size = u64::from(resource_create_3d.width) * u64::from(resource_create_3d.height) * 4;
+        self.insert(resource_id, size, false)
+    }
+
+    fn attach_backing(
+        &self,
+        resource_id: u32,
+        vecs: &mut Vec<RutabagaIovec>,
+    ) -> RutabagaResult<()> {
+        let mut resources = self.resources.lock().unwrap();
+        let resource = resources
+            .get_mut(&resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?;
+        resource.backing = Some(vecs.clone());
+        Ok(())
+    }
+
+    fn detach_backing(&self, resource_id: u32) {
+        if let Some(resource) = self.resources.lock().unwrap().get_mut(&resource_id) {
+            resource.backing = None;
+        }
+    }
+
+    fn unref_resource(&self, resource_id: u32) {
+        self.resources.lock().unwrap().remove(&resource_id);
+    }
+
+    // Resources are linear, so only the offset and size of the transfer matter.
+    fn transfer_write(
+        &self,
+        _ctx_id: u32,
+        resource: &mut RutabagaResource,
+        transfer: Transfer3D,
+        buf: Option<IoSlice>,
+    ) -> RutabagaResult<()> {
+        if transfer.is_empty() {
+            return Ok(());
+        }
+
+        let mut resources = self.resources.lock().unwrap();
+        let mock = resources
+            .get_mut(&resource.resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?;
+
+        let mut src: Vec<u8> = Vec::new();
+        match buf {
+            Some(buf) => src.extend_from_slice(&buf),
+            None => {
+                let iovecs = mock.backing.as_ref().ok_or(RutabagaError::InvalidIovec)?;
+                for iovec in iovecs.iter().filter(|iovec| !iovec.is_hole()) {
+                    // SAFETY:
+                    // Safe because Rutabaga users should have already checked the iovecs.
+                    src.extend_from_slice(unsafe {
+                        std::slice::from_raw_parts(iovec.base as *const u8, iovec.len)
+                    });
+                }
+            }
+        }
+
+        let offset = usize::try_from(transfer.offset).map_err(MesaError::TryFromIntError)?;
+        let src = src.get(offset..).ok_or(RutabagaError::InvalidIovec)?;
+        let len = src.len().min(mock.data.len());
+        mock.data[..len].copy_from_slice(&src[..len]);
+        Ok(())
+    }
+
+    fn transfer_read(
+        &self,
+        _ctx_id: u32,
+        resource: &mut RutabagaResource,
+        transfer: Transfer3D,
+        buf: Option<IoSliceMut>,
+    ) -> RutabagaResult<()> {
+        if transfer.is_empty() {
+            return Ok(());
+        }
+
+        let mut dst = buf.ok_or(MesaError::WithContext(
+            "need a destination slice for transfer read",
+        ))?;
+        let resources = self.resources.lock().unwrap();
+        let mock = resources
+            .get(&resource.resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?;
+
+        let len = dst.len().min(mock.data.len());
+        dst[..len].copy_from_slice(&mock.data[..len]);
+        Ok(())
+    }
+
+    fn create_blob(
+        &mut self,
+        _ctx_id: u32,
+        resource_id: u32,
+        resource_create_blob: ResourceCreateBlob,
+        _iovec_opt: Option<Vec<RutabagaIovec>>,
+        _handle_opt: Option<RutabagaHandle>,
+    ) -> RutabagaResult<RutabagaResource> {
+        if resource_create_blob.blob_mem != RUTABAGA_BLOB_MEM_HOST3D {
+            return Err(MesaError::Unsupported.into());
+        }
+
+        self.insert(resource_id, resource_create_blob.size, true)
+    }
+
+    fn map(&self, resource_id: u32) -> RutabagaResult<MesaMapping> {
+        let mut resources = self.resources.lock().unwrap();
+        let resource = resources
+            .get_mut(&resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?;
+        resource.mapped = true;
+        Ok(MesaMapping {
+            ptr: resource.data.as_mut_ptr() as u64,
+            size: resource.data.len() as u64,
+        })
+    }
+
+    fn unmap(&self, resource_id: u32) -> RutabagaResult<()> {
+        let mut resources = self.resources.lock().unwrap();
+        let resource = resources
+            .get_mut(&resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?;
+        if !resource.mapped {
+            return Err(MesaError::WithContext("resource isn't mapped").into());
+        }
+
+        resource.mapped = false;
+        Ok(())
+    }
+
+    fn create_context(
+        &self,
+        _params: RutabagaContextParams,
+        fence_handler: RutabagaFenceHandler,
+    ) -> RutabagaResult<Box<dyn RutabagaContext>> {
+        Ok(Box::new(MockContext {
+            fence_handler,
+            attached: Set::new(),
+        }))
+    }
+
+    fn snapshot(&self, writer: RutabagaSnapshotWriter) -> RutabagaResult<()> {
+        let snapshot = MockSnapshot {
+            resources: self
+                .resources
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(id, resource)| (*id, resource.data.clone()))
+                .collect(),
+        };
+        writer.add_fragment("mock_component", &snapshot)
+    }
+
+    fn restore(&self, reader: RutabagaSnapshotReader) -> RutabagaResult<()> {
+        let snapshot: MockSnapshot = reader.get_fragment("mock_component")?;
+        *self.resources.lock().unwrap() = snapshot
+            .resources
+            .into_iter()
+            .map(|(id, data)| {
+                let resource = MockResource {
+                    data,
+                    backing: None,
+                    mapped: false,
+                };
+                (id, resource)
+            })
+            .collect();
+        Ok(())
+    }
+
+    fn restore_context(
+        &self,
+        snapshot: Vec<u8>,
+        fence_handler: RutabagaFenceHandler,
+    ) -> RutabagaResult<Box<dyn RutabagaContext>> {
+        let attached: Set<u32> = serde_json::from_slice(&snapshot)?;
+        Ok(Box::new(MockContext {
+            fence_handler,
+            attached,
+        }))
+    }
+}
+
+/// A context of `MockComponent`, which reports itself as a 2D context.
+struct MockContext {
+    fence_handler: RutabagaFenceHandler,
+    attached: Set<u32>,
+}
+
+impl RutabagaContext for MockContext {
+    fn submit_cmd(
+        &mut self,
+        _commands: &mut [u8],
+        _fence_ids: &[u64],
+        _shareable_fences: Vec<MesaHandle>,
+    ) -> RutabagaResult<()> {
+        Ok(())
+    }
+
+    fn attach(&mut self, resource: &mut RutabagaResource) {
+        self.attached.insert(resource.resource_id);
+    }
+
+    fn detach(&mut self, resource: &RutabagaResource) {
+        self.attached.remove(&resource.resource_id);
+    }
+
+    fn context_create_fence(&mut self, fence: RutabagaFence) -> RutabagaResult<Option<MesaHandle>> {
+        self.fence_handler.call(fence);
+        Ok(None)
+    }
+
+    fn component_type(&self) -> RutabagaComponentType {
+        RutabagaComponentType::Rutabaga2D
+    }
+
+    fn snapshot(&self) -> RutabagaResult<Vec<u8>> {
+        Ok(serde_json::to_vec(&self.attached)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cross_domain::CrossDomain;
+    #[cfg(feature = "gfxstream")]
+    use crate::gfxstream::Gfxstream;
+    #[cfg(feature = "magma")]
+    use crate::magma::MagmaVirtioGpu;
+    use crate::rutabaga_2d::Rutabaga2D;
+    #[cfg(feature = "gfxstream")]
+    use crate::rutabaga_utils::GfxstreamFlags;
+    #[cfg(feature = "gfxstream")]
+    use crate::rutabaga_utils::RUTABAGA_CAPSET_GFXSTREAM_VULKAN;
+    #[cfg(feature = "virgl_renderer")]
+    use crate::rutabaga_utils::RUTABAGA_CAPSET_VIRGL2;
+    #[cfg(feature = "virgl_renderer")]
+    use crate::virgl_renderer::VirglRenderer;
+
+    #[test]
+    fn conformance_mock() {
+        run_conformance(&ConformanceTarget {
+            init: MockComponent::init,
+            component_type: RutabagaComponentType::Rutabaga2D,
+            transfers: true,
+            context_init: Some(0),
+            fence_rings: &[0, 1, 2],
+            mappable_blobs: true,
+            snapshots: true,
+        });
+    }
+
+    #[test]
+    fn conformance_2d() {
+        run_conformance(&ConformanceTarget {
+            init: Rutabaga2D::init,
+            component_type: RutabagaComponentType::Rutabaga2D,
+            transfers: true,
+            context_init: None,
+            fence_rings: &[],
+            mappable_blobs: false,
+            snapshots: true,
+        });
+    }
+
+    #[test]
+    fn conformance_cross_domain() {
+        run_conformance(&ConformanceTarget {
+            init: |fence_handler| {
+                CrossDomain::init(
+                    None,
+                    fence_handler,
+                    Default::default(),
+                    false,
+                    Default::default(),
+                    false,
+                    None,
+                )
+            },
+            component_type: RutabagaComponentType::CrossDomain,
+            transfers: false,
+            context_init: Some(0),
+            // Only the query ring signals without a channel.
+            fence_rings: &[0],
+            mappable_blobs: false,
+            snapshots: false,
+        });
+    }
+
+    #[cfg(feature = "gfxstream")]
+    #[test]
+    fn conformance_gfxstream() {
+        run_conformance(&ConformanceTarget {
+            init: |fence_handler| {
+                let flags = GfxstreamFlags::new()
+                    .use_egl(true)
+                    .use_surfaceless(true)
+                    .use_gles(true)
+                    .use_vulkan(true);
+                Gfxstream::init(
+                    CONFORMANCE_WIDTH,
+                    CONFORMANCE_HEIGHT,
+                    flags,
+                    None,
+                    fence_handler,
+                    None,
+                )
+            },
+            component_type: RutabagaComponentType::Gfxstream,
+            transfers: true,
+            context_init: Some(RUTABAGA_CAPSET_GFXSTREAM_VULKAN),
+            fence_rings: &[0],
+            mappable_blobs: false,
+            snapshots: cfg!(gfxstream_unstable),
+        });
+    }
+
+    #[cfg(feature = "virgl_renderer")]
+    #[test]
+    fn conformance_virgl() {
+        run_conformance(&ConformanceTarget {
+            init: |fence_handler| {
+                VirglRenderer::init(Default::default(), fence_handler, None, None)
+            },
+            component_type: RutabagaComponentType::VirglRenderer,
+            transfers: true,
+            context_init: Some(RUTABAGA_CAPSET_VIRGL2),
+            fence_rings: &[0],
+            mappable_blobs: false,
+            snapshots: false,
+        });
+    }
+
+    #[cfg(feature = "magma")]
+    #[test]
+    fn conformance_magma() {
+        run_conformance(&ConformanceTarget {
+            // Without GPUs, contexts open magma's default GPU once they allocate.
+            init: |fence_handler| MagmaVirtioGpu::init(fence_handler, Vec::new()),
+            component_type: RutabagaComponentType::Magma,
+            transfers: false,
+            context_init: Some(0),
+            fence_rings: &[0],
+            mappable_blobs: false,
+            snapshots: false,
+        });
+    }
+}
@@ -4,46 +4,119 @@
 
 //! rutabaga_core: Cross-platform, Rust-based, Wayland and Vulkan centric GPU virtualization.
 use std::collections::BTreeMap as Map;
+use std::collections::BTreeSet as Set;
 use std::convert::TryInto;
 use std::io::IoSlice;
 use std::io::IoSliceMut;
+use std::ops::Range;
 use std::path::Path;
+use std::ptr;
+use std::slice;
 use std::sync::Arc;
+use std::time::Duration;
 
 use mesa3d_util::MemoryMapping;
 use mesa3d_util::MesaError;
 use mesa3d_util::MesaHandle;
 use mesa3d_util::MesaMapping;
+use mesa3d_util::MesaThreadScheduling;
 use mesa3d_util::OwnedDescriptor;
+use mesa3d_util::SharedMemory;
+use mesa3d_util::MESA_HANDLE_TYPE_MEM_DMABUF;
 use mesa3d_util::MESA_HANDLE_TYPE_MEM_SHM;
 use serde::Deserialize;
 use serde::Serialize;
+use zerocopy::IntoBytes;
 
 use crate::cross_domain::CrossDomain;
 #[cfg(feature = "gfxstream")]
 use crate::gfxstream::Gfxstream;
 use crate::handle::RutabagaHandle;
+use crate::magma::enumerate_gpu_devices;
+use crate::magma::set_magma_log_sink;
 use crate::magma::MagmaVirtioGpu;
+use crate::magma::RutabagaGpuDevice;
+use crate::rutabaga_2d::host_mem_size;
 use crate::rutabaga_2d::Rutabaga2D;
+use crate::rutabaga_deferred::RutabagaDeferredDestruction;
+#[cfg(feature = "deterministic")]
+use crate::rutabaga_deterministic::RutabagaDeterministicExecutor;
+use crate::rutabaga_events::RutabagaCapsetsChanged;
+use crate::rutabaga_events::RutabagaEventSink;
+use crate::rutabaga_events::RUTABAGA_CONTEXT_EVENT_CAPSETS_CHANGED;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use crate::rutabaga_hostmem::RutabagaBlobPlacement;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use crate::rutabaga_hostmem::RutabagaHostmem;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use crate::rutabaga_hostmem::RutabagaSharedMemoryRegion;
+use crate::rutabaga_lost::RutabagaLostContexts;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use crate::rutabaga_render_server::RutabagaRenderServer;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use crate::rutabaga_render_server::RutabagaRenderServerConfig;
+use crate::rutabaga_renderdoc::RutabagaCaptureBoundary;
+use crate::rutabaga_renderdoc::RutabagaRenderDoc;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use crate::rutabaga_sandbox::RutabagaSandbox;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use crate::rutabaga_sandbox::RutabagaSandboxPolicy;
+use crate::rutabaga_shared::RutabagaContexts;
+use crate::rutabaga_shared::RutabagaResources;
+use crate::rutabaga_shared::RutabagaShared;
+use crate::rutabaga_shared::RutabagaSubmitter;
+use crate::rutabaga_stats::RutabagaDebugInfo;
+use crate::rutabaga_stats::RutabagaResourceNames;
+use crate::rutabaga_stats::RutabagaResourceStats;
+use crate::rutabaga_stats::RutabagaStats;
+use crate::rutabaga_subscribers::RutabagaFenceFilter;
+use crate::rutabaga_subscribers::RutabagaFenceSubscribers;
+use crate::rutabaga_trace::RutabagaTrace;
+use crate::rutabaga_transfer::RutabagaTransferJob;
+use crate::rutabaga_transfer::RutabagaTransferQueue;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use crate::rutabaga_udmabuf::RutabagaMemfdRegion;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use crate::rutabaga_udmabuf::RutabagaUdmabuf;
+use crate::rutabaga_utils::handle_type_mask;
+use crate::rutabaga_utils::validate_iovecs;
 use crate::rutabaga_utils::GfxstreamFlags;
 use crate::rutabaga_utils::Resource3DInfo;
 use crate::rutabaga_utils::ResourceCreate3D;
 use crate::rutabaga_utils::ResourceCreateBlob;
+use crate::rutabaga_utils::RutabagaBacking;
+use crate::rutabaga_utils::RutabagaCapsetHandler;
 use crate::rutabaga_utils::RutabagaComponentType;
+use crate::rutabaga_utils::RutabagaContextPriority;
 use crate::rutabaga_utils::RutabagaDebugHandler;
+#[cfg(feature = "virgl_renderer")]
+use crate::rutabaga_utils::RutabagaDeviceLost;
+use crate::rutabaga_utils::RutabagaDeviceLostHandler;
 use crate::rutabaga_utils::RutabagaError;
 use crate::rutabaga_utils::RutabagaFence;
 use crate::rutabaga_utils::RutabagaFenceHandler;
+use crate::rutabaga_utils::RutabagaHandleTypes;
+use crate::rutabaga_utils::RutabagaHandler;
 use crate::rutabaga_utils::RutabagaImportData;
 use crate::rutabaga_utils::RutabagaIovec;
+use crate::rutabaga_utils::RutabagaLogSink;
+use crate::rutabaga_utils::RutabagaMemoryPlacement;
 use crate::rutabaga_utils::RutabagaPath;
+use crate::rutabaga_utils::RutabagaRect;
 use crate::rutabaga_utils::RutabagaResult;
+use crate::rutabaga_utils::RutabagaThreadConfig;
+use crate::rutabaga_utils::RutabagaThreadType;
 use crate::rutabaga_utils::RutabagaWsi;
 use crate::rutabaga_utils::Transfer3D;
 use crate::rutabaga_utils::VirglRendererFlags;
 use crate::rutabaga_utils::VulkanInfo;
+use crate::rutabaga_utils::RUTABAGA_BLOB_FLAG_CREATE_GUEST_HANDLE;
+use crate::rutabaga_utils::RUTABAGA_BLOB_FLAG_PROTECTED;
 use crate::rutabaga_utils::RUTABAGA_BLOB_FLAG_USE_CROSS_DEVICE;
+use crate::rutabaga_utils::RUTABAGA_BLOB_FLAG_USE_MAPPABLE;
 use crate::rutabaga_utils::RUTABAGA_BLOB_FLAG_USE_SHAREABLE;
+use crate::rutabaga_utils::RUTABAGA_BLOB_MEM_GUEST;
+use crate::rutabaga_utils::RUTABAGA_BLOB_MEM_HOST3D_GUEST;
 use crate::rutabaga_utils::RUTABAGA_CAPSET_CROSS_DOMAIN;
 use crate::rutabaga_utils::RUTABAGA_CAPSET_DRM;
 use crate::rutabaga_utils::RUTABAGA_CAPSET_GFXSTREAM_COMPOSER;
@@ -54,13 +127,24 @@ use crate::rutabaga_utils::RUTABAGA_CAPSET_VENUS;
 use crate::rutabaga_utils::RUTABAGA_CAPSET_VIRGL;
 use crate::rutabaga_utils::RUTABAGA_CAPSET_VIRGL2;
 use crate::rutabaga_utils::RUTABAGA_CONTEXT_INIT_CAPSET_ID_MASK;
+use crate::rutabaga_utils::RUTABAGA_CONTEXT_INIT_COMPUTE_ONLY;
+use crate::rutabaga_utils::RUTABAGA_CONTEXT_INIT_DEVICE_MASK;
+use crate::rutabaga_utils::RUTABAGA_CONTEXT_INIT_PRIORITY_MASK;
 #[cfg(fence_passing_option1)]
 use crate::rutabaga_utils::RUTABAGA_FLAG_FENCE_HOST_SHAREABLE;
 use crate::rutabaga_utils::RUTABAGA_FLAG_INFO_RING_IDX;
+use crate::rutabaga_utils::RUTABAGA_MAP_ACCESS_RW;
+use crate::rutabaga_utils::RUTABAGA_MAP_CACHE_CACHED;
+use crate::rutabaga_venus::RutabagaVenusFilter;
 use crate::snapshot::RutabagaSnapshotReader;
 use crate::snapshot::RutabagaSnapshotWriter;
 #[cfg(feature = "virgl_renderer")]
+use crate::virgl_renderer::host_has_render_node;
+#[cfg(feature = "virgl_renderer")]
+use crate::virgl_renderer::software_render_server_envs;
+#[cfg(feature = "virgl_renderer")]
 use crate::virgl_renderer::VirglRenderer;
+use crate::RutabagaGrallocPolicy;
 use crate::RutabagaPaths;
 
 const RUTABAGA_DEFAULT_WIDTH: u32 = 1280;
@@ -71,6 +155,9 @@ const RUTABAGA_DEFAULT_HEIGHT: u32 = 1024;
 pub struct Rutabaga2DInfo {
     pub width: u32,
     pub height: u32,
+    /// The number of array layers and mip levels in `host_mem`.
+    pub layers: u32,
+    pub levels: u32,
     pub host_mem: Option<Vec<u8>>,
     pub scanout_stride: Option<u32>,
 }
@@ -79,6 +166,11 @@ pub struct Rutabaga2DInfo {
 struct Rutabaga2DSnapshot {
     width: u32,
     height: u32,
+    // Older snapshots only have one layer and level.
+    #[serde(default)]
+    layers: u32,
+    #[serde(default)]
+    levels: u32,
     // NOTE: `host_mem` is not preserved to avoid snapshot bloat.
 }
 
@@ -93,13 +185,69 @@ pub struct RutabagaResource {
     pub info_2d: Option<Rutabaga2DInfo>,
     pub info_3d: Option<Resource3DInfo>,
     pub vulkan_info: Option<VulkanInfo>,
-    pub backing_iovecs: Option<Vec<RutabagaIovec>>,
+    pub(crate) backing_iovecs: Option<RutabagaBacking>,
     /// Bitmask of components that have already imported this resource
     pub component_mask: u8,
     pub size: u64,
     pub mapping: Option<MemoryMapping>,
 }
 
+/// A resource handed off to the VMM's display backend by `Rutabaga::take_scanout()`.
+pub struct RutabagaScanout {
+    /// A dma-buf or shared memory handle to the resource, if it has one.
+    pub handle: Option<RutabagaHandle>,
+    pub info_3d: Option<Resource3DInfo>,
+    /// The regions that changed since the previous hand-off.  Empty if nothing changed.
+    pub damage: Vec<RutabagaRect>,
+}
+
+/// An active mapping of a blob resource, as returned by `Rutabaga::mappings()`.
+#[derive(Copy, Clone, Debug)]
+pub struct RutabagaMappingInfo {
+    pub resource_id: u32,
+    pub ptr: u64,
+    pub size: u64,
+    /// The number of `Rutabaga::map()` calls not yet balanced by `Rutabaga::unmap()`.
+    pub map_count: u32,
+}
+
+/// The metadata of a resource, as returned by `Rutabaga::query_resource()`.
+///
+/// Fields may be added over time, so the struct can't be constructed outside of rutabaga.
+#[non_exhaustive]
+#[derive(Copy, Clone, Debug)]
+pub struct RutabagaResourceInfo {
+    pub resource_id: u32,
+    pub size: u64,
+    pub blob: bool,
+    pub blob_mem: u32,
+    pub blob_flags: u32,
+    /// As returned by `Rutabaga::map_info()`.
+    pub map_info: Option<u32>,
+    pub info_3d: Option<Resource3DInfo>,
+    pub vulkan_info: Option<VulkanInfo>,
+    /// Bitmask of the components that have imported the resource, indexed by
+    /// `RutabagaComponentType`.
+    pub component_mask: u8,
+    /// True if guest memory is attached to the resource.
+    pub has_backing: bool,
+    /// The active mapping of the resource, if it's mapped.
+    pub mapping: Option<RutabagaMappingInfo>,
+}
+
+/// A resource shared with another device, as returned by `Rutabaga::hold_resource()`.
+#[non_exhaustive]
+pub struct RutabagaHeldResource {
+    /// Passed to `Rutabaga::release_resource()` once the device is done with the resource.
+    pub hold_id: u64,
+    pub resource_id: u32,
+    /// The memory of the resource, usually a dma-buf.
+    pub handle: RutabagaHandle,
+    pub size: u64,
+    /// The format, modifier and plane layout of image resources.
+    pub info_3d: Option<Resource3DInfo>,
+}
+
 /// The preserved fields of `RutabagaResource` that are saved and loaded across snapshot and
 /// restore.
 #[derive(Deserialize, Serialize)]
@@ -141,6 +289,8 @@ impl TryFrom<&RutabagaResource> for RutabagaResourceSnapshot {
             info_2d: resource.info_2d.as_ref().map(|info| Rutabaga2DSnapshot {
                 width: info.width,
                 height: info.height,
+                layers: info.layers,
+                levels: info.levels,
             }),
             info_3d: resource.info_3d,
             vulkan_info: resource.vulkan_info,
@@ -153,6 +303,23 @@ impl TryFrom<&RutabagaResource> for RutabagaResourceSnapshot {
 impl TryFrom<RutabagaResourceSnapshot> for RutabagaResource {
     type Error = RutabagaError;
     fn try_from(snapshot: RutabagaResourceSnapshot) -> Result<Self, Self::Error> {
+        let info_2d = snapshot
+            .info_2d
+            .map(|info| -> RutabagaResult<Rutabaga2DInfo> {
+                let layers = info.layers.max(1);
+                let levels = info.levels.max(1);
+                let size = host_mem_size(info.width, info.height, layers, levels)?;
+                Ok(Rutabaga2DInfo {
+                    width: info.width,
+                    height: info.height,
+                    layers,
+                    levels,
+                    host_mem: Some(vec![0; size]),
+                    scanout_stride: None,
+                })
+            })
+            .transpose()?;
+
         Ok(RutabagaResource {
             resource_id: snapshot.resource_id,
             handle: None,
@@ -160,15 +327,7 @@ impl TryFrom<RutabagaResourceSnapshot> for RutabagaResource {
             blob_mem: snapshot.blob_mem,
             blob_flags: snapshot.blob_flags,
             map_info: snapshot.map_info,
-            info_2d: snapshot.info_2d.map(|info| {
-                let size = u64::from(info.width * info.height * 4);
-                Rutabaga2DInfo {
-                    width: info.width,
-                    height: info.height,
-                    host_mem: Some(vec![0; usize::try_from(size).unwrap()]),
-                    scanout_stride: None,
-                }
-            }),
+            info_2d,
             info_3d: snapshot.info_3d,
             vulkan_info: snapshot.vulkan_info,
             backing_iovecs: None,
@@ -186,7 +345,27 @@ impl TryFrom<RutabagaResourceSnapshot> for RutabagaResource {
 ///
 /// Most methods return a `RutabagaResult` that indicate the success, failure, or requested data for
 /// the given command.
-pub trait RutabagaComponent {
+/// The context a guest asked for, passed to RutabagaComponent::create_context().
+#[derive(Copy, Clone, Debug)]
+pub struct RutabagaContextParams<'a> {
+    pub ctx_id: u32,
+    /// Determines the command stream of the context.  For virgl contexts, it is a Gallium/TGSI
+    /// command stream.  For gfxstream contexts, it's an autogenerated Vulkan or GLES streams.  The
+    /// priority and compute-only bits are cleared, since renderers don't know them.
+    pub context_init: u32,
+    #[cfg_attr(
+        not(any(feature = "virgl_renderer", feature = "gfxstream")),
+        allow(dead_code)
+    )]
+    pub context_name: Option<&'a str>,
+    /// Work of the context should be scheduled at this priority, if the component supports
+    /// priorities.
+    pub priority: RutabagaContextPriority,
+    /// If set, the guest won't submit graphics work to the context.
+    pub compute_only: bool,
+}
+
+pub trait RutabagaComponent: Send {
     /// Implementations should return the version and size of the given capset_id.  (0, 0) is
     /// returned by default.
     fn get_capset_info(&self, _capset_id: u32) -> (u32, u32) {
@@ -251,6 +430,12 @@ pub trait RutabagaComponent {
         Err(MesaError::Unsupported.into())
     }
 
+    /// Implementations must make `resource`, which was created by another component, usable by
+    /// this component through the resource's shareable handle.
+    fn import_shared(&self, _resource: &RutabagaResource) -> RutabagaResult<()> {
+        Err(MesaError::Unsupported.into())
+    }
+
     /// Implementations must attach `vecs` to the resource.
     fn attach_backing(
         &self,
@@ -278,6 +463,30 @@ pub trait RutabagaComponent {
         Ok(())
     }
 
+    /// Implementations may prepare the transfer write operation to run on the transfer worker,
+    /// instead of performing it right away.  The job may access the resource's memory until it
+    /// has run.  Returning None performs the transfer with transfer_write().
+    fn transfer_write_job(
+        &self,
+        _ctx_id: u32,
+        _resource: &mut RutabagaResource,
+        _transfer: Transfer3D,
+    ) -> RutabagaResult<Option<RutabagaTransferJob>> {
+        Ok(None)
+    }
+
+    /// Implementations may prepare the transfer read operation to the resource's attached iovecs
+    /// to run on the transfer worker, like `transfer_write_job()`.  Returning None performs the
+    /// transfer with transfer_read().
+    fn transfer_read_job(
+        &self,
+        _ctx_id: u32,
+        _resource: &mut RutabagaResource,
+        _transfer: Transfer3D,
+    ) -> RutabagaResult<Option<RutabagaTransferJob>> {
+        Ok(None)
+    }
+
     /// Implementations must perform the transfer read operation.  For 2D rutabaga components, this
     /// done via memcpy().  For 3D components, this is typically done via glReadPixels(..).
     fn transfer_read(
@@ -308,6 +517,55 @@ pub trait RutabagaComponent {
         Err(MesaError::Unsupported.into())
     }
 
+    /// Implementations must resize the blob `resource` to `size` on success.  Guest memory blobs
+    /// are given new backing `iovecs`, while host memory blobs are given a new `handle`.  The
+    /// resource must be left untouched on failure.
+    fn resize_blob(
+        &self,
+        _resource: &mut RutabagaResource,
+        _size: u64,
+        _iovecs: Option<Vec<RutabagaIovec>>,
+        _handle: Option<RutabagaHandle>,
+    ) -> RutabagaResult<()> {
+        Err(MesaError::Unsupported.into())
+    }
+
+    /// Implementations must return the handle type (MESA_HANDLE_TYPE_*) that exporting a blob
+    /// created with `resource_create_blob` would produce, without creating it.
+    fn probe_blob_handle_type(
+        &self,
+        _ctx_id: u32,
+        _resource_create_blob: &ResourceCreateBlob,
+    ) -> RutabagaResult<u32> {
+        Err(MesaError::Unsupported.into())
+    }
+
+    /// Implementations should return the handle types their blobs may be exported as, and those
+    /// they can import blobs of other components from.
+    fn handle_types(&self) -> RutabagaHandleTypes {
+        Default::default()
+    }
+
+    /// Implementations that import the host handle of guest memory blobs created with
+    /// `RUTABAGA_BLOB_FLAG_CREATE_GUEST_HANDLE` should return true.  Otherwise, rutabaga keeps the
+    /// handle to export the blob with.
+    fn imports_guest_handles(&self) -> bool {
+        false
+    }
+
+    /// Implementations that can handle holes in guest memory iovecs (see
+    /// `RutabagaIovec::is_hole`) should return true.  Sparse iovec lists are rejected otherwise.
+    fn supports_sparse_iovecs(&self) -> bool {
+        false
+    }
+
+    /// Implementations that keep the guest shadow pages of RUTABAGA_BLOB_MEM_HOST3D_GUEST blobs in
+    /// sync with host memory on transfers should return true.  Otherwise, rutabaga copies between
+    /// the shadow pages and a mapping of the blob.
+    fn syncs_hybrid_blobs(&self) -> bool {
+        false
+    }
+
     /// Implementations must map the blob resource on success. If addr is Some, the resource
     /// should be mapped at the specified address. Otherwise, the implementation may choose
     /// the address.
@@ -315,6 +573,41 @@ pub trait RutabagaComponent {
         Err(MesaError::Unsupported.into())
     }
 
+    /// Implementations must return the mask of handle types, as by `handle_type_mask()`, that
+    /// rutabaga maps blob resources through rather than calling `map()`.  Only handles the host
+    /// can mmap, such as shared memory or dma-bufs, can be mapped this way.  Zero means blobs are mapped by `map()`.
+    fn mapped_handle_types(&self) -> u32 {
+        0
+    }
+
+    /// Implementations should return true if their contexts may be used concurrently with each
+    /// other and with the component, each from one thread at a time.  `RutabagaShared` then
+    /// submits to them, and creates their fences, without holding the core lock.
+    fn concurrent_contexts(&self) -> bool {
+        false
+    }
+
+    /// Implementations that detect lost contexts, for example after a GPU reset, must report them
+    /// with `handler`.
+    fn set_device_lost_handler(&mut self, _handler: RutabagaDeviceLostHandler) {}
+
+    /// Implementations that log should send their messages to `sink` rather than the `log` crate.
+    fn set_log_sink(&mut self, _sink: RutabagaLogSink) {}
+
+    /// Implementations that deliver host events to guest contexts, such as device memory reports,
+    /// should queue them with `events`.
+    fn set_event_sink(&mut self, _events: RutabagaEventSink) {}
+
+    /// Implementations that choose where mappable blobs live should honor `placement` when
+    /// creating them.
+    fn set_memory_placement(&mut self, _placement: RutabagaMemoryPlacement) {}
+
+    /// Implementations that accept guest debug markers should forward them to `trace`.
+    fn set_trace(&mut self, _trace: RutabagaTrace) {}
+
+    /// Implementations whose guests label resources should record the labels in `names`.
+    fn set_resource_names(&mut self, _names: RutabagaResourceNames) {}
+
     /// Implementations must map the blob resource on success.  This is typically done by
     /// glMapBufferRange(...) or vkMapMemory.
     fn map(&self, _resource_id: u32) -> RutabagaResult<MesaMapping> {
@@ -332,14 +625,10 @@ pub trait RutabagaComponent {
         Err(MesaError::Unsupported.into())
     }
 
-    /// Implementations must create a context for submitting commands.  The command stream of the
-    /// context is determined by `context_init`.  For virgl contexts, it is a Gallium/TGSI command
-    /// stream.  For gfxstream contexts, it's an autogenerated Vulkan or GLES streams.
+    /// Implementations must create a context for submitting commands, as described by `params`.
     fn create_context(
         &self,
-        _ctx_id: u32,
-        _context_init: u32,
-        _context_name: Option<&str>,
+        _params: RutabagaContextParams,
         _fence_handler: RutabagaFenceHandler,
     ) -> RutabagaResult<Box<dyn RutabagaContext>> {
         Err(MesaError::Unsupported.into())
@@ -375,7 +664,7 @@ pub trait RutabagaComponent {
     }
 }
 
-pub trait RutabagaContext {
+pub trait RutabagaContext: Send {
     /// Implementations must return a RutabagaResource given the `resource_create_blob` parameters.
     fn context_create_blob(
         &mut self,
@@ -386,6 +675,15 @@ pub trait RutabagaContext {
         Err(MesaError::Unsupported.into())
     }
 
+    /// Implementations must return the handle type (MESA_HANDLE_TYPE_*) of the blob
+    /// `context_create_blob` would create given `resource_create_blob`, without creating it.
+    fn probe_blob_handle_type(
+        &self,
+        _resource_create_blob: &ResourceCreateBlob,
+    ) -> RutabagaResult<u32> {
+        Err(MesaError::Unsupported.into())
+    }
+
     /// Implementations must handle the context-specific command stream.
     fn submit_cmd(
         &mut self,
@@ -400,6 +698,16 @@ pub trait RutabagaContext {
     /// Implementations must stop using `resource` in this context's command stream.
     fn detach(&mut self, _resource: &RutabagaResource);
 
+    /// Implementations must replace any backing of `resource` they hold with the backing it was
+    /// given by a resize.
+    fn resize_blob(&mut self, _resource: &mut RutabagaResource) {}
+
+    /// Implementations must stop using any backing iovecs they hold that intersect the host
+    /// address `range`, and return the ids of the affected resources.
+    fn invalidate_guest_memory(&mut self, _range: &Range<u64>) -> Vec<u32> {
+        Vec::new()
+    }
+
     /// Implementations must create a fence on specified `ring_idx` in `fence`.  This
     /// allows for multiple synchronizations timelines per RutabagaContext.
     ///
@@ -415,6 +723,13 @@ pub trait RutabagaContext {
     /// Implementations must return the component type associated with the context.
     fn component_type(&self) -> RutabagaComponentType;
 
+    /// Implementations whose guest drivers issue transfers around CPU accesses of mapped blobs,
+    /// rather than relying on coherent mappings, should return true.  Rutabaga may then map a
+    /// staged copy of a blob the component fails to map.
+    fn explicit_transfers(&self) -> bool {
+        false
+    }
+
     /// Implementations must serialize the context.
     fn snapshot(&self) -> RutabagaResult<Vec<u8>> {
         Err(MesaError::Unsupported.into())
@@ -506,10 +821,176 @@ fn calculate_component(component_mask: u8) -> RutabagaResult<RutabagaComponentTy
         2 => Ok(RutabagaComponentType::VirglRenderer),
         3 => Ok(RutabagaComponentType::Gfxstream),
         4 => Ok(RutabagaComponentType::CrossDomain),
+        5 => Ok(RutabagaComponentType::Magma),
         _ => Err(RutabagaError::InvalidComponent),
     }
 }
 
+/// The number of damage rectangles tracked per resource before they are merged into one.
+const RUTABAGA_MAX_DAMAGE_RECTS: usize = 16;
+
+/// Estimates the number of bytes moved by `transfer` when no explicit buffer is given.  All
+/// official virtio_gpu formats are 4 bytes per pixel.
+fn transfer_size(transfer: &Transfer3D) -> usize {
+    let row_bytes = match transfer.stride {
+        0 => transfer.w as u64 * 4,
+        stride => stride as u64,
+    };
+
+    (row_bytes * transfer.h as u64 * transfer.d.max(1) as u64)
+        .try_into()
+        .unwrap_or(usize::MAX)
+}
+
+/// Copies `range` of a hybrid blob between its guest shadow pages `iovecs` and its host `mapping`,
+/// towards the host if `to_host`.
+fn copy_shadow(
+    iovecs: &[RutabagaIovec],
+    mapping: MesaMapping,
+    range: Range<usize>,
+    to_host: bool,
+) -> RutabagaResult<()> {
+    if range.end as u64 > mapping.size {
+        return Err(MesaError::WithContext("transfer exceeds the blob mapping").into());
+    }
+
+    let mut offset = 0;
+    for iovec in iovecs {
+        let start = range.start.max(offset);
+        let end = range.end.min(offset + iovec.len);
+        if start < end {
+            // SAFETY:
+            // The shadow pages are backed and pinned by the caller, `start - offset` and `end` are
+            // within the iovec and the mapping respectively, and the two never overlap.
+            unsafe {
+                let guest = (iovec.base as *mut u8).add(start - offset);
+                let host = (mapping.ptr as *mut u8).add(start);
+                if to_host {
+                    ptr::copy_nonoverlapping(guest, host, end - start);
+                } else {
+                    ptr::copy_nonoverlapping(host, guest, end - start);
+                }
+            }
+        }
+        offset += iovec.len;
+    }
+
+    Ok(())
+}
+
+/// Returns a random (version 4) UUID.
+fn new_resource_uuid() -> RutabagaResult<[u8; 16]> {
+    let mut uuid = [0u8; 16];
+    getrandom::fill(&mut uuid)
+        .map_err(|_| MesaError::WithContext("failed to generate a resource UUID"))?;
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    Ok(uuid)
+}
+
+/// Limits on what guests may allocate, for hosts shared by many guests.  Unset limits aren't
+/// enforced.
+#[derive(Clone, Default)]
+struct RutabagaLimits {
+    contexts_per_capset: Map<u32, u32>,
+    resources: Option<u32>,
+    blob_bytes_per_context: Option<u64>,
+    blob_bytes: Option<u64>,
+}
+
+/// The host mapping a hybrid blob's shadow pages are synchronized with.  Created by the first
+/// transfer and kept until the blob is destroyed or resized.
+enum RutabagaShadowMapping {
+    /// A mapping of the blob's handle, which doesn't need the blob to be mappable by the guest.
+    Handle(MemoryMapping),
+    /// A reference to the component's mapping, for blobs without a mappable handle.
+    Component(MesaMapping),
+}
+
+impl RutabagaShadowMapping {
+    fn as_mesa_mapping(&self) -> MesaMapping {
+        match self {
+            RutabagaShadowMapping::Handle(mapping) => mapping.as_mesa_mapping(),
+            RutabagaShadowMapping::Component(mapping) => *mapping,
+        }
+    }
+}
+
+/// A host shmem copy of a blob the renderer failed to map, which the guest maps instead.  The
+/// copy is synchronized with the blob on guest transfers, and written back when it's unmapped.
+struct RutabagaStagedMapping {
+    mapping: MemoryMapping,
+    // The context transfers are issued on.
+    ctx_id: u32,
+}
+
+impl RutabagaStagedMapping {
+    /// Returns a transfer of the whole blob, as a buffer.
+    fn transfer(&self) -> RutabagaResult<Transfer3D> {
+        let size = self.mapping.as_mesa_mapping().size;
+        Ok(Transfer3D {
+            x: 0,
+            y: 0,
+            z: 0,
+            w: size.try_into().map_err(MesaError::TryFromIntError)?,
+            h: 1,
+            d: 1,
+            level: 0,
+            stride: 0,
+            layer_stride: 0,
+            offset: 0,
+        })
+    }
+}
+
+/// Delivers the fences components signal.  The state tracking them is updated first, then the
+/// VMM's handler and the fence subscribers are called.
+#[derive(Clone)]
+struct RutabagaFenceDispatcher {
+    trace: RutabagaTrace,
+    deferred: RutabagaDeferredDestruction,
+    stats: RutabagaStats,
+    subscribers: RutabagaFenceSubscribers,
+    handler: RutabagaFenceHandler,
+}
+
+impl RutabagaFenceDispatcher {
+    /// Returns the handler components signal fences with.  `lost` is given a handler to signal
+    /// the pending fences of lost contexts with.
+    fn into_handler(self, lost: &RutabagaLostContexts) -> RutabagaFenceHandler {
+        let dispatcher = self.clone();
+        lost.set_fence_handler(RutabagaHandler::new(move |fence: RutabagaFence| {
+            dispatcher.deliver(fence)
+        }));
+
+        let lost = lost.clone();
+        RutabagaHandler::new(move |fence: RutabagaFence| {
+            if let Some(fence) = lost.fence_signaled(fence) {
+                self.deliver(fence);
+            }
+        })
+    }
+
+    fn deliver(&self, fence: RutabagaFence) {
+        self.trace.fence_signaled(&fence);
+        self.deferred.fence_done(&fence);
+        self.stats.fence_signaled(&fence);
+        self.handler.call(fence);
+        self.subscribers.fence_signaled(fence);
+    }
+}
+
+/// What's needed to bring up gfxstream after build().
+#[cfg(feature = "gfxstream")]
+#[derive(Clone)]
+struct GfxstreamConfig {
+    display_width: u32,
+    display_height: u32,
+    flags: GfxstreamFlags,
+    features: Option<String>,
+    debug_handler: Option<RutabagaDebugHandler>,
+}
+
 /// The global library handle used to query capability sets, create resources and contexts.
 ///
 /// Currently, Rutabaga only supports one default component.  Many components running at the
@@ -518,15 +999,70 @@ fn calculate_component(component_mask: u8) -> RutabagaResult<RutabagaComponentTy
 /// Not thread-safe, but can be made so easily.  Making non-Rutabaga, C/C++ components
 /// thread-safe is more difficult.
 pub struct Rutabaga {
-    resources: Map<u32, RutabagaResource>,
+    // Declared first such that queued transfers finish before the resources they access are
+    // dropped.
+    transfer_queue: Option<RutabagaTransferQueue>,
+    // Shared with RutabagaShared, which looks up resources without the core lock.
+    resources: RutabagaResources,
+    // Resource ids are chosen by the guest and host handles change across restore, so VMM
+    // consumers identify resources by UUID instead.
+    resource_uuids: Map<u32, [u8; 16]>,
+    // Labels given by set_resource_name() or guest commands, for debugging.
+    resource_names: RutabagaResourceNames,
+    // The component that created each resource.  Other components may import the resource when
+    // it is attached to one of their contexts, but only the owner can map or resize it.
+    resource_owners: Map<u32, RutabagaComponentType>,
+    // Rectangles written by transfer_write() since the last take_scanout().
+    resource_damage: Map<u32, Vec<RutabagaRect>>,
+    // The host mapping of each mapped blob and how many times it was mapped.  Repeated map()
+    // calls share the mapping, which is only torn down by the last unmap().
+    resource_mappings: Map<u32, (MesaMapping, u32)>,
+    staged_mappings: Map<u32, RutabagaStagedMapping>,
+    shadow_mappings: Map<u32, RutabagaShadowMapping>,
+    // Dmabufs of guest memory blobs, created on first export and dropped when the backing
+    // changes.
+    guest_dmabufs: Map<u32, RutabagaHandle>,
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    udmabuf: Option<RutabagaUdmabuf>,
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    hostmem: Option<RutabagaHostmem>,
+    // The context and size each blob is charged to, and the resulting total of every context.
+    blob_charges: Map<u32, (u32, u64)>,
+    blob_usage: Map<u32, u64>,
     #[cfg(fence_passing_option1)]
     shareable_fences: Map<u64, MesaHandle>,
-    contexts: Map<u32, Box<dyn RutabagaContext>>,
+    // Shared with RutabagaShared, which submits to some contexts without the core lock.
+    contexts: RutabagaContexts,
+    context_capsets: Map<u32, u32>,
+    // Names given by the guest at context creation, for debugging.
+    context_names: Map<u32, String>,
+    limits: RutabagaLimits,
+    venus_filter: RutabagaVenusFilter,
+    memory_placements: Map<RutabagaComponentType, RutabagaMemoryPlacement>,
     // Declare components after resources and contexts such that it is dropped last.
     components: Map<RutabagaComponentType, Box<dyn RutabagaComponent>>,
     default_component: RutabagaComponentType,
     capset_info: Vec<RutabagaCapsetInfo>,
+    // The host GPUs magma contexts may select, enumerated when magma is brought up.
+    gpu_devices: Vec<RutabagaGpuDevice>,
+    capset_handler: Option<RutabagaCapsetHandler>,
+    log_sink: Option<RutabagaLogSink>,
+    // Components passed to remove_component(), which are dropped once nothing uses them.
+    removed_components: Set<RutabagaComponentType>,
+    #[cfg(feature = "gfxstream")]
+    gfxstream_config: GfxstreamConfig,
     fence_handler: RutabagaFenceHandler,
+    stats: RutabagaStats,
+    trace: RutabagaTrace,
+    renderdoc: Option<RutabagaRenderDoc>,
+    lost: RutabagaLostContexts,
+    submitter: RutabagaSubmitter,
+    fence_subscribers: RutabagaFenceSubscribers,
+    deferred: RutabagaDeferredDestruction,
+    events: RutabagaEventSink,
+    // Killed once everything else is dropped.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    _render_server: Option<RutabagaRenderServer>,
 }
 
 /// The serialized and deserialized parts of `Rutabaga` that are preserved across
@@ -534,22 +1070,40 @@ pub struct Rutabaga {
 #[derive(Deserialize, Serialize)]
 struct RutabagaSnapshot {
     resources: Map<u32, RutabagaResourceSnapshot>,
+    // Missing from older snapshots.
+    #[serde(default)]
+    resource_uuids: Map<u32, [u8; 16]>,
+    #[serde(default)]
+    resource_names: Map<u32, String>,
+    #[serde(default)]
+    blob_charges: Map<u32, (u32, u64)>,
     contexts: Map<u32, Vec<u8>>,
+    #[serde(default)]
+    context_capsets: Map<u32, u32>,
+    #[serde(default)]
+    context_names: Map<u32, String>,
 }
 
 impl Rutabaga {
+    /// Suspends every component, e.g. so they can save memory the GPU loses while suspended.
     pub fn suspend(&self) -> RutabagaResult<()> {
-        let component = self
-            .components
-            .get(&self.default_component)
-            .ok_or(RutabagaError::InvalidComponent)?;
+        self.wait_all_transfers();
+        if !self.components.contains_key(&self.default_component) {
+            return Err(RutabagaError::InvalidComponent);
+        }
+
+        for component in self.components.values() {
+            component.suspend()?;
+        }
 
-        component.suspend()
+        Ok(())
     }
 
     /// Take a snapshot of Rutabaga's current state. The snapshot is serialized into an opaque byte
     /// stream and written to `w`.
     pub fn snapshot(&self, directory: &Path) -> RutabagaResult<()> {
+        self.wait_all_transfers();
+        self.reap_resources();
         let snapshot_writer = RutabagaSnapshotWriter::from_existing(directory);
 
         let component = self
@@ -564,24 +1118,42 @@ impl Rutabaga {
         let snapshot = RutabagaSnapshot {
             resources: self
                 .resources
-                .iter()
-                .map(|(i, r)| Ok((*i, RutabagaResourceSnapshot::try_from(r)?)))
+                .map(|i, r| {
+                    let mut snapshot = RutabagaResourceSnapshot::try_from(r)?;
+                    // Imports by other components do not survive restore since handles don't.
+                    if let Some(owner) = self.resource_owners.get(&i) {
+                        snapshot.component_mask = 1 << (*owner as u8);
+                    }
+                    Ok((i, snapshot))
+                })
+                .into_iter()
                 .collect::<RutabagaResult<_>>()?,
+            resource_uuids: self.resource_uuids.clone(),
+            resource_names: self.resource_names.snapshot(),
+            blob_charges: self.blob_charges.clone(),
             contexts: self
                 .contexts
-                .iter()
-                .map(|(i, c)| Ok((*i, c.snapshot()?)))
+                .all(None)
+                .into_iter()
+                .map(|(i, c)| Ok((i, c.lock().unwrap().snapshot()?)))
                 .collect::<RutabagaResult<_>>()?,
+            context_capsets: self.context_capsets.clone(),
+            context_names: self.context_names.clone(),
         };
         snapshot_writer.add_fragment("rutabaga_snapshot", &snapshot)
     }
 
     fn destroy_objects(&mut self) -> RutabagaResult<()> {
-        let resource_ids: Vec<_> = self.resources.keys().cloned().collect();
-        resource_ids
+        self.resources
+            .ids()
             .into_iter()
             .try_for_each(|resource_id| self.unref_resource(resource_id))?;
 
+        // Everything is replaced by the restored state, so nothing waits for the old fences.
+        for resource_id in self.deferred.resource_ids() {
+            self.destroy_deferred(resource_id);
+        }
+
         self.contexts.clear();
 
         Ok(())
@@ -624,27 +1196,58 @@ impl Rutabaga {
 
         let snapshot: RutabagaSnapshot = snapshot_reader.get_fragment("rutabaga_snapshot")?;
 
-        self.resources = snapshot
+        let resources: Map<u32, RutabagaResource> = snapshot
             .resources
             .into_iter()
             .map(|(i, s)| Ok((i, RutabagaResource::try_from(s)?)))
             .collect::<RutabagaResult<_>>()?;
-        self.contexts = snapshot
+        self.resource_uuids = resources
+            .keys()
+            .map(|i| match snapshot.resource_uuids.get(i) {
+                Some(uuid) => Ok((*i, *uuid)),
+                None => Ok((*i, new_resource_uuid()?)),
+            })
+            .collect::<RutabagaResult<_>>()?;
+        self.resource_names.restore(snapshot.resource_names);
+        self.resource_owners = resources
+            .iter()
+            .filter_map(|(i, r)| Some((*i, calculate_component(r.component_mask).ok()?)))
+            .collect();
+        self.resources.replace(resources);
+        self.blob_charges = snapshot.blob_charges;
+        self.blob_usage.clear();
+        for (ctx_id, size) in self.blob_charges.values() {
+            *self.blob_usage.entry(*ctx_id).or_default() += size;
+        }
+        let contexts = snapshot
             .contexts
             .into_iter()
             .map(|(i, c)| Ok((i, component.restore_context(c, self.fence_handler.clone())?)))
-            .collect::<RutabagaResult<_>>()?;
+            .collect::<RutabagaResult<Vec<_>>>()?;
+        let concurrent = component.concurrent_contexts();
+        self.context_names = snapshot.context_names;
+        for (ctx_id, ctx) in contexts {
+            self.lost.context_created(ctx_id, ctx.component_type());
+            self.stats
+                .context_created(ctx_id, self.context_names.get(&ctx_id).map(String::as_str));
+            self.contexts.insert(ctx_id, ctx, concurrent);
+        }
+        self.context_capsets = snapshot.context_capsets;
 
         Ok(())
     }
 
+    /// Resumes every component suspended by `suspend()`.
     pub fn resume(&self) -> RutabagaResult<()> {
-        let component = self
-            .components
-            .get(&self.default_component)
-            .ok_or(RutabagaError::InvalidComponent)?;
+        if !self.components.contains_key(&self.default_component) {
+            return Err(RutabagaError::InvalidComponent);
+        }
+
+        for component in self.components.values() {
+            component.resume()?;
+        }
 
-        component.resume()
+        Ok(())
     }
 
     fn capset_id_to_component_type(&self, capset_id: u32) -> RutabagaResult<RutabagaComponentType> {
@@ -695,7 +1298,12 @@ impl Rutabaga {
             .get(&component_type)
             .ok_or(RutabagaError::InvalidComponent)?;
 
-        Ok(component.get_capset(capset_id, version))
+        let mut capset = component.get_capset(capset_id, version);
+        if capset_id == RUTABAGA_CAPSET_VENUS {
+            self.venus_filter.apply(&mut capset);
+        }
+
+        Ok(capset)
     }
 
     /// Gets the number of capsets
@@ -703,6 +1311,136 @@ impl Rutabaga {
         self.capset_info.len() as u32
     }
 
+    /// Returns the host GPUs guests may select in context_init, in device index order.  Empty
+    /// unless magma is enabled and found GPUs.
+    pub fn gpu_devices(&self) -> &[RutabagaGpuDevice] {
+        &self.gpu_devices
+    }
+
+    /// Brings up `component_type` after build(), for instance once a GPU is hot-plugged, and
+    /// advertises its capsets.  Only gfxstream and magma can be added at runtime.
+    ///
+    /// Capsets are appended, so existing capset indices stay valid.  The capset handler is called
+    /// so the VMM can update the number of capsets, and contexts with an event ring get a
+    /// RUTABAGA_CONTEXT_EVENT_CAPSETS_CHANGED event so the guest can query them again.
+    pub fn add_component(&mut self, component_type: RutabagaComponentType) -> RutabagaResult<()> {
+        if self.components.contains_key(&component_type) {
+            if self.removed_components.remove(&component_type) {
+                self.advertise_capsets(component_type);
+                return Ok(());
+            }
+
+            return Err(MesaError::WithContext("component already present").into());
+        }
+
+        let mut component: Box<dyn RutabagaComponent> = match component_type {
+            #[cfg(feature = "gfxstream")]
+            RutabagaComponentType::Gfxstream => {
+                let config = self.gfxstream_config.clone();
+                Gfxstream::init(
+                    config.display_width,
+                    config.display_height,
+                    config.flags,
+                    config.features,
+                    self.fence_handler.clone(),
+                    config.debug_handler,
+                )?
+            }
+            RutabagaComponentType::Magma => {
+                set_magma_log_sink(self.log_sink.clone());
+                self.gpu_devices = enumerate_gpu_devices();
+                MagmaVirtioGpu::init(self.fence_handler.clone(), self.gpu_devices.clone())?
+            }
+            _ => return Err(MesaError::Unsupported.into()),
+        };
+
+        component.set_event_sink(self.events.clone());
+        component.set_device_lost_handler(self.lost.component_handler(component_type));
+        if let Some(sink) = &self.log_sink {
+            component.set_log_sink(sink.clone());
+        }
+        if let Some(placement) = self.memory_placements.get(&component_type) {
+            component.set_memory_placement(*placement);
+        }
+        component.set_trace(self.trace.clone());
+        component.set_resource_names(self.resource_names.clone());
+        self.components.insert(component_type, component);
+        self.advertise_capsets(component_type);
+        Ok(())
+    }
+
+    /// Stops advertising the capsets of `component_type`, so no new contexts are created on it,
+    /// and tears it down once its last context and resource are gone.  The default component
+    /// can't be removed.
+    ///
+    /// Capsets after the removed ones move to lower indices, so the guest must query them again
+    /// once told, like after add_component().
+    pub fn remove_component(
+        &mut self,
+        component_type: RutabagaComponentType,
+    ) -> RutabagaResult<()> {
+        if component_type == self.default_component {
+            return Err(MesaError::WithContext("the default component can't be removed").into());
+        }
+
+        if !self.components.contains_key(&component_type)
+            || !self.removed_components.insert(component_type)
+        {
+            return Err(RutabagaError::InvalidComponent);
+        }
+
+        self.capset_info
+            .retain(|capset_info| capset_info.component != component_type);
+        self.capsets_changed();
+        self.reap_components();
+        Ok(())
+    }
+
+    fn advertise_capsets(&mut self, component_type: RutabagaComponentType) {
+        self.capset_info.extend(
+            RUTABAGA_CAPSETS
+                .iter()
+                .filter(|capset_info| capset_info.component == component_type),
+        );
+        self.capsets_changed();
+    }
+
+    fn capsets_changed(&self) {
+        let num_capsets = self.get_num_capsets();
+        if let Some(handler) = &self.capset_handler {
+            handler.call(num_capsets);
+        }
+
+        let event = RutabagaCapsetsChanged {
+            num_capsets,
+            padding: 0,
+        };
+        self.events
+            .broadcast(RUTABAGA_CONTEXT_EVENT_CAPSETS_CHANGED, event.as_bytes());
+    }
+
+    /// Drops the removed components that no context or resource uses anymore.
+    fn reap_components(&mut self) {
+        let unused: Vec<RutabagaComponentType> = self
+            .removed_components
+            .iter()
+            .copied()
+            .filter(|component_type| {
+                let component_bit = 1 << (*component_type as u8);
+                !self.contexts.uses_component(*component_type)
+                    && !self
+                        .resources
+                        .map(|_, resource| resource.component_mask & component_bit != 0)
+                        .contains(&true)
+            })
+            .collect();
+
+        for component_type in unused {
+            self.removed_components.remove(&component_type);
+            self.components.remove(&component_type);
+        }
+    }
+
     /// Forces context zero for the default rutabaga component.
     pub fn force_ctx_0(&self) {
         if let Some(component) = self.components.get(&self.default_component) {
@@ -714,19 +1452,69 @@ impl Rutabaga {
     /// If the flags include RUTABAGA_FLAG_INFO_RING_IDX, then the fence is created on a
     /// specific timeline on the specific context.
     pub fn create_fence(&mut self, fence: RutabagaFence) -> RutabagaResult<()> {
+        if !self.fence_begin(fence)? {
+            return Ok(());
+        }
+
+        let result = self.submit_fence(fence);
+        if result.is_err() {
+            self.fence_rejected(fence);
+        }
+
+        self.reap_resources();
+        result
+    }
+
+    /// Records `fence` before it is handed to its context or component.  Returns false if it was
+    /// an event fence, which needs nothing more.
+    pub(crate) fn fence_begin(&mut self, fence: RutabagaFence) -> RutabagaResult<bool> {
+        self.stats.fence_created(&fence);
+        // Event fences wait for the host rather than for the context's work.
+        if self.events.is_event_fence(&fence) {
+            let result = self.events.wait(fence);
+            if result.is_err() {
+                self.stats.fence_dropped(&fence);
+            }
+            return result.map(|_| false);
+        }
+
+        if let Some(renderdoc) = &mut self.renderdoc {
+            renderdoc.fence_created(&fence);
+        }
+        let component_type = if fence.flags & RUTABAGA_FLAG_INFO_RING_IDX != 0 {
+            self.contexts.component_type(fence.ctx_id)
+        } else {
+            Some(self.default_component)
+        };
+        if let Some(component_type) = component_type {
+            self.deferred.fence_created(&fence, component_type);
+        }
+
+        Ok(true)
+    }
+
+    /// Forgets `fence` after its context or component rejected it.
+    pub(crate) fn fence_rejected(&mut self, fence: RutabagaFence) {
+        self.deferred.fence_done(&fence);
+        self.stats.fence_dropped(&fence);
+    }
+
+    fn submit_fence(&mut self, fence: RutabagaFence) -> RutabagaResult<()> {
         if fence.flags & RUTABAGA_FLAG_INFO_RING_IDX != 0 {
             let ctx = self
                 .contexts
-                .get_mut(&fence.ctx_id)
+                .get(fence.ctx_id)
                 .ok_or(RutabagaError::InvalidContextId)?;
 
             #[allow(unused_variables)]
-            let handle_opt = ctx.context_create_fence(fence)?;
+            let handle_opt = self.submitter.create_fence(&ctx, fence)?;
 
+            // Fences of lost contexts have no handle.
             #[cfg(fence_passing_option1)]
             if fence.flags & RUTABAGA_FLAG_FENCE_HOST_SHAREABLE != 0 {
-                let handle = handle_opt.unwrap();
-                self.shareable_fences.insert(fence.fence_id, handle);
+                if let Some(handle) = handle_opt {
+                    self.shareable_fences.insert(fence.fence_id, handle);
+                }
             }
         } else {
             let component = self
@@ -745,6 +1533,8 @@ impl Rutabaga {
         if let Some(component) = self.components.get(&self.default_component) {
             component.event_poll();
         }
+
+        self.reap_resources();
     }
 
     /// Returns a pollable descriptor for the default rutabaga component. In practice, it is only
@@ -760,6 +1550,9 @@ impl Rutabaga {
         resource_id: u32,
         resource_create_3d: ResourceCreate3D,
     ) -> RutabagaResult<()> {
+        self.destroy_deferred(resource_id);
+        self.check_resource_limit(resource_id)?;
+        let uuid = new_resource_uuid()?;
         let component = self
             .components
             .get_mut(&self.default_component)
@@ -770,7 +1563,7 @@ impl Rutabaga {
         }
 
         let resource = component.create_3d(resource_id, resource_create_3d)?;
-        self.resources.insert(resource_id, resource);
+        self.insert_resource(resource_id, resource, uuid);
         Ok(())
     }
 
@@ -782,6 +1575,9 @@ impl Rutabaga {
         import_handle: RutabagaHandle,
         import_data: RutabagaImportData,
     ) -> RutabagaResult<()> {
+        self.destroy_deferred(resource_id);
+        self.check_resource_limit(resource_id)?;
+        let uuid = new_resource_uuid()?;
         let component = self
             .components
             .get_mut(&self.default_component)
@@ -789,7 +1585,7 @@ impl Rutabaga {
 
         match component.import(resource_id, import_handle, import_data) {
             Ok(Some(resource)) => {
-                self.resources.insert(resource_id, resource);
+                self.insert_resource(resource_id, resource, uuid);
             }
             Ok(None) => {
                 if !self.resources.contains_key(&resource_id) {
@@ -807,73 +1603,500 @@ impl Rutabaga {
         resource_id: u32,
         mut vecs: Vec<RutabagaIovec>,
     ) -> RutabagaResult<()> {
+        self.wait_transfers(resource_id);
+        self.guest_dmabufs.remove(&resource_id);
         let component = self
             .components
             .get_mut(&self.default_component)
             .ok_or(RutabagaError::InvalidComponent)?;
 
-        let resource = self
+        let mut resource = self
             .resources
             .get_mut(&resource_id)
             .ok_or(RutabagaError::InvalidResourceId)?;
 
+        validate_iovecs(&vecs, component.supports_sparse_iovecs())?;
         component.attach_backing(resource_id, &mut vecs)?;
-        resource.backing_iovecs = Some(vecs);
+        if let Some(backing) = resource.backing_iovecs.replace(RutabagaBacking::new(vecs)) {
+            backing.detach();
+        }
         Ok(())
     }
 
     /// Detaches any previously attached iovecs from the resource.
     pub fn detach_backing(&mut self, resource_id: u32) -> RutabagaResult<()> {
+        self.wait_transfers(resource_id);
+        self.guest_dmabufs.remove(&resource_id);
         let component = self
             .components
             .get_mut(&self.default_component)
             .ok_or(RutabagaError::InvalidComponent)?;
 
-        let resource = self
+        let mut resource = self
             .resources
             .get_mut(&resource_id)
             .ok_or(RutabagaError::InvalidResourceId)?;
 
         component.detach_backing(resource_id);
-        resource.backing_iovecs = None;
+        // Waits for contexts still accessing the iovecs, such as a cross-domain ring write.
+        if let Some(backing) = resource.backing_iovecs.take() {
+            backing.detach();
+        }
         Ok(())
     }
 
-    /// Releases guest kernel reference on the resource.
-    pub fn unref_resource(&mut self, resource_id: u32) -> RutabagaResult<()> {
+    /// Detaches every backing iovec intersecting the host address `range` from the resources and
+    /// contexts using it.  VMMs must call this before hot-removing the guest memory mapped at
+    /// `range`.  Returns the sorted ids of the affected resources, which have no backing until
+    /// the guest attaches new memory.
+    pub fn invalidate_guest_memory(&mut self, range: Range<u64>) -> RutabagaResult<Vec<u32>> {
+        self.wait_all_transfers();
         let component = self
             .components
             .get_mut(&self.default_component)
             .ok_or(RutabagaError::InvalidComponent)?;
 
-        self.resources
-            .remove(&resource_id)
-            .ok_or(RutabagaError::InvalidResourceId)?;
+        let mut resource_ids = Vec::new();
+        self.resources.for_each_mut(|resource_id, resource| {
+            let intersects = resource
+                .backing_iovecs
+                .as_ref()
+                .is_some_and(|backing| backing.intersects(&range));
+
+            if intersects {
+                component.detach_backing(resource_id);
+                if let Some(backing) = resource.backing_iovecs.take() {
+                    backing.detach();
+                }
+                resource_ids.push(resource_id);
+            }
+        });
+
+        for (_, ctx) in self.contexts.all(None) {
+            resource_ids.extend(ctx.lock().unwrap().invalidate_guest_memory(&range));
+        }
+
+        resource_ids.sort_unstable();
+        resource_ids.dedup();
+        for resource_id in &resource_ids {
+            self.guest_dmabufs.remove(resource_id);
+        }
+        Ok(resource_ids)
+    }
+
+    fn insert_resource(&mut self, resource_id: u32, resource: RutabagaResource, uuid: [u8; 16]) {
+        if let Ok(owner) = calculate_component(resource.component_mask) {
+            self.resource_owners.insert(resource_id, owner);
+        }
+        self.resources.insert(resource_id, resource);
+        self.resource_uuids.insert(resource_id, uuid);
+    }
+
+    /// Fails if `resource_id` would be a new resource beyond the resource limit.
+    fn check_resource_limit(&self, resource_id: u32) -> RutabagaResult<()> {
+        let at_limit = self
+            .limits
+            .resources
+            .is_some_and(|max| self.resources.len() >= max as usize);
+        if at_limit && !self.resources.contains_key(&resource_id) {
+            return Err(RutabagaError::ResourceLimitExceeded);
+        }
 
-        component.unref_resource(resource_id);
         Ok(())
     }
 
-    /// For HOST3D_GUEST resources, copies from the attached iovecs to the host resource.  For
-    /// HOST3D resources, this may flush caches, though this feature is unused by guest userspace.
-    pub fn transfer_write(
+    /// Fails if blobs charged to `ctx_id`, or all blobs, would exceed their limit after adding
+    /// `size` bytes and releasing `released` bytes of a blob of `ctx_id`.
+    fn check_blob_quota(&self, ctx_id: u32, size: u64, released: u64) -> RutabagaResult<()> {
+        let usage = self.blob_usage.get(&ctx_id).copied().unwrap_or(0);
+        let over_quota = self
+            .limits
+            .blob_bytes_per_context
+            .is_some_and(|max| usage.saturating_sub(released).saturating_add(size) > max);
+        if over_quota {
+            return Err(RutabagaError::BlobQuotaExceeded(ctx_id));
+        }
+
+        let total: u64 = self.blob_usage.values().sum();
+        let over_limit = self
+            .limits
+            .blob_bytes
+            .is_some_and(|max| total.saturating_sub(released).saturating_add(size) > max);
+        if over_limit {
+            return Err(RutabagaError::BlobLimitExceeded);
+        }
+
+        Ok(())
+    }
+
+    /// Charges the blob given by `resource_id` to `ctx_id`, replacing any previous charge.
+    fn charge_blob(&mut self, ctx_id: u32, resource_id: u32, size: u64) {
+        self.release_blob(resource_id);
+        self.blob_charges.insert(resource_id, (ctx_id, size));
+        *self.blob_usage.entry(ctx_id).or_default() += size;
+    }
+
+    fn release_blob(&mut self, resource_id: u32) {
+        if let Some((ctx_id, size)) = self.blob_charges.remove(&resource_id) {
+            if let Some(usage) = self.blob_usage.get_mut(&ctx_id) {
+                *usage = usage.saturating_sub(size);
+                if *usage == 0 {
+                    self.blob_usage.remove(&ctx_id);
+                }
+            }
+        }
+    }
+
+    /// Waits for the queued transfers of the resource given by `resource_id`, before its memory
+    /// is accessed on the calling thread.
+    fn wait_transfers(&self, resource_id: u32) {
+        if let Some(transfer_queue) = &self.transfer_queue {
+            transfer_queue.wait(resource_id);
+        }
+    }
+
+    fn wait_all_transfers(&self) {
+        if let Some(transfer_queue) = &self.transfer_queue {
+            transfer_queue.wait_idle();
+        }
+    }
+
+    /// Returns the component that created the resource given by `resource_id`.
+    fn resource_owner(&self, resource_id: u32) -> RutabagaResult<RutabagaComponentType> {
+        if !self.resources.contains_key(&resource_id) {
+            return Err(RutabagaError::InvalidResourceId);
+        }
+
+        let owner = self.resource_owners.get(&resource_id).copied();
+        Ok(owner.ok_or(MesaError::WithContext("can't infer single component"))?)
+    }
+
+    /// Releases guest kernel reference on the resource.
+    pub fn unref_resource(&mut self, resource_id: u32) -> RutabagaResult<()> {
+        self.wait_transfers(resource_id);
+        if !self.components.contains_key(&self.default_component) {
+            return Err(RutabagaError::InvalidComponent);
+        }
+
+        // Guests may unreference blobs they never unmapped.
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        if self
+            .hostmem
+            .as_ref()
+            .is_some_and(|hostmem| hostmem.contains(resource_id))
+        {
+            if let Err(e) = self.unmap_blob(resource_id) {
+                log::warn!("failed to unmap blob {resource_id}: {e}");
+            }
+        }
+
+        if let Err(e) = self.unmap_shadow(resource_id) {
+            log::warn!("failed to unmap the shadow of blob {resource_id}: {e}");
+        }
+
+        let resource = self
+            .resources
+            .remove(&resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?;
+        self.resource_uuids.remove(&resource_id);
+        self.resource_names.remove(resource_id);
+        self.resource_owners.remove(&resource_id);
+        self.resource_damage.remove(&resource_id);
+        self.resource_mappings.remove(&resource_id);
+        self.staged_mappings.remove(&resource_id);
+        self.guest_dmabufs.remove(&resource_id);
+
+        // Components that imported the resource hold their own reference.  Work submitted to any
+        // of them before now may still use the resource, so it is only destroyed once their
+        // pending fences have signaled.
+        let components = self.holding_components(self.default_component, resource.component_mask);
+        self.deferred.defer(resource_id, components);
+
+        if let Some(backing) = resource.backing_iovecs {
+            backing.detach();
+        }
+
+        self.release_blob(resource_id);
+        self.reap_resources();
+        self.reap_components();
+        Ok(())
+    }
+
+    /// Returns `owner` and the other components that imported a resource, given its
+    /// `component_mask`.
+    fn holding_components(
+        &self,
+        owner: RutabagaComponentType,
+        component_mask: u8,
+    ) -> Vec<RutabagaComponentType> {
+        let mut components = vec![owner];
+        components.extend(self.components.keys().copied().filter(|component_type| {
+            let imported = component_mask & (1 << (*component_type as u8)) != 0;
+            imported && *component_type != owner
+        }));
+        components
+    }
+
+    fn destroy_resource(&self, resource_id: u32, components: &[RutabagaComponentType]) {
+        for component_type in components {
+            if let Some(component) = self.components.get(component_type) {
+                component.unref_resource(resource_id);
+            }
+        }
+    }
+
+    /// Destroys the resources whose deferred destruction no longer waits for any fence.
+    fn reap_resources(&self) {
+        for (resource_id, components) in self.deferred.take_ready() {
+            self.destroy_resource(resource_id, &components);
+        }
+    }
+
+    /// Destroys `resource_id` right away if its destruction was deferred, e.g. because the guest
+    /// reuses its id.
+    fn destroy_deferred(&self, resource_id: u32) {
+        if let Some(components) = self.deferred.take(resource_id) {
+            self.destroy_resource(resource_id, &components);
+        }
+    }
+
+    /// For HOST3D_GUEST resources, copies from the attached iovecs to the host resource.  For
+    /// HOST3D resources, this may flush caches, though this feature is unused by guest userspace.
+    pub fn transfer_write(
         &mut self,
         ctx_id: u32,
         resource_id: u32,
         transfer: Transfer3D,
         buf: Option<IoSlice>,
     ) -> RutabagaResult<()> {
+        self.wait_transfers(resource_id);
+        if buf.is_none() && self.staged_mappings.contains_key(&resource_id) {
+            // The guest flushes what it wrote to the staged copy.
+            return self.write_staged(resource_id);
+        }
+
+        if buf.is_none() && self.shadows_blob(resource_id) {
+            let _span = self.trace.transfer_write(ctx_id, resource_id);
+            self.sync_shadow(resource_id, &transfer, true)?;
+            self.stats.record_transfer(ctx_id, transfer.w as usize);
+            return Ok(());
+        }
+
+        let component = self
+            .components
+            .get(&self.default_component)
+            .ok_or(RutabagaError::InvalidComponent)?;
+
+        let mut resource = self
+            .resources
+            .get_mut(&resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?;
+
+        let bytes = buf.as_ref().map(|buf| buf.len());
+        let _span = self.trace.transfer_write(ctx_id, resource_id);
+        component.transfer_write(ctx_id, &mut resource, transfer, buf)?;
+        drop(resource);
+        self.stats
+            .record_transfer(ctx_id, bytes.unwrap_or_else(|| transfer_size(&transfer)));
+        if !transfer.is_empty() {
+            self.add_damage(resource_id, RutabagaRect::from(&transfer));
+        }
+        Ok(())
+    }
+
+    /// Like `transfer_write()` from the attached iovecs, but returns once the transfer is queued
+    /// when async transfers are enabled.  `fence` signals once the transfer is done, and must
+    /// have RUTABAGA_FLAG_INFO_RING_IDX set, naming a ring used only for transfers: transfers
+    /// complete in the order they were queued, but not in order with other work.
+    ///
+    /// The guest must not modify the attached iovecs until `fence` signals.  Any other use of the
+    /// resource by the VMM waits for the transfer.  Errors found once the transfer has been
+    /// queued are logged, and `fence` still signals.
+    pub fn transfer_write_async(
+        &mut self,
+        ctx_id: u32,
+        resource_id: u32,
+        transfer: Transfer3D,
+        fence: RutabagaFence,
+    ) -> RutabagaResult<()> {
+        if fence.flags & RUTABAGA_FLAG_INFO_RING_IDX == 0 {
+            return Err(MesaError::WithContext("async transfers need a ring fence").into());
+        }
+
+        let shadowed = self.shadows_blob(resource_id);
+        if self.transfer_queue.is_none() {
+            self.transfer_write(ctx_id, resource_id, transfer, None)?;
+            self.stats.fence_created(&fence);
+            self.fence_handler.call(fence);
+            return Ok(());
+        }
+
         let component = self
             .components
             .get(&self.default_component)
             .ok_or(RutabagaError::InvalidComponent)?;
 
-        let resource = self
+        let mut resource = self
             .resources
             .get_mut(&resource_id)
             .ok_or(RutabagaError::InvalidResourceId)?;
 
-        component.transfer_write(ctx_id, resource, transfer, buf)
+        // Shadow pages are synchronized through a mapping, which can't be shared with the queue.
+        let job = if shadowed {
+            None
+        } else {
+            let _span = self.trace.transfer_write(ctx_id, resource_id);
+            component.transfer_write_job(ctx_id, &mut resource, transfer)?
+        };
+        drop(resource);
+
+        match job {
+            Some(job) => {
+                self.queue_transfer(resource_id, job, fence)?;
+                self.stats.record_transfer(ctx_id, transfer_size(&transfer));
+                if !transfer.is_empty() {
+                    self.add_damage(resource_id, RutabagaRect::from(&transfer));
+                }
+            }
+            None => {
+                self.transfer_write(ctx_id, resource_id, transfer, None)?;
+                // Queued behind earlier transfers, so the ring's fences signal in order.
+                self.queue_transfer(resource_id, Box::new(|| Ok(())), fence)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Queues `job` on the transfer queue, which signals `fence` once it has run.
+    fn queue_transfer(
+        &self,
+        resource_id: u32,
+        job: RutabagaTransferJob,
+        fence: RutabagaFence,
+    ) -> RutabagaResult<()> {
+        let transfer_queue = self
+            .transfer_queue
+            .as_ref()
+            .ok_or(MesaError::WithContext("async transfers are disabled"))?;
+
+        self.stats.fence_created(&fence);
+        if let Err(e) = transfer_queue.queue(resource_id, job, fence) {
+            self.stats.fence_dropped(&fence);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if rutabaga, rather than the component owning it, keeps the guest shadow pages
+    /// of the hybrid blob `resource_id` in sync with its host memory.
+    fn shadows_blob(&self, resource_id: u32) -> bool {
+        let hybrid = self
+            .resources
+            .get(&resource_id)
+            .is_some_and(|resource| resource.blob_mem == RUTABAGA_BLOB_MEM_HOST3D_GUEST);
+
+        hybrid
+            && self
+                .resource_owner(resource_id)
+                .ok()
+                .and_then(|component_type| self.components.get(&component_type))
+                .is_some_and(|component| !component.syncs_hybrid_blobs())
+    }
+
+    /// Copies bytes `transfer.x..transfer.x + transfer.w` of a hybrid blob from its guest shadow
+    /// pages to its host memory if `to_host`, and back otherwise.  Hybrid blobs are linear, so the
+    /// rest of the box is ignored.
+    fn sync_shadow(
+        &mut self,
+        resource_id: u32,
+        transfer: &Transfer3D,
+        to_host: bool,
+    ) -> RutabagaResult<()> {
+        let resource = self
+            .resources
+            .get(&resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?;
+        let backing = resource
+            .backing_iovecs
+            .as_ref()
+            .ok_or(RutabagaError::InvalidIovec)?
+            .pin()?;
+
+        let start = transfer.x as u64;
+        let width = transfer.w as u64;
+        let end = checked_arithmetic!(start + width)?;
+        checked_range!(end; <= resource.size)?;
+        drop(resource);
+
+        let mapping = self.shadow_mapping(resource_id)?;
+        copy_shadow(&backing, mapping, start as usize..end as usize, to_host)
+    }
+
+    /// Returns the host mapping the shadow pages of the hybrid blob `resource_id` are synchronized
+    /// with, mapping the blob on first use.
+    fn shadow_mapping(&mut self, resource_id: u32) -> RutabagaResult<MesaMapping> {
+        if let Some(mapping) = self.shadow_mappings.get(&resource_id) {
+            return Ok(mapping.as_mesa_mapping());
+        }
+
+        let resource = self
+            .resources
+            .get(&resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?;
+        let handle = resource
+            .handle
+            .as_ref()
+            .and_then(|handle| handle.as_mesa_handle())
+            .filter(|handle| {
+                matches!(
+                    handle.handle_type,
+                    MESA_HANDLE_TYPE_MEM_SHM | MESA_HANDLE_TYPE_MEM_DMABUF
+                )
+            })
+            .map(|handle| handle.try_clone())
+            .transpose()?;
+        let size: usize = resource
+            .size
+            .try_into()
+            .map_err(MesaError::TryFromIntError)?;
+        drop(resource);
+
+        let mapping = match handle {
+            // Creating the mapping closes the cloned descriptor.
+            Some(handle) => RutabagaShadowMapping::Handle(MemoryMapping::from_safe_descriptor(
+                handle.os_handle,
+                size,
+                RUTABAGA_MAP_CACHE_CACHED | RUTABAGA_MAP_ACCESS_RW,
+            )?),
+            None => RutabagaShadowMapping::Component(self.map(resource_id)?),
+        };
+
+        let mesa_mapping = mapping.as_mesa_mapping();
+        self.shadow_mappings.insert(resource_id, mapping);
+        Ok(mesa_mapping)
+    }
+
+    /// Drops the shadow mapping of the hybrid blob `resource_id`, if it has one.
+    fn unmap_shadow(&mut self, resource_id: u32) -> RutabagaResult<()> {
+        match self.shadow_mappings.remove(&resource_id) {
+            Some(RutabagaShadowMapping::Component(_)) => self.unmap(resource_id),
+            _ => Ok(()),
+        }
+    }
+
+    /// Records `rect` as damage, for the next take_scanout().
+    fn add_damage(&mut self, resource_id: u32, rect: RutabagaRect) {
+        let damage = self.resource_damage.entry(resource_id).or_default();
+        damage.push(rect);
+        // Display backends gain little from many small rectangles.
+        if damage.len() > RUTABAGA_MAX_DAMAGE_RECTS {
+            let bounds = damage[1..]
+                .iter()
+                .fold(damage[0], |acc, rect| acc.union(rect));
+            *damage = vec![bounds];
+        }
     }
 
     /// 1) If specified, copies to `buf` from the resource (host or guest).
@@ -887,31 +2110,128 @@ impl Rutabaga {
         transfer: Transfer3D,
         buf: Option<IoSliceMut>,
     ) -> RutabagaResult<()> {
+        self.wait_transfers(resource_id);
+        if buf.is_none() && self.staged_mappings.contains_key(&resource_id) {
+            return self.read_staged(resource_id);
+        }
+
+        if buf.is_none() && self.shadows_blob(resource_id) {
+            let _span = self.trace.transfer_read(ctx_id, resource_id);
+            self.sync_shadow(resource_id, &transfer, false)?;
+            self.stats.record_transfer(ctx_id, transfer.w as usize);
+            return Ok(());
+        }
+
         let component = self
             .components
             .get(&self.default_component)
             .ok_or(RutabagaError::InvalidComponent)?;
 
-        let resource = self
+        let mut resource = self
+            .resources
+            .get_mut(&resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?;
+
+        let bytes = buf.as_ref().map(|buf| buf.len());
+        let _span = self.trace.transfer_read(ctx_id, resource_id);
+        component.transfer_read(ctx_id, &mut resource, transfer, buf)?;
+        self.stats
+            .record_transfer(ctx_id, bytes.unwrap_or_else(|| transfer_size(&transfer)));
+        Ok(())
+    }
+
+    /// Like `transfer_read()` to the attached iovecs, but returns once the transfer is queued
+    /// when async transfers are enabled.  `fence` signals once the iovecs hold the contents, and
+    /// must name a transfer ring as for `transfer_write_async()`, in order with whose transfers
+    /// this one completes.
+    ///
+    /// The guest must not access the attached iovecs until `fence` signals.  Any other use of the
+    /// resource by the VMM waits for the transfer.  Errors found once the transfer has been
+    /// queued are logged, and `fence` still signals.
+    pub fn transfer_read_async(
+        &mut self,
+        ctx_id: u32,
+        resource_id: u32,
+        transfer: Transfer3D,
+        fence: RutabagaFence,
+    ) -> RutabagaResult<()> {
+        if fence.flags & RUTABAGA_FLAG_INFO_RING_IDX == 0 {
+            return Err(MesaError::WithContext("async transfers need a ring fence").into());
+        }
+
+        let shadowed = self.shadows_blob(resource_id);
+        if self.transfer_queue.is_none() {
+            self.transfer_read(ctx_id, resource_id, transfer, None)?;
+            self.stats.fence_created(&fence);
+            self.fence_handler.call(fence);
+            return Ok(());
+        }
+
+        let component = self
+            .components
+            .get(&self.default_component)
+            .ok_or(RutabagaError::InvalidComponent)?;
+
+        let mut resource = self
             .resources
             .get_mut(&resource_id)
             .ok_or(RutabagaError::InvalidResourceId)?;
 
-        component.transfer_read(ctx_id, resource, transfer, buf)
+        // Shadow pages are synchronized through a mapping, which can't be shared with the queue.
+        let job = if shadowed {
+            None
+        } else {
+            let _span = self.trace.transfer_read(ctx_id, resource_id);
+            component.transfer_read_job(ctx_id, &mut resource, transfer)?
+        };
+        drop(resource);
+
+        match job {
+            Some(job) => {
+                self.queue_transfer(resource_id, job, fence)?;
+                self.stats.record_transfer(ctx_id, transfer_size(&transfer));
+            }
+            None => {
+                self.transfer_read(ctx_id, resource_id, transfer, None)?;
+                // Queued behind earlier transfers, so the ring's fences signal in order.
+                self.queue_transfer(resource_id, Box::new(|| Ok(())), fence)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Records `rect` of the resource given by `resource_id` as changed by the guest, for the
+    /// next take_scanout().  Rendering by 3D components isn't visible to rutabaga, so VMMs pass
+    /// on the rectangles of the guest's resource flushes here.
+    pub fn resource_damage(&mut self, resource_id: u32, rect: RutabagaRect) -> RutabagaResult<()> {
+        if !self.resources.contains_key(&resource_id) {
+            return Err(RutabagaError::InvalidResourceId);
+        }
+
+        if rect.w != 0 && rect.h != 0 {
+            self.add_damage(resource_id, rect);
+        }
+        Ok(())
     }
 
     pub fn resource_flush(&mut self, resource_id: u32) -> RutabagaResult<()> {
+        self.wait_transfers(resource_id);
+        if let Some(renderdoc) = &mut self.renderdoc {
+            renderdoc.scanout_flushed();
+        }
+
         let component = self
             .components
             .get(&self.default_component)
             .ok_or(MesaError::Unsupported)?;
 
-        let resource = self
+        let mut resource = self
             .resources
             .get_mut(&resource_id)
             .ok_or(RutabagaError::InvalidResourceId)?;
 
-        component.resource_flush(resource)
+        component.resource_flush(&mut resource)
     }
 
     pub fn set_scanout(
@@ -920,7 +2240,7 @@ impl Rutabaga {
         resource_id: u32,
         info: Option<Resource3DInfo>,
     ) -> RutabagaResult<()> {
-        let resource = self
+        let mut resource = self
             .resources
             .get_mut(&resource_id)
             .ok_or(RutabagaError::InvalidResourceId)?;
@@ -937,21 +2257,74 @@ impl Rutabaga {
         Ok(())
     }
 
+    /// Hands off the resource given by `resource_id` to the display, returning the regions that
+    /// changed since the previous hand-off: those written by transfers, and those passed to
+    /// resource_damage().
+    pub fn take_scanout(&mut self, resource_id: u32) -> RutabagaResult<RutabagaScanout> {
+        self.wait_transfers(resource_id);
+        let resource = self
+            .resources
+            .get(&resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?;
+
+        let damage = self
+            .resource_damage
+            .remove(&resource_id)
+            .unwrap_or_default();
+
+        let info_3d = resource.info_3d;
+        let guest_blob = resource.blob_mem == RUTABAGA_BLOB_MEM_GUEST;
+        let mut handle = resource
+            .handle
+            .as_ref()
+            .map(|handle| handle.try_clone())
+            .transpose()?;
+        drop(resource);
+
+        // Guest memory blobs are shown without a copy if they can be turned into a dmabuf.
+        if handle.is_none() && guest_blob {
+            handle = self.guest_dmabuf(resource_id).ok();
+        }
+
+        Ok(RutabagaScanout {
+            handle,
+            info_3d,
+            damage,
+        })
+    }
+
     /// Creates a blob resource with the `ctx_id` and `resource_create_blob` metadata.
     /// Associates `iovecs` with the resource, if there are any.  Associates externally
     /// created `handle` with the resource, if there is any.
+    ///
+    /// RUTABAGA_BLOB_MEM_HOST3D_GUEST blobs must be given `iovecs` covering the whole blob.  They
+    /// shadow the host memory, and transfers keep the two in sync.
     pub fn resource_create_blob(
         &mut self,
         ctx_id: u32,
         resource_id: u32,
-        resource_create_blob: ResourceCreateBlob,
+        mut resource_create_blob: ResourceCreateBlob,
         iovecs: Option<Vec<RutabagaIovec>>,
-        handle: Option<RutabagaHandle>,
+        mut handle: Option<RutabagaHandle>,
     ) -> RutabagaResult<()> {
         if self.resources.contains_key(&resource_id) {
             return Err(RutabagaError::InvalidResourceId);
         }
 
+        self.destroy_deferred(resource_id);
+        self.check_resource_limit(resource_id)?;
+        let uuid = new_resource_uuid()?;
+        self.check_blob_quota(ctx_id, resource_create_blob.size, 0)?;
+
+        let protected_mappable = RUTABAGA_BLOB_FLAG_PROTECTED | RUTABAGA_BLOB_FLAG_USE_MAPPABLE;
+        if resource_create_blob.blob_flags & protected_mappable == protected_mappable {
+            return Err(MesaError::WithContext("protected blobs can't be mapped").into());
+        }
+
+        let _span = self
+            .trace
+            .create_blob(ctx_id, resource_id, resource_create_blob.size);
+
         let component = self
             .components
             .get_mut(&self.default_component)
@@ -962,57 +2335,285 @@ impl Rutabaga {
         // rutabaga context rather than one from an external C/C++ component.  Use `ctx_id` and
         // the component type if it happens to be a cross-domain context.
         if ctx_id > 0 {
-            let ctx = self
+            let component_type = self
                 .contexts
-                .get_mut(&ctx_id)
+                .component_type(ctx_id)
                 .ok_or(RutabagaError::InvalidContextId)?;
 
-            if ctx.component_type() == RutabagaComponentType::CrossDomain {
-                context = Some(ctx);
+            if component_type == RutabagaComponentType::CrossDomain {
+                context = self.contexts.get(ctx_id);
+            }
+        }
+
+        if let Some(ref iovecs) = iovecs {
+            let allow_holes = context.is_some() || component.supports_sparse_iovecs();
+            let len = validate_iovecs(iovecs, allow_holes)?;
+            // A sparse blob must describe every byte of the blob, backed or not.
+            if iovecs.iter().any(|iovec| iovec.is_hole()) && len != resource_create_blob.size {
+                return Err(RutabagaError::InvalidIovec);
             }
         }
 
-        let resource = match context {
-            Some(ctx) => ctx.context_create_blob(resource_id, resource_create_blob, handle)?,
+        let mut shadow_iovecs = None;
+        if resource_create_blob.blob_mem == RUTABAGA_BLOB_MEM_HOST3D_GUEST {
+            let iovecs = iovecs.as_ref().ok_or(RutabagaError::InvalidIovec)?;
+            if validate_iovecs(iovecs, false)? < resource_create_blob.size {
+                return Err(RutabagaError::InvalidIovec);
+            }
+
+            // Contexts aren't given iovecs, so rutabaga keeps the shadow pages itself.
+            if context.is_some() {
+                shadow_iovecs = Some(iovecs.clone());
+            }
+        }
+
+        // Components don't know about guest handles, so the flag is only restored on the resource.
+        let mut guest_handle = None;
+        if resource_create_blob.blob_flags & RUTABAGA_BLOB_FLAG_CREATE_GUEST_HANDLE != 0 {
+            if context.is_some() {
+                return Err(MesaError::Unsupported.into());
+            }
+
+            if resource_create_blob.blob_mem != RUTABAGA_BLOB_MEM_GUEST || iovecs.is_none() {
+                return Err(MesaError::WithContext("guest handles need guest memory").into());
+            }
+
+            let memory_types = [MESA_HANDLE_TYPE_MEM_DMABUF, MESA_HANDLE_TYPE_MEM_SHM];
+            let hnd = handle
+                .take()
+                .ok_or(MesaError::WithContext("no guest handle given"))?;
+            if !hnd
+                .as_mesa_handle()
+                .is_some_and(|h| memory_types.contains(&h.handle_type))
+            {
+                return Err(MesaError::InvalidMesaHandle.into());
+            }
+
+            if component.imports_guest_handles() {
+                handle = Some(hnd.try_clone()?);
+            }
+
+            resource_create_blob.blob_flags &= !RUTABAGA_BLOB_FLAG_CREATE_GUEST_HANDLE;
+            guest_handle = Some(hnd);
+        }
+
+        let mut resource = match context {
+            Some(ctx) => ctx.lock().unwrap().context_create_blob(
+                resource_id,
+                resource_create_blob,
+                handle,
+            )?,
             None => {
                 component.create_blob(ctx_id, resource_id, resource_create_blob, iovecs, handle)?
             }
         };
 
-        self.resources.insert(resource_id, resource);
+        if let Some(iovecs) = shadow_iovecs {
+            resource.backing_iovecs = Some(RutabagaBacking::new(iovecs));
+        }
+
+        if let Some(guest_handle) = guest_handle {
+            resource.blob_flags |= RUTABAGA_BLOB_FLAG_CREATE_GUEST_HANDLE;
+            if resource.handle.is_none() {
+                resource.handle = Some(Arc::new(guest_handle));
+            }
+        }
+
+        self.insert_resource(resource_id, resource, uuid);
+        self.charge_blob(ctx_id, resource_id, resource_create_blob.size);
         Ok(())
     }
 
-    pub fn map_placed(&mut self, resource_id: u32, placed_addr: u64) -> RutabagaResult<()> {
-        let resource = self
-            .resources
-            .get_mut(&resource_id)
-            .ok_or(RutabagaError::InvalidResourceId)?;
+    /// Returns the handle type (RUTABAGA_HANDLE_TYPE_*) `export_blob` would return for a blob
+    /// created by `resource_create_blob` with the same `ctx_id` and `resource_create_blob`.  This
+    /// lets VMMs choose a mapping strategy before the blob exists.  Fails with `Unsupported` if
+    /// the component can't tell before creating the blob.
+    pub fn probe_blob_handle_type(
+        &self,
+        ctx_id: u32,
+        resource_create_blob: ResourceCreateBlob,
+    ) -> RutabagaResult<u32> {
+        // Blobs are created by the same component or context as in `resource_create_blob`.
+        if ctx_id > 0 {
+            let ctx = self
+                .contexts
+                .get(ctx_id)
+                .ok_or(RutabagaError::InvalidContextId)?;
 
-        let component_type = calculate_component(resource.component_mask)?;
+            let ctx = ctx.lock().unwrap();
+            if ctx.component_type() == RutabagaComponentType::CrossDomain {
+                return ctx.probe_blob_handle_type(&resource_create_blob);
+            }
+        }
 
         let component = self
             .components
-            .get(&component_type)
+            .get(&self.default_component)
             .ok_or(RutabagaError::InvalidComponent)?;
 
-        component.map_placed(resource_id, placed_addr)
-    }
+        if resource_create_blob.blob_flags & RUTABAGA_BLOB_FLAG_CREATE_GUEST_HANDLE != 0 {
+            return Err(
+                MesaError::WithContext("guest handle blobs export the given handle").into(),
+            );
+        }
 
-    /// Returns a memory mapping of the blob resource.
-    pub fn map(&mut self, resource_id: u32) -> RutabagaResult<MesaMapping> {
-        let resource = self
-            .resources
-            .get_mut(&resource_id)
-            .ok_or(RutabagaError::InvalidResourceId)?;
+        match component.probe_blob_handle_type(ctx_id, &resource_create_blob) {
+            Err(RutabagaError::MesaError(MesaError::Unsupported)) => (),
+            result => return result,
+        }
+
+        // Guest memory blobs the component gives no handle are exported as udmabufs.
+        if resource_create_blob.blob_mem == RUTABAGA_BLOB_MEM_GUEST {
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            if self.udmabuf.is_some() {
+                return Ok(MESA_HANDLE_TYPE_MEM_DMABUF);
+            }
+
+            return Err(MesaError::InvalidMesaHandle.into());
+        }
+
+        // A component that only exports one handle type exports every blob as it.
+        let export = component.handle_types().export;
+        if export.is_power_of_two() {
+            return Ok(export.trailing_zeros());
+        }
+
+        Err(MesaError::Unsupported.into())
+    }
+
+    /// Returns the handle types blobs of contexts of `capset_id` may be exported as, and those the
+    /// component behind the capset imports, so VMMs can advertise them to the guest.
+    pub fn handle_types(&self, capset_id: u32) -> RutabagaResult<RutabagaHandleTypes> {
+        let component_type = self
+            .capset_id_to_component_type(capset_id)
+            .unwrap_or(self.default_component);
+
+        let component = self
+            .components
+            .get(&component_type)
+            .ok_or(RutabagaError::InvalidComponent)?;
+
+        Ok(component.handle_types())
+    }
+
+    /// Returns the handle types contexts of `capset_id` export that a guest importing the handle
+    /// types in `guest_import` can use.  VMMs call this when the guest initializes a context, so
+    /// a guest that can't import any of them fails early with `HandleTypeMismatch` rather than
+    /// on its first blob export.
+    pub fn negotiate_handle_types(&self, capset_id: u32, guest_import: u32) -> RutabagaResult<u32> {
+        self.handle_types(capset_id)?.negotiate(guest_import)
+    }
+
+    /// Resizes the blob resource given by `resource_id` to `size`.  Guest memory blobs must be
+    /// given new `iovecs` and shared memory blobs must be given a new `handle`.  The new backing
+    /// replaces the old one atomically, and contexts using the resource are updated.
+    ///
+    /// Work submitted before the resize to a context the blob is attached to may still use the old
+    /// backing, so the resize fails with `AlreadyInUse` until the fences following that work have
+    /// signaled.
+    /// The blob must not be mapped either.
+    pub fn resource_resize_blob(
+        &mut self,
+        resource_id: u32,
+        size: u64,
+        iovecs: Option<Vec<RutabagaIovec>>,
+        handle: Option<RutabagaHandle>,
+    ) -> RutabagaResult<()> {
+        self.wait_transfers(resource_id);
+        let component_type = self.resource_owner(resource_id)?;
+        if self.deferred.in_use(resource_id) {
+            return Err(RutabagaError::AlreadyInUse);
+        }
+
+        let charge = self.blob_charges.get(&resource_id).copied();
+        // The next transfer maps the new backing.
+        self.unmap_shadow(resource_id)?;
+        if let Some((ctx_id, old_size)) = charge {
+            self.check_blob_quota(ctx_id, size, old_size)?;
+        }
+
+        if self.resource_mappings.contains_key(&resource_id) {
+            return Err(MesaError::WithContext("cannot resize a mapped blob").into());
+        }
+
+        self.guest_dmabufs.remove(&resource_id);
+
+        let mut resource = self
+            .resources
+            .get_mut(&resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?;
+
+        if !resource.blob {
+            return Err(MesaError::WithContext("only blob resources may be resized").into());
+        }
+
+        let component = self
+            .components
+            .get(&component_type)
+            .ok_or(RutabagaError::InvalidComponent)?;
+
+        if let Some(ref iovecs) = iovecs {
+            validate_iovecs(iovecs, component.supports_sparse_iovecs())?;
+        }
+
+        component.resize_blob(&mut resource, size, iovecs, handle)?;
+
+        for (_, ctx) in self.contexts.all(Some(component_type)) {
+            ctx.lock().unwrap().resize_blob(&mut resource);
+        }
+        drop(resource);
+
+        if let Some((ctx_id, _)) = charge {
+            self.charge_blob(ctx_id, resource_id, size);
+        }
+
+        Ok(())
+    }
+
+    pub fn map_placed(&mut self, resource_id: u32, placed_addr: u64) -> RutabagaResult<()> {
+        self.wait_transfers(resource_id);
+        let component_type = self.resource_owner(resource_id)?;
+        let component = self
+            .components
+            .get(&component_type)
+            .ok_or(RutabagaError::InvalidComponent)?;
+
+        component.map_placed(resource_id, placed_addr)
+    }
+
+    /// Returns a memory mapping of the blob resource.  Mapping an already mapped resource returns
+    /// the same mapping, which stays valid until every map is balanced by an `unmap()`.
+    pub fn map(&mut self, resource_id: u32) -> RutabagaResult<MesaMapping> {
+        self.wait_transfers(resource_id);
+        if let Some((mapping, map_count)) = self.resource_mappings.get_mut(&resource_id) {
+            *map_count += 1;
+            return Ok(*mapping);
+        }
+
+        let mapping = self.map_resource(resource_id)?;
+        self.resource_mappings.insert(resource_id, (mapping, 1));
+        Ok(mapping)
+    }
+
+    fn map_resource(&mut self, resource_id: u32) -> RutabagaResult<MesaMapping> {
+        let component_type = self.resource_owner(resource_id)?;
+        let component = self
+            .components
+            .get(&component_type)
+            .ok_or(RutabagaError::InvalidComponent)?;
+        let mut resource = self
+            .resources
+            .get_mut(&resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?;
 
-        let component_type = calculate_component(resource.component_mask)?;
-        if component_type == RutabagaComponentType::CrossDomain {
+        let mapped_handle_types = component.mapped_handle_types();
+        if mapped_handle_types != 0 {
             let handle_opt = resource.handle.take();
             match handle_opt {
                 Some(handle) => {
                     if let Some(mesa_handle) = handle.as_mesa_handle() {
-                        if mesa_handle.handle_type != MESA_HANDLE_TYPE_MEM_SHM {
+                        if mapped_handle_types & handle_type_mask(&[mesa_handle.handle_type]) == 0 {
+                            resource.handle = Some(handle);
                             return Err(
                                 MesaError::WithContext("expected a shared memory handle").into()
                             );
@@ -1045,70 +2646,276 @@ impl Rutabaga {
                 None => return Err(MesaError::WithContext("expected a handle to map").into()),
             }
         }
+        drop(resource);
+
+        match component.map(resource_id) {
+            // Such as when the host driver is out of device memory for vkMapMemory().
+            Err(RutabagaError::MappingFailed(ret)) if self.stages_mappings(resource_id) => {
+                log::warn!(
+                    "mapping {} failed ({ret}), using a staged copy",
+                    self.resource_names.label(resource_id)
+                );
+                self.map_staged(resource_id)
+            }
+            result => result,
+        }
+    }
+
+    /// Returns true if the blob may be mapped through a staged copy.  The copy is only coherent
+    /// with the blob at transfers, so the blob's context must issue them.
+    fn stages_mappings(&self, resource_id: u32) -> bool {
+        self.blob_charges
+            .get(&resource_id)
+            .and_then(|(ctx_id, _)| self.contexts.get(*ctx_id))
+            .is_some_and(|context| context.lock().unwrap().explicit_transfers())
+    }
+
+    /// Maps a host shmem copy of the resource, for when the renderer can't map it.  Accesses are
+    /// slow, as every guest transfer copies the whole resource.
+    fn map_staged(&mut self, resource_id: u32) -> RutabagaResult<MesaMapping> {
+        let resource = self
+            .resources
+            .get(&resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?;
+
+        // The copy is transferred as a single buffer box, whose width is 32 bits.
+        if resource.size > u32::MAX as u64 {
+            return Err(MesaError::WithContext("resource is too large to stage").into());
+        }
+
+        let size: usize = resource
+            .size
+            .try_into()
+            .map_err(MesaError::TryFromIntError)?;
+        let map_info = resource
+            .map_info
+            .ok_or(MesaError::WithContext("no map info available"))?;
+        let descriptor: OwnedDescriptor =
+            SharedMemory::new("rutabaga_staged", resource.size)?.into();
+        let mapping = MemoryMapping::from_safe_descriptor(descriptor, size, map_info)?;
+        let mesa_mapping = mapping.as_mesa_mapping();
+        drop(resource);
+
+        let ctx_id = self
+            .blob_charges
+            .get(&resource_id)
+            .map(|(ctx_id, _)| *ctx_id)
+            .unwrap_or(0);
+        self.staged_mappings
+            .insert(resource_id, RutabagaStagedMapping { mapping, ctx_id });
+
+        if let Err(e) = self.read_staged(resource_id) {
+            self.staged_mappings.remove(&resource_id);
+            return Err(e);
+        }
 
+        Ok(mesa_mapping)
+    }
+
+    /// Copies the resource to its staged copy.
+    fn read_staged(&mut self, resource_id: u32) -> RutabagaResult<()> {
+        let component_type = self.resource_owner(resource_id)?;
         let component = self
             .components
             .get(&component_type)
             .ok_or(RutabagaError::InvalidComponent)?;
+        let mut resource = self
+            .resources
+            .get_mut(&resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?;
+        let staged = self
+            .staged_mappings
+            .get(&resource_id)
+            .ok_or(MesaError::WithContext("resource has no staged copy"))?;
 
-        component.map(resource_id)
+        let mesa_mapping = staged.mapping.as_mesa_mapping();
+        // SAFETY:
+        // The staged mapping is owned by rutabaga, and is `size` bytes long.
+        let buf = unsafe {
+            slice::from_raw_parts_mut(mesa_mapping.ptr as *mut u8, mesa_mapping.size as usize)
+        };
+        component.transfer_read(
+            staged.ctx_id,
+            &mut resource,
+            staged.transfer()?,
+            Some(IoSliceMut::new(buf)),
+        )
     }
 
-    /// Unmaps the blob resource from the default component
-    pub fn unmap(&mut self, resource_id: u32) -> RutabagaResult<()> {
-        let resource = self
+    /// Copies the staged copy of the resource back to the resource.
+    fn write_staged(&mut self, resource_id: u32) -> RutabagaResult<()> {
+        let component_type = self.resource_owner(resource_id)?;
+        let component = self
+            .components
+            .get(&component_type)
+            .ok_or(RutabagaError::InvalidComponent)?;
+        let mut resource = self
             .resources
             .get_mut(&resource_id)
             .ok_or(RutabagaError::InvalidResourceId)?;
+        let staged = self
+            .staged_mappings
+            .get(&resource_id)
+            .ok_or(MesaError::WithContext("resource has no staged copy"))?;
 
-        let component_type = calculate_component(resource.component_mask)?;
-        if component_type == RutabagaComponentType::CrossDomain {
-            resource.mapping = None;
+        let mesa_mapping = staged.mapping.as_mesa_mapping();
+        // SAFETY:
+        // The staged mapping is owned by rutabaga, and is `size` bytes long.
+        let buf = unsafe {
+            slice::from_raw_parts(mesa_mapping.ptr as *const u8, mesa_mapping.size as usize)
+        };
+        component.transfer_write(
+            staged.ctx_id,
+            &mut resource,
+            staged.transfer()?,
+            Some(IoSlice::new(buf)),
+        )
+    }
+
+    /// Releases a mapping returned by `map()`.  The resource is unmapped from the default component
+    /// once every map has been released.  Fails if the resource isn't mapped.
+    pub fn unmap(&mut self, resource_id: u32) -> RutabagaResult<()> {
+        self.wait_transfers(resource_id);
+        let (_, map_count) = self
+            .resource_mappings
+            .get_mut(&resource_id)
+            .ok_or(MesaError::WithContext("resource is not mapped"))?;
+
+        if *map_count > 1 {
+            *map_count -= 1;
             return Ok(());
         }
 
+        self.unmap_resource(resource_id)?;
+        self.resource_mappings.remove(&resource_id);
+        Ok(())
+    }
+
+    fn unmap_resource(&mut self, resource_id: u32) -> RutabagaResult<()> {
+        if self.staged_mappings.contains_key(&resource_id) {
+            let written = self.write_staged(resource_id);
+            self.staged_mappings.remove(&resource_id);
+            return written;
+        }
+
+        let component_type = self.resource_owner(resource_id)?;
         let component = self
             .components
             .get(&component_type)
             .ok_or(RutabagaError::InvalidComponent)?;
+        let mut resource = self
+            .resources
+            .get_mut(&resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?;
+
+        if component.mapped_handle_types() != 0 {
+            resource.mapping = None;
+            return Ok(());
+        }
 
         component.unmap(resource_id)
     }
 
-    /// Returns the `map_info` of the blob resource. The valid values for `map_info`
-    /// are defined in the virtio-gpu spec.
-    pub fn map_info(&self, resource_id: u32) -> RutabagaResult<u32> {
+    /// Returns the active mappings, sorted by resource id.  VMMs can record them when taking a
+    /// snapshot to know which blobs to re-map after restore, or inspect them when debugging.
+    pub fn mappings(&self) -> Vec<RutabagaMappingInfo> {
+        self.resource_mappings
+            .iter()
+            .map(|(resource_id, (mapping, map_count))| RutabagaMappingInfo {
+                resource_id: *resource_id,
+                ptr: mapping.ptr,
+                size: mapping.size,
+                map_count: *map_count,
+            })
+            .collect()
+    }
+
+    /// Returns the metadata of the resource, for VMMs reporting it to the guest or to debugging
+    /// tools.
+    pub fn query_resource(&self, resource_id: u32) -> RutabagaResult<RutabagaResourceInfo> {
         let resource = self
             .resources
             .get(&resource_id)
             .ok_or(RutabagaError::InvalidResourceId)?;
 
-        resource
-            .map_info
-            .ok_or(MesaError::WithContext("no map info available").into())
+        let mapping = self
+            .resource_mappings
+            .get(&resource_id)
+            .map(|(mapping, map_count)| RutabagaMappingInfo {
+                resource_id,
+                ptr: mapping.ptr,
+                size: mapping.size,
+                map_count: *map_count,
+            });
+
+        Ok(RutabagaResourceInfo {
+            resource_id,
+            size: resource.size,
+            blob: resource.blob,
+            blob_mem: resource.blob_mem,
+            blob_flags: resource.blob_flags,
+            map_info: resource.map_info,
+            info_3d: resource.info_3d,
+            vulkan_info: resource.vulkan_info,
+            component_mask: resource.component_mask,
+            has_backing: resource.backing_iovecs.is_some(),
+            mapping,
+        })
+    }
+
+    /// Returns the `map_info` of the blob resource. The valid values for `map_info`
+    /// are defined in the virtio-gpu spec.
+    pub fn map_info(&self, resource_id: u32) -> RutabagaResult<u32> {
+        self.resources.map_info(resource_id)
+    }
+
+    /// Labels the resource given by `resource_id` with a guest-provided `name`, or clears its label
+    /// if `name` is None.  Labels are shown by `debug_info()`, tracing and log messages, and are
+    /// preserved across snapshot and restore.
+    pub fn set_resource_name(
+        &mut self,
+        resource_id: u32,
+        name: Option<&str>,
+    ) -> RutabagaResult<()> {
+        if !self.resources.contains_key(&resource_id) {
+            return Err(RutabagaError::InvalidResourceId);
+        }
+
+        self.resource_names.set(resource_id, name)?;
+        if let Some(name) = name {
+            self.trace.resource_named(resource_id, name);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the UUID generated when the resource was created.  Unlike the resource id and host
+    /// handles, it is preserved across snapshot and restore.
+    pub fn resource_uuid(&self, resource_id: u32) -> RutabagaResult<[u8; 16]> {
+        self.resource_uuids
+            .get(&resource_id)
+            .copied()
+            .ok_or(RutabagaError::InvalidResourceId)
+    }
+
+    /// Returns the id of the resource with UUID `uuid`.
+    pub fn resource_id_from_uuid(&self, uuid: &[u8; 16]) -> RutabagaResult<u32> {
+        self.resource_uuids
+            .iter()
+            .find(|(_, resource_uuid)| *resource_uuid == uuid)
+            .map(|(resource_id, _)| *resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)
     }
 
     /// Returns the `vulkan_info` of the blob resource, which consists of the physical device
     /// index and memory index associated with the resource.
     pub fn vulkan_info(&self, resource_id: u32) -> RutabagaResult<VulkanInfo> {
-        let resource = self
-            .resources
-            .get(&resource_id)
-            .ok_or(RutabagaError::InvalidResourceId)?;
-
-        resource.vulkan_info.ok_or(RutabagaError::InvalidVulkanInfo)
+        self.resources.vulkan_info(resource_id)
     }
 
     /// Returns the 3D info associated with the resource, if any.
     pub fn resource3d_info(&self, resource_id: u32) -> RutabagaResult<Resource3DInfo> {
-        let resource = self
-            .resources
-            .get(&resource_id)
-            .ok_or(RutabagaError::InvalidResourceId)?;
-
-        resource
-            .info_3d
-            .ok_or(MesaError::WithContext("no 3d info available").into())
+        self.resources.resource3d_info(resource_id)
     }
 
     /// Returns true if the resource is mappable by the guest CPU.
@@ -1119,11 +2926,17 @@ impl Rutabaga {
 
     /// Exports a blob resource.  See virtio-gpu spec for blob flag use flags.
     pub fn export_blob(&mut self, resource_id: u32) -> RutabagaResult<RutabagaHandle> {
-        let resource = self
+        self.wait_transfers(resource_id);
+        let mut resource = self
             .resources
             .get_mut(&resource_id)
             .ok_or(RutabagaError::InvalidResourceId)?;
 
+        if resource.handle.is_none() && resource.blob_mem == RUTABAGA_BLOB_MEM_GUEST {
+            drop(resource);
+            return self.guest_dmabuf(resource_id);
+        }
+
         // We can inspect blob flags only once guest minigbm is fully transitioned to blob.
         let share_mask = RUTABAGA_BLOB_FLAG_USE_SHAREABLE | RUTABAGA_BLOB_FLAG_USE_CROSS_DEVICE;
         let shareable = (resource.blob_flags & share_mask != 0) || !resource.blob;
@@ -1145,6 +2958,230 @@ impl Rutabaga {
         }
     }
 
+    /// Shares the resource given by `resource_id` with another device, e.g. a virtio-video decoder
+    /// writing frames into buffers the guest allocated from the GPU.  Returns the memory of the
+    /// resource along with its layout, so the device can import it without a copy.
+    ///
+    /// The resource isn't destroyed before `release_resource()` is called with the returned
+    /// `hold_id`, even if the guest unreferences it meanwhile.  If the guest reuses the resource
+    /// id first, the hold is dropped, though the handle stays valid.
+    pub fn hold_resource(&mut self, resource_id: u32) -> RutabagaResult<RutabagaHeldResource> {
+        self.wait_transfers(resource_id);
+        let resource = self
+            .resources
+            .get(&resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?;
+        let size = resource.size;
+        let info_3d = resource.info_3d;
+        let guest_blob = resource.blob_mem == RUTABAGA_BLOB_MEM_GUEST;
+        let handle = resource
+            .handle
+            .as_ref()
+            .map(|handle| handle.try_clone())
+            .transpose()?;
+        drop(resource);
+
+        let handle = match handle {
+            Some(handle) => handle,
+            None if guest_blob => self.guest_dmabuf(resource_id)?,
+            None => return Err(MesaError::InvalidMesaHandle.into()),
+        };
+
+        Ok(RutabagaHeldResource {
+            hold_id: self.deferred.hold(resource_id),
+            resource_id,
+            handle,
+            size,
+            info_3d,
+        })
+    }
+
+    /// Releases a hold returned by `hold_resource()`.  A resource the guest unreferenced while it
+    /// was held is destroyed once its last hold is released.
+    pub fn release_resource(&mut self, hold_id: u64) -> RutabagaResult<()> {
+        if !self.deferred.release(hold_id) {
+            return Err(RutabagaError::InvalidResourceId);
+        }
+
+        self.reap_resources();
+        Ok(())
+    }
+
+    /// Registers a memfd holding guest memory, so guest memory blobs backed by it can be exported
+    /// as dmabufs through /dev/udmabuf.  Fails if the region overlaps a registered one.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub fn add_memfd_region(&mut self, region: RutabagaMemfdRegion) -> RutabagaResult<()> {
+        if self.udmabuf.is_none() {
+            self.udmabuf = Some(RutabagaUdmabuf::new()?);
+        }
+
+        self.udmabuf
+            .as_mut()
+            .ok_or(RutabagaError::InvalidIovec)?
+            .add_region(region)
+    }
+
+    /// Unregisters the memfd region mapped at `host_addr`.  Dmabufs already exported from it stay
+    /// valid.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub fn remove_memfd_region(&mut self, host_addr: u64) -> RutabagaResult<()> {
+        self.udmabuf
+            .as_mut()
+            .ok_or(RutabagaError::InvalidIovec)?
+            .remove_region(host_addr)
+    }
+
+    /// Registers the shared memory region of the device, into which `map_blob()` maps blobs.
+    /// Fails if blobs are mapped into the current region.
+    ///
+    /// # Safety
+    ///
+    /// `region.host_address` must be the start of `region.size` bytes of the process's address
+    /// space that the VMM reserved for rutabaga, and that nothing else maps into or unmaps while
+    /// rutabaga uses the region.  Rutabaga replaces pages of the region with blob mappings and
+    /// keeps the rest reserved, so any other use of those addresses is undefined behavior.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub unsafe fn set_shared_memory_region(
+        &mut self,
+        region: RutabagaSharedMemoryRegion,
+    ) -> RutabagaResult<()> {
+        if self
+            .hostmem
+            .as_ref()
+            .is_some_and(|hostmem| !hostmem.is_empty())
+        {
+            return Err(
+                MesaError::WithContext("blobs are mapped into the shared memory region").into(),
+            );
+        }
+
+        self.hostmem = Some(RutabagaHostmem::new(region)?);
+        Ok(())
+    }
+
+    /// Maps the blob resource into the shared memory region, for VIRTIO_GPU_CMD_RESOURCE_MAP_BLOB.
+    /// The blob is placed at `offset` when the guest driver chose one, and at a free offset
+    /// otherwise.  Blobs of components that map their handles are mapped by rutabaga, lazily if
+    /// they're large enough, while other components place the mapping themselves.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub fn map_blob(
+        &mut self,
+        resource_id: u32,
+        offset: Option<u64>,
+    ) -> RutabagaResult<RutabagaBlobPlacement> {
+        self.wait_transfers(resource_id);
+        let component_type = self.resource_owner(resource_id)?;
+        let component = self
+            .components
+            .get(&component_type)
+            .ok_or(RutabagaError::InvalidComponent)?;
+        let resource = self
+            .resources
+            .get(&resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?;
+        let map_info = resource
+            .map_info
+            .ok_or(MesaError::WithContext("no map info available"))?;
+        let hostmem = self.hostmem.as_mut().ok_or(MesaError::WithContext(
+            "no shared memory region is registered",
+        ))?;
+
+        let offset = hostmem.allocate(resource_id, resource.size, offset)?;
+        let mapped_handle_types = component.mapped_handle_types();
+        let mapped = if mapped_handle_types != 0 {
+            match resource
+                .handle
+                .as_ref()
+                .and_then(|handle| handle.as_mesa_handle())
+            {
+                Some(handle)
+                    if mapped_handle_types & handle_type_mask(&[handle.handle_type]) == 0 =>
+                {
+                    Err(MesaError::WithContext("expected a shared memory handle").into())
+                }
+                Some(handle) => hostmem.map_handle(offset, handle, map_info),
+                None => Err(MesaError::WithContext("expected a handle to map").into()),
+            }
+        } else {
+            component
+                .map_placed(resource_id, hostmem.host_address(offset))
+                .map(|_| (false, map_info))
+        };
+
+        let (lazy, map_info) = match mapped {
+            Ok(mapped) => mapped,
+            Err(e) => {
+                hostmem.release(resource_id)?;
+                return Err(e);
+            }
+        };
+
+        Ok(RutabagaBlobPlacement {
+            offset,
+            map_info,
+            lazy,
+        })
+    }
+
+    /// Unmaps a blob mapped by `map_blob()`, for VIRTIO_GPU_CMD_RESOURCE_UNMAP_BLOB.  Its slot of
+    /// the shared memory region is freed even if the component fails to unmap it.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub fn unmap_blob(&mut self, resource_id: u32) -> RutabagaResult<()> {
+        self.wait_transfers(resource_id);
+        let hostmem = self.hostmem.as_mut().ok_or(MesaError::WithContext(
+            "no shared memory region is registered",
+        ))?;
+        if !hostmem.contains(resource_id) {
+            return Err(MesaError::WithContext("resource is not mapped").into());
+        }
+
+        let unmapped = match self.resource_owner(resource_id) {
+            Ok(component_type) => match self.components.get(&component_type) {
+                Some(component) if component.mapped_handle_types() == 0 => {
+                    component.unmap(resource_id)
+                }
+                Some(_) => Ok(()),
+                None => Err(RutabagaError::InvalidComponent),
+            },
+            Err(e) => Err(e),
+        };
+
+        if let Some(hostmem) = self.hostmem.as_mut() {
+            hostmem.release(resource_id)?;
+        }
+
+        unmapped
+    }
+
+    // Returns a dmabuf of the guest memory blob given by `resource_id`, created from its backing
+    // iovecs on first use.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn guest_dmabuf(&mut self, resource_id: u32) -> RutabagaResult<RutabagaHandle> {
+        if let Some(handle) = self.guest_dmabufs.get(&resource_id) {
+            return handle.try_clone();
+        }
+
+        let udmabuf = self.udmabuf.as_ref().ok_or(MesaError::InvalidMesaHandle)?;
+        let iovecs = self
+            .resources
+            .get(&resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?
+            .backing_iovecs
+            .as_ref()
+            .ok_or(RutabagaError::InvalidIovec)?
+            .pin()?;
+
+        let handle: RutabagaHandle = udmabuf.create(&iovecs)?.into();
+        let clone = handle.try_clone()?;
+        self.guest_dmabufs.insert(resource_id, handle);
+        Ok(clone)
+    }
+
+    #[cfg(not(any(target_os = "android", target_os = "linux")))]
+    fn guest_dmabuf(&mut self, _resource_id: u32) -> RutabagaResult<RutabagaHandle> {
+        Err(MesaError::InvalidMesaHandle.into())
+    }
+
     /// Exports the given fence for import into other processes.
     pub fn export_fence(&mut self, fence_id: u64) -> RutabagaResult<MesaHandle> {
         #[cfg(fence_passing_option1)]
@@ -1161,7 +3198,8 @@ impl Rutabaga {
     }
 
     /// Creates a context with the given `ctx_id` and `context_init` variable.
-    /// `context_init` is used to determine which rutabaga component creates the context.
+    /// `context_init` is used to determine which rutabaga component creates the context, the
+    /// priority it's created at, and whether it's compute-only.
     pub fn create_context(
         &mut self,
         ctx_id: u32,
@@ -1171,6 +3209,8 @@ impl Rutabaga {
         // The default workaround is just until context types are fully supported in all
         // Google kernels.
         let capset_id = context_init & RUTABAGA_CONTEXT_INIT_CAPSET_ID_MASK;
+        let priority = RutabagaContextPriority::from_context_init(context_init)
+            .ok_or(MesaError::WithContext("invalid context priority"))?;
         let component_type = self
             .capset_id_to_component_type(capset_id)
             .unwrap_or(self.default_component);
@@ -1180,41 +3220,108 @@ impl Rutabaga {
             .get_mut(&component_type)
             .ok_or(RutabagaError::InvalidComponent)?;
 
-        if self.contexts.contains_key(&ctx_id) {
+        if self.contexts.contains(ctx_id) {
             return Err(RutabagaError::InvalidContextId);
         }
 
-        let ctx = component.create_context(
+        if let Some(max) = self.limits.contexts_per_capset.get(&capset_id) {
+            let live = self
+                .context_capsets
+                .values()
+                .filter(|ctx_capset_id| **ctx_capset_id == capset_id)
+                .count();
+            if live >= *max as usize {
+                return Err(RutabagaError::ContextLimitExceeded(capset_id));
+            }
+        }
+
+        // Only magma creates contexts on GPUs other than the default one, so the device bits are
+        // left for it.
+        if context_init & RUTABAGA_CONTEXT_INIT_DEVICE_MASK != 0
+            && component_type != RutabagaComponentType::Magma
+        {
+            return Err(MesaError::Unsupported.into());
+        }
+
+        let params = RutabagaContextParams {
             ctx_id,
-            context_init,
+            context_init: context_init
+                & !(RUTABAGA_CONTEXT_INIT_PRIORITY_MASK | RUTABAGA_CONTEXT_INIT_COMPUTE_ONLY),
             context_name,
-            self.fence_handler.clone(),
-        )?;
-        self.contexts.insert(ctx_id, ctx);
+            priority,
+            compute_only: context_init & RUTABAGA_CONTEXT_INIT_COMPUTE_ONLY != 0,
+        };
+        let ctx = component.create_context(params, self.fence_handler.clone())?;
+        self.contexts
+            .insert(ctx_id, ctx, component.concurrent_contexts());
+        self.context_capsets.insert(ctx_id, capset_id);
+        self.lost.context_created(ctx_id, component_type);
+        self.stats.context_created(ctx_id, context_name);
+        if let Some(context_name) = context_name {
+            self.trace.context_named(ctx_id, context_name);
+            self.context_names.insert(ctx_id, context_name.to_string());
+        }
         Ok(())
     }
 
     /// Destroys the context given by `ctx_id`.
     pub fn destroy_context(&mut self, ctx_id: u32) -> RutabagaResult<()> {
         self.contexts
-            .remove(&ctx_id)
+            .remove(ctx_id)
             .ok_or(RutabagaError::InvalidContextId)?;
+        self.context_capsets.remove(&ctx_id);
+        self.context_names.remove(&ctx_id);
+        self.stats.context_destroyed(ctx_id);
+        self.lost.context_destroyed(ctx_id);
+        self.deferred.context_destroyed(ctx_id);
+        self.trace.context_destroyed(ctx_id);
+        self.events.context_destroyed(ctx_id);
+        if let Some(renderdoc) = &mut self.renderdoc {
+            renderdoc.context_destroyed(ctx_id);
+        }
+        self.reap_resources();
+        self.reap_components();
         Ok(())
     }
 
-    /// Attaches the resource given by `resource_id` to the context given by `ctx_id`.
+    /// Attaches the resource given by `resource_id` to the context given by `ctx_id`.  Fails if
+    /// the resource was created by another component, and the context's component fails to
+    /// import it.
     pub fn context_attach_resource(&mut self, ctx_id: u32, resource_id: u32) -> RutabagaResult<()> {
         let ctx = self
             .contexts
-            .get_mut(&ctx_id)
+            .get(ctx_id)
             .ok_or(RutabagaError::InvalidContextId)?;
+        let mut ctx = ctx.lock().unwrap();
 
-        let resource = self
+        let mut resource = self
             .resources
             .get_mut(&resource_id)
             .ok_or(RutabagaError::InvalidResourceId)?;
 
-        ctx.attach(resource);
+        // Resources created by another component are shared into this context's component via
+        // their dma-buf before the context may use them.
+        let component_type = ctx.component_type();
+        let component_bit = 1 << (component_type as u8);
+        let is_dmabuf = resource
+            .handle
+            .as_ref()
+            .and_then(|h| h.as_mesa_handle())
+            .is_some_and(|h| h.handle_type == MESA_HANDLE_TYPE_MEM_DMABUF);
+        if resource.component_mask & component_bit == 0 && is_dmabuf {
+            if let Some(component) = self.components.get(&component_type) {
+                match component.import_shared(&resource) {
+                    Ok(()) => resource.component_mask |= component_bit,
+                    // The context uses the resource as is if its component can't import it.
+                    Err(RutabagaError::MesaError(MesaError::Unsupported)) => (),
+                    // Otherwise, the component doesn't know the resource the context would use.
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        ctx.attach(&mut resource);
+        self.deferred.attach(ctx_id, resource_id);
         Ok(())
     }
 
@@ -1222,18 +3329,69 @@ impl Rutabaga {
     pub fn context_detach_resource(&mut self, ctx_id: u32, resource_id: u32) -> RutabagaResult<()> {
         let ctx = self
             .contexts
-            .get_mut(&ctx_id)
+            .get(ctx_id)
             .ok_or(RutabagaError::InvalidContextId)?;
+        let mut ctx = ctx.lock().unwrap();
 
         let resource = self
             .resources
-            .get_mut(&resource_id)
+            .get(&resource_id)
             .ok_or(RutabagaError::InvalidResourceId)?;
 
-        ctx.detach(resource);
+        ctx.detach(&resource);
+        self.deferred.detach(ctx_id, resource_id);
         Ok(())
     }
 
+    /// Makes the guest memory blob given by `resource_id` the event ring of the context given by
+    /// `ctx_id`.  Fences the guest creates on `ring_idx` of the context then wait for events
+    /// queued by components or with `send_context_event()`, rather than for the context's work.
+    pub fn context_attach_event_ring(
+        &mut self,
+        ctx_id: u32,
+        resource_id: u32,
+        ring_idx: u8,
+    ) -> RutabagaResult<()> {
+        if !self.contexts.contains(ctx_id) {
+            return Err(RutabagaError::InvalidContextId);
+        }
+
+        let backing = self
+            .resources
+            .get(&resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?
+            .backing_iovecs
+            .clone()
+            .ok_or(RutabagaError::InvalidIovec)?;
+
+        self.events.attach(ctx_id, ring_idx, backing)
+    }
+
+    /// Detaches the event ring of the context given by `ctx_id`.  A fence waiting for events
+    /// signals right away, possibly with no events.
+    pub fn context_detach_event_ring(&mut self, ctx_id: u32) -> RutabagaResult<()> {
+        self.events.detach(ctx_id)
+    }
+
+    /// Queues an event of `event_type` for the event ring of the context given by `ctx_id`, for
+    /// events originating in the VMM.
+    pub fn send_context_event(
+        &self,
+        ctx_id: u32,
+        event_type: u32,
+        payload: &[u8],
+    ) -> RutabagaResult<()> {
+        self.events.send(ctx_id, event_type, payload)
+    }
+
+    /// Moves Rutabaga behind `RutabagaShared`, so it can be used from several threads.
+    pub fn into_shared(self) -> RutabagaShared {
+        let contexts = self.contexts.clone();
+        let resources = self.resources.clone();
+        let submitter = self.submitter.clone();
+        RutabagaShared::new(self, contexts, resources, submitter)
+    }
+
     /// submits `commands` to the context given by `ctx_id`.
     pub fn submit_command(
         &mut self,
@@ -1243,7 +3401,7 @@ impl Rutabaga {
     ) -> RutabagaResult<()> {
         let ctx = self
             .contexts
-            .get_mut(&ctx_id)
+            .get(ctx_id)
             .ok_or(RutabagaError::InvalidContextId)?;
 
         #[allow(unused_mut)]
@@ -1260,7 +3418,82 @@ impl Rutabaga {
             shareable_fences.insert(i, clone);
         }
 
-        ctx.submit_cmd(commands, fence_ids, shareable_fences)
+        self.submitter
+            .submit(&ctx, ctx_id, commands, fence_ids, shareable_fences)
+    }
+
+    /// Returns the live contexts lost to host GPU resets, in ascending order.  Their submissions
+    /// fail with `RutabagaError::ContextLost`, and their fences complete with
+    /// `RUTABAGA_FLAG_CONTEXT_LOST` set.  Lost contexts should be destroyed by the guest.
+    pub fn lost_contexts(&self) -> Vec<u32> {
+        self.lost.lost_contexts()
+    }
+
+    /// Starts or stops emitting tracing spans for guest GPU work.  Has no effect unless rutabaga
+    /// was built with the `tracing` feature.
+    pub fn set_tracing(&self, enabled: bool) {
+        self.trace.set_enabled(enabled);
+    }
+
+    /// Captures the next frame of the context given by `ctx_id` with RenderDoc.  The capture
+    /// covers all host GPU work during the frame, including that of other contexts.  Fails with
+    /// `RutabagaUnsupported` unless RenderDoc was enabled with `RutabagaBuilder::set_renderdoc()`
+    /// and is loaded.
+    pub fn trigger_capture(&mut self, ctx_id: u32) -> RutabagaResult<()> {
+        if !self.contexts.contains(ctx_id) {
+            return Err(RutabagaError::InvalidContextId);
+        }
+
+        self.renderdoc
+            .as_mut()
+            .ok_or(MesaError::Unsupported)?
+            .trigger_capture(ctx_id)
+    }
+
+    /// Returns per-context usage statistics: submissions, transfers and fence latencies, along with
+    /// every live resource and its label.
+    pub fn debug_info(&self) -> RutabagaDebugInfo {
+        let mut debug_info = self.stats.debug_info();
+        debug_info.resources = self
+            .resources
+            .map(|resource_id, resource| RutabagaResourceStats {
+                resource_id,
+                name: self.resource_names.get(resource_id),
+                size: resource.size,
+                blob: resource.blob,
+                resident_pages: self.resident_pages(resource_id),
+            });
+        debug_info
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn resident_pages(&self, resource_id: u32) -> Option<u64> {
+        self.hostmem.as_ref()?.resident_pages(resource_id)
+    }
+
+    #[cfg(not(any(target_os = "android", target_os = "linux")))]
+    fn resident_pages(&self, _resource_id: u32) -> Option<u64> {
+        None
+    }
+
+    /// Calls `handler` with every signaled fence matching `filter`, after the fence handler given
+    /// to the builder, and returns an id for `unsubscribe_fences`.  Like the fence handler, it may
+    /// be called from any thread.
+    pub fn subscribe_fences(
+        &self,
+        filter: RutabagaFenceFilter,
+        handler: RutabagaFenceHandler,
+    ) -> u64 {
+        self.fence_subscribers.subscribe(filter, handler)
+    }
+
+    /// Stops calling the handler subscribed as `id`.
+    pub fn unsubscribe_fences(&self, id: u64) -> RutabagaResult<()> {
+        if !self.fence_subscribers.unsubscribe(id) {
+            return Err(MesaError::WithContext("unknown fence subscriber").into());
+        }
+
+        Ok(())
     }
 
     /// destroy fences that are still outstanding
@@ -1289,6 +3522,26 @@ pub struct RutabagaBuilder {
     debug_handler: Option<RutabagaDebugHandler>,
     renderer_features: Option<String>,
     server_descriptor: Option<OwnedDescriptor>,
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    render_server: Option<RutabagaRenderServerConfig>,
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    sandbox_policy: Option<RutabagaSandboxPolicy>,
+    stats_log_interval: Option<Duration>,
+    tracing: bool,
+    renderdoc: Option<RutabagaCaptureBoundary>,
+    device_lost_handler: Option<RutabagaDeviceLostHandler>,
+    capset_handler: Option<RutabagaCapsetHandler>,
+    log_sink: Option<RutabagaLogSink>,
+    thread_config: RutabagaThreadConfig,
+    wayland_trace: bool,
+    gralloc_policy: RutabagaGrallocPolicy,
+    cross_domain_strict: bool,
+    cross_domain_descriptor_limit: Option<usize>,
+    software_fallback: bool,
+    async_transfers: bool,
+    limits: RutabagaLimits,
+    venus_filter: RutabagaVenusFilter,
+    memory_placements: Map<RutabagaComponentType, RutabagaMemoryPlacement>,
 }
 
 impl RutabagaBuilder {
@@ -1310,6 +3563,26 @@ impl RutabagaBuilder {
             debug_handler: None,
             renderer_features: None,
             server_descriptor: None,
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            render_server: None,
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            sandbox_policy: None,
+            stats_log_interval: None,
+            tracing: false,
+            renderdoc: None,
+            device_lost_handler: None,
+            capset_handler: None,
+            log_sink: None,
+            thread_config: Default::default(),
+            wayland_trace: false,
+            gralloc_policy: Default::default(),
+            cross_domain_strict: false,
+            cross_domain_descriptor_limit: None,
+            software_fallback: false,
+            async_transfers: false,
+            limits: Default::default(),
+            venus_filter: Default::default(),
+            memory_placements: Default::default(),
         }
     }
 
@@ -1378,6 +3651,46 @@ impl RutabagaBuilder {
         self
     }
 
+    /// Sets use GLX in virglrenderer.
+    pub fn set_use_glx(mut self, v: bool) -> RutabagaBuilder {
+        self.virglrenderer_flags = self.virglrenderer_flags.use_glx(v);
+        self
+    }
+
+    /// Sets whether virglrenderer runs virgl (OpenGL) contexts.  A non-zero capset mask decides
+    /// this, along with venus and drm, instead.
+    pub fn set_use_virgl(mut self, v: bool) -> RutabagaBuilder {
+        self.virglrenderer_flags = self.virglrenderer_flags.use_virgl(v);
+        self
+    }
+
+    /// Sets whether virglrenderer runs drm native contexts.  A non-zero capset mask decides this
+    /// instead.
+    pub fn set_use_drm(mut self, v: bool) -> RutabagaBuilder {
+        self.virglrenderer_flags = self.virglrenderer_flags.use_drm(v);
+        self
+    }
+
+    /// Sets use thread sync in virglrenderer.  Enabled by default.
+    pub fn set_use_thread_sync(mut self, v: bool) -> RutabagaBuilder {
+        self.virglrenderer_flags = self.virglrenderer_flags.use_thread_sync(v);
+        self
+    }
+
+    /// Sets use async fence callbacks in virglrenderer, which retire fences from its sync thread.
+    /// Enabled by default, and requires thread sync.
+    pub fn set_use_async_fence_cb(mut self, v: bool) -> RutabagaBuilder {
+        self.virglrenderer_flags = self.virglrenderer_flags.use_async_fence_cb(v);
+        self
+    }
+
+    /// Replaces every virglrenderer flag, including those set by the other setters.  Conflicting
+    /// flags fail `build()`.
+    pub fn set_virglrenderer_flags(mut self, flags: VirglRendererFlags) -> RutabagaBuilder {
+        self.virglrenderer_flags = flags;
+        self
+    }
+
     /// Use the Vulkan swapchain to draw on the host window for gfxstream.
     pub fn set_wsi(mut self, v: RutabagaWsi) -> RutabagaBuilder {
         self.gfxstream_flags = self.gfxstream_flags.set_wsi(v);
@@ -1414,17 +3727,252 @@ impl RutabagaBuilder {
         self
     }
 
+    /// Spawns virglrenderer's render server from `config`, instead of connecting to the one given
+    /// by `set_server_descriptor()`.  Every virglrenderer context is reported lost if the server
+    /// exits.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub fn set_render_server(mut self, config: RutabagaRenderServerConfig) -> RutabagaBuilder {
+        self.render_server = Some(config);
+        self.virglrenderer_flags = self.virglrenderer_flags.use_render_server(true);
+        self
+    }
+
+    /// Confines the process, cross-domain workers and the spawned render server according to
+    /// `policy`.  Paths given by `set_rutabaga_paths()` remain accessible.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub fn set_sandbox_policy(mut self, policy: RutabagaSandboxPolicy) -> RutabagaBuilder {
+        self.sandbox_policy = Some(policy);
+        self
+    }
+
+    /// Periodically logs per-context usage statistics, at most once per `interval`.  The
+    /// statistics are always available via `Rutabaga::debug_info()`.
+    pub fn set_stats_log_interval(mut self, interval: Option<Duration>) -> RutabagaBuilder {
+        self.stats_log_interval = interval;
+        self
+    }
+
+    /// Emits `tracing` spans for context submissions, blob creation and transfers, and events for
+    /// signaled fences, all tagged with guest context, resource and fence ids.  Can be toggled
+    /// later with `Rutabaga::set_tracing()`.  Requires the `tracing` feature.
+    pub fn set_tracing(mut self, v: bool) -> RutabagaBuilder {
+        self.tracing = v;
+        self
+    }
+
+    /// Enables RenderDoc captures of guest frames with `Rutabaga::trigger_capture()`, bracketed
+    /// by `boundary`.  RenderDoc must already be loaded into the process.
+    pub fn set_renderdoc(mut self, boundary: Option<RutabagaCaptureBoundary>) -> RutabagaBuilder {
+        self.renderdoc = boundary;
+        self
+    }
+
+    /// Calls `handler` once for every context lost to a host GPU reset, so the VMM can notify the
+    /// guest.  Lost contexts can also be queried with `Rutabaga::lost_contexts()`.
+    pub fn set_device_lost_handler(
+        mut self,
+        handler: RutabagaDeviceLostHandler,
+    ) -> RutabagaBuilder {
+        self.device_lost_handler = Some(handler);
+        self
+    }
+
+    /// Calls `handler` with the new number of capsets whenever `Rutabaga::add_component()` or
+    /// `Rutabaga::remove_component()` changes them, so the VMM can re-advertise capsets to the
+    /// guest.
+    pub fn set_capset_handler(mut self, handler: RutabagaCapsetHandler) -> RutabagaBuilder {
+        self.capset_handler = Some(handler);
+        self
+    }
+
+    /// Sends the log messages of virglrenderer, cross-domain workers and magma to `sink` rather
+    /// than the `log` crate.
+    pub fn set_log_sink(mut self, sink: Option<RutabagaLogSink>) -> RutabagaBuilder {
+        self.log_sink = sink;
+        self
+    }
+
+    /// Sets the scheduling policy, priority and CPU affinity of internal threads of type
+    /// `thread_type`.  If the platform or the process's permissions don't allow it, a warning is
+    /// logged and the threads run with default scheduling.
+    pub fn set_thread_scheduling(
+        mut self,
+        thread_type: RutabagaThreadType,
+        scheduling: MesaThreadScheduling,
+    ) -> RutabagaBuilder {
+        self.thread_config.set(thread_type, scheduling);
+        self
+    }
+
+    /// Prefixes the names of internal threads with `prefix`, so the VMM can tell them apart from
+    /// its own.  Linux truncates thread names to 15 bytes, so short prefixes are best.
+    pub fn set_thread_name_prefix(mut self, prefix: &str) -> RutabagaBuilder {
+        self.thread_config.set_name_prefix(prefix.to_string());
+        self
+    }
+
+    /// Logs the object id, opcode and file descriptor count of Wayland messages passing over
+    /// cross-domain context channels.  Intended for debugging only.
+    pub fn set_wayland_trace(mut self, v: bool) -> RutabagaBuilder {
+        self.wayland_trace = v;
+        self
+    }
+
+    /// Sets per-format and per-usage preferences for the allocation backend of cross-domain
+    /// images, such as minigbm for NV12 and Vulkan for RGBA8 render targets.
+    pub fn set_gralloc_policy(mut self, policy: RutabagaGrallocPolicy) -> RutabagaBuilder {
+        self.gralloc_policy = policy;
+        self
+    }
+
+    /// Validates cross-domain submissions before running them, rejecting commands missing from the
+    /// capset's `supported_commands` as well as malformed ones.  The first rejected command is
+    /// reported on the guest's query ring and the whole submission is dropped.
+    pub fn set_cross_domain_strict(mut self, v: bool) -> RutabagaBuilder {
+        self.cross_domain_strict = v;
+        self
+    }
+
+    /// Caps the host descriptors (pipes and shared memory) held for cross-domain guests.  Channel
+    /// messages that would exceed the limit are dropped and reported on the guest's query ring,
+    /// instead of the host running out of descriptors.
+    pub fn set_cross_domain_descriptor_limit(mut self, max: usize) -> RutabagaBuilder {
+        self.cross_domain_descriptor_limit = Some(max);
+        self
+    }
+
+    /// Limits the number of live contexts of the capset given by `capset_id` to `max`.  Further
+    /// context creation fails with `RutabagaError::ContextLimitExceeded`.
+    pub fn set_context_limit(mut self, capset_id: u32, max: u32) -> RutabagaBuilder {
+        self.limits.contexts_per_capset.insert(capset_id, max);
+        self
+    }
+
+    /// Limits the number of live resources to `max`.  Further resource creation fails with
+    /// `RutabagaError::ResourceLimitExceeded`.
+    pub fn set_resource_limit(mut self, max: u32) -> RutabagaBuilder {
+        self.limits.resources = Some(max);
+        self
+    }
+
+    /// Limits the total size of the live blobs created by each context to `bytes`.  Blob creation
+    /// or resizing beyond it fails with `RutabagaError::BlobQuotaExceeded`.
+    pub fn set_blob_bytes_limit(mut self, bytes: u64) -> RutabagaBuilder {
+        self.limits.blob_bytes_per_context = Some(bytes);
+        self
+    }
+
+    /// Limits the total size of the live blobs of every context to `bytes`.  Blob creation or
+    /// resizing beyond it fails with `RutabagaError::BlobLimitExceeded`.
+    pub fn set_total_blob_bytes_limit(mut self, bytes: u64) -> RutabagaBuilder {
+        self.limits.blob_bytes = Some(bytes);
+        self
+    }
+
+    /// Makes `component_type` place mappable blobs by `placement`, for instance in system memory
+    /// so compute workloads don't exhaust VRAM.  Only magma, which allocates host blobs itself,
+    /// honors it.  virglrenderer allocates in the host driver, and ignores it.
+    pub fn set_memory_placement(
+        mut self,
+        component_type: RutabagaComponentType,
+        placement: RutabagaMemoryPlacement,
+    ) -> RutabagaBuilder {
+        self.memory_placements.insert(component_type, placement);
+        self
+    }
+
+    /// Only advertises the Vulkan extensions in `extensions` to Venus guests, by extension number
+    /// in the Vulkan registry.  Extensions the host doesn't support stay hidden.
+    pub fn set_venus_allowed_extensions(mut self, extensions: &[u32]) -> RutabagaBuilder {
+        self.venus_filter.allowed_extensions = Some(extensions.iter().copied().collect());
+        self
+    }
+
+    /// Hides the Vulkan extensions in `extensions`, such as 348 for VK_KHR_ray_tracing_pipeline,
+    /// from Venus guests.  Takes precedence over `set_venus_allowed_extensions()`.
+    pub fn set_venus_denied_extensions(mut self, extensions: &[u32]) -> RutabagaBuilder {
+        self.venus_filter.denied_extensions = extensions.iter().copied().collect();
+        self
+    }
+
+    /// Hides the `RUTABAGA_VENUS_FEATURE_*` bits in `features` from Venus guests, as if the
+    /// renderer didn't support them.
+    pub fn set_venus_denied_features(mut self, features: u32) -> RutabagaBuilder {
+        self.venus_filter.denied_features = features;
+        self
+    }
+
+    /// Runs Venus contexts on the lavapipe CPU Vulkan driver when the host has no GPU render node,
+    /// so 3D is available on headless and sandboxed hosts.  The driver is selected in the render
+    /// server's environment, so the server must be launched by `set_render_server()`.  A server
+    /// the VMM launches itself must have VK_DRIVER_FILES set to the driver's ICD manifest.
+    pub fn set_software_fallback(mut self, v: bool) -> RutabagaBuilder {
+        self.software_fallback = v;
+        self
+    }
+
+    /// Lets `Rutabaga::transfer_write_async()` and `Rutabaga::transfer_read_async()` run transfers
+    /// on a worker thread, instead of the thread calling them.  Components that can't transfer off their own thread still transfer
+    /// synchronously.
+    pub fn set_async_transfers(mut self, v: bool) -> RutabagaBuilder {
+        self.async_transfers = v;
+        self
+    }
+
+    /// Runs internal worker threads on `executor`, which also decides the order in which fences
+    /// are delivered.  Intended for reproducing races in tests only.
+    #[cfg(feature = "deterministic")]
+    pub fn set_deterministic_executor(
+        mut self,
+        executor: RutabagaDeterministicExecutor,
+    ) -> RutabagaBuilder {
+        self.thread_config.set_executor(executor);
+        self
+    }
+
     /// Builds Rutabaga and returns a handle to it.
     ///
     /// This should be only called once per every virtual machine instance.  Rutabaga tries to
     /// initialize all 3D components which have been built. In 2D mode, only the 2D component is
     /// initialized.
     pub fn build(mut self) -> RutabagaResult<Rutabaga> {
+        let stats = RutabagaStats::new(self.stats_log_interval);
+        let deferred: RutabagaDeferredDestruction = Default::default();
+        let trace = RutabagaTrace::new(self.tracing);
+        let resource_names = RutabagaResourceNames::default();
+        let lost = RutabagaLostContexts::new(self.device_lost_handler.take());
+        let submitter =
+            RutabagaSubmitter::new(lost.clone(), stats.clone(), trace.clone(), deferred.clone());
+        let fence_subscribers: RutabagaFenceSubscribers = Default::default();
+        let dispatcher = RutabagaFenceDispatcher {
+            trace: trace.clone(),
+            deferred: deferred.clone(),
+            stats: stats.clone(),
+            subscribers: fence_subscribers.clone(),
+            handler: self.fence_handler,
+        };
+        self.fence_handler = dispatcher.into_handler(&lost);
+        #[cfg(feature = "deterministic")]
+        if let Some(executor) = self.thread_config.executor() {
+            self.fence_handler = executor.wrap_fence_handler(self.fence_handler);
+        }
+
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        if let Some(policy) = self.sandbox_policy.take() {
+            self.thread_config
+                .set_sandbox(RutabagaSandbox::new(policy, &self.paths));
+        }
+
         let mut rutabaga_components: Map<RutabagaComponentType, Box<dyn RutabagaComponent>> =
             Default::default();
 
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        #[allow(unused_mut)]
+        let mut render_server = None;
+
         #[allow(unused_mut)]
         let mut rutabaga_capsets: Vec<RutabagaCapsetInfo> = Default::default();
+        let mut gpu_devices: Vec<RutabagaGpuDevice> = Vec::new();
 
         let capset_enabled =
             |capset_id: u32| -> bool { (self.capset_mask & (1 << capset_id)) != 0 };
@@ -1474,6 +4022,16 @@ impl RutabagaBuilder {
                 .use_vulkan(capset_enabled(RUTABAGA_CAPSET_GFXSTREAM_VULKAN))
         }
 
+        // Contexts of virglrenderer capsets run on it, even if another component is the default.
+        let virglrenderer_contexts = self.default_component == RutabagaComponentType::VirglRenderer
+            || RUTABAGA_CAPSETS.iter().any(|capset| {
+                capset.component == RutabagaComponentType::VirglRenderer
+                    && capset_enabled(capset.capset_id)
+            });
+        if virglrenderer_contexts {
+            self.virglrenderer_flags.validate()?;
+        }
+
         // Make sure that disabled components are not used as default.
         #[cfg(not(feature = "virgl_renderer"))]
         if self.default_component == RutabagaComponentType::VirglRenderer {
@@ -1487,18 +4045,57 @@ impl RutabagaBuilder {
         if self.default_component != RutabagaComponentType::Rutabaga2D {
             #[cfg(feature = "virgl_renderer")]
             if self.default_component == RutabagaComponentType::VirglRenderer {
-                if let Ok(virgl) = VirglRenderer::init(
-                    self.virglrenderer_flags,
-                    self.fence_handler.clone(),
-                    self.server_descriptor,
-                    self.paths.clone(),
-                ) {
+                let software = self.software_fallback && !host_has_render_node();
+                let software_envs = match software {
+                    true => software_render_server_envs(&self.paths),
+                    false => None,
+                };
+
+                #[cfg(any(target_os = "android", target_os = "linux"))]
+                if let Some(config) = &self.render_server {
+                    let mut config = config.clone();
+                    config.envs.extend(software_envs.iter().flatten().cloned());
+                    let lost = lost.clone();
+                    let (server, server_descriptor) =
+                        RutabagaRenderServer::spawn(&config, &self.thread_config, move || {
+                            lost.report(
+                                RutabagaComponentType::VirglRenderer,
+                                RutabagaDeviceLost { ctx_id: None },
+                            )
+                        })?;
+                    self.server_descriptor = Some(server_descriptor);
+                    render_server = Some(server);
+                }
+
+                let init = if software {
+                    VirglRenderer::init_software
+                } else {
+                    VirglRenderer::init
+                };
+
+                let virgl = match software_envs {
+                    None if software => {
+                        Err(MesaError::WithContext("no software vulkan driver found").into())
+                    }
+                    _ => init(
+                        self.virglrenderer_flags,
+                        self.fence_handler.clone(),
+                        self.server_descriptor,
+                        self.paths.clone(),
+                    ),
+                };
+
+                if let Ok(virgl) = virgl {
                     rutabaga_components.insert(RutabagaComponentType::VirglRenderer, virgl);
 
-                    push_capset(RUTABAGA_CAPSET_VIRGL);
-                    push_capset(RUTABAGA_CAPSET_VIRGL2);
+                    if !software {
+                        push_capset(RUTABAGA_CAPSET_VIRGL);
+                        push_capset(RUTABAGA_CAPSET_VIRGL2);
+                    }
                     push_capset(RUTABAGA_CAPSET_VENUS);
-                    push_capset(RUTABAGA_CAPSET_DRM);
+                    if !software {
+                        push_capset(RUTABAGA_CAPSET_DRM);
+                    }
                 } else {
                     log::warn!("error initializing gpu backend=virglrenderer, falling back to 2d.");
                     self.default_component = RutabagaComponentType::Rutabaga2D;
@@ -1511,7 +4108,7 @@ impl RutabagaBuilder {
                     self.display_width,
                     self.display_height,
                     self.gfxstream_flags,
-                    self.renderer_features,
+                    self.renderer_features.clone(),
                     self.fence_handler.clone(),
                     self.debug_handler.clone(),
                 )?;
@@ -1524,11 +4121,22 @@ impl RutabagaBuilder {
             }
 
             if capset_enabled(RUTABAGA_CAPSET_MAGMA) {
-                let magma = MagmaVirtioGpu::init(self.fence_handler.clone())?;
+                set_magma_log_sink(self.log_sink.clone());
+                gpu_devices = enumerate_gpu_devices();
+                let magma = MagmaVirtioGpu::init(self.fence_handler.clone(), gpu_devices.clone())?;
                 rutabaga_components.insert(RutabagaComponentType::Magma, magma);
+                push_capset(RUTABAGA_CAPSET_MAGMA);
             }
 
-            let cross_domain = CrossDomain::init(self.paths.clone(), self.fence_handler.clone())?;
+            let cross_domain = CrossDomain::init(
+                self.paths.clone(),
+                self.fence_handler.clone(),
+                self.thread_config.clone(),
+                self.wayland_trace,
+                self.gralloc_policy.clone(),
+                self.cross_domain_strict,
+                self.cross_domain_descriptor_limit,
+            )?;
             rutabaga_components.insert(RutabagaComponentType::CrossDomain, cross_domain);
             push_capset(RUTABAGA_CAPSET_CROSS_DOMAIN);
         }
@@ -1538,23 +4146,110 @@ impl RutabagaBuilder {
             rutabaga_components.insert(RutabagaComponentType::Rutabaga2D, rutabaga_2d);
         }
 
+        let events = RutabagaEventSink::new(self.fence_handler.clone());
+        for (component_type, component) in rutabaga_components.iter_mut() {
+            component.set_event_sink(events.clone());
+            component.set_device_lost_handler(lost.component_handler(*component_type));
+            if let Some(sink) = &self.log_sink {
+                component.set_log_sink(sink.clone());
+            }
+            if let Some(placement) = self.memory_placements.get(component_type) {
+                component.set_memory_placement(*placement);
+            }
+            component.set_trace(trace.clone());
+            component.set_resource_names(resource_names.clone());
+        }
+
+        #[allow(unused_mut)]
+        let mut async_transfers = self.async_transfers;
+        // Stepped workers can't block, so transfers run synchronously under the executor.
+        #[cfg(feature = "deterministic")]
+        if self.thread_config.executor().is_some() {
+            async_transfers = false;
+        }
+
+        let transfer_queue = if async_transfers {
+            Some(RutabagaTransferQueue::new(
+                self.fence_handler.clone(),
+                self.thread_config.clone(),
+            )?)
+        } else {
+            None
+        };
+
+        // Only once every component is set up, since their initialization needs more syscalls
+        // than running them.
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        if let Some(sandbox) = self.thread_config.sandbox() {
+            sandbox.confine_process()?;
+        }
+
         Ok(Rutabaga {
+            transfer_queue,
             resources: Default::default(),
+            resource_uuids: Default::default(),
+            resource_names,
+            resource_owners: Default::default(),
+            resource_damage: Default::default(),
+            resource_mappings: Default::default(),
+            staged_mappings: Default::default(),
+            shadow_mappings: Default::default(),
+            guest_dmabufs: Default::default(),
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            udmabuf: None,
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            hostmem: None,
+            blob_charges: Default::default(),
+            blob_usage: Default::default(),
             #[cfg(fence_passing_option1)]
             shareable_fences: Default::default(),
             contexts: Default::default(),
+            context_capsets: Default::default(),
+            context_names: Default::default(),
+            limits: self.limits,
+            venus_filter: self.venus_filter,
+            memory_placements: self.memory_placements,
             components: rutabaga_components,
             default_component: self.default_component,
             capset_info: rutabaga_capsets,
+            gpu_devices,
+            capset_handler: self.capset_handler,
+            log_sink: self.log_sink,
+            removed_components: Default::default(),
+            #[cfg(feature = "gfxstream")]
+            gfxstream_config: GfxstreamConfig {
+                display_width: self.display_width,
+                display_height: self.display_height,
+                flags: self.gfxstream_flags,
+                features: self.renderer_features,
+                debug_handler: self.debug_handler,
+            },
             fence_handler: self.fence_handler,
+            stats,
+            trace,
+            renderdoc: self.renderdoc.map(RutabagaRenderDoc::new),
+            lost,
+            submitter,
+            fence_subscribers,
+            deferred,
+            events,
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            _render_server: render_server,
         })
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::RutabagaComponent;
+    use super::RutabagaContext;
+    use super::RutabagaResource;
     use crate::*;
+    use mesa3d_util::MesaMapping;
+    use std::ffi::c_void;
     use std::fs;
+    use std::io::IoSliceMut;
+    use zerocopy::FromBytes;
 
     fn new_2d() -> Rutabaga {
         RutabagaBuilder::new(0, RutabagaHandler::new(|_| {}))
@@ -1563,6 +4258,162 @@ mod tests {
             .unwrap()
     }
 
+    #[test]
+    fn fence_subscribers() {
+        let mut rutabaga = new_2d();
+        let subscribe = |rutabaga: &Rutabaga, filter| {
+            let fences = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+            let handler_fences = fences.clone();
+            let id = rutabaga.subscribe_fences(
+                filter,
+                RutabagaHandler::new(move |fence: RutabagaFence| {
+                    handler_fences.lock().unwrap().push(fence.fence_id)
+                }),
+            );
+            (id, fences)
+        };
+
+        let (all_id, all) = subscribe(&rutabaga, Default::default());
+        let (_, ctx_0) = subscribe(
+            &rutabaga,
+            RutabagaFenceFilter {
+                ctx_id: Some(0),
+                ring_idx: None,
+            },
+        );
+        let (_, ring_0) = subscribe(
+            &rutabaga,
+            RutabagaFenceFilter {
+                ctx_id: None,
+                ring_idx: Some(0),
+            },
+        );
+
+        let fence = |fence_id| RutabagaFence {
+            flags: RUTABAGA_FLAG_FENCE,
+            fence_id,
+            ctx_id: 0,
+            ring_idx: 0,
+        };
+        rutabaga.create_fence(fence(1)).unwrap();
+        rutabaga.unsubscribe_fences(all_id).unwrap();
+        rutabaga.create_fence(fence(2)).unwrap();
+
+        assert_eq!(*all.lock().unwrap(), vec![1]);
+        assert_eq!(*ctx_0.lock().unwrap(), vec![1, 2]);
+        // Global fences have no ring.
+        assert!(ring_0.lock().unwrap().is_empty());
+        assert!(rutabaga.unsubscribe_fences(all_id).is_err());
+    }
+
+    #[test]
+    fn add_remove_component() {
+        let capset_counts = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let handler_counts = capset_counts.clone();
+        let mut rutabaga = RutabagaBuilder::new(0, RutabagaHandler::new(|_| {}))
+            .set_default_component(RutabagaComponentType::Rutabaga2D)
+            .set_capset_handler(RutabagaHandler::new(move |num_capsets| {
+                handler_counts.lock().unwrap().push(num_capsets)
+            }))
+            .build()
+            .unwrap();
+        assert_eq!(rutabaga.get_num_capsets(), 0);
+
+        rutabaga
+            .add_component(RutabagaComponentType::Magma)
+            .unwrap();
+        assert!(rutabaga
+            .add_component(RutabagaComponentType::Magma)
+            .is_err());
+        assert_eq!(rutabaga.get_num_capsets(), 1);
+        assert_eq!(
+            rutabaga.get_capset_info(0).unwrap().0,
+            RUTABAGA_CAPSET_MAGMA
+        );
+
+        // A context on the component, with an event ring backed by a 2D resource.
+        let ctx_id = 1;
+        rutabaga
+            .create_context(ctx_id, RUTABAGA_CAPSET_MAGMA, None)
+            .unwrap();
+        let resource_id = 1;
+        let mut ring = vec![0u8; 256];
+        rutabaga
+            .resource_create_3d(
+                resource_id,
+                ResourceCreate3D {
+                    target: RUTABAGA_PIPE_TEXTURE_2D,
+                    format: 1,
+                    bind: RUTABAGA_PIPE_BIND_RENDER_TARGET,
+                    width: 64,
+                    height: 1,
+                    depth: 1,
+                    array_size: 1,
+                    last_level: 0,
+                    nr_samples: 0,
+                    flags: 0,
+                },
+            )
+            .unwrap();
+        rutabaga
+            .attach_backing(
+                resource_id,
+                vec![RutabagaIovec {
+                    base: ring.as_mut_ptr() as *mut c_void,
+                    len: ring.len(),
+                }],
+            )
+            .unwrap();
+        rutabaga
+            .context_attach_event_ring(ctx_id, resource_id, 0)
+            .unwrap();
+
+        // The default component stays, and a component is removed once.
+        assert!(rutabaga
+            .remove_component(RutabagaComponentType::Rutabaga2D)
+            .is_err());
+        rutabaga
+            .remove_component(RutabagaComponentType::Magma)
+            .unwrap();
+        assert!(rutabaga
+            .remove_component(RutabagaComponentType::Magma)
+            .is_err());
+        assert_eq!(rutabaga.get_num_capsets(), 0);
+
+        // The guest is told to query the capsets again.
+        rutabaga
+            .create_fence(RutabagaFence {
+                flags: RUTABAGA_FLAG_FENCE | RUTABAGA_FLAG_INFO_RING_IDX,
+                fence_id: 1,
+                ctx_id,
+                ring_idx: 0,
+            })
+            .unwrap();
+        let (header, records) = RutabagaEventRingHeader::read_from_prefix(&ring).unwrap();
+        assert_eq!(header.count, 1);
+        let (event, payload) = RutabagaEventHeader::read_from_prefix(records).unwrap();
+        assert_eq!(event.event_type, RUTABAGA_CONTEXT_EVENT_CAPSETS_CHANGED);
+        let (changed, _) = RutabagaCapsetsChanged::read_from_prefix(payload).unwrap();
+        assert_eq!(changed.num_capsets, 0);
+
+        // The component outlives its last context, and may come back until then.
+        assert!(rutabaga
+            .components
+            .contains_key(&RutabagaComponentType::Magma));
+        rutabaga
+            .add_component(RutabagaComponentType::Magma)
+            .unwrap();
+        rutabaga
+            .remove_component(RutabagaComponentType::Magma)
+            .unwrap();
+        rutabaga.destroy_context(ctx_id).unwrap();
+        assert!(!rutabaga
+            .components
+            .contains_key(&RutabagaComponentType::Magma));
+
+        assert_eq!(*capset_counts.lock().unwrap(), vec![1, 0, 1, 0]);
+    }
+
     #[test]
     fn snapshot_restore_2d_no_resources() {
         let mut snapshot_dir = std::env::temp_dir();
@@ -1579,6 +4430,27 @@ mod tests {
         fs::remove_dir_all(&snapshot_dir).unwrap();
     }
 
+    #[test]
+    fn resource_create_2d_too_large() {
+        // 512 TiB, more than the address space.
+        let resource_create_3d = ResourceCreate3D {
+            target: RUTABAGA_PIPE_TEXTURE_2D,
+            format: 1,
+            bind: RUTABAGA_PIPE_BIND_RENDER_TARGET,
+            width: 1 << 16,
+            height: 1 << 16,
+            depth: 1,
+            array_size: 1 << 15,
+            last_level: 0,
+            nr_samples: 0,
+            flags: 0,
+        };
+
+        let mut rutabaga = new_2d();
+        assert!(rutabaga.resource_create_3d(1, resource_create_3d).is_err());
+        assert_eq!(rutabaga.resources.len(), 0);
+    }
+
     #[test]
     fn snapshot_restore_2d_one_resource() {
         let mut snapshot_dir = std::env::temp_dir();
@@ -1612,11 +4484,14 @@ mod tests {
                 }],
             )
             .unwrap();
+        let uuid = rutabaga1.resource_uuid(resource_id).unwrap();
         rutabaga1.snapshot(snapshot_dir.as_path()).unwrap();
 
         let mut rutabaga2 = new_2d();
         rutabaga2.restore(snapshot_dir.as_path()).unwrap();
 
+        assert_eq!(rutabaga2.resource_uuid(resource_id).unwrap(), uuid);
+        assert_eq!(rutabaga2.resource_id_from_uuid(&uuid).unwrap(), resource_id);
         assert_eq!(rutabaga2.resources.len(), 1);
         let rutabaga_resource = rutabaga2.resources.get(&resource_id).unwrap();
         assert_eq!(rutabaga_resource.resource_id, resource_id);
@@ -1633,4 +4508,1986 @@ mod tests {
 
         fs::remove_dir_all(&snapshot_dir).unwrap();
     }
+
+    #[test]
+    fn take_scanout_2d_damage() {
+        let resource_id = 1;
+        let resource_create_3d = ResourceCreate3D {
+            target: RUTABAGA_PIPE_TEXTURE_2D,
+            format: 1,
+            bind: RUTABAGA_PIPE_BIND_RENDER_TARGET,
+            width: 64,
+            height: 64,
+            depth: 1,
+            array_size: 1,
+            last_level: 0,
+            nr_samples: 0,
+            flags: 0,
+        };
+
+        let mut rutabaga = new_2d();
+        rutabaga
+            .resource_create_3d(resource_id, resource_create_3d)
+            .unwrap();
+        assert!(rutabaga
+            .take_scanout(resource_id)
+            .unwrap()
+            .damage
+            .is_empty());
+
+        let mut backing = vec![0u8; 64 * 64 * 4];
+        rutabaga
+            .attach_backing(
+                resource_id,
+                vec![RutabagaIovec {
+                    base: backing.as_mut_ptr() as *mut c_void,
+                    len: backing.len(),
+                }],
+            )
+            .unwrap();
+
+        let rects = [
+            Transfer3D::new_2d(0, 0, 8, 8, 0),
+            Transfer3D::new_2d(16, 32, 4, 2, 0),
+        ];
+        for transfer in rects {
+            rutabaga
+                .transfer_write(0, resource_id, transfer, None)
+                .unwrap();
+        }
+
+        let scanout = rutabaga.take_scanout(resource_id).unwrap();
+        assert!(scanout.handle.is_none());
+        assert_eq!(
+            scanout.damage,
+            [
+                RutabagaRect {
+                    x: 0,
+                    y: 0,
+                    w: 8,
+                    h: 8
+                },
+                RutabagaRect {
+                    x: 16,
+                    y: 32,
+                    w: 4,
+                    h: 2
+                },
+            ]
+        );
+        assert!(rutabaga
+            .take_scanout(resource_id)
+            .unwrap()
+            .damage
+            .is_empty());
+
+        // Past the limit, damage collapses into its bounding box.
+        for x in 0..33 {
+            rutabaga
+                .transfer_write(0, resource_id, Transfer3D::new_2d(x, x, 1, 1, 0), None)
+                .unwrap();
+        }
+        let scanout = rutabaga.take_scanout(resource_id).unwrap();
+        assert_eq!(
+            scanout.damage,
+            [RutabagaRect {
+                x: 0,
+                y: 0,
+                w: 33,
+                h: 33
+            }]
+        );
+    }
+
+    #[test]
+    fn take_scanout_3d_damage() {
+        let mut rutabaga = new_2d();
+        let resource = RutabagaResource {
+            resource_id: 1,
+            handle: None,
+            blob: true,
+            blob_mem: RUTABAGA_BLOB_MEM_HOST3D,
+            blob_flags: 0,
+            map_info: None,
+            info_2d: None,
+            info_3d: Some(Resource3DInfo {
+                width: 64,
+                height: 64,
+                drm_fourcc: 0,
+                strides: [256, 0, 0, 0],
+                offsets: [0; 4],
+                modifier: 0,
+            }),
+            vulkan_info: None,
+            backing_iovecs: None,
+            component_mask: 1 << (RutabagaComponentType::VirglRenderer as u8),
+            size: 64 * 256,
+            mapping: None,
+        };
+        rutabaga.insert_resource(1, resource, [0; 16]);
+        assert!(rutabaga.take_scanout(1).unwrap().damage.is_empty());
+
+        let rect = RutabagaRect {
+            x: 8,
+            y: 16,
+            w: 4,
+            h: 2,
+        };
+        rutabaga.resource_damage(1, rect).unwrap();
+        rutabaga
+            .resource_damage(1, RutabagaRect::default())
+            .unwrap();
+        assert!(matches!(
+            rutabaga.resource_damage(2, rect),
+            Err(RutabagaError::InvalidResourceId)
+        ));
+
+        let scanout = rutabaga.take_scanout(1).unwrap();
+        assert_eq!(scanout.damage, [rect]);
+        assert!(rutabaga.take_scanout(1).unwrap().damage.is_empty());
+    }
+
+    #[test]
+    fn resource_limit() {
+        let resource_create_3d = ResourceCreate3D {
+            target: RUTABAGA_PIPE_TEXTURE_2D,
+            format: 1,
+            bind: RUTABAGA_PIPE_BIND_RENDER_TARGET,
+            width: 2,
+            height: 2,
+            depth: 1,
+            array_size: 1,
+            last_level: 0,
+            nr_samples: 0,
+            flags: 0,
+        };
+
+        let mut rutabaga = RutabagaBuilder::new(0, RutabagaHandler::new(|_| {}))
+            .set_default_component(RutabagaComponentType::Rutabaga2D)
+            .set_resource_limit(1)
+            .build()
+            .unwrap();
+
+        rutabaga.resource_create_3d(1, resource_create_3d).unwrap();
+        assert!(matches!(
+            rutabaga.resource_create_3d(2, resource_create_3d),
+            Err(RutabagaError::ResourceLimitExceeded)
+        ));
+
+        rutabaga.unref_resource(1).unwrap();
+        rutabaga.resource_create_3d(2, resource_create_3d).unwrap();
+    }
+
+    #[test]
+    fn blob_limits() {
+        let mut rutabaga = RutabagaBuilder::new(0, RutabagaHandler::new(|_| {}))
+            .set_default_component(RutabagaComponentType::Rutabaga2D)
+            .set_blob_bytes_limit(4096)
+            .set_total_blob_bytes_limit(6144)
+            .build()
+            .unwrap();
+
+        rutabaga.check_blob_quota(1, 4096, 0).unwrap();
+        assert!(matches!(
+            rutabaga.check_blob_quota(1, 4097, 0),
+            Err(RutabagaError::BlobQuotaExceeded(1))
+        ));
+
+        // Each context stays within its quota, but together they don't.
+        rutabaga.charge_blob(1, 1, 4096);
+        rutabaga.check_blob_quota(2, 2048, 0).unwrap();
+        assert!(matches!(
+            rutabaga.check_blob_quota(2, 4096, 0),
+            Err(RutabagaError::BlobLimitExceeded)
+        ));
+
+        // Resizing releases the blob's previous size.
+        rutabaga.charge_blob(2, 2, 2048);
+        rutabaga.check_blob_quota(1, 4096, 4096).unwrap();
+        assert!(matches!(
+            rutabaga.check_blob_quota(2, 4096, 2048),
+            Err(RutabagaError::BlobLimitExceeded)
+        ));
+
+        rutabaga.release_blob(1);
+        rutabaga.check_blob_quota(2, 4096, 2048).unwrap();
+    }
+
+    #[test]
+    fn context_priority() {
+        let priority = |bits: u32| {
+            RutabagaContextPriority::from_context_init(
+                RUTABAGA_CAPSET_CROSS_DOMAIN | (bits << RUTABAGA_CONTEXT_INIT_PRIORITY_SHIFT),
+            )
+        };
+        assert_eq!(priority(0), Some(RutabagaContextPriority::Normal));
+        assert_eq!(priority(1), Some(RutabagaContextPriority::Low));
+        assert_eq!(priority(2), Some(RutabagaContextPriority::High));
+        assert_eq!(priority(3), None);
+
+        let mut rutabaga = RutabagaBuilder::new(
+            1 << RUTABAGA_CAPSET_CROSS_DOMAIN,
+            RutabagaHandler::new(|_| {}),
+        )
+        .set_default_component(RutabagaComponentType::Rutabaga2D)
+        .build()
+        .unwrap();
+        assert!(rutabaga
+            .create_context(
+                1,
+                RUTABAGA_CAPSET_CROSS_DOMAIN | RUTABAGA_CONTEXT_INIT_PRIORITY_MASK,
+                None
+            )
+            .is_err());
+        rutabaga
+            .create_context(
+                1,
+                RUTABAGA_CAPSET_CROSS_DOMAIN | (2 << RUTABAGA_CONTEXT_INIT_PRIORITY_SHIFT),
+                None,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn lost_context() {
+        let signaled = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let handler_signaled = signaled.clone();
+        let lost = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let handler_lost = lost.clone();
+        let mut rutabaga = RutabagaBuilder::new(
+            1 << RUTABAGA_CAPSET_CROSS_DOMAIN,
+            RutabagaHandler::new(move |fence: RutabagaFence| {
+                handler_signaled.lock().unwrap().push(fence)
+            }),
+        )
+        .set_default_component(RutabagaComponentType::Rutabaga2D)
+        .set_device_lost_handler(RutabagaHandler::new(
+            move |device_lost: RutabagaDeviceLost| {
+                handler_lost.lock().unwrap().push(device_lost.ctx_id)
+            },
+        ))
+        .build()
+        .unwrap();
+
+        let fence = |fence_id: u64, ring_idx: u8| RutabagaFence {
+            flags: RUTABAGA_FLAG_FENCE | RUTABAGA_FLAG_INFO_RING_IDX,
+            fence_id,
+            ctx_id: 1,
+            ring_idx,
+        };
+
+        rutabaga
+            .create_context(1, RUTABAGA_CAPSET_CROSS_DOMAIN, None)
+            .unwrap();
+
+        // Channel ring fences stay pending until a channel is initialized.
+        rutabaga.create_fence(fence(1, 1)).unwrap();
+        assert!(signaled.lock().unwrap().is_empty());
+
+        rutabaga
+            .lost
+            .component_handler(RutabagaComponentType::CrossDomain)
+            .call(RutabagaDeviceLost { ctx_id: None });
+        assert_eq!(rutabaga.lost_contexts(), vec![1]);
+        assert_eq!(*lost.lock().unwrap(), vec![Some(1)]);
+
+        rutabaga.create_fence(fence(2, 0)).unwrap();
+        let fences: Vec<(u64, bool)> = signaled
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|f| (f.fence_id, f.flags & RUTABAGA_FLAG_CONTEXT_LOST != 0))
+            .collect();
+        assert_eq!(fences, vec![(1, true), (2, true)]);
+
+        assert!(matches!(
+            rutabaga.submit_command(1, &mut [], &[]),
+            Err(RutabagaError::ContextLost)
+        ));
+
+        rutabaga.destroy_context(1).unwrap();
+        assert!(rutabaga.lost_contexts().is_empty());
+    }
+
+    #[test]
+    fn lost_context_keeps_other_fences() {
+        let signaled = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let handler_signaled = signaled.clone();
+        let mut rutabaga = RutabagaBuilder::new(
+            1 << RUTABAGA_CAPSET_CROSS_DOMAIN,
+            RutabagaHandler::new(move |fence: RutabagaFence| {
+                handler_signaled.lock().unwrap().push(fence)
+            }),
+        )
+        .set_default_component(RutabagaComponentType::Rutabaga2D)
+        .build()
+        .unwrap();
+
+        // Both contexts use fence id 1 on their channel ring.
+        for ctx_id in [1, 2] {
+            rutabaga
+                .create_context(ctx_id, RUTABAGA_CAPSET_CROSS_DOMAIN, None)
+                .unwrap();
+            rutabaga
+                .create_fence(RutabagaFence {
+                    flags: RUTABAGA_FLAG_FENCE | RUTABAGA_FLAG_INFO_RING_IDX,
+                    fence_id: 1,
+                    ctx_id,
+                    ring_idx: 1,
+                })
+                .unwrap();
+        }
+
+        rutabaga
+            .lost
+            .component_handler(RutabagaComponentType::CrossDomain)
+            .call(RutabagaDeviceLost { ctx_id: Some(1) });
+        assert_eq!(rutabaga.lost_contexts(), vec![1]);
+
+        let fences: Vec<(u32, bool)> = signaled
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|f| (f.ctx_id, f.flags & RUTABAGA_FLAG_CONTEXT_LOST != 0))
+            .collect();
+        assert_eq!(fences, vec![(1, true)]);
+    }
+
+    #[test]
+    fn context_event_ring() {
+        let signaled = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let handler_signaled = signaled.clone();
+        let mut rutabaga = RutabagaBuilder::new(
+            1 << RUTABAGA_CAPSET_CROSS_DOMAIN,
+            RutabagaHandler::new(move |fence: RutabagaFence| {
+                handler_signaled.lock().unwrap().push(fence.fence_id)
+            }),
+        )
+        .set_default_component(RutabagaComponentType::Rutabaga2D)
+        .build()
+        .unwrap();
+
+        rutabaga
+            .create_context(1, RUTABAGA_CAPSET_CROSS_DOMAIN, None)
+            .unwrap();
+        let mut ring = vec![0u64; 4];
+        let iovecs = vec![RutabagaIovec {
+            base: ring.as_mut_ptr() as *mut c_void,
+            len: 32,
+        }];
+        let resource_create_blob = ResourceCreateBlob {
+            blob_mem: RUTABAGA_BLOB_MEM_GUEST,
+            blob_flags: 0,
+            blob_id: 0,
+            size: 32,
+        };
+        rutabaga
+            .resource_create_blob(0, 1, resource_create_blob, Some(iovecs), None)
+            .unwrap();
+        rutabaga.context_attach_event_ring(1, 1, 3).unwrap();
+
+        let fence = |fence_id: u64| RutabagaFence {
+            flags: RUTABAGA_FLAG_FENCE | RUTABAGA_FLAG_INFO_RING_IDX,
+            fence_id,
+            ctx_id: 1,
+            ring_idx: 3,
+        };
+
+        // The fence waits for an event, and only one of the two fits the ring.
+        rutabaga.create_fence(fence(1)).unwrap();
+        assert!(signaled.lock().unwrap().is_empty());
+        rutabaga.send_context_event(1, 7, &[1, 2, 3]).unwrap();
+        rutabaga.send_context_event(1, 8, &[4; 16]).unwrap();
+        assert_eq!(*signaled.lock().unwrap(), vec![1]);
+        assert_eq!(ring[0], 1);
+        assert_eq!(ring[1], 7 | (3 << 32));
+        assert_eq!(ring[2], 0x030201);
+
+        // The next fence picks up the queued event.
+        rutabaga.create_fence(fence(2)).unwrap();
+        assert_eq!(*signaled.lock().unwrap(), vec![1, 2]);
+        assert_eq!(ring[0], 1);
+        assert_eq!(ring[1], 8 | (16 << 32));
+
+        rutabaga.create_fence(fence(3)).unwrap();
+        rutabaga.context_detach_event_ring(1).unwrap();
+        assert_eq!(*signaled.lock().unwrap(), vec![1, 2, 3]);
+        assert_eq!(ring[0], 0);
+        assert!(rutabaga.send_context_event(1, 7, &[]).is_err());
+    }
+
+    #[test]
+    fn destroyed_context_signals_event_fence() {
+        let signaled = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let handler_signaled = signaled.clone();
+        let mut rutabaga = RutabagaBuilder::new(
+            1 << RUTABAGA_CAPSET_CROSS_DOMAIN,
+            RutabagaHandler::new(move |fence: RutabagaFence| {
+                handler_signaled.lock().unwrap().push(fence.fence_id)
+            }),
+        )
+        .set_default_component(RutabagaComponentType::Rutabaga2D)
+        .build()
+        .unwrap();
+
+        rutabaga
+            .create_context(1, RUTABAGA_CAPSET_CROSS_DOMAIN, None)
+            .unwrap();
+        let mut ring = vec![u64::MAX; 4];
+        let iovecs = vec![RutabagaIovec {
+            base: ring.as_mut_ptr() as *mut c_void,
+            len: 32,
+        }];
+        let resource_create_blob = ResourceCreateBlob {
+            blob_mem: RUTABAGA_BLOB_MEM_GUEST,
+            blob_flags: 0,
+            blob_id: 0,
+            size: 32,
+        };
+        rutabaga
+            .resource_create_blob(0, 1, resource_create_blob, Some(iovecs), None)
+            .unwrap();
+        rutabaga.context_attach_event_ring(1, 1, 3).unwrap();
+        rutabaga
+            .create_fence(RutabagaFence {
+                flags: RUTABAGA_FLAG_FENCE | RUTABAGA_FLAG_INFO_RING_IDX,
+                fence_id: 1,
+                ctx_id: 1,
+                ring_idx: 3,
+            })
+            .unwrap();
+        assert!(signaled.lock().unwrap().is_empty());
+
+        // Like detaching the ring, the pending fence signals with an empty batch.
+        rutabaga.destroy_context(1).unwrap();
+        assert_eq!(*signaled.lock().unwrap(), vec![1]);
+        assert_eq!(ring[0], 0);
+    }
+
+    #[test]
+    fn map_only_handle_types_of_component() {
+        let mut rutabaga = RutabagaBuilder::new(
+            1 << RUTABAGA_CAPSET_CROSS_DOMAIN,
+            RutabagaHandler::new(|_| {}),
+        )
+        .build()
+        .unwrap();
+
+        // Cross-domain can mmap shared memory, but not opaque fds.
+        for (resource_id, handle_type) in [
+            (1, mesa3d_util::MESA_HANDLE_TYPE_MEM_SHM),
+            (2, mesa3d_util::MESA_HANDLE_TYPE_MEM_OPAQUE_FD),
+        ] {
+            let handle: RutabagaHandle = RutabagaMesaHandle {
+                os_handle: mesa3d_util::SharedMemory::new("map_handle_type", 4096)
+                    .unwrap()
+                    .into(),
+                handle_type,
+            }
+            .into();
+            let resource = RutabagaResource {
+                resource_id,
+                handle: Some(std::sync::Arc::new(handle)),
+                blob: true,
+                blob_mem: RUTABAGA_BLOB_MEM_HOST3D,
+                blob_flags: RUTABAGA_BLOB_FLAG_USE_MAPPABLE,
+                map_info: Some(RUTABAGA_MAP_CACHE_CACHED | RUTABAGA_MAP_ACCESS_RW),
+                info_2d: None,
+                info_3d: None,
+                vulkan_info: None,
+                backing_iovecs: None,
+                component_mask: 1 << (RutabagaComponentType::CrossDomain as u8),
+                size: 4096,
+                mapping: None,
+            };
+            rutabaga.insert_resource(resource_id, resource, [0; 16]);
+        }
+
+        rutabaga.map(1).unwrap();
+        assert!(rutabaga.map(2).is_err());
+        // A rejected map leaves the handle in place.
+        assert!(rutabaga.resources.get(&2).unwrap().handle.is_some());
+        rutabaga.unmap(1).unwrap();
+    }
+
+    #[test]
+    fn map_magma_host_blob() {
+        let mut rutabaga =
+            RutabagaBuilder::new(1 << RUTABAGA_CAPSET_MAGMA, RutabagaHandler::new(|_| {}))
+                .build()
+                .unwrap();
+
+        // Shared memory stands in for the dma-buf magma exports, since both are mapped by mmap.
+        let handle: RutabagaHandle = RutabagaMesaHandle {
+            os_handle: mesa3d_util::SharedMemory::new("magma_blob", 4096)
+                .unwrap()
+                .into(),
+            handle_type: mesa3d_util::MESA_HANDLE_TYPE_MEM_DMABUF,
+        }
+        .into();
+        let resource = RutabagaResource {
+            resource_id: 1,
+            handle: Some(std::sync::Arc::new(handle)),
+            blob: true,
+            blob_mem: RUTABAGA_BLOB_MEM_HOST3D,
+            blob_flags: RUTABAGA_BLOB_FLAG_USE_MAPPABLE,
+            map_info: Some(RUTABAGA_MAP_CACHE_WC | RUTABAGA_MAP_ACCESS_RW),
+            info_2d: None,
+            info_3d: None,
+            vulkan_info: None,
+            backing_iovecs: None,
+            component_mask: 1 << (RutabagaComponentType::Magma as u8),
+            size: 4096,
+            mapping: None,
+        };
+        rutabaga.insert_resource(1, resource, [0; 16]);
+
+        assert!(rutabaga.resource_owner(1).unwrap() == RutabagaComponentType::Magma);
+        let mapping = rutabaga.map(1).unwrap();
+        assert_eq!(mapping.size, 4096);
+        assert_eq!(
+            rutabaga.map_info(1).unwrap(),
+            RUTABAGA_MAP_CACHE_WC | RUTABAGA_MAP_ACCESS_RW
+        );
+        rutabaga.unmap(1).unwrap();
+        assert!(rutabaga.resources.get(&1).unwrap().mapping.is_none());
+    }
+
+    #[test]
+    fn map_refcount() {
+        let mut rutabaga = RutabagaBuilder::new(
+            1 << RUTABAGA_CAPSET_CROSS_DOMAIN,
+            RutabagaHandler::new(|_| {}),
+        )
+        .build()
+        .unwrap();
+
+        let handle: RutabagaHandle = RutabagaMesaHandle {
+            os_handle: mesa3d_util::SharedMemory::new("map_refcount", 4096)
+                .unwrap()
+                .into(),
+            handle_type: mesa3d_util::MESA_HANDLE_TYPE_MEM_SHM,
+        }
+        .into();
+        let resource = RutabagaResource {
+            resource_id: 1,
+            handle: Some(std::sync::Arc::new(handle)),
+            blob: true,
+            blob_mem: RUTABAGA_BLOB_MEM_HOST3D,
+            blob_flags: RUTABAGA_BLOB_FLAG_USE_MAPPABLE,
+            map_info: Some(RUTABAGA_MAP_CACHE_CACHED | RUTABAGA_MAP_ACCESS_RW),
+            info_2d: None,
+            info_3d: None,
+            vulkan_info: None,
+            backing_iovecs: None,
+            component_mask: 1 << (RutabagaComponentType::CrossDomain as u8),
+            size: 4096,
+            mapping: None,
+        };
+        rutabaga.insert_resource(1, resource, [0; 16]);
+
+        // Mapping twice shares one mapping.
+        let first = rutabaga.map(1).unwrap();
+        let second = rutabaga.map(1).unwrap();
+        assert_eq!(first.ptr, second.ptr);
+        assert_eq!(first.size, 4096);
+        let mappings = rutabaga.mappings();
+        assert_eq!(mappings.len(), 1);
+        assert_eq!(mappings[0].resource_id, 1);
+        assert_eq!(mappings[0].ptr, first.ptr);
+        assert_eq!(mappings[0].map_count, 2);
+
+        // The first unmap only drops a reference.
+        rutabaga.unmap(1).unwrap();
+        assert_eq!(rutabaga.mappings()[0].map_count, 1);
+        assert!(rutabaga.resources.get(&1).unwrap().mapping.is_some());
+
+        // The last unmap releases the mapping.
+        rutabaga.unmap(1).unwrap();
+        assert!(rutabaga.mappings().is_empty());
+        assert!(rutabaga.resources.get(&1).unwrap().mapping.is_none());
+        assert!(rutabaga.unmap(1).is_err());
+
+        // The resource can be mapped again afterwards.
+        rutabaga.map(1).unwrap();
+        assert_eq!(rutabaga.mappings()[0].map_count, 1);
+        rutabaga.unmap(1).unwrap();
+    }
+
+    #[test]
+    fn resize_waits_for_fences() {
+        let mut rutabaga = RutabagaBuilder::new(
+            1 << RUTABAGA_CAPSET_CROSS_DOMAIN,
+            RutabagaHandler::new(|_| {}),
+        )
+        .build()
+        .unwrap();
+
+        let shm_handle = |size| -> RutabagaHandle {
+            RutabagaMesaHandle {
+                os_handle: mesa3d_util::SharedMemory::new("resize", size)
+                    .unwrap()
+                    .into(),
+                handle_type: mesa3d_util::MESA_HANDLE_TYPE_MEM_SHM,
+            }
+            .into()
+        };
+        let resource = RutabagaResource {
+            resource_id: 1,
+            handle: Some(std::sync::Arc::new(shm_handle(4096))),
+            blob: true,
+            blob_mem: RUTABAGA_BLOB_MEM_HOST3D,
+            blob_flags: RUTABAGA_BLOB_FLAG_USE_MAPPABLE,
+            map_info: Some(RUTABAGA_MAP_CACHE_CACHED | RUTABAGA_MAP_ACCESS_RW),
+            info_2d: None,
+            info_3d: None,
+            vulkan_info: None,
+            backing_iovecs: None,
+            component_mask: 1 << (RutabagaComponentType::CrossDomain as u8),
+            size: 4096,
+            mapping: None,
+        };
+        rutabaga.insert_resource(1, resource, [0; 16]);
+        rutabaga.deferred.attach(1, 1);
+
+        // A channel fence of a context without the blob waits for the compositor rather than
+        // for work using the blob.
+        let channel_fence = RutabagaFence {
+            flags: RUTABAGA_FLAG_FENCE | RUTABAGA_FLAG_INFO_RING_IDX,
+            fence_id: 1,
+            ctx_id: 2,
+            ring_idx: 1,
+        };
+        rutabaga.deferred.submitted(2);
+        rutabaga
+            .deferred
+            .fence_created(&channel_fence, RutabagaComponentType::CrossDomain);
+        rutabaga
+            .resource_resize_blob(1, 4096, None, Some(shm_handle(4096)))
+            .unwrap();
+
+        // Work fenced before the resize may still use the old backing.
+        let fence = RutabagaFence {
+            flags: RUTABAGA_FLAG_FENCE | RUTABAGA_FLAG_INFO_RING_IDX,
+            fence_id: 1,
+            ctx_id: 1,
+            ring_idx: 0,
+        };
+        rutabaga.deferred.submitted(1);
+        rutabaga
+            .deferred
+            .fence_created(&fence, RutabagaComponentType::CrossDomain);
+        assert!(matches!(
+            rutabaga.resource_resize_blob(1, 8192, None, Some(shm_handle(8192))),
+            Err(RutabagaError::AlreadyInUse)
+        ));
+        assert_eq!(rutabaga.resources.get(&1).unwrap().size, 4096);
+
+        rutabaga.deferred.fence_done(&fence);
+        rutabaga
+            .resource_resize_blob(1, 8192, None, Some(shm_handle(8192)))
+            .unwrap();
+        assert_eq!(rutabaga.resources.get(&1).unwrap().size, 8192);
+    }
+
+    // A component that exports every blob as shared memory.
+    struct ShmComponent;
+
+    impl RutabagaComponent for ShmComponent {
+        fn handle_types(&self) -> RutabagaHandleTypes {
+            RutabagaHandleTypes {
+                export: handle_type_mask(&[mesa3d_util::MESA_HANDLE_TYPE_MEM_SHM]),
+                import: 0,
+            }
+        }
+    }
+
+    #[test]
+    fn probe_blob_handle_types() {
+        let mut rutabaga = new_2d();
+        let blob = |blob_mem, blob_flags| ResourceCreateBlob {
+            blob_mem,
+            blob_flags,
+            blob_id: 0,
+            size: 4096,
+        };
+
+        // Without udmabuf, guest memory blobs can't be exported.
+        assert!(rutabaga
+            .probe_blob_handle_type(0, blob(RUTABAGA_BLOB_MEM_GUEST, 0))
+            .is_err());
+        // Guest handle blobs export whatever handle the VMM gives.
+        assert!(rutabaga
+            .probe_blob_handle_type(
+                0,
+                blob(
+                    RUTABAGA_BLOB_MEM_GUEST,
+                    RUTABAGA_BLOB_FLAG_CREATE_GUEST_HANDLE
+                )
+            )
+            .is_err());
+        assert!(matches!(
+            rutabaga.probe_blob_handle_type(0, blob(RUTABAGA_BLOB_MEM_HOST3D, 0)),
+            Err(RutabagaError::MesaError(
+                mesa3d_util::MesaError::Unsupported
+            ))
+        ));
+
+        rutabaga
+            .components
+            .insert(RutabagaComponentType::Rutabaga2D, Box::new(ShmComponent));
+        assert_eq!(
+            rutabaga
+                .probe_blob_handle_type(0, blob(RUTABAGA_BLOB_MEM_HOST3D, 0))
+                .unwrap(),
+            mesa3d_util::MESA_HANDLE_TYPE_MEM_SHM
+        );
+    }
+
+    // A component that fails to import resources of other components.
+    struct FailingImportComponent;
+
+    impl RutabagaComponent for FailingImportComponent {
+        fn import_shared(&self, _resource: &RutabagaResource) -> RutabagaResult<()> {
+            Err(RutabagaError::ComponentError(-22))
+        }
+    }
+
+    #[test]
+    fn attach_fails_when_import_fails() {
+        let mut rutabaga = new_2d();
+        rutabaga.components.insert(
+            RutabagaComponentType::VirglRenderer,
+            Box::new(FailingImportComponent),
+        );
+        rutabaga.contexts.insert(
+            1,
+            Box::new(TransferContext {
+                explicit_transfers: false,
+            }),
+            false,
+        );
+
+        let handle: RutabagaHandle = RutabagaMesaHandle {
+            os_handle: mesa3d_util::SharedMemory::new("import", 4096)
+                .unwrap()
+                .into(),
+            handle_type: mesa3d_util::MESA_HANDLE_TYPE_MEM_DMABUF,
+        }
+        .into();
+        let resource = RutabagaResource {
+            resource_id: 1,
+            handle: Some(std::sync::Arc::new(handle)),
+            blob: true,
+            blob_mem: RUTABAGA_BLOB_MEM_HOST3D,
+            blob_flags: 0,
+            map_info: None,
+            info_2d: None,
+            info_3d: None,
+            vulkan_info: None,
+            backing_iovecs: None,
+            component_mask: 1 << (RutabagaComponentType::Rutabaga2D as u8),
+            size: 4096,
+            mapping: None,
+        };
+        rutabaga.insert_resource(1, resource, [0; 16]);
+
+        assert!(matches!(
+            rutabaga.context_attach_resource(1, 1),
+            Err(RutabagaError::ComponentError(-22))
+        ));
+        let component_mask = rutabaga.resources.get(&1).unwrap().component_mask;
+        assert_eq!(
+            component_mask,
+            1 << (RutabagaComponentType::Rutabaga2D as u8)
+        );
+    }
+
+    // A component that records its suspends and resumes.
+    struct SuspendComponent {
+        calls: std::sync::Arc<std::sync::Mutex<Vec<&'static str>>>,
+    }
+
+    impl RutabagaComponent for SuspendComponent {
+        fn suspend(&self) -> RutabagaResult<()> {
+            self.calls.lock().unwrap().push("suspend");
+            Ok(())
+        }
+
+        fn resume(&self) -> RutabagaResult<()> {
+            self.calls.lock().unwrap().push("resume");
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn suspend_all_components() {
+        let mut rutabaga = new_2d();
+        let calls: std::sync::Arc<std::sync::Mutex<Vec<&'static str>>> = Default::default();
+        rutabaga.components.insert(
+            RutabagaComponentType::Magma,
+            Box::new(SuspendComponent {
+                calls: calls.clone(),
+            }),
+        );
+
+        // Components other than the default one may hold memory the GPU loses while suspended.
+        rutabaga.suspend().unwrap();
+        rutabaga.resume().unwrap();
+        assert_eq!(*calls.lock().unwrap(), ["suspend", "resume"]);
+    }
+
+    // A component that fails to map blobs, and transfers them to and from `contents`.
+    struct StagingComponent {
+        contents: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+    }
+
+    impl RutabagaComponent for StagingComponent {
+        fn transfer_write(
+            &self,
+            _ctx_id: u32,
+            _resource: &mut RutabagaResource,
+            transfer: Transfer3D,
+            buf: Option<std::io::IoSlice>,
+        ) -> RutabagaResult<()> {
+            let buf = buf.unwrap();
+            assert_eq!(transfer.w as usize, buf.len());
+            self.contents.lock().unwrap().copy_from_slice(&buf);
+            Ok(())
+        }
+
+        fn transfer_read(
+            &self,
+            _ctx_id: u32,
+            _resource: &mut RutabagaResource,
+            transfer: Transfer3D,
+            buf: Option<IoSliceMut>,
+        ) -> RutabagaResult<()> {
+            let mut buf = buf.unwrap();
+            assert_eq!(transfer.w as usize, buf.len());
+            buf.copy_from_slice(&self.contents.lock().unwrap());
+            Ok(())
+        }
+
+        fn map(&self, _resource_id: u32) -> RutabagaResult<MesaMapping> {
+            Err(RutabagaError::MappingFailed(-12))
+        }
+    }
+
+    struct TransferContext {
+        explicit_transfers: bool,
+    }
+
+    impl RutabagaContext for TransferContext {
+        fn submit_cmd(
+            &mut self,
+            _commands: &mut [u8],
+            _fence_ids: &[u64],
+            _shareable_fences: Vec<mesa3d_util::MesaHandle>,
+        ) -> RutabagaResult<()> {
+            Ok(())
+        }
+
+        fn attach(&mut self, _resource: &mut RutabagaResource) {}
+
+        fn detach(&mut self, _resource: &RutabagaResource) {}
+
+        fn component_type(&self) -> RutabagaComponentType {
+            RutabagaComponentType::VirglRenderer
+        }
+
+        fn explicit_transfers(&self) -> bool {
+            self.explicit_transfers
+        }
+    }
+
+    // Returns a rutabaga whose blob 1, created by context 1, fails to map and holds `contents`.
+    fn new_staging(
+        explicit_transfers: bool,
+        contents: Vec<u8>,
+    ) -> (Rutabaga, std::sync::Arc<std::sync::Mutex<Vec<u8>>>) {
+        let mut rutabaga = new_2d();
+        let size = contents.len() as u64;
+        let contents = std::sync::Arc::new(std::sync::Mutex::new(contents));
+        rutabaga.components.insert(
+            RutabagaComponentType::VirglRenderer,
+            Box::new(StagingComponent {
+                contents: contents.clone(),
+            }),
+        );
+        rutabaga
+            .contexts
+            .insert(1, Box::new(TransferContext { explicit_transfers }), false);
+
+        let resource = RutabagaResource {
+            resource_id: 1,
+            handle: None,
+            blob: true,
+            blob_mem: RUTABAGA_BLOB_MEM_HOST3D,
+            blob_flags: RUTABAGA_BLOB_FLAG_USE_MAPPABLE,
+            map_info: Some(RUTABAGA_MAP_CACHE_WC | RUTABAGA_MAP_ACCESS_RW),
+            info_2d: None,
+            info_3d: None,
+            vulkan_info: None,
+            backing_iovecs: None,
+            component_mask: 1 << (RutabagaComponentType::VirglRenderer as u8),
+            size,
+            mapping: None,
+        };
+        rutabaga.insert_resource(1, resource, [0; 16]);
+        rutabaga.charge_blob(1, 1, size);
+        (rutabaga, contents)
+    }
+
+    #[test]
+    fn staged_mapping_syncs_on_transfers() {
+        let (mut rutabaga, contents) = new_staging(true, (0..64).collect());
+        let mapping = rutabaga.map(1).unwrap();
+        // The guest keeps the cache attributes the blob was created with.
+        assert_eq!(
+            rutabaga.map_info(1).unwrap(),
+            RUTABAGA_MAP_CACHE_WC | RUTABAGA_MAP_ACCESS_RW
+        );
+
+        // SAFETY:
+        // The staged copy stays mapped until the final unmap.
+        let staged = unsafe {
+            std::slice::from_raw_parts_mut(mapping.ptr as *mut u8, mapping.size as usize)
+        };
+        assert_eq!(staged, &*contents.lock().unwrap());
+
+        let transfer = Transfer3D::new_2d(0, 0, 64, 1, 0);
+        staged.fill(1);
+        rutabaga.transfer_write(1, 1, transfer, None).unwrap();
+        assert_eq!(*contents.lock().unwrap(), vec![1; 64]);
+
+        contents.lock().unwrap().fill(2);
+        rutabaga.transfer_read(1, 1, transfer, None).unwrap();
+        assert!(staged.iter().all(|byte| *byte == 2));
+
+        // Unmapping writes the copy back.
+        staged.fill(3);
+        rutabaga.unmap(1).unwrap();
+        assert_eq!(*contents.lock().unwrap(), vec![3; 64]);
+    }
+
+    #[test]
+    fn staged_mapping_requires_explicit_transfers() {
+        let (mut rutabaga, _) = new_staging(false, vec![0; 64]);
+        assert!(matches!(
+            rutabaga.map(1),
+            Err(RutabagaError::MappingFailed(-12))
+        ));
+    }
+
+    #[test]
+    fn staged_mapping_fits_one_transfer() {
+        let (mut rutabaga, _) = new_staging(true, Vec::new());
+        rutabaga.resources.get_mut(&1).unwrap().size = 1 << 32;
+        assert!(rutabaga.map(1).is_err());
+        assert!(rutabaga.staged_mappings.is_empty());
+    }
+
+    #[test]
+    fn trigger_capture_without_renderdoc() {
+        let mut rutabaga = RutabagaBuilder::new(
+            1 << RUTABAGA_CAPSET_CROSS_DOMAIN,
+            RutabagaHandler::new(|_| {}),
+        )
+        .set_default_component(RutabagaComponentType::Rutabaga2D)
+        .set_renderdoc(Some(RutabagaCaptureBoundary::Scanout))
+        .build()
+        .unwrap();
+
+        rutabaga
+            .create_context(1, RUTABAGA_CAPSET_CROSS_DOMAIN, None)
+            .unwrap();
+
+        assert!(matches!(
+            rutabaga.trigger_capture(2),
+            Err(RutabagaError::InvalidContextId)
+        ));
+        // RenderDoc isn't injected into the test process.
+        assert!(matches!(
+            rutabaga.trigger_capture(1),
+            Err(RutabagaError::MesaError(RutabagaUnsupported))
+        ));
+    }
+
+    #[test]
+    fn transfer_2d_sparse_backing() {
+        let resource_id = 1;
+        let resource_create_3d = ResourceCreate3D {
+            target: RUTABAGA_PIPE_TEXTURE_2D,
+            format: 1,
+            bind: RUTABAGA_PIPE_BIND_RENDER_TARGET,
+            width: 2,
+            height: 2,
+            depth: 1,
+            array_size: 1,
+            last_level: 0,
+            nr_samples: 0,
+            flags: 0,
+        };
+
+        let mut rutabaga = new_2d();
+        rutabaga
+            .resource_create_3d(resource_id, resource_create_3d)
+            .unwrap();
+
+        // Zero-length holes are meaningless.
+        assert!(rutabaga
+            .attach_backing(resource_id, vec![RutabagaIovec::hole(0)])
+            .is_err());
+
+        // The first row is backed, the second row is a hole.
+        let mut backed = [0xabu8; 8];
+        rutabaga
+            .attach_backing(
+                resource_id,
+                vec![
+                    RutabagaIovec {
+                        base: backed.as_mut_ptr() as *mut c_void,
+                        len: backed.len(),
+                    },
+                    RutabagaIovec::hole(8),
+                ],
+            )
+            .unwrap();
+
+        let transfer = Transfer3D::new_2d(0, 0, 2, 2, 0);
+        rutabaga
+            .transfer_write(0, resource_id, transfer, None)
+            .unwrap();
+
+        let mut contents = [0xffu8; 16];
+        let transfer = Transfer3D {
+            stride: 8,
+            ..transfer
+        };
+        rutabaga
+            .transfer_read(
+                0,
+                resource_id,
+                transfer,
+                Some(IoSliceMut::new(&mut contents)),
+            )
+            .unwrap();
+
+        assert_eq!(contents[..8], [0xab; 8]);
+        assert_eq!(contents[8..], [0; 8]);
+    }
+
+    #[test]
+    fn transfer_2d_layers_and_levels() {
+        let resource_id = 1;
+        let resource_create_3d = ResourceCreate3D {
+            target: RUTABAGA_PIPE_TEXTURE_2D,
+            format: 1,
+            bind: RUTABAGA_PIPE_BIND_RENDER_TARGET,
+            width: 4,
+            height: 4,
+            depth: 1,
+            array_size: 2,
+            last_level: 1,
+            nr_samples: 0,
+            flags: 0,
+        };
+
+        let mut rutabaga = new_2d();
+        rutabaga
+            .resource_create_3d(resource_id, resource_create_3d)
+            .unwrap();
+
+        // Both 2x2 layers of the second level, packed.
+        let mut backing = [0x11u8; 32];
+        backing[16..].fill(0x22);
+        rutabaga
+            .attach_backing(
+                resource_id,
+                vec![RutabagaIovec {
+                    base: backing.as_mut_ptr() as *mut c_void,
+                    len: backing.len(),
+                }],
+            )
+            .unwrap();
+
+        let transfer = Transfer3D {
+            d: 2,
+            level: 1,
+            ..Transfer3D::new_2d(0, 0, 2, 2, 0)
+        };
+        rutabaga
+            .transfer_write(0, resource_id, transfer, None)
+            .unwrap();
+
+        let mut contents = [0xffu8; 16];
+        let transfer = Transfer3D {
+            z: 1,
+            d: 1,
+            stride: 8,
+            ..transfer
+        };
+        rutabaga
+            .transfer_read(
+                0,
+                resource_id,
+                transfer,
+                Some(IoSliceMut::new(&mut contents)),
+            )
+            .unwrap();
+        assert_eq!(contents, [0x22; 16]);
+
+        // The first level is untouched.
+        let mut contents = [0xffu8; 64];
+        let level_0 = Transfer3D {
+            stride: 16,
+            ..Transfer3D::new_2d(0, 0, 4, 4, 0)
+        };
+        rutabaga
+            .transfer_read(
+                0,
+                resource_id,
+                level_0,
+                Some(IoSliceMut::new(&mut contents)),
+            )
+            .unwrap();
+        assert_eq!(contents, [0; 64]);
+
+        let past_layers = Transfer3D { d: 2, ..transfer };
+        assert!(rutabaga
+            .transfer_write(0, resource_id, past_layers, None)
+            .is_err());
+        let past_levels = Transfer3D {
+            level: 2,
+            ..transfer
+        };
+        assert!(rutabaga
+            .transfer_write(0, resource_id, past_levels, None)
+            .is_err());
+    }
+
+    #[test]
+    fn transfer_2d_fast_paths() {
+        let resource_id = 1;
+        let resource_create_3d = ResourceCreate3D {
+            target: RUTABAGA_PIPE_TEXTURE_2D,
+            format: 1,
+            bind: RUTABAGA_PIPE_BIND_RENDER_TARGET,
+            width: 4,
+            height: 4,
+            depth: 1,
+            array_size: 1,
+            last_level: 0,
+            nr_samples: 0,
+            flags: 0,
+        };
+
+        let mut rutabaga = new_2d();
+        rutabaga
+            .resource_create_3d(resource_id, resource_create_3d)
+            .unwrap();
+
+        // Rows 1 and 2 are split across a backed chunk, a hole, and another backed chunk.
+        let mut backing: Vec<u8> = (0..64).collect();
+        let (head, tail) = backing.split_at_mut(32);
+        rutabaga
+            .attach_backing(
+                resource_id,
+                vec![
+                    RutabagaIovec {
+                        base: head.as_mut_ptr() as *mut c_void,
+                        len: 20,
+                    },
+                    RutabagaIovec::hole(12),
+                    RutabagaIovec {
+                        base: tail.as_mut_ptr() as *mut c_void,
+                        len: tail.len(),
+                    },
+                ],
+            )
+            .unwrap();
+
+        // Full-width rows copy as one span.
+        rutabaga
+            .transfer_write(0, resource_id, Transfer3D::new_2d(0, 1, 4, 2, 0), None)
+            .unwrap();
+
+        let mut expected = [0u8; 64];
+        expected[16..20].copy_from_slice(&backing[16..20]);
+        expected[32..48].copy_from_slice(&backing[32..48]);
+
+        let mut contents = [0xffu8; 64];
+        let full = Transfer3D {
+            stride: 16,
+            ..Transfer3D::new_2d(0, 0, 4, 4, 0)
+        };
+        rutabaga
+            .transfer_read(0, resource_id, full, Some(IoSliceMut::new(&mut contents)))
+            .unwrap();
+        assert_eq!(contents, expected);
+
+        // Narrower rows copy one at a time out of host memory, to the same place in the
+        // destination.
+        let mut contents = [0xffu8; 32];
+        let center = Transfer3D {
+            stride: 8,
+            ..Transfer3D::new_2d(1, 1, 2, 2, 0)
+        };
+        rutabaga
+            .transfer_read(0, resource_id, center, Some(IoSliceMut::new(&mut contents)))
+            .unwrap();
+        assert_eq!(contents[12..20], expected[20..28]);
+        assert_eq!(contents[20..28], expected[36..44]);
+
+        // Destinations too small for the rectangle are still rejected.
+        let mut contents = [0xffu8; 32];
+        assert!(rutabaga
+            .transfer_read(0, resource_id, full, Some(IoSliceMut::new(&mut contents)))
+            .is_err());
+        assert!(rutabaga
+            .transfer_read(
+                0,
+                resource_id,
+                center,
+                Some(IoSliceMut::new(&mut contents[..24]))
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn invalidate_guest_memory_2d() {
+        let resource_create_3d = ResourceCreate3D {
+            target: RUTABAGA_PIPE_TEXTURE_2D,
+            format: 1,
+            bind: RUTABAGA_PIPE_BIND_RENDER_TARGET,
+            width: 2,
+            height: 2,
+            depth: 1,
+            array_size: 1,
+            last_level: 0,
+            nr_samples: 0,
+            flags: 0,
+        };
+
+        let mut rutabaga = new_2d();
+        let mut memory = [0u8; 32];
+        for (resource_id, offset) in [(1, 0), (2, 16)] {
+            rutabaga
+                .resource_create_3d(resource_id, resource_create_3d)
+                .unwrap();
+            rutabaga
+                .attach_backing(
+                    resource_id,
+                    vec![RutabagaIovec {
+                        base: memory[offset..].as_mut_ptr() as *mut c_void,
+                        len: 16,
+                    }],
+                )
+                .unwrap();
+        }
+
+        // Only the second resource is backed by the last byte.
+        let start = memory.as_ptr() as u64 + 31;
+        assert_eq!(
+            rutabaga.invalidate_guest_memory(start..start + 1).unwrap(),
+            vec![2]
+        );
+        assert!(rutabaga.resources.get(&1).unwrap().backing_iovecs.is_some());
+        assert!(rutabaga.resources.get(&2).unwrap().backing_iovecs.is_none());
+
+        // The 2D component no longer has backing to copy from.
+        let transfer = Transfer3D::new_2d(0, 0, 2, 2, 0);
+        assert!(rutabaga.transfer_write(0, 2, transfer, None).is_err());
+    }
+
+    #[test]
+    fn detach_backing_waits_for_pins() {
+        use std::sync::atomic::AtomicBool;
+        use std::sync::atomic::Ordering;
+
+        let resource_create_3d = ResourceCreate3D {
+            target: RUTABAGA_PIPE_TEXTURE_2D,
+            format: 1,
+            bind: RUTABAGA_PIPE_BIND_RENDER_TARGET,
+            width: 2,
+            height: 2,
+            depth: 1,
+            array_size: 1,
+            last_level: 0,
+            nr_samples: 0,
+            flags: 0,
+        };
+
+        let mut rutabaga = new_2d();
+        let mut memory = [0u8; 16];
+        rutabaga.resource_create_3d(1, resource_create_3d).unwrap();
+        rutabaga
+            .attach_backing(
+                1,
+                vec![RutabagaIovec {
+                    base: memory.as_mut_ptr() as *mut c_void,
+                    len: memory.len(),
+                }],
+            )
+            .unwrap();
+
+        // A context's copy of the backing, pinned by another thread.
+        let backing = rutabaga
+            .resources
+            .get(&1)
+            .unwrap()
+            .backing_iovecs
+            .clone()
+            .unwrap();
+        let guard = backing.pin().unwrap();
+        let released = std::sync::Arc::new(AtomicBool::new(false));
+        let thread_released = released.clone();
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            thread_released.store(true, Ordering::SeqCst);
+            drop(guard);
+        });
+
+        rutabaga.detach_backing(1).unwrap();
+        assert!(released.load(Ordering::SeqCst));
+        assert!(backing.pin().is_err());
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn unmap_requires_map() {
+        let mut rutabaga = new_2d();
+        let resource_create_blob = ResourceCreateBlob {
+            blob_mem: RUTABAGA_BLOB_MEM_GUEST,
+            blob_flags: 0,
+            blob_id: 0,
+            size: 16,
+        };
+        rutabaga
+            .resource_create_blob(0, 1, resource_create_blob, None, None)
+            .unwrap();
+
+        assert!(rutabaga.unmap(1).is_err());
+        assert!(rutabaga.map(1).is_err());
+        assert!(rutabaga.mappings().is_empty());
+    }
+
+    #[test]
+    fn hybrid_blob_shadow() {
+        let mut rutabaga = new_2d();
+        let resource_create_blob = ResourceCreateBlob {
+            blob_mem: RUTABAGA_BLOB_MEM_HOST3D_GUEST,
+            blob_flags: 0,
+            blob_id: 0,
+            size: 16,
+        };
+        let result = rutabaga.resource_create_blob(0, 1, resource_create_blob, None, None);
+        assert!(matches!(result, Err(RutabagaError::InvalidIovec)));
+
+        let mut shadow = [1u8; 12];
+        let (first, second) = shadow.split_at_mut(4);
+        let iovecs = vec![
+            RutabagaIovec {
+                base: first.as_mut_ptr() as *mut c_void,
+                len: first.len(),
+            },
+            RutabagaIovec {
+                base: second.as_mut_ptr() as *mut c_void,
+                len: second.len(),
+            },
+        ];
+        let mut host = [0u8; 12];
+        let mapping = MesaMapping {
+            ptr: host.as_mut_ptr() as u64,
+            size: host.len() as u64,
+        };
+
+        super::copy_shadow(&iovecs, mapping, 2..8, true).unwrap();
+        assert_eq!(host, [0, 0, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0]);
+
+        host.fill(2);
+        super::copy_shadow(&iovecs, mapping, 3..5, false).unwrap();
+        assert_eq!(shadow, [1, 1, 1, 2, 2, 1, 1, 1, 1, 1, 1, 1]);
+        assert!(super::copy_shadow(&iovecs, mapping, 8..16, true).is_err());
+    }
+
+    #[test]
+    fn hybrid_blob_transfers() {
+        let mut rutabaga = RutabagaBuilder::new(
+            1 << RUTABAGA_CAPSET_CROSS_DOMAIN,
+            RutabagaHandler::new(|_| {}),
+        )
+        .build()
+        .unwrap();
+
+        let mut shadow = vec![1u8; 4096];
+        let handle: RutabagaHandle = RutabagaMesaHandle {
+            os_handle: mesa3d_util::SharedMemory::new("hybrid_blob", 4096)
+                .unwrap()
+                .into(),
+            handle_type: mesa3d_util::MESA_HANDLE_TYPE_MEM_SHM,
+        }
+        .into();
+        // The guest never maps the blob, so it isn't mappable.
+        let resource = RutabagaResource {
+            resource_id: 1,
+            handle: Some(std::sync::Arc::new(handle)),
+            blob: true,
+            blob_mem: RUTABAGA_BLOB_MEM_HOST3D_GUEST,
+            blob_flags: 0,
+            map_info: None,
+            info_2d: None,
+            info_3d: None,
+            vulkan_info: None,
+            backing_iovecs: Some(RutabagaBacking::new(vec![RutabagaIovec {
+                base: shadow.as_mut_ptr() as *mut c_void,
+                len: shadow.len(),
+            }])),
+            component_mask: 1 << (RutabagaComponentType::CrossDomain as u8),
+            size: 4096,
+            mapping: None,
+        };
+        rutabaga.insert_resource(1, resource, [0; 16]);
+
+        rutabaga
+            .transfer_write(0, 1, Transfer3D::new_2d(16, 0, 32, 1, 0), None)
+            .unwrap();
+        let mapping = rutabaga.shadow_mappings.get(&1).unwrap().as_mesa_mapping();
+        // SAFETY:
+        // The shadow mapping stays until the blob is destroyed.
+        let host = unsafe { std::slice::from_raw_parts_mut(mapping.ptr as *mut u8, 4096) };
+        assert!(host[..16].iter().all(|byte| *byte == 0));
+        assert!(host[16..48].iter().all(|byte| *byte == 1));
+        assert!(host[48..].iter().all(|byte| *byte == 0));
+
+        host.fill(2);
+        rutabaga
+            .transfer_read(0, 1, Transfer3D::new_2d(0, 0, 8, 1, 0), None)
+            .unwrap();
+        assert!(shadow[..8].iter().all(|byte| *byte == 2));
+        assert!(shadow[8..].iter().all(|byte| *byte == 1));
+
+        // Transfers share one mapping, which isn't a guest mapping.
+        assert_eq!(rutabaga.shadow_mappings.len(), 1);
+        assert!(rutabaga.mappings().is_empty());
+
+        rutabaga.unref_resource(1).unwrap();
+        assert!(rutabaga.shadow_mappings.is_empty());
+    }
+
+    #[test]
+    fn blob_flag_values() {
+        // The first three are VIRTIO_GPU_BLOB_FLAG_*, and CREATE_GUEST_HANDLE is shared with
+        // crosvm.  VMMs pass them through unchanged, so they must never move.
+        assert_eq!(RUTABAGA_BLOB_FLAG_USE_MAPPABLE, 0x0001);
+        assert_eq!(RUTABAGA_BLOB_FLAG_USE_SHAREABLE, 0x0002);
+        assert_eq!(RUTABAGA_BLOB_FLAG_USE_CROSS_DEVICE, 0x0004);
+        assert_eq!(RUTABAGA_BLOB_FLAG_CREATE_GUEST_HANDLE, 0x0008);
+        assert_eq!(RUTABAGA_BLOB_FLAG_PROTECTED, 0x8000_0000);
+    }
+
+    #[test]
+    fn create_blob_guest_handle_2d() {
+        let mut rutabaga = new_2d();
+        let mut backing = vec![0u8; 16];
+        let iovecs = vec![RutabagaIovec {
+            base: backing.as_mut_ptr() as *mut c_void,
+            len: backing.len(),
+        }];
+        let resource_create_blob = ResourceCreateBlob {
+            blob_mem: RUTABAGA_BLOB_MEM_GUEST,
+            blob_flags: RUTABAGA_BLOB_FLAG_USE_SHAREABLE | RUTABAGA_BLOB_FLAG_CREATE_GUEST_HANDLE,
+            blob_id: 0,
+            size: 16,
+        };
+        let guest_handle = || -> RutabagaHandle {
+            RutabagaMesaHandle {
+                os_handle: mesa3d_util::SharedMemory::new("guest_handle", 16)
+                    .unwrap()
+                    .into(),
+                handle_type: mesa3d_util::MESA_HANDLE_TYPE_MEM_SHM,
+            }
+            .into()
+        };
+
+        assert!(rutabaga
+            .resource_create_blob(0, 1, resource_create_blob, Some(iovecs.clone()), None)
+            .is_err());
+        assert!(rutabaga
+            .resource_create_blob(0, 1, resource_create_blob, None, Some(guest_handle()))
+            .is_err());
+
+        rutabaga
+            .resource_create_blob(
+                0,
+                1,
+                resource_create_blob,
+                Some(iovecs),
+                Some(guest_handle()),
+            )
+            .unwrap();
+        let info = rutabaga.query_resource(1).unwrap();
+        assert_eq!(info.blob_flags, resource_create_blob.blob_flags);
+
+        let handle = rutabaga.export_blob(1).unwrap();
+        assert_eq!(
+            handle.as_mesa_handle().unwrap().handle_type,
+            mesa3d_util::MESA_HANDLE_TYPE_MEM_SHM
+        );
+    }
+
+    #[test]
+    fn hold_resource_2d() {
+        let mut rutabaga = new_2d();
+        let mut backing = vec![0u8; 16];
+        let iovecs = vec![RutabagaIovec {
+            base: backing.as_mut_ptr() as *mut c_void,
+            len: backing.len(),
+        }];
+        let resource_create_blob = ResourceCreateBlob {
+            blob_mem: RUTABAGA_BLOB_MEM_GUEST,
+            blob_flags: RUTABAGA_BLOB_FLAG_CREATE_GUEST_HANDLE,
+            blob_id: 0,
+            size: 16,
+        };
+        let guest_handle: RutabagaHandle = RutabagaMesaHandle {
+            os_handle: mesa3d_util::SharedMemory::new("guest_handle", 16)
+                .unwrap()
+                .into(),
+            handle_type: mesa3d_util::MESA_HANDLE_TYPE_MEM_SHM,
+        }
+        .into();
+        rutabaga
+            .resource_create_blob(0, 1, resource_create_blob, Some(iovecs), Some(guest_handle))
+            .unwrap();
+
+        let held = rutabaga.hold_resource(1).unwrap();
+        assert_eq!(held.resource_id, 1);
+        assert_eq!(held.size, 16);
+
+        // The held resource outlives the guest's reference until it's released.
+        rutabaga.unref_resource(1).unwrap();
+        assert_eq!(rutabaga.deferred.resource_ids(), vec![1]);
+        rutabaga.release_resource(held.hold_id).unwrap();
+        assert!(rutabaga.deferred.resource_ids().is_empty());
+        assert!(matches!(
+            rutabaga.release_resource(held.hold_id),
+            Err(RutabagaError::InvalidResourceId)
+        ));
+    }
+
+    #[test]
+    fn query_resource_2d() {
+        let mut rutabaga = new_2d();
+        let resource_create_blob = ResourceCreateBlob {
+            blob_mem: RUTABAGA_BLOB_MEM_GUEST,
+            blob_flags: 0,
+            blob_id: 0,
+            size: 16,
+        };
+        rutabaga
+            .resource_create_blob(0, 1, resource_create_blob, None, None)
+            .unwrap();
+
+        let info = rutabaga.query_resource(1).unwrap();
+        assert_eq!(info.resource_id, 1);
+        assert_eq!(info.size, 16);
+        assert!(info.blob);
+        assert_eq!(info.blob_mem, RUTABAGA_BLOB_MEM_GUEST);
+        assert!(info.mapping.is_none());
+
+        rutabaga.unref_resource(1).unwrap();
+        assert!(matches!(
+            rutabaga.query_resource(1),
+            Err(RutabagaError::InvalidResourceId)
+        ));
+    }
+
+    #[test]
+    fn negotiate_handle_types() {
+        let dmabuf = handle_type_mask(&[RUTABAGA_HANDLE_TYPE_MEM_DMABUF]);
+        let opaque_fd = handle_type_mask(&[RUTABAGA_HANDLE_TYPE_MEM_OPAQUE_FD]);
+        let handle_types = RutabagaHandleTypes {
+            export: dmabuf | opaque_fd,
+            import: dmabuf,
+        };
+        assert_eq!(handle_types.negotiate(0).unwrap(), dmabuf | opaque_fd);
+        assert_eq!(handle_types.negotiate(dmabuf).unwrap(), dmabuf);
+
+        // 2D blobs are never exported, so a guest that negotiates can't import them.
+        let rutabaga = new_2d();
+        assert_eq!(
+            rutabaga.handle_types(0).unwrap(),
+            RutabagaHandleTypes::default()
+        );
+        assert!(matches!(
+            rutabaga.negotiate_handle_types(0, dmabuf),
+            Err(RutabagaError::HandleTypeMismatch { export: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn resource_names() {
+        let mut rutabaga = new_2d();
+        let resource_create_blob = ResourceCreateBlob {
+            blob_mem: RUTABAGA_BLOB_MEM_GUEST,
+            blob_flags: 0,
+            blob_id: 0,
+            size: 16,
+        };
+        rutabaga
+            .resource_create_blob(0, 1, resource_create_blob, None, None)
+            .unwrap();
+
+        rutabaga.set_resource_name(1, Some("swapchain 0")).unwrap();
+        let debug_info = rutabaga.debug_info();
+        assert_eq!(debug_info.resources.len(), 1);
+        assert_eq!(debug_info.resources[0].name.as_deref(), Some("swapchain 0"));
+        assert!(debug_info
+            .to_string()
+            .contains("res 1 (swapchain 0): 16 bytes, blob"));
+
+        let too_long = "x".repeat(RUTABAGA_MAX_RESOURCE_NAME + 1);
+        assert!(rutabaga.set_resource_name(1, Some(&too_long)).is_err());
+        rutabaga.set_resource_name(1, None).unwrap();
+        assert!(rutabaga.debug_info().resources[0].name.is_none());
+
+        rutabaga.unref_resource(1).unwrap();
+        assert!(rutabaga.debug_info().resources.is_empty());
+        assert!(matches!(
+            rutabaga.set_resource_name(1, Some("swapchain 0")),
+            Err(RutabagaError::InvalidResourceId)
+        ));
+    }
+
+    #[test]
+    fn guest_names() {
+        let mut rutabaga = RutabagaBuilder::new(
+            1 << RUTABAGA_CAPSET_CROSS_DOMAIN,
+            RutabagaHandler::new(|_| {}),
+        )
+        .set_default_component(RutabagaComponentType::Rutabaga2D)
+        .build()
+        .unwrap();
+
+        rutabaga
+            .create_context(1, RUTABAGA_CAPSET_CROSS_DOMAIN, Some("wayland"))
+            .unwrap();
+        let debug_info = rutabaga.debug_info();
+        assert_eq!(
+            debug_info.contexts[0].context_name.as_deref(),
+            Some("wayland")
+        );
+        assert!(debug_info.to_string().contains("ctx 1 (wayland)"));
+
+        let mut backing = vec![0u8; 16];
+        let iovecs = vec![RutabagaIovec {
+            base: backing.as_mut_ptr() as *mut c_void,
+            len: backing.len(),
+        }];
+        let resource_create_blob = ResourceCreateBlob {
+            blob_mem: RUTABAGA_BLOB_MEM_GUEST,
+            blob_flags: 0,
+            blob_id: 0,
+            size: 16,
+        };
+        rutabaga
+            .resource_create_blob(0, 1, resource_create_blob, Some(iovecs), None)
+            .unwrap();
+
+        // A CROSS_DOMAIN_CMD_SET_RESOURCE_NAME of `resource_id`.
+        let set_resource_name = |resource_id: u32, name: &[u8]| {
+            let cmd_size = 16 + name.len();
+            let mut command = vec![17, 0];
+            command.extend_from_slice(&(cmd_size as u16).to_le_bytes());
+            command.extend_from_slice(&0u32.to_le_bytes());
+            command.extend_from_slice(&resource_id.to_le_bytes());
+            command.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            command.extend_from_slice(name);
+            command
+        };
+
+        // Only resources attached to the context may be named.
+        assert!(rutabaga
+            .submit_command(1, &mut set_resource_name(1, b"cursor"), &[])
+            .is_err());
+        rutabaga.context_attach_resource(1, 1).unwrap();
+        rutabaga
+            .submit_command(1, &mut set_resource_name(1, b"cursor"), &[])
+            .unwrap();
+        assert_eq!(
+            rutabaga.debug_info().resources[0].name.as_deref(),
+            Some("cursor")
+        );
+
+        rutabaga
+            .submit_command(1, &mut set_resource_name(1, b""), &[])
+            .unwrap();
+        assert!(rutabaga.debug_info().resources[0].name.is_none());
+
+        rutabaga.destroy_context(1).unwrap();
+        assert!(rutabaga.debug_info().contexts.is_empty());
+    }
+
+    #[test]
+    fn virglrenderer_flags_validation() {
+        assert!(VirglRendererFlags::default().validate().is_ok());
+
+        let egl_and_glx = VirglRendererFlags::default().use_glx(true);
+        assert!(egl_and_glx.validate().is_err());
+
+        let glx = VirglRendererFlags::new().use_glx(true);
+        assert!(glx.validate().is_ok());
+
+        let async_without_sync = VirglRendererFlags::new().use_async_fence_cb(true);
+        assert!(async_without_sync.validate().is_err());
+
+        let nothing = VirglRendererFlags::new().use_virgl(false);
+        assert!(nothing.validate().is_err());
+        assert!(nothing.use_venus(true).validate().is_ok());
+
+        let virgl_server = VirglRendererFlags::new().use_render_server(true);
+        assert!(virgl_server.validate().is_err());
+
+        // The flags are checked whenever virglrenderer capsets are enabled, not only when
+        // virglrenderer is the default component.
+        let result = RutabagaBuilder::new(
+            (1 << RUTABAGA_CAPSET_GFXSTREAM_VULKAN) | (1 << RUTABAGA_CAPSET_VENUS),
+            RutabagaHandler::new(|_| {}),
+        )
+        .set_use_egl(true)
+        .set_use_glx(true)
+        .build();
+        assert!(matches!(
+            result,
+            Err(RutabagaError::MesaError(
+                mesa3d_util::MesaError::WithContext("EGL and GLX are exclusive")
+            ))
+        ));
+    }
+
+    #[test]
+    fn venus_filter_masks_capset() {
+        let word = |capset: &[u8], offset: usize| {
+            u32::from_ne_bytes(capset[offset..offset + 4].try_into().unwrap())
+        };
+
+        // An extension mask that isn't marked valid, with both features supported.
+        let mut capset = vec![0u8; 160];
+        capset[148..152].copy_from_slice(&1u32.to_ne_bytes());
+        capset[152..156].copy_from_slice(&1u32.to_ne_bytes());
+
+        let filter = crate::rutabaga_venus::RutabagaVenusFilter {
+            allowed_extensions: None,
+            denied_extensions: [348].into(),
+            denied_features: RUTABAGA_VENUS_FEATURE_WAIT_SYNCS,
+        };
+        filter.apply(&mut capset);
+
+        // Extension 348 is bit 28 of word 10.
+        assert_eq!(word(&capset, 20), u32::MAX);
+        assert_eq!(word(&capset, 20 + 10 * 4), !(1 << 28));
+        assert_eq!(word(&capset, 148), 0);
+        assert_eq!(word(&capset, 152), 1);
+
+        let filter = crate::rutabaga_venus::RutabagaVenusFilter {
+            allowed_extensions: Some([1, 348].into()),
+            ..Default::default()
+        };
+        filter.apply(&mut capset);
+
+        assert_eq!(word(&capset, 20), 0b11);
+        assert_eq!(word(&capset, 20 + 10 * 4), 0);
+    }
+
+    #[test]
+    fn transfer_write_async_2d() {
+        let signaled = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let handler_signaled = signaled.clone();
+        let mut rutabaga = RutabagaBuilder::new(
+            0,
+            RutabagaHandler::new(move |fence: RutabagaFence| {
+                handler_signaled.lock().unwrap().push(fence.fence_id)
+            }),
+        )
+        .set_default_component(RutabagaComponentType::Rutabaga2D)
+        .set_async_transfers(true)
+        .build()
+        .unwrap();
+
+        let resource_create_3d = ResourceCreate3D {
+            target: RUTABAGA_PIPE_TEXTURE_2D,
+            format: 1,
+            bind: RUTABAGA_PIPE_BIND_RENDER_TARGET,
+            width: 2,
+            height: 2,
+            depth: 1,
+            array_size: 1,
+            last_level: 0,
+            nr_samples: 0,
+            flags: 0,
+        };
+        rutabaga.resource_create_3d(1, resource_create_3d).unwrap();
+
+        let mut memory: Vec<u8> = (0..16).collect();
+        rutabaga
+            .attach_backing(
+                1,
+                vec![RutabagaIovec {
+                    base: memory.as_mut_ptr() as *mut c_void,
+                    len: memory.len(),
+                }],
+            )
+            .unwrap();
+
+        let transfer = Transfer3D::new_2d(0, 0, 2, 2, 0);
+        let mut fence = RutabagaFence {
+            flags: 0,
+            fence_id: 7,
+            ctx_id: 0,
+            ring_idx: 1,
+        };
+        assert!(rutabaga
+            .transfer_write_async(0, 1, transfer, fence)
+            .is_err());
+
+        fence.flags = RUTABAGA_FLAG_INFO_RING_IDX;
+        rutabaga
+            .transfer_write_async(0, 1, transfer, fence)
+            .unwrap();
+
+        // Reading waits for the queued write.
+        let mut readback = [0u8; 16];
+        let read = Transfer3D {
+            stride: 8,
+            ..transfer
+        };
+        rutabaga
+            .transfer_read(0, 1, read, Some(IoSliceMut::new(&mut readback)))
+            .unwrap();
+        assert_eq!(readback.as_slice(), memory.as_slice());
+
+        drop(rutabaga);
+        assert_eq!(*signaled.lock().unwrap(), vec![7]);
+    }
+
+    #[test]
+    fn transfer_read_async_2d() {
+        let signaled = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let handler_signaled = signaled.clone();
+        let mut rutabaga = RutabagaBuilder::new(
+            0,
+            RutabagaHandler::new(move |fence: RutabagaFence| {
+                handler_signaled.lock().unwrap().push(fence.fence_id)
+            }),
+        )
+        .set_default_component(RutabagaComponentType::Rutabaga2D)
+        .set_async_transfers(true)
+        .build()
+        .unwrap();
+
+        let resource_create_3d = ResourceCreate3D {
+            target: RUTABAGA_PIPE_TEXTURE_2D,
+            format: 1,
+            bind: RUTABAGA_PIPE_BIND_RENDER_TARGET,
+            width: 2,
+            height: 2,
+            depth: 1,
+            array_size: 1,
+            last_level: 0,
+            nr_samples: 0,
+            flags: 0,
+        };
+        rutabaga.resource_create_3d(1, resource_create_3d).unwrap();
+
+        let mut memory: Vec<u8> = (0..16).collect();
+        rutabaga
+            .attach_backing(
+                1,
+                vec![RutabagaIovec {
+                    base: memory.as_mut_ptr() as *mut c_void,
+                    len: memory.len(),
+                }],
+            )
+            .unwrap();
+
+        let transfer = Transfer3D::new_2d(0, 0, 2, 2, 0);
+        let fence = |fence_id| RutabagaFence {
+            flags: RUTABAGA_FLAG_INFO_RING_IDX,
+            fence_id,
+            ctx_id: 0,
+            ring_idx: 1,
+        };
+        assert!(rutabaga
+            .transfer_read_async(
+                0,
+                1,
+                transfer,
+                RutabagaFence {
+                    flags: 0,
+                    ..fence(6)
+                }
+            )
+            .is_err());
+
+        rutabaga
+            .transfer_write_async(0, 1, transfer, fence(7))
+            .unwrap();
+        rutabaga.wait_all_transfers();
+        memory.fill(0xff);
+
+        // The read signals in order with the write before it, once the iovecs hold the contents.
+        rutabaga
+            .transfer_read_async(0, 1, transfer, fence(8))
+            .unwrap();
+        drop(rutabaga);
+        assert_eq!(*signaled.lock().unwrap(), vec![7, 8]);
+        assert_eq!(memory, (0..16).collect::<Vec<u8>>());
+    }
+
+    #[cfg(feature = "deterministic")]
+    #[test]
+    fn deterministic_fence_order() {
+        let fence_order = |seed: u64| -> Vec<u64> {
+            let signaled = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+            let handler_signaled = signaled.clone();
+            let executor = RutabagaDeterministicExecutor::new(seed);
+            let mut rutabaga = RutabagaBuilder::new(
+                0,
+                RutabagaHandler::new(move |fence: RutabagaFence| {
+                    handler_signaled.lock().unwrap().push(fence.fence_id)
+                }),
+            )
+            .set_default_component(RutabagaComponentType::Rutabaga2D)
+            .set_deterministic_executor(executor.clone())
+            .build()
+            .unwrap();
+
+            for fence_id in 0..16 {
+                rutabaga
+                    .create_fence(RutabagaFence {
+                        flags: 0,
+                        fence_id,
+                        ctx_id: 0,
+                        ring_idx: 0,
+                    })
+                    .unwrap();
+            }
+
+            // Nothing is delivered until the executor runs.
+            assert_eq!(executor.pending_fences(), 16);
+            assert!(signaled.lock().unwrap().is_empty());
+            executor.run_until_idle();
+
+            let order = signaled.lock().unwrap().clone();
+            order
+        };
+
+        let order = fence_order(1);
+        let mut sorted = order.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..16).collect::<Vec<u64>>());
+        assert_eq!(order, fence_order(1));
+    }
 }
@@ -0,0 +1,325 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! rutabaga_deferred: defers the destruction of unreferenced resources until the fences pending at
+//! the time have signaled, since work submitted before the unref may still use them, and until
+//! other devices holding them release them.  It also tracks which fences follow work referencing
+//! each live resource, so a resource isn't resized while that work may still use it.
+
+use std::collections::BTreeMap as Map;
+use std::collections::BTreeSet as Set;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use crate::rutabaga_utils::RutabagaComponentType;
+use crate::rutabaga_utils::RutabagaFence;
+use crate::rutabaga_utils::RutabagaFenceKey;
+
+struct RutabagaDeferredResource {
+    // The components holding a reference to the resource.
+    components: Vec<RutabagaComponentType>,
+    fences: Set<RutabagaFenceKey>,
+}
+
+#[derive(Default)]
+struct RutabagaDeferredInner {
+    // The component of fences that haven't signaled yet.
+    pending_fences: Map<RutabagaFenceKey, RutabagaComponentType>,
+    resources: Map<u32, RutabagaDeferredResource>,
+    // The resources attached to each context.
+    attached: Map<u32, Set<u32>>,
+    // The resources attached to each context when work was last submitted to it, until the
+    // context's next fence.
+    unfenced: Map<u32, Set<u32>>,
+    // The pending fences that follow work referencing each live resource.
+    in_use: Map<u32, Set<RutabagaFenceKey>>,
+    // The resource held by another device, keyed by hold id.  Held resources aren't destroyed
+    // until every hold is released.
+    holds: Map<u64, u32>,
+    next_hold_id: u64,
+}
+
+impl RutabagaDeferredInner {
+    fn fence_done(&mut self, key: RutabagaFenceKey) {
+        if self.pending_fences.remove(&key).is_some() {
+            for resource in self.resources.values_mut() {
+                resource.fences.remove(&key);
+            }
+            self.in_use.retain(|_, fences| {
+                fences.remove(&key);
+                !fences.is_empty()
+            });
+        }
+    }
+}
+
+/// Resources waiting for fences before being destroyed.
+#[derive(Clone, Default)]
+pub struct RutabagaDeferredDestruction {
+    inner: Arc<Mutex<RutabagaDeferredInner>>,
+}
+
+impl RutabagaDeferredDestruction {
+    /// Records a fence of `component`, which must happen before the component may signal it.
+    pub fn fence_created(&self, fence: &RutabagaFence, component: RutabagaComponentType) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.pending_fences.insert(fence.key(), component);
+        // The fence follows the work submitted to its context so far.
+        for resource_id in inner.unfenced.remove(&fence.ctx_id).unwrap_or_default() {
+            inner
+                .in_use
+                .entry(resource_id)
+                .or_default()
+                .insert(fence.key());
+        }
+    }
+
+    /// Records that `resource_id` was attached to the context given by `ctx_id`.
+    pub fn attach(&self, ctx_id: u32, resource_id: u32) {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .attached
+            .entry(ctx_id)
+            .or_default()
+            .insert(resource_id);
+    }
+
+    /// Records that `resource_id` was detached from the context given by `ctx_id`.
+    pub fn detach(&self, ctx_id: u32, resource_id: u32) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(attached) = inner.attached.get_mut(&ctx_id) {
+            attached.remove(&resource_id);
+        }
+    }
+
+    /// Records that work was submitted to the context given by `ctx_id`.  It may reference any
+    /// resource attached to the context.
+    pub fn submitted(&self, ctx_id: u32) {
+        let mut inner = self.inner.lock().unwrap();
+        let attached = inner.attached.get(&ctx_id).cloned().unwrap_or_default();
+        inner.unfenced.entry(ctx_id).or_default().extend(attached);
+    }
+
+    /// Records that `fence` signaled, or will never signal, e.g. because creating it failed.
+    pub fn fence_done(&self, fence: &RutabagaFence) {
+        self.inner.lock().unwrap().fence_done(fence.key());
+    }
+
+    /// Forgets the fences of a destroyed context, which won't signal anymore.
+    pub fn context_destroyed(&self, ctx_id: u32) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.attached.remove(&ctx_id);
+        inner.unfenced.remove(&ctx_id);
+        let keys: Vec<RutabagaFenceKey> = inner
+            .pending_fences
+            .keys()
+            .filter(|(fence_ctx_id, _, _)| *fence_ctx_id == ctx_id)
+            .copied()
+            .collect();
+
+        for key in keys {
+            inner.fence_done(key);
+        }
+    }
+
+    /// Defers the destruction of `resource_id` by `components` until the fences of those
+    /// components pending now have signaled.
+    pub fn defer(&self, resource_id: u32, components: Vec<RutabagaComponentType>) {
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+        for resource_ids in inner
+            .attached
+            .values_mut()
+            .chain(inner.unfenced.values_mut())
+        {
+            resource_ids.remove(&resource_id);
+        }
+        inner.in_use.remove(&resource_id);
+        let fences = inner
+            .pending_fences
+            .iter()
+            .filter(|(_, component)| components.contains(component))
+            .map(|(key, _)| *key)
+            .collect();
+
+        inner
+            .resources
+            .insert(resource_id, RutabagaDeferredResource { components, fences });
+    }
+
+    /// Returns true if work referencing `resource_id` may still be running: it was submitted to
+    /// a context the resource is attached to, and the fence following it hasn't signaled yet or
+    /// hasn't been created.
+    pub fn in_use(&self, resource_id: u32) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.in_use.contains_key(&resource_id)
+            || inner
+                .unfenced
+                .values()
+                .any(|resource_ids| resource_ids.contains(&resource_id))
+    }
+
+    /// Removes the resources that no longer wait for any fence, and returns them along with the
+    /// components to destroy them in.
+    pub fn take_ready(&self) -> Vec<(u32, Vec<RutabagaComponentType>)> {
+        let mut inner = self.inner.lock().unwrap();
+        let ready: Vec<u32> = inner
+            .resources
+            .iter()
+            .filter(|(resource_id, resource)| {
+                resource.fences.is_empty() && !inner.holds.values().any(|id| id == *resource_id)
+            })
+            .map(|(resource_id, _)| *resource_id)
+            .collect();
+
+        ready
+            .into_iter()
+            .filter_map(|resource_id| {
+                let resource = inner.resources.remove(&resource_id)?;
+                Some((resource_id, resource.components))
+            })
+            .collect()
+    }
+
+    /// Removes `resource_id` whether or not it still waits for fences, and returns the components
+    /// to destroy it in.
+    pub fn take(&self, resource_id: u32) -> Option<Vec<RutabagaComponentType>> {
+        let mut inner = self.inner.lock().unwrap();
+        let resource = inner.resources.remove(&resource_id)?;
+        // Holders keep their own handle of the memory, so only their holds go away.
+        inner
+            .holds
+            .retain(|_, held_resource_id| *held_resource_id != resource_id);
+        Some(resource.components)
+    }
+
+    /// Keeps `resource_id` from being destroyed, even once it's unreferenced, until the returned
+    /// hold is released.
+    pub fn hold(&self, resource_id: u32) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_hold_id += 1;
+        let hold_id = inner.next_hold_id;
+        inner.holds.insert(hold_id, resource_id);
+        hold_id
+    }
+
+    /// Releases a hold returned by `hold()`.  Returns false if there's no such hold.
+    pub fn release(&self, hold_id: u64) -> bool {
+        self.inner.lock().unwrap().holds.remove(&hold_id).is_some()
+    }
+
+    /// Returns the ids of all deferred resources.
+    pub fn resource_ids(&self) -> Vec<u32> {
+        self.inner
+            .lock()
+            .unwrap()
+            .resources
+            .keys()
+            .copied()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rutabaga_utils::RUTABAGA_FLAG_FENCE;
+    use crate::rutabaga_utils::RUTABAGA_FLAG_INFO_RING_IDX;
+
+    const VIRGL: RutabagaComponentType = RutabagaComponentType::VirglRenderer;
+    const CROSS_DOMAIN: RutabagaComponentType = RutabagaComponentType::CrossDomain;
+
+    fn ring_fence(ctx_id: u32, fence_id: u64) -> RutabagaFence {
+        RutabagaFence {
+            flags: RUTABAGA_FLAG_FENCE | RUTABAGA_FLAG_INFO_RING_IDX,
+            fence_id,
+            ctx_id,
+            ring_idx: 0,
+        }
+    }
+
+    #[test]
+    fn defer_waits_for_component_fences() {
+        let deferred: RutabagaDeferredDestruction = Default::default();
+        // Both contexts use fence id 1.
+        deferred.fence_created(&ring_fence(1, 1), VIRGL);
+        deferred.fence_created(&ring_fence(2, 1), CROSS_DOMAIN);
+        deferred.defer(1, vec![VIRGL]);
+        assert!(deferred.take_ready().is_empty());
+
+        deferred.fence_done(&ring_fence(2, 1));
+        assert!(deferred.take_ready().is_empty());
+
+        deferred.fence_done(&ring_fence(1, 1));
+        assert!(deferred.take_ready() == vec![(1, vec![VIRGL])]);
+        assert!(deferred.resource_ids().is_empty());
+    }
+
+    #[test]
+    fn later_fences_dont_delay() {
+        let deferred: RutabagaDeferredDestruction = Default::default();
+        deferred.defer(1, vec![VIRGL]);
+        deferred.fence_created(&ring_fence(1, 1), VIRGL);
+        assert!(deferred.take_ready() == vec![(1, vec![VIRGL])]);
+    }
+
+    #[test]
+    fn destroyed_context_releases_fences() {
+        let deferred: RutabagaDeferredDestruction = Default::default();
+        deferred.fence_created(&ring_fence(1, 1), VIRGL);
+        deferred.fence_created(&ring_fence(2, 1), VIRGL);
+        deferred.defer(1, vec![VIRGL]);
+
+        deferred.context_destroyed(1);
+        assert!(deferred.take_ready().is_empty());
+        deferred.context_destroyed(2);
+        assert!(deferred.take_ready() == vec![(1, vec![VIRGL])]);
+    }
+
+    #[test]
+    fn in_use_follows_submitted_work() {
+        let deferred: RutabagaDeferredDestruction = Default::default();
+        deferred.attach(1, 1);
+        // A fence pending on a context without the resource is unrelated.
+        deferred.fence_created(&ring_fence(2, 1), CROSS_DOMAIN);
+        assert!(!deferred.in_use(1));
+
+        // Work submitted to a context with the resource uses it until the next fence signals.
+        deferred.submitted(1);
+        assert!(deferred.in_use(1));
+        deferred.fence_created(&ring_fence(1, 1), CROSS_DOMAIN);
+        assert!(deferred.in_use(1));
+        deferred.fence_created(&ring_fence(1, 2), CROSS_DOMAIN);
+        deferred.fence_done(&ring_fence(1, 1));
+        assert!(!deferred.in_use(1));
+
+        // Detached resources aren't referenced by later work.
+        deferred.detach(1, 1);
+        deferred.submitted(1);
+        assert!(!deferred.in_use(1));
+
+        deferred.attach(1, 1);
+        deferred.submitted(1);
+        deferred.context_destroyed(1);
+        assert!(!deferred.in_use(1));
+    }
+
+    #[test]
+    fn holds_keep_resources() {
+        let deferred: RutabagaDeferredDestruction = Default::default();
+        let hold_id = deferred.hold(1);
+        deferred.defer(1, vec![VIRGL]);
+        assert!(deferred.take_ready().is_empty());
+
+        assert!(deferred.release(hold_id));
+        assert!(!deferred.release(hold_id));
+        assert!(deferred.take_ready() == vec![(1, vec![VIRGL])]);
+
+        // Taking a held resource drops its holds.
+        let hold_id = deferred.hold(2);
+        deferred.defer(2, vec![VIRGL]);
+        assert!(deferred.take(2) == Some(vec![VIRGL]));
+        assert!(!deferred.release(hold_id));
+    }
+}
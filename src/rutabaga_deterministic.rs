@@ -0,0 +1,215 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! rutabaga_deterministic: a single-stepped executor replacing internal worker threads, for
+//! reproducing fence ordering races in tests.
+//!
+//! Fences signaled by components are queued instead of being delivered, and internal workers are
+//! run by the executor instead of by dedicated threads.  Every `step()` either delivers one queued
+//! fence or runs one worker iteration, picked by a pseudo-random generator seeded by the caller.
+//! The same seed and the same sequence of calls into rutabaga yield the same delivery order.
+
+use std::collections::BTreeMap as Map;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use log::error;
+
+use crate::rutabaga_utils::RutabagaFence;
+use crate::rutabaga_utils::RutabagaFenceHandler;
+use crate::rutabaga_utils::RutabagaHandler;
+use crate::rutabaga_utils::RutabagaResult;
+
+/// The outcome of a single worker iteration.
+pub(crate) enum RutabagaWorkerStatus {
+    /// The worker did some work.
+    Progress,
+    /// The worker has nothing to do until some other event happens.
+    Idle,
+    /// The worker has exited and must not be stepped again.
+    Finished,
+}
+
+type RutabagaWorker = Box<dyn FnMut() -> RutabagaResult<RutabagaWorkerStatus> + Send>;
+
+struct PendingFence {
+    fence: RutabagaFence,
+    handler: RutabagaFenceHandler,
+}
+
+struct RutabagaExecutorState {
+    rng_state: u64,
+    pending_fences: Vec<PendingFence>,
+    // Workers are taken out of their slot while they run, so they may signal fences.  Slots are
+    // keyed by a stable id, since a worker may re-enter the executor and remove other workers.
+    workers: Map<u64, Option<RutabagaWorker>>,
+    next_worker_id: u64,
+}
+
+impl RutabagaExecutorState {
+    // xorshift64*, which is good enough for shuffling and has no dependencies.
+    fn next_random(&mut self) -> u64 {
+        let mut x = self.rng_state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.rng_state = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+enum RutabagaStepChoice {
+    Fence(PendingFence),
+    Worker(u64, RutabagaWorker),
+}
+
+/// A seeded, single-stepped executor.  Tests keep a clone to `step()` after handing one to the
+/// builder.
+#[derive(Clone)]
+pub struct RutabagaDeterministicExecutor {
+    state: Arc<Mutex<RutabagaExecutorState>>,
+}
+
+impl RutabagaDeterministicExecutor {
+    /// Returns a new executor whose choices are derived from `seed`.
+    pub fn new(seed: u64) -> RutabagaDeterministicExecutor {
+        RutabagaDeterministicExecutor {
+            state: Arc::new(Mutex::new(RutabagaExecutorState {
+                // xorshift gets stuck at zero.
+                rng_state: seed ^ 0x9e37_79b9_7f4a_7c15,
+                pending_fences: Vec::new(),
+                workers: Map::new(),
+                next_worker_id: 0,
+            })),
+        }
+    }
+
+    /// Returns a fence handler that queues fences until the executor delivers them to `handler`.
+    pub fn wrap_fence_handler(&self, handler: RutabagaFenceHandler) -> RutabagaFenceHandler {
+        let executor = self.clone();
+        RutabagaHandler::new(move |fence: RutabagaFence| {
+            executor
+                .state
+                .lock()
+                .unwrap()
+                .pending_fences
+                .push(PendingFence {
+                    fence,
+                    handler: handler.clone(),
+                });
+        })
+    }
+
+    /// Runs `worker` from `step()` instead of from a dedicated thread.
+    pub(crate) fn add_worker(
+        &self,
+        worker: impl FnMut() -> RutabagaResult<RutabagaWorkerStatus> + Send + 'static,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let worker_id = state.next_worker_id;
+        state.next_worker_id += 1;
+        state.workers.insert(worker_id, Some(Box::new(worker)));
+    }
+
+    /// Returns the number of fences waiting to be delivered.
+    pub fn pending_fences(&self) -> usize {
+        self.state.lock().unwrap().pending_fences.len()
+    }
+
+    /// Delivers one pending fence or runs one worker iteration.  Returns false if every worker is
+    /// idle and no fence is pending.
+    pub fn step(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let num_fences = state.pending_fences.len();
+        let num_choices = num_fences + state.workers.len();
+        if num_choices == 0 {
+            return false;
+        }
+
+        let start = (state.next_random() % num_choices as u64) as usize;
+        for i in 0..num_choices {
+            // Idle workers may have re-entered the executor, so the choices are counted again.
+            let num_fences = state.pending_fences.len();
+            let choice = (start + i) % (num_fences + state.workers.len()).max(1);
+            let step_choice = if choice < num_fences {
+                RutabagaStepChoice::Fence(state.pending_fences.remove(choice))
+            } else {
+                let slot = state.workers.iter_mut().nth(choice - num_fences);
+                match slot.and_then(|(worker_id, slot)| Some((*worker_id, slot.take()?))) {
+                    Some((worker_id, worker)) => RutabagaStepChoice::Worker(worker_id, worker),
+                    None => continue,
+                }
+            };
+
+            // Fence handlers and workers may re-enter the executor.
+            drop(state);
+            match step_choice {
+                RutabagaStepChoice::Fence(pending) => {
+                    pending.handler.call(pending.fence);
+                    return true;
+                }
+                RutabagaStepChoice::Worker(worker_id, mut worker) => {
+                    let status = worker();
+                    state = self.state.lock().unwrap();
+                    match status {
+                        Ok(RutabagaWorkerStatus::Progress) => {
+                            state.workers.insert(worker_id, Some(worker));
+                            return true;
+                        }
+                        Ok(RutabagaWorkerStatus::Idle) => {
+                            state.workers.insert(worker_id, Some(worker));
+                        }
+                        Ok(RutabagaWorkerStatus::Finished) => {
+                            state.workers.remove(&worker_id);
+                            return true;
+                        }
+                        Err(e) => {
+                            error!("Worker halting due to: {}", e);
+                            state.workers.remove(&worker_id);
+                            return true;
+                        }
+                    }
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Steps until every worker is idle and no fence is pending.
+    pub fn run_until_idle(&self) {
+        while self.step() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn workers_finish_while_another_runs() {
+        for seed in 0..16 {
+            let executor = RutabagaDeterministicExecutor::new(seed);
+            for _ in 0..2 {
+                executor.add_worker(|| Ok(RutabagaWorkerStatus::Finished));
+            }
+
+            // Runs the other workers to completion from within its own step.
+            let nested = executor.clone();
+            let mut ran = false;
+            executor.add_worker(move || {
+                if ran {
+                    return Ok(RutabagaWorkerStatus::Finished);
+                }
+
+                ran = true;
+                nested.run_until_idle();
+                Ok(RutabagaWorkerStatus::Progress)
+            });
+
+            executor.run_until_idle();
+            assert!(executor.state.lock().unwrap().workers.is_empty());
+        }
+    }
+}
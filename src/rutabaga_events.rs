@@ -0,0 +1,333 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! rutabaga_events: per-context rings through which components deliver host events to the guest,
+//! such as device memory reports.
+//!
+//! The guest attaches a blob as the event ring of a context, on a ring_idx of its choosing, and
+//! creates fences on that ring_idx to wait for events.  Such a fence stays pending until events
+//! are queued, then signals once a batch of them has been written to the ring.  The guest reads
+//! the batch and creates the next fence, which hands the ring back to the host.  Events queued
+//! while no fence is pending wait in a bounded queue, and overflow is counted in the ring header.
+
+use std::collections::BTreeMap as Map;
+use std::collections::VecDeque;
+use std::mem::size_of;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use log::warn;
+use mesa3d_util::MesaError;
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+
+use crate::rutabaga_utils::RutabagaBacking;
+use crate::rutabaga_utils::RutabagaError;
+use crate::rutabaga_utils::RutabagaFence;
+use crate::rutabaga_utils::RutabagaFenceHandler;
+use crate::rutabaga_utils::RutabagaIovec;
+use crate::rutabaga_utils::RutabagaResult;
+use crate::rutabaga_utils::RUTABAGA_FLAG_INFO_RING_IDX;
+
+/// A Vulkan device memory report, whose payload is a VkDeviceMemoryReportCallbackDataEXT without
+/// its sType and pNext.
+pub const RUTABAGA_CONTEXT_EVENT_DEVICE_MEMORY_REPORT: u32 = 1;
+
+/// Sent to every context with an event ring when capsets are added or removed, after which the
+/// guest should query them again.  The payload is a RutabagaCapsetsChanged.
+pub const RUTABAGA_CONTEXT_EVENT_CAPSETS_CHANGED: u32 = 2;
+
+/// The `event_type` of a device memory report of an allocation.
+pub const RUTABAGA_DEVICE_MEMORY_REPORT_ALLOCATE: u32 = 0;
+/// The `event_type` of a device memory report of a free.
+pub const RUTABAGA_DEVICE_MEMORY_REPORT_FREE: u32 = 1;
+/// The `event_type` of a device memory report of a failed allocation.
+pub const RUTABAGA_DEVICE_MEMORY_REPORT_ALLOCATION_FAILED: u32 = 4;
+
+/// Records in the ring start at multiples of this.
+pub const RUTABAGA_EVENT_ALIGNMENT: usize = 8;
+
+/// The most events queued per context while the guest has no fence pending.
+const RUTABAGA_EVENT_QUEUE_LIMIT: usize = 1024;
+
+/// The start of an event ring, followed by `count` records.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+pub struct RutabagaEventRingHeader {
+    pub count: u32,
+    /// Events dropped since the previous batch, because the queue or the ring was full.
+    pub dropped: u32,
+}
+
+/// The start of an event record, followed by `size` bytes of payload padded to
+/// RUTABAGA_EVENT_ALIGNMENT.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+pub struct RutabagaEventHeader {
+    pub event_type: u32,
+    pub size: u32,
+}
+
+/// The payload of a RUTABAGA_CONTEXT_EVENT_DEVICE_MEMORY_REPORT event, laid out like a
+/// VkDeviceMemoryReportCallbackDataEXT without its sType and pNext.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+pub struct RutabagaDeviceMemoryReport {
+    pub flags: u32,
+    pub event_type: u32,
+    pub memory_object_id: u64,
+    pub size: u64,
+    /// A VkObjectType.
+    pub object_type: u32,
+    pub padding: u32,
+    pub object_handle: u64,
+    pub heap_index: u32,
+    pub padding2: u32,
+}
+
+/// The payload of a RUTABAGA_CONTEXT_EVENT_CAPSETS_CHANGED event.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+pub struct RutabagaCapsetsChanged {
+    pub num_capsets: u32,
+    pub padding: u32,
+}
+
+struct RutabagaEventRing {
+    ring_idx: u8,
+    backing: RutabagaBacking,
+    fence: Option<RutabagaFence>,
+    queue: VecDeque<(u32, Vec<u8>)>,
+    dropped: u32,
+}
+
+// Returns the length of the backed prefix of the ring.
+fn backed_len(iovecs: &[RutabagaIovec]) -> usize {
+    iovecs
+        .iter()
+        .take_while(|iovec| !iovec.is_hole())
+        .map(|iovec| iovec.len)
+        .sum()
+}
+
+// Copies `data` to the start of the backed prefix of `iovecs`, which must be large enough.
+fn copy_to_iovecs(iovecs: &[RutabagaIovec], mut data: &[u8]) {
+    for iovec in iovecs {
+        if data.is_empty() {
+            break;
+        }
+
+        let len = iovec.len.min(data.len());
+        // SAFETY:
+        // The iovec is backed and pinned by the caller, and at least `len` bytes long.
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), iovec.base as *mut u8, len) };
+        data = &data[len..];
+    }
+}
+
+impl RutabagaEventRing {
+    // Writes as many queued events as fit to the ring if the guest is waiting for them, and
+    // returns the fence to signal.
+    fn deliver(&mut self, force: bool) -> Option<RutabagaFence> {
+        if !force && self.queue.is_empty() && self.dropped == 0 {
+            return None;
+        }
+
+        let fence = self.fence.take()?;
+        let ring = match self.backing.pin() {
+            Ok(ring) => ring,
+            Err(e) => {
+                warn!("dropping events of context {}: {e}", fence.ctx_id);
+                self.queue.clear();
+                return Some(fence);
+            }
+        };
+
+        let capacity = backed_len(&ring);
+        let mut header = RutabagaEventRingHeader::default();
+        let mut records = Vec::new();
+        while let Some((event_type, payload)) = self.queue.front() {
+            let record_len = size_of::<RutabagaEventHeader>()
+                + payload.len().next_multiple_of(RUTABAGA_EVENT_ALIGNMENT);
+            if size_of::<RutabagaEventRingHeader>() + records.len() + record_len > capacity {
+                // An event too large for an empty ring would otherwise block the ring forever.
+                if header.count == 0 {
+                    self.queue.pop_front();
+                    self.dropped = self.dropped.saturating_add(1);
+                    continue;
+                }
+                break;
+            }
+
+            let event = RutabagaEventHeader {
+                event_type: *event_type,
+                size: payload.len() as u32,
+            };
+            records.extend_from_slice(event.as_bytes());
+            records.extend_from_slice(payload);
+            records.resize(records.len().next_multiple_of(RUTABAGA_EVENT_ALIGNMENT), 0);
+            header.count += 1;
+            self.queue.pop_front();
+        }
+
+        header.dropped = std::mem::take(&mut self.dropped);
+        if capacity >= size_of::<RutabagaEventRingHeader>() {
+            let mut batch = header.as_bytes().to_vec();
+            batch.extend_from_slice(&records);
+            copy_to_iovecs(&ring, &batch);
+        }
+
+        Some(fence)
+    }
+}
+
+/// Queues events for the event rings of guest contexts, on behalf of every component given a
+/// clone.
+#[derive(Clone)]
+pub struct RutabagaEventSink {
+    rings: Arc<Mutex<Map<u32, RutabagaEventRing>>>,
+    fence_handler: RutabagaFenceHandler,
+}
+
+impl RutabagaEventSink {
+    pub(crate) fn new(fence_handler: RutabagaFenceHandler) -> RutabagaEventSink {
+        RutabagaEventSink {
+            rings: Default::default(),
+            fence_handler,
+        }
+    }
+
+    pub(crate) fn attach(
+        &self,
+        ctx_id: u32,
+        ring_idx: u8,
+        backing: RutabagaBacking,
+    ) -> RutabagaResult<()> {
+        let mut rings = self.rings.lock().unwrap();
+        if rings.contains_key(&ctx_id) {
+            return Err(MesaError::WithContext("context already has an event ring").into());
+        }
+
+        rings.insert(
+            ctx_id,
+            RutabagaEventRing {
+                ring_idx,
+                backing,
+                fence: None,
+                queue: VecDeque::new(),
+                dropped: 0,
+            },
+        );
+        Ok(())
+    }
+
+    /// Removes the event ring of the context.  A pending fence signals with the events queued so
+    /// far, possibly none, so the guest stops waiting.
+    pub(crate) fn detach(&self, ctx_id: u32) -> RutabagaResult<()> {
+        let ring = self
+            .rings
+            .lock()
+            .unwrap()
+            .remove(&ctx_id)
+            .ok_or(MesaError::WithContext("context has no event ring"))?;
+
+        self.close(ring);
+        Ok(())
+    }
+
+    /// Like `detach()`, for a destroyed context, which may have no event ring.
+    pub(crate) fn context_destroyed(&self, ctx_id: u32) {
+        let ring = self.rings.lock().unwrap().remove(&ctx_id);
+        if let Some(ring) = ring {
+            self.close(ring);
+        }
+    }
+
+    fn close(&self, mut ring: RutabagaEventRing) {
+        if let Some(fence) = ring.deliver(true) {
+            self.fence_handler.call(fence);
+        }
+    }
+
+    /// Returns true if `fence` waits for events rather than for work of the context.
+    pub(crate) fn is_event_fence(&self, fence: &RutabagaFence) -> bool {
+        fence.flags & RUTABAGA_FLAG_INFO_RING_IDX != 0
+            && self
+                .rings
+                .lock()
+                .unwrap()
+                .get(&fence.ctx_id)
+                .is_some_and(|ring| ring.ring_idx == fence.ring_idx)
+    }
+
+    /// Signals `fence` once events are written to the ring of its context.
+    pub(crate) fn wait(&self, fence: RutabagaFence) -> RutabagaResult<()> {
+        let fence = {
+            let mut rings = self.rings.lock().unwrap();
+            let ring = rings
+                .get_mut(&fence.ctx_id)
+                .ok_or(RutabagaError::InvalidContextId)?;
+            if ring.fence.is_some() {
+                return Err(MesaError::WithContext("an event fence is already pending").into());
+            }
+
+            ring.fence = Some(fence);
+            ring.deliver(false)
+        };
+
+        if let Some(fence) = fence {
+            self.fence_handler.call(fence);
+        }
+
+        Ok(())
+    }
+
+    /// Queues an event of `event_type` for the guest context.  Fails if the context has no event
+    /// ring.
+    pub fn send(&self, ctx_id: u32, event_type: u32, payload: &[u8]) -> RutabagaResult<()> {
+        let fence = {
+            let mut rings = self.rings.lock().unwrap();
+            let ring = rings
+                .get_mut(&ctx_id)
+                .ok_or(MesaError::WithContext("context has no event ring"))?;
+            if ring.queue.len() < RUTABAGA_EVENT_QUEUE_LIMIT {
+                ring.queue.push_back((event_type, payload.to_vec()));
+            } else {
+                ring.dropped = ring.dropped.saturating_add(1);
+            }
+
+            ring.deliver(false)
+        };
+
+        if let Some(fence) = fence {
+            self.fence_handler.call(fence);
+        }
+
+        Ok(())
+    }
+
+    /// Queues an event of `event_type` for every context with an event ring.
+    pub(crate) fn broadcast(&self, event_type: u32, payload: &[u8]) {
+        let fences: Vec<RutabagaFence> = {
+            let mut rings = self.rings.lock().unwrap();
+            rings
+                .values_mut()
+                .filter_map(|ring| {
+                    if ring.queue.len() < RUTABAGA_EVENT_QUEUE_LIMIT {
+                        ring.queue.push_back((event_type, payload.to_vec()));
+                    } else {
+                        ring.dropped = ring.dropped.saturating_add(1);
+                    }
+
+                    ring.deliver(false)
+                })
+                .collect()
+        };
+
+        for fence in fences {
+            self.fence_handler.call(fence);
+        }
+    }
+}
@@ -235,14 +235,26 @@ fn stride_from_layout(layout: &PlanarLayout, width: u32, plane: usize) -> Rutaba
 
 pub fn canonical_image_requirements(
     info: ImageAllocationInfo,
+) -> RutabagaResult<ImageMemoryRequirements> {
+    aligned_image_requirements(info, 1, 1)
+}
+
+/// Like `canonical_image_requirements()`, but rounds the stride of every plane up to
+/// `stride_alignment` and the offset of every plane after the first up to `plane_alignment`.
+pub fn aligned_image_requirements(
+    info: ImageAllocationInfo,
+    stride_alignment: u32,
+    plane_alignment: u32,
 ) -> RutabagaResult<ImageMemoryRequirements> {
     let mut image_requirements: ImageMemoryRequirements = Default::default();
     let mut size: u32 = 0;
     let layout = info.drm_format.planar_layout()?;
     for plane in 0..layout.num_planes {
-        let plane_stride = stride_from_layout(&layout, info.width, plane)?;
+        let packed_stride = stride_from_layout(&layout, info.width, plane)?;
+        let plane_stride = align_up(packed_stride, stride_alignment)?;
         image_requirements.strides[plane] = plane_stride;
         if plane > 0 {
+            size = align_up(size, plane_alignment)?;
             image_requirements.offsets[plane] = size;
         }
 
@@ -258,6 +270,16 @@ pub fn canonical_image_requirements(
     Ok(image_requirements)
 }
 
+fn align_up(value: u32, alignment: u32) -> RutabagaResult<u32> {
+    value
+        .checked_next_multiple_of(alignment)
+        .ok_or(RutabagaError::CheckedArithmetic {
+            field1: ("value", value as usize),
+            field2: ("alignment", alignment as usize),
+            op: "align",
+        })
+}
+
 #[cfg(test)]
 mod tests {
     use std::fmt::Write;
@@ -285,6 +307,7 @@ mod tests {
             height: 10,
             drm_format: DrmFormat::new(b'R', b'8', b' ', b' '),
             flags: RutabagaGrallocFlags::empty(),
+            ..Default::default()
         };
 
         let r8_reqs = canonical_image_requirements(info).unwrap();
@@ -324,6 +347,7 @@ mod tests {
             height: 10,
             drm_format: DrmFormat::new(b'N', b'V', b'1', b'2'),
             flags: RutabagaGrallocFlags::empty(),
+            ..Default::default()
         };
 
         let nv12_reqs = canonical_image_requirements(info).unwrap();
@@ -355,4 +379,27 @@ mod tests {
 
         assert_eq!(yv12_reqs.size, 150);
     }
+
+    #[test]
+    fn aligned_planar_formats() {
+        let info = ImageAllocationInfo {
+            width: 10,
+            height: 3,
+            drm_format: DrmFormat::new(b'N', b'V', b'1', b'2'),
+            flags: RutabagaGrallocFlags::empty(),
+            ..Default::default()
+        };
+
+        let nv12_reqs = aligned_image_requirements(info, 256, 512).unwrap();
+
+        assert_eq!(nv12_reqs.strides[0], 256);
+        assert_eq!(nv12_reqs.strides[1], 256);
+        assert_eq!(nv12_reqs.strides[2], 0);
+
+        assert_eq!(nv12_reqs.offsets[0], 0);
+        assert_eq!(nv12_reqs.offsets[1], 1024);
+        assert_eq!(nv12_reqs.offsets[2], 0);
+
+        assert_eq!(nv12_reqs.size, 1280);
+    }
 }
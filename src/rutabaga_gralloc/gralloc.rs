@@ -7,7 +7,8 @@
 
 use std::collections::BTreeMap as Map;
 
-#[cfg(feature = "vulkano")]
+use log::debug;
+#[cfg(any(feature = "vulkano", all(windows, feature = "magma")))]
 use log::error;
 use mesa3d_util::round_up_to_page_size;
 use mesa3d_util::MappedRegion;
@@ -20,6 +21,8 @@ use crate::rutabaga_gralloc::minigbm::MinigbmDevice;
 use crate::rutabaga_gralloc::system_gralloc::SystemGralloc;
 #[cfg(feature = "vulkano")]
 use crate::rutabaga_gralloc::vulkano_gralloc::VulkanoGralloc;
+#[cfg(all(windows, feature = "magma"))]
+use crate::rutabaga_gralloc::wddm_gralloc::WddmGralloc;
 use crate::rutabaga_utils::RutabagaError;
 use crate::rutabaga_utils::RutabagaResult;
 use crate::rutabaga_utils::VulkanInfo;
@@ -27,6 +30,7 @@ use crate::rutabaga_utils::VulkanInfo;
 const RUTABAGA_GRALLOC_BACKEND_SYSTEM: u32 = 1 << 0;
 const RUTABAGA_GRALLOC_BACKEND_GBM: u32 = 1 << 1;
 const RUTABAGA_GRALLOC_BACKEND_VULKANO: u32 = 1 << 2;
+const RUTABAGA_GRALLOC_BACKEND_WDDM: u32 = 1 << 3;
 
 /// Usage flags for constructing rutabaga gralloc backend
 #[derive(Copy, Clone, Eq, PartialEq, Default)]
@@ -39,7 +43,8 @@ impl RutabagaGrallocBackendFlags {
         RutabagaGrallocBackendFlags(
             RUTABAGA_GRALLOC_BACKEND_SYSTEM
                 | RUTABAGA_GRALLOC_BACKEND_GBM
-                | RUTABAGA_GRALLOC_BACKEND_VULKANO,
+                | RUTABAGA_GRALLOC_BACKEND_VULKANO
+                | RUTABAGA_GRALLOC_BACKEND_WDDM,
         )
     }
 
@@ -59,6 +64,10 @@ impl RutabagaGrallocBackendFlags {
     pub fn uses_vulkano(&self) -> bool {
         self.0 & RUTABAGA_GRALLOC_BACKEND_VULKANO != 0
     }
+
+    pub fn uses_wddm(&self) -> bool {
+        self.0 & RUTABAGA_GRALLOC_BACKEND_WDDM != 0
+    }
 }
 
 /*
@@ -71,7 +80,6 @@ const RUTABAGA_GRALLOC_USE_LINEAR: u32 = 1 << 4;
 const RUTABAGA_GRALLOC_USE_TEXTURING: u32 = 1 << 5;
 const RUTABAGA_GRALLOC_USE_CAMERA_WRITE: u32 = 1 << 6;
 const RUTABAGA_GRALLOC_USE_CAMERA_READ: u32 = 1 << 7;
-#[allow(dead_code)]
 const RUTABAGA_GRALLOC_USE_PROTECTED: u32 = 1 << 8;
 
 /* SW_{WRITE,READ}_RARELY omitted since not even Android uses this much. */
@@ -151,6 +159,28 @@ impl RutabagaGrallocFlags {
         }
     }
 
+    /// Sets the protected flag's presence.
+    #[inline(always)]
+    pub fn use_protected(self, e: bool) -> RutabagaGrallocFlags {
+        if e {
+            RutabagaGrallocFlags(self.0 | RUTABAGA_GRALLOC_USE_PROTECTED)
+        } else {
+            RutabagaGrallocFlags(self.0 & !RUTABAGA_GRALLOC_USE_PROTECTED)
+        }
+    }
+
+    /// Returns true if the scanout flag is set.
+    #[inline(always)]
+    pub fn uses_scanout(self) -> bool {
+        self.0 & RUTABAGA_GRALLOC_USE_SCANOUT != 0
+    }
+
+    /// Returns true if the linear flag is set.
+    #[inline(always)]
+    pub fn uses_linear(self) -> bool {
+        self.0 & RUTABAGA_GRALLOC_USE_LINEAR != 0
+    }
+
     /// Returns true if the texturing flag is set.
     #[inline(always)]
     pub fn uses_texturing(self) -> bool {
@@ -163,6 +193,12 @@ impl RutabagaGrallocFlags {
         self.0 & RUTABAGA_GRALLOC_USE_RENDERING != 0
     }
 
+    /// Returns true if the protected flag is set.
+    #[inline(always)]
+    pub fn uses_protected(self) -> bool {
+        self.0 & RUTABAGA_GRALLOC_USE_PROTECTED != 0
+    }
+
     /// Returns true if the memory will accessed by the CPU or an IP block that prefers host
     /// visible allocations (i.e, camera).
     #[inline(always)]
@@ -182,6 +218,14 @@ impl RutabagaGrallocFlags {
     }
 }
 
+/// The maximum number of DRM format modifiers that may be proposed for a single allocation.
+pub const RUTABAGA_GRALLOC_MAX_MODIFIERS: usize = 16;
+
+/// Layout of a buffer without any tiling or compression.
+pub const DRM_FORMAT_MOD_LINEAR: u64 = 0;
+/// Sentinel for an unknown or implicit layout.
+pub const DRM_FORMAT_MOD_INVALID: u64 = 0x00ff_ffff_ffff_ffff;
+
 /// Information required to allocate a swapchain image.
 #[derive(Copy, Clone, Default)]
 pub struct ImageAllocationInfo {
@@ -189,6 +233,32 @@ pub struct ImageAllocationInfo {
     pub height: u32,
     pub drm_format: DrmFormat,
     pub flags: RutabagaGrallocFlags,
+    /// Explicit list of acceptable DRM format modifiers, as negotiated by the guest.  Only the
+    /// first `num_modifiers` entries are valid.  An empty list lets the backend pick a layout
+    /// based on `flags` alone.
+    pub modifiers: [u64; RUTABAGA_GRALLOC_MAX_MODIFIERS],
+    pub num_modifiers: u32,
+}
+
+impl ImageAllocationInfo {
+    /// Sets the list of acceptable DRM format modifiers.  Fails if more than
+    /// `RUTABAGA_GRALLOC_MAX_MODIFIERS` are given.
+    pub fn set_modifiers(&mut self, modifiers: &[u64]) -> RutabagaResult<()> {
+        if modifiers.len() > RUTABAGA_GRALLOC_MAX_MODIFIERS {
+            return Err(MesaError::WithContext("too many format modifiers").into());
+        }
+
+        self.modifiers = Default::default();
+        self.modifiers[..modifiers.len()].copy_from_slice(modifiers);
+        self.num_modifiers = modifiers.len() as u32;
+        Ok(())
+    }
+
+    /// Returns the list of acceptable DRM format modifiers.
+    pub fn modifiers(&self) -> &[u64] {
+        let count = (self.num_modifiers as usize).min(RUTABAGA_GRALLOC_MAX_MODIFIERS);
+        &self.modifiers[..count]
+    }
 }
 
 /// The memory requirements, compression and layout of a swapchain image.
@@ -201,6 +271,9 @@ pub struct ImageMemoryRequirements {
     pub modifier: u64,
     pub size: u64,
     pub vulkan_info: Option<VulkanInfo>,
+    /// The backend that computed the requirements, which also allocates with them.  Set by
+    /// `RutabagaGralloc`.
+    pub backend: Option<GrallocBackend>,
 }
 
 /// Trait that needs to be implemented to service graphics memory requests.  Two step allocation
@@ -219,6 +292,12 @@ pub trait Gralloc: Send {
     /// dma-buf.  This often used for sharing with the scanout engine or multimedia subsystems.
     fn supports_dmabuf(&self) -> bool;
 
+    /// Implementations must return true if they can allocate protected memory, which is only
+    /// accessible to the GPU and secure IP blocks (e.g. Intel PXP).
+    fn supports_protected(&self) -> bool {
+        false
+    }
+
     /// Implementations must return the resource layout, compression, and caching properties of
     /// an allocation request.
     fn get_image_memory_requirements(
@@ -226,10 +305,28 @@ pub trait Gralloc: Send {
         info: ImageAllocationInfo,
     ) -> RutabagaResult<ImageMemoryRequirements>;
 
+    /// Implementations should return the DRM format modifiers they can allocate images of
+    /// `drm_format` with for the usage in `flags`.  An empty list means they can't tell.
+    fn query_modifiers(
+        &mut self,
+        _drm_format: DrmFormat,
+        _flags: RutabagaGrallocFlags,
+    ) -> RutabagaResult<Vec<u64>> {
+        Ok(Vec::new())
+    }
+
     /// Implementations must allocate memory given the requirements and return a MesaHandle
     /// upon success.
     fn allocate_memory(&mut self, reqs: ImageMemoryRequirements) -> RutabagaResult<MesaHandle>;
 
+    /// Implementations must return the handle type `allocate_memory` would return for `reqs`,
+    /// without allocating.
+    fn allocation_handle_type(&self, reqs: &ImageMemoryRequirements) -> RutabagaResult<u32>;
+
+    /// Implementations must return the mask (see `handle_type_mask()`) of every handle type
+    /// `allocate_memory` may return.
+    fn handle_types(&self) -> u32;
+
     /// Implementations must import the given `handle` and return a mapping, suitable for use with
     /// KVM and other hypervisors.  This is optional and only works with the Vulkano backend.
     fn import_and_map(
@@ -243,18 +340,73 @@ pub trait Gralloc: Send {
 }
 
 /// Enumeration of possible allocation backends.
-#[derive(Copy, Clone, Eq, PartialEq, PartialOrd, Ord)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
 pub enum GrallocBackend {
-    #[allow(dead_code)]
     Vulkano,
-    #[allow(dead_code)]
     Minigbm,
     System,
+    Wddm,
+}
+
+/// Backend preferences for allocations of a given format and usage.
+#[derive(Clone)]
+pub struct RutabagaGrallocRule {
+    /// The format the rule applies to, or every format if `None`.
+    pub drm_format: Option<DrmFormat>,
+    /// The rule applies to allocations using at least these flags.
+    pub usage: RutabagaGrallocFlags,
+    /// Backends in order of preference.
+    pub backends: Vec<GrallocBackend>,
+}
+
+impl RutabagaGrallocRule {
+    fn matches(&self, info: &ImageAllocationInfo) -> bool {
+        self.drm_format
+            .map_or(true, |format| format == info.drm_format)
+            && info.flags.0 & self.usage.0 == self.usage.0
+    }
+}
+
+/// Per-format and per-usage backend preferences.  Rules are tried in the order they were added.
+/// A backend that failed to initialize, or can't honor explicit modifiers, is skipped, so the
+/// next preference applies at runtime.  So is a backend that fails to compute the requirements of
+/// an allocation.  Allocations without a usable preference use the default choice of
+/// `RutabagaGralloc`.
+#[derive(Clone, Default)]
+pub struct RutabagaGrallocPolicy {
+    rules: Vec<RutabagaGrallocRule>,
+}
+
+impl RutabagaGrallocPolicy {
+    pub fn new() -> RutabagaGrallocPolicy {
+        Default::default()
+    }
+
+    /// Prefers `backends`, in order, for allocations of `drm_format` (or any format if `None`)
+    /// using at least the `usage` flags.
+    pub fn prefer(
+        mut self,
+        drm_format: Option<DrmFormat>,
+        usage: RutabagaGrallocFlags,
+        backends: &[GrallocBackend],
+    ) -> RutabagaGrallocPolicy {
+        self.rules.push(RutabagaGrallocRule {
+            drm_format,
+            usage,
+            backends: backends.to_vec(),
+        });
+        self
+    }
+
+    pub fn rules(&self) -> &[RutabagaGrallocRule] {
+        &self.rules
+    }
 }
 
 /// A container for a variety of allocation backends.
 pub struct RutabagaGralloc {
     grallocs: Map<GrallocBackend, Box<dyn Gralloc>>,
+    policy: RutabagaGrallocPolicy,
 }
 
 impl RutabagaGralloc {
@@ -292,7 +444,22 @@ impl RutabagaGralloc {
             }
         }
 
-        Ok(RutabagaGralloc { grallocs })
+        #[cfg(all(windows, feature = "magma"))]
+        if flags.uses_wddm() {
+            match WddmGralloc::init() {
+                Ok(wddm) => {
+                    grallocs.insert(GrallocBackend::Wddm, wddm);
+                }
+                Err(e) => {
+                    error!("failed to init WDDM gralloc: {:?}", e);
+                }
+            }
+        }
+
+        Ok(RutabagaGralloc {
+            grallocs,
+            policy: Default::default(),
+        })
     }
 
     /// Returns true if one of the allocation backends supports GPU external memory.
@@ -317,8 +484,58 @@ impl RutabagaGralloc {
         false
     }
 
+    /// Returns true if one of the allocation backends supports protected memory.
+    pub fn supports_protected(&self) -> bool {
+        self.grallocs
+            .values()
+            .any(|gralloc| gralloc.supports_protected())
+    }
+
+    /// Replaces the backend preferences of future allocations.
+    pub fn set_policy(&mut self, policy: RutabagaGrallocPolicy) {
+        self.policy = policy;
+    }
+
+    /// Returns the active backend preferences.
+    pub fn policy(&self) -> &RutabagaGrallocPolicy {
+        &self.policy
+    }
+
+    /// Returns the backend that would service an allocation described by `info`.
+    pub fn backend_for(&self, info: &ImageAllocationInfo) -> GrallocBackend {
+        self.determine_optimal_backend(*info)
+    }
+
     /// Returns the best allocation backend to service a particular request.
-    fn determine_optimal_backend(&self, _info: ImageAllocationInfo) -> GrallocBackend {
+    fn determine_optimal_backend(&self, info: ImageAllocationInfo) -> GrallocBackend {
+        self.candidate_backends(info)[0]
+    }
+
+    // Returns the backends that may service a particular request, in order: the usable
+    // preferences of the policy, then the default choice.
+    fn candidate_backends(&self, info: ImageAllocationInfo) -> Vec<GrallocBackend> {
+        let preferred = self
+            .policy
+            .rules
+            .iter()
+            .filter(|rule| rule.matches(&info))
+            .flat_map(|rule| rule.backends.iter().copied())
+            .filter(|backend| {
+                self.grallocs.get(backend).is_some_and(|gralloc| {
+                    !info.flags.uses_protected() || gralloc.supports_protected()
+                }) && (info.modifiers().is_empty() || *backend == GrallocBackend::Minigbm)
+            });
+
+        let mut backends = Vec::new();
+        for backend in preferred.chain([self.determine_default_backend(info)]) {
+            if !backends.contains(&backend) {
+                backends.push(backend);
+            }
+        }
+        backends
+    }
+
+    fn determine_default_backend(&self, _info: ImageAllocationInfo) -> GrallocBackend {
         // This function could be more sophisticated and consider the allocation info.  For example,
         // nobody has ever tried Mali allocated memory + a mediatek/rockchip display and as such it
         // probably doesn't work.  In addition, YUV calculations in minigbm have yet to make it
@@ -336,34 +553,119 @@ impl RutabagaGralloc {
             }
         }
 
+        #[cfg(all(windows, feature = "magma"))]
+        {
+            // Host compositors on Windows import NT handles to GPU memory more readily than
+            // sections.
+            if self.grallocs.contains_key(&GrallocBackend::Wddm) {
+                _backend = GrallocBackend::Wddm;
+            }
+        }
+
         #[cfg(feature = "vulkano")]
         {
-            _backend = GrallocBackend::Vulkano;
+            // VulkanoGralloc::init() fails on hosts without a usable Vulkan driver.
+            if self.grallocs.contains_key(&GrallocBackend::Vulkano) {
+                _backend = GrallocBackend::Vulkano;
+            }
+        }
+
+        // Explicit modifier negotiation is only implemented by minigbm.
+        #[cfg(feature = "gbm")]
+        {
+            if !_info.modifiers().is_empty() && self.grallocs.contains_key(&GrallocBackend::Minigbm)
+            {
+                _backend = GrallocBackend::Minigbm;
+            }
+        }
+
+        if _info.flags.uses_protected() {
+            if let Some((backend, _)) = self
+                .grallocs
+                .iter()
+                .find(|(_, gralloc)| gralloc.supports_protected())
+            {
+                _backend = *backend;
+            }
         }
 
         _backend
     }
 
-    /// Returns a image memory requirements for the given `info` upon success.
+    /// Returns a image memory requirements for the given `info` upon success.  Backends are tried
+    /// in order of preference, until one succeeds.
     pub fn get_image_memory_requirements(
         &mut self,
         info: ImageAllocationInfo,
     ) -> RutabagaResult<ImageMemoryRequirements> {
-        let backend = self.determine_optimal_backend(info);
+        let mut result = Err(RutabagaError::InvalidGrallocBackend);
+        for backend in self.candidate_backends(info) {
+            result = self.backend_image_memory_requirements(backend, info);
+            match &result {
+                Ok(_) => break,
+                Err(e) => debug!("gralloc backend {:?} can't allocate image: {}", backend, e),
+            }
+        }
 
+        result
+    }
+
+    fn backend_image_memory_requirements(
+        &mut self,
+        backend: GrallocBackend,
+        info: ImageAllocationInfo,
+    ) -> RutabagaResult<ImageMemoryRequirements> {
         let gralloc = self
             .grallocs
             .get_mut(&backend)
             .ok_or(RutabagaError::InvalidGrallocBackend)?;
 
+        if info.flags.uses_protected() {
+            if !gralloc.supports_protected() {
+                return Err(MesaError::Unsupported.into());
+            }
+
+            // The CPU can't access protected memory.
+            if info.flags.host_visible() {
+                return Err(MesaError::WithContext("protected memory isn't host visible").into());
+            }
+        }
+
         let mut reqs = gralloc.get_image_memory_requirements(info)?;
         reqs.size = round_up_to_page_size(reqs.size)?;
+        reqs.backend = Some(backend);
         Ok(reqs)
     }
 
+    /// Returns the DRM format modifiers images of `drm_format` can be allocated with for the usage
+    /// in `flags`, as reported by the backend that negotiates explicit modifiers.
+    pub fn query_modifiers(
+        &mut self,
+        drm_format: DrmFormat,
+        flags: RutabagaGrallocFlags,
+    ) -> RutabagaResult<Vec<u64>> {
+        // Route the query like an allocation proposing modifiers.
+        let mut info = ImageAllocationInfo {
+            drm_format,
+            flags,
+            ..Default::default()
+        };
+        info.set_modifiers(&[DRM_FORMAT_MOD_LINEAR])?;
+        let backend = self.determine_optimal_backend(info);
+
+        let gralloc = self
+            .grallocs
+            .get_mut(&backend)
+            .ok_or(RutabagaError::InvalidGrallocBackend)?;
+
+        gralloc.query_modifiers(drm_format, flags)
+    }
+
     /// Allocates memory given the particular `reqs` upon success.
     pub fn allocate_memory(&mut self, reqs: ImageMemoryRequirements) -> RutabagaResult<MesaHandle> {
-        let backend = self.determine_optimal_backend(reqs.info);
+        let backend = reqs
+            .backend
+            .unwrap_or_else(|| self.determine_optimal_backend(reqs.info));
 
         let gralloc = self
             .grallocs
@@ -373,6 +675,27 @@ impl RutabagaGralloc {
         gralloc.allocate_memory(reqs)
     }
 
+    /// Returns the handle type `allocate_memory` would return for `reqs`, without allocating.
+    pub fn allocation_handle_type(&self, reqs: &ImageMemoryRequirements) -> RutabagaResult<u32> {
+        let backend = reqs
+            .backend
+            .unwrap_or_else(|| self.determine_optimal_backend(reqs.info));
+
+        let gralloc = self
+            .grallocs
+            .get(&backend)
+            .ok_or(RutabagaError::InvalidGrallocBackend)?;
+
+        gralloc.allocation_handle_type(reqs)
+    }
+
+    /// Returns the mask of handle types the allocation backends may return.
+    pub fn handle_types(&self) -> u32 {
+        self.grallocs
+            .values()
+            .fold(0, |mask, gralloc| mask | gralloc.handle_types())
+    }
+
     /// Imports the `handle` using the given `vulkan_info`.  Returns a mapping using Vulkano upon
     /// success.  Should not be used with minigbm or system gralloc backends.
     pub fn import_and_map(
@@ -409,6 +732,7 @@ mod tests {
             height: 1024,
             drm_format: DrmFormat::new(b'X', b'R', b'2', b'4'),
             flags: RutabagaGrallocFlags::empty().use_scanout(true),
+            ..Default::default()
         };
 
         let reqs = gralloc.get_image_memory_requirements(info).unwrap();
@@ -423,6 +747,123 @@ mod tests {
         let _handle2 = gralloc.allocate_memory(reqs).unwrap();
     }
 
+    #[test]
+    fn policy_skips_unavailable_backends() {
+        let flags = RutabagaGrallocBackendFlags(RUTABAGA_GRALLOC_BACKEND_SYSTEM);
+        let mut gralloc = RutabagaGralloc::new(flags).unwrap();
+
+        let nv12 = DrmFormat::new(b'N', b'V', b'1', b'2');
+        let policy = RutabagaGrallocPolicy::new()
+            .prefer(
+                None,
+                RutabagaGrallocFlags::empty().use_scanout(true),
+                &[GrallocBackend::Vulkano],
+            )
+            .prefer(
+                Some(nv12),
+                RutabagaGrallocFlags::empty(),
+                &[GrallocBackend::Minigbm, GrallocBackend::System],
+            );
+        gralloc.set_policy(policy);
+        assert_eq!(gralloc.policy().rules().len(), 2);
+
+        let info = ImageAllocationInfo {
+            width: 64,
+            height: 64,
+            drm_format: nv12,
+            flags: RutabagaGrallocFlags::empty().use_linear(true),
+            ..Default::default()
+        };
+
+        assert!(!gralloc.policy().rules()[0].matches(&info));
+        assert!(gralloc.policy().rules()[1].matches(&info));
+        assert_eq!(gralloc.backend_for(&info), GrallocBackend::System);
+    }
+
+    // A backend that can't allocate any image.
+    struct FailingGralloc;
+
+    impl Gralloc for FailingGralloc {
+        fn supports_external_gpu_memory(&self) -> bool {
+            false
+        }
+
+        fn supports_dmabuf(&self) -> bool {
+            false
+        }
+
+        fn get_image_memory_requirements(
+            &mut self,
+            _info: ImageAllocationInfo,
+        ) -> RutabagaResult<ImageMemoryRequirements> {
+            Err(MesaError::Unsupported.into())
+        }
+
+        fn allocate_memory(
+            &mut self,
+            _reqs: ImageMemoryRequirements,
+        ) -> RutabagaResult<MesaHandle> {
+            Err(MesaError::Unsupported.into())
+        }
+
+        fn allocation_handle_type(&self, _reqs: &ImageMemoryRequirements) -> RutabagaResult<u32> {
+            Err(MesaError::Unsupported.into())
+        }
+
+        fn handle_types(&self) -> u32 {
+            0
+        }
+    }
+
+    #[test]
+    fn policy_falls_back_on_failure() {
+        let flags = RutabagaGrallocBackendFlags(RUTABAGA_GRALLOC_BACKEND_SYSTEM);
+        let mut gralloc = RutabagaGralloc::new(flags).unwrap();
+        gralloc
+            .grallocs
+            .insert(GrallocBackend::Minigbm, Box::new(FailingGralloc));
+        gralloc.set_policy(RutabagaGrallocPolicy::new().prefer(
+            None,
+            RutabagaGrallocFlags::empty(),
+            &[GrallocBackend::Minigbm],
+        ));
+
+        let info = ImageAllocationInfo {
+            width: 64,
+            height: 64,
+            drm_format: DrmFormat::new(b'X', b'R', b'2', b'4'),
+            flags: RutabagaGrallocFlags::empty().use_linear(true),
+            ..Default::default()
+        };
+        assert_eq!(gralloc.backend_for(&info), GrallocBackend::Minigbm);
+
+        // The default backend takes over, and allocates with its own requirements.
+        let reqs = gralloc.get_image_memory_requirements(info).unwrap();
+        assert_eq!(reqs.backend, Some(GrallocBackend::System));
+        assert_eq!(
+            gralloc.allocation_handle_type(&reqs).unwrap(),
+            mesa3d_util::MESA_HANDLE_TYPE_MEM_SHM
+        );
+        gralloc.allocate_memory(reqs).unwrap();
+    }
+
+    #[test]
+    fn protected_requires_capable_backend() {
+        let flags = RutabagaGrallocBackendFlags(RUTABAGA_GRALLOC_BACKEND_SYSTEM);
+        let mut gralloc = RutabagaGralloc::new(flags).unwrap();
+        assert!(!gralloc.supports_protected());
+
+        let info = ImageAllocationInfo {
+            width: 64,
+            height: 64,
+            drm_format: DrmFormat::new(b'X', b'R', b'2', b'4'),
+            flags: RutabagaGrallocFlags::empty().use_protected(true),
+            ..Default::default()
+        };
+
+        assert!(gralloc.get_image_memory_requirements(info).is_err());
+    }
+
     #[test]
     #[cfg_attr(target_os = "windows", ignore)]
     fn create_video_buffer() {
@@ -438,6 +879,7 @@ mod tests {
             height: 1024,
             drm_format: DrmFormat::new(b'N', b'V', b'1', b'2'),
             flags: RutabagaGrallocFlags::empty().use_linear(true),
+            ..Default::default()
         };
 
         let reqs = gralloc.get_image_memory_requirements(info).unwrap();
@@ -461,6 +903,61 @@ mod tests {
         let _handle2 = gralloc.allocate_memory(reqs).unwrap();
     }
 
+    #[test]
+    #[cfg_attr(target_os = "windows", ignore)]
+    fn create_with_modifiers() {
+        let gralloc_result = RutabagaGralloc::new(RutabagaGrallocBackendFlags::new());
+        if gralloc_result.is_err() {
+            return;
+        }
+
+        let mut gralloc = gralloc_result.unwrap();
+
+        let mut info = ImageAllocationInfo {
+            width: 512,
+            height: 1024,
+            drm_format: DrmFormat::new(b'X', b'R', b'2', b'4'),
+            flags: RutabagaGrallocFlags::empty().use_linear(true),
+            ..Default::default()
+        };
+
+        info.set_modifiers(&[DRM_FORMAT_MOD_LINEAR]).unwrap();
+        assert_eq!(info.modifiers(), &[DRM_FORMAT_MOD_LINEAR]);
+
+        let reqs = gralloc.get_image_memory_requirements(info).unwrap();
+        assert_eq!(reqs.modifier, DRM_FORMAT_MOD_LINEAR);
+
+        let _handle = gralloc.allocate_memory(reqs).unwrap();
+
+        let too_many = [DRM_FORMAT_MOD_LINEAR; RUTABAGA_GRALLOC_MAX_MODIFIERS + 1];
+        assert!(info.set_modifiers(&too_many).is_err());
+    }
+
+    #[test]
+    #[cfg_attr(target_os = "windows", ignore)]
+    fn query_linear_modifiers() {
+        let flags = RutabagaGrallocFlags::empty().use_linear(true);
+        let xr24 = DrmFormat::new(b'X', b'R', b'2', b'4');
+
+        // The system backend only allocates linear images, of formats it knows the layout of.
+        let mut system =
+            RutabagaGralloc::new(RutabagaGrallocBackendFlags(RUTABAGA_GRALLOC_BACKEND_SYSTEM))
+                .unwrap();
+        assert_eq!(
+            system.query_modifiers(xr24, flags).unwrap(),
+            [DRM_FORMAT_MOD_LINEAR]
+        );
+        assert!(system
+            .query_modifiers(DrmFormat::new(b'?', b'?', b'?', b'?'), flags)
+            .unwrap()
+            .is_empty());
+
+        // Whichever backend answers must be able to allocate linear XR24 images.
+        let mut gralloc = RutabagaGralloc::new(RutabagaGrallocBackendFlags::new()).unwrap();
+        let modifiers = gralloc.query_modifiers(xr24, flags).unwrap();
+        assert!(modifiers.contains(&DRM_FORMAT_MOD_LINEAR));
+    }
+
     #[test]
     #[cfg_attr(target_os = "windows", ignore)]
     fn export_and_map() {
@@ -479,6 +976,7 @@ mod tests {
                 .use_linear(true)
                 .use_sw_write(true)
                 .use_sw_read(true),
+            ..Default::default()
         };
 
         let mut reqs = gralloc.get_image_memory_requirements(info).unwrap();
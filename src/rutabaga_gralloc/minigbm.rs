@@ -13,6 +13,7 @@ use std::io::Error;
 use std::io::Seek;
 use std::io::SeekFrom;
 use std::os::fd::FromRawFd;
+use std::os::raw::c_uint;
 use std::sync::Arc;
 
 use mesa3d_util::FromRawDescriptor;
@@ -24,12 +25,47 @@ use crate::rutabaga_gralloc::formats::DrmFormat;
 use crate::rutabaga_gralloc::gralloc::Gralloc;
 use crate::rutabaga_gralloc::gralloc::ImageAllocationInfo;
 use crate::rutabaga_gralloc::gralloc::ImageMemoryRequirements;
+use crate::rutabaga_gralloc::gralloc::RutabagaGrallocFlags;
+use crate::rutabaga_gralloc::gralloc::DRM_FORMAT_MOD_INVALID;
+use crate::rutabaga_gralloc::gralloc::DRM_FORMAT_MOD_LINEAR;
 use crate::rutabaga_gralloc::minigbm_bindings::*;
+use crate::rutabaga_utils::handle_type_mask;
 use crate::rutabaga_utils::RutabagaError;
 use crate::rutabaga_utils::RutabagaResult;
 use crate::rutabaga_utils::RUTABAGA_MAP_CACHE_CACHED;
 use crate::rutabaga_utils::RUTABAGA_MAP_CACHE_WC;
 
+// fourcc_mod_code() of drm_fourcc.h.
+const fn fourcc_mod_code(vendor: u64, value: u64) -> u64 {
+    (vendor << 56) | (value & 0x00ff_ffff_ffff_ffff)
+}
+
+const DRM_FORMAT_MOD_VENDOR_INTEL: u64 = 0x01;
+const DRM_FORMAT_MOD_VENDOR_QCOM: u64 = 0x05;
+
+// The explicit modifiers minigbm's backends allocate with.  gbm can't enumerate the modifiers of a
+// format, so `query_modifiers` tries each with a test allocation.  Modifiers encoding
+// device-specific parameters, like AMD's, are only found as the ones minigbm picks on its own.
+const MINIGBM_PROBED_MODIFIERS: [u64; 9] = [
+    DRM_FORMAT_MOD_LINEAR,
+    // I915_FORMAT_MOD_X_TILED
+    fourcc_mod_code(DRM_FORMAT_MOD_VENDOR_INTEL, 1),
+    // I915_FORMAT_MOD_Y_TILED
+    fourcc_mod_code(DRM_FORMAT_MOD_VENDOR_INTEL, 2),
+    // I915_FORMAT_MOD_Y_TILED_CCS
+    fourcc_mod_code(DRM_FORMAT_MOD_VENDOR_INTEL, 4),
+    // I915_FORMAT_MOD_Y_TILED_GEN12_RC_CCS
+    fourcc_mod_code(DRM_FORMAT_MOD_VENDOR_INTEL, 6),
+    // I915_FORMAT_MOD_4_TILED
+    fourcc_mod_code(DRM_FORMAT_MOD_VENDOR_INTEL, 9),
+    // I915_FORMAT_MOD_4_TILED_DG2_RC_CCS
+    fourcc_mod_code(DRM_FORMAT_MOD_VENDOR_INTEL, 10),
+    // I915_FORMAT_MOD_4_TILED_MTL_RC_CCS
+    fourcc_mod_code(DRM_FORMAT_MOD_VENDOR_INTEL, 13),
+    // DRM_FORMAT_MOD_QCOM_COMPRESSED
+    fourcc_mod_code(DRM_FORMAT_MOD_VENDOR_QCOM, 1),
+];
+
 struct MinigbmDeviceInner {
     _fd: File,
     gbm: *mut gbm_device,
@@ -57,6 +93,7 @@ impl Drop for MinigbmDeviceInner {
 pub struct MinigbmDevice {
     minigbm_device: Arc<MinigbmDeviceInner>,
     last_buffer: Option<Arc<MinigbmBuffer>>,
+    supports_protected: bool,
 }
 
 impl MinigbmDevice {
@@ -79,14 +116,103 @@ impl MinigbmDevice {
             descriptor = File::from_raw_fd(fd);
         }
 
+        // Drivers only list protected usage for formats they can allocate from protected memory.
+        // SAFETY:
+        // Safe because the gbm device was just created, and the call only reads its format table.
+        let supports_protected = unsafe {
+            gbm_device_is_format_supported(
+                gbm,
+                DrmFormat::new(b'X', b'R', b'2', b'4').0,
+                GBM_BO_USE_PROTECTED,
+            ) != 0
+        };
+
         Ok(Box::new(MinigbmDevice {
             minigbm_device: Arc::new(MinigbmDeviceInner {
                 _fd: descriptor,
                 gbm,
             }),
             last_buffer: None,
+            supports_protected,
         }))
     }
+
+    /// Filters the modifiers proposed in `info` down to the ones this device can allocate for the
+    /// requested format and usage.  Linear usage restricts the list to `DRM_FORMAT_MOD_LINEAR`.
+    fn supported_modifiers(&self, info: &ImageAllocationInfo) -> Vec<u64> {
+        info.modifiers()
+            .iter()
+            .copied()
+            .filter(|&modifier| modifier != DRM_FORMAT_MOD_INVALID)
+            .filter(|&modifier| !info.flags.uses_linear() || modifier == DRM_FORMAT_MOD_LINEAR)
+            .filter(|&modifier| {
+                // SAFETY:
+                // Safe because the gbm device is valid for the lifetime of self.
+                let plane_count = unsafe {
+                    gbm_device_get_format_modifier_plane_count(
+                        self.minigbm_device.gbm,
+                        info.drm_format.0,
+                        modifier,
+                    )
+                };
+                plane_count > 0
+            })
+            .collect()
+    }
+
+    /// Allocates a buffer object for `info`.  When the guest proposes explicit modifiers, minigbm
+    /// chooses among them while still honoring the usage flags (i.e, scanout vs. texturing).
+    fn create_buffer(&self, info: ImageAllocationInfo) -> RutabagaResult<MinigbmBuffer> {
+        let bo = if info.modifiers().is_empty() {
+            // SAFETY:
+            // Safe because the gbm device is valid and minigbm validates the arguments.
+            unsafe {
+                gbm_bo_create(
+                    self.minigbm_device.gbm,
+                    info.width,
+                    info.height,
+                    info.drm_format.0,
+                    info.flags.0,
+                )
+            }
+        } else {
+            let modifiers = self.supported_modifiers(&info);
+            if modifiers.is_empty() {
+                return Err(RutabagaError::InvalidGrallocModifier);
+            }
+
+            // SAFETY:
+            // Safe because the gbm device is valid and `modifiers` outlives the call with exactly
+            // `modifiers.len()` entries.
+            unsafe {
+                gbm_bo_create_with_modifiers2(
+                    self.minigbm_device.gbm,
+                    info.width,
+                    info.height,
+                    info.drm_format.0,
+                    modifiers.as_ptr(),
+                    modifiers.len() as c_uint,
+                    info.flags.0,
+                )
+            }
+        };
+
+        if bo.is_null() {
+            return Err(MesaError::IoError(Error::last_os_error()).into());
+        }
+
+        let gbm_buffer = MinigbmBuffer {
+            bo,
+            _device: self.clone(),
+        };
+
+        let modifiers = info.modifiers();
+        if !modifiers.is_empty() && !modifiers.contains(&gbm_buffer.format_modifier()) {
+            return Err(RutabagaError::InvalidGrallocModifier);
+        }
+
+        Ok(gbm_buffer)
+    }
 }
 
 impl Gralloc for MinigbmDevice {
@@ -98,30 +224,16 @@ impl Gralloc for MinigbmDevice {
         true
     }
 
+    fn supports_protected(&self) -> bool {
+        self.supports_protected
+    }
+
     fn get_image_memory_requirements(
         &mut self,
         info: ImageAllocationInfo,
     ) -> RutabagaResult<ImageMemoryRequirements> {
-        // TODO(b/315870313): Add safety comment
-        #[allow(clippy::undocumented_unsafe_blocks)]
-        let bo = unsafe {
-            gbm_bo_create(
-                self.minigbm_device.gbm,
-                info.width,
-                info.height,
-                info.drm_format.0,
-                info.flags.0,
-            )
-        };
-        if bo.is_null() {
-            return Err(MesaError::IoError(Error::last_os_error()).into());
-        }
-
+        let gbm_buffer = self.create_buffer(info)?;
         let mut reqs: ImageMemoryRequirements = Default::default();
-        let gbm_buffer = MinigbmBuffer {
-            bo,
-            _device: self.clone(),
-        };
 
         if gbm_buffer.cached() {
             reqs.map_info = RUTABAGA_MAP_CACHE_CACHED;
@@ -151,6 +263,41 @@ impl Gralloc for MinigbmDevice {
         Ok(reqs)
     }
 
+    // The modifier minigbm picks for the usage, and each of MINIGBM_PROBED_MODIFIERS it can
+    // allocate with, are found with small test allocations.
+    fn query_modifiers(
+        &mut self,
+        drm_format: DrmFormat,
+        flags: RutabagaGrallocFlags,
+    ) -> RutabagaResult<Vec<u64>> {
+        let info = ImageAllocationInfo {
+            width: 64,
+            height: 64,
+            drm_format,
+            flags,
+            ..Default::default()
+        };
+
+        let mut probes = vec![info];
+        for modifier in MINIGBM_PROBED_MODIFIERS {
+            let mut probe = info;
+            probe.set_modifiers(&[modifier])?;
+            probes.push(probe);
+        }
+
+        let mut modifiers = Vec::new();
+        for probe in probes {
+            if let Ok(gbm_buffer) = self.create_buffer(probe) {
+                let modifier = gbm_buffer.format_modifier();
+                if modifier != DRM_FORMAT_MOD_INVALID && !modifiers.contains(&modifier) {
+                    modifiers.push(modifier);
+                }
+            }
+        }
+
+        Ok(modifiers)
+    }
+
     fn allocate_memory(&mut self, reqs: ImageMemoryRequirements) -> RutabagaResult<MesaHandle> {
         let last_buffer = self.last_buffer.take();
         if let Some(gbm_buffer) = last_buffer {
@@ -168,32 +315,25 @@ impl Gralloc for MinigbmDevice {
             });
         }
 
-        // TODO(b/315870313): Add safety comment
-        #[allow(clippy::undocumented_unsafe_blocks)]
-        let bo = unsafe {
-            gbm_bo_create(
-                self.minigbm_device.gbm,
-                reqs.info.width,
-                reqs.info.height,
-                reqs.info.drm_format.0,
-                reqs.info.flags.0,
-            )
-        };
-
-        if bo.is_null() {
-            return Err(MesaError::IoError(Error::last_os_error()).into());
+        let gbm_buffer = self.create_buffer(reqs.info)?;
+        if !reqs.info.modifiers().is_empty() && gbm_buffer.format_modifier() != reqs.modifier {
+            return Err(RutabagaError::InvalidGrallocModifier);
         }
 
-        let gbm_buffer = MinigbmBuffer {
-            bo,
-            _device: self.clone(),
-        };
         let dmabuf = gbm_buffer.export()?.into();
         Ok(MesaHandle {
             os_handle: dmabuf,
             handle_type: MESA_HANDLE_TYPE_MEM_DMABUF,
         })
     }
+
+    fn allocation_handle_type(&self, _reqs: &ImageMemoryRequirements) -> RutabagaResult<u32> {
+        Ok(MESA_HANDLE_TYPE_MEM_DMABUF)
+    }
+
+    fn handle_types(&self) -> u32 {
+        handle_type_mask(&[MESA_HANDLE_TYPE_MEM_DMABUF])
+    }
 }
 
 /// An allocation from a `MinigbmDevice`.
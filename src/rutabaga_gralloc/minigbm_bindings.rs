@@ -110,6 +110,17 @@ extern "C" {
         count: c_uint,
     ) -> *mut gbm_bo;
 }
+extern "C" {
+    pub fn gbm_bo_create_with_modifiers2(
+        gbm: *mut gbm_device,
+        width: u32,
+        height: u32,
+        format: u32,
+        modifiers: *const u64,
+        count: c_uint,
+        flags: u32,
+    ) -> *mut gbm_bo;
+}
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct gbm_import_fd_data {
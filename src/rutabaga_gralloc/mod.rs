@@ -14,10 +14,17 @@ mod minigbm;
 mod minigbm_bindings;
 mod system_gralloc;
 mod vulkano_gralloc;
+mod wddm_gralloc;
 
 pub use formats::DrmFormat;
+pub use gralloc::GrallocBackend;
 pub use gralloc::ImageAllocationInfo;
 pub use gralloc::ImageMemoryRequirements;
 pub use gralloc::RutabagaGralloc;
 pub use gralloc::RutabagaGrallocBackendFlags;
 pub use gralloc::RutabagaGrallocFlags;
+pub use gralloc::RutabagaGrallocPolicy;
+pub use gralloc::RutabagaGrallocRule;
+pub use gralloc::DRM_FORMAT_MOD_INVALID;
+pub use gralloc::DRM_FORMAT_MOD_LINEAR;
+pub use gralloc::RUTABAGA_GRALLOC_MAX_MODIFIERS;
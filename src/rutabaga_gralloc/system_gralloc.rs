@@ -10,9 +10,14 @@ use mesa3d_util::SharedMemory;
 use mesa3d_util::MESA_HANDLE_TYPE_MEM_SHM;
 
 use crate::rutabaga_gralloc::formats::canonical_image_requirements;
+use crate::rutabaga_gralloc::formats::DrmFormat;
 use crate::rutabaga_gralloc::gralloc::Gralloc;
 use crate::rutabaga_gralloc::gralloc::ImageAllocationInfo;
 use crate::rutabaga_gralloc::gralloc::ImageMemoryRequirements;
+use crate::rutabaga_gralloc::gralloc::RutabagaGrallocFlags;
+use crate::rutabaga_gralloc::gralloc::DRM_FORMAT_MOD_LINEAR;
+use crate::rutabaga_utils::handle_type_mask;
+use crate::rutabaga_utils::RutabagaError;
 use crate::rutabaga_utils::RutabagaResult;
 use crate::rutabaga_utils::RUTABAGA_MAP_CACHE_CACHED;
 
@@ -43,11 +48,30 @@ impl Gralloc for SystemGralloc {
         &mut self,
         info: ImageAllocationInfo,
     ) -> RutabagaResult<ImageMemoryRequirements> {
+        // System memory allocations are always linear.
+        let modifiers = info.modifiers();
+        if !modifiers.is_empty() && !modifiers.contains(&DRM_FORMAT_MOD_LINEAR) {
+            return Err(RutabagaError::InvalidGrallocModifier);
+        }
+
         let mut reqs = canonical_image_requirements(info)?;
         reqs.map_info = RUTABAGA_MAP_CACHE_CACHED;
+        reqs.modifier = DRM_FORMAT_MOD_LINEAR;
         Ok(reqs)
     }
 
+    fn query_modifiers(
+        &mut self,
+        drm_format: DrmFormat,
+        _flags: RutabagaGrallocFlags,
+    ) -> RutabagaResult<Vec<u64>> {
+        if drm_format.planar_layout().is_err() {
+            return Ok(Vec::new());
+        }
+
+        Ok(vec![DRM_FORMAT_MOD_LINEAR])
+    }
+
     fn allocate_memory(&mut self, reqs: ImageMemoryRequirements) -> RutabagaResult<MesaHandle> {
         let shm = SharedMemory::new("rutabaga_gralloc", reqs.size)?;
         Ok(MesaHandle {
@@ -55,4 +79,12 @@ impl Gralloc for SystemGralloc {
             handle_type: MESA_HANDLE_TYPE_MEM_SHM,
         })
     }
+
+    fn allocation_handle_type(&self, _reqs: &ImageMemoryRequirements) -> RutabagaResult<u32> {
+        Ok(MESA_HANDLE_TYPE_MEM_SHM)
+    }
+
+    fn handle_types(&self) -> u32 {
+        handle_type_mask(&[MESA_HANDLE_TYPE_MEM_SHM])
+    }
 }
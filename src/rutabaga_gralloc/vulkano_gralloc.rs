@@ -28,6 +28,7 @@ use vulkano::device::DeviceCreateInfo;
 use vulkano::device::DeviceCreationError;
 use vulkano::device::QueueCreateInfo;
 use vulkano::device::QueueFlags;
+use vulkano::format::FormatFeatures;
 use vulkano::image;
 use vulkano::image::ImageDimensions;
 use vulkano::image::ImageError;
@@ -54,9 +55,13 @@ use vulkano::LoadingError;
 use vulkano::VulkanError;
 use vulkano::VulkanLibrary;
 
+use crate::rutabaga_gralloc::formats::DrmFormat;
 use crate::rutabaga_gralloc::gralloc::Gralloc;
 use crate::rutabaga_gralloc::gralloc::ImageAllocationInfo;
 use crate::rutabaga_gralloc::gralloc::ImageMemoryRequirements;
+use crate::rutabaga_gralloc::gralloc::RutabagaGrallocFlags;
+use crate::rutabaga_gralloc::gralloc::DRM_FORMAT_MOD_LINEAR;
+use crate::rutabaga_utils::handle_type_mask;
 use crate::rutabaga_utils::DeviceId;
 use crate::rutabaga_utils::RutabagaError;
 use crate::rutabaga_utils::RutabagaResult;
@@ -432,20 +437,56 @@ impl Gralloc for VulkanoGralloc {
         Ok(reqs)
     }
 
+    // Images are allocated without explicit modifiers, and their layouts are queried as linear
+    // ones, so linear is the only modifier, if the device supports the format for the usage.
+    fn query_modifiers(
+        &mut self,
+        drm_format: DrmFormat,
+        flags: RutabagaGrallocFlags,
+    ) -> RutabagaResult<Vec<u64>> {
+        let vulkan_format = match drm_format.vulkan_format() {
+            Ok(vulkan_format) => vulkan_format,
+            Err(_) => return Ok(Vec::new()),
+        };
+
+        let device_type = if self.has_integrated_gpu {
+            &PhysicalDeviceType::IntegratedGpu
+        } else {
+            &PhysicalDeviceType::DiscreteGpu
+        };
+
+        let device = self
+            .devices
+            .get(device_type)
+            .ok_or(RutabagaError::InvalidGrallocGpuType)?;
+
+        let required_features = match flags.uses_rendering() {
+            true => FormatFeatures::COLOR_ATTACHMENT,
+            false => FormatFeatures::SAMPLED_IMAGE,
+        };
+
+        let format_properties = device.physical_device().format_properties(vulkan_format);
+        if !format_properties
+            .linear_tiling_features
+            .contains(required_features)
+        {
+            return Ok(Vec::new());
+        }
+
+        Ok(vec![DRM_FORMAT_MOD_LINEAR])
+    }
+
     fn allocate_memory(&mut self, reqs: ImageMemoryRequirements) -> RutabagaResult<MesaHandle> {
         let (raw_image, memory_requirements) = unsafe { self.create_image(reqs.info)? };
 
         let vulkan_info = reqs.vulkan_info.ok_or(RutabagaError::InvalidVulkanInfo)?;
 
-        let device = if self.has_integrated_gpu {
-            self.devices
-                .get(&PhysicalDeviceType::IntegratedGpu)
-                .ok_or(RutabagaError::InvalidGrallocGpuType)?
-        } else {
-            self.devices
-                .get(&PhysicalDeviceType::DiscreteGpu)
-                .ok_or(RutabagaError::InvalidGrallocGpuType)?
-        };
+        // Allocate on the device the requirements were computed for, so the memory index and
+        // device UUIDs reported to the guest stay valid.
+        let device = self
+            .device_by_id
+            .get(&vulkan_info.device_id)
+            .ok_or(RutabagaError::InvalidVulkanInfo)?;
 
         if vulkan_info.memory_idx as usize
             >= device
@@ -501,6 +542,33 @@ impl Gralloc for VulkanoGralloc {
         })
     }
 
+    fn allocation_handle_type(&self, reqs: &ImageMemoryRequirements) -> RutabagaResult<u32> {
+        let vulkan_info = reqs.vulkan_info.ok_or(RutabagaError::InvalidVulkanInfo)?;
+        let device = self
+            .device_by_id
+            .get(&vulkan_info.device_id)
+            .ok_or(RutabagaError::InvalidVulkanInfo)?;
+
+        match device.enabled_extensions().ext_external_memory_dma_buf {
+            true => Ok(MESA_HANDLE_TYPE_MEM_DMABUF),
+            false => Ok(MESA_HANDLE_TYPE_MEM_OPAQUE_FD),
+        }
+    }
+
+    fn handle_types(&self) -> u32 {
+        let handle_types: Vec<u32> = self
+            .device_by_id
+            .values()
+            .map(
+                |device| match device.enabled_extensions().ext_external_memory_dma_buf {
+                    true => MESA_HANDLE_TYPE_MEM_DMABUF,
+                    false => MESA_HANDLE_TYPE_MEM_OPAQUE_FD,
+                },
+            )
+            .collect();
+        handle_type_mask(&handle_types)
+    }
+
     /// Implementations must map the memory associated with the `resource_id` upon success.
     fn import_and_map(
         &mut self,
@@ -0,0 +1,228 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! wddm_gralloc: allocates exportable GPU memory on Windows hosts, through magma's D3DKMT
+//! backend.  Allocations are shared as NT handles, which host compositors can open with D3D.
+//!
+//! Images are allocated as plain buffers, not textures.  Their planes are laid out as D3D12
+//! placed subresource footprints, so a compositor can open a buffer with D3D12 and copy it into a
+//! texture of the DXGI format matching its DRM format, with the strides and offsets given to the
+//! guest.
+
+#![cfg(all(windows, feature = "magma"))]
+
+use log::error;
+use mesa3d_magma::magma_enumerate_devices;
+use mesa3d_magma::MagmaCreateBufferInfo;
+use mesa3d_magma::MagmaDevice;
+use mesa3d_magma::MAGMA_BUFFER_FLAG_EXTERNAL;
+use mesa3d_magma::MAGMA_MEMORY_PROPERTY_DEVICE_LOCAL_BIT;
+use mesa3d_magma::MAGMA_MEMORY_PROPERTY_HOST_CACHED_BIT;
+use mesa3d_magma::MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT;
+use mesa3d_util::MesaError;
+use mesa3d_util::MesaHandle;
+use mesa3d_util::MESA_HANDLE_TYPE_MEM_OPAQUE_WIN32;
+
+use crate::magma::magma_error;
+use crate::rutabaga_gralloc::formats::aligned_image_requirements;
+use crate::rutabaga_gralloc::formats::DrmFormat;
+use crate::rutabaga_gralloc::formats::DRM_FORMAT_ABGR16161616F;
+use crate::rutabaga_gralloc::formats::DRM_FORMAT_ABGR2101010;
+use crate::rutabaga_gralloc::formats::DRM_FORMAT_ABGR8888;
+use crate::rutabaga_gralloc::formats::DRM_FORMAT_ARGB8888;
+use crate::rutabaga_gralloc::formats::DRM_FORMAT_NV12;
+use crate::rutabaga_gralloc::formats::DRM_FORMAT_R8;
+use crate::rutabaga_gralloc::formats::DRM_FORMAT_RGB565;
+use crate::rutabaga_gralloc::formats::DRM_FORMAT_XBGR2101010;
+use crate::rutabaga_gralloc::formats::DRM_FORMAT_XBGR8888;
+use crate::rutabaga_gralloc::formats::DRM_FORMAT_XRGB8888;
+use crate::rutabaga_gralloc::gralloc::Gralloc;
+use crate::rutabaga_gralloc::gralloc::ImageAllocationInfo;
+use crate::rutabaga_gralloc::gralloc::ImageMemoryRequirements;
+use crate::rutabaga_gralloc::gralloc::RutabagaGrallocFlags;
+use crate::rutabaga_gralloc::gralloc::DRM_FORMAT_MOD_LINEAR;
+use crate::rutabaga_utils::handle_type_mask;
+use crate::rutabaga_utils::RutabagaError;
+use crate::rutabaga_utils::RutabagaResult;
+use crate::rutabaga_utils::RUTABAGA_MAP_CACHE_CACHED;
+use crate::rutabaga_utils::RUTABAGA_MAP_CACHE_WC;
+
+// D3D12_TEXTURE_DATA_PITCH_ALIGNMENT and D3D12_TEXTURE_DATA_PLACEMENT_ALIGNMENT: the row pitch and
+// plane offset alignments of placed subresource footprints.
+const D3D12_TEXTURE_DATA_PITCH_ALIGNMENT: u32 = 256;
+const D3D12_TEXTURE_DATA_PLACEMENT_ALIGNMENT: u32 = 512;
+
+/// Returns whether `drm_format` has a DXGI format with the same memory layout.  Host compositors
+/// can't copy images of other formats into textures, so they aren't allocated.
+fn has_dxgi_format(drm_format: DrmFormat) -> bool {
+    matches!(
+        drm_format.to_bytes(),
+        DRM_FORMAT_R8
+            | DRM_FORMAT_RGB565
+            | DRM_FORMAT_ARGB8888
+            | DRM_FORMAT_XRGB8888
+            | DRM_FORMAT_ABGR8888
+            | DRM_FORMAT_XBGR8888
+            | DRM_FORMAT_ABGR2101010
+            | DRM_FORMAT_XBGR2101010
+            | DRM_FORMAT_ABGR16161616F
+            | DRM_FORMAT_NV12
+    )
+}
+
+// The PCI vendor id of the Microsoft Basic Render Driver, which renders on the CPU.
+const MICROSOFT_VENDOR_ID: u16 = 0x1414;
+
+/// Returns the index and property flags of the memory type allocations should use, and whether
+/// it is device local.  Guests map the allocations, so they must be host visible.  Device local
+/// memory is preferred, since host compositors sample from it.
+fn select_memory_type(device: &MagmaDevice) -> RutabagaResult<Option<(u32, u32, bool)>> {
+    let mem_props = device.get_memory_properties().map_err(magma_error)?;
+    let memory_types = &mem_props.memory_types[..mem_props.memory_type_count as usize];
+    let host_visible = |flags: u32| flags & MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT != 0;
+    let device_local = |flags: u32| flags & MAGMA_MEMORY_PROPERTY_DEVICE_LOCAL_BIT != 0;
+
+    let selected = memory_types
+        .iter()
+        .position(|t| host_visible(t.property_flags) && device_local(t.property_flags))
+        .or_else(|| {
+            memory_types
+                .iter()
+                .position(|t| host_visible(t.property_flags))
+        })
+        .map(|idx| {
+            let flags = memory_types[idx].property_flags;
+            (idx as u32, flags, device_local(flags))
+        });
+
+    Ok(selected)
+}
+
+/// A gralloc implementation allocating host visible memory of a host GPU.
+pub struct WddmGralloc {
+    device: MagmaDevice,
+    memory_type_idx: u32,
+    map_info: u32,
+}
+
+impl WddmGralloc {
+    /// Returns a new `WddmGralloc` instance, or an error if there is no GPU with host visible
+    /// memory.
+    ///
+    /// Hardware adapters are preferred over the software one, and adapters with host visible
+    /// device local memory over the others.  Ties go to the adapter enumerated first.
+    pub fn init() -> RutabagaResult<Box<dyn Gralloc>> {
+        let mut selected: Option<(MagmaDevice, u32, u32, (bool, bool))> = None;
+        for physical_device in magma_enumerate_devices().map_err(magma_error)? {
+            let hardware = physical_device.pci_info().vendor_id != MICROSOFT_VENDOR_ID;
+            let device = match physical_device.create_device() {
+                Ok(device) => device,
+                Err(e) => {
+                    error!("failed to open wddm adapter: {e}");
+                    continue;
+                }
+            };
+
+            let Some((idx, flags, device_local)) = select_memory_type(&device)? else {
+                continue;
+            };
+
+            let rank = (hardware, device_local);
+            let better = match &selected {
+                Some((_, _, _, selected_rank)) => rank > *selected_rank,
+                None => true,
+            };
+            if better {
+                selected = Some((device, idx, flags, rank));
+            }
+        }
+
+        let (device, memory_type_idx, property_flags, _) = selected.ok_or(
+            MesaError::WithContext("no wddm adapter with host visible memory found"),
+        )?;
+
+        let map_info = if property_flags & MAGMA_MEMORY_PROPERTY_HOST_CACHED_BIT != 0 {
+            RUTABAGA_MAP_CACHE_CACHED
+        } else {
+            RUTABAGA_MAP_CACHE_WC
+        };
+
+        Ok(Box::new(WddmGralloc {
+            device,
+            memory_type_idx,
+            map_info,
+        }))
+    }
+}
+
+impl Gralloc for WddmGralloc {
+    fn supports_external_gpu_memory(&self) -> bool {
+        true
+    }
+
+    fn supports_dmabuf(&self) -> bool {
+        false
+    }
+
+    fn get_image_memory_requirements(
+        &mut self,
+        info: ImageAllocationInfo,
+    ) -> RutabagaResult<ImageMemoryRequirements> {
+        // Without vendor specific layouts, allocations are always linear.
+        let modifiers = info.modifiers();
+        if !modifiers.is_empty() && !modifiers.contains(&DRM_FORMAT_MOD_LINEAR) {
+            return Err(RutabagaError::InvalidGrallocModifier);
+        }
+
+        if !has_dxgi_format(info.drm_format) {
+            return Err(RutabagaError::InvalidGrallocDrmFormat);
+        }
+
+        let mut reqs = aligned_image_requirements(
+            info,
+            D3D12_TEXTURE_DATA_PITCH_ALIGNMENT,
+            D3D12_TEXTURE_DATA_PLACEMENT_ALIGNMENT,
+        )?;
+        reqs.map_info = self.map_info;
+        reqs.modifier = DRM_FORMAT_MOD_LINEAR;
+        Ok(reqs)
+    }
+
+    fn query_modifiers(
+        &mut self,
+        drm_format: DrmFormat,
+        _flags: RutabagaGrallocFlags,
+    ) -> RutabagaResult<Vec<u64>> {
+        if !has_dxgi_format(drm_format) {
+            return Ok(Vec::new());
+        }
+
+        Ok(vec![DRM_FORMAT_MOD_LINEAR])
+    }
+
+    fn allocate_memory(&mut self, reqs: ImageMemoryRequirements) -> RutabagaResult<MesaHandle> {
+        let create_info = MagmaCreateBufferInfo {
+            memory_type_idx: self.memory_type_idx,
+            alignment: 0,
+            common_flags: MAGMA_BUFFER_FLAG_EXTERNAL,
+            vendor_flags: 0,
+            size: reqs.size,
+        };
+
+        // The NT handle keeps the shared resource alive once the buffer is dropped.
+        let buffer = self
+            .device
+            .create_buffer(&create_info)
+            .map_err(magma_error)?;
+        buffer.export().map_err(magma_error)
+    }
+
+    fn allocation_handle_type(&self, _reqs: &ImageMemoryRequirements) -> RutabagaResult<u32> {
+        Ok(MESA_HANDLE_TYPE_MEM_OPAQUE_WIN32)
+    }
+
+    fn handle_types(&self) -> u32 {
+        handle_type_mask(&[MESA_HANDLE_TYPE_MEM_OPAQUE_WIN32])
+    }
+}
@@ -0,0 +1,449 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! rutabaga_hostmem: places mappable blobs into the shared memory region the VMM exposes to the
+//! guest, the host side of VIRTIO_GPU_CMD_RESOURCE_MAP_BLOB.
+//!
+//! The VMM reserves the region in its address space and maps it into the guest at
+//! `guest_address`.  Rutabaga hands out page aligned slots of the region to mapped blobs, and
+//! keeps unused slots reserved with inaccessible anonymous memory, so nothing else lands there.
+//! Blobs are populated when mapped, so the guest doesn't fault on them, except blobs of at least
+//! `lazy_map_threshold` bytes, whose pages the kernel allocates as the guest first touches them.
+//! `Rutabaga::debug_info()` reports how many pages of such blobs are resident.
+
+use std::collections::BTreeMap as Map;
+use std::io::Error as SysError;
+
+use mesa3d_util::AsRawDescriptor;
+use mesa3d_util::MesaError;
+use mesa3d_util::MesaHandle;
+use mesa3d_util::MESA_HANDLE_TYPE_MEM_DMABUF;
+use mesa3d_util::MESA_HANDLE_TYPE_MEM_OPAQUE_FD;
+use mesa3d_util::MESA_HANDLE_TYPE_MEM_SHM;
+
+use crate::rutabaga_utils::RutabagaResult;
+use crate::rutabaga_utils::RUTABAGA_MAP_ACCESS_MASK;
+use crate::rutabaga_utils::RUTABAGA_MAP_ACCESS_READ;
+use crate::rutabaga_utils::RUTABAGA_MAP_ACCESS_RW;
+use crate::rutabaga_utils::RUTABAGA_MAP_ACCESS_WRITE;
+use crate::rutabaga_utils::RUTABAGA_MAP_CACHE_CACHED;
+use crate::rutabaga_utils::RUTABAGA_MAP_CACHE_MASK;
+
+/// The shared memory region of a virtio-gpu device, which the VMM reserved at `host_address` and
+/// exposes to the guest at `guest_address`.  Both must be page aligned, as must `size`.
+#[derive(Copy, Clone, Debug)]
+pub struct RutabagaSharedMemoryRegion {
+    pub guest_address: u64,
+    pub host_address: u64,
+    pub size: u64,
+    /// Shared memory blobs of at least this many bytes are mapped without populating them, so
+    /// the pages the guest never touches are never allocated.  None populates every blob up
+    /// front.
+    pub lazy_map_threshold: Option<u64>,
+}
+
+/// Where `Rutabaga::map_blob()` placed a blob, for the VIRTIO_GPU_CMD_RESOURCE_MAP_BLOB response.
+#[derive(Copy, Clone, Debug)]
+pub struct RutabagaBlobPlacement {
+    /// The offset of the blob into the shared memory region.
+    pub offset: u64,
+    /// The cache and access attributes the guest must map the blob with.
+    pub map_info: u32,
+    /// Whether the blob is populated lazily.
+    pub lazy: bool,
+}
+
+pub(crate) struct RutabagaHostmem {
+    region: RutabagaSharedMemoryRegion,
+    page_size: u64,
+    // The blob at each occupied offset.
+    slots: Map<u64, RutabagaHostmemSlot>,
+}
+
+struct RutabagaHostmemSlot {
+    resource_id: u32,
+    size: u64,
+    lazy: bool,
+}
+
+impl RutabagaHostmem {
+    pub(crate) fn new(region: RutabagaSharedMemoryRegion) -> RutabagaResult<RutabagaHostmem> {
+        // SAFETY:
+        // sysconf has no preconditions.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        let page_size: u64 = page_size.try_into().map_err(MesaError::TryFromIntError)?;
+
+        let aligned = [region.guest_address, region.host_address, region.size]
+            .iter()
+            .all(|value| value % page_size == 0);
+        if !aligned || region.size == 0 {
+            return Err(MesaError::WithContext("shared memory region isn't page aligned").into());
+        }
+
+        Ok(RutabagaHostmem {
+            region,
+            page_size,
+            slots: Map::new(),
+        })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    pub(crate) fn contains(&self, resource_id: u32) -> bool {
+        self.slots
+            .values()
+            .any(|slot| slot.resource_id == resource_id)
+    }
+
+    pub(crate) fn host_address(&self, offset: u64) -> u64 {
+        self.region.host_address + offset
+    }
+
+    /// Reserves a slot of `size` bytes for the resource, at `offset` if the guest chose one or at
+    /// the first free offset otherwise.
+    pub(crate) fn allocate(
+        &mut self,
+        resource_id: u32,
+        size: u64,
+        offset: Option<u64>,
+    ) -> RutabagaResult<u64> {
+        if self.contains(resource_id) {
+            return Err(
+                MesaError::WithContext("blob is already in the shared memory region").into(),
+            );
+        }
+
+        let size = size
+            .checked_next_multiple_of(self.page_size)
+            .filter(|size| *size != 0)
+            .ok_or(MesaError::WithContext("invalid blob size"))?;
+
+        let offset = match offset {
+            Some(offset) => {
+                if offset % self.page_size != 0 {
+                    return Err(MesaError::WithContext("blob offset isn't page aligned").into());
+                }
+
+                let overlaps = self
+                    .slots
+                    .iter()
+                    .any(|(start, slot)| offset < start + slot.size && *start < offset + size);
+                if overlaps {
+                    return Err(MesaError::WithContext("blob offset is already in use").into());
+                }
+
+                offset
+            }
+            None => {
+                let mut candidate = 0;
+                for (start, slot) in &self.slots {
+                    if candidate + size <= *start {
+                        break;
+                    }
+                    candidate = start + slot.size;
+                }
+                candidate
+            }
+        };
+
+        let fits = offset
+            .checked_add(size)
+            .is_some_and(|end| end <= self.region.size);
+        if !fits {
+            return Err(MesaError::WithContext("shared memory region is full").into());
+        }
+
+        self.slots.insert(
+            offset,
+            RutabagaHostmemSlot {
+                resource_id,
+                size,
+                lazy: false,
+            },
+        );
+        Ok(offset)
+    }
+
+    /// Maps `handle` over the slot at `offset`, with the access given by `map_info`.  Returns
+    /// whether the mapping is populated lazily, and the map info the guest must use, whose cache
+    /// attributes match those of the host mapping.
+    pub(crate) fn map_handle(
+        &mut self,
+        offset: u64,
+        handle: &MesaHandle,
+        map_info: u32,
+    ) -> RutabagaResult<(bool, u32)> {
+        let mappable = [
+            MESA_HANDLE_TYPE_MEM_DMABUF,
+            MESA_HANDLE_TYPE_MEM_OPAQUE_FD,
+            MESA_HANDLE_TYPE_MEM_SHM,
+        ];
+        if !mappable.contains(&handle.handle_type) {
+            return Err(MesaError::WithContext("blob handle can't be mapped").into());
+        }
+
+        let prot = match map_info & RUTABAGA_MAP_ACCESS_MASK {
+            RUTABAGA_MAP_ACCESS_READ => libc::PROT_READ,
+            RUTABAGA_MAP_ACCESS_WRITE => libc::PROT_WRITE,
+            RUTABAGA_MAP_ACCESS_RW => libc::PROT_READ | libc::PROT_WRITE,
+            _ => return Err(MesaError::WithContext("incorrect access flags").into()),
+        };
+
+        // Dmabuf exporters choose the caching of their own mmaps, which the component reported.
+        // Other handles are ordinary memory, which the host maps write-back, so the guest must
+        // map them cached too to stay coherent with the host.
+        let map_info = match handle.handle_type {
+            MESA_HANDLE_TYPE_MEM_DMABUF => map_info,
+            _ => (map_info & !RUTABAGA_MAP_CACHE_MASK) | RUTABAGA_MAP_CACHE_CACHED,
+        };
+
+        let host_address = self.host_address(offset);
+        let threshold = self.region.lazy_map_threshold;
+        let slot = self
+            .slots
+            .get_mut(&offset)
+            .ok_or(MesaError::WithContext("no blob at offset"))?;
+
+        // Dmabuf exporters populate their mappings themselves, behind the page cache, so only
+        // other handles are left to fault in.
+        let lazy = handle.handle_type != MESA_HANDLE_TYPE_MEM_DMABUF
+            && threshold.is_some_and(|threshold| slot.size >= threshold);
+        let populate = if lazy { 0 } else { libc::MAP_POPULATE };
+
+        // SAFETY:
+        // The slot lies within the region the VMM reserved for rutabaga, so replacing it affects
+        // no other mapping.  The handle is a valid descriptor.
+        let addr = unsafe {
+            libc::mmap(
+                host_address as *mut libc::c_void,
+                slot.size as usize,
+                prot,
+                libc::MAP_SHARED | libc::MAP_FIXED | populate,
+                handle.os_handle.as_raw_descriptor(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(MesaError::IoError(SysError::last_os_error()).into());
+        }
+
+        slot.lazy = lazy;
+        Ok((lazy, map_info))
+    }
+
+    /// Returns how many pages of the resource's lazy mapping are resident.
+    pub(crate) fn resident_pages(&self, resource_id: u32) -> Option<u64> {
+        let (offset, slot) = self
+            .slots
+            .iter()
+            .find(|(_, slot)| slot.resource_id == resource_id && slot.lazy)?;
+
+        let mut residency = vec![0u8; (slot.size / self.page_size) as usize];
+        // SAFETY:
+        // The slot is mapped, and the vector has a byte for each of its pages.
+        let ret = unsafe {
+            libc::mincore(
+                self.host_address(*offset) as *mut libc::c_void,
+                slot.size as usize,
+                residency.as_mut_ptr(),
+            )
+        };
+        if ret != 0 {
+            return None;
+        }
+
+        Some(residency.iter().filter(|page| *page & 1 != 0).count() as u64)
+    }
+
+    /// Frees the slot of the resource, reserving its pages again.  Returns the slot's offset.
+    pub(crate) fn release(&mut self, resource_id: u32) -> RutabagaResult<u64> {
+        let (offset, size) = self
+            .slots
+            .iter()
+            .find(|(_, slot)| slot.resource_id == resource_id)
+            .map(|(offset, slot)| (*offset, slot.size))
+            .ok_or(MesaError::WithContext(
+                "blob isn't in the shared memory region",
+            ))?;
+        self.slots.remove(&offset);
+
+        // SAFETY:
+        // The slot lies within the region the VMM reserved for rutabaga, so replacing it affects
+        // no other mapping.
+        let addr = unsafe {
+            libc::mmap(
+                self.host_address(offset) as *mut libc::c_void,
+                size as usize,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE | libc::MAP_FIXED,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(MesaError::IoError(SysError::last_os_error()).into());
+        }
+
+        Ok(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mesa3d_util::SharedMemory;
+
+    use super::*;
+    use crate::rutabaga_utils::RUTABAGA_MAP_CACHE_WC;
+
+    fn page_size() -> u64 {
+        // SAFETY:
+        // sysconf has no preconditions.
+        unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 }
+    }
+
+    // Address space reserved like a VMM would, and released when the test is done with it.  It
+    // must be declared before the RutabagaHostmem using it, so it outlives it.
+    struct Reservation {
+        addr: u64,
+        size: u64,
+    }
+
+    impl Reservation {
+        fn new(size: u64) -> Reservation {
+            // SAFETY:
+            // An anonymous mapping at an address of the kernel's choosing affects no other
+            // mapping.
+            let addr = unsafe {
+                libc::mmap(
+                    std::ptr::null_mut(),
+                    size as usize,
+                    libc::PROT_NONE,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                    -1,
+                    0,
+                )
+            };
+            assert_ne!(addr, libc::MAP_FAILED);
+            Reservation {
+                addr: addr as u64,
+                size,
+            }
+        }
+    }
+
+    impl Drop for Reservation {
+        fn drop(&mut self) {
+            // SAFETY:
+            // The range was reserved by `new()`, and nothing uses it anymore.
+            unsafe { libc::munmap(self.addr as *mut libc::c_void, self.size as usize) };
+        }
+    }
+
+    #[test]
+    fn allocate_slots() {
+        let page = page_size();
+        let reservation = Reservation::new(4 * page);
+        let mut hostmem = RutabagaHostmem::new(RutabagaSharedMemoryRegion {
+            guest_address: 0x1_0000_0000,
+            host_address: reservation.addr,
+            size: 4 * page,
+            lazy_map_threshold: None,
+        })
+        .unwrap();
+
+        assert_eq!(hostmem.allocate(1, 1, None).unwrap(), 0);
+        assert_eq!(hostmem.allocate(2, page + 1, None).unwrap(), page);
+        assert!(hostmem.allocate(2, page, None).is_err());
+        assert!(hostmem.allocate(3, page, Some(2 * page)).is_err());
+        assert!(hostmem.allocate(3, 2 * page, None).is_err());
+        assert_eq!(hostmem.allocate(3, page, Some(3 * page)).unwrap(), 3 * page);
+
+        // Freed slots are reused first.
+        assert_eq!(hostmem.release(1).unwrap(), 0);
+        assert_eq!(hostmem.allocate(4, page, None).unwrap(), 0);
+        assert!(hostmem.release(1).is_err());
+    }
+
+    #[test]
+    fn map_shm_handle() {
+        let page = page_size();
+        let reservation = Reservation::new(2 * page);
+        let mut hostmem = RutabagaHostmem::new(RutabagaSharedMemoryRegion {
+            guest_address: 0,
+            host_address: reservation.addr,
+            size: 2 * page,
+            lazy_map_threshold: None,
+        })
+        .unwrap();
+
+        let handle = MesaHandle {
+            os_handle: SharedMemory::new("hostmem", page).unwrap().into(),
+            handle_type: MESA_HANDLE_TYPE_MEM_SHM,
+        };
+        let offset = hostmem.allocate(1, page, Some(page)).unwrap();
+
+        // Shared memory is mapped write-back, whatever the component asked for.
+        let (lazy, map_info) = hostmem
+            .map_handle(
+                offset,
+                &handle,
+                RUTABAGA_MAP_ACCESS_RW | RUTABAGA_MAP_CACHE_WC,
+            )
+            .unwrap();
+        assert!(!lazy);
+        assert_eq!(map_info, RUTABAGA_MAP_ACCESS_RW | RUTABAGA_MAP_CACHE_CACHED);
+        assert_eq!(hostmem.resident_pages(1), None);
+
+        let ptr = hostmem.host_address(offset) as *mut u8;
+        // SAFETY:
+        // The page was just mapped read-write.
+        unsafe {
+            ptr.write(0xab);
+            assert_eq!(ptr.read(), 0xab);
+        }
+
+        hostmem.release(1).unwrap();
+        assert!(hostmem.is_empty());
+    }
+
+    #[test]
+    fn lazy_map_shm_handle() {
+        let page = page_size();
+        let reservation = Reservation::new(4 * page);
+        let mut hostmem = RutabagaHostmem::new(RutabagaSharedMemoryRegion {
+            guest_address: 0,
+            host_address: reservation.addr,
+            size: 4 * page,
+            lazy_map_threshold: Some(2 * page),
+        })
+        .unwrap();
+
+        let handle = MesaHandle {
+            os_handle: SharedMemory::new("hostmem", 2 * page).unwrap().into(),
+            handle_type: MESA_HANDLE_TYPE_MEM_SHM,
+        };
+        let offset = hostmem.allocate(1, 2 * page, None).unwrap();
+        assert!(
+            hostmem
+                .map_handle(offset, &handle, RUTABAGA_MAP_ACCESS_RW)
+                .unwrap()
+                .0
+        );
+        assert_eq!(hostmem.resident_pages(1), Some(0));
+
+        let ptr = hostmem.host_address(offset) as *mut u8;
+        // SAFETY:
+        // The pages were just mapped read-write.
+        unsafe {
+            ptr.write(0xab);
+            assert_eq!(ptr.read(), 0xab);
+        }
+        assert_eq!(hostmem.resident_pages(1), Some(1));
+
+        hostmem.release(1).unwrap();
+        assert_eq!(hostmem.resident_pages(1), None);
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! rutabaga_lost: tracks contexts lost to host GPU resets, so their fences complete and the VMM
+//! can tell the guest.
+
+use std::collections::BTreeMap as Map;
+use std::collections::BTreeSet as Set;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use crate::rutabaga_utils::RutabagaComponentType;
+use crate::rutabaga_utils::RutabagaDeviceLost;
+use crate::rutabaga_utils::RutabagaDeviceLostHandler;
+use crate::rutabaga_utils::RutabagaFence;
+use crate::rutabaga_utils::RutabagaFenceHandler;
+use crate::rutabaga_utils::RutabagaFenceKey;
+use crate::rutabaga_utils::RutabagaHandler;
+use crate::rutabaga_utils::RUTABAGA_FLAG_CONTEXT_LOST;
+use crate::rutabaga_utils::RUTABAGA_FLAG_INFO_RING_IDX;
+
+#[derive(Default)]
+struct RutabagaLostInner {
+    // The component of every live context.
+    contexts: Map<u32, RutabagaComponentType>,
+    lost: Set<u32>,
+    // Context fences that haven't signaled yet.  Components may never signal the fences of a lost
+    // context, so they're signaled here instead.
+    pending_fences: Map<RutabagaFenceKey, RutabagaFence>,
+    fence_handler: Option<RutabagaFenceHandler>,
+}
+
+/// Tracks lost contexts.
+#[derive(Clone)]
+pub struct RutabagaLostContexts {
+    inner: Arc<Mutex<RutabagaLostInner>>,
+    notify: Option<RutabagaDeviceLostHandler>,
+}
+
+impl RutabagaLostContexts {
+    /// Returns a new `RutabagaLostContexts`.  `notify` is called once for every lost context.
+    pub fn new(notify: Option<RutabagaDeviceLostHandler>) -> RutabagaLostContexts {
+        RutabagaLostContexts {
+            inner: Default::default(),
+            notify,
+        }
+    }
+
+    /// Sets the handler the pending fences of lost contexts are signaled with.
+    pub fn set_fence_handler(&self, handler: RutabagaFenceHandler) {
+        self.inner.lock().unwrap().fence_handler = Some(handler);
+    }
+
+    /// Returns the handler `component` reports lost contexts with.
+    pub fn component_handler(&self, component: RutabagaComponentType) -> RutabagaDeviceLostHandler {
+        let lost = self.clone();
+        RutabagaHandler::new(move |device_lost: RutabagaDeviceLost| {
+            lost.report(component, device_lost)
+        })
+    }
+
+    pub fn context_created(&self, ctx_id: u32, component: RutabagaComponentType) {
+        self.inner
+            .lock()
+            .unwrap()
+            .contexts
+            .insert(ctx_id, component);
+    }
+
+    pub fn context_destroyed(&self, ctx_id: u32) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.contexts.remove(&ctx_id);
+        inner.lost.remove(&ctx_id);
+        inner
+            .pending_fences
+            .retain(|_, fence| fence.ctx_id != ctx_id);
+    }
+
+    /// Marks `ctx_id` as lost, after one of its operations failed with
+    /// `RutabagaError::ContextLost`.
+    pub fn context_lost(&self, ctx_id: u32) {
+        let component = self.inner.lock().unwrap().contexts.get(&ctx_id).copied();
+        if let Some(component) = component {
+            self.report(
+                component,
+                RutabagaDeviceLost {
+                    ctx_id: Some(ctx_id),
+                },
+            );
+        }
+    }
+
+    pub fn is_lost(&self, ctx_id: u32) -> bool {
+        self.inner.lock().unwrap().lost.contains(&ctx_id)
+    }
+
+    /// Returns the live contexts that were lost, in ascending order.
+    pub fn lost_contexts(&self) -> Vec<u32> {
+        self.inner.lock().unwrap().lost.iter().copied().collect()
+    }
+
+    /// Records `fence` as pending, or signals it right away if its context was lost.  Returns
+    /// false in the latter case.
+    pub fn fence_created(&self, fence: RutabagaFence) -> bool {
+        if fence.flags & RUTABAGA_FLAG_INFO_RING_IDX == 0 {
+            return true;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.lost.contains(&fence.ctx_id) {
+            inner.pending_fences.insert(fence.key(), fence);
+            return true;
+        }
+
+        let fence_handler = inner.fence_handler.clone();
+        drop(inner);
+        if let Some(fence_handler) = fence_handler {
+            fence_handler.call(RutabagaFence {
+                flags: fence.flags | RUTABAGA_FLAG_CONTEXT_LOST,
+                ..fence
+            });
+        }
+
+        false
+    }
+
+    /// Marks the contexts of `component` that `device_lost` names as lost and signals their
+    /// pending fences.
+    pub fn report(&self, component: RutabagaComponentType, device_lost: RutabagaDeviceLost) {
+        let mut inner = self.inner.lock().unwrap();
+        let newly_lost: Vec<u32> = inner
+            .contexts
+            .iter()
+            .filter(|(ctx_id, ctx_component)| {
+                **ctx_component == component
+                    && device_lost.ctx_id.map_or(true, |id| id == **ctx_id)
+                    && !inner.lost.contains(ctx_id)
+            })
+            .map(|(ctx_id, _)| *ctx_id)
+            .collect();
+
+        inner.lost.extend(newly_lost.iter().copied());
+        let mut fences = Vec::new();
+        inner.pending_fences.retain(|_, fence| {
+            if newly_lost.contains(&fence.ctx_id) {
+                fences.push(*fence);
+                return false;
+            }
+            true
+        });
+
+        let fence_handler = inner.fence_handler.clone();
+        drop(inner);
+
+        if let Some(fence_handler) = fence_handler {
+            for fence in fences {
+                fence_handler.call(RutabagaFence {
+                    flags: fence.flags | RUTABAGA_FLAG_CONTEXT_LOST,
+                    ..fence
+                });
+            }
+        }
+
+        if let Some(notify) = &self.notify {
+            for ctx_id in newly_lost {
+                notify.call(RutabagaDeviceLost {
+                    ctx_id: Some(ctx_id),
+                });
+            }
+        }
+    }
+
+    /// Returns `fence` as it should be delivered, flagged if its context was lost, or None if it
+    /// was already signaled when its context was lost.
+    pub fn fence_signaled(&self, fence: RutabagaFence) -> Option<RutabagaFence> {
+        let mut inner = self.inner.lock().unwrap();
+        let pending = inner.pending_fences.remove(&fence.key()).is_some();
+        if fence.flags & RUTABAGA_FLAG_INFO_RING_IDX == 0 || !inner.lost.contains(&fence.ctx_id) {
+            return Some(fence);
+        }
+
+        // The fence was signaled when its context was lost.
+        if !pending {
+            return None;
+        }
+
+        Some(RutabagaFence {
+            flags: fence.flags | RUTABAGA_FLAG_CONTEXT_LOST,
+            ..fence
+        })
+    }
+}
@@ -0,0 +1,265 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! rutabaga_render_server: launches and supervises virglrenderer's render server, for VMMs that
+//! don't manage it themselves.
+
+use std::ffi::OsString;
+use std::io::Error as SysError;
+use std::os::fd::AsRawFd;
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Child;
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+
+use log::error;
+use mesa3d_util::MesaError;
+use mesa3d_util::OwnedDescriptor;
+
+use crate::rutabaga_utils::RutabagaResult;
+use crate::rutabaga_utils::RutabagaThreadConfig;
+use crate::rutabaga_utils::RutabagaThreadType;
+
+/// How the render server is launched.  One server is spawned per `Rutabaga` instance, and it forks
+/// a sandboxed worker for each context itself.
+#[derive(Clone, Debug)]
+pub struct RutabagaRenderServerConfig {
+    /// The server binary, usually virgl_render_server.
+    pub path: PathBuf,
+    /// A command the server runs under, such as a minijail invocation.  The server path and
+    /// arguments are appended to it.
+    pub sandbox: Vec<OsString>,
+    /// Arguments passed to the server after --socket-fd.
+    pub args: Vec<OsString>,
+    /// Environment variables set for the server, on top of the ones it inherits.
+    pub envs: Vec<(OsString, OsString)>,
+}
+
+impl RutabagaRenderServerConfig {
+    pub fn new(path: PathBuf) -> RutabagaRenderServerConfig {
+        RutabagaRenderServerConfig {
+            path,
+            sandbox: Vec::new(),
+            args: Vec::new(),
+            envs: Vec::new(),
+        }
+    }
+}
+
+struct RenderServerState {
+    child: Child,
+    stopping: bool,
+}
+
+/// A running render server, which is killed when dropped.
+pub struct RutabagaRenderServer {
+    state: Arc<Mutex<RenderServerState>>,
+    monitor: Option<JoinHandle<()>>,
+}
+
+#[cfg_attr(not(feature = "virgl_renderer"), allow(dead_code))]
+impl RutabagaRenderServer {
+    /// Spawns the server and returns it, along with the socket virglrenderer talks to it over.
+    /// `on_exit` is called if the server exits before it is dropped.  The server and the sandbox
+    /// command are confined by the landlock rules of the sandbox in `thread_config`, if any.
+    pub(crate) fn spawn(
+        config: &RutabagaRenderServerConfig,
+        thread_config: &RutabagaThreadConfig,
+        on_exit: impl FnOnce() + Send + 'static,
+    ) -> RutabagaResult<(RutabagaRenderServer, OwnedDescriptor)> {
+        let mut fds = [-1; 2];
+        // SAFETY:
+        // socketpair only writes two descriptors to `fds`, which are owned below.
+        let ret = unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        };
+        if ret != 0 {
+            return Err(MesaError::IoError(SysError::last_os_error()).into());
+        }
+
+        // SAFETY:
+        // The descriptors were just created and aren't owned by anything else.
+        let (server_socket, client_socket) =
+            unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        let client_fd = client_socket.as_raw_fd();
+
+        let mut executables = vec![config.path.as_path()];
+        executables.extend(config.sandbox.first().map(Path::new));
+        let ruleset = match thread_config.sandbox() {
+            Some(sandbox) => sandbox.render_server_ruleset(&executables)?,
+            None => None,
+        };
+
+        let mut command = match config.sandbox.split_first() {
+            Some((sandbox, sandbox_args)) => {
+                let mut command = Command::new(sandbox);
+                command.args(sandbox_args).arg(&config.path);
+                command
+            }
+            None => Command::new(&config.path),
+        };
+        command
+            .arg("--socket-fd")
+            .arg(client_fd.to_string())
+            .args(&config.args)
+            .envs(config.envs.iter().map(|(key, value)| (key, value)));
+
+        // SAFETY:
+        // Only async-signal-safe fcntl, prctl and landlock calls are made between fork and exec,
+        // to keep the client socket open in the server and confine it.
+        unsafe {
+            command.pre_exec(move || {
+                if libc::fcntl(client_fd, libc::F_SETFD, 0) != 0 {
+                    return Err(SysError::last_os_error());
+                }
+                if let Some(ruleset) = &ruleset {
+                    ruleset.restrict_self()?;
+                }
+                Ok(())
+            });
+        }
+
+        let child = command.spawn().map_err(MesaError::IoError)?;
+        drop(client_socket);
+
+        let pid = child.id();
+        let state = Arc::new(Mutex::new(RenderServerState {
+            child,
+            stopping: false,
+        }));
+
+        let monitor_state = state.clone();
+        let monitor_config = thread_config.clone();
+        let monitor = thread_config
+            .builder(RutabagaThreadType::RenderServerMonitor)
+            .spawn(move || {
+                monitor_config.apply(RutabagaThreadType::RenderServerMonitor);
+
+                // Wait without reaping, so the pid can't be reused before `stopping` is checked.
+                loop {
+                    // SAFETY:
+                    // waitid only writes to the zero-initialized siginfo_t it is given.
+                    let ret = unsafe {
+                        let mut info: libc::siginfo_t = std::mem::zeroed();
+                        libc::waitid(libc::P_PID, pid, &mut info, libc::WEXITED | libc::WNOWAIT)
+                    };
+                    if ret == 0
+                        || SysError::last_os_error().kind() != std::io::ErrorKind::Interrupted
+                    {
+                        break;
+                    }
+                }
+
+                let mut state = monitor_state.lock().unwrap();
+                let status = state.child.wait();
+                if !state.stopping {
+                    drop(state);
+                    error!("render server exited unexpectedly: {:?}", status);
+                    on_exit();
+                }
+            })
+            .map_err(MesaError::IoError)?;
+
+        let render_server = RutabagaRenderServer {
+            state,
+            monitor: Some(monitor),
+        };
+
+        Ok((render_server, server_socket.into()))
+    }
+}
+
+impl Drop for RutabagaRenderServer {
+    fn drop(&mut self) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.stopping = true;
+            // The server hasn't been reaped yet, even if it already exited.
+            let _ = state.child.kill();
+        }
+
+        if let Some(monitor) = self.monitor.take() {
+            let _ = monitor.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    use mesa3d_util::AsRawDescriptor;
+
+    use super::*;
+
+    // A server run by `sh -c script`, which gets the server path as $1, and --socket-fd and its
+    // argument as $2 and $3.
+    fn shell_server(script: &str) -> RutabagaRenderServerConfig {
+        let mut config = RutabagaRenderServerConfig::new(PathBuf::from("render_server"));
+        config.sandbox = vec!["/bin/sh".into(), "-c".into(), script.into(), "sh".into()];
+        config
+    }
+
+    #[test]
+    fn server_exit_is_reported() {
+        let path = std::env::temp_dir().join(format!("render-server-{}", std::process::id()));
+        let mut config =
+            shell_server(r#"[ -e "/proc/$$/fd/$3" ] && echo "$1 $2 $4 $SERVER_ENV" > "$5""#);
+        config.args = vec!["--arg".into(), path.clone().into()];
+        config.envs = vec![("SERVER_ENV".into(), "env".into())];
+
+        let (sender, receiver) = channel();
+        let (_server, _socket) =
+            RutabagaRenderServer::spawn(&config, &RutabagaThreadConfig::default(), move || {
+                sender.send(()).unwrap()
+            })
+            .unwrap();
+
+        receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        // The server inherited its end of the socket.
+        let output = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(output, "render_server --socket-fd --arg env\n");
+    }
+
+    #[test]
+    fn dropped_server_is_killed_silently() {
+        let (sender, receiver) = channel();
+        let (server, socket) = RutabagaRenderServer::spawn(
+            &shell_server("exec sleep 60"),
+            &RutabagaThreadConfig::default(),
+            move || sender.send(()).unwrap(),
+        )
+        .unwrap();
+
+        drop(server);
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // The server's end of the socket closed with it.
+        let mut buf = [0u8; 1];
+        // SAFETY:
+        // recv only writes to `buf`, which is valid for its length.
+        let ret = unsafe {
+            libc::recv(
+                socket.as_raw_descriptor(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                libc::MSG_DONTWAIT,
+            )
+        };
+        assert_eq!(ret, 0);
+    }
+}
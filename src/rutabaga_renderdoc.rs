@@ -0,0 +1,195 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! rutabaga_renderdoc: RenderDoc in-application API hooks, for capturing frames of guest GL and
+//! Vulkan applications rendered by virglrenderer or gfxstream on the host.
+//!
+//! Guest frames never reach a host swapchain, so RenderDoc can't delimit them itself.  Instead,
+//! captures are bracketed by frame boundaries that rutabaga observes.  RenderDoc must already be
+//! injected into the process, e.g. by launching the VMM from the RenderDoc UI or with
+//! `renderdoccmd capture`.  Renderers running in another process, such as the virgl render server,
+//! aren't captured.
+
+use std::ffi::c_void;
+
+use log::warn;
+use mesa3d_util::MesaError;
+
+use crate::rutabaga_utils::RutabagaFence;
+use crate::rutabaga_utils::RutabagaResult;
+
+/// eRENDERDOC_API_Version_1_1_2, the oldest version with everything used here.
+#[cfg(unix)]
+const RENDERDOC_API_VERSION: u32 = 10102;
+
+/// What ends a guest frame, for bracketing captures.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RutabagaCaptureBoundary {
+    /// Frames end when the guest flushes a resource to a scanout.
+    Scanout,
+    /// Frames end with each fence of the captured context.  Suits guests that don't present,
+    /// or that present through a path rutabaga doesn't see, at the cost of capturing less than a
+    /// full frame when the guest fences more than once per frame.
+    Fence,
+}
+
+type RenderDocStartFrameCapture = unsafe extern "C" fn(device: *mut c_void, window: *mut c_void);
+type RenderDocIsFrameCapturing = unsafe extern "C" fn() -> u32;
+type RenderDocEndFrameCapture =
+    unsafe extern "C" fn(device: *mut c_void, window: *mut c_void) -> u32;
+
+/// The prefix of RENDERDOC_API_1_1_2 up to and including EndFrameCapture.
+#[repr(C)]
+struct RenderDocApi {
+    // GetAPIVersion through SetActiveWindow, which aren't used.
+    _unused: [*const c_void; 19],
+    start_frame_capture: RenderDocStartFrameCapture,
+    is_frame_capturing: RenderDocIsFrameCapturing,
+    end_frame_capture: RenderDocEndFrameCapture,
+}
+
+#[cfg(unix)]
+fn load_renderdoc() -> RutabagaResult<&'static RenderDocApi> {
+    type RenderDocGetApi = unsafe extern "C" fn(version: u32, api: *mut *mut c_void) -> i32;
+
+    // SAFETY:
+    // The library name is a valid C string.  RTLD_NOLOAD only returns libraries already loaded,
+    // and the handle is never closed, so the library stays loaded for the life of the process.
+    let library = unsafe {
+        libc::dlopen(
+            c"librenderdoc.so".as_ptr(),
+            libc::RTLD_NOW | libc::RTLD_NOLOAD,
+        )
+    };
+    if library.is_null() {
+        return Err(MesaError::WithContext("RenderDoc isn't loaded into the process").into());
+    }
+
+    // SAFETY:
+    // `library` is a valid handle, and the symbol name is a valid C string.
+    let get_api = unsafe { libc::dlsym(library, c"RENDERDOC_GetAPI".as_ptr()) };
+    if get_api.is_null() {
+        return Err(MesaError::WithContext("RenderDoc has no RENDERDOC_GetAPI").into());
+    }
+
+    // SAFETY:
+    // RENDERDOC_GetAPI has the signature of RenderDocGetApi.
+    let get_api: RenderDocGetApi = unsafe { std::mem::transmute(get_api) };
+    let mut api: *mut c_void = std::ptr::null_mut();
+    // SAFETY:
+    // `api` is valid for writes, and is only set when the call succeeds.
+    if unsafe { get_api(RENDERDOC_API_VERSION, &mut api) } != 1 || api.is_null() {
+        return Err(MesaError::WithContext("RenderDoc doesn't support API version 1.1.2").into());
+    }
+
+    // SAFETY:
+    // RenderDoc returned a RENDERDOC_API_1_1_2, which starts with RenderDocApi and is never freed.
+    Ok(unsafe { &*(api as *const RenderDocApi) })
+}
+
+#[cfg(not(unix))]
+fn load_renderdoc() -> RutabagaResult<&'static RenderDocApi> {
+    Err(MesaError::Unsupported.into())
+}
+
+/// Brackets the next frame of a guest context with a RenderDoc capture.
+pub struct RutabagaRenderDoc {
+    api: Option<&'static RenderDocApi>,
+    boundary: RutabagaCaptureBoundary,
+    // The context whose frame is captured, and whether its capture has started.
+    capture: Option<(u32, bool)>,
+}
+
+// SAFETY:
+// The API functions may be called from any thread.
+unsafe impl Send for RutabagaRenderDoc {}
+
+impl RutabagaRenderDoc {
+    /// Attaches to RenderDoc if it's loaded.  Otherwise, every capture fails as unsupported.
+    pub fn new(boundary: RutabagaCaptureBoundary) -> RutabagaRenderDoc {
+        let api = load_renderdoc()
+            .map_err(|e| warn!("RenderDoc captures are unavailable: {e}"))
+            .ok();
+
+        RutabagaRenderDoc {
+            api,
+            boundary,
+            capture: None,
+        }
+    }
+
+    /// Captures the frame of `ctx_id` after the next frame boundary.
+    pub fn trigger_capture(&mut self, ctx_id: u32) -> RutabagaResult<()> {
+        if self.api.is_none() {
+            return Err(MesaError::Unsupported.into());
+        }
+
+        if self.capture.is_some() {
+            return Err(MesaError::WithContext("a RenderDoc capture is already pending").into());
+        }
+
+        self.capture = Some((ctx_id, false));
+        Ok(())
+    }
+
+    pub fn scanout_flushed(&mut self) {
+        if self.boundary == RutabagaCaptureBoundary::Scanout {
+            self.frame_boundary();
+        }
+    }
+
+    pub fn fence_created(&mut self, fence: &RutabagaFence) {
+        if self.boundary == RutabagaCaptureBoundary::Fence
+            && self
+                .capture
+                .is_some_and(|(ctx_id, _)| ctx_id == fence.ctx_id)
+        {
+            self.frame_boundary();
+        }
+    }
+
+    /// Ends or cancels the capture of a destroyed context.
+    pub fn context_destroyed(&mut self, ctx_id: u32) {
+        if let Some((capture_ctx_id, started)) = self.capture {
+            if capture_ctx_id == ctx_id {
+                if started {
+                    self.end_capture();
+                }
+                self.capture = None;
+            }
+        }
+    }
+
+    fn frame_boundary(&mut self) {
+        let (Some(api), Some((ctx_id, started))) = (self.api, self.capture) else {
+            return;
+        };
+
+        if started {
+            self.end_capture();
+            self.capture = None;
+        } else {
+            // SAFETY:
+            // Null device and window pointers capture every device and window.
+            unsafe { (api.start_frame_capture)(std::ptr::null_mut(), std::ptr::null_mut()) };
+            self.capture = Some((ctx_id, true));
+        }
+    }
+
+    fn end_capture(&self) {
+        let Some(api) = self.api else {
+            return;
+        };
+
+        // SAFETY:
+        // Null device and window pointers match those the capture was started with.
+        let captured = unsafe {
+            (api.is_frame_capturing)() != 0
+                && (api.end_frame_capture)(std::ptr::null_mut(), std::ptr::null_mut()) != 0
+        };
+        if !captured {
+            warn!("RenderDoc failed to capture the guest frame");
+        }
+    }
+}
@@ -0,0 +1,541 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! rutabaga_sandbox: confines the process with seccomp, rutabaga's worker threads with landlock,
+//! and the render server with landlock, so every VMM doesn't have to.
+
+use std::ffi::CString;
+use std::io::Error as SysError;
+use std::mem::size_of;
+use std::os::fd::AsRawFd;
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use log::warn;
+use mesa3d_util::MesaError;
+
+use crate::rutabaga_utils::RutabagaPaths;
+use crate::rutabaga_utils::RutabagaResult;
+
+const DRM_DIR: &str = "/dev/dri";
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+
+const LANDLOCK_ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const LANDLOCK_ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const LANDLOCK_ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const LANDLOCK_ACCESS_FS_READ_DIR: u64 = 1 << 3;
+// Every access right of the first landlock ABI, which are all denied unless a rule allows them.
+const LANDLOCK_ACCESS_FS_ABI_1: u64 = (1 << 13) - 1;
+// Rights that may be granted on files rather than directories.
+const LANDLOCK_ACCESS_FS_FILE: u64 =
+    LANDLOCK_ACCESS_FS_EXECUTE | LANDLOCK_ACCESS_FS_WRITE_FILE | LANDLOCK_ACCESS_FS_READ_FILE;
+
+const SANDBOX_READ: u64 = LANDLOCK_ACCESS_FS_READ_FILE | LANDLOCK_ACCESS_FS_READ_DIR;
+const SANDBOX_READ_WRITE: u64 = SANDBOX_READ | LANDLOCK_ACCESS_FS_WRITE_FILE;
+const SANDBOX_READ_EXECUTE: u64 = SANDBOX_READ | LANDLOCK_ACCESS_FS_EXECUTE;
+
+const SECCOMP_SET_MODE_FILTER: libc::c_ulong = 1;
+const SECCOMP_FILTER_FLAG_TSYNC: libc::c_ulong = 1 << 0;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JEQ_K: u16 = 0x15;
+const BPF_RET_K: u16 = 0x06;
+
+// Offsets into struct seccomp_data.
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+// Syscalls made by worker threads, including by fence handlers, logging and the allocator.
+const WORKER_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_brk,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_close,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_eventfd2,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_fcntl,
+    libc::SYS_fstat,
+    libc::SYS_ftruncate,
+    libc::SYS_futex,
+    libc::SYS_getpid,
+    libc::SYS_getrandom,
+    libc::SYS_gettid,
+    libc::SYS_ioctl,
+    libc::SYS_lseek,
+    libc::SYS_madvise,
+    libc::SYS_memfd_create,
+    libc::SYS_mmap,
+    libc::SYS_mprotect,
+    libc::SYS_mremap,
+    libc::SYS_munmap,
+    libc::SYS_nanosleep,
+    libc::SYS_newfstatat,
+    libc::SYS_openat,
+    libc::SYS_pipe2,
+    libc::SYS_ppoll,
+    libc::SYS_prctl,
+    libc::SYS_read,
+    libc::SYS_readv,
+    libc::SYS_recvfrom,
+    libc::SYS_recvmsg,
+    libc::SYS_restart_syscall,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_sched_yield,
+    libc::SYS_sendmsg,
+    libc::SYS_sendto,
+    libc::SYS_shutdown,
+    libc::SYS_sigaltstack,
+    libc::SYS_statx,
+    libc::SYS_write,
+    libc::SYS_writev,
+];
+
+// Syscalls made outside of workers once rutabaga is built: spawning and confining workers, and
+// connecting to the channels of new contexts.
+const PROCESS_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_connect,
+    libc::SYS_landlock_add_rule,
+    libc::SYS_landlock_create_ruleset,
+    libc::SYS_landlock_restrict_self,
+    libc::SYS_rseq,
+    libc::SYS_sched_setattr,
+    libc::SYS_sched_setscheduler,
+    libc::SYS_set_robust_list,
+    libc::SYS_setpriority,
+    libc::SYS_socket,
+];
+
+// Legacy syscalls that aarch64 doesn't have.
+#[cfg(target_arch = "x86_64")]
+const WORKER_SYSCALLS_LEGACY: &[libc::c_long] = &[
+    libc::SYS_epoll_wait,
+    libc::SYS_open,
+    libc::SYS_pipe,
+    libc::SYS_poll,
+];
+#[cfg(not(target_arch = "x86_64"))]
+const WORKER_SYSCALLS_LEGACY: &[libc::c_long] = &[];
+
+/// Confinement of rutabaga's worker threads and the render server.
+#[derive(Clone, Debug, Default)]
+pub struct RutabagaSandboxPolicy {
+    /// Limits every thread of the process to the syscalls rutabaga needs, plus `extra_syscalls`,
+    /// once rutabaga is built.  Other syscalls fail with EPERM.  Threads share their memory, so a
+    /// filter on rutabaga's threads alone could be bypassed through the others: this is meant for
+    /// processes that run little besides rutabaga, such as the GPU device process of a VMM.
+    pub seccomp: bool,
+    /// Syscalls the rest of the process needs, allowed in addition to rutabaga's.
+    pub extra_syscalls: Vec<libc::c_long>,
+    /// Limits the files worker threads and the render server can access to DRM nodes and the
+    /// configured `RutabagaPaths`.
+    pub landlock: bool,
+    /// Extra paths the render server may read and execute, such as the directories its libraries
+    /// and drivers are loaded from.
+    pub render_server_paths: Vec<PathBuf>,
+}
+
+#[repr(C)]
+struct LandlockRulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct LandlockPathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// A landlock ruleset that is ready to be enforced.
+pub(crate) struct LandlockRuleset {
+    fd: OwnedFd,
+}
+
+impl LandlockRuleset {
+    fn new(rules: &[(PathBuf, u64)]) -> RutabagaResult<LandlockRuleset> {
+        // SAFETY:
+        // Querying the ABI version takes no pointers.
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<LandlockRulesetAttr>(),
+                0,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 1 {
+            return Err(MesaError::WithContext("landlock is unavailable").into());
+        }
+
+        let attr = LandlockRulesetAttr {
+            handled_access_fs: LANDLOCK_ACCESS_FS_ABI_1,
+        };
+
+        // SAFETY:
+        // `attr` is valid for the duration of the call, and the returned fd is owned below.
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr,
+                size_of::<LandlockRulesetAttr>(),
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(MesaError::IoError(SysError::last_os_error()).into());
+        }
+
+        // SAFETY:
+        // The ruleset fd was just created and isn't owned by anything else.
+        let ruleset = LandlockRuleset {
+            fd: unsafe { OwnedFd::from_raw_fd(fd as i32) },
+        };
+
+        for (path, access) in rules {
+            ruleset.add_rule(path, *access)?;
+        }
+
+        Ok(ruleset)
+    }
+
+    fn add_rule(&self, path: &Path, access: u64) -> RutabagaResult<()> {
+        let Ok(metadata) = path.metadata() else {
+            warn!("sandbox: skipping missing path {}", path.display());
+            return Ok(());
+        };
+
+        let access = match metadata.is_dir() {
+            true => access,
+            false => access & LANDLOCK_ACCESS_FS_FILE,
+        };
+
+        let c_path =
+            CString::new(path.as_os_str().as_bytes()).map_err(|_| MesaError::InvalidMesaHandle)?;
+        // SAFETY:
+        // `c_path` is a valid C string, and the returned fd is owned below.
+        let parent_fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        if parent_fd < 0 {
+            return Err(MesaError::IoError(SysError::last_os_error()).into());
+        }
+
+        // SAFETY:
+        // The fd was just opened and isn't owned by anything else.
+        let parent = unsafe { OwnedFd::from_raw_fd(parent_fd) };
+        let attr = LandlockPathBeneathAttr {
+            allowed_access: access,
+            parent_fd: parent.as_raw_fd(),
+        };
+
+        // SAFETY:
+        // `attr` is valid for the duration of the call.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                self.fd.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &attr,
+                0,
+            )
+        };
+        if ret != 0 {
+            return Err(MesaError::IoError(SysError::last_os_error()).into());
+        }
+
+        Ok(())
+    }
+
+    /// Enforces the ruleset on the calling thread and anything it later executes.  Only makes
+    /// async-signal-safe calls, so it may run between fork and exec.
+    pub(crate) fn restrict_self(&self) -> std::io::Result<()> {
+        set_no_new_privs()?;
+
+        // SAFETY:
+        // Only takes the ruleset fd, which is valid for the lifetime of `self`.
+        let ret =
+            unsafe { libc::syscall(libc::SYS_landlock_restrict_self, self.fd.as_raw_fd(), 0) };
+        if ret != 0 {
+            return Err(SysError::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+/// Applies a `RutabagaSandboxPolicy`, given the paths rutabaga was configured with.
+#[derive(Clone)]
+pub(crate) struct RutabagaSandbox {
+    policy: RutabagaSandboxPolicy,
+    paths: Vec<PathBuf>,
+}
+
+impl RutabagaSandbox {
+    pub(crate) fn new(policy: RutabagaSandboxPolicy, paths: &Option<RutabagaPaths>) -> Self {
+        let paths = paths
+            .iter()
+            .flatten()
+            .map(|rutabaga_path| rutabaga_path.path.clone())
+            .collect();
+
+        RutabagaSandbox { policy, paths }
+    }
+
+    fn base_rules(&self) -> Vec<(PathBuf, u64)> {
+        let mut rules = vec![(PathBuf::from(DRM_DIR), SANDBOX_READ_WRITE)];
+        rules.extend(self.paths.iter().map(|path| (path.clone(), SANDBOX_READ)));
+        rules
+    }
+
+    /// Confines the calling thread with landlock.  Other threads are unaffected.
+    pub(crate) fn confine_thread(&self) -> RutabagaResult<()> {
+        if self.policy.landlock {
+            LandlockRuleset::new(&self.base_rules())?
+                .restrict_self()
+                .map_err(MesaError::IoError)?;
+        }
+
+        Ok(())
+    }
+
+    /// Confines every thread of the process with seccomp.  Threads spawned later inherit the
+    /// filter.
+    pub(crate) fn confine_process(&self) -> RutabagaResult<()> {
+        if self.policy.seccomp {
+            install_seccomp_filter(
+                WORKER_SYSCALLS
+                    .iter()
+                    .chain(WORKER_SYSCALLS_LEGACY)
+                    .chain(PROCESS_SYSCALLS)
+                    .chain(&self.policy.extra_syscalls)
+                    .copied(),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the landlock ruleset for the render server, which may also execute `executables`.
+    /// Executables that are looked up in PATH must be given as absolute paths instead.
+    pub(crate) fn render_server_ruleset(
+        &self,
+        executables: &[&Path],
+    ) -> RutabagaResult<Option<LandlockRuleset>> {
+        if !self.policy.landlock {
+            return Ok(None);
+        }
+
+        let mut rules = self.base_rules();
+        rules.extend(
+            executables
+                .iter()
+                .map(|path| (path.to_path_buf(), SANDBOX_READ_EXECUTE)),
+        );
+        rules.extend(
+            self.policy
+                .render_server_paths
+                .iter()
+                .map(|path| (path.clone(), SANDBOX_READ_EXECUTE)),
+        );
+
+        Ok(Some(LandlockRuleset::new(&rules)?))
+    }
+}
+
+fn set_no_new_privs() -> std::io::Result<()> {
+    // SAFETY:
+    // PR_SET_NO_NEW_PRIVS takes no pointers.
+    let ret = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+    if ret != 0 {
+        return Err(SysError::last_os_error());
+    }
+
+    Ok(())
+}
+
+fn bpf_stmt(code: u16, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn bpf_jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+// Returns a filter allowing `syscalls` and failing every other syscall with EPERM.  Syscalls made
+// with another ABI than `audit_arch`, such as x32 or 32-bit arm, kill the process.
+fn seccomp_filter(
+    audit_arch: u32,
+    syscalls: impl Iterator<Item = libc::c_long>,
+) -> Vec<libc::sock_filter> {
+    let mut filter = vec![
+        bpf_stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+        bpf_jump(BPF_JEQ_K, audit_arch, 1, 0),
+        bpf_stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+        bpf_stmt(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+    ];
+    for syscall in syscalls {
+        filter.push(bpf_jump(BPF_JEQ_K, syscall as u32, 0, 1));
+        filter.push(bpf_stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+    }
+    filter.push(bpf_stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+    filter
+}
+
+// Installs a filter allowing `syscalls` on every thread of the process.
+fn install_seccomp_filter(syscalls: impl Iterator<Item = libc::c_long>) -> RutabagaResult<()> {
+    let audit_arch = AUDIT_ARCH.ok_or(MesaError::Unsupported)?;
+    let mut filter = seccomp_filter(audit_arch, syscalls);
+
+    let program = libc::sock_fprog {
+        len: filter
+            .len()
+            .try_into()
+            .map_err(|_| MesaError::WithContext("seccomp filter too long"))?,
+        filter: filter.as_mut_ptr(),
+    };
+
+    set_no_new_privs().map_err(MesaError::IoError)?;
+
+    // SAFETY:
+    // `program` and the filter it points to are valid for the duration of the call, and the
+    // kernel copies them.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            SECCOMP_SET_MODE_FILTER,
+            SECCOMP_FILTER_FLAG_TSYNC,
+            &program as *const libc::sock_fprog,
+        )
+    };
+    // With TSYNC, a positive value is the id of a thread whose filters can't be synchronized.
+    if ret > 0 {
+        return Err(
+            MesaError::WithContext("seccomp filters of a thread can't be synchronized").into(),
+        );
+    }
+    if ret != 0 {
+        return Err(MesaError::IoError(SysError::last_os_error()).into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    use super::*;
+
+    const CHILD_ENV: &str = "RUTABAGA_SANDBOX_TEST_CHILD";
+
+    // Runs the classic BPF `filter` on a syscall `nr` of `arch`, and returns its verdict.
+    fn run_filter(filter: &[libc::sock_filter], arch: u32, nr: u32) -> u32 {
+        let mut acc = 0;
+        let mut pc = 0;
+        loop {
+            let insn = filter[pc];
+            pc += 1;
+            match insn.code {
+                BPF_LD_W_ABS if insn.k == SECCOMP_DATA_ARCH => acc = arch,
+                BPF_LD_W_ABS if insn.k == SECCOMP_DATA_NR => acc = nr,
+                BPF_JEQ_K if acc == insn.k => pc += insn.jt as usize,
+                BPF_JEQ_K => pc += insn.jf as usize,
+                BPF_RET_K => return insn.k,
+                code => panic!("unexpected instruction {code:#x}"),
+            }
+        }
+    }
+
+    #[test]
+    fn seccomp_filter_verdicts() {
+        let filter = seccomp_filter(0xc000_003e, [1, 60].into_iter());
+        assert_eq!(run_filter(&filter, 0xc000_003e, 1), SECCOMP_RET_ALLOW);
+        assert_eq!(run_filter(&filter, 0xc000_003e, 60), SECCOMP_RET_ALLOW);
+        assert_eq!(
+            run_filter(&filter, 0xc000_003e, 2),
+            SECCOMP_RET_ERRNO | libc::EPERM as u32
+        );
+        assert_eq!(
+            run_filter(&filter, 0x4000_0028, 1),
+            SECCOMP_RET_KILL_PROCESS
+        );
+    }
+
+    // The filter confines threads spawned before it's installed, too.  It's installed in a child
+    // process, which the filter would otherwise outlive.
+    #[test]
+    fn seccomp_confines_every_thread() {
+        if AUDIT_ARCH.is_none() {
+            return;
+        }
+
+        if std::env::var_os(CHILD_ENV).is_none() {
+            let status = Command::new(std::env::current_exe().unwrap())
+                .args([
+                    "--exact",
+                    "rutabaga_sandbox::tests::seccomp_confines_every_thread",
+                    "--test-threads=1",
+                ])
+                .env(CHILD_ENV, "1")
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        let (start_sender, start_receiver) = channel();
+        let (result_sender, result_receiver) = channel();
+        let thread = thread::spawn(move || {
+            start_receiver.recv().unwrap();
+            // SAFETY:
+            // getppid takes no arguments.
+            let ret = unsafe { libc::syscall(libc::SYS_getppid) };
+            result_sender
+                .send((ret, SysError::last_os_error().raw_os_error()))
+                .unwrap();
+        });
+
+        let sandbox = RutabagaSandbox::new(
+            RutabagaSandboxPolicy {
+                seccomp: true,
+                ..Default::default()
+            },
+            &None,
+        );
+        let confined = sandbox.confine_process().is_ok();
+        start_sender.send(()).unwrap();
+        let denied = result_receiver.recv().unwrap() == (-1, Some(libc::EPERM));
+        thread.join().unwrap();
+        std::process::exit(if confined && denied { 0 } else { 1 });
+    }
+}
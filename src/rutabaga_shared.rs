@@ -0,0 +1,671 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! rutabaga_shared: a thread-safe front for Rutabaga, so the queues of a multi-queue virtio-gpu
+//! device can be served from several threads.
+//!
+//! Each context lives behind its own lock, in a registry shared by the core and the front.
+//! Components whose contexts are safe to run concurrently with each other and with the component
+//! say so with `RutabagaComponent::concurrent_contexts()`.  Submissions to such contexts, and the
+//! creation of their fences, only take the context's lock, while everything else is serialized by
+//! the core lock.  Locks are always taken in the order core, registry, context, and the registry
+//! lock is never held across calls into a context.
+//!
+//! Resources live in shards, each behind its own lock.  Only the core, holding its lock, changes
+//! them, so the front can look up a resource while the core lock is held elsewhere.  Only lookups
+//! in a shard whose resource the core is changing wait for it.
+
+use std::collections::BTreeMap as Map;
+use std::ops::Deref;
+use std::ops::DerefMut;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::RwLock;
+use std::sync::RwLockReadGuard;
+use std::sync::RwLockWriteGuard;
+
+use mesa3d_util::MesaError;
+use mesa3d_util::MesaHandle;
+
+use crate::rutabaga_core::Rutabaga;
+use crate::rutabaga_core::RutabagaContext;
+use crate::rutabaga_core::RutabagaResource;
+use crate::rutabaga_deferred::RutabagaDeferredDestruction;
+use crate::rutabaga_lost::RutabagaLostContexts;
+use crate::rutabaga_stats::RutabagaStats;
+use crate::rutabaga_trace::RutabagaTrace;
+use crate::rutabaga_utils::Resource3DInfo;
+use crate::rutabaga_utils::RutabagaComponentType;
+use crate::rutabaga_utils::RutabagaError;
+use crate::rutabaga_utils::RutabagaFence;
+use crate::rutabaga_utils::RutabagaResult;
+use crate::rutabaga_utils::VulkanInfo;
+use crate::rutabaga_utils::RUTABAGA_FLAG_FENCE_HOST_SHAREABLE;
+use crate::rutabaga_utils::RUTABAGA_FLAG_INFO_RING_IDX;
+
+const RESOURCE_SHARDS: usize = 16;
+
+/// A context behind its own lock.
+pub(crate) type RutabagaContextLock = Arc<Mutex<Box<dyn RutabagaContext>>>;
+
+struct RutabagaContextEntry {
+    context: RutabagaContextLock,
+    // Cached so the registry can be searched without locking every context.
+    component_type: RutabagaComponentType,
+    concurrent: bool,
+}
+
+/// The live contexts, keyed by context id, as seen by both `Rutabaga` and `RutabagaShared`.
+#[derive(Clone, Default)]
+pub(crate) struct RutabagaContexts {
+    entries: Arc<RwLock<Map<u32, RutabagaContextEntry>>>,
+}
+
+impl RutabagaContexts {
+    /// Adds a context.  If `concurrent`, `RutabagaShared` submits to it without the core lock.
+    pub(crate) fn insert(&self, ctx_id: u32, context: Box<dyn RutabagaContext>, concurrent: bool) {
+        let component_type = context.component_type();
+        self.entries.write().unwrap().insert(
+            ctx_id,
+            RutabagaContextEntry {
+                context: Arc::new(Mutex::new(context)),
+                component_type,
+                concurrent,
+            },
+        );
+    }
+
+    /// Removes a context.  The context is dropped once submissions already running on it return.
+    pub(crate) fn remove(&self, ctx_id: u32) -> Option<RutabagaContextLock> {
+        self.entries
+            .write()
+            .unwrap()
+            .remove(&ctx_id)
+            .map(|entry| entry.context)
+    }
+
+    pub(crate) fn clear(&self) {
+        self.entries.write().unwrap().clear();
+    }
+
+    pub(crate) fn get(&self, ctx_id: u32) -> Option<RutabagaContextLock> {
+        self.entries
+            .read()
+            .unwrap()
+            .get(&ctx_id)
+            .map(|entry| entry.context.clone())
+    }
+
+    /// Returns the context if it may be used without the core lock.
+    pub(crate) fn get_concurrent(&self, ctx_id: u32) -> Option<RutabagaContextLock> {
+        self.entries
+            .read()
+            .unwrap()
+            .get(&ctx_id)
+            .filter(|entry| entry.concurrent)
+            .map(|entry| entry.context.clone())
+    }
+
+    pub(crate) fn contains(&self, ctx_id: u32) -> bool {
+        self.entries.read().unwrap().contains_key(&ctx_id)
+    }
+
+    pub(crate) fn component_type(&self, ctx_id: u32) -> Option<RutabagaComponentType> {
+        self.entries
+            .read()
+            .unwrap()
+            .get(&ctx_id)
+            .map(|entry| entry.component_type)
+    }
+
+    /// Returns true if any context belongs to `component_type`.
+    pub(crate) fn uses_component(&self, component_type: RutabagaComponentType) -> bool {
+        self.entries
+            .read()
+            .unwrap()
+            .values()
+            .any(|entry| entry.component_type == component_type)
+    }
+
+    /// Returns every context in ascending order of id, optionally only those of `component_type`.
+    pub(crate) fn all(
+        &self,
+        component_type: Option<RutabagaComponentType>,
+    ) -> Vec<(u32, RutabagaContextLock)> {
+        self.entries
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, entry)| {
+                component_type.is_none() || component_type == Some(entry.component_type)
+            })
+            .map(|(ctx_id, entry)| (*ctx_id, entry.context.clone()))
+            .collect()
+    }
+}
+
+type RutabagaResourceShard = Map<u32, RutabagaResource>;
+
+/// A resource borrowed from its shard, which stays read locked while it is held.
+pub(crate) struct RutabagaResourceRef<'a> {
+    shard: RwLockReadGuard<'a, RutabagaResourceShard>,
+    resource_id: u32,
+}
+
+impl Deref for RutabagaResourceRef<'_> {
+    type Target = RutabagaResource;
+
+    fn deref(&self) -> &RutabagaResource {
+        &self.shard[&self.resource_id]
+    }
+}
+
+/// A resource borrowed mutably from its shard, which stays write locked while it is held.
+pub(crate) struct RutabagaResourceMut<'a> {
+    shard: RwLockWriteGuard<'a, RutabagaResourceShard>,
+    resource_id: u32,
+}
+
+impl Deref for RutabagaResourceMut<'_> {
+    type Target = RutabagaResource;
+
+    fn deref(&self) -> &RutabagaResource {
+        &self.shard[&self.resource_id]
+    }
+}
+
+impl DerefMut for RutabagaResourceMut<'_> {
+    fn deref_mut(&mut self) -> &mut RutabagaResource {
+        self.shard.get_mut(&self.resource_id).unwrap()
+    }
+}
+
+/// The live resources, keyed by resource id and sharded by it, as seen by both `Rutabaga` and
+/// `RutabagaShared`.
+///
+/// Methods that change resources take `&mut self`, so the borrow checker keeps the core from
+/// holding a resource while it changes another, which could deadlock on a shared shard.
+#[derive(Clone)]
+pub(crate) struct RutabagaResources {
+    shards: Arc<[RwLock<RutabagaResourceShard>]>,
+}
+
+impl Default for RutabagaResources {
+    fn default() -> RutabagaResources {
+        RutabagaResources {
+            shards: (0..RESOURCE_SHARDS)
+                .map(|_| RwLock::new(Map::new()))
+                .collect(),
+        }
+    }
+}
+
+impl RutabagaResources {
+    fn shard(&self, resource_id: u32) -> &RwLock<RutabagaResourceShard> {
+        &self.shards[resource_id as usize % RESOURCE_SHARDS]
+    }
+
+    pub(crate) fn get(&self, resource_id: &u32) -> Option<RutabagaResourceRef<'_>> {
+        let shard = self.shard(*resource_id).read().unwrap();
+        shard
+            .contains_key(resource_id)
+            .then_some(RutabagaResourceRef {
+                shard,
+                resource_id: *resource_id,
+            })
+    }
+
+    pub(crate) fn get_mut(&mut self, resource_id: &u32) -> Option<RutabagaResourceMut<'_>> {
+        let shard = self.shard(*resource_id).write().unwrap();
+        shard
+            .contains_key(resource_id)
+            .then_some(RutabagaResourceMut {
+                shard,
+                resource_id: *resource_id,
+            })
+    }
+
+    pub(crate) fn contains_key(&self, resource_id: &u32) -> bool {
+        self.shard(*resource_id)
+            .read()
+            .unwrap()
+            .contains_key(resource_id)
+    }
+
+    pub(crate) fn insert(&mut self, resource_id: u32, resource: RutabagaResource) {
+        self.shard(resource_id)
+            .write()
+            .unwrap()
+            .insert(resource_id, resource);
+    }
+
+    pub(crate) fn remove(&mut self, resource_id: &u32) -> Option<RutabagaResource> {
+        self.shard(*resource_id)
+            .write()
+            .unwrap()
+            .remove(resource_id)
+    }
+
+    /// Replaces every resource with `resources`.
+    pub(crate) fn replace(&mut self, resources: Map<u32, RutabagaResource>) {
+        for shard in self.shards.iter() {
+            shard.write().unwrap().clear();
+        }
+        for (resource_id, resource) in resources {
+            self.insert(resource_id, resource);
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap().len())
+            .sum()
+    }
+
+    /// Returns the ids of every resource, in ascending order.
+    pub(crate) fn ids(&self) -> Vec<u32> {
+        let mut resource_ids: Vec<u32> = self
+            .shards
+            .iter()
+            .flat_map(|shard| shard.read().unwrap().keys().copied().collect::<Vec<_>>())
+            .collect();
+        resource_ids.sort_unstable();
+        resource_ids
+    }
+
+    /// Calls `f` with every resource, in ascending order of id, and collects the results.
+    pub(crate) fn map<T>(&self, mut f: impl FnMut(u32, &RutabagaResource) -> T) -> Vec<T> {
+        self.ids()
+            .into_iter()
+            .filter_map(|resource_id| {
+                let resource = self.get(&resource_id)?;
+                Some(f(resource_id, &resource))
+            })
+            .collect()
+    }
+
+    /// Calls `f` with every resource, in ascending order of id.
+    pub(crate) fn for_each_mut(&mut self, mut f: impl FnMut(u32, &mut RutabagaResource)) {
+        for resource_id in self.ids() {
+            if let Some(mut resource) = self.get_mut(&resource_id) {
+                f(resource_id, &mut resource);
+            }
+        }
+    }
+
+    /// Returns the `map_info` of the blob resource.
+    pub(crate) fn map_info(&self, resource_id: u32) -> RutabagaResult<u32> {
+        let resource = self
+            .get(&resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?;
+
+        resource
+            .map_info
+            .ok_or(MesaError::WithContext("no map info available").into())
+    }
+
+    /// Returns the `vulkan_info` of the blob resource.
+    pub(crate) fn vulkan_info(&self, resource_id: u32) -> RutabagaResult<VulkanInfo> {
+        let resource = self
+            .get(&resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?;
+
+        resource.vulkan_info.ok_or(RutabagaError::InvalidVulkanInfo)
+    }
+
+    /// Returns the 3D info associated with the resource, if any.
+    pub(crate) fn resource3d_info(&self, resource_id: u32) -> RutabagaResult<Resource3DInfo> {
+        let resource = self
+            .get(&resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?;
+
+        resource
+            .info_3d
+            .ok_or(MesaError::WithContext("no 3d info available").into())
+    }
+}
+
+/// Submits to contexts and creates their fences, on behalf of both `Rutabaga` and
+/// `RutabagaShared`.  Holds clones of the core's trackers, so it works without the core lock.
+#[derive(Clone)]
+pub(crate) struct RutabagaSubmitter {
+    lost: RutabagaLostContexts,
+    stats: RutabagaStats,
+    trace: RutabagaTrace,
+    deferred: RutabagaDeferredDestruction,
+}
+
+impl RutabagaSubmitter {
+    pub(crate) fn new(
+        lost: RutabagaLostContexts,
+        stats: RutabagaStats,
+        trace: RutabagaTrace,
+        deferred: RutabagaDeferredDestruction,
+    ) -> RutabagaSubmitter {
+        RutabagaSubmitter {
+            lost,
+            stats,
+            trace,
+            deferred,
+        }
+    }
+
+    /// Submits `commands` to `context`, recording the submission and whether it lost the context.
+    pub(crate) fn submit(
+        &self,
+        context: &RutabagaContextLock,
+        ctx_id: u32,
+        commands: &mut [u8],
+        fence_ids: &[u64],
+        shareable_fences: Vec<MesaHandle>,
+    ) -> RutabagaResult<()> {
+        if self.lost.is_lost(ctx_id) {
+            return Err(RutabagaError::ContextLost);
+        }
+
+        self.stats.record_submit(ctx_id, commands.len());
+        self.deferred.submitted(ctx_id);
+        let _span = self.trace.submit_cmd(ctx_id, commands.len());
+        let result = context
+            .lock()
+            .unwrap()
+            .submit_cmd(commands, fence_ids, shareable_fences);
+        if let Err(RutabagaError::ContextLost) = result {
+            self.lost.context_lost(ctx_id);
+        }
+
+        result
+    }
+
+    /// Creates `fence` on `context`.  Fences of lost contexts complete right away, without a
+    /// handle.
+    pub(crate) fn create_fence(
+        &self,
+        context: &RutabagaContextLock,
+        fence: RutabagaFence,
+    ) -> RutabagaResult<Option<MesaHandle>> {
+        if !self.lost.fence_created(fence) {
+            return Ok(None);
+        }
+
+        match context.lock().unwrap().context_create_fence(fence) {
+            Err(RutabagaError::ContextLost) => {
+                self.lost.context_lost(fence.ctx_id);
+                Ok(None)
+            }
+            result => result,
+        }
+    }
+}
+
+/// Rutabaga shared between threads.  It is `Send` and `Sync`.
+///
+/// Everything done through `lock()` is serialized, as if Rutabaga were behind a mutex.
+/// `submit_command()` and `create_fence()` on a context of a component that allows concurrent
+/// contexts only take that context's lock while the context runs.  Only cross-domain allows it:
+/// its contexts block on host sockets, while other components share state between contexts, or
+/// are not thread-safe.  Such work runs concurrently with work on other contexts and with
+/// whatever holds the core lock, such as resource creation or fence polling.  Work on the same
+/// context is serialized.  A context destroyed while a submission to it runs is dropped once the
+/// submission returns.
+///
+/// The resource queries of `RutabagaShared` only lock the shard of the resource, so they don't
+/// wait for the core lock either.
+///
+/// Callbacks invoked while a lock is held, such as the fence handler of a synchronous component,
+/// must not call back into `RutabagaShared`.
+pub struct RutabagaShared {
+    core: Mutex<Rutabaga>,
+    contexts: RutabagaContexts,
+    resources: RutabagaResources,
+    submitter: RutabagaSubmitter,
+}
+
+impl RutabagaShared {
+    pub(crate) fn new(
+        rutabaga: Rutabaga,
+        contexts: RutabagaContexts,
+        resources: RutabagaResources,
+        submitter: RutabagaSubmitter,
+    ) -> RutabagaShared {
+        RutabagaShared {
+            core: Mutex::new(rutabaga),
+            contexts,
+            resources,
+            submitter,
+        }
+    }
+
+    /// Takes the core lock, for every operation other than concurrent submissions.
+    pub fn lock(&self) -> MutexGuard<'_, Rutabaga> {
+        self.core.lock().unwrap()
+    }
+
+    /// Like `Rutabaga::submit_command()`, but without the core lock when the context allows it.
+    pub fn submit_command(
+        &self,
+        ctx_id: u32,
+        commands: &mut [u8],
+        fence_ids: &[u64],
+    ) -> RutabagaResult<()> {
+        // Shareable fences are kept by the core.
+        let context = match self.contexts.get_concurrent(ctx_id) {
+            Some(context) if !cfg!(fence_passing_option1) || fence_ids.is_empty() => context,
+            _ => return self.lock().submit_command(ctx_id, commands, fence_ids),
+        };
+
+        self.submitter
+            .submit(&context, ctx_id, commands, fence_ids, Vec::new())
+    }
+
+    /// Like `Rutabaga::create_fence()`, but the context creates ring fences without the core lock
+    /// when it allows it, so a context blocked in a submission doesn't stall the core.
+    pub fn create_fence(&self, fence: RutabagaFence) -> RutabagaResult<()> {
+        let shareable = fence.flags & RUTABAGA_FLAG_FENCE_HOST_SHAREABLE != 0;
+        let context = match self.contexts.get_concurrent(fence.ctx_id) {
+            Some(context)
+                if fence.flags & RUTABAGA_FLAG_INFO_RING_IDX != 0
+                    && (!cfg!(fence_passing_option1) || !shareable) =>
+            {
+                context
+            }
+            _ => return self.lock().create_fence(fence),
+        };
+
+        if !self.lock().fence_begin(fence)? {
+            return Ok(());
+        }
+
+        let result = self.submitter.create_fence(&context, fence).map(|_| ());
+        if result.is_err() {
+            self.lock().fence_rejected(fence);
+        }
+
+        result
+    }
+
+    /// Like `Rutabaga::map_info()`, without the core lock.
+    pub fn map_info(&self, resource_id: u32) -> RutabagaResult<u32> {
+        self.resources.map_info(resource_id)
+    }
+
+    /// Like `Rutabaga::vulkan_info()`, without the core lock.
+    pub fn vulkan_info(&self, resource_id: u32) -> RutabagaResult<VulkanInfo> {
+        self.resources.vulkan_info(resource_id)
+    }
+
+    /// Like `Rutabaga::resource3d_info()`, without the core lock.
+    pub fn resource3d_info(&self, resource_id: u32) -> RutabagaResult<Resource3DInfo> {
+        self.resources.resource3d_info(resource_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::*;
+
+    fn new_shared_cross_domain(fence_handler: RutabagaFenceHandler) -> RutabagaShared {
+        let shared = RutabagaBuilder::new(1 << RUTABAGA_CAPSET_CROSS_DOMAIN, fence_handler)
+            .set_default_component(RutabagaComponentType::Rutabaga2D)
+            .build()
+            .unwrap()
+            .into_shared();
+
+        for ctx_id in 1..=2 {
+            shared
+                .lock()
+                .create_context(ctx_id, RUTABAGA_CAPSET_CROSS_DOMAIN, None)
+                .unwrap();
+        }
+
+        shared
+    }
+
+    // A CROSS_DOMAIN_CMD_POLL command, which cross-domain contexts accept before initialization.
+    const CROSS_DOMAIN_POLL: [u8; 8] = [3, 0, 8, 0, 0, 0, 0, 0];
+
+    #[test]
+    fn shared_concurrent_submit() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<RutabagaShared>();
+
+        let shared = new_shared_cross_domain(RutabagaHandler::new(|_| {}));
+
+        // Submissions to cross-domain contexts don't wait for the core lock, held here.
+        let core = shared.lock();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::scope(|s| {
+            for ctx_id in 1..=2 {
+                let shared = &shared;
+                let sender = sender.clone();
+                s.spawn(move || {
+                    let mut commands = CROSS_DOMAIN_POLL;
+                    let result = shared.submit_command(ctx_id, &mut commands, &[]);
+                    sender.send((ctx_id, result.is_ok())).unwrap();
+                });
+            }
+
+            let mut results: Vec<(u32, bool)> = (1..=2)
+                .map(|_| receiver.recv_timeout(Duration::from_secs(10)).unwrap())
+                .collect();
+            results.sort();
+            assert_eq!(results, vec![(1, true), (2, true)]);
+            drop(core);
+        });
+
+        // Unknown contexts go through the core.
+        shared.lock().destroy_context(1).unwrap();
+        let mut commands = CROSS_DOMAIN_POLL;
+        assert!(matches!(
+            shared.submit_command(1, &mut commands, &[]),
+            Err(RutabagaError::InvalidContextId)
+        ));
+        shared.submit_command(2, &mut commands, &[]).unwrap();
+        let debug_info = shared.lock().debug_info();
+        let stats = debug_info
+            .contexts
+            .iter()
+            .find(|ctx| ctx.ctx_id == 2)
+            .unwrap();
+        assert_eq!(stats.submit_count, 2);
+    }
+
+    #[test]
+    fn shared_fence_waits_without_core_lock() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let sender = std::sync::Mutex::new(sender);
+        let shared = new_shared_cross_domain(RutabagaHandler::new(move |fence: RutabagaFence| {
+            sender.lock().unwrap().send(fence.fence_id).unwrap()
+        }));
+
+        // Stands in for a submission blocked on the host.
+        let context = shared.contexts.get(1).unwrap();
+        let busy = context.lock().unwrap();
+        std::thread::scope(|s| {
+            let fence_thread = s.spawn(|| {
+                shared.create_fence(RutabagaFence {
+                    flags: RUTABAGA_FLAG_FENCE | RUTABAGA_FLAG_INFO_RING_IDX,
+                    fence_id: 7,
+                    ctx_id: 1,
+                    ring_idx: 0,
+                })
+            });
+
+            // The fence waits for the context, but not while holding the core lock.
+            std::thread::sleep(Duration::from_millis(100));
+            assert!(shared.core.try_lock().is_ok());
+            assert!(receiver.try_recv().is_err());
+
+            drop(busy);
+            fence_thread.join().unwrap().unwrap();
+        });
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(10)), Ok(7));
+    }
+
+    fn resource(resource_id: u32, map_info: Option<u32>) -> RutabagaResource {
+        RutabagaResource {
+            resource_id,
+            handle: None,
+            blob: true,
+            blob_mem: RUTABAGA_BLOB_MEM_HOST3D,
+            blob_flags: 0,
+            map_info,
+            info_2d: None,
+            info_3d: None,
+            vulkan_info: None,
+            backing_iovecs: None,
+            component_mask: 0,
+            size: 4096,
+            mapping: None,
+        }
+    }
+
+    #[test]
+    fn resources_are_sharded() {
+        let mut resources = RutabagaResources::default();
+        for resource_id in [RESOURCE_SHARDS as u32 + 1, 1, 2] {
+            resources.insert(resource_id, resource(resource_id, Some(resource_id)));
+        }
+        assert_eq!(resources.len(), 3);
+        assert_eq!(resources.ids(), vec![1, 2, RESOURCE_SHARDS as u32 + 1]);
+
+        // A resource being changed only locks its shard.
+        let shared = resources.clone();
+        let mut changing = resources.get_mut(&1).unwrap();
+        changing.size = 8192;
+        assert_eq!(shared.map_info(2).unwrap(), 2);
+        drop(changing);
+        assert_eq!(shared.get(&1).unwrap().size, 8192);
+
+        assert!(resources.remove(&(RESOURCE_SHARDS as u32 + 1)).is_some());
+        assert!(shared.get(&(RESOURCE_SHARDS as u32 + 1)).is_none());
+        assert!(shared.map_info(3).is_err());
+
+        resources.replace(Map::from([(5, resource(5, None))]));
+        assert_eq!(shared.ids(), vec![5]);
+    }
+
+    #[test]
+    fn shared_resource_queries_skip_core_lock() {
+        let shared = new_shared_cross_domain(RutabagaHandler::new(|_| {}));
+        shared
+            .resources
+            .clone()
+            .insert(3, resource(3, Some(RUTABAGA_MAP_CACHE_CACHED)));
+
+        let core = shared.lock();
+        assert_eq!(shared.map_info(3).unwrap(), RUTABAGA_MAP_CACHE_CACHED);
+        assert!(shared.vulkan_info(3).is_err());
+        assert!(matches!(
+            shared.resource3d_info(4),
+            Err(RutabagaError::InvalidResourceId)
+        ));
+        assert_eq!(core.map_info(3).unwrap(), RUTABAGA_MAP_CACHE_CACHED);
+    }
+}
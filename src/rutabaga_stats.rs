@@ -0,0 +1,374 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! rutabaga_stats: per-context usage statistics, for debugging slow guest graphics.
+
+use std::collections::BTreeMap as Map;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use log::info;
+use mesa3d_util::MesaError;
+use serde::Serialize;
+
+use crate::rutabaga_utils::RutabagaFence;
+use crate::rutabaga_utils::RutabagaFenceKey;
+use crate::rutabaga_utils::RutabagaResult;
+use crate::rutabaga_utils::RUTABAGA_MAX_RESOURCE_NAME;
+
+/// Number of buckets in the fence latency histogram.
+pub const RUTABAGA_FENCE_LATENCY_BUCKETS: usize = 8;
+
+/// Exclusive upper bounds, in microseconds, of every fence latency bucket except the last one.
+/// The last bucket counts everything slower.
+pub const RUTABAGA_FENCE_LATENCY_BUCKET_LIMITS_US: [u64; RUTABAGA_FENCE_LATENCY_BUCKETS - 1] =
+    [100, 500, 1_000, 4_000, 16_000, 50_000, 100_000];
+
+/// Usage statistics of a single context.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RutabagaContextStats {
+    pub ctx_id: u32,
+    pub context_name: Option<String>,
+    pub submit_count: u64,
+    pub submit_bytes: u64,
+    pub transfer_count: u64,
+    pub transfer_bytes: u64,
+    pub fences_created: u64,
+    pub fences_signaled: u64,
+    pub fence_latency_histogram: [u64; RUTABAGA_FENCE_LATENCY_BUCKETS],
+    pub max_fence_latency_us: u64,
+}
+
+/// A live resource, for finding leaked or misbehaving resources.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RutabagaResourceStats {
+    pub resource_id: u32,
+    /// The label given with `Rutabaga::set_resource_name()`.
+    pub name: Option<String>,
+    pub size: u64,
+    pub blob: bool,
+    /// The resident pages of a lazily mapped blob, that is the pages the guest has touched.
+    pub resident_pages: Option<u64>,
+}
+
+/// A snapshot of the usage statistics of every live context, as returned by
+/// `Rutabaga::debug_info()`.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RutabagaDebugInfo {
+    pub contexts: Vec<RutabagaContextStats>,
+    pub resources: Vec<RutabagaResourceStats>,
+}
+
+impl fmt::Display for RutabagaDebugInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for ctx in &self.contexts {
+            writeln!(
+                f,
+                "ctx {} ({}): {} submits ({} bytes), {} transfers ({} bytes), \
+                 {}/{} fences signaled, max fence latency {}us, histogram {:?}",
+                ctx.ctx_id,
+                ctx.context_name.as_deref().unwrap_or("unnamed"),
+                ctx.submit_count,
+                ctx.submit_bytes,
+                ctx.transfer_count,
+                ctx.transfer_bytes,
+                ctx.fences_signaled,
+                ctx.fences_created,
+                ctx.max_fence_latency_us,
+                ctx.fence_latency_histogram,
+            )?;
+        }
+
+        for resource in &self.resources {
+            writeln!(
+                f,
+                "res {} ({}): {} bytes{}",
+                resource.resource_id,
+                resource.name.as_deref().unwrap_or("unnamed"),
+                resource.size,
+                if resource.blob { ", blob" } else { "" },
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Guest-provided resource labels.  Shared with the components whose guests label resources
+/// through their command stream.
+#[derive(Clone, Default)]
+pub struct RutabagaResourceNames {
+    names: Arc<Mutex<Map<u32, String>>>,
+}
+
+impl RutabagaResourceNames {
+    pub fn get(&self, resource_id: u32) -> Option<String> {
+        self.names.lock().unwrap().get(&resource_id).cloned()
+    }
+
+    /// Labels the resource given by `resource_id` with `name`, or clears its label if `name` is
+    /// None.  Callers check that the resource exists.
+    pub fn set(&self, resource_id: u32, name: Option<&str>) -> RutabagaResult<()> {
+        let mut names = self.names.lock().unwrap();
+        match name {
+            Some(name) if name.len() > RUTABAGA_MAX_RESOURCE_NAME => {
+                return Err(MesaError::WithContext("resource name is too long").into());
+            }
+            Some(name) => names.insert(resource_id, name.to_string()),
+            None => names.remove(&resource_id),
+        };
+
+        Ok(())
+    }
+
+    pub fn remove(&self, resource_id: u32) {
+        self.names.lock().unwrap().remove(&resource_id);
+    }
+
+    /// Names a resource in log messages, along with its label if it has one.
+    pub fn label(&self, resource_id: u32) -> String {
+        match self.get(resource_id) {
+            Some(name) => format!("resource {resource_id} ({name:?})"),
+            None => format!("resource {resource_id}"),
+        }
+    }
+
+    pub fn snapshot(&self) -> Map<u32, String> {
+        self.names.lock().unwrap().clone()
+    }
+
+    pub fn restore(&self, names: Map<u32, String>) {
+        *self.names.lock().unwrap() = names;
+    }
+}
+
+/// Most fences whose latency is measured at once.  Past it, the oldest fence is forgotten, so
+/// fences that are never signaled can't grow the map without bound.
+const MAX_PENDING_FENCES: usize = 4096;
+
+#[derive(Default)]
+struct RutabagaStatsInner {
+    contexts: Map<u32, RutabagaContextStats>,
+    // Context and creation time of every outstanding fence.
+    pending_fences: Map<RutabagaFenceKey, (u32, Instant)>,
+    last_log: Option<Instant>,
+}
+
+/// Collects per-context statistics.
+#[derive(Clone, Default)]
+pub struct RutabagaStats {
+    inner: Arc<Mutex<RutabagaStatsInner>>,
+    log_interval: Option<Duration>,
+}
+
+fn latency_bucket(latency_us: u64) -> usize {
+    RUTABAGA_FENCE_LATENCY_BUCKET_LIMITS_US
+        .iter()
+        .position(|&limit| latency_us < limit)
+        .unwrap_or(RUTABAGA_FENCE_LATENCY_BUCKETS - 1)
+}
+
+impl RutabagaStats {
+    /// Returns a new `RutabagaStats`.  If `log_interval` is given, the statistics are logged at
+    /// most once per interval.
+    pub fn new(log_interval: Option<Duration>) -> RutabagaStats {
+        RutabagaStats {
+            inner: Default::default(),
+            log_interval,
+        }
+    }
+
+    pub fn context_created(&self, ctx_id: u32, context_name: Option<&str>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.contexts.insert(
+            ctx_id,
+            RutabagaContextStats {
+                ctx_id,
+                context_name: context_name.map(|name| name.to_string()),
+                ..Default::default()
+            },
+        );
+    }
+
+    pub fn context_destroyed(&self, ctx_id: u32) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.contexts.remove(&ctx_id);
+        inner
+            .pending_fences
+            .retain(|_, (fence_ctx_id, _)| *fence_ctx_id != ctx_id);
+    }
+
+    pub fn record_submit(&self, ctx_id: u32, bytes: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(ctx) = inner.contexts.get_mut(&ctx_id) {
+            ctx.submit_count += 1;
+            ctx.submit_bytes += bytes as u64;
+        }
+
+        self.maybe_log(&mut inner);
+    }
+
+    pub fn record_transfer(&self, ctx_id: u32, bytes: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(ctx) = inner.contexts.get_mut(&ctx_id) {
+            ctx.transfer_count += 1;
+            ctx.transfer_bytes += bytes as u64;
+        }
+    }
+
+    /// Records a fence before it's handed to a component, which may signal it right away.  If the
+    /// component rejects it, `fence_dropped()` must be called.
+    pub fn fence_created(&self, fence: &RutabagaFence) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(ctx) = inner.contexts.get_mut(&fence.ctx_id) else {
+            return;
+        };
+
+        ctx.fences_created += 1;
+        if inner.pending_fences.len() >= MAX_PENDING_FENCES {
+            let oldest = inner
+                .pending_fences
+                .iter()
+                .min_by_key(|(_, (_, created))| *created)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                inner.pending_fences.remove(&oldest);
+            }
+        }
+
+        inner
+            .pending_fences
+            .insert(fence.key(), (fence.ctx_id, Instant::now()));
+    }
+
+    /// Forgets a fence the component rejected, which will never signal.
+    pub fn fence_dropped(&self, fence: &RutabagaFence) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some((ctx_id, _)) = inner.pending_fences.remove(&fence.key()) {
+            if let Some(ctx) = inner.contexts.get_mut(&ctx_id) {
+                ctx.fences_created = ctx.fences_created.saturating_sub(1);
+            }
+        }
+    }
+
+    pub fn fence_signaled(&self, fence: &RutabagaFence) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some((ctx_id, created)) = inner.pending_fences.remove(&fence.key()) {
+            let latency_us = created.elapsed().as_micros().try_into().unwrap_or(u64::MAX);
+            if let Some(ctx) = inner.contexts.get_mut(&ctx_id) {
+                ctx.fences_signaled += 1;
+                ctx.fence_latency_histogram[latency_bucket(latency_us)] += 1;
+                ctx.max_fence_latency_us = ctx.max_fence_latency_us.max(latency_us);
+            }
+        }
+
+        self.maybe_log(&mut inner);
+    }
+
+    /// Returns a snapshot of the statistics of every live context.
+    pub fn debug_info(&self) -> RutabagaDebugInfo {
+        let inner = self.inner.lock().unwrap();
+        RutabagaDebugInfo {
+            contexts: inner.contexts.values().cloned().collect(),
+            ..Default::default()
+        }
+    }
+
+    fn maybe_log(&self, inner: &mut RutabagaStatsInner) {
+        let Some(log_interval) = self.log_interval else {
+            return;
+        };
+
+        let now = Instant::now();
+        let last_log = *inner.last_log.get_or_insert(now);
+        if now.duration_since(last_log) < log_interval {
+            return;
+        }
+
+        inner.last_log = Some(now);
+        let debug_info = RutabagaDebugInfo {
+            contexts: inner.contexts.values().cloned().collect(),
+            ..Default::default()
+        };
+        info!("rutabaga context statistics:\n{}", debug_info);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rutabaga_utils::RUTABAGA_FLAG_FENCE;
+    use crate::rutabaga_utils::RUTABAGA_FLAG_INFO_RING_IDX;
+
+    fn ring_fence(ctx_id: u32, ring_idx: u8, fence_id: u64) -> RutabagaFence {
+        RutabagaFence {
+            flags: RUTABAGA_FLAG_FENCE | RUTABAGA_FLAG_INFO_RING_IDX,
+            fence_id,
+            ctx_id,
+            ring_idx,
+        }
+    }
+
+    fn fence_counts(stats: &RutabagaStats) -> Vec<(u32, u64, u64)> {
+        stats
+            .debug_info()
+            .contexts
+            .iter()
+            .map(|ctx| (ctx.ctx_id, ctx.fences_created, ctx.fences_signaled))
+            .collect()
+    }
+
+    #[test]
+    fn fences_are_keyed_by_ring() {
+        let stats = RutabagaStats::new(None);
+        stats.context_created(1, None);
+        stats.context_created(2, None);
+
+        // Every fence has id 1.
+        stats.fence_created(&ring_fence(1, 0, 1));
+        stats.fence_created(&ring_fence(1, 1, 1));
+        stats.fence_created(&ring_fence(2, 0, 1));
+        stats.fence_signaled(&ring_fence(2, 0, 1));
+        stats.fence_signaled(&ring_fence(1, 1, 1));
+        assert_eq!(fence_counts(&stats), vec![(1, 2, 1), (2, 1, 1)]);
+
+        // A fence signals once.
+        stats.fence_signaled(&ring_fence(1, 1, 1));
+        assert_eq!(fence_counts(&stats), vec![(1, 2, 1), (2, 1, 1)]);
+    }
+
+    #[test]
+    fn dropped_fences_are_not_counted() {
+        let stats = RutabagaStats::new(None);
+        stats.context_created(1, None);
+        stats.fence_created(&ring_fence(1, 0, 1));
+        stats.fence_dropped(&ring_fence(1, 0, 1));
+        assert_eq!(fence_counts(&stats), vec![(1, 0, 0)]);
+        assert!(stats.inner.lock().unwrap().pending_fences.is_empty());
+    }
+
+    #[test]
+    fn pending_fences_are_bounded() {
+        let stats = RutabagaStats::new(None);
+        stats.context_created(1, None);
+        for fence_id in 0..MAX_PENDING_FENCES as u64 + 1 {
+            stats.fence_created(&ring_fence(1, 0, fence_id));
+        }
+
+        let inner = stats.inner.lock().unwrap();
+        assert_eq!(inner.pending_fences.len(), MAX_PENDING_FENCES);
+        assert!(!inner.pending_fences.contains_key(&(1, 0, 0)));
+    }
+
+    #[test]
+    fn latency_buckets() {
+        assert_eq!(latency_bucket(0), 0);
+        assert_eq!(latency_bucket(100), 1);
+        assert_eq!(latency_bucket(99_999), RUTABAGA_FENCE_LATENCY_BUCKETS - 2);
+        assert_eq!(latency_bucket(u64::MAX), RUTABAGA_FENCE_LATENCY_BUCKETS - 1);
+    }
+}
@@ -0,0 +1,77 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! rutabaga_subscribers: delivers signaled fences to listeners besides the VMM's fence handler,
+//! such as a display path that only cares about scanout fences.
+
+use std::collections::BTreeMap as Map;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use crate::rutabaga_utils::RutabagaFence;
+use crate::rutabaga_utils::RutabagaFenceHandler;
+use crate::rutabaga_utils::RUTABAGA_FLAG_INFO_RING_IDX;
+
+/// Selects the fences a subscriber receives.  Fields left unset match every fence.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RutabagaFenceFilter {
+    pub ctx_id: Option<u32>,
+    /// Only fences with RUTABAGA_FLAG_INFO_RING_IDX set match a ring.
+    pub ring_idx: Option<u8>,
+}
+
+impl RutabagaFenceFilter {
+    fn matches(&self, fence: &RutabagaFence) -> bool {
+        let has_ring = fence.flags & RUTABAGA_FLAG_INFO_RING_IDX != 0;
+        self.ctx_id.map_or(true, |ctx_id| fence.ctx_id == ctx_id)
+            && self
+                .ring_idx
+                .map_or(true, |ring_idx| has_ring && fence.ring_idx == ring_idx)
+    }
+}
+
+#[derive(Default)]
+struct RutabagaSubscribersInner {
+    subscribers: Map<u64, (RutabagaFenceFilter, RutabagaFenceHandler)>,
+    next_id: u64,
+}
+
+/// Fence subscribers.
+#[derive(Clone, Default)]
+pub struct RutabagaFenceSubscribers {
+    inner: Arc<Mutex<RutabagaSubscribersInner>>,
+}
+
+impl RutabagaFenceSubscribers {
+    pub fn subscribe(&self, filter: RutabagaFenceFilter, handler: RutabagaFenceHandler) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let id = inner.next_id;
+        inner.subscribers.insert(id, (filter, handler));
+        id
+    }
+
+    /// Returns false if `id` isn't subscribed.
+    pub fn unsubscribe(&self, id: u64) -> bool {
+        self.inner.lock().unwrap().subscribers.remove(&id).is_some()
+    }
+
+    /// Forwards `fence` to every matching subscriber.
+    pub fn fence_signaled(&self, fence: RutabagaFence) {
+        // Handlers are called without the lock held, so they may subscribe or unsubscribe.
+        let handlers: Vec<RutabagaFenceHandler> = self
+            .inner
+            .lock()
+            .unwrap()
+            .subscribers
+            .values()
+            .filter(|(filter, _)| filter.matches(&fence))
+            .map(|(_, handler)| handler.clone())
+            .collect();
+
+        for handler in handlers {
+            handler.call(fence);
+        }
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! rutabaga_trace: host-side timelines of guest GPU work, emitted as `tracing` spans and events
+//! so they can be correlated with guest traces by context, resource and fence id.
+
+#[cfg(feature = "tracing")]
+use std::collections::BTreeMap as Map;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+#[cfg(feature = "tracing")]
+use std::sync::Mutex;
+
+use crate::rutabaga_utils::RutabagaFence;
+
+/// Keeps a span entered until dropped.  Does nothing when tracing is disabled.
+#[must_use]
+pub struct RutabagaTraceGuard {
+    #[cfg(feature = "tracing")]
+    _span: Option<tracing::span::EnteredSpan>,
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! trace_span {
+    ($trace:expr, $name:literal, $($fields:tt)*) => {
+        RutabagaTraceGuard {
+            _span: $trace
+                .enabled()
+                .then(|| tracing::info_span!(target: "rutabaga", $name, $($fields)*).entered()),
+        }
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_span {
+    ($trace:expr, $name:literal, $($fields:tt)*) => {
+        RutabagaTraceGuard {}
+    };
+}
+
+/// The deepest nesting of debug marker spans per context.  Deeper BEGIN markers are only counted,
+/// so that a guest that never ends its spans can't grow the host's memory use.
+#[cfg(feature = "tracing")]
+const RUTABAGA_TRACE_MAX_MARKER_DEPTH: usize = 64;
+
+/// A guest debug marker, for annotating host traces with the structure of guest work.
+#[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+#[derive(Copy, Clone, Debug)]
+pub enum RutabagaDebugMarker<'a> {
+    /// Opens a span, closed by the next `End` of the context.
+    Begin(&'a str),
+    End,
+    /// Marks a single point.
+    Insert(&'a str),
+}
+
+// The open debug marker spans of a context.  Spans opened while tracing was disabled are kept as
+// None, so that every END still closes the BEGIN it pairs with.
+#[cfg(feature = "tracing")]
+#[derive(Default)]
+struct RutabagaMarkerStack {
+    spans: Vec<Option<tracing::Span>>,
+    overflow: usize,
+}
+
+/// Emits spans and events for guest GPU work while enabled, which `Rutabaga::set_tracing()`
+/// toggles at runtime.  Without the `tracing` feature, nothing is ever emitted.
+#[derive(Clone, Default)]
+pub struct RutabagaTrace {
+    enabled: Arc<AtomicBool>,
+    #[cfg(feature = "tracing")]
+    markers: Arc<Mutex<Map<u32, RutabagaMarkerStack>>>,
+}
+
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+impl RutabagaTrace {
+    pub fn new(enabled: bool) -> RutabagaTrace {
+        RutabagaTrace {
+            enabled: Arc::new(AtomicBool::new(enabled)),
+            #[cfg(feature = "tracing")]
+            markers: Default::default(),
+        }
+    }
+
+    #[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn submit_cmd(&self, ctx_id: u32, bytes: usize) -> RutabagaTraceGuard {
+        trace_span!(self, "submit_cmd", ctx_id, bytes)
+    }
+
+    pub fn create_blob(&self, ctx_id: u32, resource_id: u32, size: u64) -> RutabagaTraceGuard {
+        trace_span!(self, "create_blob", ctx_id, resource_id, size)
+    }
+
+    pub fn transfer_write(&self, ctx_id: u32, resource_id: u32) -> RutabagaTraceGuard {
+        trace_span!(self, "transfer_write", ctx_id, resource_id)
+    }
+
+    pub fn transfer_read(&self, ctx_id: u32, resource_id: u32) -> RutabagaTraceGuard {
+        trace_span!(self, "transfer_read", ctx_id, resource_id)
+    }
+
+    /// Emits an event associating a context with its guest-provided name.
+    pub fn context_named(&self, ctx_id: u32, name: &str) {
+        #[cfg(feature = "tracing")]
+        if self.enabled() {
+            tracing::info!(target: "rutabaga", ctx_id, name, "context_named");
+        }
+    }
+
+    /// Emits an event associating a resource with its guest-provided name.
+    pub fn resource_named(&self, resource_id: u32, name: &str) {
+        #[cfg(feature = "tracing")]
+        if self.enabled() {
+            tracing::info!(target: "rutabaga", resource_id, name, "resource_named");
+        }
+    }
+
+    /// Forwards a debug marker of the guest context `ctx_id`.  Marker spans aren't nested in the
+    /// span of the submission carrying them, since they usually outlive it.
+    pub fn debug_marker(&self, ctx_id: u32, marker: RutabagaDebugMarker) {
+        #[cfg(feature = "tracing")]
+        {
+            let mut markers = self.markers.lock().unwrap();
+            let stack = markers.entry(ctx_id).or_default();
+            match marker {
+                RutabagaDebugMarker::Begin(_)
+                    if stack.spans.len() >= RUTABAGA_TRACE_MAX_MARKER_DEPTH =>
+                {
+                    stack.overflow += 1;
+                }
+                RutabagaDebugMarker::Begin(label) => {
+                    let span = self.enabled().then(|| {
+                        tracing::info_span!(
+                            target: "rutabaga",
+                            parent: None,
+                            "debug_marker",
+                            ctx_id,
+                            label
+                        )
+                    });
+                    stack.spans.push(span);
+                }
+                RutabagaDebugMarker::End if stack.overflow > 0 => stack.overflow -= 1,
+                RutabagaDebugMarker::End => {
+                    stack.spans.pop();
+                }
+                RutabagaDebugMarker::Insert(label) => {
+                    if self.enabled() {
+                        tracing::info!(target: "rutabaga", ctx_id, label, "debug_marker");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Closes the debug marker spans left open by a destroyed context.
+    pub fn context_destroyed(&self, ctx_id: u32) {
+        #[cfg(feature = "tracing")]
+        self.markers.lock().unwrap().remove(&ctx_id);
+    }
+
+    pub fn fence_signaled(&self, fence: &RutabagaFence) {
+        #[cfg(feature = "tracing")]
+        if self.enabled() {
+            tracing::info!(
+                target: "rutabaga",
+                ctx_id = fence.ctx_id,
+                fence_id = fence.fence_id,
+                ring_idx = fence.ring_idx,
+                "fence_signaled"
+            );
+        }
+    }
+}
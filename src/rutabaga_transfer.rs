@@ -0,0 +1,170 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! rutabaga_transfer: runs transfers on a worker thread, so large copies don't stall the VMM's
+//! virtio queue.
+//!
+//! Transfers run one at a time, in the order they were queued, and each signals its fence once it
+//! is done.  Rutabaga waits for the pending transfers of a resource before anything else touches
+//! its memory.
+
+use std::collections::BTreeMap as Map;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+
+use log::error;
+use mesa3d_util::MesaError;
+
+use crate::rutabaga_utils::RutabagaFence;
+use crate::rutabaga_utils::RutabagaFenceHandler;
+use crate::rutabaga_utils::RutabagaResult;
+use crate::rutabaga_utils::RutabagaThreadConfig;
+use crate::rutabaga_utils::RutabagaThreadType;
+
+/// A transfer that a component prepared to run off the calling thread.
+pub(crate) type RutabagaTransferJob = Box<dyn FnOnce() -> RutabagaResult<()> + Send>;
+
+struct TransferRequest {
+    resource_id: u32,
+    job: RutabagaTransferJob,
+    fence: RutabagaFence,
+}
+
+/// The number of queued or running transfers, by resource.
+#[derive(Default)]
+struct PendingTransfers {
+    resources: Mutex<Map<u32, usize>>,
+    done: Condvar,
+}
+
+impl PendingTransfers {
+    fn add(&self, resource_id: u32) {
+        *self
+            .resources
+            .lock()
+            .unwrap()
+            .entry(resource_id)
+            .or_default() += 1;
+    }
+
+    fn remove(&self, resource_id: u32) {
+        let mut resources = self.resources.lock().unwrap();
+        if let Some(count) = resources.get_mut(&resource_id) {
+            *count -= 1;
+            if *count == 0 {
+                resources.remove(&resource_id);
+            }
+        }
+        self.done.notify_all();
+    }
+}
+
+pub(crate) struct RutabagaTransferQueue {
+    sender: Option<mpsc::Sender<TransferRequest>>,
+    pending: Arc<PendingTransfers>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl RutabagaTransferQueue {
+    /// Starts the worker, which signals the fences of finished transfers through `fence_handler`.
+    pub(crate) fn new(
+        fence_handler: RutabagaFenceHandler,
+        thread_config: RutabagaThreadConfig,
+    ) -> RutabagaResult<RutabagaTransferQueue> {
+        let (sender, receiver) = mpsc::channel::<TransferRequest>();
+        let (started_sender, started_receiver) = mpsc::channel();
+        let pending: Arc<PendingTransfers> = Default::default();
+
+        let worker_pending = pending.clone();
+        let worker = thread_config
+            .builder(RutabagaThreadType::TransferWorker)
+            .spawn(move || {
+                thread_config.apply(RutabagaThreadType::TransferWorker);
+                let confined = thread_config.confine();
+                let failed = confined.is_err();
+                let _ = started_sender.send(confined);
+                if failed {
+                    return;
+                }
+
+                for request in receiver {
+                    // Errors can't be returned to the guest by now, but the fence must still
+                    // signal for it to make progress.
+                    if let Err(e) = (request.job)() {
+                        error!("transfer to resource {} failed: {}", request.resource_id, e);
+                    }
+                    worker_pending.remove(request.resource_id);
+                    fence_handler.call(request.fence);
+                }
+            })
+            .map_err(MesaError::IoError)?;
+
+        started_receiver
+            .recv()
+            .map_err(|_| MesaError::WithContext("transfer worker exited during startup"))??;
+
+        Ok(RutabagaTransferQueue {
+            sender: Some(sender),
+            pending,
+            worker: Some(worker),
+        })
+    }
+
+    /// Queues `job`, which accesses the memory of the resource given by `resource_id`, and
+    /// signals `fence` once it has run.
+    pub(crate) fn queue(
+        &self,
+        resource_id: u32,
+        job: RutabagaTransferJob,
+        fence: RutabagaFence,
+    ) -> RutabagaResult<()> {
+        let sender = self.sender.as_ref().ok_or(MesaError::Unsupported)?;
+
+        self.pending.add(resource_id);
+        let request = TransferRequest {
+            resource_id,
+            job,
+            fence,
+        };
+        if sender.send(request).is_err() {
+            self.pending.remove(resource_id);
+            return Err(MesaError::WithContext("transfer worker exited").into());
+        }
+
+        Ok(())
+    }
+
+    /// Blocks until the queued transfers of the resource given by `resource_id` have run.
+    pub(crate) fn wait(&self, resource_id: u32) {
+        let resources = self.pending.resources.lock().unwrap();
+        let _resources = self
+            .pending
+            .done
+            .wait_while(resources, |resources| resources.contains_key(&resource_id))
+            .unwrap();
+    }
+
+    /// Blocks until every queued transfer has run.
+    pub(crate) fn wait_idle(&self) {
+        let resources = self.pending.resources.lock().unwrap();
+        let _resources = self
+            .pending
+            .done
+            .wait_while(resources, |resources| !resources.is_empty())
+            .unwrap();
+    }
+}
+
+impl Drop for RutabagaTransferQueue {
+    fn drop(&mut self) {
+        // Closing the channel lets the worker finish the queued transfers and exit.
+        self.sender.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
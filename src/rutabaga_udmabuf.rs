@@ -0,0 +1,257 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! rutabaga_udmabuf: turns guest memory blobs into host dmabufs with the udmabuf driver, so they
+//! can be scanned out or imported by other devices without copying.
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Error as SysError;
+use std::os::fd::AsRawFd;
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+
+use mesa3d_util::AsRawDescriptor;
+use mesa3d_util::MesaError;
+use mesa3d_util::MesaHandle;
+use mesa3d_util::OwnedDescriptor;
+use mesa3d_util::MESA_HANDLE_TYPE_MEM_DMABUF;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+
+use crate::rutabaga_utils::RutabagaError;
+use crate::rutabaga_utils::RutabagaIovec;
+use crate::rutabaga_utils::RutabagaResult;
+
+const UDMABUF_FLAGS_CLOEXEC: u32 = 0x01;
+// _IOW('u', 0x43, struct udmabuf_create_list)
+const UDMABUF_CREATE_LIST: u64 = 0x40087543;
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Copy, Clone, IntoBytes, Immutable)]
+struct udmabuf_create_list {
+    flags: u32,
+    count: u32,
+    // Followed by `count` udmabuf_create_item.
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Copy, Clone, IntoBytes, Immutable)]
+struct udmabuf_create_item {
+    memfd: u32,
+    pad: u32,
+    offset: u64,
+    size: u64,
+}
+
+/// A memfd holding guest memory, which the VMM mapped at `host_addr`.  The memfd must be sealed
+/// with F_SEAL_SHRINK, as udmabuf requires.
+pub struct RutabagaMemfdRegion {
+    pub host_addr: u64,
+    pub size: u64,
+    pub memfd: OwnedDescriptor,
+    /// The offset into the memfd mapped at `host_addr`.
+    pub offset: u64,
+}
+
+pub(crate) struct RutabagaUdmabuf {
+    device: File,
+    regions: Vec<RutabagaMemfdRegion>,
+    page_size: u64,
+}
+
+impl RutabagaUdmabuf {
+    pub(crate) fn new() -> RutabagaResult<RutabagaUdmabuf> {
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/udmabuf")
+            .map_err(MesaError::IoError)?;
+
+        // SAFETY:
+        // sysconf has no preconditions.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+
+        Ok(RutabagaUdmabuf {
+            device,
+            regions: Vec::new(),
+            page_size: page_size.try_into().map_err(MesaError::TryFromIntError)?,
+        })
+    }
+
+    /// Fails if `region` is empty or overlaps a registered region, which would make the memfd
+    /// backing an address ambiguous.
+    pub(crate) fn add_region(&mut self, region: RutabagaMemfdRegion) -> RutabagaResult<()> {
+        let end = region
+            .host_addr
+            .checked_add(region.size)
+            .filter(|end| *end > region.host_addr)
+            .ok_or(RutabagaError::InvalidIovec)?;
+
+        let overlaps = self
+            .regions
+            .iter()
+            .any(|other| region.host_addr < other.host_addr + other.size && other.host_addr < end);
+        if overlaps {
+            return Err(MesaError::WithContext("memfd region overlaps another region").into());
+        }
+
+        self.regions.push(region);
+        Ok(())
+    }
+
+    /// Dmabufs created from the region keep its pages alive after it is removed.
+    pub(crate) fn remove_region(&mut self, host_addr: u64) -> RutabagaResult<()> {
+        let index = self
+            .regions
+            .iter()
+            .position(|region| region.host_addr == host_addr)
+            .ok_or(RutabagaError::InvalidIovec)?;
+
+        self.regions.remove(index);
+        Ok(())
+    }
+
+    /// Creates a dmabuf of the memory described by `iovecs`, which must be page aligned and lie
+    /// within the registered regions.
+    pub(crate) fn create(&self, iovecs: &[RutabagaIovec]) -> RutabagaResult<MesaHandle> {
+        let mut items: Vec<udmabuf_create_item> = Vec::new();
+        for iovec in iovecs {
+            if iovec.is_hole() {
+                return Err(MesaError::WithContext("sparse blobs can't be exported").into());
+            }
+
+            let start = iovec.base as u64;
+            let len = iovec.len as u64;
+            if start % self.page_size != 0 || len % self.page_size != 0 {
+                return Err(MesaError::WithContext("udmabuf needs page aligned iovecs").into());
+            }
+
+            let region = self
+                .regions
+                .iter()
+                .find(|region| {
+                    start >= region.host_addr && start - region.host_addr + len <= region.size
+                })
+                .ok_or(MesaError::WithContext("iovec isn't backed by a memfd"))?;
+
+            let memfd = region.memfd.as_raw_descriptor() as u32;
+            let offset = region.offset + (start - region.host_addr);
+            match items.last_mut() {
+                Some(last) if last.memfd == memfd && last.offset + last.size == offset => {
+                    last.size += len;
+                }
+                _ => items.push(udmabuf_create_item {
+                    memfd,
+                    pad: 0,
+                    offset,
+                    size: len,
+                }),
+            }
+        }
+
+        let list = udmabuf_create_list {
+            flags: UDMABUF_FLAGS_CLOEXEC,
+            count: items.len().try_into().map_err(MesaError::TryFromIntError)?,
+        };
+
+        // Allocated as u64 so the items are naturally aligned.
+        let mut buf = vec![0u64; (list.as_bytes().len() + items.as_bytes().len()) / 8];
+        let (header, list_items) = buf.as_mut_bytes().split_at_mut(list.as_bytes().len());
+        header.copy_from_slice(list.as_bytes());
+        list_items.copy_from_slice(items.as_bytes());
+
+        // SAFETY:
+        // The buffer holds a udmabuf_create_list followed by its `count` items, and the kernel
+        // only reads it.
+        let fd = unsafe {
+            libc::ioctl(
+                self.device.as_raw_fd(),
+                UDMABUF_CREATE_LIST as _,
+                buf.as_ptr(),
+            )
+        };
+        if fd < 0 {
+            return Err(MesaError::IoError(SysError::last_os_error()).into());
+        }
+
+        // SAFETY:
+        // The ioctl returned a new dmabuf, which nothing else owns.
+        let dmabuf = unsafe { OwnedFd::from_raw_fd(fd) };
+        Ok(MesaHandle {
+            os_handle: dmabuf.into(),
+            handle_type: MESA_HANDLE_TYPE_MEM_DMABUF,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mesa3d_util::SharedMemory;
+
+    use super::*;
+
+    // A udmabuf without the device, which only fails to create dmabufs.
+    fn new_udmabuf() -> RutabagaUdmabuf {
+        RutabagaUdmabuf {
+            device: File::open("/dev/null").unwrap(),
+            regions: Vec::new(),
+            page_size: 4096,
+        }
+    }
+
+    fn region(host_addr: u64, size: u64) -> RutabagaMemfdRegion {
+        RutabagaMemfdRegion {
+            host_addr,
+            size,
+            memfd: SharedMemory::new("udmabuf_region", size).unwrap().into(),
+            offset: 0,
+        }
+    }
+
+    fn iovec(base: u64, len: usize) -> RutabagaIovec {
+        RutabagaIovec {
+            base: base as *mut _,
+            len,
+        }
+    }
+
+    #[test]
+    fn regions_dont_overlap() {
+        let mut udmabuf = new_udmabuf();
+        udmabuf.add_region(region(0x10000, 0x4000)).unwrap();
+        udmabuf.add_region(region(0x14000, 0x1000)).unwrap();
+        udmabuf.add_region(region(0xf000, 0x1000)).unwrap();
+
+        assert!(udmabuf.add_region(region(0x13000, 0x2000)).is_err());
+        assert!(udmabuf.add_region(region(0xe000, 0x3000)).is_err());
+        assert!(udmabuf.add_region(region(0x11000, 0x1000)).is_err());
+        assert!(udmabuf.add_region(region(0x8000, 0x10000)).is_err());
+        assert!(udmabuf.add_region(region(0x20000, 0)).is_err());
+        assert!(udmabuf
+            .add_region(region(u64::MAX - 0xfff, 0x2000))
+            .is_err());
+
+        udmabuf.remove_region(0x14000).unwrap();
+        assert!(udmabuf.remove_region(0x14000).is_err());
+        udmabuf.add_region(region(0x14000, 0x2000)).unwrap();
+    }
+
+    #[test]
+    fn create_checks_iovecs() {
+        let mut udmabuf = new_udmabuf();
+        udmabuf.add_region(region(0x10000, 0x4000)).unwrap();
+
+        // Unaligned.
+        assert!(udmabuf.create(&[iovec(0x10800, 0x1000)]).is_err());
+        assert!(udmabuf.create(&[iovec(0x10000, 0x800)]).is_err());
+        // Outside the region, or running past its end.
+        assert!(udmabuf.create(&[iovec(0x20000, 0x1000)]).is_err());
+        assert!(udmabuf.create(&[iovec(0x13000, 0x2000)]).is_err());
+        // Holes.
+        assert!(udmabuf.create(&[iovec(0, 0x1000)]).is_err());
+    }
+}
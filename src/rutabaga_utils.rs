@@ -4,13 +4,25 @@
 
 //! rutabaga_utils: Utility enums, structs, and implementations needed by the rest of the crate.
 
+use std::collections::BTreeMap as Map;
 use std::fmt;
+use std::ops::Deref;
+use std::ops::Range;
 use std::os::raw::c_char;
 use std::os::raw::c_void;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::thread;
 
+use log::log;
+use log::warn;
+use log::Level;
 use mesa3d_util::MesaError;
+use mesa3d_util::MesaThreadScheduling;
+use mesa3d_util::MESA_HANDLE_TYPE_MEM_OPAQUE_FD;
+use mesa3d_util::MESA_HANDLE_TYPE_MEM_ZIRCON;
 use remain::sorted;
 use serde::Deserialize;
 use serde::Serialize;
@@ -34,8 +46,14 @@ use zerocopy::FromBytes;
 use zerocopy::Immutable;
 use zerocopy::IntoBytes;
 
+#[cfg(feature = "deterministic")]
+use crate::rutabaga_deterministic::RutabagaDeterministicExecutor;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use crate::rutabaga_sandbox::RutabagaSandbox;
+
 /// Represents a buffer.  `base` contains the address of a buffer, while `len` contains the length
-/// of the buffer.
+/// of the buffer.  A null `base` describes a hole: `len` bytes of a sparse blob that are not
+/// backed by guest memory.
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct RutabagaIovec {
@@ -43,12 +61,145 @@ pub struct RutabagaIovec {
     pub len: usize,
 }
 
+impl RutabagaIovec {
+    /// Returns a hole of `len` bytes.
+    pub fn hole(len: usize) -> RutabagaIovec {
+        RutabagaIovec {
+            base: std::ptr::null_mut(),
+            len,
+        }
+    }
+
+    /// Returns true if the iovec is an unbacked region of a sparse blob.
+    pub fn is_hole(&self) -> bool {
+        self.base.is_null()
+    }
+
+    /// Returns true if the iovec overlaps the host address range `range`.  Holes never do.
+    pub fn intersects(&self, range: &Range<u64>) -> bool {
+        if self.is_hole() {
+            return false;
+        }
+
+        let start = self.base as u64;
+        let end = start.saturating_add(self.len as u64);
+        start < range.end && range.start < end
+    }
+}
+
+/// Validates a list of iovecs, returning the total number of bytes it describes.  Holes must be
+/// non-empty, and are only accepted if `allow_holes` is set.
+pub fn validate_iovecs(iovecs: &[RutabagaIovec], allow_holes: bool) -> RutabagaResult<u64> {
+    let mut total: u64 = 0;
+    for iovec in iovecs {
+        if iovec.is_hole() && (!allow_holes || iovec.len == 0) {
+            return Err(RutabagaError::InvalidIovec);
+        }
+
+        total = total
+            .checked_add(iovec.len as u64)
+            .ok_or(RutabagaError::InvalidIovec)?;
+    }
+
+    Ok(total)
+}
+
 // SAFETY: trivially safe
 unsafe impl Send for RutabagaIovec {}
 
 // SAFETY: trivially safe
 unsafe impl Sync for RutabagaIovec {}
 
+#[derive(Default)]
+struct BackingPins {
+    count: usize,
+    detached: bool,
+}
+
+struct BackingState {
+    iovecs: Vec<RutabagaIovec>,
+    pins: Mutex<BackingPins>,
+    unpinned: Condvar,
+}
+
+/// The guest memory backing a resource.  Clones share the memory, so contexts can hold on to the
+/// backing of the resources attached to them.  The iovecs are only accessed through a
+/// `RutabagaBackingGuard`, and `detach()` waits for the outstanding guards before the guest may
+/// reuse the memory.
+#[derive(Clone)]
+pub(crate) struct RutabagaBacking {
+    state: Arc<BackingState>,
+}
+
+impl RutabagaBacking {
+    pub(crate) fn new(iovecs: Vec<RutabagaIovec>) -> RutabagaBacking {
+        RutabagaBacking {
+            state: Arc::new(BackingState {
+                iovecs,
+                pins: Default::default(),
+                unpinned: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Pins the iovecs until the returned guard is dropped.  Fails once the backing is detached.
+    pub(crate) fn pin(&self) -> RutabagaResult<RutabagaBackingGuard> {
+        let mut pins = self.state.pins.lock().unwrap();
+        if pins.detached {
+            return Err(RutabagaError::InvalidIovec);
+        }
+
+        pins.count += 1;
+        Ok(RutabagaBackingGuard {
+            state: self.state.clone(),
+        })
+    }
+
+    /// Detaches the backing from every clone, blocking until the iovecs are no longer pinned.
+    pub(crate) fn detach(&self) {
+        let mut pins = self.state.pins.lock().unwrap();
+        pins.detached = true;
+        let _pins = self
+            .state
+            .unpinned
+            .wait_while(pins, |pins| pins.count > 0)
+            .unwrap();
+    }
+
+    /// Returns true if the backing is still attached and overlaps the host address range `range`.
+    pub(crate) fn intersects(&self, range: &Range<u64>) -> bool {
+        !self.state.pins.lock().unwrap().detached
+            && self
+                .state
+                .iovecs
+                .iter()
+                .any(|iovec| iovec.intersects(range))
+    }
+}
+
+/// Keeps the iovecs of a `RutabagaBacking` valid while they are accessed.
+pub(crate) struct RutabagaBackingGuard {
+    state: Arc<BackingState>,
+}
+
+impl Deref for RutabagaBackingGuard {
+    type Target = [RutabagaIovec];
+
+    fn deref(&self) -> &[RutabagaIovec] {
+        &self.state.iovecs
+    }
+}
+
+impl Drop for RutabagaBackingGuard {
+    fn drop(&mut self) {
+        let mut pins = self.state.pins.lock().unwrap();
+        pins.count -= 1;
+        if pins.count == 0 {
+            self.state.unpinned.notify_all();
+        }
+    }
+}
+
 /// 3D resource creation parameters.  Also used to create 2D resource.  Constants based on Mesa's
 /// (internal) Gallium interface.  Not in the virtio-gpu spec, but should be since dumb resources
 /// can't work with gfxstream/virglrenderer without this.
@@ -77,6 +228,18 @@ pub const RUTABAGA_BLOB_MEM_HOST3D_GUEST: u32 = 0x0003;
 pub const RUTABAGA_BLOB_FLAG_USE_MAPPABLE: u32 = 0x0001;
 pub const RUTABAGA_BLOB_FLAG_USE_SHAREABLE: u32 = 0x0002;
 pub const RUTABAGA_BLOB_FLAG_USE_CROSS_DEVICE: u32 = 0x0004;
+/// The blob is allocated from protected memory, and can't be mapped.  This is a rutabaga extension
+/// rather than a virtio-gpu blob flag, so it lives in the top bit, away from the ones the spec
+/// assigns.
+pub const RUTABAGA_BLOB_FLAG_PROTECTED: u32 = 0x8000_0000;
+/// The VMM created a host handle to the guest memory of the blob, such as a udmabuf, and passes it
+/// to `Rutabaga::resource_create_blob`.  Exporting the blob returns that handle.  Matches the value
+/// crosvm uses.
+pub const RUTABAGA_BLOB_FLAG_CREATE_GUEST_HANDLE: u32 = 0x0008;
+
+/// The longest resource name accepted by `Rutabaga::set_resource_name()`, in bytes.
+pub const RUTABAGA_MAX_RESOURCE_NAME: usize = 64;
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
 pub struct ResourceCreateBlob {
@@ -145,10 +308,53 @@ pub struct VulkanInfo {
 /// Rutabaga context init capset id mask.
 pub const RUTABAGA_CONTEXT_INIT_CAPSET_ID_MASK: u32 = 0x00ff;
 
+/// Rutabaga context init priority mask and shift.  Contexts are created at the priority in these
+/// bits, which the component may schedule by.  None does yet: virglrenderer and gfxstream take no
+/// priority, and magma contexts don't create host contexts yet.
+pub const RUTABAGA_CONTEXT_INIT_PRIORITY_MASK: u32 = 0x0300;
+pub const RUTABAGA_CONTEXT_INIT_PRIORITY_SHIFT: u32 = 8;
+
+/// Rutabaga context init flag requesting a compute-only context.  Components that can skip
+/// graphics state, or run the context on a compute engine, may do so.  None does yet, so such
+/// contexts are full contexts.
+pub const RUTABAGA_CONTEXT_INIT_COMPUTE_ONLY: u32 = 1 << 10;
+
+/// Rutabaga context init device index mask and shift.  Selects the host GPU a magma context runs
+/// on, by its index in the magma capset.  If the capset lists no GPUs, index 0 selects the GPU
+/// magma opens by default.  Other components only accept index 0.
+pub const RUTABAGA_CONTEXT_INIT_DEVICE_MASK: u32 = 0xf000;
+pub const RUTABAGA_CONTEXT_INIT_DEVICE_SHIFT: u32 = 12;
+
+/// The scheduling priority of a context, relative to the other contexts of the guest.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum RutabagaContextPriority {
+    #[default]
+    Normal = 0,
+    Low = 1,
+    High = 2,
+}
+
+impl RutabagaContextPriority {
+    /// Returns the priority requested by `context_init`, or None for the reserved value.
+    pub fn from_context_init(context_init: u32) -> Option<RutabagaContextPriority> {
+        match (context_init & RUTABAGA_CONTEXT_INIT_PRIORITY_MASK)
+            >> RUTABAGA_CONTEXT_INIT_PRIORITY_SHIFT
+        {
+            0 => Some(RutabagaContextPriority::Normal),
+            1 => Some(RutabagaContextPriority::Low),
+            2 => Some(RutabagaContextPriority::High),
+            _ => None,
+        }
+    }
+}
+
 /// Rutabaga flags for creating fences.
 pub const RUTABAGA_FLAG_FENCE: u32 = 1 << 0;
 pub const RUTABAGA_FLAG_INFO_RING_IDX: u32 = 1 << 1;
 pub const RUTABAGA_FLAG_FENCE_HOST_SHAREABLE: u32 = 1 << 2;
+/// Set on fences that completed because their context was lost, rather than because its work
+/// finished.
+pub const RUTABAGA_FLAG_CONTEXT_LOST: u32 = 1 << 3;
 
 /// Convenience struct for Rutabaga fences
 #[repr(C)]
@@ -160,11 +366,32 @@ pub struct RutabagaFence {
     pub ring_idx: u8,
 }
 
+/// Identifies a fence among those pending.  Fence ids are only unique per context ring, or among
+/// global fences, whose context and ring don't matter.
+pub(crate) type RutabagaFenceKey = (u32, u8, u64);
+
+impl RutabagaFence {
+    pub(crate) fn key(&self) -> RutabagaFenceKey {
+        match self.flags & RUTABAGA_FLAG_INFO_RING_IDX {
+            0 => (0, 0, self.fence_id),
+            _ => (self.ctx_id, self.ring_idx, self.fence_id),
+        }
+    }
+}
+
 /// Rutabaga debug types
 pub const RUTABAGA_DEBUG_ERROR: u32 = 0x01;
 pub const RUTABAGA_DEBUG_WARNING: u32 = 0x02;
 pub const RUTABAGA_DEBUG_INFO: u32 = 0x03;
 
+/// Reported when the host GPU lost contexts, for example after a GPU reset.  Components report
+/// the loss of one of their contexts, or of all of them if `ctx_id` is None.  Rutabaga reports
+/// every lost context by id.
+#[derive(Copy, Clone, Debug)]
+pub struct RutabagaDeviceLost {
+    pub ctx_id: Option<u32>,
+}
+
 /// Convenience struct for debug data
 #[repr(C)]
 #[derive(Copy, Clone)]
@@ -198,6 +425,55 @@ unsafe impl Send for RutabagaDebug {}
 // by Rust code, a different struct should be used.
 unsafe impl Sync for RutabagaDebug {}
 
+/// A log message of a component, as passed to the `RutabagaLogSink`.
+#[derive(Clone)]
+pub struct RutabagaLogMessage {
+    pub component: RutabagaComponentType,
+    pub level: Level,
+    /// The context the message is about, if any.
+    pub ctx_id: Option<u32>,
+    pub message: String,
+}
+
+/// Forwards the log messages of a component to the `RutabagaLogSink`, or to the `log` crate if
+/// there is none.
+#[derive(Clone)]
+pub(crate) struct RutabagaLogger {
+    component: RutabagaComponentType,
+    ctx_id: Option<u32>,
+    sink: Option<RutabagaLogSink>,
+}
+
+impl RutabagaLogger {
+    pub fn new(component: RutabagaComponentType, sink: Option<RutabagaLogSink>) -> RutabagaLogger {
+        RutabagaLogger {
+            component,
+            ctx_id: None,
+            sink,
+        }
+    }
+
+    /// Returns a logger tagging its messages with `ctx_id`.
+    pub fn for_context(&self, ctx_id: u32) -> RutabagaLogger {
+        RutabagaLogger {
+            ctx_id: Some(ctx_id),
+            ..self.clone()
+        }
+    }
+
+    pub fn log(&self, level: Level, args: fmt::Arguments) {
+        match &self.sink {
+            Some(sink) => sink.call(RutabagaLogMessage {
+                component: self.component,
+                level,
+                ctx_id: self.ctx_id,
+                message: args.to_string(),
+            }),
+            None => log!(level, "{}", args),
+        }
+    }
+}
+
 /// Mapped memory caching flags (see virtio_gpu spec)
 pub const RUTABAGA_MAP_CACHE_MASK: u32 = 0x0f;
 pub const RUTABAGA_MAP_CACHE_CACHED: u32 = 0x01;
@@ -209,6 +485,22 @@ pub const RUTABAGA_MAP_ACCESS_READ: u32 = 0x10;
 pub const RUTABAGA_MAP_ACCESS_WRITE: u32 = 0x20;
 pub const RUTABAGA_MAP_ACCESS_RW: u32 = 0x30;
 
+/// Where a component prefers to place mappable blobs in host memory.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum RutabagaMemoryPlacement {
+    /// Whatever the component picks.
+    #[default]
+    Default,
+    /// Host visible device memory.
+    Vram,
+    /// System memory the GPU reaches through its aperture, write-combined for the CPU.
+    Gtt,
+    /// Cached system memory.
+    System,
+    /// VRAM while the host GPU has VRAM budget to spare, and system memory once it runs low.
+    Heuristic,
+}
+
 /// Rutabaga capsets.
 pub const RUTABAGA_CAPSET_VIRGL: u32 = 1;
 pub const RUTABAGA_CAPSET_VIRGL2: u32 = 2;
@@ -234,6 +526,12 @@ pub enum RutabagaError {
     /// is allowed.
     #[error("attempted to use a rutabaga asset already in use")]
     AlreadyInUse,
+    /// The blobs of every context would exceed the configured total size.
+    #[error("blob limit reached")]
+    BlobLimitExceeded,
+    /// A context's blobs would exceed the configured total size.
+    #[error("blob quota of context {0} exceeded")]
+    BlobQuotaExceeded(u32),
     /// Checked Arithmetic error
     #[error("arithmetic failed: {}({}) {op} {}({})", .field1.0, .field1.1, .field2.0, .field2.1)]
     CheckedArithmetic {
@@ -250,6 +548,18 @@ pub enum RutabagaError {
     /// An internal Rutabaga component error was returned.
     #[error("rutabaga component failed with error {0}")]
     ComponentError(i32),
+    /// Too many contexts of a capset are alive.
+    #[error("context limit of capset {0} reached")]
+    ContextLimitExceeded(u32),
+    /// The context was lost, for example to a GPU reset, and accepts no more work.
+    #[error("the context was lost")]
+    ContextLost,
+    /// Cross-domain items would hold more host descriptors than the configured limit.
+    #[error("descriptor limit of {0} reached")]
+    DescriptorLimitExceeded(usize),
+    /// The guest can import none of the handle types the host exports blobs as.
+    #[error("no common handle type: host exports {export:#x}, guest imports {import:#x}")]
+    HandleTypeMismatch { export: u32, import: u32 },
     /// Invalid 2D info
     #[error("invalid 2D info")]
     Invalid2DInfo,
@@ -271,6 +581,13 @@ pub enum RutabagaError {
     /// Invalid cross domain channel
     #[error("invalid cross domain channel")]
     InvalidCrossDomainChannel,
+    /// A cross-domain command was rejected in strict mode.
+    #[error("cross domain command {cmd} at offset {offset} rejected: {reason}")]
+    InvalidCrossDomainCommand {
+        cmd: u8,
+        offset: usize,
+        reason: &'static str,
+    },
     /// Invalid cross domain item ID
     #[error("invalid cross domain item id")]
     InvalidCrossDomainItemId,
@@ -292,6 +609,9 @@ pub enum RutabagaError {
     /// Invalid GPU type.
     #[error("invalid GPU type for gralloc")]
     InvalidGrallocGpuType,
+    /// None of the proposed DRM format modifiers are supported.
+    #[error("no supported DRM format modifier for gralloc")]
+    InvalidGrallocModifier,
     /// Invalid number of YUV planes.
     #[error("invalid number of YUV planes")]
     InvalidGrallocNumberOfPlanes,
@@ -313,6 +633,9 @@ pub enum RutabagaError {
     /// A Mesa Error
     #[error("An mesa error was returned {0}")]
     MesaError(MesaError),
+    /// Too many resources are alive.
+    #[error("resource limit reached")]
+    ResourceLimitExceeded,
     /// A snapshot JSON error was returned
     #[error("An serde json snapshot error was returned {0}")]
     SerdeJsonError(SerdeJsonError),
@@ -364,10 +687,118 @@ impl From<SerdeJsonError> for RutabagaError {
 /// The result of an operation in this crate.
 pub type RutabagaResult<T> = std::result::Result<T, RutabagaError>;
 
+/// Stable error codes for C callers, which see them negated.  The values are those of the Linux
+/// errno with the same meaning, on every platform, and won't change.
+#[repr(i32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RutabagaErrorCode {
+    /// EPERM: the caller isn't permitted to access the object.
+    PermissionDenied = 1,
+    /// ENOENT: an id doesn't name an existing context, resource or item.
+    NotFound = 2,
+    /// EIO: a renderer, the host OS or a snapshot failed.
+    Io = 5,
+    /// ENOMEM: the host ran out of memory.
+    OutOfMemory = 12,
+    /// EBUSY: the asset is already in use.
+    Busy = 16,
+    /// ENODEV: the context or its device was lost.
+    ContextLost = 19,
+    /// EINVAL: an argument or command is malformed.
+    InvalidArgument = 22,
+    /// ENOSPC: a configured limit or quota was reached.
+    LimitExceeded = 28,
+    /// EOPNOTSUPP: the operation isn't supported by this build or host.
+    Unsupported = 95,
+    /// ETIMEDOUT: the operation timed out.
+    TimedOut = 110,
+}
+
+impl RutabagaErrorCode {
+    fn from_io_error_kind(kind: std::io::ErrorKind) -> RutabagaErrorCode {
+        match kind {
+            std::io::ErrorKind::NotFound => RutabagaErrorCode::NotFound,
+            std::io::ErrorKind::OutOfMemory => RutabagaErrorCode::OutOfMemory,
+            std::io::ErrorKind::PermissionDenied => RutabagaErrorCode::PermissionDenied,
+            std::io::ErrorKind::TimedOut => RutabagaErrorCode::TimedOut,
+            std::io::ErrorKind::Unsupported => RutabagaErrorCode::Unsupported,
+            _ => RutabagaErrorCode::Io,
+        }
+    }
+}
+
+impl From<&MesaError> for RutabagaErrorCode {
+    fn from(e: &MesaError) -> RutabagaErrorCode {
+        match e {
+            MesaError::IoError(e) => RutabagaErrorCode::from_io_error_kind(e.kind()),
+            MesaError::RustixError(e) => RutabagaErrorCode::from_io_error_kind(
+                std::io::Error::from_raw_os_error(e.raw_os_error()).kind(),
+            ),
+            MesaError::Unsupported => RutabagaErrorCode::Unsupported,
+            MesaError::InvalidMesaHandle
+            | MesaError::NulError(_)
+            | MesaError::ParseIntError(_)
+            | MesaError::TryFromIntError(_)
+            | MesaError::Utf8Error(_)
+            | MesaError::WithContext(_) => RutabagaErrorCode::InvalidArgument,
+        }
+    }
+}
+
+impl From<&RutabagaError> for RutabagaErrorCode {
+    fn from(e: &RutabagaError) -> RutabagaErrorCode {
+        match e {
+            RutabagaError::AlreadyInUse => RutabagaErrorCode::Busy,
+            RutabagaError::BlobLimitExceeded
+            | RutabagaError::BlobQuotaExceeded(_)
+            | RutabagaError::ContextLimitExceeded(_)
+            | RutabagaError::DescriptorLimitExceeded(_)
+            | RutabagaError::ResourceLimitExceeded => RutabagaErrorCode::LimitExceeded,
+            RutabagaError::ComponentError(_)
+            | RutabagaError::MappingFailed(_)
+            | RutabagaError::SerdeJsonError(_)
+            | RutabagaError::SnapshotError => RutabagaErrorCode::Io,
+            RutabagaError::ContextLost => RutabagaErrorCode::ContextLost,
+            RutabagaError::HandleTypeMismatch { .. } => RutabagaErrorCode::Unsupported,
+            RutabagaError::InvalidContextId
+            | RutabagaError::InvalidCrossDomainChannel
+            | RutabagaError::InvalidCrossDomainItemId
+            | RutabagaError::InvalidResourceId => RutabagaErrorCode::NotFound,
+            RutabagaError::CheckedArithmetic { .. }
+            | RutabagaError::CheckedRange { .. }
+            | RutabagaError::Invalid2DInfo
+            | RutabagaError::InvalidCapset
+            | RutabagaError::InvalidCommandBuffer
+            | RutabagaError::InvalidCommandSize(_)
+            | RutabagaError::InvalidComponent
+            | RutabagaError::InvalidCrossDomainCommand { .. }
+            | RutabagaError::InvalidCrossDomainItemType
+            | RutabagaError::InvalidCrossDomainState
+            | RutabagaError::InvalidGrallocBackend
+            | RutabagaError::InvalidGrallocDimensions
+            | RutabagaError::InvalidGrallocDrmFormat
+            | RutabagaError::InvalidGrallocGpuType
+            | RutabagaError::InvalidGrallocModifier
+            | RutabagaError::InvalidGrallocNumberOfPlanes
+            | RutabagaError::InvalidIovec
+            | RutabagaError::InvalidRutabagaBuild
+            | RutabagaError::InvalidVulkanInfo => RutabagaErrorCode::InvalidArgument,
+            RutabagaError::MesaError(e) => e.into(),
+            #[cfg(feature = "vulkano")]
+            RutabagaError::VkDeviceCreationError(_)
+            | RutabagaError::VkDeviceMemoryError(_)
+            | RutabagaError::VkError(_)
+            | RutabagaError::VkImageCreationError(_)
+            | RutabagaError::VkInstanceCreationError(_)
+            | RutabagaError::VkLoadingError(_)
+            | RutabagaError::VkMemoryMapError(_) => RutabagaErrorCode::Io,
+        }
+    }
+}
+
 /// Flags for virglrenderer.  Copied from virglrenderer bindings.
 const VIRGLRENDERER_USE_EGL: u32 = 1 << 0;
 const VIRGLRENDERER_THREAD_SYNC: u32 = 1 << 1;
-#[allow(dead_code)]
 const VIRGLRENDERER_USE_GLX: u32 = 1 << 2;
 const VIRGLRENDERER_USE_SURFACELESS: u32 = 1 << 3;
 const VIRGLRENDERER_USE_GLES: u32 = 1 << 4;
@@ -440,6 +871,11 @@ impl VirglRendererFlags {
         self.set_flag(VIRGLRENDERER_USE_EGL, v)
     }
 
+    /// Use GLX for context creation.
+    pub fn use_glx(self, v: bool) -> VirglRendererFlags {
+        self.set_flag(VIRGLRENDERER_USE_GLX, v)
+    }
+
     /// Use a dedicated thread for fence synchronization.
     pub fn use_thread_sync(self, v: bool) -> VirglRendererFlags {
         self.set_flag(VIRGLRENDERER_THREAD_SYNC, v)
@@ -468,6 +904,44 @@ impl VirglRendererFlags {
     pub fn use_render_server(self, v: bool) -> VirglRendererFlags {
         self.set_flag(VIRGLRENDERER_RENDER_SERVER, v)
     }
+
+    fn has_flag(&self, bitmask: u32) -> bool {
+        self.0 & bitmask != 0
+    }
+
+    /// Returns an error naming the conflict if virglrenderer can't initialize with these flags.
+    pub fn validate(&self) -> RutabagaResult<()> {
+        let conflict = if self.has_flag(VIRGLRENDERER_USE_EGL)
+            && self.has_flag(VIRGLRENDERER_USE_GLX)
+        {
+            Some("EGL and GLX are exclusive")
+        } else if self.has_flag(VIRGLRENDERER_USE_GLES) && self.has_flag(VIRGLRENDERER_USE_GLX) {
+            Some("GLX doesn't support GLES")
+        } else if self.has_flag(VIRGLRENDERER_USE_SURFACELESS)
+            && !self.has_flag(VIRGLRENDERER_USE_EGL)
+        {
+            Some("surfaceless requires EGL")
+        } else if self.has_flag(VIRGLRENDERER_USE_ASYNC_FENCE_CB)
+            && !self.has_flag(VIRGLRENDERER_THREAD_SYNC)
+        {
+            Some("async fence callbacks require thread sync")
+        } else if self.has_flag(VIRGLRENDERER_NO_VIRGL)
+            && !self.has_flag(VIRGLRENDERER_VENUS | VIRGLRENDERER_DRM)
+        {
+            Some("virgl, venus and drm contexts are all disabled")
+        } else if self.has_flag(VIRGLRENDERER_RENDER_SERVER)
+            && !self.has_flag(VIRGLRENDERER_VENUS | VIRGLRENDERER_DRM)
+        {
+            Some("the render server only runs venus and drm contexts")
+        } else {
+            None
+        };
+
+        match conflict {
+            Some(conflict) => Err(MesaError::WithContext(conflict).into()),
+            None => Ok(()),
+        }
+    }
 }
 
 /// Flags for the gfxstream renderer.
@@ -605,9 +1079,55 @@ impl Transfer3D {
     }
 }
 
+/// A rectangle of a resource that changed since the display last consumed it.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RutabagaRect {
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+impl RutabagaRect {
+    /// Returns the smallest rectangle containing both `self` and `other`.
+    pub fn union(&self, other: &RutabagaRect) -> RutabagaRect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = self
+            .x
+            .saturating_add(self.w)
+            .max(other.x.saturating_add(other.w));
+        let bottom = self
+            .y
+            .saturating_add(self.h)
+            .max(other.y.saturating_add(other.h));
+        RutabagaRect {
+            x,
+            y,
+            w: right - x,
+            h: bottom - y,
+        }
+    }
+}
+
+impl From<&Transfer3D> for RutabagaRect {
+    fn from(transfer: &Transfer3D) -> RutabagaRect {
+        RutabagaRect {
+            x: transfer.x,
+            y: transfer.y,
+            w: transfer.w,
+            h: transfer.h,
+        }
+    }
+}
+
 /// Rutabaga path types
 pub const RUTABAGA_PATH_TYPE_WAYLAND: u32 = 0x0001;
 pub const RUTABAGA_PATH_TYPE_GPU: u32 = 0x0002;
+pub const RUTABAGA_PATH_TYPE_X11: u32 = 0x0003;
+pub const RUTABAGA_PATH_TYPE_PULSE: u32 = 0x0004;
+pub const RUTABAGA_PATH_TYPE_CAMERA: u32 = 0x0005;
+pub const RUTABAGA_PATH_TYPE_VULKAN_ICD: u32 = 0x0006;
 
 pub type RutabagaPaths = Vec<RutabagaPath>;
 
@@ -644,11 +1164,157 @@ impl RutabagaComponentType {
     }
 }
 
+/// Internal rutabaga threads whose scheduling may be configured.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub enum RutabagaThreadType {
+    /// Per-context worker proxying a cross-domain channel, such as the host Wayland socket.
+    CrossDomainWorker,
+    /// Worker running transfers when async transfers are enabled.
+    TransferWorker,
+    /// Watches the render server spawned for virglrenderer, and reports it lost if it exits.
+    RenderServerMonitor,
+}
+
+impl RutabagaThreadType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RutabagaThreadType::CrossDomainWorker => "cross_domain_worker",
+            RutabagaThreadType::TransferWorker => "transfer_worker",
+            RutabagaThreadType::RenderServerMonitor => "render_server_monitor",
+        }
+    }
+
+    fn thread_name(&self) -> &'static str {
+        match self {
+            RutabagaThreadType::CrossDomainWorker => "cross domain",
+            RutabagaThreadType::TransferWorker => "rutabaga transfer",
+            RutabagaThreadType::RenderServerMonitor => "render_server_monitor",
+        }
+    }
+}
+
+/// Scheduling of internal threads, by thread type.  Threads without an entry inherit the
+/// scheduling of the thread that spawned them.
+#[derive(Clone, Default)]
+pub(crate) struct RutabagaThreadConfig {
+    scheduling: Map<RutabagaThreadType, MesaThreadScheduling>,
+    name_prefix: String,
+    #[cfg(feature = "deterministic")]
+    executor: Option<RutabagaDeterministicExecutor>,
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    sandbox: Option<RutabagaSandbox>,
+}
+
+impl RutabagaThreadConfig {
+    /// Runs internal workers on `executor` instead of on dedicated threads.
+    #[cfg(feature = "deterministic")]
+    pub(crate) fn set_executor(&mut self, executor: RutabagaDeterministicExecutor) {
+        self.executor = Some(executor);
+    }
+
+    #[cfg(feature = "deterministic")]
+    pub(crate) fn executor(&self) -> Option<&RutabagaDeterministicExecutor> {
+        self.executor.as_ref()
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub(crate) fn set_sandbox(&mut self, sandbox: RutabagaSandbox) {
+        self.sandbox = Some(sandbox);
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub(crate) fn sandbox(&self) -> Option<&RutabagaSandbox> {
+        self.sandbox.as_ref()
+    }
+
+    pub(crate) fn set_name_prefix(&mut self, prefix: String) {
+        self.name_prefix = prefix;
+    }
+
+    /// Returns a builder for a thread of type `thread_type`, named after its type.
+    pub(crate) fn builder(&self, thread_type: RutabagaThreadType) -> thread::Builder {
+        thread::Builder::new().name(format!("{}{}", self.name_prefix, thread_type.thread_name()))
+    }
+
+    pub(crate) fn set(
+        &mut self,
+        thread_type: RutabagaThreadType,
+        scheduling: MesaThreadScheduling,
+    ) {
+        self.scheduling.insert(thread_type, scheduling);
+    }
+
+    /// Applies the configured scheduling to the calling thread.  Missing permissions aren't
+    /// fatal: the thread keeps running with its inherited scheduling.
+    pub(crate) fn apply(&self, thread_type: RutabagaThreadType) {
+        let Some(scheduling) = self.scheduling.get(&thread_type) else {
+            return;
+        };
+
+        if let Err(e) = scheduling.apply() {
+            warn!(
+                "failed to set {} thread scheduling {:?}: {}",
+                thread_type.as_str(),
+                scheduling,
+                e
+            );
+        }
+    }
+
+    /// Confines the calling thread with the configured sandbox, if any.  Unlike scheduling,
+    /// failing to confine the thread is fatal.
+    pub(crate) fn confine(&self) -> RutabagaResult<()> {
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        if let Some(sandbox) = &self.sandbox {
+            sandbox.confine_thread()?;
+        }
+
+        Ok(())
+    }
+}
+
 // Handle types to support special-case consumers.
 pub const RUTABAGA_HANDLE_TYPE_PLATFORM_SCREEN_BUFFER_QNX: u32 = 0x01000000;
 pub const RUTABAGA_HANDLE_TYPE_PLATFORM_EGL_NATIVE_PIXMAP: u32 = 0x02000000;
 pub const RUTABAGA_HANDLE_TYPE_PLATFORM_AHB: u32 = 0x03000000;
 
+/// Returns the mask of `handle_types`, with bit `1 << handle_type` set for each memory handle
+/// type (MESA_HANDLE_TYPE_MEM_*).  Other handle types have no bit and are left out.
+pub fn handle_type_mask(handle_types: &[u32]) -> u32 {
+    handle_types
+        .iter()
+        .filter(|handle_type| {
+            (MESA_HANDLE_TYPE_MEM_OPAQUE_FD..=MESA_HANDLE_TYPE_MEM_ZIRCON).contains(handle_type)
+        })
+        .fold(0, |mask, handle_type| mask | (1 << handle_type))
+}
+
+/// The memory handle types a component exports blobs as, and imports blobs of other components
+/// from.  Both are masks built by `handle_type_mask()`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RutabagaHandleTypes {
+    pub export: u32,
+    pub import: u32,
+}
+
+impl RutabagaHandleTypes {
+    /// Returns the exported handle types a guest importing `guest_import` can use, or an error if
+    /// there are none.  A zero `guest_import` is from a guest that doesn't negotiate, and accepts
+    /// every handle type.
+    pub fn negotiate(&self, guest_import: u32) -> RutabagaResult<u32> {
+        if guest_import == 0 {
+            return Ok(self.export);
+        }
+
+        match self.export & guest_import {
+            0 => Err(RutabagaError::HandleTypeMismatch {
+                export: self.export,
+                import: guest_import,
+            }),
+            common => Ok(common),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct RutabagaHandler<S> {
@@ -678,3 +1344,65 @@ impl<S> fmt::Debug for RutabagaHandler<S> {
 
 pub type RutabagaFenceHandler = RutabagaHandler<RutabagaFence>;
 pub type RutabagaDebugHandler = RutabagaHandler<RutabagaDebug>;
+pub type RutabagaDeviceLostHandler = RutabagaHandler<RutabagaDeviceLost>;
+/// Receives the log messages of components, instead of the `log` crate.  VMMs can use it to tag
+/// messages with the context or guest they came from.
+pub type RutabagaLogSink = RutabagaHandler<RutabagaLogMessage>;
+/// Called with the new number of capsets when components are added or removed at runtime.
+pub type RutabagaCapsetHandler = RutabagaHandler<u32>;
+
+#[cfg(test)]
+mod tests {
+    use mesa3d_util::MesaSchedulingPolicy;
+
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn thread_scheduling() {
+        let mut thread_config = RutabagaThreadConfig::default();
+        thread_config.set_name_prefix("vm0 ".to_string());
+        thread_config.set(
+            RutabagaThreadType::CrossDomainWorker,
+            MesaThreadScheduling {
+                policy: MesaSchedulingPolicy::Batch,
+                priority: 3,
+                affinity: Vec::new(),
+            },
+        );
+        // CPUs that don't exist fail to apply, which isn't fatal.
+        thread_config.set(
+            RutabagaThreadType::TransferWorker,
+            MesaThreadScheduling {
+                affinity: vec![usize::MAX],
+                ..Default::default()
+            },
+        );
+
+        let run = |thread_type: RutabagaThreadType| {
+            let thread_config = thread_config.clone();
+            thread_config
+                .builder(thread_type)
+                .spawn(move || {
+                    thread_config.apply(thread_type);
+                    // SAFETY:
+                    // Trivially safe, no memory is passed to the kernel.
+                    let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+                    (thread::current().name().unwrap().to_string(), nice)
+                })
+                .unwrap()
+                .join()
+                .unwrap()
+        };
+
+        // SAFETY:
+        // Trivially safe, no memory is passed to the kernel.
+        let inherited = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+        assert_eq!(
+            run(RutabagaThreadType::CrossDomainWorker),
+            ("vm0 cross domain".to_string(), 3)
+        );
+        assert_eq!(run(RutabagaThreadType::TransferWorker).1, inherited);
+        assert_eq!(run(RutabagaThreadType::RenderServerMonitor).1, inherited);
+    }
+}
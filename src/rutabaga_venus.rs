@@ -0,0 +1,98 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! rutabaga_venus: hides Vulkan extensions and renderer features from guests, by masking them out
+//! of the Venus capset virglrenderer reports.
+
+use std::collections::BTreeSet as Set;
+use std::mem::size_of;
+
+/// Lets the guest driver wait on Vulkan sync objects in the renderer.
+pub const RUTABAGA_VENUS_FEATURE_WAIT_SYNCS: u32 = 1 << 0;
+/// Lets the guest driver give each VkQueue its own fence timeline.
+pub const RUTABAGA_VENUS_FEATURE_MULTIPLE_TIMELINES: u32 = 1 << 1;
+
+// Offsets into struct virgl_renderer_capset_venus.  Fields are only ever appended to it, so older
+// virglrenderers report a prefix of it.
+const VENUS_EXTENSION_MASK_OFFSET: usize = 20;
+const VENUS_EXTENSION_MASK_WORDS: usize = 32;
+const VENUS_ALLOW_WAIT_SYNCS_OFFSET: usize = 148;
+const VENUS_MULTIPLE_TIMELINES_OFFSET: usize = 152;
+
+const VENUS_FEATURE_OFFSETS: [(u32, usize); 2] = [
+    (
+        RUTABAGA_VENUS_FEATURE_WAIT_SYNCS,
+        VENUS_ALLOW_WAIT_SYNCS_OFFSET,
+    ),
+    (
+        RUTABAGA_VENUS_FEATURE_MULTIPLE_TIMELINES,
+        VENUS_MULTIPLE_TIMELINES_OFFSET,
+    ),
+];
+
+/// Extensions are identified by their extension number in the Vulkan registry, such as 348 for
+/// VK_KHR_ray_tracing_pipeline.
+#[derive(Clone, Default)]
+pub(crate) struct RutabagaVenusFilter {
+    pub(crate) allowed_extensions: Option<Set<u32>>,
+    pub(crate) denied_extensions: Set<u32>,
+    pub(crate) denied_features: u32,
+}
+
+fn read_word(capset: &[u8], offset: usize) -> Option<u32> {
+    let bytes = capset.get(offset..offset + size_of::<u32>())?;
+    Some(u32::from_ne_bytes(bytes.try_into().ok()?))
+}
+
+fn write_word(capset: &mut [u8], offset: usize, value: u32) {
+    if let Some(bytes) = capset.get_mut(offset..offset + size_of::<u32>()) {
+        bytes.copy_from_slice(&value.to_ne_bytes());
+    }
+}
+
+impl RutabagaVenusFilter {
+    fn filters_extensions(&self) -> bool {
+        self.allowed_extensions.is_some() || !self.denied_extensions.is_empty()
+    }
+
+    fn allows_extension(&self, extension: u32) -> bool {
+        let allowed = match self.allowed_extensions {
+            Some(ref allowed) => allowed.contains(&extension),
+            None => true,
+        };
+
+        allowed && !self.denied_extensions.contains(&extension)
+    }
+
+    /// Masks the filtered extensions and features out of `capset`, which holds a Venus capset.
+    pub(crate) fn apply(&self, capset: &mut [u8]) {
+        let mask_end = VENUS_EXTENSION_MASK_OFFSET + VENUS_EXTENSION_MASK_WORDS * size_of::<u32>();
+        if self.filters_extensions() && capset.len() >= mask_end {
+            // Bit 0 marks the mask as valid.  Without it, every extension is assumed supported.
+            let valid = read_word(capset, VENUS_EXTENSION_MASK_OFFSET).unwrap_or(0) & 1 != 0;
+            for word in 0..VENUS_EXTENSION_MASK_WORDS {
+                let offset = VENUS_EXTENSION_MASK_OFFSET + word * size_of::<u32>();
+                let mut mask = if valid {
+                    read_word(capset, offset).unwrap_or(0)
+                } else {
+                    u32::MAX
+                };
+
+                for bit in 0..u32::BITS {
+                    let extension = word as u32 * u32::BITS + bit;
+                    if extension != 0 && !self.allows_extension(extension) {
+                        mask &= !(1 << bit);
+                    }
+                }
+                write_word(capset, offset, mask);
+            }
+        }
+
+        for (feature, offset) in VENUS_FEATURE_OFFSETS {
+            if self.denied_features & feature != 0 {
+                write_word(capset, offset, 0);
+            }
+        }
+    }
+}
@@ -0,0 +1,1080 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! rutabaga_vhost_user: glue for running rutabaga behind a vhost-user-gpu backend daemon, such as
+//! the one QEMU's vhost-user-gpu device connects to.
+//!
+//! The daemon owns the vhost-user socket and the virtqueues.  It maps the guest memory regions it
+//! is sent into a `RutabagaVhostUserMemory`, passes control queue requests to
+//! `RutabagaVhostUserGpu::process_command()`, and holds the fenced ones with
+//! `RutabagaVhostUserFences` until rutabaga completes them.  Displays, the cursor queue and blob
+//! mappings are left to the daemon, since they go through QEMU's vhost-user-gpu socket and the
+//! vhost-user shared memory regions.
+
+use std::collections::BTreeMap as Map;
+use std::mem::size_of;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use log::error;
+use mesa3d_util::Event;
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+
+use crate::rutabaga_core::Rutabaga;
+use crate::rutabaga_utils::Resource3DInfo;
+use crate::rutabaga_utils::ResourceCreate3D;
+use crate::rutabaga_utils::ResourceCreateBlob;
+use crate::rutabaga_utils::RutabagaError;
+use crate::rutabaga_utils::RutabagaFence;
+use crate::rutabaga_utils::RutabagaFenceHandler;
+use crate::rutabaga_utils::RutabagaHandler;
+use crate::rutabaga_utils::RutabagaIovec;
+use crate::rutabaga_utils::RutabagaRect;
+use crate::rutabaga_utils::RutabagaResult;
+use crate::rutabaga_utils::Transfer3D;
+use crate::rutabaga_utils::RUTABAGA_FLAG_FENCE;
+use crate::rutabaga_utils::RUTABAGA_FLAG_INFO_RING_IDX;
+use crate::rutabaga_utils::RUTABAGA_PIPE_BIND_RENDER_TARGET;
+use crate::rutabaga_utils::RUTABAGA_PIPE_TEXTURE_2D;
+
+// Control queue commands, from the virtio specification.
+const VIRTIO_GPU_CMD_GET_DISPLAY_INFO: u32 = 0x100;
+const VIRTIO_GPU_CMD_RESOURCE_CREATE_2D: u32 = 0x101;
+const VIRTIO_GPU_CMD_RESOURCE_UNREF: u32 = 0x102;
+const VIRTIO_GPU_CMD_SET_SCANOUT: u32 = 0x103;
+const VIRTIO_GPU_CMD_RESOURCE_FLUSH: u32 = 0x104;
+const VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D: u32 = 0x105;
+const VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING: u32 = 0x106;
+const VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING: u32 = 0x107;
+const VIRTIO_GPU_CMD_GET_CAPSET_INFO: u32 = 0x108;
+const VIRTIO_GPU_CMD_GET_CAPSET: u32 = 0x109;
+const VIRTIO_GPU_CMD_RESOURCE_ASSIGN_UUID: u32 = 0x10b;
+const VIRTIO_GPU_CMD_RESOURCE_CREATE_BLOB: u32 = 0x10c;
+const VIRTIO_GPU_CMD_SET_SCANOUT_BLOB: u32 = 0x10d;
+const VIRTIO_GPU_CMD_CTX_CREATE: u32 = 0x200;
+const VIRTIO_GPU_CMD_CTX_DESTROY: u32 = 0x201;
+const VIRTIO_GPU_CMD_CTX_ATTACH_RESOURCE: u32 = 0x202;
+const VIRTIO_GPU_CMD_CTX_DETACH_RESOURCE: u32 = 0x203;
+const VIRTIO_GPU_CMD_RESOURCE_CREATE_3D: u32 = 0x204;
+const VIRTIO_GPU_CMD_TRANSFER_TO_HOST_3D: u32 = 0x205;
+const VIRTIO_GPU_CMD_TRANSFER_FROM_HOST_3D: u32 = 0x206;
+const VIRTIO_GPU_CMD_SUBMIT_3D: u32 = 0x207;
+
+const VIRTIO_GPU_RESP_OK_NODATA: u32 = 0x1100;
+const VIRTIO_GPU_RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+const VIRTIO_GPU_RESP_OK_CAPSET_INFO: u32 = 0x1102;
+const VIRTIO_GPU_RESP_OK_CAPSET: u32 = 0x1103;
+const VIRTIO_GPU_RESP_OK_RESOURCE_UUID: u32 = 0x1105;
+
+const VIRTIO_GPU_RESP_ERR_UNSPEC: u32 = 0x1200;
+const VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY: u32 = 0x1201;
+const VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID: u32 = 0x1202;
+const VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID: u32 = 0x1203;
+const VIRTIO_GPU_RESP_ERR_INVALID_CONTEXT_ID: u32 = 0x1204;
+const VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER: u32 = 0x1205;
+
+const VIRTIO_GPU_MAX_SCANOUTS: usize = 16;
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+struct virtio_gpu_ctrl_hdr {
+    type_: u32,
+    flags: u32,
+    fence_id: u64,
+    ctx_id: u32,
+    ring_idx: u8,
+    padding: [u8; 3],
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+struct virtio_gpu_rect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+struct virtio_gpu_display_one {
+    r: virtio_gpu_rect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+struct virtio_gpu_resource_create_2d {
+    hdr: virtio_gpu_ctrl_hdr,
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+// Shared by the commands that only name a resource.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+struct virtio_gpu_resource {
+    hdr: virtio_gpu_ctrl_hdr,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+struct virtio_gpu_set_scanout {
+    hdr: virtio_gpu_ctrl_hdr,
+    r: virtio_gpu_rect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+struct virtio_gpu_set_scanout_blob {
+    hdr: virtio_gpu_ctrl_hdr,
+    r: virtio_gpu_rect,
+    scanout_id: u32,
+    resource_id: u32,
+    width: u32,
+    height: u32,
+    format: u32,
+    padding: u32,
+    strides: [u32; 4],
+    offsets: [u32; 4],
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+struct virtio_gpu_resource_flush {
+    hdr: virtio_gpu_ctrl_hdr,
+    r: virtio_gpu_rect,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+struct virtio_gpu_transfer_to_host_2d {
+    hdr: virtio_gpu_ctrl_hdr,
+    r: virtio_gpu_rect,
+    offset: u64,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+struct virtio_gpu_mem_entry {
+    addr: u64,
+    length: u32,
+    padding: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+struct virtio_gpu_resource_attach_backing {
+    hdr: virtio_gpu_ctrl_hdr,
+    resource_id: u32,
+    nr_entries: u32,
+    // Followed by `nr_entries` virtio_gpu_mem_entry.
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+struct virtio_gpu_get_capset_info {
+    hdr: virtio_gpu_ctrl_hdr,
+    capset_index: u32,
+    padding: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+struct virtio_gpu_resp_capset_info {
+    capset_id: u32,
+    capset_max_version: u32,
+    capset_max_size: u32,
+    padding: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+struct virtio_gpu_get_capset {
+    hdr: virtio_gpu_ctrl_hdr,
+    capset_id: u32,
+    capset_version: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+struct virtio_gpu_resource_create_blob {
+    hdr: virtio_gpu_ctrl_hdr,
+    resource_id: u32,
+    blob_mem: u32,
+    blob_flags: u32,
+    nr_entries: u32,
+    blob_id: u64,
+    size: u64,
+    // Followed by `nr_entries` virtio_gpu_mem_entry.
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, FromBytes, IntoBytes, Immutable)]
+struct virtio_gpu_ctx_create {
+    hdr: virtio_gpu_ctrl_hdr,
+    nlen: u32,
+    context_init: u32,
+    debug_name: [u8; 64],
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+struct virtio_gpu_resource_create_3d {
+    hdr: virtio_gpu_ctrl_hdr,
+    resource_id: u32,
+    target: u32,
+    format: u32,
+    bind: u32,
+    width: u32,
+    height: u32,
+    depth: u32,
+    array_size: u32,
+    last_level: u32,
+    nr_samples: u32,
+    flags: u32,
+    padding: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+struct virtio_gpu_box {
+    x: u32,
+    y: u32,
+    z: u32,
+    w: u32,
+    h: u32,
+    d: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+struct virtio_gpu_transfer_host_3d {
+    hdr: virtio_gpu_ctrl_hdr,
+    box_: virtio_gpu_box,
+    offset: u64,
+    resource_id: u32,
+    level: u32,
+    stride: u32,
+    layer_stride: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+struct virtio_gpu_cmd_submit {
+    hdr: virtio_gpu_ctrl_hdr,
+    size: u32,
+    padding: u32,
+    // Followed by `size` bytes of commands.
+}
+
+impl From<virtio_gpu_rect> for RutabagaRect {
+    fn from(r: virtio_gpu_rect) -> RutabagaRect {
+        RutabagaRect {
+            x: r.x,
+            y: r.y,
+            w: r.width,
+            h: r.height,
+        }
+    }
+}
+
+impl From<&virtio_gpu_transfer_host_3d> for Transfer3D {
+    fn from(cmd: &virtio_gpu_transfer_host_3d) -> Transfer3D {
+        Transfer3D {
+            x: cmd.box_.x,
+            y: cmd.box_.y,
+            z: cmd.box_.z,
+            w: cmd.box_.w,
+            h: cmd.box_.h,
+            d: cmd.box_.d,
+            level: cmd.level,
+            stride: cmd.stride,
+            layer_stride: cmd.layer_stride,
+            offset: cmd.offset,
+        }
+    }
+}
+
+fn read_command<T: FromBytes>(request: &[u8]) -> RutabagaResult<(T, &[u8])> {
+    T::read_from_prefix(request).map_err(|_| RutabagaError::InvalidCommandSize(request.len()))
+}
+
+// Returns the virtio-gpu error response for a command failing with `e`.
+fn error_response(e: &RutabagaError) -> u32 {
+    match e {
+        RutabagaError::InvalidResourceId => VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID,
+        RutabagaError::InvalidContextId => VIRTIO_GPU_RESP_ERR_INVALID_CONTEXT_ID,
+        RutabagaError::BlobLimitExceeded
+        | RutabagaError::BlobQuotaExceeded(_)
+        | RutabagaError::ContextLimitExceeded(_)
+        | RutabagaError::ResourceLimitExceeded => VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY,
+        RutabagaError::InvalidCommandBuffer
+        | RutabagaError::InvalidCommandSize(_)
+        | RutabagaError::InvalidIovec => VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER,
+        _ => VIRTIO_GPU_RESP_ERR_UNSPEC,
+    }
+}
+
+/// A region of guest memory, as described by a vhost-user memory table entry.
+#[derive(Copy, Clone, Debug)]
+pub struct RutabagaVhostUserMemoryRegion {
+    pub guest_phys_addr: u64,
+    pub memory_size: u64,
+    /// Where the daemon mapped the region, with the entry's mmap offset already applied.
+    pub host_addr: u64,
+}
+
+/// The guest memory shared with the daemon, used to turn guest physical addresses into iovecs.
+#[derive(Default)]
+pub struct RutabagaVhostUserMemory {
+    regions: Vec<RutabagaVhostUserMemoryRegion>,
+}
+
+impl RutabagaVhostUserMemory {
+    pub fn new(regions: Vec<RutabagaVhostUserMemoryRegion>) -> RutabagaVhostUserMemory {
+        RutabagaVhostUserMemory { regions }
+    }
+
+    /// Adds a region, for VHOST_USER_ADD_MEM_REG.
+    pub fn add_region(&mut self, region: RutabagaVhostUserMemoryRegion) {
+        self.regions.push(region);
+    }
+
+    /// Removes the region at `guest_phys_addr`, for VHOST_USER_REM_MEM_REG.  Its backing is
+    /// detached from every resource using it, and the ids of those resources are returned.  The
+    /// daemon may only unmap the region afterwards.
+    pub fn remove_region(
+        &mut self,
+        rutabaga: &mut Rutabaga,
+        guest_phys_addr: u64,
+    ) -> RutabagaResult<Vec<u32>> {
+        let index = self
+            .regions
+            .iter()
+            .position(|region| region.guest_phys_addr == guest_phys_addr)
+            .ok_or(RutabagaError::InvalidIovec)?;
+
+        let region = self.regions.remove(index);
+        rutabaga.invalidate_guest_memory(region.host_addr..region.host_addr + region.memory_size)
+    }
+
+    /// Returns the host iovecs backing `len` bytes of guest memory at `addr`, which may span
+    /// adjacent regions.
+    pub fn translate(&self, addr: u64, len: u64) -> RutabagaResult<Vec<RutabagaIovec>> {
+        let mut iovecs = Vec::new();
+        let end = addr.checked_add(len).ok_or(RutabagaError::InvalidIovec)?;
+        let mut addr = addr;
+        while addr < end {
+            let region = self
+                .regions
+                .iter()
+                .find(|region| {
+                    addr >= region.guest_phys_addr
+                        && addr - region.guest_phys_addr < region.memory_size
+                })
+                .ok_or(RutabagaError::InvalidIovec)?;
+
+            let offset = addr - region.guest_phys_addr;
+            let chunk = (end - addr).min(region.memory_size - offset);
+            iovecs.push(RutabagaIovec {
+                base: (region.host_addr + offset) as *mut _,
+                len: chunk as usize,
+            });
+            addr += chunk;
+        }
+
+        Ok(iovecs)
+    }
+
+    // Translates the `nr_entries` virtio_gpu_mem_entry at the start of `entries`.
+    fn translate_entries(
+        &self,
+        entries: &[u8],
+        nr_entries: u32,
+    ) -> RutabagaResult<Vec<RutabagaIovec>> {
+        let (entries, _) =
+            <[virtio_gpu_mem_entry]>::ref_from_prefix_with_elems(entries, nr_entries as usize)
+                .map_err(|_| RutabagaError::InvalidCommandSize(entries.len()))?;
+
+        let mut iovecs = Vec::new();
+        for entry in entries {
+            iovecs.extend(self.translate(entry.addr, entry.length.into())?);
+        }
+
+        Ok(iovecs)
+    }
+}
+
+/// Display work that the daemon forwards over QEMU's vhost-user-gpu socket.
+#[derive(Copy, Clone, Debug)]
+pub enum RutabagaVhostUserDisplay {
+    /// The resource given by `resource_id` is now shown on `scanout_id`, or nothing is if it's
+    /// zero.
+    SetScanout {
+        scanout_id: u32,
+        resource_id: u32,
+        rect: RutabagaRect,
+    },
+    /// The resource given by `resource_id` changed within `rect`.
+    Flush {
+        resource_id: u32,
+        rect: RutabagaRect,
+    },
+}
+
+/// The outcome of a control queue request.
+#[derive(Copy, Clone)]
+pub struct RutabagaVhostUserCommand {
+    /// The number of bytes of response written.
+    pub response_len: usize,
+    /// If set, the request is returned to the guest once the fence completes.  See
+    /// `RutabagaVhostUserFences::hold()`.
+    pub fence: Option<RutabagaFence>,
+    pub display: Option<RutabagaVhostUserDisplay>,
+}
+
+/// Translates virtio-gpu control queue requests into calls to rutabaga.
+pub struct RutabagaVhostUserGpu {
+    memory: RutabagaVhostUserMemory,
+    scanouts: Vec<RutabagaRect>,
+}
+
+impl RutabagaVhostUserGpu {
+    /// Creates the translator, with the guest memory shared with the daemon and the sizes of the
+    /// enabled scanouts.
+    pub fn new(
+        memory: RutabagaVhostUserMemory,
+        scanouts: Vec<RutabagaRect>,
+    ) -> RutabagaVhostUserGpu {
+        RutabagaVhostUserGpu { memory, scanouts }
+    }
+
+    pub fn memory(&mut self) -> &mut RutabagaVhostUserMemory {
+        &mut self.memory
+    }
+
+    /// Replaces the scanouts reported to the guest, after QEMU's display configuration changed.
+    pub fn set_scanouts(&mut self, scanouts: Vec<RutabagaRect>) {
+        self.scanouts = scanouts;
+    }
+
+    /// Runs the control queue request in `request` and writes its response to `response`.
+    /// Failed commands are answered with virtio-gpu error responses, so an error is only returned
+    /// if the request has no header or the response doesn't fit.  Only successful fenced commands
+    /// create their fence, failed ones are returned to the guest right away.
+    pub fn process_command(
+        &mut self,
+        rutabaga: &mut Rutabaga,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> RutabagaResult<RutabagaVhostUserCommand> {
+        let (hdr, _) = read_command::<virtio_gpu_ctrl_hdr>(request)?;
+
+        let mut display = None;
+        let mut fence = None;
+        let (resp_type, payload) = match self.run_command(rutabaga, &hdr, request, &mut display) {
+            Ok((resp_type, payload))
+                if resp_type < VIRTIO_GPU_RESP_ERR_UNSPEC
+                    && hdr.flags & RUTABAGA_FLAG_FENCE != 0 =>
+            {
+                let command_fence = RutabagaFence {
+                    flags: hdr.flags,
+                    fence_id: hdr.fence_id,
+                    ctx_id: hdr.ctx_id,
+                    ring_idx: hdr.ring_idx,
+                };
+                match rutabaga.create_fence(command_fence) {
+                    Ok(()) => {
+                        fence = Some(command_fence);
+                        (resp_type, payload)
+                    }
+                    Err(e) => (error_response(&e), Vec::new()),
+                }
+            }
+            Ok(result) => result,
+            Err(e) => {
+                error!("virtio-gpu command {:#x} failed: {}", hdr.type_, e);
+                (error_response(&e), Vec::new())
+            }
+        };
+
+        let resp_hdr = virtio_gpu_ctrl_hdr {
+            type_: resp_type,
+            flags: hdr.flags & (RUTABAGA_FLAG_FENCE | RUTABAGA_FLAG_INFO_RING_IDX),
+            fence_id: hdr.fence_id,
+            ctx_id: hdr.ctx_id,
+            ring_idx: hdr.ring_idx,
+            padding: Default::default(),
+        };
+
+        let response_len = size_of::<virtio_gpu_ctrl_hdr>() + payload.len();
+        let response = response
+            .get_mut(..response_len)
+            .ok_or(RutabagaError::InvalidCommandBuffer)?;
+        let (resp_hdr_bytes, resp_payload) =
+            response.split_at_mut(size_of::<virtio_gpu_ctrl_hdr>());
+        resp_hdr_bytes.copy_from_slice(resp_hdr.as_bytes());
+        resp_payload.copy_from_slice(&payload);
+
+        Ok(RutabagaVhostUserCommand {
+            response_len,
+            fence,
+            display,
+        })
+    }
+
+    // Returns the response type and the response data following the header.
+    fn run_command(
+        &mut self,
+        rutabaga: &mut Rutabaga,
+        hdr: &virtio_gpu_ctrl_hdr,
+        request: &[u8],
+        display: &mut Option<RutabagaVhostUserDisplay>,
+    ) -> RutabagaResult<(u32, Vec<u8>)> {
+        let no_data = (VIRTIO_GPU_RESP_OK_NODATA, Vec::new());
+        match hdr.type_ {
+            VIRTIO_GPU_CMD_GET_DISPLAY_INFO => {
+                let mut pmodes = [virtio_gpu_display_one::default(); VIRTIO_GPU_MAX_SCANOUTS];
+                for (pmode, scanout) in pmodes.iter_mut().zip(&self.scanouts) {
+                    pmode.r.x = scanout.x;
+                    pmode.r.y = scanout.y;
+                    pmode.r.width = scanout.w;
+                    pmode.r.height = scanout.h;
+                    pmode.enabled = 1;
+                }
+                Ok((VIRTIO_GPU_RESP_OK_DISPLAY_INFO, pmodes.as_bytes().to_vec()))
+            }
+            VIRTIO_GPU_CMD_RESOURCE_CREATE_2D => {
+                let (cmd, _) = read_command::<virtio_gpu_resource_create_2d>(request)?;
+                let resource_create_3d = ResourceCreate3D {
+                    target: RUTABAGA_PIPE_TEXTURE_2D,
+                    format: cmd.format,
+                    bind: RUTABAGA_PIPE_BIND_RENDER_TARGET,
+                    width: cmd.width,
+                    height: cmd.height,
+                    depth: 1,
+                    array_size: 1,
+                    last_level: 0,
+                    nr_samples: 0,
+                    flags: 0,
+                };
+                rutabaga.resource_create_3d(cmd.resource_id, resource_create_3d)?;
+                Ok(no_data)
+            }
+            VIRTIO_GPU_CMD_RESOURCE_UNREF => {
+                let (cmd, _) = read_command::<virtio_gpu_resource>(request)?;
+                rutabaga.unref_resource(cmd.resource_id)?;
+                Ok(no_data)
+            }
+            VIRTIO_GPU_CMD_SET_SCANOUT => {
+                let (cmd, _) = read_command::<virtio_gpu_set_scanout>(request)?;
+                if cmd.scanout_id as usize >= self.scanouts.len() {
+                    return Ok((VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID, Vec::new()));
+                }
+                if cmd.resource_id != 0 {
+                    rutabaga.set_scanout(cmd.scanout_id, cmd.resource_id, None)?;
+                }
+                *display = Some(RutabagaVhostUserDisplay::SetScanout {
+                    scanout_id: cmd.scanout_id,
+                    resource_id: cmd.resource_id,
+                    rect: cmd.r.into(),
+                });
+                Ok(no_data)
+            }
+            VIRTIO_GPU_CMD_SET_SCANOUT_BLOB => {
+                let (cmd, _) = read_command::<virtio_gpu_set_scanout_blob>(request)?;
+                if cmd.scanout_id as usize >= self.scanouts.len() {
+                    return Ok((VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID, Vec::new()));
+                }
+                if cmd.resource_id != 0 {
+                    let info = Resource3DInfo {
+                        width: cmd.width,
+                        height: cmd.height,
+                        strides: cmd.strides,
+                        offsets: cmd.offsets,
+                        ..Default::default()
+                    };
+                    rutabaga.set_scanout(cmd.scanout_id, cmd.resource_id, Some(info))?;
+                }
+                *display = Some(RutabagaVhostUserDisplay::SetScanout {
+                    scanout_id: cmd.scanout_id,
+                    resource_id: cmd.resource_id,
+                    rect: cmd.r.into(),
+                });
+                Ok(no_data)
+            }
+            VIRTIO_GPU_CMD_RESOURCE_FLUSH => {
+                let (cmd, _) = read_command::<virtio_gpu_resource_flush>(request)?;
+                rutabaga.resource_damage(cmd.resource_id, cmd.r.into())?;
+                *display = Some(RutabagaVhostUserDisplay::Flush {
+                    resource_id: cmd.resource_id,
+                    rect: cmd.r.into(),
+                });
+                Ok(no_data)
+            }
+            VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D => {
+                let (cmd, _) = read_command::<virtio_gpu_transfer_to_host_2d>(request)?;
+                let transfer =
+                    Transfer3D::new_2d(cmd.r.x, cmd.r.y, cmd.r.width, cmd.r.height, cmd.offset);
+                rutabaga.transfer_write(0, cmd.resource_id, transfer, None)?;
+                Ok(no_data)
+            }
+            VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING => {
+                let (cmd, entries) = read_command::<virtio_gpu_resource_attach_backing>(request)?;
+                let iovecs = self.memory.translate_entries(entries, cmd.nr_entries)?;
+                rutabaga.attach_backing(cmd.resource_id, iovecs)?;
+                Ok(no_data)
+            }
+            VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING => {
+                let (cmd, _) = read_command::<virtio_gpu_resource>(request)?;
+                rutabaga.detach_backing(cmd.resource_id)?;
+                Ok(no_data)
+            }
+            VIRTIO_GPU_CMD_GET_CAPSET_INFO => {
+                let (cmd, _) = read_command::<virtio_gpu_get_capset_info>(request)?;
+                let (capset_id, capset_max_version, capset_max_size) =
+                    rutabaga.get_capset_info(cmd.capset_index)?;
+                let resp = virtio_gpu_resp_capset_info {
+                    capset_id,
+                    capset_max_version,
+                    capset_max_size,
+                    padding: 0,
+                };
+                Ok((VIRTIO_GPU_RESP_OK_CAPSET_INFO, resp.as_bytes().to_vec()))
+            }
+            VIRTIO_GPU_CMD_GET_CAPSET => {
+                let (cmd, _) = read_command::<virtio_gpu_get_capset>(request)?;
+                let capset = rutabaga.get_capset(cmd.capset_id, cmd.capset_version)?;
+                Ok((VIRTIO_GPU_RESP_OK_CAPSET, capset))
+            }
+            VIRTIO_GPU_CMD_RESOURCE_ASSIGN_UUID => {
+                let (cmd, _) = read_command::<virtio_gpu_resource>(request)?;
+                let uuid = rutabaga.resource_uuid(cmd.resource_id)?;
+                Ok((VIRTIO_GPU_RESP_OK_RESOURCE_UUID, uuid.to_vec()))
+            }
+            VIRTIO_GPU_CMD_RESOURCE_CREATE_BLOB => {
+                let (cmd, entries) = read_command::<virtio_gpu_resource_create_blob>(request)?;
+                let iovecs = match cmd.nr_entries {
+                    0 => None,
+                    nr_entries => Some(self.memory.translate_entries(entries, nr_entries)?),
+                };
+                let resource_create_blob = ResourceCreateBlob {
+                    blob_mem: cmd.blob_mem,
+                    blob_flags: cmd.blob_flags,
+                    blob_id: cmd.blob_id,
+                    size: cmd.size,
+                };
+                rutabaga.resource_create_blob(
+                    hdr.ctx_id,
+                    cmd.resource_id,
+                    resource_create_blob,
+                    iovecs,
+                    None,
+                )?;
+                Ok(no_data)
+            }
+            VIRTIO_GPU_CMD_CTX_CREATE => {
+                let (cmd, _) = read_command::<virtio_gpu_ctx_create>(request)?;
+                let name = cmd
+                    .debug_name
+                    .get(..cmd.nlen as usize)
+                    .and_then(|name| std::str::from_utf8(name).ok());
+                rutabaga.create_context(hdr.ctx_id, cmd.context_init, name)?;
+                Ok(no_data)
+            }
+            VIRTIO_GPU_CMD_CTX_DESTROY => {
+                rutabaga.destroy_context(hdr.ctx_id)?;
+                Ok(no_data)
+            }
+            VIRTIO_GPU_CMD_CTX_ATTACH_RESOURCE => {
+                let (cmd, _) = read_command::<virtio_gpu_resource>(request)?;
+                rutabaga.context_attach_resource(hdr.ctx_id, cmd.resource_id)?;
+                Ok(no_data)
+            }
+            VIRTIO_GPU_CMD_CTX_DETACH_RESOURCE => {
+                let (cmd, _) = read_command::<virtio_gpu_resource>(request)?;
+                rutabaga.context_detach_resource(hdr.ctx_id, cmd.resource_id)?;
+                Ok(no_data)
+            }
+            VIRTIO_GPU_CMD_RESOURCE_CREATE_3D => {
+                let (cmd, _) = read_command::<virtio_gpu_resource_create_3d>(request)?;
+                let resource_create_3d = ResourceCreate3D {
+                    target: cmd.target,
+                    format: cmd.format,
+                    bind: cmd.bind,
+                    width: cmd.width,
+                    height: cmd.height,
+                    depth: cmd.depth,
+                    array_size: cmd.array_size,
+                    last_level: cmd.last_level,
+                    nr_samples: cmd.nr_samples,
+                    flags: cmd.flags,
+                };
+                rutabaga.resource_create_3d(cmd.resource_id, resource_create_3d)?;
+                Ok(no_data)
+            }
+            VIRTIO_GPU_CMD_TRANSFER_TO_HOST_3D => {
+                let (cmd, _) = read_command::<virtio_gpu_transfer_host_3d>(request)?;
+                rutabaga.transfer_write(hdr.ctx_id, cmd.resource_id, (&cmd).into(), None)?;
+                Ok(no_data)
+            }
+            VIRTIO_GPU_CMD_TRANSFER_FROM_HOST_3D => {
+                let (cmd, _) = read_command::<virtio_gpu_transfer_host_3d>(request)?;
+                rutabaga.transfer_read(hdr.ctx_id, cmd.resource_id, (&cmd).into(), None)?;
+                Ok(no_data)
+            }
+            VIRTIO_GPU_CMD_SUBMIT_3D => {
+                let (cmd, commands) = read_command::<virtio_gpu_cmd_submit>(request)?;
+                let mut commands = commands
+                    .get(..cmd.size as usize)
+                    .ok_or(RutabagaError::InvalidCommandSize(cmd.size as usize))?
+                    .to_vec();
+                rutabaga.submit_command(hdr.ctx_id, &mut commands, &[])?;
+                Ok(no_data)
+            }
+            _ => Ok((VIRTIO_GPU_RESP_ERR_UNSPEC, Vec::new())),
+        }
+    }
+}
+
+/// A descriptor chain to put on the used ring of the control queue.
+#[derive(Copy, Clone, Debug)]
+pub struct RutabagaVhostUserUsed {
+    pub desc_index: u16,
+    pub len: u32,
+}
+
+pub type RutabagaVhostUserUsedHandler = RutabagaHandler<RutabagaVhostUserUsed>;
+
+// Fenced requests complete in order on their timeline: the global one, or a context's ring.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum FenceTimeline {
+    Global,
+    Ring(u32, u8),
+}
+
+impl From<&RutabagaFence> for FenceTimeline {
+    fn from(fence: &RutabagaFence) -> FenceTimeline {
+        if fence.flags & RUTABAGA_FLAG_INFO_RING_IDX != 0 {
+            FenceTimeline::Ring(fence.ctx_id, fence.ring_idx)
+        } else {
+            FenceTimeline::Global
+        }
+    }
+}
+
+#[derive(Default)]
+struct FenceState {
+    // The requests waiting on each timeline, by fence id.
+    held: Map<FenceTimeline, Vec<(u64, RutabagaVhostUserUsed)>>,
+    // The last fence completed on each timeline.
+    completed: Map<FenceTimeline, u64>,
+    call: Option<Event>,
+}
+
+/// Holds fenced control queue requests until rutabaga completes their fences, then returns them
+/// to the guest and signals the control queue's callfd.
+#[derive(Clone)]
+pub struct RutabagaVhostUserFences {
+    state: Arc<Mutex<FenceState>>,
+    used_handler: RutabagaVhostUserUsedHandler,
+}
+
+impl RutabagaVhostUserFences {
+    /// `used_handler` puts completed requests on the used ring.  It is called from the threads
+    /// completing fences, before the callfd is signaled.
+    pub fn new(used_handler: RutabagaVhostUserUsedHandler) -> RutabagaVhostUserFences {
+        RutabagaVhostUserFences {
+            state: Default::default(),
+            used_handler,
+        }
+    }
+
+    /// Returns the handler to build rutabaga with.
+    pub fn fence_handler(&self) -> RutabagaFenceHandler {
+        let fences = self.clone();
+        RutabagaFenceHandler::new(move |fence: RutabagaFence| fences.complete(fence))
+    }
+
+    /// Sets the callfd of the control queue, from VHOST_USER_SET_VRING_CALL.
+    pub fn set_call_event(&self, call: Option<Event>) {
+        self.state.lock().unwrap().call = call;
+    }
+
+    /// Holds the request `used` until `fence` completes.  The request is returned right away if
+    /// the fence already did.
+    pub fn hold(&self, fence: RutabagaFence, used: RutabagaVhostUserUsed) {
+        let timeline = FenceTimeline::from(&fence);
+        let mut state = self.state.lock().unwrap();
+        if state
+            .completed
+            .get(&timeline)
+            .is_some_and(|completed| fence.fence_id <= *completed)
+        {
+            drop(state);
+            self.return_used(vec![used]);
+            return;
+        }
+
+        state
+            .held
+            .entry(timeline)
+            .or_default()
+            .push((fence.fence_id, used));
+    }
+
+    fn complete(&self, fence: RutabagaFence) {
+        let timeline = FenceTimeline::from(&fence);
+        let mut state = self.state.lock().unwrap();
+        let completed = state.completed.entry(timeline).or_default();
+        *completed = (*completed).max(fence.fence_id);
+        let completed = *completed;
+
+        let Some(held) = state.held.get_mut(&timeline) else {
+            return;
+        };
+
+        let mut used = Vec::new();
+        held.retain(|(fence_id, held_used)| {
+            let done = *fence_id <= completed;
+            if done {
+                used.push(*held_used);
+            }
+            !done
+        });
+        drop(state);
+
+        if !used.is_empty() {
+            self.return_used(used);
+        }
+    }
+
+    fn return_used(&self, used: Vec<RutabagaVhostUserUsed>) {
+        for used in used {
+            self.used_handler.call(used);
+        }
+
+        if let Some(call) = self.state.lock().unwrap().call.as_mut() {
+            if let Err(e) = call.signal() {
+                error!("failed to signal the control queue: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RutabagaBuilder;
+    use crate::RutabagaComponentType;
+
+    const HDR_SIZE: usize = size_of::<virtio_gpu_ctrl_hdr>();
+
+    fn new_2d() -> Rutabaga {
+        RutabagaBuilder::new(0, RutabagaHandler::new(|_| {}))
+            .set_default_component(RutabagaComponentType::Rutabaga2D)
+            .build()
+            .unwrap()
+    }
+
+    fn new_gpu() -> RutabagaVhostUserGpu {
+        let scanout = RutabagaRect {
+            x: 0,
+            y: 0,
+            w: 64,
+            h: 64,
+        };
+        RutabagaVhostUserGpu::new(Default::default(), vec![scanout])
+    }
+
+    fn hdr(type_: u32, flags: u32) -> virtio_gpu_ctrl_hdr {
+        virtio_gpu_ctrl_hdr {
+            type_,
+            flags,
+            fence_id: 7,
+            ..Default::default()
+        }
+    }
+
+    // Runs `request`, and returns the response type along with the command's outcome.
+    fn process(
+        gpu: &mut RutabagaVhostUserGpu,
+        rutabaga: &mut Rutabaga,
+        request: &[u8],
+    ) -> (u32, RutabagaVhostUserCommand) {
+        let mut response = [0u8; 1024];
+        let command = gpu
+            .process_command(rutabaga, request, &mut response)
+            .unwrap();
+        let (resp_hdr, _) = read_command::<virtio_gpu_ctrl_hdr>(&response).unwrap();
+        (resp_hdr.type_, command)
+    }
+
+    #[test]
+    fn truncated_header() {
+        let mut rutabaga = new_2d();
+        let mut gpu = new_gpu();
+        let request = hdr(VIRTIO_GPU_CMD_GET_DISPLAY_INFO, 0);
+        let mut response = [0u8; 1024];
+        assert!(gpu
+            .process_command(
+                &mut rutabaga,
+                &request.as_bytes()[..HDR_SIZE - 1],
+                &mut response
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn truncated_commands() {
+        let mut rutabaga = new_2d();
+        let mut gpu = new_gpu();
+
+        // Commands whose body is missing or cut short are invalid, and create no fence.
+        for type_ in [
+            VIRTIO_GPU_CMD_RESOURCE_CREATE_2D,
+            VIRTIO_GPU_CMD_RESOURCE_UNREF,
+            VIRTIO_GPU_CMD_SET_SCANOUT,
+            VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING,
+            VIRTIO_GPU_CMD_RESOURCE_CREATE_BLOB,
+            VIRTIO_GPU_CMD_SUBMIT_3D,
+        ] {
+            let mut request = hdr(type_, RUTABAGA_FLAG_FENCE).as_bytes().to_vec();
+            request.extend_from_slice(&[0u8; 4]);
+            let (resp_type, command) = process(&mut gpu, &mut rutabaga, &request);
+            assert_eq!(resp_type, VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+            assert_eq!(command.response_len, HDR_SIZE);
+            assert!(command.fence.is_none());
+        }
+    }
+
+    #[test]
+    fn truncated_payloads() {
+        let mut rutabaga = new_2d();
+        let mut gpu = new_gpu();
+
+        // More backing entries than the request holds.
+        let attach = virtio_gpu_resource_attach_backing {
+            hdr: hdr(VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING, 0),
+            resource_id: 1,
+            nr_entries: 2,
+        };
+        let mut request = attach.as_bytes().to_vec();
+        request.extend_from_slice(virtio_gpu_mem_entry::default().as_bytes());
+        let (resp_type, _) = process(&mut gpu, &mut rutabaga, &request);
+        assert_eq!(resp_type, VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+
+        // More command bytes than the request holds.
+        let submit = virtio_gpu_cmd_submit {
+            hdr: hdr(VIRTIO_GPU_CMD_SUBMIT_3D, 0),
+            size: 16,
+            padding: 0,
+        };
+        let mut request = submit.as_bytes().to_vec();
+        request.extend_from_slice(&[0u8; 8]);
+        let (resp_type, _) = process(&mut gpu, &mut rutabaga, &request);
+        assert_eq!(resp_type, VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+    }
+
+    #[test]
+    fn response_too_small() {
+        let mut rutabaga = new_2d();
+        let mut gpu = new_gpu();
+        let request = hdr(VIRTIO_GPU_CMD_GET_DISPLAY_INFO, 0);
+        let mut response = [0u8; HDR_SIZE];
+        assert!(gpu
+            .process_command(&mut rutabaga, request.as_bytes(), &mut response)
+            .is_err());
+    }
+
+    #[test]
+    fn fenced_errors_create_no_fence() {
+        let mut rutabaga = new_2d();
+        let mut gpu = new_gpu();
+
+        let request = hdr(0xffff, RUTABAGA_FLAG_FENCE);
+        let (resp_type, command) = process(&mut gpu, &mut rutabaga, request.as_bytes());
+        assert_eq!(resp_type, VIRTIO_GPU_RESP_ERR_UNSPEC);
+        assert!(command.fence.is_none());
+
+        let set_scanout = virtio_gpu_set_scanout {
+            hdr: hdr(VIRTIO_GPU_CMD_SET_SCANOUT, RUTABAGA_FLAG_FENCE),
+            scanout_id: 1,
+            ..Default::default()
+        };
+        let (resp_type, command) = process(&mut gpu, &mut rutabaga, set_scanout.as_bytes());
+        assert_eq!(resp_type, VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID);
+        assert!(command.fence.is_none());
+        assert!(command.display.is_none());
+
+        let unref = virtio_gpu_resource {
+            hdr: hdr(VIRTIO_GPU_CMD_RESOURCE_UNREF, RUTABAGA_FLAG_FENCE),
+            resource_id: 1,
+            padding: 0,
+        };
+        let (resp_type, command) = process(&mut gpu, &mut rutabaga, unref.as_bytes());
+        assert_eq!(resp_type, VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID);
+        assert!(command.fence.is_none());
+    }
+
+    #[test]
+    fn fenced_command() {
+        let mut rutabaga = new_2d();
+        let mut gpu = new_gpu();
+
+        let create = virtio_gpu_resource_create_2d {
+            hdr: hdr(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D, RUTABAGA_FLAG_FENCE),
+            resource_id: 1,
+            format: 1,
+            width: 16,
+            height: 16,
+        };
+        let (resp_type, command) = process(&mut gpu, &mut rutabaga, create.as_bytes());
+        assert_eq!(resp_type, VIRTIO_GPU_RESP_OK_NODATA);
+        assert_eq!(command.fence.unwrap().fence_id, 7);
+
+        let request = hdr(VIRTIO_GPU_CMD_GET_DISPLAY_INFO, 0);
+        let (resp_type, command) = process(&mut gpu, &mut rutabaga, request.as_bytes());
+        assert_eq!(resp_type, VIRTIO_GPU_RESP_OK_DISPLAY_INFO);
+        assert_eq!(
+            command.response_len,
+            HDR_SIZE + VIRTIO_GPU_MAX_SCANOUTS * size_of::<virtio_gpu_display_one>()
+        );
+        assert!(command.fence.is_none());
+    }
+}
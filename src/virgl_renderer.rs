@@ -7,10 +7,16 @@
 
 #![cfg(feature = "virgl_renderer")]
 
+#[cfg(not(virgl_renderer_unstable))]
+use std::collections::BTreeMap;
 use std::ffi::CStr;
+use std::ffi::OsString;
 use std::fs::canonicalize;
+use std::fs::read_dir;
 use std::fs::OpenOptions;
 use std::io::Error as SysError;
+#[cfg(not(virgl_renderer_unstable))]
+use std::io::ErrorKind;
 use std::io::IoSlice;
 use std::io::IoSliceMut;
 use std::mem::size_of;
@@ -21,17 +27,24 @@ use std::os::raw::c_int;
 use std::os::raw::c_void;
 use std::os::unix::fs::OpenOptionsExt;
 use std::panic::catch_unwind;
+use std::path::PathBuf;
 use std::process::abort;
 use std::ptr::null_mut;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
+#[cfg(not(virgl_renderer_unstable))]
+use std::time::Duration;
+#[cfg(not(virgl_renderer_unstable))]
+use std::time::Instant;
 
 use log::error;
 use log::info;
 use log::log;
 use log::warn;
 use log::Level;
+#[cfg(not(virgl_renderer_unstable))]
+use mesa3d_util::AsRawDescriptor;
 use mesa3d_util::FromRawDescriptor;
 use mesa3d_util::IntoRawDescriptor;
 use mesa3d_util::MesaError;
@@ -39,10 +52,13 @@ use mesa3d_util::MesaHandle;
 use mesa3d_util::MesaMapping;
 use mesa3d_util::OwnedDescriptor;
 use mesa3d_util::RawDescriptor;
+#[cfg(not(virgl_renderer_unstable))]
+use mesa3d_util::WaitContext;
+#[cfg(not(virgl_renderer_unstable))]
+use mesa3d_util::WaitTimeout;
 use mesa3d_util::MESA_HANDLE_TYPE_MEM_DMABUF;
 use mesa3d_util::MESA_HANDLE_TYPE_MEM_OPAQUE_FD;
 use mesa3d_util::MESA_HANDLE_TYPE_MEM_SHM;
-#[cfg(virgl_renderer_unstable)]
 use mesa3d_util::MESA_HANDLE_TYPE_SIGNAL_SYNC_FD;
 
 use crate::generated::virgl_renderer_bindings::*;
@@ -52,24 +68,36 @@ use crate::renderer_utils::RutabagaCookie;
 use crate::renderer_utils::VirglBox;
 use crate::rutabaga_core::RutabagaComponent;
 use crate::rutabaga_core::RutabagaContext;
+use crate::rutabaga_core::RutabagaContextParams;
 use crate::rutabaga_core::RutabagaResource;
+use crate::rutabaga_trace::RutabagaDebugMarker;
+use crate::rutabaga_trace::RutabagaTrace;
+use crate::rutabaga_utils::handle_type_mask;
 use crate::rutabaga_utils::Resource3DInfo;
 use crate::rutabaga_utils::ResourceCreate3D;
 use crate::rutabaga_utils::ResourceCreateBlob;
+use crate::rutabaga_utils::RutabagaBacking;
 use crate::rutabaga_utils::RutabagaComponentType;
 use crate::rutabaga_utils::RutabagaError;
 use crate::rutabaga_utils::RutabagaFence;
 use crate::rutabaga_utils::RutabagaFenceHandler;
+use crate::rutabaga_utils::RutabagaHandleTypes;
 use crate::rutabaga_utils::RutabagaIovec;
+use crate::rutabaga_utils::RutabagaLogSink;
+use crate::rutabaga_utils::RutabagaLogger;
 use crate::rutabaga_utils::RutabagaResult;
 use crate::rutabaga_utils::Transfer3D;
 use crate::rutabaga_utils::VirglRendererFlags;
+use crate::rutabaga_utils::RUTABAGA_CAPSET_VIRGL;
+use crate::rutabaga_utils::RUTABAGA_CAPSET_VIRGL2;
+use crate::rutabaga_utils::RUTABAGA_CONTEXT_INIT_CAPSET_ID_MASK;
 use crate::rutabaga_utils::RUTABAGA_FLAG_FENCE;
 use crate::rutabaga_utils::RUTABAGA_FLAG_INFO_RING_IDX;
 use crate::rutabaga_utils::RUTABAGA_MAP_ACCESS_RW;
 use crate::RutabagaPath;
 use crate::RutabagaPaths;
 use crate::RUTABAGA_PATH_TYPE_GPU;
+use crate::RUTABAGA_PATH_TYPE_VULKAN_ICD;
 
 type Query = virgl_renderer_export_query;
 
@@ -77,6 +105,101 @@ type Query = virgl_renderer_export_query;
 /// find an available GPU itself.
 const DEFAULT_DRM_FD: i32 = -1;
 
+/// Directories searched by the Vulkan loader for ICD manifests, in its search order.
+const VULKAN_ICD_DIRS: [&str; 3] = [
+    "/etc/vulkan/icd.d",
+    "/usr/local/share/vulkan/icd.d",
+    "/usr/share/vulkan/icd.d",
+];
+
+/// File name prefix of lavapipe's ICD manifests, e.g. "lvp_icd.x86_64.json".
+const LAVAPIPE_ICD_PREFIX: &str = "lvp_icd";
+
+/// VIRGL_CCMD_SEND_STRING_MARKER of virgl_protocol.h.  The first dword of the command is the size
+/// of the string that follows it.
+const VIRGL_CCMD_SEND_STRING_MARKER: u32 = 51;
+
+/// Returns the strings of the VIRGL_CCMD_SEND_STRING_MARKER commands of a virgl command stream.
+/// The search stops at a malformed command, which virglrenderer rejects itself.
+fn virgl_string_markers(commands: &[u8]) -> Vec<&[u8]> {
+    let dword = |offset: usize| {
+        commands
+            .get(offset..offset + size_of::<u32>())
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+    };
+
+    let mut markers = Vec::new();
+    let mut offset = 0;
+    while let Some(header) = dword(offset) {
+        let payload_offset = offset + size_of::<u32>();
+        let payload_size = (header >> 16) as usize * size_of::<u32>();
+        let Some(payload) = commands.get(payload_offset..payload_offset + payload_size) else {
+            break;
+        };
+
+        if header & 0xff == VIRGL_CCMD_SEND_STRING_MARKER {
+            let string = dword(payload_offset)
+                .and_then(|size| payload.get(size_of::<u32>()..)?.get(..size as usize));
+            markers.extend(string);
+        }
+
+        offset = payload_offset + payload_size;
+    }
+
+    markers
+}
+
+/// Returns true if the host has a DRM render node virglrenderer could use.
+pub fn host_has_render_node() -> bool {
+    read_dir("/dev/dri")
+        .map(|entries| {
+            entries
+                .flatten()
+                .any(|entry| entry.file_name().to_string_lossy().starts_with("renderD"))
+        })
+        .unwrap_or(false)
+}
+
+/// Returns the ICD manifest of the CPU Vulkan driver.  A RUTABAGA_PATH_TYPE_VULKAN_ICD path takes
+/// precedence over lavapipe's manifest in the Vulkan loader's search directories.
+fn software_icd_path(rutabaga_paths: &Option<RutabagaPaths>) -> Option<PathBuf> {
+    let provided = rutabaga_paths.as_ref().and_then(|rpaths| {
+        rpaths
+            .iter()
+            .find(|rpath| rpath.path_type == RUTABAGA_PATH_TYPE_VULKAN_ICD)
+            .map(|rpath| rpath.path.clone())
+    });
+
+    provided.or_else(|| {
+        VULKAN_ICD_DIRS.iter().find_map(|dir| {
+            read_dir(dir)
+                .ok()?
+                .flatten()
+                .map(|entry| entry.path())
+                .find(|path| {
+                    path.file_name()
+                        .is_some_and(|name| name.to_string_lossy().starts_with(LAVAPIPE_ICD_PREFIX))
+                })
+        })
+    })
+}
+
+/// Returns the environment that makes a render server run Venus on the CPU Vulkan driver, or
+/// None if there is no such driver.  Only the server's environment is changed, so the driver
+/// isn't forced on the rest of the VMM.
+pub(crate) fn software_render_server_envs(
+    rutabaga_paths: &Option<RutabagaPaths>,
+) -> Option<Vec<(OsString, OsString)>> {
+    let icd_path = software_icd_path(rutabaga_paths)?;
+    info!("using software vulkan driver {icd_path:?}");
+
+    // VK_ICD_FILENAMES is the name used by loaders older than 1.3.207.
+    Some(vec![
+        ("VK_DRIVER_FILES".into(), icd_path.clone().into()),
+        ("VK_ICD_FILENAMES".into(), icd_path.into()),
+    ])
+}
+
 /// Check if the given rutabaga path is a valid GPU path.
 fn is_valid_gpu_path(rpath: &RutabagaPath) -> bool {
     if rpath.path_type != RUTABAGA_PATH_TYPE_GPU {
@@ -107,56 +230,20 @@ fn dup(rd: RawDescriptor) -> RutabagaResult<OwnedDescriptor> {
 }
 
 /// The virtio-gpu backend state tracker which supports accelerated rendering.
-pub struct VirglRenderer {}
+pub struct VirglRenderer {
+    // Venus runs on a CPU Vulkan driver, whose exported memory is host shmem.
+    software: bool,
+    trace: RutabagaTrace,
+    #[cfg(not(virgl_renderer_unstable))]
+    egl_fences: Option<EglFenceExporter>,
+}
 
 struct VirglRendererContext {
     ctx_id: u32,
-}
-
-fn import_resource(resource: &mut RutabagaResource) -> RutabagaResult<()> {
-    if (resource.component_mask & (1 << (RutabagaComponentType::VirglRenderer as u8))) != 0 {
-        return Ok(());
-    }
-
-    if let Some(mesa_handle) = resource.handle.as_ref().and_then(|h| h.as_mesa_handle()) {
-        #[cfg(target_os = "linux")]
-        if mesa_handle.handle_type == MESA_HANDLE_TYPE_MEM_DMABUF {
-            let dmabuf_fd = mesa_handle
-                .os_handle
-                .try_clone()
-                .map_err(MesaError::IoError)?
-                .into_raw_descriptor();
-
-            // SAFETY:
-            // Safe because we are being passed a valid fd
-            unsafe {
-                let dmabuf_size = libc::lseek64(dmabuf_fd, 0, libc::SEEK_END);
-                libc::lseek64(dmabuf_fd, 0, libc::SEEK_SET);
-                let args = virgl_renderer_resource_import_blob_args {
-                    res_handle: resource.resource_id,
-                    blob_mem: resource.blob_mem,
-                    fd_type: VIRGL_RENDERER_BLOB_FD_TYPE_DMABUF,
-                    fd: dmabuf_fd,
-                    size: dmabuf_size as u64,
-                };
-                let ret = virgl_renderer_resource_import_blob(&args);
-                if ret != 0 {
-                    // import_blob can fail if we've previously imported this resource,
-                    // but in any case virglrenderer does not take ownership of the fd
-                    // in error paths
-                    //
-                    // Because of the re-import case we must still fall through to the
-                    // virgl_renderer_ctx_attach_resource() call.
-                    libc::close(dmabuf_fd);
-                    return Ok(());
-                }
-
-                resource.component_mask |= 1 << (RutabagaComponentType::VirglRenderer as u8);
-            }
-        }
-    }
-
-    Ok(())
+    // Virgl guest drivers transfer blobs, while Venus and native context guests map them
+    // coherently.
+    explicit_transfers: bool,
+    trace: RutabagaTrace,
 }
 
 impl RutabagaContext for VirglRendererContext {
@@ -164,15 +251,30 @@ impl RutabagaContext for VirglRendererContext {
         &mut self,
         commands: &mut [u8],
         fence_ids: &[u64],
-        _shareable_fences: Vec<MesaHandle>,
+        shareable_fences: Vec<MesaHandle>,
     ) -> RutabagaResult<()> {
+        // Stable releases can't take in-fences, so wait for them here instead.
         #[cfg(not(virgl_renderer_unstable))]
         if !fence_ids.is_empty() {
-            return Err(MesaError::Unsupported.into());
+            if shareable_fences.len() != fence_ids.len() {
+                return Err(MesaError::Unsupported.into());
+            }
+            wait_sync_fds(&shareable_fences)?;
         }
+        #[cfg(virgl_renderer_unstable)]
+        let _ = shareable_fences;
         if commands.len() % size_of::<u32>() != 0 {
             return Err(RutabagaError::InvalidCommandSize(commands.len()));
         }
+        // Virgl guests send their debug markers as string markers, which virglrenderer only
+        // passes on to the host GL driver.
+        if self.explicit_transfers && self.trace.enabled() {
+            for marker in virgl_string_markers(commands) {
+                let label = String::from_utf8_lossy(marker);
+                self.trace
+                    .debug_marker(self.ctx_id, RutabagaDebugMarker::Insert(&label));
+            }
+        }
         let dword_count = (commands.len() / size_of::<u32>()) as i32;
         #[cfg(not(virgl_renderer_unstable))]
         // SAFETY:
@@ -202,11 +304,6 @@ impl RutabagaContext for VirglRendererContext {
     }
 
     fn attach(&mut self, resource: &mut RutabagaResource) {
-        match import_resource(resource) {
-            Ok(()) => (),
-            Err(e) => error!("importing resource failing with {}", e),
-        }
-
         // SAFETY:
         // The context id and resource id must be valid because the respective instances ensure
         // their lifetime.
@@ -228,6 +325,10 @@ impl RutabagaContext for VirglRendererContext {
         RutabagaComponentType::VirglRenderer
     }
 
+    fn explicit_transfers(&self) -> bool {
+        self.explicit_transfers
+    }
+
     fn context_create_fence(&mut self, fence: RutabagaFence) -> RutabagaResult<Option<MesaHandle>> {
         // RutabagaFence::flags are not compatible with virglrenderer's fencing API and currently
         // virglrenderer context's assume all fences on a single timeline are MERGEABLE, and enforce
@@ -249,6 +350,231 @@ impl RutabagaContext for VirglRendererContext {
     }
 }
 
+/// How long a submission waits for its in-fences.  The wait blocks the thread the submission
+/// came from, so a fence that never signals fails the submission rather than wedging the queue.
+#[cfg(not(virgl_renderer_unstable))]
+const IN_FENCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Blocks until every sync fd in `fences` has signaled, or fails after `IN_FENCE_TIMEOUT`.
+#[cfg(not(virgl_renderer_unstable))]
+fn wait_sync_fds(fences: &[MesaHandle]) -> RutabagaResult<()> {
+    let mut wait_ctx = WaitContext::new()?;
+    for (i, fence) in fences.iter().enumerate() {
+        if fence.handle_type != MESA_HANDLE_TYPE_SIGNAL_SYNC_FD {
+            return Err(MesaError::InvalidMesaHandle.into());
+        }
+        wait_ctx.add(i as u64, &fence.os_handle)?;
+    }
+
+    // Sync fds stay readable once signaled, so each is removed after it is reported.
+    let deadline = Instant::now() + IN_FENCE_TIMEOUT;
+    let mut pending = fences.len();
+    while pending > 0 {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(MesaError::IoError(SysError::from(ErrorKind::TimedOut)).into());
+        }
+
+        for event in wait_ctx.wait(WaitTimeout::Finite(remaining))? {
+            wait_ctx.delete(&fences[event.connection_id as usize].os_handle)?;
+            pending -= 1;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(virgl_renderer_unstable))]
+const EGL_NONE: i32 = 0x3038;
+#[cfg(not(virgl_renderer_unstable))]
+const EGL_SYNC_NATIVE_FENCE_ANDROID: u32 = 0x3144;
+#[cfg(not(virgl_renderer_unstable))]
+const EGL_SYNC_FLUSH_COMMANDS_BIT_KHR: i32 = 0x0001;
+#[cfg(not(virgl_renderer_unstable))]
+const EGL_NO_NATIVE_FENCE_FD_ANDROID: i32 = -1;
+
+// Returns whether the sync fd has signaled, without blocking.
+#[cfg(not(virgl_renderer_unstable))]
+fn is_signaled(sync_fd: &OwnedDescriptor) -> bool {
+    let mut pollfd = libc::pollfd {
+        fd: sync_fd.as_raw_descriptor(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY:
+    // `pollfd` is a single valid pollfd, and a zero timeout never blocks.
+    let ret = unsafe { libc::poll(&mut pollfd, 1, 0) };
+    ret == 1 && pollfd.revents & libc::POLLIN != 0
+}
+
+#[cfg(not(virgl_renderer_unstable))]
+type EglGetProcAddress = unsafe extern "C" fn(name: *const c_char) -> *mut c_void;
+#[cfg(not(virgl_renderer_unstable))]
+type EglGetCurrentDisplay = unsafe extern "C" fn() -> *mut c_void;
+#[cfg(not(virgl_renderer_unstable))]
+type EglCreateSyncKhr =
+    unsafe extern "C" fn(display: *mut c_void, sync_type: u32, attribs: *const i32) -> *mut c_void;
+#[cfg(not(virgl_renderer_unstable))]
+type EglClientWaitSyncKhr =
+    unsafe extern "C" fn(display: *mut c_void, sync: *mut c_void, flags: i32, timeout: u64) -> i32;
+#[cfg(not(virgl_renderer_unstable))]
+type EglDestroySyncKhr = unsafe extern "C" fn(display: *mut c_void, sync: *mut c_void) -> u32;
+#[cfg(not(virgl_renderer_unstable))]
+type EglDupNativeFenceFdAndroid =
+    unsafe extern "C" fn(display: *mut c_void, sync: *mut c_void) -> i32;
+
+/// Exports sync fds through EGL_ANDROID_native_fence_sync, for stable releases without
+/// virgl_renderer_export_fence().  GL commands are submitted as they're decoded, so a native fence
+/// inserted on context 0 right after a virgl fence signals once the work of that fence completes.
+#[cfg(not(virgl_renderer_unstable))]
+struct EglFenceExporter {
+    get_current_display: EglGetCurrentDisplay,
+    create_sync: EglCreateSyncKhr,
+    client_wait_sync: EglClientWaitSyncKhr,
+    destroy_sync: EglDestroySyncKhr,
+    dup_native_fence_fd: EglDupNativeFenceFdAndroid,
+    // The native fences of the fences not yet seen signaled, by fence id.
+    pending: BTreeMap<u64, OwnedDescriptor>,
+    // The native fence last seen signaled, exported for fences that already retired.
+    signaled: Option<OwnedDescriptor>,
+    last_fence_id: Option<u64>,
+}
+
+#[cfg(not(virgl_renderer_unstable))]
+impl EglFenceExporter {
+    fn load() -> RutabagaResult<EglFenceExporter> {
+        // SAFETY:
+        // The library name is a valid C string.  RTLD_NOLOAD only returns the libEGL virglrenderer
+        // already loaded, and the handle is never closed, so it stays loaded.
+        let library =
+            unsafe { libc::dlopen(c"libEGL.so.1".as_ptr(), libc::RTLD_NOW | libc::RTLD_NOLOAD) };
+        if library.is_null() {
+            return Err(MesaError::WithContext("libEGL isn't loaded into the process").into());
+        }
+
+        // SAFETY:
+        // `library` is a valid handle, and the symbol names are valid C strings.
+        let (get_proc_address, get_current_display) = unsafe {
+            (
+                libc::dlsym(library, c"eglGetProcAddress".as_ptr()),
+                libc::dlsym(library, c"eglGetCurrentDisplay".as_ptr()),
+            )
+        };
+        if get_proc_address.is_null() || get_current_display.is_null() {
+            return Err(MesaError::WithContext("libEGL lacks eglGetProcAddress").into());
+        }
+
+        // SAFETY:
+        // eglGetProcAddress has the signature of EglGetProcAddress.
+        let get_proc_address: EglGetProcAddress = unsafe { std::mem::transmute(get_proc_address) };
+        let proc_address = |name: &CStr| -> RutabagaResult<*mut c_void> {
+            // SAFETY:
+            // `name` is a valid C string.
+            let address = unsafe { get_proc_address(name.as_ptr()) };
+            if address.is_null() {
+                return Err(MesaError::WithContext("EGL lacks native fence syncs").into());
+            }
+            Ok(address)
+        };
+
+        // SAFETY:
+        // The EGL functions have the signatures of their types.
+        unsafe {
+            Ok(EglFenceExporter {
+                get_current_display: std::mem::transmute::<*mut c_void, EglGetCurrentDisplay>(
+                    get_current_display,
+                ),
+                create_sync: std::mem::transmute::<*mut c_void, EglCreateSyncKhr>(proc_address(
+                    c"eglCreateSyncKHR",
+                )?),
+                client_wait_sync: std::mem::transmute::<*mut c_void, EglClientWaitSyncKhr>(
+                    proc_address(c"eglClientWaitSyncKHR")?,
+                ),
+                destroy_sync: std::mem::transmute::<*mut c_void, EglDestroySyncKhr>(proc_address(
+                    c"eglDestroySyncKHR",
+                )?),
+                dup_native_fence_fd: std::mem::transmute::<*mut c_void, EglDupNativeFenceFdAndroid>(
+                    proc_address(c"eglDupNativeFenceFDANDROID")?,
+                ),
+                pending: Default::default(),
+                signaled: None,
+                last_fence_id: None,
+            })
+        }
+    }
+
+    /// Inserts the native fence of `fence_id`, which virglrenderer just created.  Native fences of
+    /// earlier fences that signaled are released, since GL work completes in submission order.
+    fn create(&mut self, fence_id: u64) -> RutabagaResult<()> {
+        while let Some(entry) = self.pending.first_entry() {
+            if !is_signaled(entry.get()) {
+                break;
+            }
+            self.signaled = Some(entry.remove());
+        }
+
+        let sync_fd = self.insert_native_fence()?;
+        self.pending.insert(fence_id, sync_fd);
+        self.last_fence_id = Some(fence_id);
+        Ok(())
+    }
+
+    /// Returns a sync fd that signals once the GL work of `fence_id` completes.
+    fn export(&self, fence_id: u64) -> RutabagaResult<OwnedDescriptor> {
+        if let Some(sync_fd) = self.pending.get(&fence_id) {
+            return Ok(sync_fd.try_clone().map_err(MesaError::IoError)?);
+        }
+
+        // Fence ids increase, so an earlier fence without a native fence has retired.
+        match (&self.signaled, self.last_fence_id) {
+            (Some(sync_fd), Some(last_fence_id)) if fence_id <= last_fence_id => {
+                Ok(sync_fd.try_clone().map_err(MesaError::IoError)?)
+            }
+            _ => Err(MesaError::WithContext("unknown virgl fence").into()),
+        }
+    }
+
+    // Inserts a native fence into context 0, which virglrenderer makes current on this thread.
+    fn insert_native_fence(&self) -> RutabagaResult<OwnedDescriptor> {
+        // SAFETY:
+        // virglrenderer is initialized, and this is the thread it decodes commands on.
+        unsafe { virgl_renderer_force_ctx_0() };
+
+        // SAFETY:
+        // eglGetCurrentDisplay takes no arguments.
+        let display = unsafe { (self.get_current_display)() };
+        if display.is_null() {
+            return Err(MesaError::WithContext("no current EGL display").into());
+        }
+
+        let attribs = [EGL_NONE];
+        // SAFETY:
+        // `display` is the current display, and `attribs` is terminated by EGL_NONE.
+        let sync =
+            unsafe { (self.create_sync)(display, EGL_SYNC_NATIVE_FENCE_ANDROID, attribs.as_ptr()) };
+        if sync.is_null() {
+            return Err(MesaError::WithContext("eglCreateSyncKHR failed").into());
+        }
+
+        // The fence only gets an fd once it's flushed, which a wait with a zero timeout does.
+        // SAFETY:
+        // `sync` was created on `display` above, and is destroyed once, after its last use.
+        let fd = unsafe {
+            (self.client_wait_sync)(display, sync, EGL_SYNC_FLUSH_COMMANDS_BIT_KHR, 0);
+            let fd = (self.dup_native_fence_fd)(display, sync);
+            (self.destroy_sync)(display, sync);
+            fd
+        };
+        if fd == EGL_NO_NATIVE_FENCE_FD_ANDROID {
+            return Err(MesaError::WithContext("eglDupNativeFenceFDANDROID failed").into());
+        }
+
+        // SAFETY:
+        // The fd was just duplicated by EGL, so it's valid and owned by us.
+        Ok(unsafe { OwnedDescriptor::from_raw_descriptor(fd) })
+    }
+}
+
 impl Drop for VirglRendererContext {
     fn drop(&mut self) {
         // SAFETY:
@@ -262,7 +588,7 @@ impl Drop for VirglRendererContext {
 extern "C" fn log_callback(
     log_level: virgl_log_level_flags,
     message: *const ::std::os::raw::c_char,
-    _user_data: *mut ::std::os::raw::c_void,
+    user_data: *mut ::std::os::raw::c_void,
 ) {
     let level = match log_level {
         VIRGL_LOG_LEVEL_DEBUG => Level::Debug,
@@ -275,8 +601,23 @@ extern "C" fn log_callback(
     // SAFETY:
     // The caller ensures that `message` is always a valid pointer to a NULL-terminated string
     // (even if zero-length).
-    let message_str = unsafe { CStr::from_ptr(message) };
-    log!(level, "{}", message_str.to_string_lossy());
+    let message_str = unsafe { CStr::from_ptr(message) }.to_string_lossy();
+    if user_data.is_null() {
+        log!(level, "{}", message_str);
+        return;
+    }
+
+    // SAFETY:
+    // A non-null `user_data` is the logger set by `set_log_sink()`, which virglrenderer keeps
+    // alive until it frees it with `free_logger()`.
+    let logger = unsafe { &*(user_data as *const RutabagaLogger) };
+    logger.log(level, format_args!("{}", message_str));
+}
+
+extern "C" fn free_logger(user_data: *mut c_void) {
+    // SAFETY:
+    // `user_data` was leaked by `set_log_sink()`, and virglrenderer frees it only once.
+    drop(unsafe { Box::from_raw(user_data as *mut RutabagaLogger) });
 }
 
 extern "C" fn get_drm_fd(cookie: *mut c_void) -> c_int {
@@ -420,6 +761,55 @@ impl VirglRenderer {
         fence_handler: RutabagaFenceHandler,
         render_server_fd: Option<OwnedDescriptor>,
         rutabaga_paths: Option<RutabagaPaths>,
+    ) -> RutabagaResult<Box<dyn RutabagaComponent>> {
+        VirglRenderer::init_renderer(
+            virglrenderer_flags,
+            fence_handler,
+            render_server_fd,
+            rutabaga_paths,
+            false,
+        )
+    }
+
+    /// Initializes virglrenderer for hosts without a GPU.  Only Venus is enabled, and it runs in
+    /// a render server whose environment selects the CPU Vulkan driver, as returned by
+    /// `software_render_server_envs()`.
+    ///
+    /// The server must already be running, since one spawned by virglrenderer would inherit the
+    /// host's environment.  Blobs are exported to the VMM, which maps them as host shmem.
+    pub fn init_software(
+        virglrenderer_flags: VirglRendererFlags,
+        fence_handler: RutabagaFenceHandler,
+        render_server_fd: Option<OwnedDescriptor>,
+        rutabaga_paths: Option<RutabagaPaths>,
+    ) -> RutabagaResult<Box<dyn RutabagaComponent>> {
+        if render_server_fd.is_none() {
+            return Err(MesaError::WithContext("the software driver needs a render server").into());
+        }
+
+        let virglrenderer_flags = virglrenderer_flags
+            .use_virgl(false)
+            .use_drm(false)
+            .use_egl(false)
+            .use_venus(true)
+            .use_render_server(true)
+            .use_external_blob(true);
+
+        VirglRenderer::init_renderer(
+            virglrenderer_flags,
+            fence_handler,
+            render_server_fd,
+            rutabaga_paths,
+            true,
+        )
+    }
+
+    fn init_renderer(
+        virglrenderer_flags: VirglRendererFlags,
+        fence_handler: RutabagaFenceHandler,
+        render_server_fd: Option<OwnedDescriptor>,
+        rutabaga_paths: Option<RutabagaPaths>,
+        software: bool,
     ) -> RutabagaResult<Box<dyn RutabagaComponent>> {
         if cfg!(debug_assertions) {
             // TODO(b/315870313): Add safety comment
@@ -474,7 +864,34 @@ impl VirglRenderer {
         };
 
         ret_to_res(ret)?;
-        Ok(Box::new(VirglRenderer {}))
+
+        // The EGL fallback only covers GL work, so it's left out when venus or native contexts
+        // may create fences too.  Async fence callbacks retire fences on virglrenderer's sync
+        // thread, racing the native fences inserted after each fence on this one, so it's left
+        // out with them as well.  Thread sync alone still retires fences on this thread.
+        #[cfg(not(virgl_renderer_unstable))]
+        let egl_fences = {
+            let flags = u32::from(virglrenderer_flags);
+            let non_gl = VIRGL_RENDERER_VENUS | VIRGL_RENDERER_DRM | VIRGL_RENDERER_NO_VIRGL;
+            let async_fences = VIRGL_RENDERER_THREAD_SYNC | VIRGL_RENDERER_ASYNC_FENCE_CB;
+            if flags & VIRGL_RENDERER_USE_EGL != 0
+                && flags & non_gl == 0
+                && flags & async_fences != async_fences
+            {
+                EglFenceExporter::load()
+                    .map_err(|e| info!("virgl fences can't be exported: {e}"))
+                    .ok()
+            } else {
+                None
+            }
+        };
+
+        Ok(Box::new(VirglRenderer {
+            software,
+            trace: Default::default(),
+            #[cfg(not(virgl_renderer_unstable))]
+            egl_fences,
+        }))
     }
 
     fn map_info(&self, resource_id: u32) -> RutabagaResult<u32> {
@@ -552,6 +969,45 @@ impl Drop for VirglRenderer {
 }
 
 impl RutabagaComponent for VirglRenderer {
+    // The log callback is global, like the rest of virglrenderer's state.
+    fn set_log_sink(&mut self, sink: RutabagaLogSink) {
+        let logger = Box::new(RutabagaLogger::new(
+            RutabagaComponentType::VirglRenderer,
+            Some(sink),
+        ));
+
+        // SAFETY:
+        // Safe because `log_callback` only reads the logger, which virglrenderer owns until it
+        // calls `free_logger`.
+        unsafe {
+            virgl_set_log_callback(
+                Some(log_callback),
+                Box::into_raw(logger) as *mut c_void,
+                Some(free_logger),
+            );
+        }
+    }
+
+    fn set_trace(&mut self, trace: RutabagaTrace) {
+        self.trace = trace;
+    }
+
+    // Exported blobs are of every fd type virglrenderer returns, and only dma-bufs are imported.
+    fn handle_types(&self) -> RutabagaHandleTypes {
+        RutabagaHandleTypes {
+            export: handle_type_mask(&[
+                MESA_HANDLE_TYPE_MEM_DMABUF,
+                MESA_HANDLE_TYPE_MEM_OPAQUE_FD,
+                MESA_HANDLE_TYPE_MEM_SHM,
+            ]),
+            import: if cfg!(target_os = "linux") {
+                handle_type_mask(&[MESA_HANDLE_TYPE_MEM_DMABUF])
+            } else {
+                0
+            },
+        }
+    }
+
     fn get_capset_info(&self, capset_id: u32) -> (u32, u32) {
         let mut version = 0;
         let mut size = 0;
@@ -588,7 +1044,16 @@ impl RutabagaComponent for VirglRenderer {
         // TODO(b/315870313): Add safety comment
         #[allow(clippy::undocumented_unsafe_blocks)]
         let ret = unsafe { virgl_renderer_create_fence(fence.fence_id as i32, fence.ctx_id) };
-        ret_to_res(ret)
+        ret_to_res(ret)?;
+
+        #[cfg(not(virgl_renderer_unstable))]
+        if let Some(egl_fences) = self.egl_fences.as_mut() {
+            if let Err(e) = egl_fences.create(fence.fence_id) {
+                warn!("virgl fence {} can't be exported: {e}", fence.fence_id);
+            }
+        }
+
+        Ok(())
     }
 
     fn event_poll(&self) {
@@ -736,6 +1201,47 @@ impl RutabagaComponent for VirglRenderer {
         }
     }
 
+    fn import_shared(&self, resource: &RutabagaResource) -> RutabagaResult<()> {
+        let mesa_handle = resource
+            .handle
+            .as_ref()
+            .and_then(|h| h.as_mesa_handle())
+            .ok_or(MesaError::InvalidMesaHandle)?;
+
+        #[cfg(target_os = "linux")]
+        if mesa_handle.handle_type == MESA_HANDLE_TYPE_MEM_DMABUF {
+            let dmabuf_fd = mesa_handle
+                .os_handle
+                .try_clone()
+                .map_err(MesaError::IoError)?
+                .into_raw_descriptor();
+
+            // SAFETY:
+            // Safe because we are being passed a valid fd
+            let ret = unsafe {
+                let dmabuf_size = libc::lseek64(dmabuf_fd, 0, libc::SEEK_END);
+                libc::lseek64(dmabuf_fd, 0, libc::SEEK_SET);
+                let args = virgl_renderer_resource_import_blob_args {
+                    res_handle: resource.resource_id,
+                    blob_mem: resource.blob_mem,
+                    fd_type: VIRGL_RENDERER_BLOB_FD_TYPE_DMABUF,
+                    fd: dmabuf_fd,
+                    size: dmabuf_size as u64,
+                };
+                let ret = virgl_renderer_resource_import_blob(&args);
+                if ret != 0 {
+                    // virglrenderer only takes ownership of the fd on success.
+                    libc::close(dmabuf_fd);
+                }
+                ret
+            };
+
+            return ret_to_res(ret);
+        }
+
+        Err(MesaError::Unsupported.into())
+    }
+
     fn transfer_write(
         &self,
         ctx_id: u32,
@@ -747,10 +1253,6 @@ impl RutabagaComponent for VirglRenderer {
             return Ok(());
         }
 
-        if buf.is_some() {
-            return Err(MesaError::Unsupported.into());
-        }
-
         let mut transfer_box = VirglBox {
             x: transfer.x,
             y: transfer.y,
@@ -760,8 +1262,24 @@ impl RutabagaComponent for VirglRenderer {
             d: transfer.d,
         };
 
+        let mut iov = RutabagaIovec {
+            base: null_mut(),
+            len: 0,
+        };
+
+        // Without `buf`, virglrenderer copies from the attached iovecs.
+        let (iovecs, num_iovecs) = match buf {
+            Some(buf) => {
+                iov.base = buf.as_ptr() as *mut c_void;
+                iov.len = buf.len();
+                (&mut iov as *mut RutabagaIovec as *mut iovec, 1)
+            }
+            None => (null_mut(), 0),
+        };
+
         // SAFETY:
-        // Safe because only stack variables of the appropriate type are used.
+        // Safe because only stack variables of the appropriate type are used, and virglrenderer
+        // only reads from `buf`.
         let ret = unsafe {
             virgl_renderer_transfer_write_iov(
                 resource.resource_id,
@@ -771,8 +1289,8 @@ impl RutabagaComponent for VirglRenderer {
                 transfer.layer_stride,
                 &mut transfer_box as *mut VirglBox as *mut virgl_box,
                 transfer.offset,
-                null_mut(),
-                0,
+                iovecs,
+                num_iovecs,
             )
         };
         ret_to_res(ret)
@@ -874,7 +1392,7 @@ impl RutabagaComponent for VirglRenderer {
             info_2d: None,
             info_3d: self.query(resource_id).ok(),
             vulkan_info: None,
-            backing_iovecs: iovec_opt,
+            backing_iovecs: iovec_opt.map(RutabagaBacking::new),
             component_mask: 1 << (RutabagaComponentType::VirglRenderer as u8),
             size: resource_create_blob.size,
             mapping: None,
@@ -904,6 +1422,19 @@ impl RutabagaComponent for VirglRenderer {
         Err(MesaError::Unsupported.into())
     }
 
+    // virglrenderer copies between the iovecs given at blob creation and the host resource.
+    fn syncs_hybrid_blobs(&self) -> bool {
+        true
+    }
+
+    fn mapped_handle_types(&self) -> u32 {
+        // CPU Vulkan drivers back the opaque fds they export with shared memory.
+        match self.software {
+            true => handle_type_mask(&[MESA_HANDLE_TYPE_MEM_SHM, MESA_HANDLE_TYPE_MEM_OPAQUE_FD]),
+            false => 0,
+        }
+    }
+
     fn map(&self, resource_id: u32) -> RutabagaResult<MesaMapping> {
         let mut map: *mut c_void = null_mut();
         let mut size: u64 = 0;
@@ -947,17 +1478,28 @@ impl RutabagaComponent for VirglRenderer {
             })
         }
         #[cfg(not(virgl_renderer_unstable))]
-        Err(MesaError::Unsupported.into())
+        {
+            let egl_fences = self.egl_fences.as_ref().ok_or(MesaError::Unsupported)?;
+            Ok(MesaHandle {
+                os_handle: egl_fences.export(fence_id)?,
+                handle_type: MESA_HANDLE_TYPE_SIGNAL_SYNC_FD,
+            })
+        }
     }
 
-    #[allow(unused_variables)]
+    // virglrenderer has no context flag for priorities, nor for compute-only contexts.  Native
+    // contexts pick the priority of their submit queues themselves.
     fn create_context(
         &self,
-        ctx_id: u32,
-        context_init: u32,
-        context_name: Option<&str>,
+        params: RutabagaContextParams,
         _fence_handler: RutabagaFenceHandler,
     ) -> RutabagaResult<Box<dyn RutabagaContext>> {
+        let RutabagaContextParams {
+            ctx_id,
+            context_init,
+            context_name,
+            ..
+        } = params;
         let mut name: &str = "gpu_renderer";
         if let Some(name_string) = context_name.filter(|s| !s.is_empty()) {
             name = name_string;
@@ -982,6 +1524,61 @@ impl RutabagaComponent for VirglRenderer {
             }
         };
         ret_to_res(ret)?;
-        Ok(Box::new(VirglRendererContext { ctx_id }))
+
+        // A zero `context_init` creates a virgl context.
+        let explicit_transfers = matches!(
+            context_init & RUTABAGA_CONTEXT_INIT_CAPSET_ID_MASK,
+            0 | RUTABAGA_CAPSET_VIRGL | RUTABAGA_CAPSET_VIRGL2
+        );
+        Ok(Box::new(VirglRendererContext {
+            ctx_id,
+            explicit_transfers,
+            trace: self.trace.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A virgl command of `cmd` with `payload`, padded to whole dwords.
+    fn virgl_command(cmd: u32, payload: &[u8]) -> Vec<u8> {
+        let dwords = payload.len().div_ceil(size_of::<u32>());
+        let mut command = (cmd | (dwords as u32) << 16).to_le_bytes().to_vec();
+        command.extend_from_slice(payload);
+        command.resize(size_of::<u32>() * (dwords + 1), 0);
+        command
+    }
+
+    fn string_marker(string: &[u8]) -> Vec<u8> {
+        let mut payload = (string.len() as u32).to_le_bytes().to_vec();
+        payload.extend_from_slice(string);
+        virgl_command(VIRGL_CCMD_SEND_STRING_MARKER, &payload)
+    }
+
+    #[test]
+    fn string_markers() {
+        let mut commands = virgl_command(8, &[0; 12]);
+        commands.extend(string_marker(b"frame 1"));
+        commands.extend(virgl_command(0, &[]));
+        commands.extend(string_marker(b"draw"));
+        assert_eq!(
+            virgl_string_markers(&commands),
+            [b"frame 1".as_slice(), b"draw"]
+        );
+    }
+
+    #[test]
+    fn string_markers_bounds() {
+        // A string size past the end of the command.
+        let mut commands = virgl_command(VIRGL_CCMD_SEND_STRING_MARKER, &64u32.to_le_bytes());
+        commands.extend(string_marker(b"draw"));
+        assert_eq!(virgl_string_markers(&commands), [b"draw".as_slice()]);
+
+        // A command past the end of the stream stops the search.
+        let mut commands = string_marker(b"frame");
+        commands.truncate(commands.len() - size_of::<u32>());
+        assert!(virgl_string_markers(&commands).is_empty());
     }
 }
@@ -4,6 +4,7 @@
 mod magma;
 mod magma_defines;
 mod magma_kumquat;
+mod magma_log;
 mod sys;
 mod traits;
 
@@ -11,6 +12,25 @@ pub use magma_defines::*;
 
 pub use magma::magma_enumerate_devices;
 pub use magma::MagmaBuffer;
+pub use magma::MagmaBufferPool;
+pub use magma::MagmaBufferPoolConfig;
+pub use magma::MagmaBufferPoolStats;
 pub use magma::MagmaContext;
+pub use magma::MagmaCrossDeviceBuffer;
 pub use magma::MagmaDevice;
+pub use magma::MagmaHeapCallback;
+pub use magma::MagmaHeapSample;
+pub use magma::MagmaHeapSampler;
 pub use magma::MagmaPhysicalDevice;
+pub use magma::MagmaResidencyConfig;
+pub use magma::MagmaResidencyManager;
+pub use magma::MagmaResidencyStats;
+pub use magma::MagmaSemaphore;
+pub use magma::MagmaShareRegistry;
+pub use magma::MagmaSubAllocation;
+pub use magma::MagmaSuspendSnapshot;
+pub use magma::MagmaUserFence;
+
+pub use magma_log::magma_log;
+pub use magma_log::magma_set_log_handler;
+pub use magma_log::MagmaLogHandler;
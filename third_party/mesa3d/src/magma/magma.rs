@@ -5,13 +5,37 @@
 //!
 //! Design found at <https://fuchsia.dev/fuchsia-third_party/mesa3d/src/development/graphics/magma/concepts/design>.
 
+use std::collections::BTreeMap as Map;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::channel;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::Sender;
 use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::Instant;
 
+use mesa3d_util::AsBorrowedDescriptor;
+use mesa3d_util::Event;
 use mesa3d_util::MappedRegion;
+use mesa3d_util::MesaError;
 use mesa3d_util::MesaHandle;
+use mesa3d_util::MesaResult;
 use mesa3d_util::OwnedDescriptor;
+use mesa3d_util::WaitContext;
+use mesa3d_util::WaitTimeout;
+use mesa3d_util::MESA_HANDLE_TYPE_MEM_DMABUF;
+use mesa3d_util::MESA_HANDLE_TYPE_MEM_OPAQUE_WIN32;
 
+use crate::magma_defines::MagmaContextPriority;
 use crate::magma_defines::MagmaCreateBufferInfo;
+use crate::magma_defines::MagmaEngineClass;
+use crate::magma_defines::MagmaEngineInfo;
 use crate::magma_defines::MagmaError;
 use crate::magma_defines::MagmaHeapBudget;
 use crate::magma_defines::MagmaImportHandleInfo;
@@ -19,17 +43,34 @@ use crate::magma_defines::MagmaMappedMemoryRange;
 use crate::magma_defines::MagmaMemoryProperties;
 use crate::magma_defines::MagmaPciBusInfo;
 use crate::magma_defines::MagmaPciInfo;
+use crate::magma_defines::MagmaQueryResult;
 use crate::magma_defines::MagmaResult;
+use crate::magma_defines::MAGMA_BUFFER_FLAG_EXTERNAL;
+use crate::magma_defines::MAGMA_BUFFER_FLAG_SPARSE;
+use crate::magma_defines::MAGMA_DEVICE_FLAGS;
+use crate::magma_defines::MAGMA_DEVICE_FLAG_USER_GPU_VA;
+use crate::magma_defines::MAGMA_MAP_GPU_FLAGS;
+use crate::magma_defines::MAGMA_MEMORY_PROPERTY_HOST_COHERENT_BIT;
+use crate::magma_defines::MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT;
+use crate::magma_defines::MAGMA_SYNC_RANGES;
+use crate::magma_defines::MAGMA_SYNC_WHOLE_RANGE;
+use crate::magma_defines::MAGMA_USER_FENCE_SIZE;
+use crate::magma_error;
+use crate::magma_warn;
 
 use crate::traits::Buffer;
 use crate::traits::Context;
 use crate::traits::Device;
+use crate::traits::GenericBuffer;
 use crate::traits::PhysicalDevice;
 
 use crate::magma_kumquat::enumerate_devices as magma_kumquat_enumerate_devices;
 use crate::sys::platform::enumerate_devices as platform_enumerate_devices;
 
 const VIRTGPU_KUMQUAT_ENABLED: &str = "VIRTGPU_KUMQUAT";
+const USER_FENCE_POLL_INTERVAL: Duration = Duration::from_micros(100);
+// Blocks are at least 64KiB aligned, so drivers can map them with large pages.
+const MAGMA_POOL_BLOCK_ALIGNMENT: u32 = 0x10000;
 
 #[repr(C)]
 #[derive(Clone)]
@@ -42,16 +83,300 @@ pub struct MagmaPhysicalDevice {
 #[derive(Clone)]
 pub struct MagmaDevice {
     device: Arc<dyn Device>,
+    user_gpu_va: bool,
 }
 
 #[derive(Clone)]
 pub struct MagmaContext {
-    _context: Arc<dyn Context>,
+    device: Arc<dyn Device>,
+    context: Arc<dyn Context>,
+    user_gpu_va: bool,
 }
 
 #[derive(Clone)]
 pub struct MagmaBuffer {
     buffer: Arc<dyn Buffer>,
+    memory_type_idx: u32,
+    // GPU virtual addresses the buffer is mapped at must be a multiple of this, if non-zero.
+    alignment: u64,
+}
+
+// A reservation of GPU virtual address space, which the context backs with no pages when mapping
+// it.
+struct MagmaSparseBuffer {
+    size: u64,
+}
+
+/// Contents of critical buffers saved to system memory by `MagmaDevice::prepare_suspend`.
+pub struct MagmaSuspendSnapshot {
+    saved: Vec<(MagmaBuffer, Vec<u8>)>,
+}
+
+/// A buffer made resident on one device from a buffer allocated on another, e.g. to present
+/// frames rendered on a discrete GPU with an integrated GPU.  If the devices can share the memory,
+/// both buffers alias it.  Otherwise, contents are copied by the CPU on `sync`.
+pub struct MagmaCrossDeviceBuffer {
+    src: MagmaBuffer,
+    dst: MagmaBuffer,
+    shared: bool,
+}
+
+/// Shares buffers between the MagmaDevices of a process, in place of GEM flink names.  A buffer
+/// is exported once, and any device may import it with the returned token.  Tokens are random
+/// and only meaningful to the registry that issued them, or its clones.
+#[derive(Clone, Default)]
+pub struct MagmaShareRegistry {
+    shared: Arc<Mutex<Map<u64, (MesaHandle, u64)>>>,
+}
+
+/// A completion seqno living in a small shared page.  `MagmaContext::submit_signaled()` writes the
+/// seqno of each submission once it completes, which lets waiters check completion without a
+/// syscall.  The page may be exported and mapped into a guest as a blob.
+#[derive(Clone)]
+pub struct MagmaUserFence {
+    buffer: MagmaBuffer,
+    seqno: Arc<MagmaUserFenceSeqno>,
+    // (sync file, seqno) of every submission whose completion writes the seqno.
+    completions: Sender<(OwnedDescriptor, u64)>,
+}
+
+// The seqno page of a user fence.  Writes from this process wake waiters through `written`, while
+// writes from elsewhere, such as a guest, are polled for.
+struct MagmaUserFenceSeqno {
+    mapping: Arc<dyn MappedRegion>,
+    lock: Mutex<()>,
+    written: Condvar,
+}
+
+/// Tunables of `MagmaResidencyManager`.  Scores are exponentially decaying averages of the
+/// accesses in each sampling period, scaled by 16.
+#[derive(Clone, Debug)]
+pub struct MagmaResidencyConfig {
+    /// A host memory buffer whose GPU score reaches this is promoted to device-local memory.
+    pub promote_threshold: u32,
+    /// A device-local buffer whose GPU score falls below this is demoted to host memory.  Must be
+    /// lower than `promote_threshold`, so buffers don't bounce between heaps.
+    pub demote_threshold: u32,
+    /// The number of sampling periods a buffer stays in a heap before it can migrate again.
+    pub min_dwell_periods: u32,
+}
+
+impl Default for MagmaResidencyConfig {
+    fn default() -> MagmaResidencyConfig {
+        MagmaResidencyConfig {
+            promote_threshold: 64,
+            demote_threshold: 8,
+            min_dwell_periods: 4,
+        }
+    }
+}
+
+/// Counters of a `MagmaResidencyManager`.
+#[derive(Clone, Debug, Default)]
+pub struct MagmaResidencyStats {
+    /// Buffers moved to device-local memory.
+    pub promotions: u64,
+    /// Buffers moved to host memory.
+    pub demotions: u64,
+    pub bytes_migrated: u64,
+    /// Migrations abandoned, e.g. because the target heap was over budget.
+    pub failed_migrations: u64,
+}
+
+/// The budget and usage of one heap, as reported to `MagmaHeapSampler` callbacks.
+#[derive(Clone, Debug)]
+pub struct MagmaHeapSample {
+    pub heap_idx: u32,
+    pub budget: u64,
+    pub usage: u64,
+}
+
+/// Receives the samples of every heap, all taken in the same sampling period.  Callbacks run
+/// without any sampler lock held, so they may register or unregister callbacks themselves.
+pub type MagmaHeapCallback = Arc<dyn Fn(&[MagmaHeapSample]) + Send + Sync>;
+
+/// Periodically samples the budget and usage of every heap of a device on a background thread,
+/// and hands them to registered callbacks, e.g. to emit Perfetto counters that show memory
+/// pressure while guest workloads run.  The thread stops when the sampler is dropped.
+pub struct MagmaHeapSampler {
+    callbacks: Arc<Mutex<Map<u64, MagmaHeapCallback>>>,
+    next_id: AtomicU64,
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct MagmaResidentBuffer {
+    buffer: MagmaBuffer,
+    // Accesses in the current sampling period.
+    maps: u32,
+    submissions: u32,
+    cpu_score: u32,
+    gpu_score: u32,
+    periods_since_migration: u32,
+    // (context, gpu_va, flags) of every GPU binding, which is moved along with the buffer.
+    gpu_mappings: Vec<(MagmaContext, u64, u64)>,
+}
+
+#[derive(Default)]
+struct MagmaResidencyState {
+    buffers: Map<u64, MagmaResidentBuffer>,
+    next_id: u64,
+    stats: MagmaResidencyStats,
+}
+
+/// Migrates buffers between device-local and host memory based on how they are accessed, for
+/// systems with a small BAR or under memory pressure.  Buffers the GPU uses heavily move to
+/// CPU-visible VRAM, while buffers that are mostly mapped by the CPU or unused move to system
+/// memory.  Only host visible memory types are considered, since contents are copied by the CPU.
+///
+/// Managed buffers are referred to by id, since their backing buffer changes on migration.
+/// Buffers shared with other devices or processes must not be managed.
+#[derive(Clone)]
+pub struct MagmaResidencyManager {
+    device: MagmaDevice,
+    config: MagmaResidencyConfig,
+    state: Arc<Mutex<MagmaResidencyState>>,
+}
+
+/// Tunables of `MagmaBufferPool`.
+#[derive(Clone, Debug)]
+pub struct MagmaBufferPoolConfig {
+    /// The size of the driver buffers, or blocks, that allocations are carved from.  Larger
+    /// allocations get a block of their own.
+    pub block_size: u64,
+}
+
+impl Default for MagmaBufferPoolConfig {
+    fn default() -> MagmaBufferPoolConfig {
+        MagmaBufferPoolConfig {
+            block_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// Counters of a `MagmaBufferPool`, summed over all memory types.
+#[repr(C)]
+#[derive(Clone, Debug, Default)]
+pub struct MagmaBufferPoolStats {
+    pub block_count: u64,
+    pub block_bytes: u64,
+    pub allocation_count: u64,
+    pub allocated_bytes: u64,
+    /// Free ranges between allocations.  Many ranges for few free bytes mean the pool is
+    /// fragmented.
+    pub free_range_count: u64,
+    /// The largest allocation that fits without a new block, ignoring alignment.
+    pub largest_free_range: u64,
+}
+
+struct MagmaPoolBlock {
+    buffer: MagmaBuffer,
+    size: u64,
+    // Only allocations aligned to at most this share the block, since the block is only mapped
+    // at GPU virtual addresses aligned to it.
+    alignment: u64,
+    // Offset to size of the free ranges, which are merged so that no two are adjacent.
+    free: Map<u64, u64>,
+    allocation_count: u64,
+    allocated_bytes: u64,
+}
+
+#[derive(Default)]
+struct MagmaBufferPoolState {
+    // Blocks by memory type, then by id.
+    blocks: Map<u32, Map<u64, MagmaPoolBlock>>,
+    next_block_id: u64,
+}
+
+/// Sub-allocates small buffers from larger driver buffers, so that small Vulkan allocations don't
+/// cost a GEM object (or WDDM allocation) each.  Each memory type has its own pool of blocks, and
+/// ranges are placed first-fit, with freed ranges merged back with their free neighbours.
+///
+/// Sub-allocations can't be shared or made sparse, since they share their driver buffer.  Cheap to
+/// clone, since all clones share the same state.
+#[derive(Clone)]
+pub struct MagmaBufferPool {
+    device: MagmaDevice,
+    config: MagmaBufferPoolConfig,
+    state: Arc<Mutex<MagmaBufferPoolState>>,
+}
+
+/// A range of a block of a `MagmaBufferPool`, which is returned to the pool when dropped.
+/// Mappings of `buffer()` cover the whole block, so CPU pointers and GPU virtual addresses of the
+/// range are offset by `offset()`.
+pub struct MagmaSubAllocation {
+    buffer: MagmaBuffer,
+    offset: u64,
+    size: u64,
+    memory_type_idx: u32,
+    block_id: u64,
+    state: Arc<Mutex<MagmaBufferPoolState>>,
+}
+
+// Cache maintenance is a no-op on drivers without it.
+fn ignore_unsupported(result: MagmaResult<()>) -> MagmaResult<()> {
+    match result {
+        Err(MagmaError::MesaError(MesaError::Unsupported)) => Ok(()),
+        result => result,
+    }
+}
+
+// Buffers are only shared as dma-bufs or NT handles, which are capabilities held by the process.
+// Legacy GEM flink names are global to the DRM device and can be guessed by any of its clients, so
+// they are rejected, as is any other handle type that could carry one.
+fn check_shareable_handle_type(handle_type: u32) -> MagmaResult<()> {
+    match handle_type {
+        MESA_HANDLE_TYPE_MEM_DMABUF | MESA_HANDLE_TYPE_MEM_OPAQUE_WIN32 => Ok(()),
+        _ => Err(MagmaError::AccessDenied),
+    }
+}
+
+// Exactly one of MAGMA_SYNC_WHOLE_RANGE and MAGMA_SYNC_RANGES must be set, and every range must lie
+// within the buffer, so drivers may rely on both.
+fn validate_sync_ranges(
+    size: u64,
+    sync_flags: u64,
+    ranges: &[MagmaMappedMemoryRange],
+) -> MagmaResult<()> {
+    let whole_range = sync_flags & MAGMA_SYNC_WHOLE_RANGE != 0;
+    let some_ranges = sync_flags & MAGMA_SYNC_RANGES != 0;
+    if whole_range == some_ranges {
+        return Err(MagmaError::InvalidArgs);
+    }
+
+    let in_bounds = |range: &MagmaMappedMemoryRange| {
+        range
+            .offset
+            .checked_add(range.size)
+            .is_some_and(|end| end <= size)
+    };
+
+    if some_ranges && !ranges.iter().all(in_bounds) {
+        return Err(MagmaError::InvalidArgs);
+    }
+
+    Ok(())
+}
+
+// Returns the first host visible memory type in a device-local heap if `device_local`, or in a
+// host heap otherwise.
+// Restores the binding of `buffer` at `gpu_va` after a failed migration.
+fn rebind(context: &MagmaContext, buffer: &MagmaBuffer, gpu_va: u64, flags: u64) {
+    if let Err(e) = context.map_gpu(buffer, gpu_va, flags) {
+        magma_error!("failed to rebind {:#x} after a migration: {}", gpu_va, e);
+    }
+}
+
+fn migration_target(mem_props: &MagmaMemoryProperties, device_local: bool) -> Option<u32> {
+    (0..mem_props.memory_type_count).find(|idx| {
+        let memory_type = mem_props.get_memory_type(*idx);
+        memory_type.property_flags & MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT != 0
+            && !memory_type.is_protected()
+            && mem_props
+                .get_memory_heap(memory_type.heap_idx)
+                .is_device_local()
+                == device_local
+    })
 }
 
 pub fn magma_enumerate_devices() -> MagmaResult<Vec<MagmaPhysicalDevice>> {
@@ -77,16 +402,39 @@ impl MagmaPhysicalDevice {
     }
 
     pub fn create_device(&self) -> MagmaResult<MagmaDevice> {
-        let device = self
-            .physical_device
-            .create_device(&self.physical_device, &self.pci_info)?;
-        Ok(MagmaDevice { device })
+        self.create_device_with_flags(0)
+    }
+
+    /// Creates a device with the MAGMA_DEVICE_FLAG_* bits in `flags`.
+    pub fn create_device_with_flags(&self, flags: u32) -> MagmaResult<MagmaDevice> {
+        if flags & !MAGMA_DEVICE_FLAGS != 0 {
+            return Err(MagmaError::InvalidArgs);
+        }
+
+        let device =
+            self.physical_device
+                .create_device(&self.physical_device, &self.pci_info, flags)?;
+        Ok(MagmaDevice {
+            device,
+            user_gpu_va: flags & MAGMA_DEVICE_FLAG_USER_GPU_VA != 0,
+        })
+    }
+
+    pub fn pci_info(&self) -> &MagmaPciInfo {
+        &self.pci_info
+    }
+
+    pub fn pci_bus_info(&self) -> &MagmaPciBusInfo {
+        &self.pci_bus_info
     }
 }
 
-#[allow(dead_code)]
+/// A binary semaphore shared between user-mode drivers, and possibly processes.  A successful
+/// wait resets it.
 pub struct MagmaSemaphore {
-    semaphore: OwnedDescriptor,
+    event: Event,
+    // Signaling needs exclusive access to an event, but mustn't wait for waiters.
+    signaler: Mutex<Event>,
 }
 
 #[allow(dead_code)]
@@ -130,20 +478,201 @@ impl MagmaDevice {
         Ok(budget)
     }
 
-    pub fn create_context(&self) -> MagmaResult<MagmaContext> {
-        let context = self.device.create_context(&self.device)?;
-        Ok(MagmaContext { _context: context })
+    /// Returns the engines of the device, by class.
+    pub fn enumerate_engines(&self) -> MagmaResult<Vec<MagmaEngineInfo>> {
+        let engines = self.device.enumerate_engines()?;
+        Ok(engines)
+    }
+
+    /// Creates a context that submits to engines of `engine_class`.  Fails with `InvalidArgs` if
+    /// the device has no such engine, so compute-only users can fall back to the render engine.
+    pub fn create_context(
+        &self,
+        priority: MagmaContextPriority,
+        engine_class: MagmaEngineClass,
+    ) -> MagmaResult<MagmaContext> {
+        let engines = self.device.enumerate_engines()?;
+        if !engines.iter().any(|e| e.engine_class == engine_class) {
+            return Err(MagmaError::InvalidArgs);
+        }
+
+        let context = self
+            .device
+            .create_context(&self.device, priority, engine_class)?;
+        Ok(MagmaContext {
+            device: self.device.clone(),
+            context,
+            user_gpu_va: self.user_gpu_va,
+        })
     }
 
+    /// Creates a buffer.  A non-zero `alignment`, which must be a power of two, applies to both
+    /// the allocation and the GPU virtual addresses it is mapped at.  With
+    /// MAGMA_BUFFER_FLAG_SPARSE, only GPU virtual address space is reserved, which fails with
+    /// `Unsupported` on drivers without sparse bindings.
     pub fn create_buffer(&self, create_info: &MagmaCreateBufferInfo) -> MagmaResult<MagmaBuffer> {
-        let buffer = self.device.create_buffer(&self.device, create_info)?;
-        Ok(MagmaBuffer { buffer })
+        let alignment = create_info.alignment as u64;
+        if alignment != 0 && !alignment.is_power_of_two() {
+            return Err(MagmaError::InvalidArgs);
+        }
+
+        let buffer: Arc<dyn Buffer> = if create_info.common_flags & MAGMA_BUFFER_FLAG_SPARSE != 0 {
+            if !self.device.supports_sparse_buffers() {
+                return Err(MesaError::Unsupported.into());
+            }
+
+            let size = create_info
+                .size
+                .checked_next_multiple_of(alignment.max(1))
+                .ok_or(MagmaError::InvalidArgs)?;
+            Arc::new(MagmaSparseBuffer { size })
+        } else {
+            self.device.create_buffer(&self.device, create_info)?
+        };
+
+        Ok(MagmaBuffer {
+            buffer,
+            memory_type_idx: create_info.memory_type_idx,
+            alignment,
+        })
     }
 
     // FIXME: we probably want to import with a memory type
     pub fn import(&self, info: MagmaImportHandleInfo) -> MagmaResult<MagmaBuffer> {
+        check_shareable_handle_type(info.handle.handle_type)?;
+        let memory_type_idx = info.memory_type_idx;
         let buffer = self.device.import(&self.device, info)?;
-        Ok(MagmaBuffer { buffer })
+        Ok(MagmaBuffer {
+            buffer,
+            memory_type_idx,
+            alignment: 0,
+        })
+    }
+
+    /// Makes a vendor-specific query, so user-mode drivers can discover chip details without
+    /// issuing ioctls themselves.  `id` is the kernel driver's own query id on Linux, and a
+    /// KMTQUERYADAPTERINFOTYPE on Windows, where `input` is passed as the private driver data.
+    pub fn query(&self, id: u64, input: &[u8]) -> MagmaResult<MagmaQueryResult> {
+        let result = self.device.query(id, input)?;
+        Ok(result)
+    }
+
+    pub fn create_semaphore(&self) -> MagmaResult<MagmaSemaphore> {
+        MagmaSemaphore::new(Event::new()?)
+    }
+
+    /// Imports a semaphore exported by `MagmaSemaphore::export`, possibly in another process.
+    pub fn import_semaphore(&self, handle: MesaHandle) -> MagmaResult<MagmaSemaphore> {
+        MagmaSemaphore::new(handle.try_into()?)
+    }
+
+    /// Returns true if the contents of buffers allocated from `heap_idx` survive the device being
+    /// suspended.
+    pub fn heap_preserved_on_suspend(&self, heap_idx: u32) -> MagmaResult<bool> {
+        let mem_props = self.device.get_memory_properties()?;
+        if heap_idx >= mem_props.memory_heap_count {
+            return Err(MagmaError::InvalidArgs);
+        }
+
+        Ok(mem_props
+            .get_memory_heap(heap_idx)
+            .is_preserved_on_suspend())
+    }
+
+    /// Pre-suspend hook.  Saves the contents of every buffer in `critical_buffers` whose heap
+    /// isn't preserved on suspend to system memory.  Such buffers must be host visible, and the
+    /// GPU must be idle.  Rutabaga's magma component calls it for its mappable blobs from
+    /// `Rutabaga::suspend()`, and restores the snapshot from `Rutabaga::resume()`.
+    pub fn prepare_suspend(
+        &self,
+        critical_buffers: &[MagmaBuffer],
+    ) -> MagmaResult<MagmaSuspendSnapshot> {
+        let mem_props = self.device.get_memory_properties()?;
+        let mut saved = Vec::new();
+        for buffer in critical_buffers {
+            if buffer.memory_type_idx >= mem_props.memory_type_count {
+                return Err(MagmaError::InvalidArgs);
+            }
+
+            let memory_type = mem_props.get_memory_type(buffer.memory_type_idx);
+            if mem_props
+                .get_memory_heap(memory_type.heap_idx)
+                .is_preserved_on_suspend()
+            {
+                continue;
+            }
+
+            if memory_type.property_flags & MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT == 0 {
+                return Err(MagmaError::InvalidArgs);
+            }
+
+            let mapping = buffer.map()?;
+            if !memory_type.is_coherent() {
+                ignore_unsupported(buffer.invalidate(MAGMA_SYNC_WHOLE_RANGE, &[]))?;
+            }
+
+            // SAFETY:
+            // The mapping is valid for `size()` bytes and lives until the end of the iteration.
+            let contents = unsafe { std::slice::from_raw_parts(mapping.as_ptr(), mapping.size()) };
+            saved.push((buffer.clone(), contents.to_vec()));
+        }
+
+        Ok(MagmaSuspendSnapshot { saved })
+    }
+
+    /// Makes `src`, which was allocated on another device, resident on this device with memory
+    /// type `memory_type_idx`.  The exported handle of `src` is imported directly when possible.
+    /// Otherwise, a new buffer is allocated and the contents of `src` are copied into it, which
+    /// requires both memory types to be host visible.
+    pub fn import_cross_device(
+        &self,
+        src: &MagmaBuffer,
+        memory_type_idx: u32,
+    ) -> MagmaResult<MagmaCrossDeviceBuffer> {
+        let mem_props = self.device.get_memory_properties()?;
+        if memory_type_idx >= mem_props.memory_type_count {
+            return Err(MagmaError::InvalidArgs);
+        }
+
+        let size = src.size();
+        let imported = src.export().and_then(|handle| {
+            self.import(MagmaImportHandleInfo {
+                handle,
+                size,
+                memory_type_idx,
+            })
+        });
+
+        let cross_device = match imported {
+            Ok(dst) => MagmaCrossDeviceBuffer {
+                src: src.clone(),
+                dst,
+                shared: true,
+            },
+            Err(_) => {
+                let memory_type = mem_props.get_memory_type(memory_type_idx);
+                if memory_type.property_flags & MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT == 0 {
+                    return Err(MagmaError::InvalidArgs);
+                }
+
+                let create_info = MagmaCreateBufferInfo {
+                    memory_type_idx,
+                    alignment: 0,
+                    common_flags: MAGMA_BUFFER_FLAG_EXTERNAL,
+                    vendor_flags: 0,
+                    size,
+                };
+
+                MagmaCrossDeviceBuffer {
+                    src: src.clone(),
+                    dst: self.create_buffer(&create_info)?,
+                    shared: false,
+                }
+            }
+        };
+
+        cross_device.sync()?;
+        Ok(cross_device)
     }
 }
 
@@ -163,17 +692,169 @@ impl MagmaBuffer {
         sync_flags: u64,
         ranges: &[MagmaMappedMemoryRange],
     ) -> MagmaResult<()> {
+        validate_sync_ranges(self.size(), sync_flags, ranges)?;
         self.buffer.invalidate(sync_flags, ranges)?;
         Ok(())
     }
 
     pub fn flush(&self, sync_flags: u64, ranges: &[MagmaMappedMemoryRange]) -> MagmaResult<()> {
+        validate_sync_ranges(self.size(), sync_flags, ranges)?;
         self.buffer.flush(sync_flags, ranges)?;
         Ok(())
     }
+
+    pub fn size(&self) -> u64 {
+        self.buffer.size()
+    }
+
+    /// Returns true if the buffer was created with MAGMA_BUFFER_FLAG_SPARSE.
+    pub fn is_sparse(&self) -> bool {
+        self.buffer.is_sparse()
+    }
+}
+
+impl GenericBuffer for MagmaSparseBuffer {
+    fn map(&self, _buffer: &Arc<dyn Buffer>) -> MesaResult<Arc<dyn MappedRegion>> {
+        Err(MesaError::Unsupported)
+    }
+
+    fn export(&self) -> MesaResult<MesaHandle> {
+        Err(MesaError::Unsupported)
+    }
+
+    fn invalidate(&self, _sync_flags: u64, _ranges: &[MagmaMappedMemoryRange]) -> MesaResult<()> {
+        Err(MesaError::Unsupported)
+    }
+
+    fn flush(&self, _sync_flags: u64, _ranges: &[MagmaMappedMemoryRange]) -> MesaResult<()> {
+        Err(MesaError::Unsupported)
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn is_sparse(&self) -> bool {
+        true
+    }
 }
 
+impl Buffer for MagmaSparseBuffer {}
+
 impl MagmaContext {
+    /// Allocates a host visible, coherent page holding this context's completion seqno.
+    pub fn create_user_fence(&self) -> MagmaResult<MagmaUserFence> {
+        let mem_props = self.device.get_memory_properties()?;
+        let required_flags =
+            MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT | MAGMA_MEMORY_PROPERTY_HOST_COHERENT_BIT;
+
+        let memory_type_idx = mem_props.memory_types[..mem_props.memory_type_count as usize]
+            .iter()
+            .position(|mem_type| mem_type.property_flags & required_flags == required_flags)
+            .ok_or(MagmaError::MemoryError)?;
+
+        let create_info = MagmaCreateBufferInfo {
+            memory_type_idx: memory_type_idx
+                .try_into()
+                .map_err(MesaError::TryFromIntError)?,
+            alignment: MAGMA_USER_FENCE_SIZE as u32,
+            common_flags: MAGMA_BUFFER_FLAG_EXTERNAL,
+            vendor_flags: 0,
+            size: MAGMA_USER_FENCE_SIZE,
+        };
+
+        let buffer = MagmaBuffer {
+            buffer: self.device.create_buffer(&self.device, &create_info)?,
+            memory_type_idx: create_info.memory_type_idx,
+            alignment: MAGMA_USER_FENCE_SIZE,
+        };
+
+        let mapping = buffer.map()?;
+        if mapping.size() < size_of::<AtomicU64>() {
+            return Err(MagmaError::MemoryError);
+        }
+
+        let seqno = Arc::new(MagmaUserFenceSeqno {
+            mapping,
+            lock: Mutex::new(()),
+            written: Condvar::new(),
+        });
+        seqno.get().store(0, Ordering::Release);
+
+        // The thread exits once every clone of the user fence is dropped.
+        let (completions, pending) = channel();
+        let thread_seqno = seqno.clone();
+        thread::Builder::new()
+            .name("magma_user_fence".to_string())
+            .spawn(move || thread_seqno.signal_completions(pending))
+            .map_err(MesaError::IoError)?;
+
+        Ok(MagmaUserFence {
+            buffer,
+            seqno,
+            completions,
+        })
+    }
+
+    /// Binds all of `buffer` into this context's GPU virtual address space at `gpu_va`.  `flags`
+    /// is a combination of MAGMA_MAP_GPU_FLAG_* bits.  The user-mode driver owns the layout of
+    /// the address space, so `gpu_va` must not overlap an existing binding, and must be aligned
+    /// like the buffer.  Fails with `Unsupported` unless the device was created with
+    /// MAGMA_DEVICE_FLAG_USER_GPU_VA.
+    pub fn map_gpu(&self, buffer: &MagmaBuffer, gpu_va: u64, flags: u64) -> MagmaResult<()> {
+        if !self.user_gpu_va {
+            return Err(MesaError::Unsupported.into());
+        }
+
+        if flags & !MAGMA_MAP_GPU_FLAGS != 0 {
+            return Err(MagmaError::InvalidArgs);
+        }
+
+        if buffer.alignment != 0 && gpu_va & (buffer.alignment - 1) != 0 {
+            return Err(MagmaError::InvalidArgs);
+        }
+
+        self.context.map_gpu(&buffer.buffer, gpu_va, flags)?;
+        Ok(())
+    }
+
+    /// Removes the binding of `buffer` at `gpu_va` created by `map_gpu`.
+    pub fn unmap_gpu(&self, buffer: &MagmaBuffer, gpu_va: u64) -> MagmaResult<()> {
+        if !self.user_gpu_va {
+            return Err(MesaError::Unsupported.into());
+        }
+
+        self.context.unmap_gpu(&buffer.buffer, gpu_va)?;
+        Ok(())
+    }
+
+    /// Submits an opaque command stream referencing `buffers`.  Only supported by virtio-gpu
+    /// devices, which forward it to the host.
+    pub fn submit(&self, commands: &[u8], buffers: &[MagmaBuffer]) -> MagmaResult<()> {
+        let buffers: Vec<Arc<dyn Buffer>> =
+            buffers.iter().map(|buffer| buffer.buffer.clone()).collect();
+        self.context.submit(commands, &buffers)?;
+        Ok(())
+    }
+
+    /// Submits like `submit`, and writes `seqno` to `user_fence` once the submission completes.
+    pub fn submit_signaled(
+        &self,
+        commands: &[u8],
+        buffers: &[MagmaBuffer],
+        user_fence: &MagmaUserFence,
+        seqno: u64,
+    ) -> MagmaResult<()> {
+        let buffers: Vec<Arc<dyn Buffer>> =
+            buffers.iter().map(|buffer| buffer.buffer.clone()).collect();
+        let sync_file = self.context.submit_fenced(commands, &buffers)?;
+        user_fence
+            .completions
+            .send((sync_file, seqno))
+            .map_err(|_| MagmaError::InternalError)?;
+        Ok(())
+    }
+
     pub fn execute_command(
         _connection: &MagmaPhysicalDevice,
         _command_descriptor: u64,
@@ -194,72 +875,1081 @@ impl MagmaContext {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::*;
-
-    fn get_physical_device() -> Option<MagmaPhysicalDevice> {
-        let valid_vendor_ids: [u16; 4] = [
-            MAGMA_VENDOR_ID_INTEL,
-            MAGMA_VENDOR_ID_AMD,
-            MAGMA_VENDOR_ID_MALI,
-            MAGMA_VENDOR_ID_QCOM,
-        ];
+impl MagmaSemaphore {
+    fn new(event: Event) -> MagmaResult<MagmaSemaphore> {
+        let signaler = Mutex::new(event.try_clone()?);
+        Ok(MagmaSemaphore { event, signaler })
+    }
 
-        let physical_devices = magma_enumerate_devices().unwrap();
-        physical_devices
-            .into_iter()
-            .find(|device| valid_vendor_ids.contains(&device.pci_info.vendor_id))
+    pub fn signal(&self) -> MagmaResult<()> {
+        self.signaler.lock().unwrap().signal()?;
+        Ok(())
     }
 
-    #[test]
-    fn test_memory_properties() {
-        let physical_device = get_physical_device().unwrap();
-        let device = physical_device.create_device().unwrap();
-        let mem_props = device.get_memory_properties().unwrap();
+    /// Blocks until the semaphore is signaled or `timeout` elapses, and resets it.
+    pub fn wait(&self, timeout: Duration) -> MagmaResult<()> {
+        let start = Instant::now();
+        let mut wait_ctx = WaitContext::new()?;
+        wait_ctx.add(0, self.event.as_borrowed_descriptor())?;
 
-        assert_ne!(mem_props.memory_type_count, 0);
-        assert_ne!(mem_props.memory_heap_count, 0);
+        // Another waiter may consume the signal between the wakeup and the reset, so the reset
+        // mustn't block.
+        while !self.event.try_wait()? {
+            let remaining = timeout.saturating_sub(start.elapsed());
+            if remaining.is_zero() {
+                return Err(MagmaError::TimedOut);
+            }
 
-        assert_ne!(mem_props.memory_type_count, 0,);
-        assert_ne!(mem_props.memory_heap_count, 0,);
+            wait_ctx.wait(WaitTimeout::Finite(remaining))?;
+        }
 
-        println!("--- Retrieved Magma Memory Properties ---");
-        println!("  Total Memory Type Count: {}", mem_props.memory_type_count);
-        println!("  Total Memory Heap Count: {}", mem_props.memory_heap_count);
+        Ok(())
+    }
 
-        println!("--- Validating Memory Heaps ---");
-        for i in 0..mem_props.memory_heap_count as usize {
-            let heap = &mem_props.memory_heaps[i];
-            println!("  Heap {}:", i);
-            println!("    Size: {} bytes", heap.heap_size);
-            println!("    Flags: {:#x}", heap.heap_flags); // Print flags in hex for clarity
+    /// Exports the semaphore, so it can be imported by another device or process.
+    pub fn export(&self) -> MagmaResult<MesaHandle> {
+        let event = self.event.try_clone()?;
+        Ok(event.into())
+    }
+}
 
-            // Assertions for heaps
-            assert!(heap.heap_size > 0);
-        }
+impl MagmaSuspendSnapshot {
+    /// Returns the number of buffers whose contents were saved.
+    pub fn len(&self) -> usize {
+        self.saved.len()
+    }
 
-        // Loop through and validate Memory Types
-        println!("--- Validating Memory Types ---");
-        for i in 0..mem_props.memory_type_count as usize {
-            let mem_type = &mem_props.memory_types[i];
-            println!("  Memory Type {}:", i);
-            println!("    Heap Index: {}", mem_type.heap_idx);
-            println!("    Property Flags: {:#x}", mem_type.property_flags); // Print flags in hex
+    pub fn is_empty(&self) -> bool {
+        self.saved.is_empty()
+    }
 
-            // Assertions for memory types
-            assert!(mem_type.heap_idx < mem_props.memory_heap_count,);
+    /// Writes the saved contents back into their buffers, once the device has resumed.
+    pub fn restore(self) -> MagmaResult<()> {
+        for (buffer, contents) in self.saved {
+            let mapping = buffer.map()?;
+            if mapping.size() < contents.len() {
+                return Err(MagmaError::MemoryError);
+            }
 
-            // Assert that each memory type has at least one property flag set (not NONE)
-            assert_ne!(mem_type.property_flags, 0,);
+            // SAFETY:
+            // The mapping is valid for at least `contents.len()` bytes and doesn't alias
+            // `contents`.
+            unsafe {
+                std::ptr::copy_nonoverlapping(contents.as_ptr(), mapping.as_ptr(), contents.len())
+            };
+
+            ignore_unsupported(buffer.flush(MAGMA_SYNC_WHOLE_RANGE, &[]))?;
         }
 
-        println!("--- Magma Memory Properties Test Passed Successfully! ---");
+        Ok(())
     }
+}
 
-    #[test]
-    fn test_memory_allocation() {
-        let physical_device = get_physical_device().unwrap();
+impl MagmaCrossDeviceBuffer {
+    /// Returns the buffer resident on the target device.
+    pub fn buffer(&self) -> &MagmaBuffer {
+        &self.dst
+    }
+
+    /// Returns true if the source and target buffers share memory, and `sync` is a no-op.
+    pub fn is_shared(&self) -> bool {
+        self.shared
+    }
+
+    /// Makes the current contents of the source buffer visible in the target buffer.  The
+    /// source device must be done writing to it.
+    pub fn sync(&self) -> MagmaResult<()> {
+        if self.shared {
+            return Ok(());
+        }
+
+        let src_mapping = self.src.map()?;
+        let dst_mapping = self.dst.map()?;
+        let len = src_mapping.size().min(dst_mapping.size());
+
+        ignore_unsupported(self.src.invalidate(MAGMA_SYNC_WHOLE_RANGE, &[]))?;
+
+        // SAFETY:
+        // Both mappings are valid for at least `len` bytes, and belong to different buffers.
+        unsafe { std::ptr::copy_nonoverlapping(src_mapping.as_ptr(), dst_mapping.as_ptr(), len) };
+
+        ignore_unsupported(self.dst.flush(MAGMA_SYNC_WHOLE_RANGE, &[]))?;
+        Ok(())
+    }
+}
+
+impl MagmaShareRegistry {
+    pub fn new() -> MagmaShareRegistry {
+        Default::default()
+    }
+
+    /// Exports `buffer` and returns a token which imports it.  The token stays valid until it's
+    /// revoked, even if `buffer` is destroyed.
+    pub fn share(&self, buffer: &MagmaBuffer) -> MagmaResult<u64> {
+        let handle = buffer.export()?;
+        check_shareable_handle_type(handle.handle_type)?;
+
+        let mut shared = self.shared.lock().unwrap();
+        // Tokens are random, so they can't be predicted from previous ones.  Zero is never issued,
+        // so it can stand for "no token".
+        let token = loop {
+            let mut bytes = [0u8; 8];
+            getrandom::fill(&mut bytes)
+                .map_err(|_| MesaError::WithContext("failed to generate a share token"))?;
+            let token = u64::from_ne_bytes(bytes);
+            if token != 0 && !shared.contains_key(&token) {
+                break token;
+            }
+        };
+
+        shared.insert(token, (handle, buffer.size()));
+        Ok(token)
+    }
+
+    /// Imports the buffer shared as `token` into `device`, with memory type `memory_type_idx`.
+    pub fn import(
+        &self,
+        device: &MagmaDevice,
+        token: u64,
+        memory_type_idx: u32,
+    ) -> MagmaResult<MagmaBuffer> {
+        let (handle, size) = {
+            let shared = self.shared.lock().unwrap();
+            let (handle, size) = shared.get(&token).ok_or(MagmaError::AccessDenied)?;
+            (handle.try_clone()?, *size)
+        };
+
+        device.import(MagmaImportHandleInfo {
+            handle,
+            size,
+            memory_type_idx,
+        })
+    }
+
+    /// Invalidates `token`.  Buffers already imported with it are unaffected.
+    pub fn revoke(&self, token: u64) -> MagmaResult<()> {
+        self.shared
+            .lock()
+            .unwrap()
+            .remove(&token)
+            .map(|_| ())
+            .ok_or(MagmaError::InvalidArgs)
+    }
+}
+
+impl MagmaResidencyManager {
+    pub fn new(device: &MagmaDevice, config: MagmaResidencyConfig) -> MagmaResidencyManager {
+        MagmaResidencyManager {
+            device: device.clone(),
+            config,
+            state: Default::default(),
+        }
+    }
+
+    /// Starts managing `buffer`, which must have been allocated on this manager's device, and
+    /// returns its id.
+    pub fn add(&self, buffer: MagmaBuffer) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.next_id += 1;
+        let id = state.next_id;
+        state.buffers.insert(
+            id,
+            MagmaResidentBuffer {
+                buffer,
+                maps: 0,
+                submissions: 0,
+                cpu_score: 0,
+                gpu_score: 0,
+                periods_since_migration: 0,
+                gpu_mappings: Vec::new(),
+            },
+        );
+        id
+    }
+
+    /// Stops managing buffer `id` and returns its current backing buffer.  GPU bindings made
+    /// through the manager are kept.
+    pub fn remove(&self, id: u64) -> MagmaResult<MagmaBuffer> {
+        let resident = self
+            .state
+            .lock()
+            .unwrap()
+            .buffers
+            .remove(&id)
+            .ok_or(MagmaError::InvalidArgs)?;
+        Ok(resident.buffer)
+    }
+
+    /// Returns the current backing buffer of `id`.  It is only valid until the next
+    /// `migrate_idle`.
+    pub fn buffer(&self, id: u64) -> MagmaResult<MagmaBuffer> {
+        let state = self.state.lock().unwrap();
+        let resident = state.buffers.get(&id).ok_or(MagmaError::InvalidArgs)?;
+        Ok(resident.buffer.clone())
+    }
+
+    /// Maps buffer `id` for CPU access and records the access.  The mapping is only valid until
+    /// the next `migrate_idle`.
+    pub fn map(&self, id: u64) -> MagmaResult<Arc<dyn MappedRegion>> {
+        let mut state = self.state.lock().unwrap();
+        let resident = state.buffers.get_mut(&id).ok_or(MagmaError::InvalidArgs)?;
+        resident.maps = resident.maps.saturating_add(1);
+        resident.buffer.map()
+    }
+
+    /// Binds buffer `id` into `context` like `MagmaContext::map_gpu`.  The binding follows the
+    /// buffer when it migrates.
+    pub fn map_gpu(
+        &self,
+        id: u64,
+        context: &MagmaContext,
+        gpu_va: u64,
+        flags: u64,
+    ) -> MagmaResult<()> {
+        let mut state = self.state.lock().unwrap();
+        let resident = state.buffers.get_mut(&id).ok_or(MagmaError::InvalidArgs)?;
+        context.map_gpu(&resident.buffer, gpu_va, flags)?;
+        resident.gpu_mappings.push((context.clone(), gpu_va, flags));
+        Ok(())
+    }
+
+    /// Removes the binding of buffer `id` at `gpu_va` created by `map_gpu`.
+    pub fn unmap_gpu(&self, id: u64, context: &MagmaContext, gpu_va: u64) -> MagmaResult<()> {
+        let mut state = self.state.lock().unwrap();
+        let resident = state.buffers.get_mut(&id).ok_or(MagmaError::InvalidArgs)?;
+        let pos = resident
+            .gpu_mappings
+            .iter()
+            .position(|(ctx, va, _)| Arc::ptr_eq(&ctx.context, &context.context) && *va == gpu_va)
+            .ok_or(MagmaError::InvalidArgs)?;
+
+        context.unmap_gpu(&resident.buffer, gpu_va)?;
+        resident.gpu_mappings.remove(pos);
+        Ok(())
+    }
+
+    /// Records that a submission references the buffers `ids`.  Unknown ids are ignored.
+    pub fn record_submission(&self, ids: &[u64]) {
+        let mut state = self.state.lock().unwrap();
+        for id in ids {
+            if let Some(resident) = state.buffers.get_mut(id) {
+                resident.submissions = resident.submissions.saturating_add(1);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> MagmaResidencyStats {
+        self.state.lock().unwrap().stats.clone()
+    }
+
+    /// Ends the current sampling period and migrates the buffers whose access pattern calls for
+    /// it.  The GPU must be idle, and CPU mappings of managed buffers must not be in use.
+    /// Returns the number of buffers migrated, which is always zero on devices that can't place
+    /// buffers in a given heap.
+    pub fn migrate_idle(&self) -> MagmaResult<u32> {
+        if !self.device.device.supports_buffer_placement() {
+            return Ok(0);
+        }
+
+        let mem_props = self.device.get_memory_properties()?;
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let mut migrated = 0;
+
+        for resident in state.buffers.values_mut() {
+            resident.cpu_score = resident.cpu_score / 2 + resident.maps.saturating_mul(16);
+            resident.gpu_score = resident.gpu_score / 2 + resident.submissions.saturating_mul(16);
+            resident.maps = 0;
+            resident.submissions = 0;
+            resident.periods_since_migration = resident.periods_since_migration.saturating_add(1);
+
+            // Sparse buffers have no pages to move.
+            if resident.periods_since_migration < self.config.min_dwell_periods
+                || resident.buffer.is_sparse()
+                || resident.buffer.memory_type_idx >= mem_props.memory_type_count
+            {
+                continue;
+            }
+
+            let memory_type = mem_props.get_memory_type(resident.buffer.memory_type_idx);
+            let device_local = mem_props
+                .get_memory_heap(memory_type.heap_idx)
+                .is_device_local();
+
+            // Reads over the BAR are slow, so buffers mostly accessed by the CPU belong in host
+            // memory even if the GPU uses them too.
+            let cpu_bound = resident.cpu_score > resident.gpu_score;
+            let promote =
+                !device_local && !cpu_bound && resident.gpu_score >= self.config.promote_threshold;
+            let demote =
+                device_local && (cpu_bound || resident.gpu_score < self.config.demote_threshold);
+            if !promote && !demote {
+                continue;
+            }
+
+            let Some(memory_type_idx) = migration_target(&mem_props, promote) else {
+                continue;
+            };
+
+            match self.migrate(resident, &mem_props, memory_type_idx) {
+                Ok(true) => {
+                    if promote {
+                        state.stats.promotions += 1;
+                    } else {
+                        state.stats.demotions += 1;
+                    }
+                    state.stats.bytes_migrated += resident.buffer.size();
+                    resident.periods_since_migration = 0;
+                    migrated += 1;
+                }
+                Ok(false) => state.stats.failed_migrations += 1,
+                Err(e) => {
+                    // Back off like after a migration, rather than failing again every period.
+                    state.stats.failed_migrations += 1;
+                    resident.periods_since_migration = 0;
+                    magma_warn!("failed to migrate buffer: {}", e);
+                }
+            }
+        }
+
+        Ok(migrated)
+    }
+
+    // Copies `resident` into a new buffer of memory type `memory_type_idx` and moves its GPU
+    // bindings.  Returns false if the target heap has no room.  On failure, the buffer and all its
+    // bindings are left as they were.
+    fn migrate(
+        &self,
+        resident: &mut MagmaResidentBuffer,
+        mem_props: &MagmaMemoryProperties,
+        memory_type_idx: u32,
+    ) -> MagmaResult<bool> {
+        let size = resident.buffer.size();
+
+        // Drivers without budgets are assumed to have room.
+        let heap_idx = mem_props.get_memory_type(memory_type_idx).heap_idx;
+        if let Ok(budget) = self.device.get_memory_budget(heap_idx) {
+            if budget.usage.saturating_add(size) > budget.budget {
+                return Ok(false);
+            }
+        }
+
+        let dst = self.device.create_buffer(&MagmaCreateBufferInfo {
+            memory_type_idx,
+            alignment: resident.buffer.alignment as u32,
+            common_flags: 0,
+            vendor_flags: 0,
+            size,
+        })?;
+
+        MagmaCrossDeviceBuffer {
+            src: resident.buffer.clone(),
+            dst: dst.clone(),
+            shared: false,
+        }
+        .sync()?;
+
+        let mut moved = 0;
+        let result = resident
+            .gpu_mappings
+            .iter()
+            .try_for_each(|(context, gpu_va, flags)| {
+                context.unmap_gpu(&resident.buffer, *gpu_va)?;
+                if let Err(e) = context.map_gpu(&dst, *gpu_va, *flags) {
+                    rebind(context, &resident.buffer, *gpu_va, *flags);
+                    return Err(e);
+                }
+
+                moved += 1;
+                Ok(())
+            });
+
+        if let Err(e) = result {
+            for (context, gpu_va, flags) in &resident.gpu_mappings[..moved] {
+                match context.unmap_gpu(&dst, *gpu_va) {
+                    Ok(()) => rebind(context, &resident.buffer, *gpu_va, *flags),
+                    Err(e) => {
+                        magma_error!("failed to unbind {:#x} after a migration: {}", gpu_va, e)
+                    }
+                }
+            }
+
+            return Err(e);
+        }
+
+        resident.buffer = dst;
+        Ok(true)
+    }
+}
+
+impl MagmaUserFenceSeqno {
+    fn get(&self) -> &AtomicU64 {
+        // SAFETY:
+        // The mapping is page aligned, at least 8 bytes long and lives as long as `self`.  The
+        // seqno is only ever accessed atomically.
+        unsafe { &*(self.mapping.as_ptr() as *const AtomicU64) }
+    }
+
+    fn signal(&self, seqno: u64) {
+        self.get().fetch_max(seqno, Ordering::AcqRel);
+        // Waiters check the seqno under the lock, so taking it here means none misses the wakeup.
+        let _guard = self.lock.lock().unwrap();
+        self.written.notify_all();
+    }
+
+    // Writes the seqno of each submission once its sync file signals.  Submissions of a context
+    // complete in order, so waiting for them in turn delays none.
+    fn signal_completions(&self, pending: Receiver<(OwnedDescriptor, u64)>) {
+        for (sync_file, seqno) in pending {
+            let result = WaitContext::new().and_then(|mut wait_ctx| {
+                wait_ctx.add(0, &sync_file)?;
+                wait_ctx.wait(WaitTimeout::NoTimeout)
+            });
+
+            match result {
+                Ok(_) => self.signal(seqno),
+                Err(e) => magma_error!("failed to wait for magma submission {}: {}", seqno, e),
+            }
+        }
+    }
+}
+
+impl MagmaUserFence {
+    /// Returns the most recently completed seqno.
+    pub fn current(&self) -> u64 {
+        self.seqno.get().load(Ordering::Acquire)
+    }
+
+    /// Returns true if `seqno` has completed.
+    pub fn is_signaled(&self, seqno: u64) -> bool {
+        self.current() >= seqno
+    }
+
+    /// Marks `seqno` as completed.  Seqnos never move backwards.
+    pub fn signal(&self, seqno: u64) {
+        self.seqno.signal(seqno);
+    }
+
+    /// Blocks until `seqno` has completed or `timeout` elapses.  Wakes as soon as this process
+    /// writes the seqno, and polls the shared page for writes from elsewhere.
+    pub fn wait(&self, seqno: u64, timeout: Duration) -> MagmaResult<()> {
+        let start = Instant::now();
+        let mut guard = self.seqno.lock.lock().unwrap();
+        while !self.is_signaled(seqno) {
+            let remaining = timeout.saturating_sub(start.elapsed());
+            if remaining.is_zero() {
+                return Err(MagmaError::TimedOut);
+            }
+
+            guard = self
+                .seqno
+                .written
+                .wait_timeout(guard, remaining.min(USER_FENCE_POLL_INTERVAL))
+                .unwrap()
+                .0;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the buffer backing the seqno page.
+    pub fn buffer(&self) -> &MagmaBuffer {
+        &self.buffer
+    }
+
+    /// Exports the seqno page, so it can be shared with a guest.
+    pub fn export(&self) -> MagmaResult<MesaHandle> {
+        self.buffer.export()
+    }
+}
+
+impl MagmaHeapSampler {
+    /// Starts sampling `device` every `interval`.  Heaps whose budget can't be queried are left
+    /// out of the samples.
+    pub fn new(device: &MagmaDevice, interval: Duration) -> MagmaResult<MagmaHeapSampler> {
+        let heap_count = device.get_memory_properties()?.memory_heap_count;
+        let callbacks: Arc<Mutex<Map<u64, MagmaHeapCallback>>> = Default::default();
+        let (stop, stopped) = channel();
+
+        let device = device.clone();
+        let thread_callbacks = callbacks.clone();
+        let thread = thread::Builder::new()
+            .name("magma_heap_sampler".to_string())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    let samples: Vec<MagmaHeapSample> = (0..heap_count)
+                        .filter_map(|heap_idx| {
+                            let budget = device.get_memory_budget(heap_idx).ok()?;
+                            Some(MagmaHeapSample {
+                                heap_idx,
+                                budget: budget.budget,
+                                usage: budget.usage,
+                            })
+                        })
+                        .collect();
+
+                    let callbacks: Vec<MagmaHeapCallback> =
+                        thread_callbacks.lock().unwrap().values().cloned().collect();
+                    for callback in callbacks {
+                        callback(&samples);
+                    }
+                }
+            })
+            .map_err(MesaError::IoError)?;
+
+        Ok(MagmaHeapSampler {
+            callbacks,
+            next_id: AtomicU64::new(0),
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    /// Registers `callback` for every later sample, returning an id for `unregister`.
+    pub fn register(&self, callback: MagmaHeapCallback) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.callbacks.lock().unwrap().insert(id, callback);
+        id
+    }
+
+    /// Stops delivering samples to the callback registered as `id`.  A sample already being
+    /// delivered may still reach it.
+    pub fn unregister(&self, id: u64) -> MagmaResult<()> {
+        self.callbacks
+            .lock()
+            .unwrap()
+            .remove(&id)
+            .map(|_| ())
+            .ok_or(MagmaError::InvalidArgs)
+    }
+}
+
+impl Drop for MagmaHeapSampler {
+    fn drop(&mut self) {
+        // Dropping the sender wakes the thread immediately.
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                magma_error!("magma heap sampler thread panicked");
+            }
+        }
+    }
+}
+
+impl MagmaPoolBlock {
+    // Carves `size` bytes aligned to `alignment` out of the first free range they fit in, and
+    // returns their offset.
+    fn allocate(&mut self, size: u64, alignment: u64) -> Option<u64> {
+        let (start, end, offset) = self.free.iter().find_map(|(&start, &len)| {
+            let offset = start.checked_next_multiple_of(alignment)?;
+            let end = start + len;
+            (offset.checked_add(size)? <= end).then_some((start, end, offset))
+        })?;
+
+        // Alignment padding stays free.
+        self.free.remove(&start);
+        if offset > start {
+            self.free.insert(start, offset - start);
+        }
+        if offset + size < end {
+            self.free.insert(offset + size, end - offset - size);
+        }
+
+        self.allocation_count += 1;
+        self.allocated_bytes += size;
+        Some(offset)
+    }
+
+    fn free(&mut self, offset: u64, size: u64) {
+        let mut start = offset;
+        let mut end = offset + size;
+        if let Some((&prev, &len)) = self.free.range(..offset).next_back() {
+            if prev + len == offset {
+                self.free.remove(&prev);
+                start = prev;
+            }
+        }
+        if let Some(len) = self.free.remove(&end) {
+            end += len;
+        }
+
+        self.free.insert(start, end - start);
+        self.allocation_count -= 1;
+        self.allocated_bytes -= size;
+    }
+}
+
+impl MagmaBufferPool {
+    pub fn new(device: &MagmaDevice, config: MagmaBufferPoolConfig) -> MagmaBufferPool {
+        MagmaBufferPool {
+            device: device.clone(),
+            config,
+            state: Default::default(),
+        }
+    }
+
+    /// Allocates `create_info.size` bytes of memory type `create_info.memory_type_idx`, at an
+    /// offset aligned to `create_info.alignment`.  Fails with InvalidArgs if any buffer flag is
+    /// set.
+    pub fn allocate(&self, create_info: &MagmaCreateBufferInfo) -> MagmaResult<MagmaSubAllocation> {
+        let alignment = u64::from(create_info.alignment.max(1));
+        if create_info.common_flags != 0
+            || create_info.vendor_flags != 0
+            || create_info.size == 0
+            || !alignment.is_power_of_two()
+        {
+            return Err(MagmaError::InvalidArgs);
+        }
+
+        let size = create_info.size;
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let blocks = state.blocks.entry(create_info.memory_type_idx).or_default();
+
+        let found = blocks.iter_mut().find_map(|(&block_id, block)| {
+            if block.alignment < alignment {
+                return None;
+            }
+
+            let offset = block.allocate(size, alignment)?;
+            Some((block_id, block.buffer.clone(), offset))
+        });
+
+        let (block_id, buffer, offset) = match found {
+            Some(found) => found,
+            None => {
+                let block_alignment = create_info.alignment.max(MAGMA_POOL_BLOCK_ALIGNMENT);
+                let block_size = size
+                    .checked_next_multiple_of(u64::from(block_alignment))
+                    .ok_or(MagmaError::InvalidArgs)?
+                    .max(self.config.block_size);
+                let buffer = self.device.create_buffer(&MagmaCreateBufferInfo {
+                    memory_type_idx: create_info.memory_type_idx,
+                    alignment: block_alignment,
+                    common_flags: 0,
+                    vendor_flags: 0,
+                    size: block_size,
+                })?;
+
+                let mut block = MagmaPoolBlock {
+                    buffer: buffer.clone(),
+                    size: block_size,
+                    alignment: u64::from(block_alignment),
+                    free: Map::from([(0, block_size)]),
+                    allocation_count: 0,
+                    allocated_bytes: 0,
+                };
+                // The block is empty and at least as large as the allocation.
+                let offset = block.allocate(size, alignment).unwrap();
+
+                state.next_block_id += 1;
+                blocks.insert(state.next_block_id, block);
+                (state.next_block_id, buffer, offset)
+            }
+        };
+
+        Ok(MagmaSubAllocation {
+            buffer,
+            offset,
+            size,
+            memory_type_idx: create_info.memory_type_idx,
+            block_id,
+            state: self.state.clone(),
+        })
+    }
+
+    pub fn stats(&self) -> MagmaBufferPoolStats {
+        let state = self.state.lock().unwrap();
+        let mut stats: MagmaBufferPoolStats = Default::default();
+        for block in state.blocks.values().flat_map(|blocks| blocks.values()) {
+            stats.block_count += 1;
+            stats.block_bytes += block.size;
+            stats.allocation_count += block.allocation_count;
+            stats.allocated_bytes += block.allocated_bytes;
+            stats.free_range_count += block.free.len() as u64;
+            let largest = block.free.values().copied().max().unwrap_or(0);
+            stats.largest_free_range = stats.largest_free_range.max(largest);
+        }
+
+        stats
+    }
+}
+
+impl MagmaSubAllocation {
+    /// Returns the block the range was carved from.
+    pub fn buffer(&self) -> &MagmaBuffer {
+        &self.buffer
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Drop for MagmaSubAllocation {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        let Some(blocks) = state.blocks.get_mut(&self.memory_type_idx) else {
+            return;
+        };
+
+        let Some(block) = blocks.get_mut(&self.block_id) else {
+            return;
+        };
+
+        block.free(self.offset, self.size);
+        // Empty blocks are released, except for the last block of the memory type, so that a
+        // single allocation freed and made again doesn't create a driver buffer each time.
+        if block.allocation_count == 0 && blocks.len() > 1 {
+            blocks.remove(&self.block_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use crate::*;
+
+    fn get_physical_device() -> Option<MagmaPhysicalDevice> {
+        let valid_vendor_ids: [u16; 4] = [
+            MAGMA_VENDOR_ID_INTEL,
+            MAGMA_VENDOR_ID_AMD,
+            MAGMA_VENDOR_ID_MALI,
+            MAGMA_VENDOR_ID_QCOM,
+        ];
+
+        let physical_devices = magma_enumerate_devices().unwrap();
+        physical_devices
+            .into_iter()
+            .find(|device| valid_vendor_ids.contains(&device.pci_info.vendor_id))
+    }
+
+    // A device backed by system memory, which exercises the parts of magma above the drivers
+    // without a GPU.
+    mod fake {
+        use std::cell::Cell;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        use mesa3d_util::Event;
+        use mesa3d_util::MappedRegion;
+        use mesa3d_util::MesaError;
+        use mesa3d_util::MesaHandle;
+        use mesa3d_util::MesaMapping;
+        use mesa3d_util::MesaResult;
+        use mesa3d_util::OwnedDescriptor;
+
+        use crate::sys::platform::PlatformDevice;
+        use crate::sys::platform::PlatformPhysicalDevice;
+        use crate::traits::AsVirtGpu;
+        use crate::traits::Buffer;
+        use crate::traits::Context;
+        use crate::traits::Device;
+        use crate::traits::GenericBuffer;
+        use crate::traits::GenericContext;
+        use crate::traits::GenericDevice;
+        use crate::traits::GenericPhysicalDevice;
+        use crate::traits::PhysicalDevice;
+        use crate::*;
+
+        // Submissions of these commands never complete.
+        pub const HANG: &[u8] = b"hang";
+
+        thread_local! {
+            // The number of `map_gpu` calls on this thread that succeed before one fails.
+            pub static MAP_GPU_FAILS_AFTER: Cell<Option<u32>> = const { Cell::new(None) };
+        }
+
+        pub struct FakePhysicalDevice;
+
+        pub struct FakeDevice {
+            mem_props: MagmaMemoryProperties,
+        }
+
+        #[derive(Default)]
+        pub struct FakeContext {
+            // (gpu_va, buffer address) of every live binding.
+            pub bindings: Mutex<Vec<(u64, usize)>>,
+        }
+
+        pub struct FakeBuffer {
+            memory: Mutex<Vec<u8>>,
+            ptr: *mut u8,
+        }
+
+        struct FakeMapping {
+            _buffer: Arc<dyn Buffer>,
+            ptr: *mut u8,
+            size: usize,
+        }
+
+        // SAFETY: The memory behind the raw pointers is owned by the buffer and never moves.
+        unsafe impl Send for FakeBuffer {}
+        // SAFETY: See above.
+        unsafe impl Sync for FakeBuffer {}
+        // SAFETY: See above.
+        unsafe impl Send for FakeMapping {}
+        // SAFETY: See above.
+        unsafe impl Sync for FakeMapping {}
+
+        pub fn physical_device() -> MagmaPhysicalDevice {
+            MagmaPhysicalDevice::new(
+                Arc::new(FakePhysicalDevice),
+                Default::default(),
+                Default::default(),
+            )
+        }
+
+        impl PlatformPhysicalDevice for FakePhysicalDevice {}
+        impl AsVirtGpu for FakePhysicalDevice {}
+        impl PhysicalDevice for FakePhysicalDevice {}
+
+        impl GenericPhysicalDevice for FakePhysicalDevice {
+            fn create_device(
+                &self,
+                _physical_device: &Arc<dyn PhysicalDevice>,
+                _pci_info: &MagmaPciInfo,
+                _flags: u32,
+            ) -> MesaResult<Arc<dyn Device>> {
+                let mut mem_props: MagmaMemoryProperties = Default::default();
+                mem_props.add_heap(1 << 30, MAGMA_HEAP_CPU_VISIBLE_BIT);
+                mem_props.add_memory_type(
+                    MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT
+                        | MAGMA_MEMORY_PROPERTY_HOST_COHERENT_BIT,
+                );
+                mem_props.increment_heap_count();
+                mem_props.add_heap(
+                    1 << 28,
+                    MAGMA_HEAP_DEVICE_LOCAL_BIT | MAGMA_HEAP_CPU_VISIBLE_BIT,
+                );
+                mem_props.add_memory_type(
+                    MAGMA_MEMORY_PROPERTY_DEVICE_LOCAL_BIT
+                        | MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT
+                        | MAGMA_MEMORY_PROPERTY_HOST_COHERENT_BIT,
+                );
+                mem_props.increment_heap_count();
+                Ok(Arc::new(FakeDevice { mem_props }))
+            }
+        }
+
+        impl PlatformDevice for FakeDevice {}
+        impl Device for FakeDevice {}
+
+        impl GenericDevice for FakeDevice {
+            fn get_memory_properties(&self) -> MesaResult<MagmaMemoryProperties> {
+                Ok(self.mem_props.clone())
+            }
+
+            fn get_memory_budget(&self, _heap_idx: u32) -> MesaResult<MagmaHeapBudget> {
+                Err(MesaError::Unsupported)
+            }
+
+            fn create_context(
+                &self,
+                _device: &Arc<dyn Device>,
+                _priority: MagmaContextPriority,
+                _engine_class: MagmaEngineClass,
+            ) -> MesaResult<Arc<dyn Context>> {
+                Ok(Arc::new(FakeContext::default()))
+            }
+
+            fn create_buffer(
+                &self,
+                _device: &Arc<dyn Device>,
+                create_info: &MagmaCreateBufferInfo,
+            ) -> MesaResult<Arc<dyn Buffer>> {
+                let mut memory = vec![0u8; create_info.size.try_into()?];
+                let ptr = memory.as_mut_ptr();
+                Ok(Arc::new(FakeBuffer {
+                    memory: Mutex::new(memory),
+                    ptr,
+                }))
+            }
+
+            fn import(
+                &self,
+                _device: &Arc<dyn Device>,
+                _info: MagmaImportHandleInfo,
+            ) -> MesaResult<Arc<dyn Buffer>> {
+                Err(MesaError::Unsupported)
+            }
+
+            fn supports_buffer_placement(&self) -> bool {
+                true
+            }
+        }
+
+        impl Context for FakeContext {}
+
+        impl GenericContext for FakeContext {
+            fn map_gpu(
+                &self,
+                buffer: &Arc<dyn Buffer>,
+                gpu_va: u64,
+                _flags: u64,
+            ) -> MesaResult<()> {
+                let fail = MAP_GPU_FAILS_AFTER.with(|fails_after| match fails_after.get() {
+                    Some(0) => {
+                        fails_after.set(None);
+                        true
+                    }
+                    Some(count) => {
+                        fails_after.set(Some(count - 1));
+                        false
+                    }
+                    None => false,
+                });
+                if fail {
+                    return Err(MesaError::WithContext("injected map_gpu failure"));
+                }
+
+                self.bindings
+                    .lock()
+                    .unwrap()
+                    .push((gpu_va, buffer_address(buffer)));
+                Ok(())
+            }
+
+            fn unmap_gpu(&self, buffer: &Arc<dyn Buffer>, gpu_va: u64) -> MesaResult<()> {
+                let mut bindings = self.bindings.lock().unwrap();
+                let idx = bindings
+                    .iter()
+                    .position(|binding| *binding == (gpu_va, buffer_address(buffer)))
+                    .ok_or(MesaError::WithContext("no binding at the address"))?;
+                bindings.remove(idx);
+                Ok(())
+            }
+
+            fn submit_fenced(
+                &self,
+                commands: &[u8],
+                _buffers: &[Arc<dyn Buffer>],
+            ) -> MesaResult<OwnedDescriptor> {
+                let mut completion = Event::new()?;
+                if commands != HANG {
+                    completion.signal()?;
+                }
+
+                Ok(MesaHandle::from(completion).os_handle)
+            }
+        }
+
+        fn buffer_address(buffer: &Arc<dyn Buffer>) -> usize {
+            Arc::as_ptr(buffer) as *const () as usize
+        }
+
+        impl Buffer for FakeBuffer {}
+
+        impl GenericBuffer for FakeBuffer {
+            fn map(&self, buffer: &Arc<dyn Buffer>) -> MesaResult<Arc<dyn MappedRegion>> {
+                Ok(Arc::new(FakeMapping {
+                    _buffer: buffer.clone(),
+                    ptr: self.ptr,
+                    size: self.memory.lock().unwrap().len(),
+                }))
+            }
+
+            fn export(&self) -> MesaResult<MesaHandle> {
+                Err(MesaError::Unsupported)
+            }
+
+            fn invalidate(
+                &self,
+                _sync_flags: u64,
+                _ranges: &[MagmaMappedMemoryRange],
+            ) -> MesaResult<()> {
+                Ok(())
+            }
+
+            fn flush(
+                &self,
+                _sync_flags: u64,
+                _ranges: &[MagmaMappedMemoryRange],
+            ) -> MesaResult<()> {
+                Ok(())
+            }
+
+            fn size(&self) -> u64 {
+                self.memory.lock().unwrap().len() as u64
+            }
+        }
+
+        // SAFETY: The mapping keeps the buffer, and so its memory, alive.
+        unsafe impl MappedRegion for FakeMapping {
+            fn as_ptr(&self) -> *mut u8 {
+                self.ptr
+            }
+
+            fn size(&self) -> usize {
+                self.size
+            }
+
+            fn as_mesa_mapping(&self) -> MesaMapping {
+                MesaMapping {
+                    ptr: self.ptr as u64,
+                    size: self.size as u64,
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_memory_properties() {
+        let physical_device = get_physical_device().unwrap();
+        let device = physical_device.create_device().unwrap();
+        let mem_props = device.get_memory_properties().unwrap();
+
+        assert_ne!(mem_props.memory_type_count, 0);
+        assert_ne!(mem_props.memory_heap_count, 0);
+
+        assert_ne!(mem_props.memory_type_count, 0,);
+        assert_ne!(mem_props.memory_heap_count, 0,);
+
+        println!("--- Retrieved Magma Memory Properties ---");
+        println!("  Total Memory Type Count: {}", mem_props.memory_type_count);
+        println!("  Total Memory Heap Count: {}", mem_props.memory_heap_count);
+
+        println!("--- Validating Memory Heaps ---");
+        for i in 0..mem_props.memory_heap_count as usize {
+            let heap = &mem_props.memory_heaps[i];
+            println!("  Heap {}:", i);
+            println!("    Size: {} bytes", heap.heap_size);
+            println!("    Flags: {:#x}", heap.heap_flags); // Print flags in hex for clarity
+
+            // Assertions for heaps
+            assert!(heap.heap_size > 0);
+        }
+
+        // Loop through and validate Memory Types
+        println!("--- Validating Memory Types ---");
+        for i in 0..mem_props.memory_type_count as usize {
+            let mem_type = &mem_props.memory_types[i];
+            println!("  Memory Type {}:", i);
+            println!("    Heap Index: {}", mem_type.heap_idx);
+            println!("    Property Flags: {:#x}", mem_type.property_flags); // Print flags in hex
+
+            // Assertions for memory types
+            assert!(mem_type.heap_idx < mem_props.memory_heap_count,);
+
+            // Assert that each memory type has at least one property flag set (not NONE)
+            assert_ne!(mem_type.property_flags, 0,);
+        }
+
+        println!("--- Magma Memory Properties Test Passed Successfully! ---");
+    }
+
+    #[test]
+    fn test_memory_allocation() {
+        let physical_device = get_physical_device().unwrap();
         let device = physical_device.create_device().unwrap();
 
         let mem_props = device.get_memory_properties().unwrap();
@@ -288,4 +1978,426 @@ mod tests {
 
         let buffer = device.create_buffer(&create_info).unwrap();
     }
+
+    #[test]
+    fn test_share_registry() {
+        let physical_device = get_physical_device().unwrap();
+        let device = physical_device.create_device().unwrap();
+        let create_info = MagmaCreateBufferInfo {
+            memory_type_idx: 0,
+            alignment: 4096,
+            common_flags: MAGMA_BUFFER_FLAG_EXTERNAL,
+            vendor_flags: 0,
+            size: 4096,
+        };
+        let buffer = device.create_buffer(&create_info).unwrap();
+
+        let registry = MagmaShareRegistry::new();
+        let token = registry.share(&buffer).unwrap();
+        let imported = registry.import(&device, token, 0).unwrap();
+        assert_eq!(imported.size(), buffer.size());
+
+        registry.revoke(token).unwrap();
+        assert!(registry.import(&device, token, 0).is_err());
+    }
+
+    #[test]
+    fn test_sync_ranges() {
+        let physical_device = get_physical_device().unwrap();
+        let device = physical_device.create_device().unwrap();
+        let create_info = MagmaCreateBufferInfo {
+            memory_type_idx: 0,
+            alignment: 4096,
+            common_flags: 0,
+            vendor_flags: 0,
+            size: 4096,
+        };
+        let buffer = device.create_buffer(&create_info).unwrap();
+
+        let past_end = MagmaMappedMemoryRange {
+            offset: 4096,
+            size: 1,
+        };
+        let overflow = MagmaMappedMemoryRange {
+            offset: u64::MAX,
+            size: 2,
+        };
+
+        assert!(buffer
+            .flush(MAGMA_SYNC_RANGES, std::slice::from_ref(&past_end))
+            .is_err());
+        assert!(buffer.invalidate(MAGMA_SYNC_RANGES, &[overflow]).is_err());
+        assert!(buffer
+            .flush(MAGMA_SYNC_WHOLE_RANGE | MAGMA_SYNC_RANGES, &[])
+            .is_err());
+        assert!(buffer.invalidate(0, &[past_end]).is_err());
+    }
+
+    #[test]
+    fn test_heap_sampler() {
+        let physical_device = get_physical_device().unwrap();
+        let device = physical_device.create_device().unwrap();
+        let heap_count = device.get_memory_properties().unwrap().memory_heap_count;
+
+        let sampler = MagmaHeapSampler::new(&device, Duration::from_millis(1)).unwrap();
+        let (sender, receiver) = channel();
+        let sender = Mutex::new(sender);
+        let id = sampler.register(Arc::new(move |samples: &[MagmaHeapSample]| {
+            let _ = sender.lock().unwrap().send(samples.to_vec());
+        }));
+
+        let samples = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(samples.len() <= heap_count as usize);
+        assert!(samples.iter().all(|sample| sample.heap_idx < heap_count));
+
+        sampler.unregister(id).unwrap();
+        assert!(sampler.unregister(id).is_err());
+    }
+
+    #[test]
+    fn test_residency_manager() {
+        let physical_device = get_physical_device().unwrap();
+        let device = physical_device.create_device().unwrap();
+        let create_info = MagmaCreateBufferInfo {
+            memory_type_idx: 0,
+            alignment: 4096,
+            common_flags: 0,
+            vendor_flags: 0,
+            size: 4096,
+        };
+        let buffer = device.create_buffer(&create_info).unwrap();
+
+        let config = MagmaResidencyConfig {
+            min_dwell_periods: 0,
+            ..Default::default()
+        };
+        let manager = MagmaResidencyManager::new(&device, config);
+        let id = manager.add(buffer);
+
+        let mapping = manager.map(id).unwrap();
+        // SAFETY: The mapping is valid for 4096 bytes and isn't used after the migration.
+        unsafe { std::ptr::write_bytes(mapping.as_ptr(), 0xa5, 4096) };
+        manager
+            .buffer(id)
+            .unwrap()
+            .flush(MAGMA_SYNC_WHOLE_RANGE, &[])
+            .unwrap();
+
+        // Whether the buffer migrates depends on the heaps of the device, but its contents must
+        // survive either way.
+        for _ in 0..4 {
+            manager.record_submission(&[id; 16]);
+            manager.migrate_idle().unwrap();
+        }
+
+        let stats = manager.stats();
+        assert_eq!(
+            stats.bytes_migrated,
+            (stats.promotions + stats.demotions) * 4096
+        );
+
+        let mapping = manager.map(id).unwrap();
+        // SAFETY: The mapping is valid for 4096 bytes.
+        let contents = unsafe { std::slice::from_raw_parts(mapping.as_ptr(), 4096) };
+        assert!(contents.iter().all(|byte| *byte == 0xa5));
+        assert!(manager.remove(id).is_ok());
+    }
+
+    #[test]
+    fn test_residency_rollback() {
+        let device = fake::physical_device()
+            .create_device_with_flags(MAGMA_DEVICE_FLAG_USER_GPU_VA)
+            .unwrap();
+        let context = device
+            .create_context(MagmaContextPriority::Medium, MagmaEngineClass::Render)
+            .unwrap();
+        let create_info = MagmaCreateBufferInfo {
+            memory_type_idx: 0,
+            alignment: 4096,
+            common_flags: 0,
+            vendor_flags: 0,
+            size: 4096,
+        };
+        let config = MagmaResidencyConfig {
+            min_dwell_periods: 0,
+            ..Default::default()
+        };
+        let manager = MagmaResidencyManager::new(&device, config);
+        let id = manager.add(device.create_buffer(&create_info).unwrap());
+        manager
+            .map_gpu(id, &context, 0x10000, MAGMA_MAP_GPU_FLAG_READ)
+            .unwrap();
+        manager
+            .map_gpu(id, &context, 0x20000, MAGMA_MAP_GPU_FLAG_READ)
+            .unwrap();
+
+        // Moving the second binding fails, so the first is moved back.
+        manager.record_submission(&[id; 16]);
+        fake::MAP_GPU_FAILS_AFTER.with(|fails_after| fails_after.set(Some(1)));
+        assert_eq!(manager.migrate_idle().unwrap(), 0);
+        assert_eq!(manager.stats().failed_migrations, 1);
+        let buffer = manager.buffer(id).unwrap();
+        assert_eq!(buffer.memory_type_idx, 0);
+        context.unmap_gpu(&buffer, 0x10000).unwrap();
+        context.unmap_gpu(&buffer, 0x20000).unwrap();
+        context
+            .map_gpu(&buffer, 0x10000, MAGMA_MAP_GPU_FLAG_READ)
+            .unwrap();
+        context
+            .map_gpu(&buffer, 0x20000, MAGMA_MAP_GPU_FLAG_READ)
+            .unwrap();
+
+        // Once the bindings can be moved, the buffer migrates along with them.
+        manager.record_submission(&[id; 16]);
+        assert_eq!(manager.migrate_idle().unwrap(), 1);
+        assert_eq!(manager.stats().promotions, 1);
+        let buffer = manager.buffer(id).unwrap();
+        assert_eq!(buffer.memory_type_idx, 1);
+        manager.unmap_gpu(id, &context, 0x10000).unwrap();
+        manager.unmap_gpu(id, &context, 0x20000).unwrap();
+    }
+
+    #[test]
+    fn test_map_gpu_requires_user_gpu_va() {
+        let physical_device = fake::physical_device();
+        assert!(matches!(
+            physical_device.create_device_with_flags(!MAGMA_DEVICE_FLAGS),
+            Err(MagmaError::InvalidArgs)
+        ));
+
+        let create_info = MagmaCreateBufferInfo {
+            memory_type_idx: 0,
+            alignment: 0x10000,
+            common_flags: 0,
+            vendor_flags: 0,
+            size: 4096,
+        };
+
+        let device = physical_device.create_device().unwrap();
+        let context = device
+            .create_context(MagmaContextPriority::Medium, MagmaEngineClass::Render)
+            .unwrap();
+        let buffer = device.create_buffer(&create_info).unwrap();
+        assert!(context
+            .map_gpu(&buffer, 0x10000, MAGMA_MAP_GPU_FLAG_READ)
+            .is_err());
+        assert!(context.unmap_gpu(&buffer, 0x10000).is_err());
+
+        let device = physical_device
+            .create_device_with_flags(MAGMA_DEVICE_FLAG_USER_GPU_VA)
+            .unwrap();
+        let context = device
+            .create_context(MagmaContextPriority::Medium, MagmaEngineClass::Render)
+            .unwrap();
+        let buffer = device.create_buffer(&create_info).unwrap();
+        assert!(matches!(
+            context.map_gpu(&buffer, 0x10000, 1 << 63),
+            Err(MagmaError::InvalidArgs)
+        ));
+        assert!(matches!(
+            context.map_gpu(&buffer, 0x8000, MAGMA_MAP_GPU_FLAG_READ),
+            Err(MagmaError::InvalidArgs)
+        ));
+
+        context
+            .map_gpu(&buffer, 0x10000, MAGMA_MAP_GPU_FLAGS)
+            .unwrap();
+        context.unmap_gpu(&buffer, 0x10000).unwrap();
+        assert!(context.unmap_gpu(&buffer, 0x10000).is_err());
+    }
+
+    #[test]
+    fn test_semaphore() {
+        let device = fake::physical_device().create_device().unwrap();
+        let semaphore = Arc::new(device.create_semaphore().unwrap());
+        assert!(matches!(
+            semaphore.wait(Duration::from_millis(1)),
+            Err(MagmaError::TimedOut)
+        ));
+
+        semaphore.signal().unwrap();
+        semaphore.wait(Duration::from_secs(5)).unwrap();
+
+        // A signal wakes every waiter, but only one of them consumes it.  The rest must time out
+        // rather than block.
+        let waiters: Vec<_> = (0..4)
+            .map(|_| {
+                let semaphore = semaphore.clone();
+                std::thread::spawn(move || semaphore.wait(Duration::from_millis(200)).is_ok())
+            })
+            .collect();
+        semaphore.signal().unwrap();
+
+        let woken = waiters
+            .into_iter()
+            .map(|waiter| waiter.join().unwrap())
+            .filter(|woken| *woken)
+            .count();
+        assert_eq!(woken, 1);
+    }
+
+    #[test]
+    fn test_user_fence() {
+        let device = fake::physical_device().create_device().unwrap();
+        let context = device
+            .create_context(MagmaContextPriority::Medium, MagmaEngineClass::Render)
+            .unwrap();
+        let user_fence = context.create_user_fence().unwrap();
+        assert_eq!(user_fence.current(), 0);
+
+        // Seqnos never move backwards.
+        user_fence.signal(2);
+        user_fence.signal(1);
+        assert_eq!(user_fence.current(), 2);
+        assert!(user_fence.is_signaled(2));
+        assert!(matches!(
+            user_fence.wait(3, Duration::from_millis(1)),
+            Err(MagmaError::TimedOut)
+        ));
+
+        let signaler = user_fence.clone();
+        let thread = std::thread::spawn(move || signaler.signal(3));
+        user_fence.wait(3, Duration::from_secs(5)).unwrap();
+        thread.join().unwrap();
+
+        // Completed submissions write their seqno, while pending ones don't.
+        context.submit_signaled(&[], &[], &user_fence, 4).unwrap();
+        user_fence.wait(4, Duration::from_secs(5)).unwrap();
+        context
+            .submit_signaled(fake::HANG, &[], &user_fence, 5)
+            .unwrap();
+        assert!(matches!(
+            user_fence.wait(5, Duration::from_millis(10)),
+            Err(MagmaError::TimedOut)
+        ));
+        assert_eq!(user_fence.current(), 4);
+    }
+
+    fn pool_info(size: u64, alignment: u32) -> MagmaCreateBufferInfo {
+        MagmaCreateBufferInfo {
+            memory_type_idx: 0,
+            alignment,
+            common_flags: 0,
+            vendor_flags: 0,
+            size,
+        }
+    }
+
+    #[test]
+    fn test_buffer_pool_merges_free_ranges() {
+        let device = fake::physical_device().create_device().unwrap();
+        let config = MagmaBufferPoolConfig {
+            block_size: 0x10000,
+        };
+        let pool = MagmaBufferPool::new(&device, config);
+
+        let a = pool.allocate(&pool_info(0x1000, 0x1000)).unwrap();
+        let b = pool.allocate(&pool_info(0x1000, 0x1000)).unwrap();
+        let c = pool.allocate(&pool_info(0x1000, 0x1000)).unwrap();
+        assert_eq!([a.offset(), b.offset(), c.offset()], [0, 0x1000, 0x2000]);
+
+        let stats = pool.stats();
+        assert_eq!(stats.block_count, 1);
+        assert_eq!(stats.allocation_count, 3);
+        assert_eq!(stats.allocated_bytes, 0x3000);
+        assert_eq!(stats.free_range_count, 1);
+        assert_eq!(stats.largest_free_range, 0xd000);
+
+        // A hole between allocations, then merged with its free neighbours on either side.
+        drop(b);
+        assert_eq!(pool.stats().free_range_count, 2);
+        drop(a);
+        let stats = pool.stats();
+        assert_eq!(stats.free_range_count, 2);
+        assert_eq!(stats.largest_free_range, 0xd000);
+        drop(c);
+        let stats = pool.stats();
+        assert_eq!(stats.free_range_count, 1);
+        assert_eq!(stats.largest_free_range, 0x10000);
+
+        // The last block of the memory type is kept, and reused.
+        assert_eq!(stats.block_count, 1);
+        let d = pool.allocate(&pool_info(0x10000, 0x1000)).unwrap();
+        assert_eq!(d.offset(), 0);
+        assert_eq!(pool.stats().block_count, 1);
+    }
+
+    #[test]
+    fn test_buffer_pool_blocks() {
+        let device = fake::physical_device().create_device().unwrap();
+        let config = MagmaBufferPoolConfig {
+            block_size: 0x10000,
+        };
+        let pool = MagmaBufferPool::new(&device, config);
+
+        // Alignment padding stays free for smaller allocations.
+        let small = pool.allocate(&pool_info(0x100, 1)).unwrap();
+        let aligned = pool.allocate(&pool_info(0x1000, 0x1000)).unwrap();
+        assert_eq!(aligned.offset(), 0x1000);
+        let filler = pool.allocate(&pool_info(0x100, 1)).unwrap();
+        assert_eq!(filler.offset(), 0x100);
+
+        // Full blocks and large allocations get blocks of their own, released once empty.
+        let rest = pool.allocate(&pool_info(0xf000, 0x1000)).unwrap();
+        assert_eq!(rest.offset(), 0);
+        let large = pool.allocate(&pool_info(0x18000, 0x1000)).unwrap();
+        assert_eq!(large.buffer().size(), 0x20000);
+        assert_eq!(pool.stats().block_count, 3);
+        drop(rest);
+        drop(large);
+        assert_eq!(pool.stats().block_count, 1);
+
+        drop((small, aligned, filler));
+        assert_eq!(pool.stats().allocation_count, 0);
+    }
+
+    #[test]
+    fn test_buffer_pool_invalid_args() {
+        let device = fake::physical_device().create_device().unwrap();
+        let pool = MagmaBufferPool::new(&device, Default::default());
+
+        let mut flagged = pool_info(0x1000, 0x1000);
+        flagged.common_flags = MAGMA_BUFFER_FLAG_EXTERNAL;
+        for create_info in [
+            flagged,
+            pool_info(0, 0x1000),
+            pool_info(0x1000, 0x1800),
+            pool_info(u64::MAX, 0x1000),
+        ] {
+            assert!(matches!(
+                pool.allocate(&create_info),
+                Err(MagmaError::InvalidArgs)
+            ));
+        }
+        assert_eq!(pool.stats().block_count, 0);
+    }
+
+    #[test]
+    fn test_memory_type_properties() {
+        let memory_type = |property_flags| MagmaMemoryType {
+            property_flags,
+            heap_idx: 0,
+        };
+
+        // Host memory of integrated GPUs and GTT are coherent without being device local.
+        let gtt = memory_type(
+            MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT | MAGMA_MEMORY_PROPERTY_HOST_COHERENT_BIT,
+        );
+        assert!(!gtt.is_device_local());
+        assert!(gtt.is_host_visible());
+        assert!(gtt.is_coherent());
+
+        let vram = memory_type(MAGMA_MEMORY_PROPERTY_DEVICE_LOCAL_BIT);
+        assert!(vram.is_device_local());
+        assert!(!vram.is_host_visible());
+        assert!(!vram.is_coherent());
+
+        let bar = memory_type(
+            MAGMA_MEMORY_PROPERTY_DEVICE_LOCAL_BIT
+                | MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT
+                | MAGMA_MEMORY_PROPERTY_HOST_COHERENT_BIT,
+        );
+        assert!(bar.is_device_local());
+        assert!(bar.is_host_visible());
+    }
 }
@@ -65,6 +65,9 @@ pub struct MagmaPciBusInfo {
 // Should be set in the case of VRAM only
 pub const MAGMA_HEAP_DEVICE_LOCAL_BIT: u64 = 0x00000001;
 pub const MAGMA_HEAP_CPU_VISIBLE_BIT: u64 = 0x00000010;
+// Set if buffer contents survive the device being suspended, either because the heap is system
+// memory or because the kernel driver evicts it to system memory on suspend.
+pub const MAGMA_HEAP_PRESERVED_ON_SUSPEND_BIT: u64 = 0x00000100;
 #[repr(C)]
 #[derive(Clone, Default, Debug, IntoBytes, FromBytes)]
 pub struct MagmaHeap {
@@ -80,6 +83,10 @@ impl MagmaHeap {
     pub fn is_cpu_visible(&self) -> bool {
         self.heap_flags & MAGMA_HEAP_CPU_VISIBLE_BIT != 0
     }
+
+    pub fn is_preserved_on_suspend(&self) -> bool {
+        self.heap_flags & MAGMA_HEAP_PRESERVED_ON_SUSPEND_BIT != 0
+    }
 }
 
 pub const MAGMA_MEMORY_PROPERTY_DEVICE_LOCAL_BIT: u32 = 0x00000001;
@@ -97,7 +104,11 @@ pub struct MagmaMemoryType {
 
 impl MagmaMemoryType {
     pub fn is_device_local(&self) -> bool {
-        self.property_flags & MAGMA_MEMORY_PROPERTY_HOST_COHERENT_BIT != 0
+        self.property_flags & MAGMA_MEMORY_PROPERTY_DEVICE_LOCAL_BIT != 0
+    }
+
+    pub fn is_host_visible(&self) -> bool {
+        self.property_flags & MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT != 0
     }
 
     pub fn is_coherent(&self) -> bool {
@@ -140,6 +151,13 @@ impl MagmaMemoryProperties {
         self.memory_type_count += 1;
     }
 
+    /// For drivers whose kernel driver saves every heap across device suspend.
+    pub(crate) fn set_all_heaps_preserved_on_suspend(&mut self) {
+        for heap in &mut self.memory_heaps[..self.memory_heap_count as usize] {
+            heap.heap_flags |= MAGMA_HEAP_PRESERVED_ON_SUSPEND_BIT;
+        }
+    }
+
     pub(crate) fn get_memory_heap(&self, heap_idx: u32) -> &MagmaHeap {
         &self.memory_heaps[heap_idx as usize]
     }
@@ -156,11 +174,72 @@ pub struct MagmaHeapBudget {
     pub usage: u64,
 }
 
+/// The scheduling priority of a context, relative to other contexts on the device.  Drivers
+/// without priorities schedule every context at `Medium`.  Raising the priority may need
+/// privileges the process lacks, in which case the context falls back to `Medium`.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum MagmaContextPriority {
+    Low,
+    #[default]
+    Medium,
+    High,
+}
+
+/// A class of hardware engine.  A context submits to engines of the class it was created for.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum MagmaEngineClass {
+    #[default]
+    Render,
+    Copy,
+    Compute,
+    Video,
+}
+
+/// The number of engines of a class on a device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MagmaEngineInfo {
+    pub engine_class: MagmaEngineClass,
+    pub count: u32,
+}
+
+impl MagmaEngineInfo {
+    /// Adds `count` engines of `engine_class` to `engines`, merging with an existing entry.
+    pub(crate) fn add(
+        engines: &mut Vec<MagmaEngineInfo>,
+        engine_class: MagmaEngineClass,
+        count: u32,
+    ) {
+        if count == 0 {
+            return;
+        }
+
+        match engines.iter_mut().find(|e| e.engine_class == engine_class) {
+            Some(info) => info.count += count,
+            None => engines.push(MagmaEngineInfo {
+                engine_class,
+                count,
+            }),
+        }
+    }
+}
+
+/// The result of `MagmaDevice::query`.  Scalar queries return a value, and queries that fill in a
+/// driver structure return its bytes as the kernel wrote them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MagmaQueryResult {
+    Value(u64),
+    Buffer(Vec<u8>),
+}
+
 // Common allocation flags
 //  - MAGMA_BUFFER_FLAG_EXTERNAL: The buffer *may* be exported as an OS-specific handle
 //  - MAGMA_BUFFER_FLAG_SCANOUT: The buffer *may* be used by the scanout engine directly
+//  - MAGMA_BUFFER_FLAG_SPARSE: The buffer only reserves GPU virtual address space and has no
+//                              backing pages, for Vulkan sparse binding.  It can't be mapped by
+//                              the CPU or exported, and reads of its GPU mappings return zero.
 pub const MAGMA_BUFFER_FLAG_EXTERNAL: u32 = 0x000000001;
 pub const MAGMA_BUFFER_FLAG_SCANOUT: u32 = 0x000000002;
+pub const MAGMA_BUFFER_FLAG_SPARSE: u32 = 0x000000004;
 
 // Acceptable buffer vendor flags if the vendor is AMD:
 //  - MAGMA_BUFFER_FLAG_AMD_FLAG_OA: Ordered append, used by 3D/Compute engines
@@ -169,6 +248,28 @@ pub const MAGMA_BUFFER_FLAG_SCANOUT: u32 = 0x000000002;
 pub const MAGMA_BUFFER_FLAG_AMD_OA: u32 = 0x000000001;
 pub const MAGMA_BUFFER_FLAG_AMD_GDS: u32 = 0x000000002;
 
+// GPU virtual address mapping flags
+//  - MAGMA_MAP_GPU_FLAG_READ: The GPU may read from the mapping
+//  - MAGMA_MAP_GPU_FLAG_WRITE: The GPU may write to the mapping
+//  - MAGMA_MAP_GPU_FLAG_EXECUTE: The GPU may execute shaders or command buffers from the mapping
+pub const MAGMA_MAP_GPU_FLAG_READ: u64 = 1 << 0;
+pub const MAGMA_MAP_GPU_FLAG_WRITE: u64 = 1 << 1;
+pub const MAGMA_MAP_GPU_FLAG_EXECUTE: u64 = 1 << 2;
+pub const MAGMA_MAP_GPU_FLAGS: u64 =
+    MAGMA_MAP_GPU_FLAG_READ | MAGMA_MAP_GPU_FLAG_WRITE | MAGMA_MAP_GPU_FLAG_EXECUTE;
+
+// Device creation flags
+//  - MAGMA_DEVICE_FLAG_USER_GPU_VA: The user-mode driver lays out the GPU virtual address space of
+//                                   its contexts with map_gpu/unmap_gpu.  Some kernels, such as
+//                                   msm, stop placing buffers themselves once this is enabled, so
+//                                   it is only done on request.  Fails with `Unsupported` on
+//                                   drivers that can't hand the address space to userspace.
+pub const MAGMA_DEVICE_FLAG_USER_GPU_VA: u32 = 0x000000001;
+pub const MAGMA_DEVICE_FLAGS: u32 = MAGMA_DEVICE_FLAG_USER_GPU_VA;
+
+// Size of the shared page backing a user fence
+pub const MAGMA_USER_FENCE_SIZE: u64 = 4096;
+
 pub const MAGMA_SYNC_WHOLE_RANGE: u64 = 1 << 0;
 pub const MAGMA_SYNC_RANGES: u64 = 1 << 1;
 pub const MAGMA_SYNC_INVALIDATE_READ: u64 = 1 << 2;
@@ -196,6 +297,11 @@ pub const MAGMA_VENDOR_ID_INTEL: u16 = 0x8086;
 pub const MAGMA_VENDOR_ID_AMD: u16 = 0x1002;
 pub const MAGMA_VENDOR_ID_MALI: u16 = 0x13B5;
 pub const MAGMA_VENDOR_ID_QCOM: u16 = 0x5413;
+pub const MAGMA_VENDOR_ID_VIRTIO: u16 = 0x1AF4;
+
+// PCI domain reported for platform (non-PCI) devices, such as most ARM GPUs.  The device number is
+// then the minor of the DRM render node rather than a PCI slot.
+pub const MAGMA_PCI_DOMAIN_PLATFORM: u16 = 0xffff;
 
 use mesa3d_util::MesaHandle;
 
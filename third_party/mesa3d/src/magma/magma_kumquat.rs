@@ -8,7 +8,9 @@ use mesa3d_util::MesaResult;
 use virtgpu_kumquat::VirtGpuKumquat;
 
 use crate::magma::MagmaPhysicalDevice;
+use crate::magma_defines::MagmaContextPriority;
 use crate::magma_defines::MagmaCreateBufferInfo;
+use crate::magma_defines::MagmaEngineClass;
 use crate::magma_defines::MagmaHeapBudget;
 use crate::magma_defines::MagmaImportHandleInfo;
 use crate::magma_defines::MagmaMemoryProperties;
@@ -49,6 +51,7 @@ impl GenericPhysicalDevice for MagmaKumquat {
         &self,
         physical_device: &Arc<dyn PhysicalDevice>,
         _pci_info: &MagmaPciInfo,
+        _flags: u32,
     ) -> MesaResult<Arc<dyn Device>> {
         let _virtgpu = physical_device.as_virtgpu().unwrap();
         Err(MesaError::Unsupported)
@@ -64,7 +67,12 @@ impl GenericDevice for MagmaKumquat {
         Err(MesaError::Unsupported)
     }
 
-    fn create_context(&self, _device: &Arc<dyn Device>) -> MesaResult<Arc<dyn Context>> {
+    fn create_context(
+        &self,
+        _device: &Arc<dyn Device>,
+        _priority: MagmaContextPriority,
+        _engine_class: MagmaEngineClass,
+    ) -> MesaResult<Arc<dyn Context>> {
         Err(MesaError::Unsupported)
     }
 
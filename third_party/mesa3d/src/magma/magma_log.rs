@@ -0,0 +1,88 @@
+// Copyright 2025 Google
+// SPDX-License-Identifier: MIT
+
+//! Routes the log messages of magma to a handler set by the embedder, or to the `log` crate if
+//! there is none.
+
+use std::fmt;
+use std::sync::RwLock;
+
+use log::log;
+use log::Level;
+
+/// Receives the log messages of magma.
+pub type MagmaLogHandler = Box<dyn Fn(Level, fmt::Arguments) + Send + Sync>;
+
+static LOG_HANDLER: RwLock<Option<MagmaLogHandler>> = RwLock::new(None);
+
+/// Sends the log messages of magma to `handler`, or to the `log` crate if it's None.  The handler
+/// is shared by every device of the process, and must not log through magma itself.
+pub fn magma_set_log_handler(handler: Option<MagmaLogHandler>) {
+    *LOG_HANDLER.write().unwrap() = handler;
+}
+
+/// Logs `args` at `level` through the handler set with `magma_set_log_handler()`.
+pub fn magma_log(level: Level, args: fmt::Arguments) {
+    match &*LOG_HANDLER.read().unwrap() {
+        Some(handler) => handler(level, args),
+        None => log!(level, "{}", args),
+    }
+}
+
+#[macro_export]
+macro_rules! magma_warn {
+    ($($arg:tt)+) => {
+        $crate::magma_log(log::Level::Warn, format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! magma_error {
+    ($($arg:tt)+) => {
+        $crate::magma_log(log::Level::Error, format_args!($($arg)+))
+    };
+}
+
+/// Logs the error of a `MesaResult`, if any.
+#[macro_export]
+macro_rules! magma_log_status {
+    ($result:expr) => {
+        match $result {
+            Ok(_) => (),
+            Err(e) => $crate::magma_error!("Error recieved: {}", e),
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn log_handler() {
+        let messages: Arc<Mutex<Vec<(Level, String)>>> = Default::default();
+        let handler_messages = messages.clone();
+        magma_set_log_handler(Some(Box::new(move |level, args| {
+            handler_messages
+                .lock()
+                .unwrap()
+                .push((level, args.to_string()));
+        })));
+
+        magma_warn!("denied {}", 1);
+        magma_error!("failed");
+        magma_set_log_handler(None);
+        magma_error!("goes to the log crate");
+
+        assert_eq!(
+            *messages.lock().unwrap(),
+            [
+                (Level::Warn, "denied 1".to_string()),
+                (Level::Error, "failed".to_string()),
+            ]
+        );
+    }
+}
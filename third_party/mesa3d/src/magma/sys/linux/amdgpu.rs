@@ -1,11 +1,14 @@
 // Copyright 2025 Google
 // SPDX-License-Identifier: MIT
 
+use std::mem::size_of;
+use std::mem::size_of_val;
 use std::os::fd::BorrowedFd;
+use std::ptr::copy_nonoverlapping;
+use std::sync::atomic::fence;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
-use log::error;
-use mesa3d_util::log_status;
 use mesa3d_util::MappedRegion;
 use mesa3d_util::MesaError;
 use mesa3d_util::MesaHandle;
@@ -13,16 +16,25 @@ use mesa3d_util::MesaResult;
 
 use crate::ioctl_readwrite;
 use crate::ioctl_write_ptr;
+use crate::magma_log_status;
+use crate::magma_warn;
 
+use crate::magma_defines::MagmaContextPriority;
 use crate::magma_defines::MagmaCreateBufferInfo;
+use crate::magma_defines::MagmaEngineClass;
+use crate::magma_defines::MagmaEngineInfo;
 use crate::magma_defines::MagmaHeapBudget;
 use crate::magma_defines::MagmaImportHandleInfo;
 use crate::magma_defines::MagmaMappedMemoryRange;
 use crate::magma_defines::MagmaMemoryProperties;
+use crate::magma_defines::MagmaQueryResult;
 use crate::magma_defines::MAGMA_BUFFER_FLAG_AMD_GDS;
 use crate::magma_defines::MAGMA_BUFFER_FLAG_AMD_OA;
 use crate::magma_defines::MAGMA_HEAP_CPU_VISIBLE_BIT;
 use crate::magma_defines::MAGMA_HEAP_DEVICE_LOCAL_BIT;
+use crate::magma_defines::MAGMA_MAP_GPU_FLAG_EXECUTE;
+use crate::magma_defines::MAGMA_MAP_GPU_FLAG_READ;
+use crate::magma_defines::MAGMA_MAP_GPU_FLAG_WRITE;
 use crate::magma_defines::MAGMA_MEMORY_PROPERTY_DEVICE_LOCAL_BIT;
 use crate::magma_defines::MAGMA_MEMORY_PROPERTY_HOST_CACHED_BIT;
 use crate::magma_defines::MAGMA_MEMORY_PROPERTY_HOST_COHERENT_BIT;
@@ -37,9 +49,17 @@ use crate::traits::Buffer;
 use crate::traits::Context;
 use crate::traits::Device;
 use crate::traits::GenericBuffer;
+use crate::traits::GenericContext;
 use crate::traits::GenericDevice;
 use crate::traits::PhysicalDevice;
 
+ioctl_readwrite!(
+    drm_ioctl_amdgpu_gem_wait_idle,
+    DRM_IOCTL_BASE,
+    DRM_COMMAND_BASE + DRM_AMDGPU_GEM_WAIT_IDLE,
+    drm_amdgpu_gem_wait_idle
+);
+
 ioctl_readwrite!(
     drm_ioctl_amdgpu_ctx,
     DRM_IOCTL_BASE,
@@ -96,6 +116,34 @@ amdgpu_info_ioctl!(
     u64
 );
 
+// The engine class of each AMDGPU_HW_IP_* type.  VPE, the video processing engine, isn't exposed.
+const AMDGPU_HW_IP_CLASSES: [(u32, MagmaEngineClass); 9] = [
+    (AMDGPU_HW_IP_GFX, MagmaEngineClass::Render),
+    (AMDGPU_HW_IP_COMPUTE, MagmaEngineClass::Compute),
+    (AMDGPU_HW_IP_DMA, MagmaEngineClass::Copy),
+    (AMDGPU_HW_IP_UVD, MagmaEngineClass::Video),
+    (AMDGPU_HW_IP_VCE, MagmaEngineClass::Video),
+    (AMDGPU_HW_IP_UVD_ENC, MagmaEngineClass::Video),
+    (AMDGPU_HW_IP_VCN_DEC, MagmaEngineClass::Video),
+    (AMDGPU_HW_IP_VCN_ENC, MagmaEngineClass::Video),
+    (AMDGPU_HW_IP_VCN_JPEG, MagmaEngineClass::Video),
+];
+
+// The AMDGPU_INFO queries `query` accepts, with the size of their result.  Queries that could leak
+// other processes' state, such as register reads, aren't included.
+const AMDGPU_QUERIES: [(u32, usize); 10] = [
+    (AMDGPU_INFO_ACCEL_WORKING, size_of::<u32>()),
+    (AMDGPU_INFO_HW_IP_INFO, size_of::<drm_amdgpu_info_hw_ip>()),
+    (AMDGPU_INFO_HW_IP_COUNT, size_of::<u32>()),
+    (AMDGPU_INFO_TIMESTAMP, size_of::<u64>()),
+    (AMDGPU_INFO_VRAM_USAGE, size_of::<u64>()),
+    (AMDGPU_INFO_GTT_USAGE, size_of::<u64>()),
+    (AMDGPU_INFO_VRAM_GTT, size_of::<drm_amdgpu_info_vram_gtt>()),
+    (AMDGPU_INFO_VIS_VRAM_USAGE, size_of::<u64>()),
+    (AMDGPU_INFO_DEV_INFO, size_of::<drm_amdgpu_info_device>()),
+    (AMDGPU_INFO_MEMORY, size_of::<drm_amdgpu_memory_info>()),
+];
+
 ioctl_readwrite!(
     drm_ioctl_amdgpu_gem_create,
     DRM_IOCTL_BASE,
@@ -110,14 +158,45 @@ ioctl_readwrite!(
     drm_amdgpu_gem_mmap
 );
 
+ioctl_write_ptr!(
+    drm_ioctl_amdgpu_gem_va,
+    DRM_IOCTL_BASE,
+    DRM_COMMAND_BASE + DRM_AMDGPU_GEM_VA,
+    drm_amdgpu_gem_va
+);
+
+// The amdgpu memory pool behind each magma heap.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum AmdGpuHeap {
+    Gtt,
+    // All of VRAM, when the CPU can access all of it through a resizable BAR.
+    Vram,
+    // The VRAM beyond a small BAR, and the part of VRAM inside it.
+    InvisibleVram,
+    VisibleVram,
+}
+
 pub struct AmdGpu {
     physical_device: Arc<dyn PhysicalDevice>,
     mem_props: MagmaMemoryProperties,
+    // Indexed by magma heap index.
+    heaps: Vec<AmdGpuHeap>,
 }
 
 struct AmdGpuContext {
     physical_device: Arc<dyn PhysicalDevice>,
     context_id: u32,
+    // amdgpu contexts aren't bound to an IP, which is chosen per submission, so the context
+    // records the AMDGPU_HW_IP_* types of its engine class for submissions to pick from.
+    _ip_types: Vec<u32>,
+}
+
+fn amdgpu_ip_types(engine_class: MagmaEngineClass) -> Vec<u32> {
+    AMDGPU_HW_IP_CLASSES
+        .iter()
+        .filter(|(_, class)| *class == engine_class)
+        .map(|(ip_type, _)| *ip_type)
+        .collect()
 }
 
 struct AmdGpuBuffer {
@@ -139,6 +218,13 @@ impl AmdGpu {
             drm_ioctl_amdgpu_info_memory(physical_device.as_fd().unwrap(), &mut memory_info)?;
         };
 
+        let mut heaps = Vec::new();
+        let vram_size = memory_info.vram.total_heap_size;
+        let visible_vram_size = memory_info.cpu_accessible_vram.total_heap_size;
+        // With a resizable BAR, the CPU accessible VRAM is all of VRAM.  Reporting it as a heap
+        // of its own would count the same memory twice.
+        let resizable_bar = vram_size > 0 && visible_vram_size >= vram_size;
+
         if memory_info.gtt.total_heap_size > 0 {
             mem_props.add_heap(memory_info.gtt.total_heap_size, MAGMA_HEAP_CPU_VISIBLE_BIT);
             mem_props.add_memory_type(
@@ -150,33 +236,52 @@ impl AmdGpu {
                     | MAGMA_MEMORY_PROPERTY_HOST_CACHED_BIT,
             );
             mem_props.increment_heap_count();
+            heaps.push(AmdGpuHeap::Gtt);
         }
 
-        if memory_info.vram.total_heap_size > 0 {
-            mem_props.add_heap(
-                memory_info.vram.total_heap_size,
-                MAGMA_HEAP_DEVICE_LOCAL_BIT,
-            );
-            mem_props.add_memory_type(MAGMA_MEMORY_PROPERTY_DEVICE_LOCAL_BIT);
-            mem_props.increment_heap_count();
-        }
-
-        if memory_info.cpu_accessible_vram.total_heap_size > 0 {
+        if resizable_bar {
             mem_props.add_heap(
-                memory_info.cpu_accessible_vram.total_heap_size,
+                vram_size,
                 MAGMA_HEAP_DEVICE_LOCAL_BIT | MAGMA_HEAP_CPU_VISIBLE_BIT,
             );
+            mem_props.add_memory_type(MAGMA_MEMORY_PROPERTY_DEVICE_LOCAL_BIT);
             mem_props.add_memory_type(
                 MAGMA_MEMORY_PROPERTY_DEVICE_LOCAL_BIT
                     | MAGMA_MEMORY_PROPERTY_HOST_COHERENT_BIT
                     | MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT,
             );
             mem_props.increment_heap_count();
+            heaps.push(AmdGpuHeap::Vram);
+        } else {
+            if vram_size > visible_vram_size {
+                mem_props.add_heap(vram_size - visible_vram_size, MAGMA_HEAP_DEVICE_LOCAL_BIT);
+                mem_props.add_memory_type(MAGMA_MEMORY_PROPERTY_DEVICE_LOCAL_BIT);
+                mem_props.increment_heap_count();
+                heaps.push(AmdGpuHeap::InvisibleVram);
+            }
+
+            if visible_vram_size > 0 {
+                mem_props.add_heap(
+                    visible_vram_size,
+                    MAGMA_HEAP_DEVICE_LOCAL_BIT | MAGMA_HEAP_CPU_VISIBLE_BIT,
+                );
+                mem_props.add_memory_type(
+                    MAGMA_MEMORY_PROPERTY_DEVICE_LOCAL_BIT
+                        | MAGMA_MEMORY_PROPERTY_HOST_COHERENT_BIT
+                        | MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT,
+                );
+                mem_props.increment_heap_count();
+                heaps.push(AmdGpuHeap::VisibleVram);
+            }
         }
 
+        // amdgpu evicts VRAM to system memory on both system and runtime suspend.
+        mem_props.set_all_heaps_preserved_on_suspend();
+
         Ok(AmdGpu {
             physical_device,
             mem_props,
+            heaps,
         })
     }
 }
@@ -186,69 +291,170 @@ impl GenericDevice for AmdGpu {
         Ok(self.mem_props.clone())
     }
 
+    // Budgets are the usable sizes reported by the kernel, and usage is that of every process.
+    // With a small BAR, the usage of the VRAM beyond it is what isn't visible to the CPU.
     fn get_memory_budget(&self, heap_idx: u32) -> MesaResult<MagmaHeapBudget> {
-        if heap_idx >= self.mem_props.memory_heap_count {
-            return Err(MesaError::WithContext("Heap Index out of bounds"));
-        }
+        let heap = *self
+            .heaps
+            .get(heap_idx as usize)
+            .ok_or(MesaError::WithContext("Heap Index out of bounds"))?;
 
+        let fd = self.physical_device.as_fd().unwrap();
         let mut vram_gtt: drm_amdgpu_info_vram_gtt = Default::default();
+        let mut usage: u64 = 0;
+        let mut visible_usage: u64 = 0;
 
         // SAFETY:
         // Valid arguments are supplied for the following arguments:
         //   - Underlying descriptor
-        //   - drm_amdgpu_memory_info_vram_gtt struct
+        //   - drm_amdgpu_info_vram_gtt struct, and u64 usages
         unsafe {
-            drm_ioctl_amdgpu_info_vram_gtt(self.physical_device.as_fd().unwrap(), &mut vram_gtt)?;
+            drm_ioctl_amdgpu_info_vram_gtt(fd, &mut vram_gtt)?;
+            match heap {
+                AmdGpuHeap::Gtt => drm_ioctl_amdgpu_info_gtt_usage(fd, &mut usage)?,
+                AmdGpuHeap::Vram | AmdGpuHeap::InvisibleVram => {
+                    drm_ioctl_amdgpu_info_vram_usage(fd, &mut usage)?
+                }
+                AmdGpuHeap::VisibleVram => {}
+            }
+            if heap == AmdGpuHeap::InvisibleVram || heap == AmdGpuHeap::VisibleVram {
+                drm_ioctl_amdgpu_info_vis_vram_usage(fd, &mut visible_usage)?;
+            }
         };
 
-        let budget: u64;
-        let mut usage: u64 = 0;
-        let heap = &self.mem_props.memory_heaps[heap_idx as usize];
+        let (budget, usage) = match heap {
+            AmdGpuHeap::Gtt => (vram_gtt.gtt_size, usage),
+            AmdGpuHeap::Vram => (vram_gtt.vram_size, usage),
+            AmdGpuHeap::InvisibleVram => (
+                vram_gtt
+                    .vram_size
+                    .saturating_sub(vram_gtt.vram_cpu_accessible_size),
+                usage.saturating_sub(visible_usage),
+            ),
+            AmdGpuHeap::VisibleVram => (vram_gtt.vram_cpu_accessible_size, visible_usage),
+        };
 
-        if heap.is_device_local() && heap.is_cpu_visible() {
-            budget = vram_gtt.vram_cpu_accessible_size;
+        Ok(MagmaHeapBudget { budget, usage })
+    }
 
-            // SAFETY:
-            // Valid arguments are supplied for the following arguments:
-            //   - Underlying descriptor
-            //   - usage
-            unsafe {
-                drm_ioctl_amdgpu_info_vis_vram_usage(
-                    self.physical_device.as_fd().unwrap(),
-                    &mut usage,
-                )?;
+    // Each ring of an IP is reported as an engine.
+    fn enumerate_engines(&self) -> MesaResult<Vec<MagmaEngineInfo>> {
+        let mut engines = Vec::new();
+        for (ip_type, engine_class) in AMDGPU_HW_IP_CLASSES {
+            let mut hw_ip: drm_amdgpu_info_hw_ip = Default::default();
+            let mut info = drm_amdgpu_info {
+                query: AMDGPU_INFO_HW_IP_INFO,
+                return_size: size_of::<drm_amdgpu_info_hw_ip>() as u32,
+                return_pointer: &mut hw_ip as *mut drm_amdgpu_info_hw_ip as __u64,
+                ..Default::default()
             };
-        } else if heap.is_device_local() {
-            budget = vram_gtt.vram_size;
+            info.__bindgen_anon_1.query_hw_ip.type_ = ip_type;
 
             // SAFETY:
             // Valid arguments are supplied for the following arguments:
             //   - Underlying descriptor
-            //   - usage
-            unsafe {
-                drm_ioctl_amdgpu_info_vram_usage(
-                    self.physical_device.as_fd().unwrap(),
-                    &mut usage,
-                )?;
-            };
-        } else if heap.is_cpu_visible() {
-            budget = vram_gtt.gtt_size;
-            // SAFETY:
-            // Valid arguments are supplied for the following arguments:
-            //   - Underlying descriptor
-            //   - usage
+            //   - drm_amdgpu_info struct, whose return pointer is a drm_amdgpu_info_hw_ip
             unsafe {
-                drm_ioctl_amdgpu_info_gtt_usage(self.physical_device.as_fd().unwrap(), &mut usage)?;
+                drm_ioctl_amdgpu_info(self.physical_device.as_fd().unwrap(), &info)?;
             };
-        } else {
-            return Err(MesaError::Unsupported);
+
+            MagmaEngineInfo::add(
+                &mut engines,
+                engine_class,
+                hw_ip.available_rings.count_ones(),
+            );
         }
 
-        Ok(MagmaHeapBudget { budget, usage })
+        Ok(engines)
     }
 
-    fn create_context(&self, _device: &Arc<dyn Device>) -> MesaResult<Arc<dyn Context>> {
-        let ctx = AmdGpuContext::new(self.physical_device.clone(), 0)?;
+    fn query(&self, id: u64, input: &[u8]) -> MesaResult<MagmaQueryResult> {
+        let (query, size) = AMDGPU_QUERIES
+            .iter()
+            .find(|(query, _)| u64::from(*query) == id)
+            .copied()
+            .ok_or(MesaError::Unsupported)?;
+
+        let mut info: drm_amdgpu_info = Default::default();
+        if input.len() > size_of_val(&info.__bindgen_anon_1) {
+            return Err(MesaError::WithContext("query input too large"));
+        }
+
+        // SAFETY:
+        // The union is plain data, and `input` was checked to fit in it.
+        unsafe {
+            copy_nonoverlapping(
+                input.as_ptr(),
+                &mut info.__bindgen_anon_1 as *mut _ as *mut u8,
+                input.len(),
+            );
+        }
+
+        let mut data = vec![0u8; size];
+        info.query = query;
+        info.return_size = size as u32;
+        info.return_pointer = data.as_mut_ptr() as __u64;
+
+        // SAFETY:
+        // Valid arguments are supplied for the following arguments:
+        //   - Underlying descriptor
+        //   - drm_amdgpu_info struct, whose return pointer covers `size` bytes
+        unsafe {
+            drm_ioctl_amdgpu_info(self.physical_device.as_fd().unwrap(), &info)?;
+        };
+
+        let result = match data[..] {
+            [a, b, c, d] => MagmaQueryResult::Value(u32::from_ne_bytes([a, b, c, d]).into()),
+            [a, b, c, d, e, f, g, h] => {
+                MagmaQueryResult::Value(u64::from_ne_bytes([a, b, c, d, e, f, g, h]))
+            }
+            _ => MagmaQueryResult::Buffer(data),
+        };
+
+        Ok(result)
+    }
+
+    fn create_context(
+        &self,
+        _device: &Arc<dyn Device>,
+        priority: MagmaContextPriority,
+        engine_class: MagmaEngineClass,
+    ) -> MesaResult<Arc<dyn Context>> {
+        let has_rings = self
+            .enumerate_engines()?
+            .iter()
+            .any(|engine| engine.engine_class == engine_class && engine.count != 0);
+        if !has_rings {
+            return Err(MesaError::WithContext("no amdgpu ring of the class"));
+        }
+
+        let ip_types = amdgpu_ip_types(engine_class);
+        let amdgpu_priority = match priority {
+            MagmaContextPriority::Low => AMDGPU_CTX_PRIORITY_LOW,
+            MagmaContextPriority::Medium => AMDGPU_CTX_PRIORITY_NORMAL as i32,
+            MagmaContextPriority::High => AMDGPU_CTX_PRIORITY_HIGH as i32,
+        };
+
+        // Priorities above normal need CAP_SYS_NICE or DRM master.
+        let ctx = match AmdGpuContext::new(
+            self.physical_device.clone(),
+            amdgpu_priority,
+            ip_types.clone(),
+        ) {
+            Err(MesaError::IoError(e))
+                if priority == MagmaContextPriority::High
+                    && e.raw_os_error() == Some(libc::EACCES) =>
+            {
+                magma_warn!("high priority amdgpu context denied, using normal priority");
+                AmdGpuContext::new(
+                    self.physical_device.clone(),
+                    AMDGPU_CTX_PRIORITY_NORMAL as i32,
+                    ip_types,
+                )?
+            }
+            result => result?,
+        };
+
         Ok(Arc::new(ctx))
     }
 
@@ -274,15 +480,28 @@ impl GenericDevice for AmdGpu {
         )?;
         Ok(Arc::new(buf))
     }
+
+    fn supports_sparse_buffers(&self) -> bool {
+        true
+    }
+
+    fn supports_buffer_placement(&self) -> bool {
+        true
+    }
 }
 
 impl Device for AmdGpu {}
 impl PlatformDevice for AmdGpu {}
 
 impl AmdGpuContext {
-    fn new(physical_device: Arc<dyn PhysicalDevice>, _priority: i32) -> MesaResult<AmdGpuContext> {
+    fn new(
+        physical_device: Arc<dyn PhysicalDevice>,
+        priority: i32,
+        ip_types: Vec<u32>,
+    ) -> MesaResult<AmdGpuContext> {
         let mut ctx_arg = drm_amdgpu_ctx::default();
         ctx_arg.in_.op = AMDGPU_CTX_OP_ALLOC_CTX;
+        ctx_arg.in_.priority = priority;
 
         // SAFETY:
         // Valid arguments are supplied for the following arguments:
@@ -296,8 +515,21 @@ impl AmdGpuContext {
         Ok(AmdGpuContext {
             physical_device,
             context_id,
+            _ip_types: ip_types,
         })
     }
+
+    fn gem_va(&self, gem_va: &drm_amdgpu_gem_va) -> MesaResult<()> {
+        // SAFETY:
+        // Valid arguments are supplied for the following arguments:
+        //   - Underlying descriptor
+        //   - drm_amdgpu_gem_va struct
+        unsafe {
+            drm_ioctl_amdgpu_gem_va(self.physical_device.as_fd().unwrap(), gem_va)?;
+        };
+
+        Ok(())
+    }
 }
 
 impl Drop for AmdGpuContext {
@@ -312,7 +544,69 @@ impl Drop for AmdGpuContext {
         //   - drm_amdgpu_ctx struct
         let result =
             unsafe { drm_ioctl_amdgpu_ctx(self.physical_device.as_fd().unwrap(), &mut ctx_arg) };
-        log_status!(result);
+        magma_log_status!(result);
+    }
+}
+
+// amdgpu has a single GPU address space per file description, shared by all of its contexts.
+impl GenericContext for AmdGpuContext {
+    fn map_gpu(&self, buffer: &Arc<dyn Buffer>, gpu_va: u64, flags: u64) -> MesaResult<()> {
+        // Partially resident texture mappings have no backing BO.
+        if buffer.is_sparse() {
+            let gem_va = drm_amdgpu_gem_va {
+                handle: 0,
+                operation: AMDGPU_VA_OP_MAP,
+                flags: AMDGPU_VM_PAGE_PRT,
+                va_address: gpu_va,
+                offset_in_bo: 0,
+                map_size: buffer.size(),
+                ..Default::default()
+            };
+
+            return self.gem_va(&gem_va);
+        }
+
+        let mut gem_va = drm_amdgpu_gem_va {
+            handle: buffer.gem_handle()?,
+            operation: AMDGPU_VA_OP_MAP,
+            va_address: gpu_va,
+            offset_in_bo: 0,
+            map_size: buffer.size(),
+            ..Default::default()
+        };
+
+        if flags & MAGMA_MAP_GPU_FLAG_READ != 0 {
+            gem_va.flags |= AMDGPU_VM_PAGE_READABLE;
+        }
+
+        if flags & MAGMA_MAP_GPU_FLAG_WRITE != 0 {
+            gem_va.flags |= AMDGPU_VM_PAGE_WRITEABLE;
+        }
+
+        if flags & MAGMA_MAP_GPU_FLAG_EXECUTE != 0 {
+            gem_va.flags |= AMDGPU_VM_PAGE_EXECUTABLE;
+        }
+
+        self.gem_va(&gem_va)
+    }
+
+    fn unmap_gpu(&self, buffer: &Arc<dyn Buffer>, gpu_va: u64) -> MesaResult<()> {
+        let handle = if buffer.is_sparse() {
+            0
+        } else {
+            buffer.gem_handle()?
+        };
+
+        let gem_va = drm_amdgpu_gem_va {
+            handle,
+            operation: AMDGPU_VA_OP_UNMAP,
+            va_address: gpu_va,
+            offset_in_bo: 0,
+            map_size: buffer.size(),
+            ..Default::default()
+        };
+
+        self.gem_va(&gem_va)
     }
 }
 
@@ -330,7 +624,7 @@ impl AmdGpuBuffer {
         let memory_type = mem_props.get_memory_type(create_info.memory_type_idx);
 
         gem_create_in.bo_size = create_info.size;
-        // FIXME: gpu_info.pte_fragment_size, alignment
+        // FIXME: gpu_info.pte_fragment_size
         // Need GPU topology crate
         gem_create_in.alignment = create_info.alignment as u64;
 
@@ -338,10 +632,13 @@ impl AmdGpuBuffer {
         gem_create_in.domain_flags |= AMDGPU_GEM_CREATE_EXPLICIT_SYNC as u64;
         gem_create_in.domain_flags |= AMDGPU_GEM_CREATE_DISCARDABLE as u64;
 
-        if memory_type.is_coherent() {
-            gem_create_in.domain_flags |= AMDGPU_GEM_CREATE_CPU_GTT_USWC as u64;
-        } else {
+        // CPU visible VRAM must be placed inside a small BAR.
+        if !memory_type.is_host_visible() {
             gem_create_in.domain_flags |= AMDGPU_GEM_CREATE_NO_CPU_ACCESS as u64;
+        } else if memory_type.is_device_local() {
+            gem_create_in.domain_flags |= AMDGPU_GEM_CREATE_CPU_ACCESS_REQUIRED as u64;
+        } else if !memory_type.is_cached() {
+            gem_create_in.domain_flags |= AMDGPU_GEM_CREATE_CPU_GTT_USWC as u64;
         }
 
         if memory_type.is_protected() {
@@ -411,12 +708,41 @@ impl GenericBuffer for AmdGpuBuffer {
         self.physical_device.export(self.gem_handle)
     }
 
+    // Every amdgpu memory type is coherent: GTT is snooped or write-combined, and CPU-visible VRAM
+    // is write-combined.  Invalidation only waits for the GPU to stop writing the buffer, and
+    // flushing drains the write-combining buffers.
     fn invalidate(&self, _sync_flags: u64, _ranges: &[MagmaMappedMemoryRange]) -> MesaResult<()> {
-        Err(MesaError::Unsupported)
+        let mut wait_idle: drm_amdgpu_gem_wait_idle = Default::default();
+        wait_idle.in_ = drm_amdgpu_gem_wait_idle_in {
+            handle: self.gem_handle,
+            flags: 0,
+            // The kernel treats the largest timeout as infinite.
+            timeout: u64::MAX,
+        };
+
+        // SAFETY:
+        // Valid arguments are supplied for the following arguments:
+        //   - Underlying descriptor
+        //   - drm_amdgpu_gem_wait_idle union
+        unsafe {
+            drm_ioctl_amdgpu_gem_wait_idle(self.physical_device.as_fd().unwrap(), &mut wait_idle)?;
+        }
+
+        fence(Ordering::SeqCst);
+        Ok(())
     }
 
     fn flush(&self, _sync_flags: u64, _ranges: &[MagmaMappedMemoryRange]) -> MesaResult<()> {
-        Err(MesaError::Unsupported)
+        fence(Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.size as u64
+    }
+
+    fn gem_handle(&self) -> MesaResult<u32> {
+        Ok(self.gem_handle)
     }
 }
 
@@ -436,3 +762,22 @@ unsafe impl Sync for AmdGpuContext {}
 
 unsafe impl Send for AmdGpuBuffer {}
 unsafe impl Sync for AmdGpuBuffer {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ip_types_of_engine_class() {
+        assert_eq!(
+            amdgpu_ip_types(MagmaEngineClass::Render),
+            [AMDGPU_HW_IP_GFX]
+        );
+        assert_eq!(
+            amdgpu_ip_types(MagmaEngineClass::Compute),
+            [AMDGPU_HW_IP_COMPUTE]
+        );
+        assert_eq!(amdgpu_ip_types(MagmaEngineClass::Copy), [AMDGPU_HW_IP_DMA]);
+        assert_eq!(amdgpu_ip_types(MagmaEngineClass::Video).len(), 6);
+    }
+}
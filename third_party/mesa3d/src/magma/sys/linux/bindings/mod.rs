@@ -5,4 +5,5 @@ pub mod amdgpu_bindings;
 pub mod drm_bindings;
 pub mod i915_bindings;
 pub mod msm_bindings;
+pub mod virtgpu_bindings;
 pub mod xe_bindings;
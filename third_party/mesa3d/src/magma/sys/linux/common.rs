@@ -10,9 +10,8 @@ use std::os::fd::BorrowedFd;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::Mutex;
 
-use log::error;
-use mesa3d_util::log_status;
 use mesa3d_util::AsRawDescriptor;
 use mesa3d_util::FromRawDescriptor;
 use mesa3d_util::MemoryMapping;
@@ -38,9 +37,14 @@ use libc::O_RDWR;
 use crate::magma::MagmaPhysicalDevice;
 use crate::magma_defines::MagmaPciBusInfo;
 use crate::magma_defines::MagmaPciInfo;
+use crate::magma_defines::MAGMA_DEVICE_FLAG_USER_GPU_VA;
+use crate::magma_defines::MAGMA_PCI_DOMAIN_PLATFORM;
 use crate::magma_defines::MAGMA_VENDOR_ID_AMD;
 use crate::magma_defines::MAGMA_VENDOR_ID_INTEL;
+use crate::magma_defines::MAGMA_VENDOR_ID_MALI;
 use crate::magma_defines::MAGMA_VENDOR_ID_QCOM;
+use crate::magma_defines::MAGMA_VENDOR_ID_VIRTIO;
+use crate::magma_log_status;
 
 use crate::sys::linux::bindings::drm_bindings::drm_gem_close;
 use crate::sys::linux::bindings::drm_bindings::drm_prime_handle;
@@ -50,6 +54,7 @@ use crate::sys::linux::drm_ioctl_prime_handle_to_fd;
 use crate::sys::linux::get_drm_device_name;
 use crate::sys::linux::AmdGpu;
 use crate::sys::linux::Msm;
+use crate::sys::linux::VirtGpu;
 use crate::sys::linux::Xe;
 use crate::sys::linux::DRM_DIR_NAME;
 use crate::sys::linux::DRM_RENDER_MINOR_NAME;
@@ -72,6 +77,8 @@ const PCI_ATTRS: [&str; 5] = [
 pub struct LinuxPhysicalDevice {
     descriptor: OwnedDescriptor,
     name: String,
+    // Whether init_once() has succeeded on the descriptor.
+    initialized: Mutex<bool>,
 }
 
 #[allow(dead_code)]
@@ -97,6 +104,12 @@ pub trait PlatformPhysicalDevice {
     }
 
     fn close(&self, _gem_handle: u32) {}
+
+    /// Runs `init` for state that belongs to the device file rather than to a device, such as a
+    /// virtio-gpu context.  Once `init` succeeds, later calls return without running it.
+    fn init_once(&self, init: &mut dyn FnMut() -> MesaResult<()>) -> MesaResult<()> {
+        init()
+    }
 }
 
 impl GenericPhysicalDevice for LinuxPhysicalDevice {
@@ -104,18 +117,25 @@ impl GenericPhysicalDevice for LinuxPhysicalDevice {
         &self,
         physical_device: &Arc<dyn PhysicalDevice>,
         pci_info: &MagmaPciInfo,
+        flags: u32,
     ) -> MesaResult<Arc<dyn Device>> {
+        let user_gpu_va = flags & MAGMA_DEVICE_FLAG_USER_GPU_VA != 0;
         let device: Arc<dyn Device> = match pci_info.vendor_id {
             MAGMA_VENDOR_ID_AMD => Arc::new(AmdGpu::new(physical_device.clone())?),
-            MAGMA_VENDOR_ID_QCOM => Arc::new(Msm::new(physical_device.clone())),
+            MAGMA_VENDOR_ID_QCOM => Arc::new(Msm::new(physical_device.clone(), user_gpu_va)?),
+            MAGMA_VENDOR_ID_VIRTIO => Arc::new(VirtGpu::new(physical_device.clone())?),
             MAGMA_VENDOR_ID_INTEL => {
                 if self.name == "xe" {
                     Arc::new(Xe::new(physical_device.clone(), pci_info)?)
+                } else if user_gpu_va {
+                    // Upstream i915 has no VM_BIND uapi, and only places buffers at execbuffer
+                    // time.
+                    return Err(MesaError::Unsupported);
                 } else {
                     Arc::new(I915::new(physical_device.clone())?)
                 }
             }
-            _ => todo!(),
+            _ => return Err(MesaError::Unsupported),
         };
 
         Ok(device)
@@ -136,7 +156,11 @@ impl LinuxPhysicalDevice {
         let name = get_drm_device_name(&descriptor)?;
         println!("the name is {}", name);
 
-        Ok(LinuxPhysicalDevice { descriptor, name })
+        Ok(LinuxPhysicalDevice {
+            descriptor,
+            name,
+            initialized: Mutex::new(false),
+        })
     }
 }
 
@@ -179,7 +203,13 @@ impl PlatformPhysicalDevice for LinuxPhysicalDevice {
         })
     }
 
+    // Only PRIME is used for sharing.  DRM_IOCTL_GEM_FLINK and DRM_IOCTL_GEM_OPEN expose buffers to
+    // every client of the device by a guessable global name, so they must never be used.
     fn import(&self, handle: MesaHandle) -> MesaResult<u32> {
+        if handle.handle_type != MESA_HANDLE_TYPE_MEM_DMABUF {
+            return Err(MesaError::Unsupported);
+        }
+
         let mut arg: drm_prime_handle = drm_prime_handle {
             ..Default::default()
         };
@@ -209,7 +239,16 @@ impl PlatformPhysicalDevice for LinuxPhysicalDevice {
         //   - drm_gem_handle
         let result = unsafe { drm_ioctl_gem_close(self.descriptor.as_fd(), &arg) };
 
-        log_status!(result);
+        magma_log_status!(result);
+    }
+
+    fn init_once(&self, init: &mut dyn FnMut() -> MesaResult<()>) -> MesaResult<()> {
+        let mut initialized = self.initialized.lock().unwrap();
+        if !*initialized {
+            init()?;
+            *initialized = true;
+        }
+        Ok(())
     }
 }
 
@@ -222,6 +261,77 @@ fn parse_hex_u16(s: &str) -> MesaResult<u16> {
     Ok(u16::from_str_radix(valid_str, 16)?)
 }
 
+// Parses a PCI_SLOT_NAME of the form "dddd:bb:dd.f", where every field is hexadecimal.
+fn parse_pci_slot_name(slot_name: &str) -> MesaResult<MagmaPciBusInfo> {
+    let invalid = || MesaError::WithContext("invalid PCI_SLOT_NAME");
+    let (domain, rest) = slot_name.trim().split_once(':').ok_or_else(invalid)?;
+    let (bus, rest) = rest.split_once(':').ok_or_else(invalid)?;
+    let (device, function) = rest.split_once('.').ok_or_else(invalid)?;
+
+    Ok(MagmaPciBusInfo {
+        domain: u16::from_str_radix(domain, 16)?,
+        bus: u8::from_str_radix(bus, 16)?,
+        device: u8::from_str_radix(device, 16)?,
+        function: u8::from_str_radix(function, 16)?,
+        ..Default::default()
+    })
+}
+
+// Platform and virtio devices have no PCI IDs, so the vendor is inferred from the kernel driver.
+// Display-only drivers aren't GPUs and are skipped.
+fn platform_vendor_id(driver_name: &str) -> Option<u16> {
+    match driver_name {
+        "msm" => Some(MAGMA_VENDOR_ID_QCOM),
+        "panfrost" | "panthor" => Some(MAGMA_VENDOR_ID_MALI),
+        "virtio_gpu" => Some(MAGMA_VENDOR_ID_VIRTIO),
+        _ => None,
+    }
+}
+
+// Returns the size of system memory, for GPUs that share it with the CPU.
+pub fn system_memory_size() -> MesaResult<u64> {
+    // SAFETY: sysinfo only writes to the zero-initialized struct it is given.
+    let info = unsafe {
+        let mut info: libc::sysinfo = std::mem::zeroed();
+        if libc::sysinfo(&mut info) != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        info
+    };
+
+    Ok((info.totalram as u64).saturating_mul(info.mem_unit.into()))
+}
+
+fn enumerate_pci_device(device_dir: &str) -> MesaResult<(MagmaPciInfo, MagmaPciBusInfo)> {
+    let mut pci_info: MagmaPciInfo = Default::default();
+    let mut pci_bus_info: MagmaPciBusInfo = Default::default();
+    for attr in PCI_ATTRS {
+        let attr_path = format!("{}/{}", device_dir, attr);
+        let mut file = File::open(attr_path)?;
+        let mut hex_string = String::new();
+        file.read_to_string(&mut hex_string)?;
+
+        match attr {
+            "revision" => pci_info.revision_id = parse_hex_u16(&hex_string)?.try_into()?,
+            "vendor" => pci_info.vendor_id = parse_hex_u16(&hex_string)?,
+            "device" => pci_info.device_id = parse_hex_u16(&hex_string)?,
+            "subsystem_vendor" => pci_info.subvendor_id = parse_hex_u16(&hex_string)?,
+            "subsystem_device" => pci_info.subdevice_id = parse_hex_u16(&hex_string)?,
+            _ => unimplemented!(),
+        }
+    }
+
+    let uevent_path = format!("{}/uevent", device_dir);
+    let text: String = fs::read_to_string(uevent_path)?;
+    for line in text.lines() {
+        if let Some(slot_name) = line.strip_prefix("PCI_SLOT_NAME=") {
+            pci_bus_info = parse_pci_slot_name(slot_name)?;
+        }
+    }
+
+    Ok((pci_info, pci_bus_info))
+}
+
 pub fn enumerate_devices() -> MesaResult<Vec<MagmaPhysicalDevice>> {
     let mut devices: Vec<MagmaPhysicalDevice> = Vec::new();
     let dir_fd = open(
@@ -245,57 +355,131 @@ pub fn enumerate_devices() -> MesaResult<Vec<MagmaPhysicalDevice>> {
             let subsystem_path = Path::new(&pci_subsystem_dir);
             let subsystem = readlink(subsystem_path, Vec::new())?;
 
-            // If not valid UTF-8, assume not PCI
-            let is_pci_subsystem = subsystem
-                .to_str()
-                .map(|s| s.contains("/pci"))
-                .unwrap_or(false);
-
-            if !is_pci_subsystem {
-                continue;
+            // If not valid UTF-8, assume neither PCI nor platform
+            let subsystem = subsystem.to_str().unwrap_or("");
+            if subsystem.contains("/pci") {
+                let (pci_info, pci_bus_info) = enumerate_pci_device(&pci_device_dir)?;
+                devices.push(MagmaPhysicalDevice::new(
+                    Arc::new(LinuxPhysicalDevice::new(path.to_path_buf())?),
+                    pci_info,
+                    pci_bus_info,
+                ));
+            } else if subsystem.ends_with("/platform") || subsystem.ends_with("/virtio") {
+                let physical_device = LinuxPhysicalDevice::new(path.to_path_buf())?;
+                let Some(vendor_id) = platform_vendor_id(&physical_device.name) else {
+                    continue;
+                };
+
+                let pci_info = MagmaPciInfo {
+                    vendor_id,
+                    ..Default::default()
+                };
+                let pci_bus_info = MagmaPciBusInfo {
+                    domain: MAGMA_PCI_DOMAIN_PLATFORM,
+                    device: min.try_into()?,
+                    ..Default::default()
+                };
+
+                devices.push(MagmaPhysicalDevice::new(
+                    Arc::new(physical_device),
+                    pci_info,
+                    pci_bus_info,
+                ));
             }
+        }
+    }
 
-            let mut pci_info: MagmaPciInfo = Default::default();
-            let mut pci_bus_info: MagmaPciBusInfo = Default::default();
-            for attr in PCI_ATTRS {
-                let attr_path = format!("{}/{}", pci_device_dir, attr);
-                let mut file = File::open(attr_path)?;
-                let mut hex_string = String::new();
-                file.read_to_string(&mut hex_string)?;
-
-                match attr {
-                    "revision" => pci_info.revision_id = parse_hex_u16(&hex_string)?.try_into()?,
-                    "vendor" => pci_info.vendor_id = parse_hex_u16(&hex_string)?,
-                    "device" => pci_info.device_id = parse_hex_u16(&hex_string)?,
-                    "subsystem_vendor" => pci_info.subvendor_id = parse_hex_u16(&hex_string)?,
-                    "subsystem_device" => pci_info.subdevice_id = parse_hex_u16(&hex_string)?,
-                    _ => unimplemented!(),
-                }
-            }
+    Ok(devices)
+}
+
+unsafe impl Send for LinuxPhysicalDevice {}
+unsafe impl Sync for LinuxPhysicalDevice {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            let uevent_path = format!("{}/uevent", pci_device_dir);
-            let text: String = fs::read_to_string(uevent_path)?;
-            for line in text.lines() {
-                if line.contains("PCI_SLOT_NAME") {
-                    let v: Vec<&str> = line.split(&['=', ':', '.'][..]).collect();
+    #[test]
+    fn init_once_until_success() {
+        let physical_device = LinuxPhysicalDevice {
+            descriptor: File::open("/dev/null").unwrap().into(),
+            name: "virtio_gpu".to_string(),
+            initialized: Mutex::new(false),
+        };
 
-                    pci_bus_info.domain = v[1].parse::<u16>()?;
-                    pci_bus_info.bus = v[2].parse::<u8>()?;
-                    pci_bus_info.device = v[3].parse::<u8>()?;
-                    pci_bus_info.function = v[4].parse::<u8>()?;
+        let mut calls = 0;
+        let mut init = |fail: bool| {
+            let mut init = || {
+                calls += 1;
+                match fail {
+                    true => Err(MesaError::WithContext("context init failed")),
+                    false => Ok(()),
                 }
-            }
+            };
+            physical_device.init_once(&mut init)
+        };
+
+        // A failed init is retried, and a successful one isn't repeated.
+        assert!(init(true).is_err());
+        init(false).unwrap();
+        init(false).unwrap();
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    fn pci_slot_names() {
+        let bus_info = parse_pci_slot_name("000a:1f:0b.7\n").unwrap();
+        assert_eq!(
+            (
+                bus_info.domain,
+                bus_info.bus,
+                bus_info.device,
+                bus_info.function
+            ),
+            (0xa, 0x1f, 0xb, 7)
+        );
+
+        assert!(parse_pci_slot_name("0000:00:02").is_err());
+        assert!(parse_pci_slot_name("0000:0g:02.0").is_err());
+        assert!(parse_pci_slot_name("0000:100:02.0").is_err());
+    }
 
-            devices.push(MagmaPhysicalDevice::new(
-                Arc::new(LinuxPhysicalDevice::new(path.to_path_buf())?),
-                pci_info,
-                pci_bus_info,
-            ));
+    #[test]
+    fn pci_device_attributes() {
+        let dir = std::env::temp_dir().join(format!("magma-pci-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (attr, value) in [
+            ("vendor", "0x1002"),
+            ("device", "0x73bf"),
+            ("revision", "0xc1"),
+            ("subsystem_vendor", "0x1da2"),
+            ("subsystem_device", "0xe445"),
+            ("uevent", "DRIVER=amdgpu\nPCI_SLOT_NAME=0000:0a:00.0\n"),
+        ] {
+            fs::write(dir.join(attr), format!("{value}\n")).unwrap();
         }
+
+        let result = enumerate_pci_device(dir.to_str().unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+        let (pci_info, bus_info) = result.unwrap();
+        assert_eq!(
+            (
+                pci_info.vendor_id,
+                pci_info.device_id,
+                pci_info.revision_id,
+                pci_info.subvendor_id,
+                pci_info.subdevice_id
+            ),
+            (MAGMA_VENDOR_ID_AMD, 0x73bf, 0xc1, 0x1da2, 0xe445)
+        );
+        assert_eq!((bus_info.domain, bus_info.bus), (0, 0xa));
     }
 
-    Ok(devices)
+    #[test]
+    fn platform_vendors() {
+        assert_eq!(platform_vendor_id("msm"), Some(MAGMA_VENDOR_ID_QCOM));
+        assert_eq!(platform_vendor_id("panthor"), Some(MAGMA_VENDOR_ID_MALI));
+        // Display controllers without a GPU.
+        assert_eq!(platform_vendor_id("vc4"), None);
+    }
 }
-
-unsafe impl Send for LinuxPhysicalDevice {}
-unsafe impl Sync for LinuxPhysicalDevice {}
@@ -1,11 +1,10 @@
 // Copyright 2025 Google
 // SPDX-License-Identifier: MIT
 
+use std::sync::atomic::fence;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
-use log::error;
-
-use mesa3d_util::log_status;
 use mesa3d_util::MappedRegion;
 use mesa3d_util::MesaError;
 use mesa3d_util::MesaHandle;
@@ -14,13 +13,19 @@ use mesa3d_util::MesaResult;
 use crate::flexible_array_impl;
 use crate::ioctl_readwrite;
 use crate::ioctl_write_ptr;
+use crate::magma_log_status;
+use crate::magma_warn;
 use crate::sys::linux::flexible_array::FlexibleArray;
 use crate::sys::linux::flexible_array::FlexibleArrayWrapper;
 
+use crate::magma_defines::MagmaContextPriority;
 use crate::magma_defines::MagmaCreateBufferInfo;
+use crate::magma_defines::MagmaEngineClass;
+use crate::magma_defines::MagmaEngineInfo;
 use crate::magma_defines::MagmaHeapBudget;
 use crate::magma_defines::MagmaImportHandleInfo;
 use crate::magma_defines::MagmaMemoryProperties;
+use crate::magma_defines::MagmaQueryResult;
 use crate::magma_defines::MAGMA_HEAP_CPU_VISIBLE_BIT;
 use crate::magma_defines::MAGMA_HEAP_DEVICE_LOCAL_BIT;
 use crate::magma_defines::MAGMA_MEMORY_PROPERTY_DEVICE_LOCAL_BIT;
@@ -37,6 +42,7 @@ use crate::traits::Buffer;
 use crate::traits::Context;
 use crate::traits::Device;
 use crate::traits::GenericBuffer;
+use crate::traits::GenericContext;
 use crate::traits::GenericDevice;
 use crate::traits::PhysicalDevice;
 
@@ -61,6 +67,13 @@ ioctl_readwrite!(
     drm_i915_gem_create
 );
 
+ioctl_write_ptr!(
+    drm_ioctl_i915_gem_set_domain,
+    DRM_IOCTL_BASE,
+    DRM_COMMAND_BASE + DRM_I915_GEM_SET_DOMAIN,
+    drm_i915_gem_set_domain
+);
+
 ioctl_readwrite!(
     drm_ioctl_i915_gem_mmap_offset,
     DRM_IOCTL_BASE,
@@ -75,6 +88,13 @@ ioctl_readwrite!(
     drm_i915_gem_context_create_ext
 );
 
+ioctl_readwrite!(
+    drm_ioctl_i915_gem_context_setparam,
+    DRM_IOCTL_BASE,
+    DRM_COMMAND_BASE + DRM_I915_GEM_CONTEXT_SETPARAM,
+    drm_i915_gem_context_param
+);
+
 ioctl_write_ptr!(
     drm_ioctl_i915_gem_context_destroy,
     DRM_IOCTL_BASE,
@@ -82,6 +102,22 @@ ioctl_write_ptr!(
     drm_i915_gem_context_destroy
 );
 
+// The I915_PARAM_* values `query` accepts, which describe the device and the kernel's features.
+const I915_QUERY_PARAMS: [u32; 12] = [
+    I915_PARAM_CHIPSET_ID,
+    I915_PARAM_REVISION,
+    I915_PARAM_EU_TOTAL,
+    I915_PARAM_SUBSLICE_TOTAL,
+    I915_PARAM_SLICE_MASK,
+    I915_PARAM_SUBSLICE_MASK,
+    I915_PARAM_CS_TIMESTAMP_FREQUENCY,
+    I915_PARAM_HAS_LLC,
+    I915_PARAM_HAS_SCHEDULER,
+    I915_PARAM_HAS_CONTEXT_ISOLATION,
+    I915_PARAM_HAS_EXEC_TIMELINE_FENCES,
+    I915_PARAM_MMAP_GTT_VERSION,
+];
+
 flexible_array_impl!(
     drm_i915_query_memory_regions,
     drm_i915_memory_region_info,
@@ -89,6 +125,13 @@ flexible_array_impl!(
     regions
 );
 
+flexible_array_impl!(
+    drm_i915_query_engine_info,
+    drm_i915_engine_info,
+    num_engines,
+    engines
+);
+
 fn i915_query<T, S>(
     physical_device: &Arc<dyn PhysicalDevice>,
     query_id: u64,
@@ -136,6 +179,29 @@ where
     Ok(wrapper)
 }
 
+fn i915_engine_class(engine_class: u16) -> Option<MagmaEngineClass> {
+    match i32::from(engine_class) {
+        I915_ENGINE_CLASS_RENDER => Some(MagmaEngineClass::Render),
+        I915_ENGINE_CLASS_COPY => Some(MagmaEngineClass::Copy),
+        I915_ENGINE_CLASS_VIDEO | I915_ENGINE_CLASS_VIDEO_ENHANCE => Some(MagmaEngineClass::Video),
+        I915_ENGINE_CLASS_COMPUTE => Some(MagmaEngineClass::Compute),
+        _ => None,
+    }
+}
+
+// Returns the engine map of a context on `engine_class`: the engines of the class, in the order
+// the kernel lists them.
+fn i915_engine_map(
+    engines: &[i915_engine_class_instance],
+    engine_class: MagmaEngineClass,
+) -> Vec<i915_engine_class_instance> {
+    engines
+        .iter()
+        .filter(|engine| i915_engine_class(engine.engine_class) == Some(engine_class))
+        .copied()
+        .collect()
+}
+
 #[derive(Default)]
 struct I915MemoryInfo {
     sysmem_total: u64,
@@ -274,6 +340,9 @@ impl I915 {
             mem_props.increment_heap_count();
         }
 
+        // i915 backs up local memory to system memory on suspend.
+        mem_props.set_all_heaps_preserved_on_suspend();
+
         Ok(I915 {
             physical_device,
             mem_props,
@@ -313,8 +382,84 @@ impl GenericDevice for I915 {
         })
     }
 
-    fn create_context(&self, _device: &Arc<dyn Device>) -> MesaResult<Arc<dyn Context>> {
+    // Ids are the I915_PARAM_* values in I915_QUERY_PARAMS, which take no input.
+    fn query(&self, id: u64, input: &[u8]) -> MesaResult<MagmaQueryResult> {
+        let param = I915_QUERY_PARAMS
+            .iter()
+            .copied()
+            .find(|param| u64::from(*param) == id)
+            .ok_or(MesaError::Unsupported)?;
+        if !input.is_empty() {
+            return Err(MesaError::WithContext("i915 params take no input"));
+        }
+
+        let mut val: i32 = 0;
+        let mut getparam = drm_i915_getparam {
+            param: param.try_into()?,
+            value: &mut val as *mut _,
+        };
+
+        // SAFETY:
+        // Valid arguments are supplied for the following arguments:
+        //   - Underlying descriptor
+        //   - drm_i915_getparam struct
+        unsafe {
+            drm_ioctl_i915_getparam(self.physical_device.as_fd().unwrap(), &mut getparam)?;
+        }
+
+        Ok(MagmaQueryResult::Value(val as u32 as u64))
+    }
+
+    fn enumerate_engines(&self) -> MesaResult<Vec<MagmaEngineInfo>> {
+        let query_engines = i915_query::<drm_i915_query_engine_info, drm_i915_engine_info>(
+            &self.physical_device,
+            DRM_I915_QUERY_ENGINE_INFO as u64,
+        )?;
+
+        let mut engines = Vec::new();
+        for engine in query_engines.entries_slice() {
+            if let Some(engine_class) = i915_engine_class(engine.engine.engine_class) {
+                MagmaEngineInfo::add(&mut engines, engine_class, 1);
+            }
+        }
+
+        Ok(engines)
+    }
+
+    fn create_context(
+        &self,
+        _device: &Arc<dyn Device>,
+        priority: MagmaContextPriority,
+        engine_class: MagmaEngineClass,
+    ) -> MesaResult<Arc<dyn Context>> {
+        let query_engines = i915_query::<drm_i915_query_engine_info, drm_i915_engine_info>(
+            &self.physical_device,
+            DRM_I915_QUERY_ENGINE_INFO as u64,
+        )?;
+        let engines: Vec<i915_engine_class_instance> = query_engines
+            .entries_slice()
+            .iter()
+            .map(|engine| engine.engine)
+            .collect();
+
+        // Execbuffers select an engine of the class by its index in the context's engine map.
         let ctx = I915Context::new(self.physical_device.clone())?;
+        ctx.set_engines(&i915_engine_map(&engines, engine_class))?;
+
+        let i915_priority = match priority {
+            MagmaContextPriority::Low => I915_CONTEXT_MIN_USER_PRIORITY,
+            MagmaContextPriority::Medium => I915_CONTEXT_DEFAULT_PRIORITY as i32,
+            MagmaContextPriority::High => I915_CONTEXT_MAX_USER_PRIORITY as i32,
+        };
+
+        // Priorities above the default need CAP_SYS_NICE, and older kernels have no scheduler
+        // priorities at all.  Either way, the context keeps the default priority.
+        if priority != MagmaContextPriority::Medium {
+            if let Err(e) = ctx.set_priority(i915_priority) {
+                magma_warn!("failed to set i915 context priority: {}", e);
+            }
+        }
+
         Ok(Arc::new(ctx))
     }
 
@@ -365,6 +510,54 @@ impl I915Context {
             context_id: ctx_create.ctx_id,
         })
     }
+
+    fn set_engines(&self, engines: &[i915_engine_class_instance]) -> MesaResult<()> {
+        if engines.is_empty() {
+            return Err(MesaError::WithContext("no i915 engine of the class"));
+        }
+
+        // An i915_context_param_engines: a zero u64 extensions field, followed by the engines.
+        let mut param_engines = vec![0u16; 4];
+        for engine in engines {
+            param_engines.extend([engine.engine_class, engine.engine_instance]);
+        }
+
+        let mut param = drm_i915_gem_context_param {
+            ctx_id: self.context_id,
+            size: size_of_val(param_engines.as_slice()).try_into()?,
+            param: I915_CONTEXT_PARAM_ENGINES as u64,
+            value: param_engines.as_ptr() as u64,
+        };
+
+        // SAFETY:
+        // Valid arguments are supplied for the following arguments:
+        //   - Underlying descriptor
+        //   - drm_i915_gem_context_param struct, whose value points to `size` bytes
+        unsafe {
+            drm_ioctl_i915_gem_context_setparam(self.physical_device.as_fd().unwrap(), &mut param)?;
+        };
+
+        Ok(())
+    }
+
+    fn set_priority(&self, priority: i32) -> MesaResult<()> {
+        let mut param = drm_i915_gem_context_param {
+            ctx_id: self.context_id,
+            param: I915_CONTEXT_PARAM_PRIORITY as u64,
+            value: priority as u64,
+            ..Default::default()
+        };
+
+        // SAFETY:
+        // Valid arguments are supplied for the following arguments:
+        //   - Underlying descriptor
+        //   - drm_i915_gem_context_param struct
+        unsafe {
+            drm_ioctl_i915_gem_context_setparam(self.physical_device.as_fd().unwrap(), &mut param)?;
+        };
+
+        Ok(())
+    }
 }
 
 impl Drop for I915Context {
@@ -381,10 +574,13 @@ impl Drop for I915Context {
         let result = unsafe {
             drm_ioctl_i915_gem_context_destroy(self.physical_device.as_fd().unwrap(), &ctx_destroy)
         };
-        log_status!(result);
+        magma_log_status!(result);
     }
 }
 
+// Upstream i915 has no VM_BIND uapi.  Buffers are soft-pinned to GPU addresses at execbuffer
+// time instead.
+impl GenericContext for I915Context {}
 impl Context for I915Context {}
 
 impl I915Buffer {
@@ -453,12 +649,29 @@ impl GenericBuffer for I915Buffer {
         self.physical_device.export(self.gem_handle)
     }
 
+    // Buffers are always mapped write-combined.  Moving the buffer to the WC read domain waits for
+    // GPU writes and flushes them out of the GPU caches.  The kernel flushes the WC domain before
+    // the next execbuffer, so flushing only drains the CPU write-combining buffers.
     fn invalidate(
         &self,
         _sync_flags: u64,
         _ranges: &[crate::magma_defines::MagmaMappedMemoryRange],
     ) -> MesaResult<()> {
-        Err(MesaError::Unsupported)
+        let set_domain = drm_i915_gem_set_domain {
+            handle: self.gem_handle,
+            read_domains: I915_GEM_DOMAIN_WC,
+            write_domain: 0,
+        };
+
+        // SAFETY:
+        // Valid arguments are supplied for the following arguments:
+        //   - Underlying descriptor
+        //   - drm_i915_gem_set_domain struct
+        unsafe {
+            drm_ioctl_i915_gem_set_domain(self.physical_device.as_fd().unwrap(), &set_domain)?;
+        }
+
+        Ok(())
     }
 
     fn flush(
@@ -466,7 +679,16 @@ impl GenericBuffer for I915Buffer {
         _sync_flags: u64,
         _ranges: &[crate::magma_defines::MagmaMappedMemoryRange],
     ) -> MesaResult<()> {
-        Err(MesaError::Unsupported)
+        fence(Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.size as u64
+    }
+
+    fn gem_handle(&self) -> MesaResult<u32> {
+        Ok(self.gem_handle)
     }
 }
 
@@ -486,3 +708,40 @@ unsafe impl Sync for I915Context {}
 
 unsafe impl Send for I915Buffer {}
 unsafe impl Sync for I915Buffer {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn engine_map() {
+        let engine = |engine_class: i32, engine_instance| i915_engine_class_instance {
+            engine_class: engine_class as u16,
+            engine_instance,
+        };
+        let engines = [
+            engine(I915_ENGINE_CLASS_RENDER, 0),
+            engine(I915_ENGINE_CLASS_VIDEO, 0),
+            engine(I915_ENGINE_CLASS_COPY, 0),
+            engine(I915_ENGINE_CLASS_VIDEO_ENHANCE, 0),
+            engine(I915_ENGINE_CLASS_VIDEO, 1),
+        ];
+        let map = |engine_class| {
+            i915_engine_map(&engines, engine_class)
+                .iter()
+                .map(|e| (i32::from(e.engine_class), e.engine_instance))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            map(MagmaEngineClass::Video),
+            [
+                (I915_ENGINE_CLASS_VIDEO, 0),
+                (I915_ENGINE_CLASS_VIDEO_ENHANCE, 0),
+                (I915_ENGINE_CLASS_VIDEO, 1)
+            ]
+        );
+        assert_eq!(map(MagmaEngineClass::Copy), [(I915_ENGINE_CLASS_COPY, 0)]);
+        assert!(map(MagmaEngineClass::Compute).is_empty());
+    }
+}
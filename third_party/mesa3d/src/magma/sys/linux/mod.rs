@@ -9,13 +9,16 @@ pub mod flexible_array;
 mod i915;
 mod macros;
 mod msm;
+mod virtgpu;
 mod xe;
 
 pub use amdgpu::AmdGpu;
 pub use common::enumerate_devices;
+pub use common::system_memory_size;
 pub use common::PlatformDevice;
 pub use common::PlatformPhysicalDevice;
 pub use drm::*;
 pub use i915::I915;
 pub use msm::Msm;
+pub use virtgpu::VirtGpu;
 pub use xe::Xe;
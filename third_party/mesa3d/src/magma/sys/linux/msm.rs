@@ -15,20 +15,45 @@ use crate::traits::Buffer;
 use crate::traits::Context;
 use crate::traits::Device;
 use crate::traits::GenericBuffer;
+use crate::traits::GenericContext;
 use crate::traits::GenericDevice;
 use crate::traits::PhysicalDevice;
 
+use crate::magma_defines::MagmaContextPriority;
 use crate::magma_defines::MagmaCreateBufferInfo;
+use crate::magma_defines::MagmaEngineClass;
 use crate::magma_defines::MagmaHeapBudget;
 use crate::magma_defines::MagmaImportHandleInfo;
 use crate::magma_defines::MagmaMappedMemoryRange;
 use crate::magma_defines::MagmaMemoryProperties;
+use crate::magma_defines::MagmaQueryResult;
+use crate::magma_defines::MAGMA_HEAP_CPU_VISIBLE_BIT;
+use crate::magma_defines::MAGMA_HEAP_DEVICE_LOCAL_BIT;
+use crate::magma_defines::MAGMA_MEMORY_PROPERTY_DEVICE_LOCAL_BIT;
+use crate::magma_defines::MAGMA_MEMORY_PROPERTY_HOST_CACHED_BIT;
+use crate::magma_defines::MAGMA_MEMORY_PROPERTY_HOST_COHERENT_BIT;
+use crate::magma_defines::MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT;
 
 use crate::sys::linux::bindings::drm_bindings::DRM_COMMAND_BASE;
 use crate::sys::linux::bindings::drm_bindings::DRM_IOCTL_BASE;
 use crate::sys::linux::bindings::msm_bindings::*;
+use crate::sys::linux::system_memory_size;
 use crate::sys::linux::PlatformDevice;
 
+ioctl_readwrite!(
+    drm_ioctl_msm_get_param,
+    DRM_IOCTL_BASE,
+    DRM_COMMAND_BASE + DRM_MSM_GET_PARAM,
+    drm_msm_param
+);
+
+ioctl_write_ptr!(
+    drm_ioctl_msm_set_param,
+    DRM_IOCTL_BASE,
+    DRM_COMMAND_BASE + DRM_MSM_SET_PARAM,
+    drm_msm_param
+);
+
 ioctl_readwrite!(
     drm_ioctl_msm_gem_new,
     DRM_IOCTL_BASE,
@@ -71,26 +96,87 @@ ioctl_write_ptr!(
     __u32
 );
 
+ioctl_readwrite!(
+    drm_ioctl_msm_vm_bind,
+    DRM_IOCTL_BASE,
+    DRM_COMMAND_BASE + DRM_MSM_VM_BIND,
+    drm_msm_vm_bind
+);
+
 struct MsmContext {
     physical_device: Arc<dyn PhysicalDevice>,
     submit_queue_id: u32,
+    // Only present if VM_BIND was enabled when the device was opened.
+    vm_bind_queue_id: Option<u32>,
+}
+
+impl MsmContext {
+    fn vm_bind(&self, bind_op: drm_msm_vm_bind_op) -> MesaResult<()> {
+        let queue_id = self.vm_bind_queue_id.ok_or(MesaError::Unsupported)?;
+        let mut vm_bind = drm_msm_vm_bind {
+            nr_ops: 1,
+            queue_id,
+            op_stride: size_of::<drm_msm_vm_bind_op>() as u32,
+            ..Default::default()
+        };
+        vm_bind.__bindgen_anon_1.op = bind_op;
+
+        // SAFETY: This is a valid file descriptor, a VM_BIND submitqueue and a single inline bind
+        // op.  Without fences, the bind is complete when the ioctl returns.
+        unsafe {
+            drm_ioctl_msm_vm_bind(self.physical_device.as_fd().unwrap(), &mut vm_bind)?;
+        }
+
+        Ok(())
+    }
 }
 
 impl Drop for MsmContext {
     fn drop(&mut self) {
         // SAFETY: This is a valid file descriptor and a valid submitqueue id.
         unsafe {
+            if let Some(vm_bind_queue_id) = self.vm_bind_queue_id {
+                let _ =
+                    msm_submitqueue_close(self.physical_device.as_fd().unwrap(), &vm_bind_queue_id);
+            }
+
             let _ =
                 msm_submitqueue_close(self.physical_device.as_fd().unwrap(), &self.submit_queue_id);
         }
     }
 }
 
+// msm has a single GPU address space per file description, shared by all of its contexts.  The
+// kernel has no read-only or no-execute mappings, so `flags` is only validated by the caller.
+impl GenericContext for MsmContext {
+    fn map_gpu(&self, buffer: &Arc<dyn Buffer>, gpu_va: u64, _flags: u64) -> MesaResult<()> {
+        self.vm_bind(drm_msm_vm_bind_op {
+            op: MSM_VM_BIND_OP_MAP,
+            handle: buffer.gem_handle()?,
+            obj_offset: 0,
+            iova: gpu_va,
+            range: buffer.size(),
+            ..Default::default()
+        })
+    }
+
+    fn unmap_gpu(&self, buffer: &Arc<dyn Buffer>, gpu_va: u64) -> MesaResult<()> {
+        self.vm_bind(drm_msm_vm_bind_op {
+            op: MSM_VM_BIND_OP_UNMAP,
+            iova: gpu_va,
+            range: buffer.size(),
+            ..Default::default()
+        })
+    }
+}
+
 impl Context for MsmContext {}
 
 pub struct Msm {
     physical_device: Arc<dyn PhysicalDevice>,
     mem_props: MagmaMemoryProperties,
+    vm_bind: bool,
+    priorities: u32,
 }
 
 struct MsmBuffer {
@@ -99,28 +185,117 @@ struct MsmBuffer {
     size: usize,
 }
 
+fn msm_get_param(physical_device: &Arc<dyn PhysicalDevice>, param: u32) -> MesaResult<u64> {
+    let mut get_param = drm_msm_param {
+        pipe: MSM_PIPE_3D0,
+        param,
+        ..Default::default()
+    };
+
+    // SAFETY: This is a valid file descriptor and a well-formed drm_msm_param.
+    unsafe {
+        drm_ioctl_msm_get_param(physical_device.as_fd().unwrap(), &mut get_param)?;
+    }
+
+    Ok(get_param.value)
+}
+
+// Returns the submit queue priority for `priority`, of the `priorities` the kernel has.  Zero is the
+// highest.  Like freedreno, use the next one by default, so compositors can preempt other
+// contexts.
+fn submitqueue_prio(priorities: u32, priority: MagmaContextPriority) -> u32 {
+    let lowest = priorities.saturating_sub(1);
+    match priority {
+        MagmaContextPriority::Low => lowest,
+        MagmaContextPriority::Medium => lowest.min(1),
+        MagmaContextPriority::High => 0,
+    }
+}
+
 impl Msm {
-    pub fn new(physical_device: Arc<dyn PhysicalDevice>) -> Msm {
-        Msm {
-            physical_device,
-            mem_props: Default::default(),
+    pub fn new(physical_device: Arc<dyn PhysicalDevice>, user_gpu_va: bool) -> MesaResult<Msm> {
+        // The chip id is zero if the kernel couldn't identify the GPU, e.g. because its firmware
+        // is missing.
+        if msm_get_param(&physical_device, MSM_PARAM_CHIP_ID)? == 0 {
+            return Err(MesaError::WithContext("unidentified Adreno GPU"));
+        }
+
+        // Adreno GPUs share system memory with the CPU, but can't address more than the VA size.
+        let va_size = msm_get_param(&physical_device, MSM_PARAM_VA_SIZE)?;
+        let heap_size = system_memory_size()?.min(va_size);
+
+        let mut mem_props: MagmaMemoryProperties = Default::default();
+        mem_props.add_heap(
+            heap_size,
+            MAGMA_HEAP_DEVICE_LOCAL_BIT | MAGMA_HEAP_CPU_VISIBLE_BIT,
+        );
+        mem_props.add_memory_type(
+            MAGMA_MEMORY_PROPERTY_DEVICE_LOCAL_BIT
+                | MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT
+                | MAGMA_MEMORY_PROPERTY_HOST_COHERENT_BIT
+                | MAGMA_MEMORY_PROPERTY_HOST_CACHED_BIT,
+        );
+        mem_props.increment_heap_count();
+
+        // Buffers live in system memory, which is preserved across suspend.
+        mem_props.set_all_heaps_preserved_on_suspend();
+
+        // VM_BIND must be enabled before any buffers are allocated, and is refused by kernels or
+        // GPUs without per-process page tables.  Once enabled, the kernel no longer assigns GPU
+        // addresses, which breaks drivers that expect it to, so it is only enabled on request.
+        if user_gpu_va {
+            let param = drm_msm_param {
+                pipe: MSM_PIPE_3D0,
+                param: MSM_PARAM_EN_VM_BIND,
+                value: 1,
+                ..Default::default()
+            };
+
+            // SAFETY: This is a valid file descriptor and a well-formed drm_msm_param.
+            unsafe { drm_ioctl_msm_set_param(physical_device.as_fd().unwrap(), &param) }
+                .map_err(|_| MesaError::Unsupported)?;
         }
+
+        // Kernels without MSM_PARAM_PRIORITIES have a single submit queue priority.
+        let priorities = match msm_get_param(&physical_device, MSM_PARAM_PRIORITIES) {
+            Ok(priorities) => priorities.try_into()?,
+            Err(_) => 1,
+        };
+
+        Ok(Msm {
+            physical_device,
+            mem_props,
+            vm_bind: user_gpu_va,
+            priorities,
+        })
     }
 }
 
 impl GenericDevice for Msm {
     fn get_memory_properties(&self) -> MesaResult<MagmaMemoryProperties> {
-        Err(MesaError::Unsupported)
+        Ok(self.mem_props.clone())
     }
 
     fn get_memory_budget(&self, _heap_idx: u32) -> MesaResult<MagmaHeapBudget> {
         Err(MesaError::Unsupported)
     }
 
-    fn create_context(&self, _device: &Arc<dyn Device>) -> MesaResult<Arc<dyn Context>> {
+    // Ids are MSM_PARAM_* values for the 3D pipe, which take no input.
+    fn query(&self, id: u64, _input: &[u8]) -> MesaResult<MagmaQueryResult> {
+        let value = msm_get_param(&self.physical_device, id.try_into()?)?;
+        Ok(MagmaQueryResult::Value(value))
+    }
+
+    fn create_context(
+        &self,
+        _device: &Arc<dyn Device>,
+        priority: MagmaContextPriority,
+        // Only the 3D pipe is exposed, which is the single render engine.
+        _engine_class: MagmaEngineClass,
+    ) -> MesaResult<Arc<dyn Context>> {
         let mut new_submit_queue = drm_msm_submitqueue {
             flags: 0,
-            prio: 0,
+            prio: submitqueue_prio(self.priorities, priority),
             ..Default::default()
         };
 
@@ -129,9 +304,28 @@ impl GenericDevice for Msm {
             msm_submitqueue_new(self.physical_device.as_fd().unwrap(), &mut new_submit_queue)?;
         }
 
+        let mut vm_bind_queue_id = None;
+        if self.vm_bind {
+            let mut new_vm_bind_queue = drm_msm_submitqueue {
+                flags: MSM_SUBMITQUEUE_VM_BIND,
+                ..Default::default()
+            };
+
+            // SAFETY: This is a valid file descriptor.
+            unsafe {
+                msm_submitqueue_new(
+                    self.physical_device.as_fd().unwrap(),
+                    &mut new_vm_bind_queue,
+                )?;
+            }
+
+            vm_bind_queue_id = Some(new_vm_bind_queue.id);
+        }
+
         Ok(Arc::new(MsmContext {
             physical_device: self.physical_device.clone(),
             submit_queue_id: new_submit_queue.id,
+            vm_bind_queue_id,
         }))
     }
 
@@ -249,6 +443,14 @@ impl GenericBuffer for MsmBuffer {
         }
         Ok(())
     }
+
+    fn size(&self) -> u64 {
+        self.size as u64
+    }
+
+    fn gem_handle(&self) -> MesaResult<u32> {
+        Ok(self.gem_handle)
+    }
 }
 
 impl Drop for MsmBuffer {
@@ -267,3 +469,26 @@ unsafe impl Sync for MsmContext {}
 
 unsafe impl Send for MsmBuffer {}
 unsafe impl Sync for MsmBuffer {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn submitqueue_priorities() {
+        let prios = |priorities| {
+            [
+                MagmaContextPriority::Low,
+                MagmaContextPriority::Medium,
+                MagmaContextPriority::High,
+            ]
+            .map(|priority| submitqueue_prio(priorities, priority))
+        };
+
+        assert_eq!(prios(4), [3, 1, 0]);
+        assert_eq!(prios(2), [1, 1, 0]);
+        // Kernels with a single priority, or without MSM_PARAM_PRIORITIES.
+        assert_eq!(prios(1), [0, 0, 0]);
+        assert_eq!(prios(0), [0, 0, 0]);
+    }
+}
@@ -0,0 +1,381 @@
+// Copyright 2025 Google
+// SPDX-License-Identifier: MIT
+
+use std::sync::Arc;
+
+use crate::ioctl_readwrite;
+use crate::ioctl_write_ptr;
+
+use mesa3d_util::FromRawDescriptor;
+use mesa3d_util::MappedRegion;
+use mesa3d_util::MesaError;
+use mesa3d_util::MesaHandle;
+use mesa3d_util::MesaResult;
+use mesa3d_util::OwnedDescriptor;
+
+use crate::traits::Buffer;
+use crate::traits::Context;
+use crate::traits::Device;
+use crate::traits::GenericBuffer;
+use crate::traits::GenericContext;
+use crate::traits::GenericDevice;
+use crate::traits::PhysicalDevice;
+
+use crate::magma_defines::MagmaContextPriority;
+use crate::magma_defines::MagmaCreateBufferInfo;
+use crate::magma_defines::MagmaEngineClass;
+use crate::magma_defines::MagmaHeapBudget;
+use crate::magma_defines::MagmaImportHandleInfo;
+use crate::magma_defines::MagmaMappedMemoryRange;
+use crate::magma_defines::MagmaMemoryProperties;
+use crate::magma_defines::MagmaQueryResult;
+use crate::magma_defines::MAGMA_HEAP_CPU_VISIBLE_BIT;
+use crate::magma_defines::MAGMA_MEMORY_PROPERTY_HOST_CACHED_BIT;
+use crate::magma_defines::MAGMA_MEMORY_PROPERTY_HOST_COHERENT_BIT;
+use crate::magma_defines::MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT;
+
+use crate::sys::linux::bindings::drm_bindings::DRM_COMMAND_BASE;
+use crate::sys::linux::bindings::drm_bindings::DRM_IOCTL_BASE;
+use crate::sys::linux::bindings::virtgpu_bindings::*;
+use crate::sys::linux::system_memory_size;
+use crate::sys::linux::PlatformDevice;
+
+// Same as RUTABAGA_CAPSET_MAGMA on the host.
+const VIRTGPU_CAPSET_MAGMA: u32 = 7;
+
+ioctl_readwrite!(
+    drm_ioctl_virtgpu_map,
+    DRM_IOCTL_BASE,
+    DRM_COMMAND_BASE + DRM_VIRTGPU_MAP,
+    drm_virtgpu_map
+);
+
+ioctl_readwrite!(
+    drm_ioctl_virtgpu_execbuffer,
+    DRM_IOCTL_BASE,
+    DRM_COMMAND_BASE + DRM_VIRTGPU_EXECBUFFER,
+    drm_virtgpu_execbuffer
+);
+
+ioctl_readwrite!(
+    drm_ioctl_virtgpu_getparam,
+    DRM_IOCTL_BASE,
+    DRM_COMMAND_BASE + DRM_VIRTGPU_GETPARAM,
+    drm_virtgpu_getparam
+);
+
+ioctl_write_ptr!(
+    drm_ioctl_virtgpu_wait,
+    DRM_IOCTL_BASE,
+    DRM_COMMAND_BASE + DRM_VIRTGPU_WAIT,
+    drm_virtgpu_3d_wait
+);
+
+ioctl_readwrite!(
+    drm_ioctl_virtgpu_resource_create_blob,
+    DRM_IOCTL_BASE,
+    DRM_COMMAND_BASE + DRM_VIRTGPU_RESOURCE_CREATE_BLOB,
+    drm_virtgpu_resource_create_blob
+);
+
+ioctl_readwrite!(
+    drm_ioctl_virtgpu_context_init,
+    DRM_IOCTL_BASE,
+    DRM_COMMAND_BASE + DRM_VIRTGPU_CONTEXT_INIT,
+    drm_virtgpu_context_init
+);
+
+// The virtio-gpu context belongs to the file description, so every magma context of the device
+// shares it.
+struct VirtGpuContext {
+    physical_device: Arc<dyn PhysicalDevice>,
+}
+
+pub struct VirtGpu {
+    physical_device: Arc<dyn PhysicalDevice>,
+    mem_props: MagmaMemoryProperties,
+}
+
+struct VirtGpuBuffer {
+    physical_device: Arc<dyn PhysicalDevice>,
+    gem_handle: u32,
+    size: usize,
+}
+
+fn virtgpu_get_param(physical_device: &Arc<dyn PhysicalDevice>, param: u32) -> MesaResult<u32> {
+    // The kernel writes the value to the address given by the argument, as an int.
+    let mut value: u32 = 0;
+    let mut get_param = drm_virtgpu_getparam {
+        param: param.into(),
+        value: &mut value as *mut u32 as u64,
+    };
+
+    // SAFETY: This is a valid file descriptor, and `value` outlives the ioctl.
+    unsafe {
+        drm_ioctl_virtgpu_getparam(physical_device.as_fd().unwrap(), &mut get_param)?;
+    }
+
+    Ok(value)
+}
+
+impl VirtGpu {
+    pub fn new(physical_device: Arc<dyn PhysicalDevice>) -> MesaResult<VirtGpu> {
+        for param in [VIRTGPU_PARAM_CONTEXT_INIT, VIRTGPU_PARAM_RESOURCE_BLOB] {
+            if virtgpu_get_param(&physical_device, param)? == 0 {
+                return Err(MesaError::WithContext("missing virtio-gpu features"));
+            }
+        }
+
+        let capset_ids = virtgpu_get_param(&physical_device, VIRTGPU_PARAM_SUPPORTED_CAPSET_IDs)?;
+        if capset_ids & (1 << VIRTGPU_CAPSET_MAGMA) == 0 {
+            return Err(MesaError::WithContext("no magma capset"));
+        }
+
+        let params = [
+            drm_virtgpu_context_set_param {
+                param: VIRTGPU_CONTEXT_PARAM_CAPSET_ID.into(),
+                value: VIRTGPU_CAPSET_MAGMA.into(),
+            },
+            drm_virtgpu_context_set_param {
+                param: VIRTGPU_CONTEXT_PARAM_NUM_RINGS.into(),
+                value: 1,
+            },
+        ];
+
+        let mut context_init = drm_virtgpu_context_init {
+            num_params: params.len() as u32,
+            ctx_set_params: params.as_ptr() as u64,
+            ..Default::default()
+        };
+
+        // The context belongs to the file description, which the kernel only lets initialize it
+        // once, so later devices share the context of the first.
+        physical_device.init_once(&mut || {
+            // SAFETY: This is a valid file descriptor, and `params` outlives the ioctl.
+            unsafe {
+                drm_ioctl_virtgpu_context_init(
+                    physical_device.as_fd().unwrap(),
+                    &mut context_init,
+                )?;
+            }
+            Ok(())
+        })?;
+
+        // Buffers are guest memory, which the host imports when they are attached to the context.
+        let mut mem_props: MagmaMemoryProperties = Default::default();
+        mem_props.add_heap(system_memory_size()?, MAGMA_HEAP_CPU_VISIBLE_BIT);
+        mem_props.add_memory_type(
+            MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT
+                | MAGMA_MEMORY_PROPERTY_HOST_COHERENT_BIT
+                | MAGMA_MEMORY_PROPERTY_HOST_CACHED_BIT,
+        );
+        mem_props.increment_heap_count();
+        mem_props.set_all_heaps_preserved_on_suspend();
+
+        Ok(VirtGpu {
+            physical_device,
+            mem_props,
+        })
+    }
+}
+
+impl GenericDevice for VirtGpu {
+    fn get_memory_properties(&self) -> MesaResult<MagmaMemoryProperties> {
+        Ok(self.mem_props.clone())
+    }
+
+    fn get_memory_budget(&self, _heap_idx: u32) -> MesaResult<MagmaHeapBudget> {
+        Err(MesaError::Unsupported)
+    }
+
+    // Ids are VIRTGPU_PARAM_* values, which take no input.
+    fn query(&self, id: u64, _input: &[u8]) -> MesaResult<MagmaQueryResult> {
+        let value = virtgpu_get_param(&self.physical_device, id.try_into()?)?;
+        Ok(MagmaQueryResult::Value(value.into()))
+    }
+
+    // The kernel builds the host's context_init from the capset id alone, so there is no way to
+    // pass the priority along.
+    fn create_context(
+        &self,
+        _device: &Arc<dyn Device>,
+        _priority: MagmaContextPriority,
+        _engine_class: MagmaEngineClass,
+    ) -> MesaResult<Arc<dyn Context>> {
+        Ok(Arc::new(VirtGpuContext {
+            physical_device: self.physical_device.clone(),
+        }))
+    }
+
+    fn create_buffer(
+        &self,
+        _device: &Arc<dyn Device>,
+        create_info: &MagmaCreateBufferInfo,
+    ) -> MesaResult<Arc<dyn Buffer>> {
+        let buf = VirtGpuBuffer::new(self.physical_device.clone(), create_info)?;
+        Ok(Arc::new(buf))
+    }
+
+    fn import(
+        &self,
+        _device: &Arc<dyn Device>,
+        info: MagmaImportHandleInfo,
+    ) -> MesaResult<Arc<dyn Buffer>> {
+        let gem_handle = self.physical_device.import(info.handle)?;
+        Ok(Arc::new(VirtGpuBuffer {
+            physical_device: self.physical_device.clone(),
+            gem_handle,
+            size: info.size.try_into()?,
+        }))
+    }
+}
+
+impl PlatformDevice for VirtGpu {}
+impl Device for VirtGpu {}
+
+impl GenericContext for VirtGpuContext {
+    fn submit(&self, commands: &[u8], buffers: &[Arc<dyn Buffer>]) -> MesaResult<()> {
+        self.execbuffer(commands, buffers, 0)?;
+        Ok(())
+    }
+
+    fn submit_fenced(
+        &self,
+        commands: &[u8],
+        buffers: &[Arc<dyn Buffer>],
+    ) -> MesaResult<OwnedDescriptor> {
+        let fence_fd = self.execbuffer(commands, buffers, VIRTGPU_EXECBUF_FENCE_FD_OUT)?;
+        // SAFETY: The kernel returned a new sync file, which nothing else owns.
+        Ok(unsafe { OwnedDescriptor::from_raw_descriptor(fence_fd) })
+    }
+}
+
+impl Context for VirtGpuContext {}
+
+impl VirtGpuContext {
+    // Submits `commands` with the VIRTGPU_EXECBUF_* `flags`, and returns the out fence fd, which is
+    // only valid with VIRTGPU_EXECBUF_FENCE_FD_OUT.
+    fn execbuffer(
+        &self,
+        commands: &[u8],
+        buffers: &[Arc<dyn Buffer>],
+        flags: u32,
+    ) -> MesaResult<i32> {
+        let bo_handles = buffers
+            .iter()
+            .map(|buffer| buffer.gem_handle())
+            .collect::<MesaResult<Vec<u32>>>()?;
+
+        let mut execbuffer = drm_virtgpu_execbuffer {
+            flags,
+            size: commands.len().try_into()?,
+            command: commands.as_ptr() as u64,
+            bo_handles: bo_handles.as_ptr() as u64,
+            num_bo_handles: bo_handles.len().try_into()?,
+            fence_fd: -1,
+            ..Default::default()
+        };
+
+        // SAFETY: This is a valid file descriptor, and `commands` and `bo_handles` outlive the
+        // ioctl.
+        unsafe {
+            drm_ioctl_virtgpu_execbuffer(self.physical_device.as_fd().unwrap(), &mut execbuffer)?;
+        }
+
+        Ok(execbuffer.fence_fd)
+    }
+}
+
+impl VirtGpuBuffer {
+    fn new(
+        physical_device: Arc<dyn PhysicalDevice>,
+        create_info: &MagmaCreateBufferInfo,
+    ) -> MesaResult<VirtGpuBuffer> {
+        let mut create_blob = drm_virtgpu_resource_create_blob {
+            blob_mem: VIRTGPU_BLOB_MEM_GUEST,
+            blob_flags: VIRTGPU_BLOB_FLAG_USE_MAPPABLE | VIRTGPU_BLOB_FLAG_USE_SHAREABLE,
+            size: create_info.size,
+            ..Default::default()
+        };
+
+        // SAFETY: This is a valid file descriptor and a well-formed guest blob request.
+        unsafe {
+            drm_ioctl_virtgpu_resource_create_blob(
+                physical_device.as_fd().unwrap(),
+                &mut create_blob,
+            )?;
+        }
+
+        Ok(VirtGpuBuffer {
+            physical_device,
+            gem_handle: create_blob.bo_handle,
+            size: create_info.size.try_into()?,
+        })
+    }
+}
+
+impl GenericBuffer for VirtGpuBuffer {
+    fn map(&self, _buffer: &Arc<dyn Buffer>) -> MesaResult<Arc<dyn MappedRegion>> {
+        let mut map = drm_virtgpu_map {
+            handle: self.gem_handle,
+            ..Default::default()
+        };
+
+        // SAFETY: This is a valid file descriptor and a valid gem handle.
+        let offset = unsafe {
+            drm_ioctl_virtgpu_map(self.physical_device.as_fd().unwrap(), &mut map)?;
+            map.offset
+        };
+
+        let mapping = self.physical_device.cpu_map(offset, self.size)?;
+        Ok(Arc::new(mapping))
+    }
+
+    fn export(&self) -> MesaResult<MesaHandle> {
+        self.physical_device.export(self.gem_handle)
+    }
+
+    // Waits for the host to finish with the buffer.
+    fn invalidate(&self, _sync_flags: u64, _ranges: &[MagmaMappedMemoryRange]) -> MesaResult<()> {
+        let wait = drm_virtgpu_3d_wait {
+            handle: self.gem_handle,
+            flags: 0,
+        };
+
+        // SAFETY: This is a valid file descriptor and a valid gem handle.
+        unsafe {
+            drm_ioctl_virtgpu_wait(self.physical_device.as_fd().unwrap(), &wait)?;
+        }
+        Ok(())
+    }
+
+    // Guest memory is coherent with the host.
+    fn flush(&self, _sync_flags: u64, _ranges: &[MagmaMappedMemoryRange]) -> MesaResult<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.size as u64
+    }
+
+    fn gem_handle(&self) -> MesaResult<u32> {
+        Ok(self.gem_handle)
+    }
+}
+
+impl Drop for VirtGpuBuffer {
+    fn drop(&mut self) {
+        self.physical_device.close(self.gem_handle);
+    }
+}
+
+impl Buffer for VirtGpuBuffer {}
+
+unsafe impl Send for VirtGpu {}
+unsafe impl Sync for VirtGpu {}
+
+unsafe impl Send for VirtGpuContext {}
+unsafe impl Sync for VirtGpuContext {}
+
+unsafe impl Send for VirtGpuBuffer {}
+unsafe impl Sync for VirtGpuBuffer {}
@@ -1,11 +1,10 @@
 // Copyright 2025 Google
 // SPDX-License-Identifier: MIT
 
+use std::sync::atomic::fence;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
-use log::error;
-
-use mesa3d_util::log_status;
 use mesa3d_util::MappedRegion;
 use mesa3d_util::MesaError;
 use mesa3d_util::MesaHandle;
@@ -13,22 +12,30 @@ use mesa3d_util::MesaResult;
 
 use crate::ioctl_readwrite;
 use crate::ioctl_write_ptr;
+use crate::magma_log_status;
+use crate::magma_warn;
 
 use crate::traits::Buffer;
 use crate::traits::Context;
 use crate::traits::Device;
 use crate::traits::GenericBuffer;
+use crate::traits::GenericContext;
 use crate::traits::GenericDevice;
 use crate::traits::PhysicalDevice;
 
+use crate::magma_defines::MagmaContextPriority;
 use crate::magma_defines::MagmaCreateBufferInfo;
+use crate::magma_defines::MagmaEngineClass;
+use crate::magma_defines::MagmaEngineInfo;
 use crate::magma_defines::MagmaHeapBudget;
 use crate::magma_defines::MagmaImportHandleInfo;
 use crate::magma_defines::MagmaMappedMemoryRange;
 use crate::magma_defines::MagmaMemoryProperties;
 use crate::magma_defines::MagmaPciInfo;
+use crate::magma_defines::MagmaQueryResult;
 use crate::magma_defines::MAGMA_HEAP_CPU_VISIBLE_BIT;
 use crate::magma_defines::MAGMA_HEAP_DEVICE_LOCAL_BIT;
+use crate::magma_defines::MAGMA_MAP_GPU_FLAG_WRITE;
 use crate::magma_defines::MAGMA_MEMORY_PROPERTY_DEVICE_LOCAL_BIT;
 use crate::magma_defines::MAGMA_MEMORY_PROPERTY_HOST_CACHED_BIT;
 use crate::magma_defines::MAGMA_MEMORY_PROPERTY_HOST_COHERENT_BIT;
@@ -104,6 +111,44 @@ ioctl_write_ptr!(
     drm_xe_vm_destroy
 );
 
+ioctl_write_ptr!(
+    drm_ioctl_xe_vm_bind,
+    DRM_IOCTL_BASE,
+    DRM_COMMAND_BASE + DRM_XE_VM_BIND,
+    drm_xe_vm_bind
+);
+
+ioctl_readwrite!(
+    drm_ioctl_xe_exec_queue_create,
+    DRM_IOCTL_BASE,
+    DRM_COMMAND_BASE + DRM_XE_EXEC_QUEUE_CREATE,
+    drm_xe_exec_queue_create
+);
+
+ioctl_write_ptr!(
+    drm_ioctl_xe_exec_queue_destroy,
+    DRM_IOCTL_BASE,
+    DRM_COMMAND_BASE + DRM_XE_EXEC_QUEUE_DESTROY,
+    drm_xe_exec_queue_destroy
+);
+
+// The DRM_XE_DEVICE_QUERY_* values `query` accepts.  Queries that take input, or that report other
+// processes' state, such as engine cycles and OA units, aren't included.
+const XE_QUERIES: [u32; 6] = [
+    DRM_XE_DEVICE_QUERY_ENGINES,
+    DRM_XE_DEVICE_QUERY_MEM_REGIONS,
+    DRM_XE_DEVICE_QUERY_CONFIG,
+    DRM_XE_DEVICE_QUERY_GT_LIST,
+    DRM_XE_DEVICE_QUERY_HWCONFIG,
+    DRM_XE_DEVICE_QUERY_GT_TOPOLOGY,
+];
+
+// Exec queue priorities, from the kernel's enum xe_exec_queue_priority.  Raising the priority above
+// normal needs CAP_SYS_NICE.
+const XE_EXEC_QUEUE_PRIORITY_LOW: u64 = 0;
+const XE_EXEC_QUEUE_PRIORITY_NORMAL: u64 = 1;
+const XE_EXEC_QUEUE_PRIORITY_HIGH: u64 = 2;
+
 flexible_array_impl!(drm_xe_query_config, __u64, num_params, info);
 flexible_array_impl!(
     drm_xe_query_mem_regions,
@@ -111,11 +156,13 @@ flexible_array_impl!(
     num_mem_regions,
     mem_regions
 );
+flexible_array_impl!(drm_xe_query_engines, drm_xe_engine, num_engines, engines);
 
 pub struct Xe {
     physical_device: Arc<dyn PhysicalDevice>,
+    pat_index: u16,
     _gtt_size: u64,
-    _mem_alignment: u64,
+    mem_alignment: u64,
     mem_props: MagmaMemoryProperties,
     sysmem_instance: u16,
     vram_instance: u16,
@@ -130,6 +177,41 @@ struct XeBuffer {
 struct XeContext {
     physical_device: Arc<dyn PhysicalDevice>,
     vm_id: u32,
+    pat_index: u16,
+    // The exec queue on the context's engine class.  Only None while the context is created.
+    exec_queue_id: Option<u32>,
+}
+
+fn xe_engine_class(engine_class: u16) -> Option<MagmaEngineClass> {
+    match u32::from(engine_class) {
+        DRM_XE_ENGINE_CLASS_RENDER => Some(MagmaEngineClass::Render),
+        DRM_XE_ENGINE_CLASS_COPY => Some(MagmaEngineClass::Copy),
+        DRM_XE_ENGINE_CLASS_VIDEO_DECODE | DRM_XE_ENGINE_CLASS_VIDEO_ENHANCE => {
+            Some(MagmaEngineClass::Video)
+        }
+        DRM_XE_ENGINE_CLASS_COMPUTE => Some(MagmaEngineClass::Compute),
+        _ => None,
+    }
+}
+
+// Returns the placements of an exec queue on `engine_class`.  The placements of an exec queue share
+// their kernel engine class and GT, so these are the engines like the first one of the class.
+fn xe_placements(
+    engines: &[drm_xe_engine_class_instance],
+    engine_class: MagmaEngineClass,
+) -> Vec<drm_xe_engine_class_instance> {
+    let Some(first) = engines
+        .iter()
+        .find(|engine| xe_engine_class(engine.engine_class) == Some(engine_class))
+    else {
+        return Vec::new();
+    };
+
+    engines
+        .iter()
+        .filter(|engine| engine.engine_class == first.engine_class && engine.gt_id == first.gt_id)
+        .copied()
+        .collect()
 }
 
 fn xe_device_query<T, S>(
@@ -202,6 +284,18 @@ fn determine_graphics_version(pci_device_id: u16) -> MesaResult<u32> {
     }
 }
 
+/// Returns the PAT index for write-back, 1-way coherent GPU mappings.  The kernel accepts it for
+/// both WB and WC CPU caching modes.  Values match Mesa's intel_device_info.
+fn determine_pat_index(pci_device_id: u16, graphics_version: u32) -> u16 {
+    if graphics_version >= 20 {
+        1
+    } else if MTL_IDS.contains(&pci_device_id) {
+        3
+    } else {
+        0
+    }
+}
+
 #[derive(Default)]
 struct XeMemoryInfo {
     vram_size: u64,
@@ -258,7 +352,8 @@ impl Xe {
         physical_device: Arc<dyn PhysicalDevice>,
         pci_info: &MagmaPciInfo,
     ) -> MesaResult<Xe> {
-        let _graphics_version = determine_graphics_version(pci_info.device_id)?;
+        let graphics_version = determine_graphics_version(pci_info.device_id)?;
+        let pat_index = determine_pat_index(pci_info.device_id, graphics_version);
         let mut mem_props: MagmaMemoryProperties = Default::default();
 
         let query_config = xe_device_query::<drm_xe_query_config, __u64>(
@@ -305,10 +400,14 @@ impl Xe {
             mem_props.increment_heap_count();
         }
 
+        // xe evicts VRAM to system memory before the device loses power.
+        mem_props.set_all_heaps_preserved_on_suspend();
+
         Ok(Xe {
             physical_device,
+            pat_index,
             _gtt_size: gtt_size,
-            _mem_alignment: mem_alignment,
+            mem_alignment,
             mem_props,
             sysmem_instance: memory_info.sysmem_instance,
             vram_instance: memory_info.vram_instance,
@@ -345,8 +444,75 @@ impl GenericDevice for Xe {
         Ok(MagmaHeapBudget { budget, usage })
     }
 
-    fn create_context(&self, _device: &Arc<dyn Device>) -> MesaResult<Arc<dyn Context>> {
-        let ctx = XeContext::new(self.physical_device.clone(), 0)?;
+    // Ids are the DRM_XE_DEVICE_QUERY_* values in XE_QUERIES, which take no input.  The result is
+    // the raw query structure, such as drm_xe_query_gt_list.
+    fn query(&self, id: u64, input: &[u8]) -> MesaResult<MagmaQueryResult> {
+        let query = XE_QUERIES
+            .iter()
+            .copied()
+            .find(|query| u64::from(*query) == id)
+            .ok_or(MesaError::Unsupported)?;
+        if !input.is_empty() {
+            return Err(MesaError::WithContext("xe queries take no input"));
+        }
+
+        let mut device_query: drm_xe_device_query = drm_xe_device_query {
+            query,
+            ..Default::default()
+        };
+
+        // SAFETY:
+        // Valid arguments are supplied for the following arguments:
+        //   - Underlying descriptor
+        //   - drm_xe_device_query
+        unsafe {
+            drm_ioctl_xe_device_query(self.physical_device.as_fd().unwrap(), &mut device_query)?;
+        };
+
+        let mut data = vec![0u8; device_query.size as usize];
+
+        // SAFETY:
+        // Valid arguments are supplied for the following arguments:
+        //   - Underlying descriptor
+        //   - drm_xe_device_query
+        //   - drm_xe_device_query.data: `data` holds the size returned by the first call
+        unsafe {
+            device_query.data = data.as_mut_ptr() as __u64;
+            drm_ioctl_xe_device_query(self.physical_device.as_fd().unwrap(), &mut device_query)?;
+        };
+
+        data.truncate(device_query.size as usize);
+        Ok(MagmaQueryResult::Buffer(data))
+    }
+
+    fn enumerate_engines(&self) -> MesaResult<Vec<MagmaEngineInfo>> {
+        let query_engines = xe_device_query::<drm_xe_query_engines, drm_xe_engine>(
+            &self.physical_device,
+            DRM_XE_DEVICE_QUERY_ENGINES,
+        )?;
+
+        let mut engines = Vec::new();
+        for engine in query_engines.entries_slice() {
+            if let Some(engine_class) = xe_engine_class(engine.instance.engine_class) {
+                MagmaEngineInfo::add(&mut engines, engine_class, 1);
+            }
+        }
+
+        Ok(engines)
+    }
+
+    fn create_context(
+        &self,
+        _device: &Arc<dyn Device>,
+        priority: MagmaContextPriority,
+        engine_class: MagmaEngineClass,
+    ) -> MesaResult<Arc<dyn Context>> {
+        let ctx = XeContext::new(
+            self.physical_device.clone(),
+            self.pat_index,
+            priority,
+            engine_class,
+        )?;
         Ok(Arc::new(ctx))
     }
 
@@ -359,6 +525,7 @@ impl GenericDevice for Xe {
             self.physical_device.clone(),
             create_info,
             &self.mem_props,
+            self.mem_alignment,
             self.sysmem_instance,
             self.vram_instance,
         )?;
@@ -378,13 +545,40 @@ impl GenericDevice for Xe {
         )?;
         Ok(Arc::new(buf))
     }
+
+    fn supports_sparse_buffers(&self) -> bool {
+        true
+    }
+
+    fn supports_buffer_placement(&self) -> bool {
+        true
+    }
 }
 
 impl PlatformDevice for Xe {}
 impl Device for Xe {}
 
 impl XeContext {
-    fn new(physical_device: Arc<dyn PhysicalDevice>, _priority: i32) -> MesaResult<XeContext> {
+    fn new(
+        physical_device: Arc<dyn PhysicalDevice>,
+        pat_index: u16,
+        priority: MagmaContextPriority,
+        engine_class: MagmaEngineClass,
+    ) -> MesaResult<XeContext> {
+        let query_engines = xe_device_query::<drm_xe_query_engines, drm_xe_engine>(
+            &physical_device,
+            DRM_XE_DEVICE_QUERY_ENGINES,
+        )?;
+        let engines: Vec<drm_xe_engine_class_instance> = query_engines
+            .entries_slice()
+            .iter()
+            .map(|engine| engine.instance)
+            .collect();
+        let placements = xe_placements(&engines, engine_class);
+        if placements.is_empty() {
+            return Err(MesaError::WithContext("no xe engine of the class"));
+        }
+
         let mut vm_create = drm_xe_vm_create {
             flags: DRM_XE_VM_CREATE_FLAG_SCRATCH_PAGE,
             ..Default::default()
@@ -398,15 +592,107 @@ impl XeContext {
             drm_ioctl_xe_vm_create(physical_device.as_fd().unwrap(), &mut vm_create)?;
         };
 
-        Ok(XeContext {
+        let mut ctx = XeContext {
             physical_device,
             vm_id: vm_create.vm_id,
-        })
+            pat_index,
+            exec_queue_id: None,
+        };
+
+        let xe_priority = match priority {
+            MagmaContextPriority::Low => XE_EXEC_QUEUE_PRIORITY_LOW,
+            MagmaContextPriority::Medium => XE_EXEC_QUEUE_PRIORITY_NORMAL,
+            MagmaContextPriority::High => XE_EXEC_QUEUE_PRIORITY_HIGH,
+        };
+
+        let exec_queue_id = match ctx.create_exec_queue(&placements, xe_priority) {
+            Err(MesaError::IoError(e))
+                if priority == MagmaContextPriority::High
+                    && e.raw_os_error() == Some(libc::EPERM) =>
+            {
+                magma_warn!("high priority xe exec queue denied, using normal priority");
+                ctx.create_exec_queue(&placements, XE_EXEC_QUEUE_PRIORITY_NORMAL)?
+            }
+            result => result?,
+        };
+
+        ctx.exec_queue_id = Some(exec_queue_id);
+        Ok(ctx)
+    }
+
+    fn create_exec_queue(
+        &self,
+        placements: &[drm_xe_engine_class_instance],
+        priority: u64,
+    ) -> MesaResult<u32> {
+        let mut priority_ext: drm_xe_ext_set_property = Default::default();
+        priority_ext.base.name = DRM_XE_EXEC_QUEUE_EXTENSION_SET_PROPERTY;
+        priority_ext.property = DRM_XE_EXEC_QUEUE_SET_PROPERTY_PRIORITY;
+        priority_ext.value = priority;
+
+        let mut exec_queue_create = drm_xe_exec_queue_create {
+            extensions: &priority_ext as *const drm_xe_ext_set_property as u64,
+            width: 1,
+            num_placements: placements.len().try_into()?,
+            vm_id: self.vm_id,
+            instances: placements.as_ptr() as u64,
+            ..Default::default()
+        };
+
+        // SAFETY:
+        // Valid arguments are supplied for the following arguments:
+        //   - Underlying descriptor
+        //   - drm_xe_exec_queue_create struct, whose extension and instances outlive the call
+        unsafe {
+            drm_ioctl_xe_exec_queue_create(
+                self.physical_device.as_fd().unwrap(),
+                &mut exec_queue_create,
+            )?;
+        };
+
+        Ok(exec_queue_create.exec_queue_id)
+    }
+
+    fn vm_bind(&self, mut bind_op: drm_xe_vm_bind_op) -> MesaResult<()> {
+        bind_op.pat_index = self.pat_index;
+        let mut vm_bind = drm_xe_vm_bind {
+            vm_id: self.vm_id,
+            num_binds: 1,
+            ..Default::default()
+        };
+        vm_bind.__bindgen_anon_1.bind = bind_op;
+
+        // SAFETY:
+        // Valid arguments are supplied for the following arguments:
+        //   - Underlying descriptor
+        //   - drm_xe_vm_bind struct, with a single inline bind op and no syncs.  The bind is
+        //     complete when the ioctl returns.
+        unsafe {
+            drm_ioctl_xe_vm_bind(self.physical_device.as_fd().unwrap(), &vm_bind)?;
+        };
+
+        Ok(())
     }
 }
 
 impl Drop for XeContext {
     fn drop(&mut self) {
+        if let Some(exec_queue_id) = self.exec_queue_id {
+            let destroy = drm_xe_exec_queue_destroy {
+                exec_queue_id,
+                ..Default::default()
+            };
+
+            // SAFETY:
+            // Valid arguments are supplied for the following arguments:
+            //   - Underlying descriptor
+            //   - drm_xe_exec_queue_destroy struct
+            let result = unsafe {
+                drm_ioctl_xe_exec_queue_destroy(self.physical_device.as_fd().unwrap(), &destroy)
+            };
+            magma_log_status!(result);
+        }
+
         let destroy = drm_xe_vm_destroy {
             vm_id: self.vm_id,
             ..Default::default()
@@ -418,7 +704,49 @@ impl Drop for XeContext {
         //   - drm_xe_vm_destroy struct
         let result =
             unsafe { drm_ioctl_xe_vm_destroy(self.physical_device.as_fd().unwrap(), &destroy) };
-        log_status!(result);
+        magma_log_status!(result);
+    }
+}
+
+impl GenericContext for XeContext {
+    fn map_gpu(&self, buffer: &Arc<dyn Buffer>, gpu_va: u64, flags: u64) -> MesaResult<()> {
+        // NULL bindings have no backing object: reads return zero and writes are dropped.
+        if buffer.is_sparse() {
+            let bind_op = drm_xe_vm_bind_op {
+                range: buffer.size(),
+                addr: gpu_va,
+                op: DRM_XE_VM_BIND_OP_MAP,
+                flags: DRM_XE_VM_BIND_FLAG_NULL,
+                ..Default::default()
+            };
+
+            return self.vm_bind(bind_op);
+        }
+
+        let mut bind_op = drm_xe_vm_bind_op {
+            obj: buffer.gem_handle()?,
+            range: buffer.size(),
+            addr: gpu_va,
+            op: DRM_XE_VM_BIND_OP_MAP,
+            ..Default::default()
+        };
+
+        if flags & MAGMA_MAP_GPU_FLAG_WRITE == 0 {
+            bind_op.flags |= DRM_XE_VM_BIND_FLAG_READONLY;
+        }
+
+        self.vm_bind(bind_op)
+    }
+
+    fn unmap_gpu(&self, buffer: &Arc<dyn Buffer>, gpu_va: u64) -> MesaResult<()> {
+        let bind_op = drm_xe_vm_bind_op {
+            range: buffer.size(),
+            addr: gpu_va,
+            op: DRM_XE_VM_BIND_OP_UNMAP,
+            ..Default::default()
+        };
+
+        self.vm_bind(bind_op)
     }
 }
 
@@ -429,13 +757,18 @@ impl XeBuffer {
         physical_device: Arc<dyn PhysicalDevice>,
         create_info: &MagmaCreateBufferInfo,
         mem_props: &MagmaMemoryProperties,
+        mem_alignment: u64,
         sysmem_instance: u16,
         vram_instance: u16,
     ) -> MesaResult<XeBuffer> {
         let mut gem_create: drm_xe_gem_create = Default::default();
         let mut pxp_ext: drm_xe_ext_set_property = Default::default();
 
-        gem_create.size = create_info.size;
+        // xe has no alignment argument, so the size is padded to the requested alignment and to
+        // the minimum alignment of VRAM objects.  `MagmaContext::map_gpu` aligns the GPU virtual
+        // address.
+        let alignment = (create_info.alignment as u64).max(mem_alignment).max(1);
+        gem_create.size = create_info.size.next_multiple_of(alignment);
         let memory_type = mem_props.get_memory_type(create_info.memory_type_idx);
         let memory_heap = mem_props.get_memory_heap(memory_type.heap_idx);
 
@@ -473,7 +806,7 @@ impl XeBuffer {
         Ok(XeBuffer {
             physical_device,
             gem_handle: gem_create.handle,
-            size: create_info.size.try_into()?,
+            size: gem_create.size.try_into()?,
         })
     }
 
@@ -512,12 +845,26 @@ impl GenericBuffer for XeBuffer {
         self.physical_device.export(self.gem_handle)
     }
 
+    // Cached memory types are mapped write-back and snooped by the GPU, and the others are mapped
+    // write-combined, so every memory type is coherent.  xe has no implicit synchronization:
+    // callers wait for GPU work with semaphores before invalidating, which then only orders the
+    // CPU's reads after that wait.  Flushing drains the CPU write-combining buffers.
     fn invalidate(&self, _sync_flags: u64, _ranges: &[MagmaMappedMemoryRange]) -> MesaResult<()> {
-        Err(MesaError::Unsupported)
+        fence(Ordering::SeqCst);
+        Ok(())
     }
 
     fn flush(&self, _sync_flags: u64, _ranges: &[MagmaMappedMemoryRange]) -> MesaResult<()> {
-        Err(MesaError::Unsupported)
+        fence(Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.size as u64
+    }
+
+    fn gem_handle(&self) -> MesaResult<u32> {
+        Ok(self.gem_handle)
     }
 }
 
@@ -537,3 +884,51 @@ unsafe impl Sync for XeContext {}
 
 unsafe impl Send for XeBuffer {}
 unsafe impl Sync for XeBuffer {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exec_queue_placements() {
+        let engine = |engine_class: u32, engine_instance, gt_id| drm_xe_engine_class_instance {
+            engine_class: engine_class as u16,
+            engine_instance,
+            gt_id,
+            pad: 0,
+        };
+        let engines = [
+            engine(DRM_XE_ENGINE_CLASS_RENDER, 0, 0),
+            engine(DRM_XE_ENGINE_CLASS_COPY, 0, 0),
+            engine(DRM_XE_ENGINE_CLASS_VIDEO_ENHANCE, 0, 1),
+            engine(DRM_XE_ENGINE_CLASS_VIDEO_DECODE, 0, 1),
+            engine(DRM_XE_ENGINE_CLASS_VIDEO_DECODE, 1, 1),
+            engine(DRM_XE_ENGINE_CLASS_COPY, 1, 0),
+            engine(DRM_XE_ENGINE_CLASS_COPY, 0, 1),
+        ];
+        let placements = |engine_class| {
+            xe_placements(&engines, engine_class)
+                .iter()
+                .map(|e| (u32::from(e.engine_class), e.engine_instance, e.gt_id))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            placements(MagmaEngineClass::Copy),
+            [
+                (DRM_XE_ENGINE_CLASS_COPY, 0, 0),
+                (DRM_XE_ENGINE_CLASS_COPY, 1, 0)
+            ]
+        );
+        // Video enhance and decode engines can't be placements of the same exec queue.
+        assert_eq!(
+            placements(MagmaEngineClass::Video),
+            [(DRM_XE_ENGINE_CLASS_VIDEO_ENHANCE, 0, 1)]
+        );
+        assert_eq!(
+            placements(MagmaEngineClass::Render),
+            [(DRM_XE_ENGINE_CLASS_RENDER, 0, 0)]
+        );
+        assert!(placements(MagmaEngineClass::Compute).is_empty());
+    }
+}
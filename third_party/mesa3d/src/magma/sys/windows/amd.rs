@@ -5,10 +5,19 @@
 //
 // https://gitlab.freedesktop.org/gfxstrand/mesa/-/tree/radv/wddm2?ref_type=heads
 
+use mesa3d_util::MesaError;
+use mesa3d_util::MesaResult;
+
 use crate::magma_defines::MagmaCreateBufferInfo;
 use crate::magma_defines::MagmaMemoryProperties;
+use crate::magma_defines::MAGMA_BUFFER_FLAG_AMD_GDS;
+use crate::magma_defines::MAGMA_BUFFER_FLAG_AMD_OA;
 use crate::sys::windows::VendorPrivateData;
 
+// The captured allocation is mapped with 64KiB PTE fragments, so physical allocations are at
+// least that aligned.
+const AMD_PTE_FRAGMENT_SIZE: u64 = 0x10000;
+
 static AMD_CREATE_ALLOC_PDATA: [u32; 15] = [
     0x00000000, 0x00000000, 0x00000080, 0x00000420, 0x00000000, 0x00000000, 0x00000000, 0x00000000,
     0x00000000, 0x00000000, 0x00000000, 0x00000000, 0x00000000, 0x00000000, 0x00000000,
@@ -54,15 +63,21 @@ impl VendorPrivateData for Amd {
         &self,
         create_info: &MagmaCreateBufferInfo,
         mem_props: &MagmaMemoryProperties,
-    ) -> Vec<u32> {
+    ) -> MesaResult<Vec<u32>> {
+        // TODO: Only VRAM and GTT layouts have been captured.  GDS and OA live in on-chip memory,
+        // and their private data needs a capture from the AMD KMD before it can be filled in.
+        if create_info.vendor_flags & (MAGMA_BUFFER_FLAG_AMD_OA | MAGMA_BUFFER_FLAG_AMD_GDS) != 0 {
+            return Err(MesaError::Unsupported);
+        }
+
         let mut alloc_pdata = AMD_ALLOC_PDATA;
         let memory_type = mem_props.get_memory_type(create_info.memory_type_idx);
 
-        // FIXME: gpu_info.pte_fragment_size, alignment
-        // Need GPU topology crate
-        let size: u32 = 0;
-        let phys_size: u32 = 0;
-        let phys_alignment: u32 = 0;
+        // The layout only has room for 32-bit sizes.
+        let alignment = u64::from(create_info.alignment).max(AMD_PTE_FRAGMENT_SIZE);
+        let size: u32 = create_info.size.try_into()?;
+        let phys_size: u32 = create_info.size.next_multiple_of(alignment).try_into()?;
+        let phys_alignment: u32 = alignment.try_into()?;
 
         alloc_pdata[21] = phys_size;
         alloc_pdata[22] = phys_alignment;
@@ -101,6 +116,6 @@ impl VendorPrivateData for Amd {
             alloc_pdata[25] = 4;
         }
 
-        Vec::from(alloc_pdata)
+        Ok(Vec::from(alloc_pdata))
     }
 }
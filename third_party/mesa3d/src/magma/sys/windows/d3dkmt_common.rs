@@ -6,25 +6,35 @@ use std::slice::from_raw_parts;
 use std::sync::Arc;
 
 use libc::wcslen;
-use log::error;
 
+use mesa3d_util::FromRawDescriptor;
 use mesa3d_util::IntoRawDescriptor;
 use mesa3d_util::MappedRegion;
 use mesa3d_util::MesaError;
 use mesa3d_util::MesaHandle;
 use mesa3d_util::MesaMapping;
 use mesa3d_util::MesaResult;
+use mesa3d_util::OwnedDescriptor;
+use mesa3d_util::MESA_HANDLE_TYPE_MEM_OPAQUE_WIN32;
 
 use crate::check_ntstatus;
 use crate::log_ntstatus;
+use crate::magma_defines::MagmaContextPriority;
 use crate::magma_defines::MagmaCreateBufferInfo;
+use crate::magma_defines::MagmaEngineClass;
+use crate::magma_defines::MagmaEngineInfo;
 use crate::magma_defines::MagmaHeapBudget;
 use crate::magma_defines::MagmaImportHandleInfo;
 use crate::magma_defines::MagmaMappedMemoryRange;
 use crate::magma_defines::MagmaMemoryProperties;
 use crate::magma_defines::MagmaPciBusInfo;
 use crate::magma_defines::MagmaPciInfo;
+use crate::magma_defines::MagmaQueryResult;
+use crate::magma_defines::MAGMA_BUFFER_FLAG_EXTERNAL;
 use crate::magma_defines::MAGMA_HEAP_DEVICE_LOCAL_BIT;
+use crate::magma_defines::MAGMA_HEAP_PRESERVED_ON_SUSPEND_BIT;
+use crate::magma_defines::MAGMA_MAP_GPU_FLAG_EXECUTE;
+use crate::magma_defines::MAGMA_MAP_GPU_FLAG_WRITE;
 use crate::magma_defines::MAGMA_MEMORY_PROPERTY_DEVICE_LOCAL_BIT;
 use crate::magma_defines::MAGMA_MEMORY_PROPERTY_HOST_CACHED_BIT;
 use crate::magma_defines::MAGMA_MEMORY_PROPERTY_HOST_COHERENT_BIT;
@@ -41,15 +51,33 @@ use crate::traits::Buffer;
 use crate::traits::Context;
 use crate::traits::Device;
 use crate::traits::GenericBuffer;
+use crate::traits::GenericContext;
 use crate::traits::GenericDevice;
 use crate::traits::GenericPhysicalDevice;
 use crate::traits::PhysicalDevice;
 
+use windows_sys::Wdk::Foundation::OBJECT_ATTRIBUTES;
 use windows_sys::Wdk::Graphics::Direct3D::*;
+use windows_sys::Win32::Foundation::GENERIC_ALL;
+use windows_sys::Win32::Foundation::HANDLE;
 use windows_sys::Win32::Foundation::LUID;
 
 type D3dkmtHandle = u32;
 
+// Bits of D3DKMT_CREATEALLOCATIONFLAGS, which windows-sys only exposes as a raw bitfield.
+const D3DKMT_CREATEALLOCATION_CREATE_RESOURCE: u32 = 1 << 0;
+const D3DKMT_CREATEALLOCATION_CREATE_SHARED: u32 = 1 << 1;
+const D3DKMT_CREATEALLOCATION_NT_SECURITY_SHARING: u32 = 1 << 6;
+
+// Bits of D3DDDIGPUVIRTUALADDRESS_PROTECTION_TYPE.  Mappings are always readable.
+const D3DDDIGPUVIRTUALADDRESS_PROTECTION_WRITE: u64 = 1 << 0;
+const D3DDDIGPUVIRTUALADDRESS_PROTECTION_EXECUTE: u64 = 1 << 1;
+
+const WDDM_PAGE_SIZE: u64 = 4096;
+
+// Node ordinals are dense, so enumeration stops at the first one that fails to query.
+const WDDM_MAX_NODES: u32 = 64;
+
 pub struct WddmAdapter {
     handle: D3dkmtHandle,
     _luid: LUID,
@@ -65,17 +93,26 @@ pub struct WddmDevice {
     adapter: Arc<dyn PhysicalDevice>,
     vendor_private_data: Box<dyn VendorPrivateData>,
     mem_props: MagmaMemoryProperties,
+    // The engine class of each node, by node ordinal.
+    node_classes: Vec<Option<MagmaEngineClass>>,
 }
 
 pub struct WddmBuffer {
     handle: D3dkmtHandle,
+    // Set for buffers created with MAGMA_BUFFER_FLAG_EXTERNAL, which are shared through their
+    // resource.
+    resource: D3dkmtHandle,
     device: Arc<dyn Device>,
     size: u64,
 }
 
 pub struct WddmContext {
     handle: D3dkmtHandle,
-    _device: Arc<dyn Device>,
+    adapter_handle: D3dkmtHandle,
+    // GPU virtual address updates are queued here, and signal its sync object when done.
+    paging_queue: D3dkmtHandle,
+    paging_sync_object: D3dkmtHandle,
+    device: Arc<dyn Device>,
 }
 
 struct WddmMapping {
@@ -236,10 +273,13 @@ impl GenericPhysicalDevice for WddmAdapter {
         &self,
         physical_device: &Arc<dyn PhysicalDevice>,
         pci_info: &MagmaPciInfo,
+        // WDDM contexts always manage their own GPU virtual address space.
+        _flags: u32,
     ) -> MesaResult<Arc<dyn Device>> {
-        let vendor_private_data = match pci_info.vendor_id {
+        let vendor_private_data: Box<dyn VendorPrivateData> = match pci_info.vendor_id {
             MAGMA_VENDOR_ID_AMD => Box::new(Amd(())),
-            _ => todo!(),
+            // Allocations need vendor private data, which is only known for AMD.
+            _ => return Err(MesaError::Unsupported),
         };
 
         let device = WddmDevice::new(physical_device.clone(), vendor_private_data)?;
@@ -260,6 +300,9 @@ impl WindowsPhysicalDevice for WddmAdapter {
 impl AsVirtGpu for WddmAdapter {}
 impl PhysicalDevice for WddmAdapter {}
 
+unsafe impl Send for WddmAdapter {}
+unsafe impl Sync for WddmAdapter {}
+
 impl Drop for WddmAdapter {
     fn drop(&mut self) {
         let mut close = D3DKMT_CLOSEADAPTER {
@@ -304,6 +347,53 @@ pub fn enumerate_adapters() -> MesaResult<Vec<(WddmAdapter, MagmaPciInfo, MagmaP
     Ok(adapters)
 }
 
+// Returns the engine class of each node of the adapter, by node ordinal.  Nodes without a class,
+// such as overlay or crypto engines, are None.  Compute work runs on 3D nodes, so there are no
+// compute nodes.
+fn query_node_classes(adapter: D3dkmtHandle) -> Vec<Option<MagmaEngineClass>> {
+    let mut node_classes = Vec::new();
+    for node_ordinal in 0..WDDM_MAX_NODES {
+        let mut node_metadata = D3DKMT_NODEMETADATA {
+            NodeOrdinalAndAdapterIndex: node_ordinal,
+            ..Default::default()
+        };
+
+        let mut adapter_info = D3DKMT_QUERYADAPTERINFO {
+            hAdapter: adapter,
+            Type: KMTQAITYPE_NODEMETADATA,
+            pPrivateDriverData: &mut node_metadata as *mut D3DKMT_NODEMETADATA as *mut c_void,
+            PrivateDriverDataSize: std::mem::size_of::<D3DKMT_NODEMETADATA>() as u32,
+        };
+
+        // SAFETY:
+        //  - `adapter_info` is stack-allocated and properly typed.
+        //  - `pPrivateDriverData` and `PrivateDriverDataSize` are both correct for the
+        //      KMTQAITYPE_NODEMETADATA operation
+        let result = check_ntstatus!(unsafe {
+            D3DKMTQueryAdapterInfo(&mut adapter_info as *mut D3DKMT_QUERYADAPTERINFO)
+        });
+        if result.is_err() {
+            break;
+        }
+
+        node_classes.push(match node_metadata.NodeData.EngineType {
+            DXGK_ENGINE_TYPE_3D => Some(MagmaEngineClass::Render),
+            DXGK_ENGINE_TYPE_COPY => Some(MagmaEngineClass::Copy),
+            DXGK_ENGINE_TYPE_VIDEO_DECODE
+            | DXGK_ENGINE_TYPE_VIDEO_ENCODE
+            | DXGK_ENGINE_TYPE_VIDEO_PROCESSING => Some(MagmaEngineClass::Video),
+            _ => None,
+        });
+    }
+
+    // Drivers without node metadata have a single 3D node.
+    if node_classes.is_empty() {
+        node_classes.push(Some(MagmaEngineClass::Render));
+    }
+
+    node_classes
+}
+
 impl WddmDevice {
     pub fn new(
         adapter: Arc<dyn PhysicalDevice>,
@@ -325,7 +415,10 @@ impl WddmDevice {
 
         let segment_group_size = adapter.segment_group_size();
         if segment_group_size.NonLocalMemory > 0 {
-            mem_props.add_heap(segment_group_size.NonLocalMemory, 0);
+            mem_props.add_heap(
+                segment_group_size.NonLocalMemory,
+                MAGMA_HEAP_PRESERVED_ON_SUSPEND_BIT,
+            );
             mem_props.add_memory_type(
                 MAGMA_MEMORY_PROPERTY_HOST_COHERENT_BIT | MAGMA_MEMORY_PROPERTY_HOST_VISIBLE_BIT,
             );
@@ -343,11 +436,14 @@ impl WddmDevice {
             mem_props.increment_heap_count();
         }
 
+        let node_classes = query_node_classes(adapter.as_wddm_handle());
+
         Ok(WddmDevice {
             handle: arg.hDevice,
             adapter,
             vendor_private_data,
             mem_props,
+            node_classes,
         })
     }
 }
@@ -388,8 +484,54 @@ impl GenericDevice for WddmDevice {
         })
     }
 
-    fn create_context(&self, device: &Arc<dyn Device>) -> MesaResult<Arc<dyn Context>> {
-        let ctx = WddmContext::new(device.clone())?;
+    // Ids are KMTQUERYADAPTERINFOTYPE values.  `input` is the private driver data, which the
+    // kernel fills in and is returned.
+    fn query(&self, id: u64, input: &[u8]) -> MesaResult<MagmaQueryResult> {
+        let mut data = input.to_vec();
+        let mut adapter_info = D3DKMT_QUERYADAPTERINFO {
+            hAdapter: self.adapter.as_wddm_handle(),
+            Type: id.try_into()?,
+            pPrivateDriverData: data.as_mut_ptr() as *mut c_void,
+            PrivateDriverDataSize: data.len().try_into()?,
+        };
+
+        // SAFETY:
+        //  - `adapter_info` is stack-allocated and properly typed.
+        //  - `pPrivateDriverData` points to `data`, which holds `PrivateDriverDataSize` bytes.
+        check_ntstatus!(unsafe {
+            D3DKMTQueryAdapterInfo(&mut adapter_info as *mut D3DKMT_QUERYADAPTERINFO)
+        })?;
+
+        Ok(MagmaQueryResult::Buffer(data))
+    }
+
+    fn enumerate_engines(&self) -> MesaResult<Vec<MagmaEngineInfo>> {
+        let mut engines = Vec::new();
+        for engine_class in self.node_classes.iter().flatten() {
+            MagmaEngineInfo::add(&mut engines, *engine_class, 1);
+        }
+
+        Ok(engines)
+    }
+
+    // WDDM schedules contexts by process priority class, which is left to the caller.
+    fn create_context(
+        &self,
+        device: &Arc<dyn Device>,
+        _priority: MagmaContextPriority,
+        engine_class: MagmaEngineClass,
+    ) -> MesaResult<Arc<dyn Context>> {
+        let node_ordinal = self
+            .node_classes
+            .iter()
+            .position(|c| *c == Some(engine_class))
+            .ok_or(MesaError::Unsupported)?;
+
+        let ctx = WddmContext::new(
+            device.clone(),
+            self.adapter.as_wddm_handle(),
+            node_ordinal as u32,
+        )?;
         Ok(Arc::new(ctx))
     }
 
@@ -433,6 +575,10 @@ impl GenericDevice for WddmDevice {
             WddmBuffer::from_existing(device.clone(), open_alloc_info.hAllocation, info.size)?;
         Ok(Arc::new(buf))
     }
+
+    fn supports_buffer_placement(&self) -> bool {
+        true
+    }
 }
 
 impl Drop for WddmDevice {
@@ -460,11 +606,15 @@ impl WindowsDevice for WddmDevice {
 impl Device for WddmDevice {}
 
 impl WddmContext {
-    pub fn new(device: Arc<dyn Device>) -> MesaResult<WddmContext> {
-        // TODO: Fill in NodeOrdinal, EngineAffinity, pPrivateDriverData
+    pub fn new(
+        device: Arc<dyn Device>,
+        adapter_handle: D3dkmtHandle,
+        node_ordinal: u32,
+    ) -> MesaResult<WddmContext> {
+        // TODO: Fill in EngineAffinity, pPrivateDriverData
         let mut arg = D3DKMT_CREATECONTEXTVIRTUAL {
             hDevice: device.as_wddm_handle(),
-            NodeOrdinal: Default::default(),
+            NodeOrdinal: node_ordinal,
             EngineAffinity: Default::default(),
             Flags: D3DDDI_CREATECONTEXTFLAGS {
                 Anonymous: D3DDDI_CREATECONTEXTFLAGS_0 {
@@ -481,15 +631,70 @@ impl WddmContext {
             D3DKMTCreateContextVirtual(&mut arg as *mut D3DKMT_CREATECONTEXTVIRTUAL)
         })?;
 
+        let mut paging_queue = D3DKMT_CREATEPAGINGQUEUE {
+            hDevice: device.as_wddm_handle(),
+            Priority: D3DDDI_PAGINGQUEUE_PRIORITY_NORMAL,
+            ..Default::default()
+        };
+
+        // SAFETY:
+        // `paging_queue` is stack-allocated and properly typed, and D3DKMT only writes its output
+        // fields.
+        let status =
+            unsafe { D3DKMTCreatePagingQueue(&mut paging_queue as *mut D3DKMT_CREATEPAGINGQUEUE) };
+
+        if status != windows_sys::Win32::Foundation::STATUS_SUCCESS {
+            // Safe because const arg is allocated locally on the stack and we trust the D3DKMT
+            // API not to modify any other memory.
+            log_ntstatus!(unsafe {
+                D3DKMTDestroyContext(&D3DKMT_DESTROYCONTEXT {
+                    hContext: arg.hContext,
+                } as *const D3DKMT_DESTROYCONTEXT)
+            });
+            check_ntstatus!(status)?;
+        }
+
         Ok(WddmContext {
             handle: arg.hContext,
-            _device: device,
+            adapter_handle,
+            paging_queue: paging_queue.hPagingQueue,
+            paging_sync_object: paging_queue.hSyncObject,
+            device,
+        })
+    }
+
+    // Blocks until the paging queue has executed the operation that returned `fence_value`.
+    fn wait_paging_fence(&self, fence_value: u64) -> MesaResult<()> {
+        let wait = D3DKMT_WAITFORSYNCHRONIZATIONOBJECTFROMCPU {
+            hDevice: self.device.as_wddm_handle(),
+            ObjectCount: 1,
+            ObjectHandleArray: &self.paging_sync_object,
+            FenceValueArray: &fence_value,
+            // A null event makes the wait synchronous.
+            hAsyncEvent: std::ptr::null_mut(),
+            Flags: Default::default(),
+        };
+
+        // SAFETY:
+        // `wait` is stack-allocated and properly typed, and its arrays hold one element each.
+        check_ntstatus!(unsafe {
+            D3DKMTWaitForSynchronizationObjectFromCpu(
+                &wait as *const D3DKMT_WAITFORSYNCHRONIZATIONOBJECTFROMCPU,
+            )
         })
     }
 }
 
 impl Drop for WddmContext {
     fn drop(&mut self) {
+        // Safe because const arg is allocated locally on the stack and we trust the D3DKMT API
+        // not to modify any other memory.
+        log_ntstatus!(unsafe {
+            D3DKMTDestroyPagingQueue(&mut D3DDDI_DESTROYPAGINGQUEUE {
+                hPagingQueue: self.paging_queue,
+            } as *mut D3DDDI_DESTROYPAGINGQUEUE)
+        });
+
         // Safe because const arg is allocated locally on the stack and we trust the D3DKMT API
         // not to modify any other memory.
         log_ntstatus!(unsafe {
@@ -500,6 +705,56 @@ impl Drop for WddmContext {
     }
 }
 
+// WDDM 2.0 drivers leave the GPU virtual address space to the user-mode driver.  Mappings are
+// made by the paging queue, and are waited for so the caller can use them immediately.
+impl GenericContext for WddmContext {
+    fn map_gpu(&self, buffer: &Arc<dyn Buffer>, gpu_va: u64, flags: u64) -> MesaResult<()> {
+        let mut protection: D3DDDIGPUVIRTUALADDRESS_PROTECTION_TYPE = Default::default();
+        let mut bits = 0;
+        if flags & MAGMA_MAP_GPU_FLAG_WRITE != 0 {
+            bits |= D3DDDIGPUVIRTUALADDRESS_PROTECTION_WRITE;
+        }
+        if flags & MAGMA_MAP_GPU_FLAG_EXECUTE != 0 {
+            bits |= D3DDDIGPUVIRTUALADDRESS_PROTECTION_EXECUTE;
+        }
+        protection.Anonymous.Value = bits;
+
+        let mut map = D3DDDI_MAPGPUVIRTUALADDRESS {
+            hPagingQueue: self.paging_queue,
+            BaseAddress: gpu_va,
+            hAllocation: buffer.gem_handle()?,
+            OffsetInPages: 0,
+            SizeInPages: buffer.size().div_ceil(WDDM_PAGE_SIZE),
+            Protection: protection,
+            ..Default::default()
+        };
+
+        // SAFETY:
+        // `map` is stack-allocated and properly typed, and D3DKMT only writes its output fields.
+        let status = unsafe { D3DKMTMapGpuVirtualAddress(&mut map as *mut _) };
+        match status {
+            windows_sys::Win32::Foundation::STATUS_SUCCESS => Ok(()),
+            windows_sys::Win32::Foundation::STATUS_PENDING => {
+                self.wait_paging_fence(map.PagingFenceValue)
+            }
+            e => check_ntstatus!(e),
+        }
+    }
+
+    fn unmap_gpu(&self, buffer: &Arc<dyn Buffer>, gpu_va: u64) -> MesaResult<()> {
+        let free = D3DKMT_FREEGPUVIRTUALADDRESS {
+            hAdapter: self.adapter_handle,
+            BaseAddress: gpu_va,
+            Size: buffer.size().div_ceil(WDDM_PAGE_SIZE) * WDDM_PAGE_SIZE,
+        };
+
+        // SAFETY:
+        // `free` is stack-allocated and properly typed, and D3DKMT doesn't modify it.
+        check_ntstatus!(unsafe {
+            D3DKMTFreeGpuVirtualAddress(&free as *const D3DKMT_FREEGPUVIRTUALADDRESS)
+        })
+    }
+}
 impl Context for WddmContext {}
 
 impl WddmBuffer {
@@ -510,15 +765,22 @@ impl WddmBuffer {
     ) -> MesaResult<WddmBuffer> {
         let vendor_private_data = device.vendor_private_data().unwrap();
 
-        let flags: D3DKMT_CREATEALLOCATIONFLAGS = Default::default();
+        let mut flags: D3DKMT_CREATEALLOCATIONFLAGS = Default::default();
 
         // flags.set_NonSecure(1);
         // flags.set_CreateWriteCombined(1);
 
+        // Only resources can be shared, and NT handles are needed to share with other processes.
+        if create_info.common_flags & MAGMA_BUFFER_FLAG_EXTERNAL != 0 {
+            flags._bitfield |= D3DKMT_CREATEALLOCATION_CREATE_RESOURCE
+                | D3DKMT_CREATEALLOCATION_CREATE_SHARED
+                | D3DKMT_CREATEALLOCATION_NT_SECURITY_SHARING;
+        }
+
         // type annotations important for following calculation
         let mut create_allocation: Vec<u32> = vendor_private_data.createallocation_pdata();
         let mut allocationinfo2: Vec<u32> =
-            vendor_private_data.allocationinfo2_pdata(create_info, mem_props);
+            vendor_private_data.allocationinfo2_pdata(create_info, mem_props)?;
 
         let size_create_allocation: usize = create_allocation.len() * std::mem::size_of::<u32>();
         let size_allocationinfo2: usize = allocationinfo2.len() * std::mem::size_of::<u32>();
@@ -553,6 +815,7 @@ impl WddmBuffer {
 
         Ok(WddmBuffer {
             handle: alloc_info.hAllocation,
+            resource: arg.hResource,
             device,
             size: create_info.size,
         })
@@ -564,6 +827,7 @@ impl WddmBuffer {
     ) -> MesaResult<WddmBuffer> {
         Ok(WddmBuffer {
             handle,
+            resource: 0,
             device,
             size,
         })
@@ -608,7 +872,34 @@ impl GenericBuffer for WddmBuffer {
     }
 
     fn export(&self) -> MesaResult<MesaHandle> {
-        Err(MesaError::Unsupported)
+        if self.resource == 0 {
+            return Err(MesaError::Unsupported);
+        }
+
+        let object_attributes = OBJECT_ATTRIBUTES {
+            Length: std::mem::size_of::<OBJECT_ATTRIBUTES>().try_into()?,
+            ..Default::default()
+        };
+        let mut nt_handle: HANDLE = std::ptr::null_mut();
+
+        // Safe because the resource is owned by this buffer, and the arguments are allocated
+        // locally on the stack.
+        check_ntstatus!(unsafe {
+            D3DKMTShareObjects(
+                1,
+                &self.resource as *const D3dkmtHandle,
+                &object_attributes as *const OBJECT_ATTRIBUTES,
+                GENERIC_ALL,
+                &mut nt_handle as *mut HANDLE,
+            )
+        })?;
+
+        // Safe because D3DKMTShareObjects returned a new NT handle, which nothing else owns.
+        let os_handle = unsafe { OwnedDescriptor::from_raw_descriptor(nt_handle) };
+        Ok(MesaHandle {
+            os_handle,
+            handle_type: MESA_HANDLE_TYPE_MEM_OPAQUE_WIN32,
+        })
     }
 
     fn invalidate(&self, sync_flags: u64, ranges: &[MagmaMappedMemoryRange]) -> MesaResult<()> {
@@ -639,17 +930,32 @@ impl GenericBuffer for WddmBuffer {
     fn flush(&self, _sync_flags: u64, _ranges: &[MagmaMappedMemoryRange]) -> MesaResult<()> {
         Ok(())
     }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    // The allocation handle, which contexts of the same device map into their address space.
+    fn gem_handle(&self) -> MesaResult<u32> {
+        Ok(self.handle)
+    }
 }
 
 impl Drop for WddmBuffer {
     fn drop(&mut self) {
         // Safe because const arg is allocated locally on the stack and we trust the D3DKMT API
         // not to modify any other memory.
+        // Destroying a resource destroys its allocations.
+        let (allocation_list, allocation_count) = if self.resource != 0 {
+            (std::ptr::null(), 0)
+        } else {
+            (&self.handle as *const D3dkmtHandle, 1)
+        };
         let arg = D3DKMT_DESTROYALLOCATION2 {
             hDevice: self.device.as_wddm_handle(),
-            hResource: Default::default(),
-            phAllocationList: &self.handle as *const D3dkmtHandle,
-            AllocationCount: 1,
+            hResource: self.resource,
+            phAllocationList: allocation_list,
+            AllocationCount: allocation_count,
             Flags: D3DDDICB_DESTROYALLOCATION2FLAGS {
                 Anonymous: D3DDDICB_DESTROYALLOCATION2FLAGS_0 {
                     Value: Default::default(),
@@ -19,7 +19,7 @@ macro_rules! log_ntstatus {
     ($x: expr) => {{
         match $x {
             windows_sys::Win32::Foundation::STATUS_SUCCESS => (),
-            e => error!("logging error status: {:#X}", e),
+            e => $crate::magma_error!("logging error status: {:#X}", e),
         }
     }};
 }
@@ -14,12 +14,13 @@ pub trait VendorPrivateData {
         Vec::new()
     }
 
+    /// Fails with `Unsupported` for buffers the vendor layout can't describe.
     fn allocationinfo2_pdata(
         &self,
         _create_info: &MagmaCreateBufferInfo,
         _mem_props: &MagmaMemoryProperties,
-    ) -> Vec<u32> {
-        Vec::new()
+    ) -> MesaResult<Vec<u32>> {
+        Ok(Vec::new())
     }
 }
 
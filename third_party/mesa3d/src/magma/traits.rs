@@ -4,16 +4,22 @@
 use std::sync::Arc;
 
 use mesa3d_util::MappedRegion;
+use mesa3d_util::MesaError;
 use mesa3d_util::MesaHandle;
 use mesa3d_util::MesaResult;
+use mesa3d_util::OwnedDescriptor;
 use virtgpu_kumquat::VirtGpuKumquat;
 
+use crate::magma_defines::MagmaContextPriority;
 use crate::magma_defines::MagmaCreateBufferInfo;
+use crate::magma_defines::MagmaEngineClass;
+use crate::magma_defines::MagmaEngineInfo;
 use crate::magma_defines::MagmaHeapBudget;
 use crate::magma_defines::MagmaImportHandleInfo;
 use crate::magma_defines::MagmaMappedMemoryRange;
 use crate::magma_defines::MagmaMemoryProperties;
 use crate::magma_defines::MagmaPciInfo;
+use crate::magma_defines::MagmaQueryResult;
 use crate::sys::platform::PlatformDevice;
 use crate::sys::platform::PlatformPhysicalDevice;
 
@@ -24,10 +30,12 @@ pub trait AsVirtGpu {
 }
 
 pub trait GenericPhysicalDevice {
+    /// Creates a device with the MAGMA_DEVICE_FLAG_* bits in `flags`.
     fn create_device(
         &self,
         physical_device: &Arc<dyn PhysicalDevice>,
         pci_info: &MagmaPciInfo,
+        flags: u32,
     ) -> MesaResult<Arc<dyn Device>>;
 }
 
@@ -36,7 +44,14 @@ pub trait GenericDevice {
 
     fn get_memory_budget(&self, _heap_idx: u32) -> MesaResult<MagmaHeapBudget>;
 
-    fn create_context(&self, device: &Arc<dyn Device>) -> MesaResult<Arc<dyn Context>>;
+    /// Creates a context on an engine of `engine_class`, which is one of the classes returned by
+    /// `enumerate_engines`.
+    fn create_context(
+        &self,
+        device: &Arc<dyn Device>,
+        priority: MagmaContextPriority,
+        engine_class: MagmaEngineClass,
+    ) -> MesaResult<Arc<dyn Context>>;
 
     fn create_buffer(
         &self,
@@ -49,6 +64,33 @@ pub trait GenericDevice {
         _device: &Arc<dyn Device>,
         _info: MagmaImportHandleInfo,
     ) -> MesaResult<Arc<dyn Buffer>>;
+
+    /// Returns true if contexts of the device can map sparse buffers, which have no backing
+    /// pages.
+    fn supports_sparse_buffers(&self) -> bool {
+        false
+    }
+
+    /// Returns true if buffers are allocated in the memory type they are created with.  Drivers
+    /// that ignore `memory_type_idx` return false.
+    fn supports_buffer_placement(&self) -> bool {
+        false
+    }
+
+    /// Returns the engines of the device, by class.  Drivers that can't tell report a single
+    /// render engine.
+    fn enumerate_engines(&self) -> MesaResult<Vec<MagmaEngineInfo>> {
+        Ok(vec![MagmaEngineInfo {
+            engine_class: MagmaEngineClass::Render,
+            count: 1,
+        }])
+    }
+
+    /// Makes the driver-specific query `id`, such as an AMDGPU_INFO_* or I915_PARAM_* value, with
+    /// `input` as its arguments.  Queries the driver doesn't expose return `Unsupported`.
+    fn query(&self, _id: u64, _input: &[u8]) -> MesaResult<MagmaQueryResult> {
+        Err(MesaError::Unsupported)
+    }
 }
 
 pub trait GenericBuffer {
@@ -59,9 +101,56 @@ pub trait GenericBuffer {
     fn invalidate(&self, sync_flags: u64, ranges: &[MagmaMappedMemoryRange]) -> MesaResult<()>;
 
     fn flush(&self, sync_flags: u64, ranges: &[MagmaMappedMemoryRange]) -> MesaResult<()>;
+
+    fn size(&self) -> u64;
+
+    /// Returns the kernel handle of the buffer, which is only meaningful to contexts of the same
+    /// device.
+    fn gem_handle(&self) -> MesaResult<u32> {
+        Err(MesaError::Unsupported)
+    }
+
+    /// Returns true if the buffer only reserves GPU virtual address space.  Contexts map such
+    /// buffers without backing pages.
+    fn is_sparse(&self) -> bool {
+        false
+    }
 }
 
-pub trait PhysicalDevice: PlatformPhysicalDevice + AsVirtGpu + GenericPhysicalDevice {}
-pub trait Device: GenericDevice + PlatformDevice {}
-pub trait Context {}
-pub trait Buffer: GenericBuffer {}
+pub trait GenericContext {
+    /// Binds all of `buffer` into the context's GPU virtual address space at `gpu_va`, with the
+    /// access given by the MAGMA_MAP_GPU_FLAG_* bits in `flags`.  Only called on devices created
+    /// with MAGMA_DEVICE_FLAG_USER_GPU_VA.
+    fn map_gpu(&self, _buffer: &Arc<dyn Buffer>, _gpu_va: u64, _flags: u64) -> MesaResult<()> {
+        Err(MesaError::Unsupported)
+    }
+
+    /// Removes the binding of `buffer` at `gpu_va`.
+    fn unmap_gpu(&self, _buffer: &Arc<dyn Buffer>, _gpu_va: u64) -> MesaResult<()> {
+        Err(MesaError::Unsupported)
+    }
+
+    /// Submits `commands`, which reference `buffers`, to the device.  The command stream is
+    /// opaque to magma and is only understood by paravirtualized devices.
+    fn submit(&self, _commands: &[u8], _buffers: &[Arc<dyn Buffer>]) -> MesaResult<()> {
+        Err(MesaError::Unsupported)
+    }
+
+    /// Submits like `submit`, and returns a sync file that signals once the submission completes.
+    fn submit_fenced(
+        &self,
+        _commands: &[u8],
+        _buffers: &[Arc<dyn Buffer>],
+    ) -> MesaResult<OwnedDescriptor> {
+        Err(MesaError::Unsupported)
+    }
+}
+
+// Objects are shared across threads by the C API, so implementations must be thread-safe.
+pub trait PhysicalDevice:
+    PlatformPhysicalDevice + AsVirtGpu + GenericPhysicalDevice + Send + Sync
+{
+}
+pub trait Device: GenericDevice + PlatformDevice + Send + Sync {}
+pub trait Context: GenericContext + Send + Sync {}
+pub trait Buffer: GenericBuffer + Send + Sync {}
@@ -8,6 +8,7 @@ mod error;
 mod memory_mapping;
 mod shm;
 mod sys;
+mod thread;
 
 pub use bytestream::Reader;
 pub use bytestream::Writer;
@@ -20,6 +21,7 @@ pub use error::MesaError;
 pub use error::MesaResult;
 pub use memory_mapping::MemoryMapping;
 pub use shm::round_up_to_page_size;
+pub use shm::seal_shared_memory;
 pub use shm::SharedMemory;
 pub use sys::platform::descriptor::OwnedDescriptor;
 pub use sys::platform::descriptor::RawDescriptor;
@@ -31,3 +33,5 @@ pub use sys::platform::pipe::WritePipe;
 pub use sys::platform::tube::Listener;
 pub use sys::platform::tube::Tube;
 pub use sys::platform::wait_context::WaitContext;
+pub use thread::MesaSchedulingPolicy;
+pub use thread::MesaThreadScheduling;
@@ -4,6 +4,7 @@
 use std::ffi::CString;
 
 use crate::sys::platform::page_size;
+use crate::sys::platform::seal_shared_memory as platform_seal_shared_memory;
 use crate::sys::platform::SharedMemory as PlatformSharedMemory;
 use crate::AsRawDescriptor;
 use crate::FromRawDescriptor;
@@ -49,6 +50,12 @@ impl From<SharedMemory> for OwnedDescriptor {
     }
 }
 
+/// Seals shared memory, so its size and contents can no longer change.  Fails while writable
+/// mappings of it exist.
+pub fn seal_shared_memory(descriptor: &OwnedDescriptor) -> MesaResult<()> {
+    platform_seal_shared_memory(descriptor)
+}
+
 /// Uses the system's page size in bytes to round the given value up to the nearest page boundary.
 pub fn round_up_to_page_size(v: u64) -> MesaResult<u64> {
     v.checked_next_multiple_of(page_size()? as _)
@@ -5,8 +5,12 @@ use std::os::fd::OwnedFd;
 
 use rustix::event::eventfd;
 use rustix::event::EventfdFlags;
+use rustix::fs::fcntl_getfl;
+use rustix::fs::fcntl_setfl;
+use rustix::fs::OFlags;
 use rustix::io::read;
 use rustix::io::write;
+use rustix::io::Errno;
 
 use crate::AsBorrowedDescriptor;
 use crate::MesaError;
@@ -37,6 +41,21 @@ impl Event {
         Ok(())
     }
 
+    /// Consumes a pending signal without blocking, and returns false if there was none.  Makes the
+    /// eventfd non-blocking, for its clones and importers as well.
+    pub fn try_wait(&self) -> MesaResult<bool> {
+        let flags = fcntl_getfl(&self.descriptor)?;
+        if !flags.contains(OFlags::NONBLOCK) {
+            fcntl_setfl(&self.descriptor, flags | OFlags::NONBLOCK)?;
+        }
+
+        match read(&self.descriptor, &mut 1u64.to_ne_bytes()) {
+            Ok(_) => Ok(true),
+            Err(Errno::AGAIN) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
     pub fn try_clone(&self) -> MesaResult<Event> {
         let clone = self.descriptor.try_clone()?;
         Ok(Event { descriptor: clone })
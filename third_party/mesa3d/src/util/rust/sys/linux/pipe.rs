@@ -1,9 +1,11 @@
 // Copyright 2025 Google
 // SPDX-License-Identifier: MIT
 
+use std::io::IoSliceMut;
 use std::os::fd::AsFd;
 
 use rustix::io::read;
+use rustix::io::readv;
 use rustix::io::write;
 use rustix::pipe::pipe;
 
@@ -39,6 +41,12 @@ impl ReadPipe {
         let bytes_read = read(&self.descriptor, data)?;
         Ok(bytes_read)
     }
+
+    /// Reads into several buffers with a single syscall, filling them in order.
+    pub fn read_vectored(&self, bufs: &mut [IoSliceMut]) -> MesaResult<usize> {
+        let bytes_read = readv(&self.descriptor, bufs)?;
+        Ok(bytes_read)
+    }
 }
 
 impl AsBorrowedDescriptor for ReadPipe {
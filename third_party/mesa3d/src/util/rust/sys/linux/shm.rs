@@ -6,13 +6,16 @@ use std::os::fd::AsRawFd;
 use std::os::fd::IntoRawFd;
 use std::os::unix::io::OwnedFd;
 
+use rustix::fs::fcntl_add_seals;
 use rustix::fs::ftruncate;
 use rustix::fs::memfd_create;
 use rustix::fs::MemfdFlags;
+use rustix::fs::SealFlags;
 
 use crate::descriptor::AsRawDescriptor;
 use crate::descriptor::IntoRawDescriptor;
 use crate::MesaResult;
+use crate::OwnedDescriptor;
 use crate::RawDescriptor;
 
 pub struct SharedMemory {
@@ -56,6 +59,14 @@ impl IntoRawDescriptor for SharedMemory {
     }
 }
 
+pub fn seal_shared_memory(descriptor: &OwnedDescriptor) -> MesaResult<()> {
+    fcntl_add_seals(
+        descriptor,
+        SealFlags::SEAL | SealFlags::SHRINK | SealFlags::GROW | SealFlags::WRITE,
+    )?;
+    Ok(())
+}
+
 pub fn page_size() -> MesaResult<u64> {
     // TODO: Once all platforms support it, use rustix page_size() function everywhere.
     Ok(rustix::param::page_size() as _)
@@ -0,0 +1,126 @@
+// Copyright 2025 Google
+// SPDX-License-Identifier: MIT
+
+use std::io::Error as IoError;
+
+use crate::MesaError;
+use crate::MesaResult;
+use crate::MesaSchedulingPolicy;
+use crate::MesaThreadScheduling;
+
+fn check_errno(ret: libc::c_int) -> MesaResult<()> {
+    if ret < 0 {
+        return Err(IoError::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+pub fn set_current_thread_scheduling(scheduling: &MesaThreadScheduling) -> MesaResult<()> {
+    let (policy, realtime) = match scheduling.policy {
+        MesaSchedulingPolicy::Other => (libc::SCHED_OTHER, false),
+        MesaSchedulingPolicy::Batch => (libc::SCHED_BATCH, false),
+        MesaSchedulingPolicy::Idle => (libc::SCHED_IDLE, false),
+        MesaSchedulingPolicy::Fifo => (libc::SCHED_FIFO, true),
+        MesaSchedulingPolicy::RoundRobin => (libc::SCHED_RR, true),
+    };
+
+    let param = libc::sched_param {
+        sched_priority: if realtime { scheduling.priority } else { 0 },
+    };
+
+    // SAFETY:
+    // A pid of 0 is the calling thread, and `param` is a valid sched_param.
+    check_errno(unsafe { libc::sched_setscheduler(0, policy, &param) })?;
+
+    if !realtime && scheduling.policy != MesaSchedulingPolicy::Idle {
+        // On Linux, nice values are per-thread and a `who` of 0 is the calling thread.
+        // SAFETY:
+        // Trivially safe, no memory is passed to the kernel.
+        check_errno(unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, scheduling.priority) })?;
+    }
+
+    if !scheduling.affinity.is_empty() {
+        // SAFETY:
+        // cpu_set_t is plain old data, for which all zeroes is the empty set.
+        let mut cpu_set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        for &cpu in &scheduling.affinity {
+            if cpu >= libc::CPU_SETSIZE as usize {
+                return Err(MesaError::WithContext("cpu index out of range"));
+            }
+
+            // SAFETY:
+            // `cpu` was checked to be within the bounds of `cpu_set`.
+            unsafe { libc::CPU_SET(cpu, &mut cpu_set) };
+        }
+
+        let set_size = size_of::<libc::cpu_set_t>();
+        // SAFETY:
+        // A pid of 0 is the calling thread, and `cpu_set` is a valid cpu_set_t of the given size.
+        check_errno(unsafe { libc::sched_setaffinity(0, set_size, &cpu_set) })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    // Returns the CPUs the calling thread may run on.
+    fn current_affinity() -> Vec<usize> {
+        // SAFETY:
+        // cpu_set_t is plain old data, for which all zeroes is the empty set.
+        let mut cpu_set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        // SAFETY:
+        // A pid of 0 is the calling thread, and `cpu_set` is a valid cpu_set_t of the given size.
+        let ret = unsafe { libc::sched_getaffinity(0, size_of::<libc::cpu_set_t>(), &mut cpu_set) };
+        assert_eq!(ret, 0);
+        (0..libc::CPU_SETSIZE as usize)
+            // SAFETY:
+            // `cpu` is within the bounds of `cpu_set`.
+            .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &cpu_set) })
+            .collect()
+    }
+
+    #[test]
+    fn time_sharing_scheduling() {
+        thread::spawn(|| {
+            let cpu = current_affinity()[0];
+            let scheduling = MesaThreadScheduling {
+                policy: MesaSchedulingPolicy::Batch,
+                priority: 5,
+                affinity: vec![cpu],
+            };
+            set_current_thread_scheduling(&scheduling).unwrap();
+
+            // SAFETY:
+            // Trivially safe, no memory is passed to the kernel.
+            let (policy, nice) = unsafe {
+                (
+                    libc::sched_getscheduler(0),
+                    libc::getpriority(libc::PRIO_PROCESS, 0),
+                )
+            };
+            assert_eq!((policy, nice), (libc::SCHED_BATCH, 5));
+            assert_eq!(current_affinity(), [cpu]);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn affinity_out_of_range() {
+        thread::spawn(|| {
+            let scheduling = MesaThreadScheduling {
+                affinity: vec![libc::CPU_SETSIZE as usize],
+                ..Default::default()
+            };
+            assert!(set_current_thread_scheduling(&scheduling).is_err());
+        })
+        .join()
+        .unwrap();
+    }
+}
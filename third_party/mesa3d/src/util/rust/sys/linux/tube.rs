@@ -10,6 +10,8 @@ use std::path::Path;
 use rustix::cmsg_space;
 use rustix::fs::fcntl_setfl;
 use rustix::fs::OFlags;
+use rustix::io::fcntl_setfd;
+use rustix::io::FdFlags;
 use rustix::net::accept;
 use rustix::net::bind;
 use rustix::net::connect;
@@ -17,6 +19,8 @@ use rustix::net::listen;
 use rustix::net::recvmsg;
 use rustix::net::sendmsg;
 use rustix::net::socket_with;
+use rustix::net::sockopt::socket_acceptconn;
+use rustix::net::sockopt::socket_type;
 use rustix::net::AddressFamily;
 use rustix::net::RecvAncillaryBuffer;
 use rustix::net::RecvAncillaryMessage;
@@ -145,6 +149,20 @@ impl Listener {
         })
     }
 
+    /// Creates a `Listener` from a socket that is already listening, such as one inherited from a
+    /// service manager.
+    pub fn from_descriptor(socket: OwnedDescriptor) -> MesaResult<Listener> {
+        if socket_type(&socket)? != SocketType::SEQPACKET || !socket_acceptconn(&socket)? {
+            return Err(MesaError::WithContext("not a listening seqpacket socket"));
+        }
+
+        // Inherited sockets aren't close-on-exec.
+        fcntl_setfd(&socket, FdFlags::CLOEXEC)?;
+        fcntl_setfl(&socket, OFlags::NONBLOCK)?;
+
+        Ok(Listener { socket })
+    }
+
     pub fn accept(&self) -> MesaResult<Tube> {
         let accepted_fd = accept(&self.socket)?;
         let descriptor: OwnedDescriptor = accepted_fd.into();
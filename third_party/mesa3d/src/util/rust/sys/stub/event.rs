@@ -22,6 +22,10 @@ impl Event {
         Err(MesaError::Unsupported)
     }
 
+    pub fn try_wait(&self) -> MesaResult<bool> {
+        Err(MesaError::Unsupported)
+    }
+
     pub fn try_clone(&self) -> MesaResult<Event> {
         Err(MesaError::Unsupported)
     }
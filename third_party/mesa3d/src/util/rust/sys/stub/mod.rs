@@ -6,9 +6,11 @@ pub mod event;
 pub mod memory_mapping;
 pub mod pipe;
 pub mod shm;
+pub mod thread;
 pub mod tube;
 pub mod wait_context;
 
 pub use memory_mapping::MemoryMapping;
 pub use shm::page_size;
+pub use shm::seal_shared_memory;
 pub use shm::SharedMemory;
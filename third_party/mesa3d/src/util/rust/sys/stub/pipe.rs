@@ -1,6 +1,8 @@
 // Copyright 2025 Google
 // SPDX-License-Identifier: MIT
 
+use std::io::IoSliceMut;
+
 use crate::AsBorrowedDescriptor;
 use crate::AsRawDescriptor;
 use crate::MesaError;
@@ -19,6 +21,10 @@ impl ReadPipe {
     pub fn read(&self, _data: &mut [u8]) -> MesaResult<usize> {
         Err(MesaError::Unsupported)
     }
+
+    pub fn read_vectored(&self, _bufs: &mut [IoSliceMut]) -> MesaResult<usize> {
+        Err(MesaError::Unsupported)
+    }
 }
 
 impl AsBorrowedDescriptor for ReadPipe {
@@ -7,6 +7,7 @@ use crate::descriptor::AsRawDescriptor;
 use crate::descriptor::IntoRawDescriptor;
 use crate::MesaError;
 use crate::MesaResult;
+use crate::OwnedDescriptor;
 use crate::RawDescriptor;
 
 pub struct SharedMemory {
@@ -40,6 +41,10 @@ impl IntoRawDescriptor for SharedMemory {
     }
 }
 
+pub fn seal_shared_memory(_descriptor: &OwnedDescriptor) -> MesaResult<()> {
+    Err(MesaError::Unsupported)
+}
+
 pub fn page_size() -> MesaResult<u64> {
     Err(MesaError::Unsupported)
 }
@@ -38,6 +38,10 @@ impl Listener {
         Err(MesaError::Unsupported)
     }
 
+    pub fn from_descriptor(_socket: OwnedDescriptor) -> MesaResult<Listener> {
+        Err(MesaError::Unsupported)
+    }
+
     pub fn accept(&self) -> MesaResult<Tube> {
         Err(MesaError::Unsupported)
     }
@@ -1,21 +1,39 @@
 // Copyright 2025 Google
 // SPDX-License-Identifier: MIT
 
+use std::ffi::c_void;
 use std::fs::File;
 use std::io::Error;
 use std::io::ErrorKind;
 use std::io::Result;
+use std::mem::size_of;
+use std::mem::zeroed;
 use std::os::windows::io::AsRawHandle;
 use std::os::windows::io::FromRawHandle;
 use std::os::windows::io::IntoRawHandle;
 use std::os::windows::io::OwnedHandle;
 use std::os::windows::io::RawHandle;
 use std::os::windows::raw::HANDLE;
+use std::ptr::null_mut;
+
+use windows_sys::Wdk::Foundation::NtQueryObject;
+use windows_sys::Wdk::Foundation::ObjectBasicInformation;
+use windows_sys::Win32::Storage::FileSystem::GetFileType;
+use windows_sys::Win32::Storage::FileSystem::FILE_READ_DATA;
+use windows_sys::Win32::Storage::FileSystem::FILE_TYPE_PIPE;
+use windows_sys::Win32::Storage::FileSystem::FILE_WRITE_DATA;
+use windows_sys::Win32::System::Memory::MapViewOfFile;
+use windows_sys::Win32::System::Memory::UnmapViewOfFile;
+use windows_sys::Win32::System::Memory::VirtualQuery;
+use windows_sys::Win32::System::Memory::FILE_MAP_READ;
+use windows_sys::Win32::System::Memory::MEMORY_BASIC_INFORMATION;
+use windows_sys::Win32::System::WindowsProgramming::PUBLIC_OBJECT_BASIC_INFORMATION;
 
 use crate::descriptor::AsRawDescriptor;
 use crate::descriptor::FromRawDescriptor;
 use crate::descriptor::IntoRawDescriptor;
 use crate::DescriptorType;
+use crate::MESA_HANDLE_TYPE_MEM_SHM;
 
 pub type RawDescriptor = RawHandle;
 // Same as winapi::um::handleapi::INVALID_HANDLE_VALUE, but avoids compile issues.
@@ -31,8 +49,66 @@ impl OwnedDescriptor {
         Ok(OwnedDescriptor { owned: clone })
     }
 
+    /// Sections are reported as shared memory, and pipes opened for writing only as write pipes.
     pub fn determine_type(&self) -> Result<DescriptorType> {
-        Err(Error::from(ErrorKind::Unsupported))
+        let handle = self.as_raw_descriptor();
+        // SAFETY:
+        // GetFileType only queries the handle, which is owned by `self`.
+        if unsafe { GetFileType(handle) } == FILE_TYPE_PIPE {
+            // Like on Linux, read ends and duplex pipes are unsupported.  The direction of the
+            // pipe is only known from the access granted to the handle.
+            let mut info = PUBLIC_OBJECT_BASIC_INFORMATION::default();
+            // SAFETY:
+            // `info` is plain data, and NtQueryObject fills in at most the given size.
+            let status = unsafe {
+                NtQueryObject(
+                    handle,
+                    ObjectBasicInformation,
+                    &mut info as *mut PUBLIC_OBJECT_BASIC_INFORMATION as *mut c_void,
+                    size_of::<PUBLIC_OBJECT_BASIC_INFORMATION>() as u32,
+                    null_mut(),
+                )
+            };
+            if status < 0 {
+                return Err(Error::from(ErrorKind::Unsupported));
+            }
+
+            return match (
+                info.GrantedAccess & FILE_READ_DATA,
+                info.GrantedAccess & FILE_WRITE_DATA,
+            ) {
+                (0, FILE_WRITE_DATA) => Ok(DescriptorType::WritePipe),
+                _ => Err(Error::from(ErrorKind::Unsupported)),
+            };
+        }
+
+        // Sections are the only handles that can be mapped, and the size of the mapping is the
+        // size of the section rounded up to pages.
+        // SAFETY:
+        // The view is only queried and unmapped again, never accessed.
+        let view = unsafe { MapViewOfFile(handle, FILE_MAP_READ, 0, 0, 0) };
+        if view.Value.is_null() {
+            return Err(Error::from(ErrorKind::Unsupported));
+        }
+
+        // SAFETY:
+        // MEMORY_BASIC_INFORMATION is plain data, and VirtualQuery fills in at most the given
+        // size.
+        let (ret, info) = unsafe {
+            let mut info: MEMORY_BASIC_INFORMATION = zeroed();
+            let ret = VirtualQuery(view.Value, &mut info, size_of::<MEMORY_BASIC_INFORMATION>());
+            UnmapViewOfFile(view);
+            (ret, info)
+        };
+        if ret == 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let size: u32 = info
+            .RegionSize
+            .try_into()
+            .map_err(|_| Error::from(ErrorKind::Unsupported))?;
+        Ok(DescriptorType::Memory(size, MESA_HANDLE_TYPE_MEM_SHM))
     }
 }
 
@@ -1,47 +1,123 @@
 // Copyright 2025 Google
 // SPDX-License-Identifier: MIT
 
+use std::io::Error;
+use std::ptr::null;
+
+use windows_sys::Win32::Foundation::WAIT_OBJECT_0;
+use windows_sys::Win32::Foundation::WAIT_TIMEOUT;
+use windows_sys::Win32::System::Threading::CreateEventW;
+use windows_sys::Win32::System::Threading::ResetEvent;
+use windows_sys::Win32::System::Threading::SetEvent;
+use windows_sys::Win32::System::Threading::WaitForSingleObject;
+use windows_sys::Win32::System::Threading::INFINITE;
+
 use crate::AsBorrowedDescriptor;
+use crate::AsRawDescriptor;
+use crate::FromRawDescriptor;
 use crate::MesaError;
 use crate::MesaHandle;
 use crate::MesaResult;
 use crate::OwnedDescriptor;
+use crate::MESA_HANDLE_TYPE_SIGNAL_OPAQUE_WIN32;
 
-pub struct Event;
+/// A manual-reset event, so waiting on it in a `WaitContext` doesn't consume the signal.
+pub struct Event {
+    descriptor: OwnedDescriptor,
+}
 
 impl Event {
     pub fn new() -> MesaResult<Event> {
-        Err(MesaError::Unsupported)
+        // SAFETY:
+        // No security attributes or name are passed, so the event is private to this process.
+        let handle = unsafe { CreateEventW(null(), 1, 0, null()) };
+        if handle.is_null() {
+            return Err(MesaError::IoError(Error::last_os_error()));
+        }
+
+        // SAFETY:
+        // The handle was just created and nothing else owns it.
+        let descriptor = unsafe { OwnedDescriptor::from_raw_descriptor(handle) };
+        Ok(Event { descriptor })
     }
 
     pub fn signal(&mut self) -> MesaResult<()> {
-        Err(MesaError::Unsupported)
+        // SAFETY:
+        // The handle is a valid event owned by `self`.
+        if unsafe { SetEvent(self.descriptor.as_raw_descriptor()) } == 0 {
+            return Err(MesaError::IoError(Error::last_os_error()));
+        }
+
+        Ok(())
     }
 
     pub fn wait(&self) -> MesaResult<()> {
-        Err(MesaError::Unsupported)
+        let handle = self.descriptor.as_raw_descriptor();
+        // SAFETY:
+        // The handle is a valid event owned by `self`.
+        if unsafe { WaitForSingleObject(handle, INFINITE) } != WAIT_OBJECT_0 {
+            return Err(MesaError::IoError(Error::last_os_error()));
+        }
+
+        // SAFETY:
+        // The handle is a valid event owned by `self`.
+        if unsafe { ResetEvent(handle) } == 0 {
+            return Err(MesaError::IoError(Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    /// Consumes a pending signal without blocking, and returns false if there was none.
+    pub fn try_wait(&self) -> MesaResult<bool> {
+        let handle = self.descriptor.as_raw_descriptor();
+        // SAFETY:
+        // The handle is a valid event owned by `self`.
+        match unsafe { WaitForSingleObject(handle, 0) } {
+            WAIT_OBJECT_0 => (),
+            WAIT_TIMEOUT => return Ok(false),
+            _ => return Err(MesaError::IoError(Error::last_os_error())),
+        }
+
+        // SAFETY:
+        // The handle is a valid event owned by `self`.
+        if unsafe { ResetEvent(handle) } == 0 {
+            return Err(MesaError::IoError(Error::last_os_error()));
+        }
+
+        Ok(true)
     }
 
     pub fn try_clone(&self) -> MesaResult<Event> {
-        Err(MesaError::Unsupported)
+        let clone = self.descriptor.try_clone()?;
+        Ok(Event { descriptor: clone })
     }
 }
 
 impl TryFrom<MesaHandle> for Event {
     type Error = MesaError;
-    fn try_from(_handle: MesaHandle) -> Result<Self, Self::Error> {
-        Err(MesaError::Unsupported)
+    fn try_from(handle: MesaHandle) -> Result<Self, Self::Error> {
+        if handle.handle_type != MESA_HANDLE_TYPE_SIGNAL_OPAQUE_WIN32 {
+            return Err(MesaError::InvalidMesaHandle);
+        }
+
+        Ok(Event {
+            descriptor: handle.os_handle,
+        })
     }
 }
 
 impl From<Event> for MesaHandle {
-    fn from(_evt: Event) -> Self {
-        unimplemented!()
+    fn from(evt: Event) -> Self {
+        MesaHandle {
+            os_handle: evt.descriptor,
+            handle_type: MESA_HANDLE_TYPE_SIGNAL_OPAQUE_WIN32,
+        }
     }
 }
 
 impl AsBorrowedDescriptor for Event {
     fn as_borrowed_descriptor(&self) -> &OwnedDescriptor {
-        unimplemented!()
+        &self.descriptor
     }
 }
@@ -1,15 +1,27 @@
 // Copyright 2025 Google
 // SPDX-License-Identifier: MIT
 
+use std::io::Error;
+use std::io::IoSliceMut;
+use std::ptr::null_mut;
+
+use windows_sys::Win32::Storage::FileSystem::WriteFile;
+
 use crate::AsBorrowedDescriptor;
 use crate::AsRawDescriptor;
+use crate::FromRawDescriptor;
 use crate::MesaError;
 use crate::MesaResult;
 use crate::OwnedDescriptor;
 use crate::RawDescriptor;
 
+// Anonymous pipes can't be waited on, so only writing to pipes handed over by other processes is
+// supported.
 pub struct ReadPipe;
-pub struct WritePipe;
+
+pub struct WritePipe {
+    descriptor: OwnedDescriptor,
+}
 
 pub fn create_pipe() -> MesaResult<(ReadPipe, WritePipe)> {
     Err(MesaError::Unsupported)
@@ -19,6 +31,10 @@ impl ReadPipe {
     pub fn read(&self, _data: &mut [u8]) -> MesaResult<usize> {
         Err(MesaError::Unsupported)
     }
+
+    pub fn read_vectored(&self, _bufs: &mut [IoSliceMut]) -> MesaResult<usize> {
+        Err(MesaError::Unsupported)
+    }
 }
 
 impl AsBorrowedDescriptor for ReadPipe {
@@ -28,23 +44,45 @@ impl AsBorrowedDescriptor for ReadPipe {
 }
 
 impl WritePipe {
-    pub fn new(_descriptor: RawDescriptor) -> WritePipe {
-        unimplemented!()
+    // Matches the Linux signature, where descriptors aren't pointers.
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    pub fn new(descriptor: RawDescriptor) -> WritePipe {
+        // SAFETY: Safe because we know the underlying OS descriptor is valid and
+        // owned by us.
+        let owned = unsafe { OwnedDescriptor::from_raw_descriptor(descriptor) };
+        WritePipe { descriptor: owned }
     }
 
-    pub fn write(&self, _data: &[u8]) -> MesaResult<usize> {
-        Err(MesaError::Unsupported)
+    pub fn write(&self, data: &[u8]) -> MesaResult<usize> {
+        let len: u32 = data.len().try_into()?;
+        let mut bytes_written: u32 = 0;
+        // SAFETY:
+        // The handle is owned by `self`, and `data` outlives the synchronous write.
+        let ret = unsafe {
+            WriteFile(
+                self.descriptor.as_raw_descriptor(),
+                data.as_ptr(),
+                len,
+                &mut bytes_written,
+                null_mut(),
+            )
+        };
+        if ret == 0 {
+            return Err(MesaError::IoError(Error::last_os_error()));
+        }
+
+        Ok(bytes_written.try_into()?)
     }
 }
 
 impl AsBorrowedDescriptor for WritePipe {
     fn as_borrowed_descriptor(&self) -> &OwnedDescriptor {
-        unimplemented!()
+        &self.descriptor
     }
 }
 
 impl AsRawDescriptor for WritePipe {
     fn as_raw_descriptor(&self) -> RawDescriptor {
-        unimplemented!()
+        self.descriptor.as_raw_descriptor()
     }
 }
@@ -2,8 +2,18 @@
 // SPDX-License-Identifier: MIT
 
 use std::ffi::CStr;
+use std::io::Error;
+use std::mem::zeroed;
+use std::ptr::null;
+
+use windows_sys::Win32::Foundation::INVALID_HANDLE_VALUE;
+use windows_sys::Win32::System::Memory::CreateFileMappingW;
+use windows_sys::Win32::System::Memory::PAGE_READWRITE;
+use windows_sys::Win32::System::SystemInformation::GetSystemInfo;
+use windows_sys::Win32::System::SystemInformation::SYSTEM_INFO;
 
 use crate::descriptor::AsRawDescriptor;
+use crate::descriptor::FromRawDescriptor;
 use crate::descriptor::IntoRawDescriptor;
 use crate::MesaError;
 use crate::MesaResult;
@@ -17,9 +27,32 @@ pub struct SharedMemory {
 }
 
 impl SharedMemory {
-    /// Creates a new shared memory file mapping with zero size.
-    pub fn new(_debug_name: &CStr, _size: u64) -> MesaResult<Self> {
-        Err(MesaError::Unsupported)
+    /// Creates a new shared memory file mapping of `size` bytes, backed by the paging file.
+    ///
+    /// Sections can't be named without also being visible to other processes, so the debug name
+    /// is ignored.
+    pub fn new(_debug_name: &CStr, size: u64) -> MesaResult<Self> {
+        // SAFETY:
+        // No security attributes or name are passed, and INVALID_HANDLE_VALUE asks for a section
+        // backed by the paging file.
+        let handle = unsafe {
+            CreateFileMappingW(
+                INVALID_HANDLE_VALUE,
+                null(),
+                PAGE_READWRITE,
+                (size >> 32) as u32,
+                size as u32,
+                null(),
+            )
+        };
+        if handle.is_null() {
+            return Err(MesaError::IoError(Error::last_os_error()));
+        }
+
+        // SAFETY:
+        // The section was just created and nothing else owns it.
+        let descriptor = unsafe { OwnedDescriptor::from_raw_descriptor(handle) };
+        Ok(SharedMemory { descriptor, size })
     }
 
     /// Gets the size in bytes of the shared memory.
@@ -46,7 +79,17 @@ impl IntoRawDescriptor for SharedMemory {
     }
 }
 
-pub fn page_size() -> MesaResult<u64> {
-    // TODO: rustix ideally should support this using GetSystemInfo(..)
+pub fn seal_shared_memory(_descriptor: &OwnedDescriptor) -> MesaResult<()> {
     Err(MesaError::Unsupported)
 }
+
+pub fn page_size() -> MesaResult<u64> {
+    // SAFETY:
+    // SYSTEM_INFO is plain data, and GetSystemInfo only fills it in.
+    let info = unsafe {
+        let mut info: SYSTEM_INFO = zeroed();
+        GetSystemInfo(&mut info);
+        info
+    };
+    Ok(info.dwPageSize.into())
+}
@@ -0,0 +1,10 @@
+// Copyright 2025 Google
+// SPDX-License-Identifier: MIT
+
+use crate::MesaError;
+use crate::MesaResult;
+use crate::MesaThreadScheduling;
+
+pub fn set_current_thread_scheduling(_scheduling: &MesaThreadScheduling) -> MesaResult<()> {
+    Err(MesaError::Unsupported)
+}
@@ -1,43 +1,745 @@
 // Copyright 2025 Google
 // SPDX-License-Identifier: MIT
 
+//! Tubes are the client end of a connection to a local server.
+//!
+//! Stream tubes connect to an AF_UNIX socket, such as the one a Wayland compositor listens on,
+//! and carry the data unframed.  Winsock can't pass handles over AF_UNIX sockets, so sending
+//! handles over a stream tube is unsupported.
+//!
+//! Packet tubes connect to a message mode named pipe, where each message is a `TubeHeader`
+//! followed by the opaque data.  Handles can't be passed over pipes either, so the header carries
+//! the values of handles that stay open in the sending process until the receiver duplicates
+//! them out with `DUPLICATE_CLOSE_SOURCE`.  The server must speak the same protocol.
+
+use std::ffi::OsStr;
+use std::io::Error;
+use std::mem::size_of;
+use std::mem::zeroed;
+use std::os::windows::ffi::OsStrExt;
 use std::path::Path;
+use std::ptr::null;
+use std::ptr::null_mut;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+
+use windows_sys::Win32::Foundation::DuplicateHandle;
+use windows_sys::Win32::Foundation::GetLastError;
+use windows_sys::Win32::Foundation::DUPLICATE_CLOSE_SOURCE;
+use windows_sys::Win32::Foundation::DUPLICATE_SAME_ACCESS;
+use windows_sys::Win32::Foundation::ERROR_BROKEN_PIPE;
+use windows_sys::Win32::Foundation::ERROR_IO_PENDING;
+use windows_sys::Win32::Foundation::ERROR_MORE_DATA;
+use windows_sys::Win32::Foundation::GENERIC_READ;
+use windows_sys::Win32::Foundation::GENERIC_WRITE;
+use windows_sys::Win32::Foundation::HANDLE;
+use windows_sys::Win32::Foundation::INVALID_HANDLE_VALUE;
+use windows_sys::Win32::Networking::WinSock::closesocket;
+use windows_sys::Win32::Networking::WinSock::connect;
+use windows_sys::Win32::Networking::WinSock::recv;
+use windows_sys::Win32::Networking::WinSock::select;
+use windows_sys::Win32::Networking::WinSock::send;
+use windows_sys::Win32::Networking::WinSock::socket;
+use windows_sys::Win32::Networking::WinSock::WSAEnumNetworkEvents;
+use windows_sys::Win32::Networking::WinSock::WSAEventSelect;
+use windows_sys::Win32::Networking::WinSock::WSAGetLastError;
+use windows_sys::Win32::Networking::WinSock::WSAStartup;
+use windows_sys::Win32::Networking::WinSock::AF_UNIX;
+use windows_sys::Win32::Networking::WinSock::FD_CLOSE;
+use windows_sys::Win32::Networking::WinSock::FD_READ;
+use windows_sys::Win32::Networking::WinSock::FD_SET;
+use windows_sys::Win32::Networking::WinSock::INVALID_SOCKET;
+use windows_sys::Win32::Networking::WinSock::SOCKADDR;
+use windows_sys::Win32::Networking::WinSock::SOCKADDR_UN;
+use windows_sys::Win32::Networking::WinSock::SOCKET;
+use windows_sys::Win32::Networking::WinSock::SOCKET_ERROR;
+use windows_sys::Win32::Networking::WinSock::SOCK_STREAM;
+use windows_sys::Win32::Networking::WinSock::WSADATA;
+use windows_sys::Win32::Networking::WinSock::WSAEVENT;
+use windows_sys::Win32::Networking::WinSock::WSAEWOULDBLOCK;
+use windows_sys::Win32::Networking::WinSock::WSANETWORKEVENTS;
+use windows_sys::Win32::Storage::FileSystem::CreateFileW;
+use windows_sys::Win32::Storage::FileSystem::ReadFile;
+use windows_sys::Win32::Storage::FileSystem::WriteFile;
+use windows_sys::Win32::Storage::FileSystem::FILE_FLAG_OVERLAPPED;
+use windows_sys::Win32::Storage::FileSystem::OPEN_EXISTING;
+use windows_sys::Win32::System::Pipes::GetNamedPipeServerProcessId;
+use windows_sys::Win32::System::Pipes::SetNamedPipeHandleState;
+use windows_sys::Win32::System::Pipes::PIPE_READMODE_MESSAGE;
+use windows_sys::Win32::System::Threading::CreateEventW;
+use windows_sys::Win32::System::Threading::GetCurrentProcess;
+use windows_sys::Win32::System::Threading::OpenProcess;
+use windows_sys::Win32::System::Threading::ResetEvent;
+use windows_sys::Win32::System::Threading::SetEvent;
+use windows_sys::Win32::System::Threading::PROCESS_DUP_HANDLE;
+use windows_sys::Win32::System::IO::CancelIoEx;
+use windows_sys::Win32::System::IO::GetOverlappedResult;
+use windows_sys::Win32::System::IO::OVERLAPPED;
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
 
 use crate::AsBorrowedDescriptor;
+use crate::AsRawDescriptor;
+use crate::FromRawDescriptor;
+use crate::IntoRawDescriptor;
 use crate::MesaError;
 use crate::MesaResult;
 use crate::OwnedDescriptor;
 use crate::TubeType;
 
-pub struct Tube;
-pub struct Listener;
+const MAX_IDENTIFIERS: usize = 28;
+
+#[repr(C)]
+#[derive(Copy, Clone, Default, FromBytes, IntoBytes, Immutable)]
+struct TubeHeader {
+    data_len: u32,
+    num_handles: u32,
+    handles: [u64; MAX_IDENTIFIERS],
+}
+
+/// The zero byte read that signals `Tube::read_event` once a message can be received.  Boxed,
+/// since the kernel writes to the OVERLAPPED until the read completes.
+struct ReadNotify {
+    overlapped: Box<OVERLAPPED>,
+    pending: bool,
+}
+
+struct PipeTube {
+    pipe: OwnedDescriptor,
+    peer_process: OwnedDescriptor,
+    read_event: OwnedDescriptor,
+    // Sends and receives may run concurrently on different threads, so each waits on its own
+    // event.
+    send_event: OwnedDescriptor,
+    receive_event: OwnedDescriptor,
+    read_notify: Mutex<ReadNotify>,
+}
+
+struct SocketTube {
+    socket: SOCKET,
+    read_event: OwnedDescriptor,
+}
+
+enum Transport {
+    Pipe(PipeTube),
+    Socket(SocketTube),
+}
+
+pub struct Tube {
+    transport: Transport,
+}
+
+// SAFETY:
+// The OVERLAPPED structures are only accessed with the `read_notify` lock held, or during a
+// synchronous call on the calling thread.  Winsock sockets may be used from any thread.
+unsafe impl Send for Tube {}
+// SAFETY:
+// See above.
+unsafe impl Sync for Tube {}
+
+fn last_error() -> MesaError {
+    MesaError::IoError(Error::last_os_error())
+}
+
+fn last_socket_error() -> MesaError {
+    // SAFETY:
+    // WSAGetLastError has no preconditions.
+    MesaError::IoError(Error::from_raw_os_error(unsafe { WSAGetLastError() }))
+}
+
+fn os_error(e: &MesaError) -> Option<u32> {
+    match e {
+        MesaError::IoError(e) => e.raw_os_error().map(|code| code as u32),
+        _ => None,
+    }
+}
+
+fn create_event() -> MesaResult<OwnedDescriptor> {
+    // SAFETY:
+    // No security attributes or name are passed, so the manual-reset event is private to this
+    // process.
+    let handle = unsafe { CreateEventW(null(), 1, 0, null()) };
+    if handle.is_null() {
+        return Err(last_error());
+    }
+
+    // SAFETY:
+    // The event was just created and nothing else owns it.
+    Ok(unsafe { OwnedDescriptor::from_raw_descriptor(handle) })
+}
+
+fn io_overlapped(event: &OwnedDescriptor) -> OVERLAPPED {
+    // SAFETY:
+    // OVERLAPPED is plain data, for which zero is a valid value.
+    let mut overlapped: OVERLAPPED = unsafe { zeroed() };
+    overlapped.hEvent = event.as_raw_descriptor();
+    overlapped
+}
+
+// Waits for the overlapped operation on `pipe` to finish, returning the number of bytes
+// transferred.
+fn finish_io(pipe: &OwnedDescriptor, overlapped: &OVERLAPPED) -> MesaResult<u32> {
+    let mut transferred: u32 = 0;
+    // SAFETY:
+    // The OVERLAPPED was used to start an operation on the pipe, and outlives the wait.
+    let ret =
+        unsafe { GetOverlappedResult(pipe.as_raw_descriptor(), overlapped, &mut transferred, 1) };
+    if ret == 0 {
+        return Err(last_error());
+    }
+
+    Ok(transferred)
+}
+
+// Closes a handle that was sent to us, but that won't be duplicated out of the peer.
+fn close_peer_handle(peer_process: &OwnedDescriptor, value: u64) {
+    // SAFETY:
+    // With DUPLICATE_CLOSE_SOURCE and no target, DuplicateHandle only closes the handle in the
+    // peer, which gave up ownership of it by sending it.
+    unsafe {
+        DuplicateHandle(
+            peer_process.as_raw_descriptor(),
+            value as HANDLE,
+            null_mut(),
+            null_mut(),
+            0,
+            0,
+            DUPLICATE_CLOSE_SOURCE,
+        )
+    };
+}
+
+fn init_winsock() -> MesaResult<()> {
+    static WSA_STARTUP: OnceLock<i32> = OnceLock::new();
+    let ret = *WSA_STARTUP.get_or_init(|| {
+        // SAFETY:
+        // WSADATA is plain data, which WSAStartup fills in.  Winsock is never cleaned up, since
+        // tubes may be created until the process exits.
+        unsafe {
+            let mut data: WSADATA = zeroed();
+            WSAStartup(0x0202, &mut data)
+        }
+    });
+
+    if ret != 0 {
+        return Err(MesaError::IoError(Error::from_raw_os_error(ret)));
+    }
+
+    Ok(())
+}
+
+impl PipeTube {
+    fn new<P: AsRef<Path>>(path: P) -> MesaResult<PipeTube> {
+        let wide_path: Vec<u16> = OsStr::new(path.as_ref())
+            .encode_wide()
+            .chain(Some(0))
+            .collect();
+
+        // SAFETY:
+        // `wide_path` is NUL terminated, and no security attributes or template are passed.
+        let handle = unsafe {
+            CreateFileW(
+                wide_path.as_ptr(),
+                GENERIC_READ | GENERIC_WRITE,
+                0,
+                null(),
+                OPEN_EXISTING,
+                FILE_FLAG_OVERLAPPED,
+                null_mut(),
+            )
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(last_error());
+        }
+
+        // SAFETY:
+        // The pipe was just opened and nothing else owns it.
+        let pipe = unsafe { OwnedDescriptor::from_raw_descriptor(handle) };
+
+        let mode = PIPE_READMODE_MESSAGE;
+        // SAFETY:
+        // `mode` outlives the call, and the other settings are left unchanged.
+        if unsafe { SetNamedPipeHandleState(handle, &mode, null(), null()) } == 0 {
+            return Err(last_error());
+        }
+
+        let mut server_process_id: u32 = 0;
+        // SAFETY:
+        // `server_process_id` outlives the call.
+        if unsafe { GetNamedPipeServerProcessId(handle, &mut server_process_id) } == 0 {
+            return Err(last_error());
+        }
+
+        // SAFETY:
+        // OpenProcess has no memory safety preconditions.
+        let process = unsafe { OpenProcess(PROCESS_DUP_HANDLE, 0, server_process_id) };
+        if process.is_null() {
+            return Err(last_error());
+        }
+
+        // SAFETY:
+        // The process handle was just opened and nothing else owns it.
+        let peer_process = unsafe { OwnedDescriptor::from_raw_descriptor(process) };
+
+        let tube = PipeTube {
+            pipe,
+            peer_process,
+            read_event: create_event()?,
+            send_event: create_event()?,
+            receive_event: create_event()?,
+            read_notify: Mutex::new(ReadNotify {
+                // SAFETY:
+                // OVERLAPPED is plain data, for which zero is a valid value.
+                overlapped: Box::new(unsafe { zeroed() }),
+                pending: false,
+            }),
+        };
+
+        tube.arm_read_notify(&mut tube.read_notify.lock().unwrap())?;
+        Ok(tube)
+    }
+
+    // Starts a zero byte read, which completes and signals `read_event` once a message arrives
+    // or the server hangs up.
+    fn arm_read_notify(&self, read_notify: &mut ReadNotify) -> MesaResult<()> {
+        // SAFETY:
+        // The event is owned by `self`.
+        unsafe { ResetEvent(self.read_event.as_raw_descriptor()) };
+
+        // SAFETY:
+        // OVERLAPPED is plain data, for which zero is a valid value.
+        *read_notify.overlapped = unsafe { zeroed() };
+        read_notify.overlapped.hEvent = self.read_event.as_raw_descriptor();
+
+        // SAFETY:
+        // The OVERLAPPED is boxed, and isn't moved or freed until the read completes.  Nothing is
+        // read into the buffer.
+        let ret = unsafe {
+            ReadFile(
+                self.pipe.as_raw_descriptor(),
+                null_mut(),
+                0,
+                null_mut(),
+                &mut *read_notify.overlapped,
+            )
+        };
+        // SAFETY:
+        // GetLastError has no preconditions.
+        let error = unsafe { GetLastError() };
+        if ret != 0 || error == ERROR_IO_PENDING || error == ERROR_MORE_DATA {
+            read_notify.pending = true;
+            return Ok(());
+        }
+
+        read_notify.pending = false;
+        if error == ERROR_BROKEN_PIPE {
+            // Wake up waiters, so the next receive reports the hang up.
+            // SAFETY:
+            // The event is owned by `self`.
+            unsafe { SetEvent(self.read_event.as_raw_descriptor()) };
+            return Ok(());
+        }
+
+        Err(MesaError::IoError(Error::from_raw_os_error(error as i32)))
+    }
+
+    fn send(&self, opaque_data: &[u8], descriptors: &[OwnedDescriptor]) -> MesaResult<usize> {
+        if descriptors.len() > MAX_IDENTIFIERS {
+            return Err(MesaError::WithContext("too many handles for one message"));
+        }
+
+        let mut header = TubeHeader {
+            data_len: opaque_data.len().try_into()?,
+            num_handles: descriptors.len().try_into()?,
+            ..Default::default()
+        };
+
+        // The peer closes these copies when it duplicates them out, so they're only reclaimed
+        // here if the message is never sent.
+        let mut sent_handles: Vec<HANDLE> = Vec::with_capacity(descriptors.len());
+        let reclaim = |handles: &[HANDLE]| {
+            for handle in handles {
+                // SAFETY:
+                // The copy was made below, and the peer never saw it.
+                drop(unsafe { OwnedDescriptor::from_raw_descriptor(*handle) });
+            }
+        };
+
+        for (value, descriptor) in header.handles.iter_mut().zip(descriptors) {
+            match descriptor.try_clone() {
+                Ok(copy) => {
+                    let handle = copy.into_raw_descriptor();
+                    sent_handles.push(handle);
+                    *value = handle as u64;
+                }
+                Err(e) => {
+                    reclaim(&sent_handles);
+                    return Err(e.into());
+                }
+            }
+        }
+
+        let mut message = header.as_bytes().to_vec();
+        message.extend_from_slice(opaque_data);
+
+        let mut overlapped = io_overlapped(&self.send_event);
+        // SAFETY:
+        // `message` and `overlapped` outlive the write, which is waited for below.
+        let ret = unsafe {
+            WriteFile(
+                self.pipe.as_raw_descriptor(),
+                message.as_ptr(),
+                message.len().try_into()?,
+                null_mut(),
+                &mut overlapped,
+            )
+        };
+        // SAFETY:
+        // GetLastError has no preconditions.
+        if ret == 0 && unsafe { GetLastError() } != ERROR_IO_PENDING {
+            let e = last_error();
+            reclaim(&sent_handles);
+            return Err(e);
+        }
+
+        if let Err(e) = finish_io(&self.pipe, &overlapped) {
+            reclaim(&sent_handles);
+            return Err(e);
+        }
+
+        Ok(opaque_data.len())
+    }
+
+    fn receive(&self, opaque_data: &mut [u8]) -> MesaResult<(usize, Vec<OwnedDescriptor>)> {
+        let mut read_notify = self.read_notify.lock().unwrap();
+        if read_notify.pending {
+            read_notify.pending = false;
+            // The zero byte read fails with ERROR_MORE_DATA once a message arrives.
+            if let Err(e) = finish_io(&self.pipe, &read_notify.overlapped) {
+                match os_error(&e) {
+                    Some(ERROR_MORE_DATA) => (),
+                    Some(ERROR_BROKEN_PIPE) => return Ok((0, Vec::new())),
+                    _ => return Err(e),
+                }
+            }
+        }
+
+        let mut message = vec![0u8; size_of::<TubeHeader>() + opaque_data.len()];
+        let mut overlapped = io_overlapped(&self.receive_event);
+        // SAFETY:
+        // `message` and `overlapped` outlive the read, which is waited for below.
+        let ret = unsafe {
+            ReadFile(
+                self.pipe.as_raw_descriptor(),
+                message.as_mut_ptr(),
+                message.len().try_into()?,
+                null_mut(),
+                &mut overlapped,
+            )
+        };
+        // SAFETY:
+        // GetLastError has no preconditions.
+        let error = unsafe { GetLastError() };
+        if ret == 0 && error == ERROR_BROKEN_PIPE {
+            return Ok((0, Vec::new()));
+        } else if ret == 0 && error != ERROR_IO_PENDING {
+            return Err(MesaError::IoError(Error::from_raw_os_error(error as i32)));
+        }
+
+        let len: usize = match finish_io(&self.pipe, &overlapped) {
+            Ok(len) => len.try_into()?,
+            Err(e) if os_error(&e) == Some(ERROR_MORE_DATA) => {
+                return Err(MesaError::WithContext("message doesn't fit in the buffer"));
+            }
+            Err(e) => return Err(e),
+        };
+
+        let result = self.unpack(&message[..len], opaque_data);
+        self.arm_read_notify(&mut read_notify)?;
+        result
+    }
+
+    // Copies out the data of a received message, and duplicates its handles out of the peer.
+    fn unpack(
+        &self,
+        message: &[u8],
+        opaque_data: &mut [u8],
+    ) -> MesaResult<(usize, Vec<OwnedDescriptor>)> {
+        let (header, data) = TubeHeader::read_from_prefix(message)
+            .map_err(|_| MesaError::WithContext("message is missing its header"))?;
+
+        let num_handles = header.num_handles as usize;
+        let data_len = header.data_len as usize;
+        if num_handles > MAX_IDENTIFIERS || data_len > data.len() {
+            for value in &header.handles[..num_handles.min(MAX_IDENTIFIERS)] {
+                close_peer_handle(&self.peer_process, *value);
+            }
+            return Err(MesaError::WithContext("malformed message header"));
+        }
+
+        let values = &header.handles[..num_handles];
+        let mut descriptors = Vec::with_capacity(num_handles);
+        for (i, value) in values.iter().enumerate() {
+            let handle = *value as HANDLE;
+            let mut duplicate: HANDLE = null_mut();
+            // SAFETY:
+            // Both process handles are valid, and `duplicate` outlives the call.  DuplicateHandle
+            // fails unless `handle` is open in the peer, so a bogus value is never wrapped.
+            let ret = (!handle.is_null() && handle != INVALID_HANDLE_VALUE)
+                && unsafe {
+                    DuplicateHandle(
+                        self.peer_process.as_raw_descriptor(),
+                        handle,
+                        GetCurrentProcess(),
+                        &mut duplicate,
+                        0,
+                        0,
+                        DUPLICATE_SAME_ACCESS | DUPLICATE_CLOSE_SOURCE,
+                    )
+                } != 0;
+            if !ret {
+                for value in &values[i + 1..] {
+                    close_peer_handle(&self.peer_process, *value);
+                }
+                return Err(MesaError::WithContext("invalid handle in message"));
+            }
+
+            // SAFETY:
+            // The handle was just duplicated into this process, and nothing else owns it.
+            descriptors.push(unsafe { OwnedDescriptor::from_raw_descriptor(duplicate) });
+        }
+
+        opaque_data[..data_len].copy_from_slice(&data[..data_len]);
+        Ok((data_len, descriptors))
+    }
+}
+
+impl Drop for PipeTube {
+    fn drop(&mut self) {
+        let read_notify = self.read_notify.get_mut().unwrap();
+        if read_notify.pending {
+            // The OVERLAPPED must not be freed while the kernel may still write to it.
+            // SAFETY:
+            // The OVERLAPPED was used to start the read on the pipe.
+            unsafe { CancelIoEx(self.pipe.as_raw_descriptor(), &*read_notify.overlapped) };
+            let _ = finish_io(&self.pipe, &read_notify.overlapped);
+        }
+    }
+}
+
+impl SocketTube {
+    fn new<P: AsRef<Path>>(path: P) -> MesaResult<SocketTube> {
+        init_winsock()?;
+
+        let path = path
+            .as_ref()
+            .to_str()
+            .ok_or(MesaError::WithContext("socket path isn't valid UTF-8"))?;
+
+        // SAFETY:
+        // SOCKADDR_UN is plain data, for which zero is a valid value.
+        let mut address: SOCKADDR_UN = unsafe { zeroed() };
+        address.sun_family = AF_UNIX;
+        // Leave room for the NUL terminator.
+        if path.len() >= address.sun_path.len() {
+            return Err(MesaError::WithContext("socket path is too long"));
+        }
+
+        for (dst, src) in address.sun_path.iter_mut().zip(path.bytes()) {
+            *dst = src as i8;
+        }
+
+        // SAFETY:
+        // socket has no memory safety preconditions.
+        let socket = unsafe { socket(AF_UNIX.into(), SOCK_STREAM, 0) };
+        if socket == INVALID_SOCKET {
+            return Err(last_socket_error());
+        }
+
+        let tube = SocketTube {
+            socket,
+            read_event: create_event()?,
+        };
+
+        // SAFETY:
+        // `address` outlives the call, and its size is passed along.
+        let ret = unsafe {
+            connect(
+                tube.socket,
+                &address as *const SOCKADDR_UN as *const SOCKADDR,
+                size_of::<SOCKADDR_UN>().try_into()?,
+            )
+        };
+        if ret == SOCKET_ERROR {
+            return Err(last_socket_error());
+        }
+
+        // This also makes the socket non-blocking, so sends and receives wait in `wait_socket`.
+        // SAFETY:
+        // The event is owned by `tube`, and outlives the socket.
+        let ret = unsafe {
+            WSAEventSelect(
+                tube.socket,
+                tube.read_event.as_raw_descriptor() as WSAEVENT,
+                (FD_READ | FD_CLOSE) as i32,
+            )
+        };
+        if ret == SOCKET_ERROR {
+            return Err(last_socket_error());
+        }
+
+        Ok(tube)
+    }
+
+    // Waits until the socket is readable or writable, depending on `write`.
+    fn wait_socket(&self, write: bool) -> MesaResult<()> {
+        let mut fds = FD_SET {
+            fd_count: 1,
+            fd_array: [0; 64],
+        };
+        fds.fd_array[0] = self.socket;
+
+        let (read_fds, write_fds) = match write {
+            true => (null_mut(), &mut fds as *mut FD_SET),
+            false => (&mut fds as *mut FD_SET, null_mut()),
+        };
+
+        // SAFETY:
+        // `fds` outlives the call, and no timeout is passed, so select blocks until the socket is
+        // ready.
+        let ret = unsafe { select(0, read_fds, write_fds, null_mut(), null()) };
+        if ret == SOCKET_ERROR {
+            return Err(last_socket_error());
+        }
+
+        Ok(())
+    }
+
+    fn send(&self, opaque_data: &[u8], descriptors: &[OwnedDescriptor]) -> MesaResult<usize> {
+        if !descriptors.is_empty() {
+            return Err(MesaError::Unsupported);
+        }
+
+        let mut sent = 0;
+        while sent < opaque_data.len() {
+            let remaining = &opaque_data[sent..];
+            let len: i32 = remaining.len().min(i32::MAX as usize).try_into()?;
+            // SAFETY:
+            // `remaining` is valid for `len` bytes.
+            let ret = unsafe { send(self.socket, remaining.as_ptr(), len, 0) };
+            if ret == SOCKET_ERROR {
+                // SAFETY:
+                // WSAGetLastError has no preconditions.
+                if unsafe { WSAGetLastError() } == WSAEWOULDBLOCK {
+                    self.wait_socket(true)?;
+                    continue;
+                }
+
+                return Err(last_socket_error());
+            }
+
+            sent += ret as usize;
+        }
+
+        Ok(sent)
+    }
+
+    fn receive(&self, opaque_data: &mut [u8]) -> MesaResult<(usize, Vec<OwnedDescriptor>)> {
+        // Clears `read_event`.  A receive that leaves data behind re-enables FD_READ, which sets
+        // it again.
+        // SAFETY:
+        // WSANETWORKEVENTS is plain data, which WSAEnumNetworkEvents fills in.
+        let ret = unsafe {
+            let mut events: WSANETWORKEVENTS = zeroed();
+            WSAEnumNetworkEvents(
+                self.socket,
+                self.read_event.as_raw_descriptor() as WSAEVENT,
+                &mut events,
+            )
+        };
+        if ret == SOCKET_ERROR {
+            return Err(last_socket_error());
+        }
+
+        let len: i32 = opaque_data.len().min(i32::MAX as usize).try_into()?;
+        loop {
+            // SAFETY:
+            // `opaque_data` is valid for `len` bytes.
+            let ret = unsafe { recv(self.socket, opaque_data.as_mut_ptr(), len, 0) };
+            if ret != SOCKET_ERROR {
+                // Zero bytes are received once the server hangs up.
+                return Ok((ret as usize, Vec::new()));
+            }
+
+            // SAFETY:
+            // WSAGetLastError has no preconditions.
+            if unsafe { WSAGetLastError() } != WSAEWOULDBLOCK {
+                return Err(last_socket_error());
+            }
+
+            self.wait_socket(false)?;
+        }
+    }
+}
+
+impl Drop for SocketTube {
+    fn drop(&mut self) {
+        // SAFETY:
+        // The socket is owned by `self`.
+        unsafe { closesocket(self.socket) };
+    }
+}
 
 impl Tube {
-    pub fn new<P: AsRef<Path>>(_path: P, _kind: TubeType) -> MesaResult<Tube> {
-        Err(MesaError::Unsupported)
+    pub fn new<P: AsRef<Path>>(path: P, kind: TubeType) -> MesaResult<Tube> {
+        let transport = match kind {
+            TubeType::Stream => Transport::Socket(SocketTube::new(path)?),
+            TubeType::Packet => Transport::Pipe(PipeTube::new(path)?),
+        };
+
+        Ok(Tube { transport })
     }
 
-    pub fn send(&self, _opaque_data: &[u8], _descriptors: &[OwnedDescriptor]) -> MesaResult<usize> {
-        Err(MesaError::Unsupported)
+    pub fn send(&self, opaque_data: &[u8], descriptors: &[OwnedDescriptor]) -> MesaResult<usize> {
+        match &self.transport {
+            Transport::Pipe(pipe) => pipe.send(opaque_data, descriptors),
+            Transport::Socket(socket) => socket.send(opaque_data, descriptors),
+        }
     }
 
-    pub fn receive(&self, _opaque_data: &mut [u8]) -> MesaResult<(usize, Vec<OwnedDescriptor>)> {
-        Err(MesaError::Unsupported)
+    /// Receives the next message, or the next bytes for a stream tube.  Zero bytes and no handles
+    /// are returned once the server hangs up.
+    pub fn receive(&self, opaque_data: &mut [u8]) -> MesaResult<(usize, Vec<OwnedDescriptor>)> {
+        match &self.transport {
+            Transport::Pipe(pipe) => pipe.receive(opaque_data),
+            Transport::Socket(socket) => socket.receive(opaque_data),
+        }
     }
 }
 
 impl AsBorrowedDescriptor for Tube {
+    /// The event that is signaled once a message can be received.
     fn as_borrowed_descriptor(&self) -> &OwnedDescriptor {
-        unimplemented!()
+        match &self.transport {
+            Transport::Pipe(pipe) => &pipe.read_event,
+            Transport::Socket(socket) => &socket.read_event,
+        }
     }
 }
 
+pub struct Listener;
+
 impl Listener {
     /// Creates a new `Listener` bound to the given path.
     pub fn bind<P: AsRef<Path>>(_path: P) -> MesaResult<Listener> {
         Err(MesaError::Unsupported)
     }
 
+    pub fn from_descriptor(_socket: OwnedDescriptor) -> MesaResult<Listener> {
+        Err(MesaError::Unsupported)
+    }
+
     pub fn accept(&self) -> MesaResult<Tube> {
         Err(MesaError::Unsupported)
     }
@@ -48,3 +750,225 @@ impl AsBorrowedDescriptor for Listener {
         unimplemented!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use windows_sys::Win32::Networking::WinSock::accept;
+    use windows_sys::Win32::Networking::WinSock::bind;
+    use windows_sys::Win32::Networking::WinSock::listen;
+    use windows_sys::Win32::Storage::FileSystem::PIPE_ACCESS_DUPLEX;
+    use windows_sys::Win32::System::Pipes::CreateNamedPipeW;
+    use windows_sys::Win32::System::Pipes::PIPE_TYPE_MESSAGE;
+    use windows_sys::Win32::System::Pipes::PIPE_WAIT;
+
+    use super::*;
+
+    // Creates the server end of a message mode pipe, which is serviced synchronously by the test.
+    fn pipe_server(name: &str) -> (String, OwnedDescriptor) {
+        let path = format!(r"\\.\pipe\mesa3d-tube-{}-{}", name, process::id());
+        let wide_path: Vec<u16> = OsStr::new(&path).encode_wide().chain(Some(0)).collect();
+        // SAFETY:
+        // `wide_path` is NUL terminated, and no security attributes are passed.
+        let handle = unsafe {
+            CreateNamedPipeW(
+                wide_path.as_ptr(),
+                PIPE_ACCESS_DUPLEX,
+                PIPE_TYPE_MESSAGE | PIPE_READMODE_MESSAGE | PIPE_WAIT,
+                1,
+                4096,
+                4096,
+                0,
+                null(),
+            )
+        };
+        assert_ne!(handle, INVALID_HANDLE_VALUE);
+        // SAFETY:
+        // The pipe was just created and nothing else owns it.
+        (path, unsafe {
+            OwnedDescriptor::from_raw_descriptor(handle)
+        })
+    }
+
+    fn server_write(server: &OwnedDescriptor, header: &TubeHeader, data: &[u8]) {
+        let mut message = header.as_bytes().to_vec();
+        message.extend_from_slice(data);
+        let mut written: u32 = 0;
+        // SAFETY:
+        // The server end isn't overlapped, so the write completes before returning.
+        let ret = unsafe {
+            WriteFile(
+                server.as_raw_descriptor(),
+                message.as_ptr(),
+                message.len() as u32,
+                &mut written,
+                null_mut(),
+            )
+        };
+        assert_ne!(ret, 0);
+    }
+
+    #[test]
+    fn packet_tube_pulls_handles() {
+        let (path, server) = pipe_server("pull");
+        let tube = Tube::new(&path, TubeType::Packet).unwrap();
+
+        // The server is this process, so the handle it sends is one of ours.
+        let event = create_event().unwrap().into_raw_descriptor();
+        let mut header = TubeHeader {
+            data_len: 5,
+            num_handles: 1,
+            ..Default::default()
+        };
+        header.handles[0] = event as u64;
+        server_write(&server, &header, b"hello");
+
+        let mut data = [0u8; 16];
+        let (len, descriptors) = tube.receive(&mut data).unwrap();
+        assert_eq!(&data[..len], b"hello");
+        assert_eq!(descriptors.len(), 1);
+        // SAFETY:
+        // The event was duplicated out for the tube to own.
+        assert_ne!(unsafe { SetEvent(descriptors[0].as_raw_descriptor()) }, 0);
+    }
+
+    #[test]
+    fn packet_tube_rejects_invalid_handles() {
+        let (path, server) = pipe_server("invalid");
+        let tube = Tube::new(&path, TubeType::Packet).unwrap();
+
+        let mut header = TubeHeader {
+            data_len: 1,
+            num_handles: 1,
+            ..Default::default()
+        };
+        server_write(&server, &header, b"x");
+
+        let mut data = [0u8; 16];
+        assert!(tube.receive(&mut data).is_err());
+
+        // Later messages are still received.
+        header.num_handles = 0;
+        server_write(&server, &header, b"y");
+        let (len, descriptors) = tube.receive(&mut data).unwrap();
+        assert_eq!(&data[..len], b"y");
+        assert!(descriptors.is_empty());
+    }
+
+    #[test]
+    fn packet_tube_sends_handles() {
+        let (path, server) = pipe_server("send");
+        let tube = Tube::new(&path, TubeType::Packet).unwrap();
+
+        let event = create_event().unwrap();
+        assert_eq!(tube.send(b"abc", &[event]).unwrap(), 3);
+
+        let mut message = vec![0u8; size_of::<TubeHeader>() + 16];
+        let mut read: u32 = 0;
+        // SAFETY:
+        // The server end isn't overlapped, so the read completes before returning.
+        let ret = unsafe {
+            ReadFile(
+                server.as_raw_descriptor(),
+                message.as_mut_ptr(),
+                message.len() as u32,
+                &mut read,
+                null_mut(),
+            )
+        };
+        assert_ne!(ret, 0);
+
+        let (header, data) = TubeHeader::read_from_prefix(&message[..read as usize]).unwrap();
+        assert_eq!(header.num_handles, 1);
+        assert_eq!(&data[..header.data_len as usize], b"abc");
+
+        // The tube left its copy open for the server to pull.
+        let mut duplicate: HANDLE = null_mut();
+        // SAFETY:
+        // The copy is owned by the message, and is closed by the duplication.
+        let ret = unsafe {
+            DuplicateHandle(
+                GetCurrentProcess(),
+                header.handles[0] as HANDLE,
+                GetCurrentProcess(),
+                &mut duplicate,
+                0,
+                0,
+                DUPLICATE_SAME_ACCESS | DUPLICATE_CLOSE_SOURCE,
+            )
+        };
+        assert_ne!(ret, 0);
+        // SAFETY:
+        // The duplicate was just created and nothing else owns it.
+        drop(unsafe { OwnedDescriptor::from_raw_descriptor(duplicate) });
+    }
+
+    #[test]
+    fn stream_tube_is_unframed() {
+        init_winsock().unwrap();
+
+        let path = std::env::temp_dir().join(format!("mesa3d-tube-{}", process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // SAFETY:
+        // SOCKADDR_UN is plain data, for which zero is a valid value.
+        let mut address: SOCKADDR_UN = unsafe { zeroed() };
+        address.sun_family = AF_UNIX;
+        for (dst, src) in address
+            .sun_path
+            .iter_mut()
+            .zip(path.to_str().unwrap().bytes())
+        {
+            *dst = src as i8;
+        }
+
+        // SAFETY:
+        // `address` outlives the calls, and the sockets are closed below.
+        let listener = unsafe { socket(AF_UNIX.into(), SOCK_STREAM, 0) };
+        assert_ne!(listener, INVALID_SOCKET);
+        let ret = unsafe {
+            bind(
+                listener,
+                &address as *const SOCKADDR_UN as *const SOCKADDR,
+                size_of::<SOCKADDR_UN>() as i32,
+            )
+        };
+        assert_ne!(ret, SOCKET_ERROR);
+        assert_ne!(unsafe { listen(listener, 1) }, SOCKET_ERROR);
+
+        let tube = Tube::new(&path, TubeType::Stream).unwrap();
+        // SAFETY:
+        // The peer address isn't needed.
+        let server = unsafe { accept(listener, null_mut(), null_mut()) };
+        assert_ne!(server, INVALID_SOCKET);
+
+        assert_eq!(tube.send(b"wayland", &[]).unwrap(), 7);
+        let mut data = [0u8; 16];
+        // SAFETY:
+        // `data` is valid for its length.
+        let len = unsafe { recv(server, data.as_mut_ptr(), data.len() as i32, 0) };
+        assert_eq!(&data[..len as usize], b"wayland");
+
+        // SAFETY:
+        // The data is valid for its length.
+        assert_eq!(unsafe { send(server, b"events".as_ptr(), 6, 0) }, 6);
+        let (len, descriptors) = tube.receive(&mut data).unwrap();
+        assert_eq!(&data[..len], b"events");
+        assert!(descriptors.is_empty());
+
+        let event = create_event().unwrap();
+        assert!(matches!(
+            tube.send(b"fd", &[event]),
+            Err(MesaError::Unsupported)
+        ));
+
+        // SAFETY:
+        // Both sockets are owned by the test.
+        unsafe {
+            closesocket(server);
+            closesocket(listener);
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,28 +1,102 @@
 // Copyright 2025 Google
 // SPDX-License-Identifier: MIT
 
+use std::io::Error;
+
+use windows_sys::Win32::Foundation::HANDLE;
+use windows_sys::Win32::Foundation::WAIT_ABANDONED_0;
+use windows_sys::Win32::Foundation::WAIT_OBJECT_0;
+use windows_sys::Win32::Foundation::WAIT_TIMEOUT;
+use windows_sys::Win32::System::Threading::WaitForMultipleObjects;
+use windows_sys::Win32::System::Threading::INFINITE;
+
+use crate::AsRawDescriptor;
 use crate::MesaError;
 use crate::MesaResult;
 use crate::OwnedDescriptor;
 use crate::WaitEvent;
 use crate::WaitTimeout;
+use crate::WAIT_CONTEXT_MAX;
 
-pub struct WaitContext;
+/// Waits on waitable handles, such as events or the read event of a `Tube`.
+///
+/// Only the first signaled handle is reported by each wait, since checking the others would
+/// consume the signal of auto-reset objects.
+pub struct WaitContext {
+    connection_ids: Vec<u64>,
+    handles: Vec<HANDLE>,
+}
+
+// SAFETY:
+// The handles are only waited on, and their owners remove them before closing them.
+unsafe impl Send for WaitContext {}
 
 impl WaitContext {
     pub fn new() -> MesaResult<WaitContext> {
-        Err(MesaError::Unsupported)
+        Ok(WaitContext {
+            connection_ids: Vec::new(),
+            handles: Vec::new(),
+        })
     }
 
-    pub fn add(&mut self, _connection_id: u64, _descriptor: &OwnedDescriptor) -> MesaResult<()> {
-        Err(MesaError::Unsupported)
+    pub fn add(&mut self, connection_id: u64, descriptor: &OwnedDescriptor) -> MesaResult<()> {
+        let handle = descriptor.as_raw_descriptor();
+        if self.handles.contains(&handle) {
+            return Err(MesaError::WithContext("handle is already being waited on"));
+        }
+
+        if self.handles.len() >= WAIT_CONTEXT_MAX {
+            return Err(MesaError::WithContext("too many handles to wait on"));
+        }
+
+        self.connection_ids.push(connection_id);
+        self.handles.push(handle);
+        Ok(())
     }
 
-    pub fn wait(&mut self, _timeout: WaitTimeout) -> MesaResult<Vec<WaitEvent>> {
-        Err(MesaError::Unsupported)
+    pub fn wait(&mut self, timeout: WaitTimeout) -> MesaResult<Vec<WaitEvent>> {
+        let milliseconds = match timeout {
+            WaitTimeout::Finite(duration) => {
+                duration.as_millis().try_into().unwrap_or(INFINITE - 1)
+            }
+            WaitTimeout::NoTimeout => INFINITE,
+        };
+
+        let count: u32 = self.handles.len().try_into()?;
+        // SAFETY:
+        // The handles are valid until deleted from the context, and at most WAIT_CONTEXT_MAX of
+        // them are passed, which is below MAXIMUM_WAIT_OBJECTS.
+        let result =
+            unsafe { WaitForMultipleObjects(count, self.handles.as_ptr(), 0, milliseconds) };
+
+        // An abandoned mutex means its owner exited, which is treated like a hang up.
+        let (index, hung_up) = if (WAIT_OBJECT_0..WAIT_OBJECT_0 + count).contains(&result) {
+            (result - WAIT_OBJECT_0, false)
+        } else if (WAIT_ABANDONED_0..WAIT_ABANDONED_0 + count).contains(&result) {
+            (result - WAIT_ABANDONED_0, true)
+        } else if result == WAIT_TIMEOUT {
+            return Ok(Vec::new());
+        } else {
+            return Err(MesaError::IoError(Error::last_os_error()));
+        };
+
+        Ok(vec![WaitEvent {
+            connection_id: self.connection_ids[index as usize],
+            hung_up,
+            readable: !hung_up,
+        }])
     }
 
-    pub fn delete(&mut self, _descriptor: &OwnedDescriptor) -> MesaResult<()> {
-        Err(MesaError::Unsupported)
+    pub fn delete(&mut self, descriptor: &OwnedDescriptor) -> MesaResult<()> {
+        let handle = descriptor.as_raw_descriptor();
+        let index = self
+            .handles
+            .iter()
+            .position(|h| *h == handle)
+            .ok_or(MesaError::WithContext("handle isn't being waited on"))?;
+
+        self.connection_ids.remove(index);
+        self.handles.remove(index);
+        Ok(())
     }
 }
@@ -0,0 +1,39 @@
+// Copyright 2025 Google
+// SPDX-License-Identifier: MIT
+
+use crate::sys::platform::thread::set_current_thread_scheduling;
+use crate::MesaResult;
+
+/// Scheduling policy of a thread.  Mirrors the Linux policies.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum MesaSchedulingPolicy {
+    /// The default time-sharing policy.  The priority is a nice value.
+    #[default]
+    Other,
+    /// Time-sharing for CPU-bound, non-interactive threads.  The priority is a nice value.
+    Batch,
+    /// Only runs when nothing else wants to.  The priority is ignored.
+    Idle,
+    /// Real-time, first-in first-out.  The priority is a real-time priority from 1 to 99.
+    Fifo,
+    /// Real-time, round-robin.  The priority is a real-time priority from 1 to 99.
+    RoundRobin,
+}
+
+/// Scheduling parameters of a thread.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MesaThreadScheduling {
+    pub policy: MesaSchedulingPolicy,
+    pub priority: i32,
+    /// CPUs the thread may run on.  Empty means any CPU.
+    pub affinity: Vec<usize>,
+}
+
+impl MesaThreadScheduling {
+    /// Applies the scheduling parameters to the calling thread.  Fails if the platform doesn't
+    /// support them or the process lacks the permissions, such as CAP_SYS_NICE for real-time
+    /// policies.  Parameters applied before the failure stay applied.
+    pub fn apply(&self) -> MesaResult<()> {
+        set_current_thread_scheduling(self)
+    }
+}
@@ -65,16 +65,24 @@ impl KumquatStream {
     }
 
     pub fn read(&mut self) -> MesaResult<Vec<KumquatGpuProtocol>> {
-        let mut vec: Vec<KumquatGpuProtocol> = Vec::new();
         let (bytes_read, descriptor_vec) = self.stream.receive(&mut self.read_buffer)?;
         let mut descriptors: VecDeque<OwnedDescriptor> = descriptor_vec.into();
 
         if bytes_read == 0 {
-            vec.push(KumquatGpuProtocol::OkNoData);
-            return Ok(vec);
+            return Ok(vec![KumquatGpuProtocol::OkNoData]);
         }
 
-        let mut reader = Reader::new(&self.read_buffer[0..bytes_read]);
+        KumquatStream::decode(&self.read_buffer[0..bytes_read], &mut descriptors)
+    }
+
+    /// Decodes every message in `bytes`, taking the descriptors they carry from `descriptors`.
+    /// Allocations are bounded by the length of `bytes`.
+    pub fn decode(
+        bytes: &[u8],
+        descriptors: &mut VecDeque<OwnedDescriptor>,
+    ) -> MesaResult<Vec<KumquatGpuProtocol>> {
+        let mut vec: Vec<KumquatGpuProtocol> = Vec::new();
+        let mut reader = Reader::new(bytes);
         while reader.available_bytes() != 0 {
             let hdr = reader.peek_obj::<kumquat_gpu_protocol_ctrl_hdr>()?;
             let protocol = match hdr.type_ {
@@ -135,6 +143,13 @@ impl KumquatStream {
                     } else if reader.available_bytes() != 0 {
                         let num_in_fences = cmd.num_in_fences as usize;
                         let cmd_size = cmd.size as usize;
+                        let fences_size = num_in_fences
+                            .checked_mul(size_of::<u64>())
+                            .ok_or(MesaError::Unsupported)?;
+                        if reader.available_bytes() < cmd_size.saturating_add(fences_size) {
+                            return Err(MesaError::Unsupported);
+                        }
+
                         let mut cmd_buf = vec![0; cmd_size];
                         let mut fence_ids: Vec<u64> = Vec::with_capacity(num_in_fences);
                         for _ in 0..num_in_fences {
@@ -154,6 +169,9 @@ impl KumquatStream {
                 KUMQUAT_GPU_PROTOCOL_RESOURCE_CREATE_BLOB => {
                     KumquatGpuProtocol::ResourceCreateBlob(reader.read_obj()?)
                 }
+                KUMQUAT_GPU_PROTOCOL_RESOURCE_RESIZE_BLOB => {
+                    KumquatGpuProtocol::ResourceResizeBlob(reader.read_obj()?)
+                }
                 KUMQUAT_GPU_PROTOCOL_SNAPSHOT_SAVE => {
                     reader.consume(size_of::<kumquat_gpu_protocol_ctrl_hdr>());
                     KumquatGpuProtocol::SnapshotSave
@@ -172,6 +190,10 @@ impl KumquatStream {
                 KUMQUAT_GPU_PROTOCOL_RESP_CAPSET => {
                     let len: usize = hdr.payload.try_into()?;
                     reader.consume(size_of::<kumquat_gpu_protocol_ctrl_hdr>());
+                    if reader.available_bytes() < len {
+                        return Err(MesaError::Unsupported);
+                    }
+
                     let mut capset: Vec<u8> = vec![0; len];
                     reader.read_exact(&mut capset)?;
                     KumquatGpuProtocol::RespCapset(capset)
@@ -221,3 +243,50 @@ impl KumquatStream {
         self.stream.as_borrowed_descriptor()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn submit_3d(size: u32, num_in_fences: u32) -> kumquat_gpu_protocol_cmd_submit {
+        kumquat_gpu_protocol_cmd_submit {
+            hdr: kumquat_gpu_protocol_ctrl_hdr {
+                type_: KUMQUAT_GPU_PROTOCOL_SUBMIT_3D,
+                ..Default::default()
+            },
+            size,
+            num_in_fences,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn decode_submit_3d() {
+        let mut bytes = submit_3d(4, 1).as_bytes().to_vec();
+        bytes.extend_from_slice(7u64.as_bytes());
+        bytes.extend_from_slice(&[1, 2, 3, 4]);
+
+        let protocols = KumquatStream::decode(&bytes, &mut VecDeque::new()).unwrap();
+        match &protocols[..] {
+            [KumquatGpuProtocol::CmdSubmit3d(_, cmd_buf, fence_ids)] => {
+                assert_eq!(cmd_buf[..], [1, 2, 3, 4]);
+                assert_eq!(fence_ids[..], [7]);
+            }
+            _ => panic!("unexpected protocols: {:?}", protocols),
+        }
+    }
+
+    #[test]
+    fn decode_rejects_lengths_beyond_message() {
+        // The in-fences would need 32 GiB, but the message only holds the command buffer.
+        let mut bytes = submit_3d(4, u32::MAX).as_bytes().to_vec();
+        bytes.extend_from_slice(&[0; 4]);
+        assert!(KumquatStream::decode(&bytes, &mut VecDeque::new()).is_err());
+
+        let capset = kumquat_gpu_protocol_ctrl_hdr {
+            type_: KUMQUAT_GPU_PROTOCOL_RESP_CAPSET,
+            payload: u32::MAX,
+        };
+        assert!(KumquatStream::decode(capset.as_bytes(), &mut VecDeque::new()).is_err());
+    }
+}
@@ -56,6 +56,7 @@ pub const KUMQUAT_GPU_PROTOCOL_GET_NUM_CAPSETS: u32 = 0x101;
 pub const KUMQUAT_GPU_PROTOCOL_GET_CAPSET_INFO: u32 = 0x102;
 pub const KUMQUAT_GPU_PROTOCOL_GET_CAPSET: u32 = 0x103;
 pub const KUMQUAT_GPU_PROTOCOL_RESOURCE_CREATE_BLOB: u32 = 0x104;
+pub const KUMQUAT_GPU_PROTOCOL_RESOURCE_RESIZE_BLOB: u32 = 0x105;
 
 /* 3d commands */
 pub const KUMQUAT_GPU_PROTOCOL_CTX_CREATE: u32 = 0x200;
@@ -218,6 +219,16 @@ pub struct kumquat_gpu_protocol_resource_create_blob {
     pub size: u64,
 }
 
+/* KUMQUAT_GPU_PROTOCOL_RESOURCE_RESIZE_BLOB */
+#[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct kumquat_gpu_protocol_resource_resize_blob {
+    pub hdr: kumquat_gpu_protocol_ctrl_hdr,
+    pub ctx_id: u32,
+    pub resource_id: u32,
+    pub size: u64,
+}
+
 #[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
 #[repr(C)]
 pub struct kumquat_gpu_protocol_resp_resource_create {
@@ -252,6 +263,7 @@ pub enum KumquatGpuProtocol {
     TransferFromHost3d(kumquat_gpu_protocol_transfer_host_3d, MesaHandle),
     CmdSubmit3d(kumquat_gpu_protocol_cmd_submit, Vec<u8>, Vec<u64>),
     ResourceCreateBlob(kumquat_gpu_protocol_resource_create_blob),
+    ResourceResizeBlob(kumquat_gpu_protocol_resource_resize_blob),
     SnapshotSave,
     SnapshotRestore,
     RespNumCapsets(u32),
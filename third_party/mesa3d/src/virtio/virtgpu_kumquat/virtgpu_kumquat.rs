@@ -287,6 +287,42 @@ impl VirtGpuKumquat {
         Ok(())
     }
 
+    pub fn resource_resize_blob(&mut self, bo_handle: u32, size: u64) -> MesaResult<()> {
+        let resource = self
+            .resources
+            .get_mut(&bo_handle)
+            .ok_or(MesaError::Unsupported)?;
+
+        if resource.system_mapping.is_some() {
+            return Err(MesaError::WithContext("cannot resize a mapped resource"));
+        }
+
+        let resource_resize_blob = kumquat_gpu_protocol_resource_resize_blob {
+            hdr: kumquat_gpu_protocol_ctrl_hdr {
+                type_: KUMQUAT_GPU_PROTOCOL_RESOURCE_RESIZE_BLOB,
+                ..Default::default()
+            },
+            ctx_id: self.context_id,
+            resource_id: resource.resource_id,
+            size,
+        };
+
+        self.stream
+            .write(KumquatGpuProtocolWrite::Cmd(resource_resize_blob))?;
+        let mut protocols = self.stream.read()?;
+        match protocols.remove(0) {
+            KumquatGpuProtocol::RespResourceCreate(_, handle) => {
+                resource.handle = handle;
+                resource.size = size.try_into()?;
+            }
+            _ => {
+                return Err(MesaError::Unsupported);
+            }
+        };
+
+        Ok(())
+    }
+
     pub fn resource_unref(&mut self, bo_handle: u32) -> MesaResult<()> {
         let resource = self
             .resources